@@ -0,0 +1,215 @@
+//! Client aborts: a client that closes its connection, or whose connection fails, before its
+//! response is complete has gone away. The backend request is then aborted at once, by resetting
+//! the backend connection, rather than left rendering a response nobody reads. The client is
+//! watched from the time its request waits for a backend, through connecting and forwarding the
+//! request, and such requests are logged with status 499.
+
+use crate::intercept::{self, BodyFraming, BodyTracker};
+use crate::keepalive::Exchanges;
+use std::future::Future;
+use std::io;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::task::{Context, Poll};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, ReadBuf};
+
+/// Status logged for requests whose client went away before the response was complete (as nginx does)
+pub const CLIENT_CLOSED: u16 = 499;
+
+/// Request bytes read ahead at most while the client is watched
+const MAX_PENDING: usize = 64 * 1024;
+
+/// What is known about a client connection while its response is streamed
+#[derive(Default)]
+pub struct ResponseProgress {
+    /// The first response has reached the client in full
+    complete: AtomicBool,
+    /// The client closed its side of the connection
+    closed: AtomicBool,
+    /// Reading from or writing to the client failed
+    failed: AtomicBool,
+    /// The backend has sent all it will; a client leaving after that did not cut anything short
+    backend_done: AtomicBool,
+}
+
+impl ResponseProgress {
+    pub fn is_complete(&self) -> bool {
+        self.complete.load(Ordering::Relaxed)
+    }
+
+    /// Whether the client went away before its response was complete
+    pub fn is_aborted(&self) -> bool {
+        !self.is_complete() && !self.backend_done.load(Ordering::Relaxed)
+            && (self.closed.load(Ordering::Relaxed) || self.failed.load(Ordering::Relaxed))
+    }
+
+    /// Record that the backend closed its side, after everything it sent was forwarded
+    pub fn backend_done(&self) {
+        self.backend_done.store(true, Ordering::Relaxed);
+    }
+}
+
+/// Where the client is in the response written to it
+enum State {
+    /// Bytes of a response head so far
+    Head(Vec<u8>),
+    Body(BodyTracker),
+    /// The response ends when the backend closes the connection, or its end is not known
+    Open,
+    Done,
+}
+
+/// A client connection that follows the responses written to it, to tell a client that went away
+/// from one that closed the connection after its response
+pub struct WatchedClient<'p, S> {
+    inner: S,
+    progress: &'p ResponseProgress,
+    state: State,
+    /// The request being answered was a `HEAD`, whose responses have no body
+    head_request: bool,
+    /// Later requests relayed on the connection, whose responses are followed too
+    exchanges: Option<&'p Exchanges>,
+}
+
+impl<'p, S> WatchedClient<'p, S> {
+    pub fn new(inner: S, progress: &'p ResponseProgress, head_request: bool, exchanges: Option<&'p Exchanges>) -> Self {
+        WatchedClient { inner, progress, state: State::Head(Vec::new()), head_request, exchanges }
+    }
+
+    /// Follow bytes written to the client
+    fn observe(&mut self, mut data: &[u8]) {
+        while !data.is_empty() {
+            match &mut self.state {
+                State::Head(head) => {
+                    let start = head.len().saturating_sub(3);
+                    head.extend_from_slice(data);
+                    data = &[];
+                    let Some(end) = head[start..].windows(4).position(|w| w == b"\r\n\r\n").map(|i| start + i + 4) else {
+                        if head.len() > intercept::MAX_RESPONSE_HEAD {
+                            self.state = State::Open;
+                        }
+                        continue;
+                    };
+                    let rest = head.split_off(end);
+                    let head = std::mem::take(head);
+                    self.state = self.body_state(&head);
+                    if matches!(self.state, State::Done) {
+                        self.answered();
+                    }
+                    if !rest.is_empty() {
+                        self.observe(&rest);
+                    }
+                }
+                State::Body(body) => match body.advance(data) {
+                    Some(n) => {
+                        data = &data[n..];
+                        self.state = State::Done;
+                        self.answered();
+                    }
+                    None if body.is_open() => self.state = State::Open,
+                    None => return,
+                },
+                // The response to a later request relayed on the connection
+                State::Done => match self.exchanges.and_then(Exchanges::next_request) {
+                    Some(head_request) => {
+                        self.head_request = head_request;
+                        self.state = State::Head(Vec::new());
+                    }
+                    None => return,
+                },
+                State::Open => return,
+            }
+        }
+    }
+
+    /// Record a response the client has had in full
+    fn answered(&self) {
+        self.progress.complete.store(true, Ordering::Relaxed);
+        if let Some(exchanges) = self.exchanges {
+            exchanges.answered();
+        }
+    }
+
+    /// Where the body of a response with this head ends
+    fn body_state(&self, head: &[u8]) -> State {
+        match intercept::status(head) {
+            // A protocol switch hands the connection over; the client ends it when it is done
+            Some(101) => {
+                if let Some(exchanges) = self.exchanges {
+                    exchanges.switched();
+                }
+                return State::Done;
+            }
+            Some(status) if (100..200).contains(&status) => return State::Head(Vec::new()),
+            Some(204 | 304) => return State::Done,
+            _ if self.head_request => return State::Done,
+            _ => {}
+        }
+        match intercept::framing(head) {
+            Some(BodyFraming::Close) | None => State::Open,
+            framing => {
+                let mut body = BodyTracker::new(framing);
+                match body.advance(&[]) {
+                    Some(_) => State::Done,
+                    None => State::Body(body),
+                }
+            }
+        }
+    }
+}
+
+impl<S: AsyncRead + Unpin> AsyncRead for WatchedClient<'_, S> {
+    fn poll_read(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        let filled = buf.filled().len();
+        let result = Pin::new(&mut this.inner).poll_read(cx, buf);
+        match &result {
+            Poll::Ready(Ok(())) if buf.filled().len() == filled && buf.remaining() > 0 => this.progress.closed.store(true, Ordering::Relaxed),
+            Poll::Ready(Err(_)) => this.progress.failed.store(true, Ordering::Relaxed),
+            _ => {}
+        }
+        result
+    }
+}
+
+impl<S: AsyncWrite + Unpin> AsyncWrite for WatchedClient<'_, S> {
+    fn poll_write(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+        let result = Pin::new(&mut this.inner).poll_write(cx, buf);
+        match &result {
+            Poll::Ready(Ok(n)) => this.observe(&buf[..*n]),
+            Poll::Ready(Err(_)) => this.progress.failed.store(true, Ordering::Relaxed),
+            Poll::Pending => {}
+        }
+        result
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().inner).poll_shutdown(cx)
+    }
+}
+
+/// Run backend work while watching the client connection: None when the client closes it first,
+/// which drops the work. Bytes the client sends meanwhile, the rest of its request, are kept in
+/// `pending` to forward after it; once that holds `MAX_PENDING`, the client is no longer watched.
+pub async fn unless_client_leaves<S: AsyncRead + Unpin, F: Future>(client: &mut S, pending: &mut Vec<u8>, work: F) -> Option<F::Output> {
+    tokio::pin!(work);
+    let mut buf = [0u8; 8192];
+    loop {
+        if pending.len() >= MAX_PENDING {
+            return Some(work.await);
+        }
+        tokio::select! {
+            biased;
+            output = &mut work => return Some(output),
+            read = client.read(&mut buf) => match read {
+                Ok(0) | Err(_) => return None,
+                Ok(n) => pending.extend_from_slice(&buf[..n]),
+            },
+        }
+    }
+}
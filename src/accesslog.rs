@@ -0,0 +1,271 @@
+//! The access log: one line per request, written once it is done, as text for reading or as JSON
+//! or logfmt for log pipelines (`--log-format`), to stdout or the log target, a rotated file
+//! (`--access-log`), or shipped to a collector (`--access-log-sink`); with `--scrub`, scrubbed of
+//! credentials and personal data
+
+use crate::logfile::LogFile;
+use crate::logsink::LogSink;
+use crate::request::RequestHead;
+use crate::response::{reason_phrase, rfc3339};
+use crate::scrub::Scrubber;
+use std::borrow::Cow;
+use std::fmt::Write;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::{Duration, Instant, SystemTime};
+
+#[derive(Clone, Copy)]
+pub enum LogFormat {
+    Text,
+    Json,
+    Logfmt,
+}
+
+impl LogFormat {
+    pub fn parse(name: &str) -> Result<Self, String> {
+        match name {
+            "text" => Ok(LogFormat::Text),
+            "json" => Ok(LogFormat::Json),
+            "logfmt" => Ok(LogFormat::Logfmt),
+            _ => Err(format!("Invalid log format '{}'. Expected text, json or logfmt", name)),
+        }
+    }
+}
+
+/// A request being handled, filled in as it goes
+pub struct Entry<'a> {
+    time: SystemTime,
+    start: Instant,
+    client: SocketAddr,
+    method: &'a str,
+    path: &'a str,
+    query: Option<&'a str>,
+    /// The matched route, once routing is done
+    pub route: Option<&'a str>,
+    /// The backend the request was sent to
+    pub backend: Option<&'a str>,
+    /// Status of the response sent, when known: the backend's final response on the connection's
+    /// first exchange, or the proxy's own response
+    pub status: Option<u16>,
+    pub request_bytes: u64,
+    pub response_bytes: u64,
+    /// The client's autonomous system, with an `--asn-db`
+    pub asn: Option<u32>,
+    /// How the request was handled beyond its route and status (`no route`, `rewritten to /x`, ...)
+    pub notes: Vec<Cow<'a, str>>,
+    pub timings: Timings,
+}
+
+/// Time a proxied request spent in each phase, summed over its attempts on `;retry` routes;
+/// phases it did not go through stay `None`
+#[derive(Default)]
+pub struct Timings {
+    /// Waiting for a turn at a `;backend-rate` backend
+    pub queue: Option<Duration>,
+    /// Looking up the backend's address
+    pub dns: Option<Duration>,
+    /// Setting up the TCP connection to the backend
+    pub connect: Option<Duration>,
+    /// The TLS handshake with the backend
+    pub tls: Option<Duration>,
+    /// Writing the request to the backend
+    pub write: Option<Duration>,
+    /// From the request written to the first byte of the response
+    pub first_byte: Option<Duration>,
+    /// From the first byte of the response to the end of the connection
+    pub transfer: Option<Duration>,
+}
+
+impl Timings {
+    /// Add time spent in a phase
+    pub fn add(phase: &mut Option<Duration>, elapsed: Duration) {
+        *phase = Some(phase.unwrap_or_default() + elapsed);
+    }
+
+    /// The phases gone through, with their names in the log, in milliseconds
+    fn phases(&self) -> impl Iterator<Item = (&'static str, f64)> {
+        [
+            ("queue_ms", self.queue),
+            ("dns_ms", self.dns),
+            ("connect_ms", self.connect),
+            ("tls_ms", self.tls),
+            ("write_ms", self.write),
+            ("ttfb_ms", self.first_byte),
+            ("transfer_ms", self.transfer),
+        ]
+        .into_iter()
+        .filter_map(|(name, phase)| phase.map(|phase| (name, (phase.as_secs_f64() * 1_000_000.0).round() / 1000.0)))
+    }
+}
+
+impl<'a> Entry<'a> {
+    pub fn new(head: &RequestHead<'a>, head_len: usize, client: SocketAddr, start: Instant) -> Self {
+        Entry {
+            time: SystemTime::now(),
+            start,
+            client,
+            method: head.method,
+            path: head.path,
+            query: head.query,
+            route: None,
+            backend: None,
+            status: None,
+            request_bytes: head_len as u64,
+            response_bytes: 0,
+            asn: None,
+            notes: Vec::new(),
+            timings: Timings::default(),
+        }
+    }
+
+    /// Record a response from the proxy itself
+    pub fn local(&mut self, status: u16, bytes: usize, note: impl Into<Cow<'a, str>>) {
+        self.status = Some(status);
+        self.response_bytes = bytes as u64;
+        self.note(note);
+    }
+
+    pub fn note(&mut self, note: impl Into<Cow<'a, str>>) {
+        let note = note.into();
+        if !note.is_empty() {
+            self.notes.push(note);
+        }
+    }
+}
+
+/// Writes entries in the configured format, to standard output or a log file, and ships them to
+/// a sink; with a sink and no file, they are not written out
+pub struct AccessLog {
+    format: LogFormat,
+    file: Option<LogFile>,
+    sink: Option<LogSink>,
+    scrubber: Option<Arc<Scrubber>>,
+}
+
+impl AccessLog {
+    pub fn new(format: LogFormat, file: Option<LogFile>, sink: Option<LogSink>, scrubber: Option<Arc<Scrubber>>) -> Self {
+        AccessLog { format, file, sink, scrubber }
+    }
+
+    pub fn log(&self, entry: &Entry) {
+        let line = self.format(entry);
+        match (&self.file, &self.sink) {
+            (Some(file), Some(sink)) => {
+                sink.push(line.clone());
+                file.write(line);
+            }
+            (Some(file), None) => file.write(line),
+            (None, Some(sink)) => sink.push(line),
+            (None, None) => crate::logging::info(&line),
+        }
+    }
+
+    /// Write out the entries still queued for the log file, and send those queued for the sink
+    pub async fn flush(&self) {
+        if let Some(file) = &self.file {
+            file.flush();
+        }
+        if let Some(sink) = &self.sink {
+            sink.flush().await;
+        }
+    }
+
+    fn scrub<'t>(&self, text: &'t str) -> Cow<'t, str> {
+        self.scrubber.as_ref().map_or(Cow::Borrowed(text), |scrubber| scrubber.scrub(text))
+    }
+
+    fn format(&self, entry: &Entry) -> String {
+        let duration_ms = entry.start.elapsed().as_secs_f64() * 1000.0;
+        // Paths, queries and notes (which may quote them) are what carries data of the clients
+        let path = self.scrub(entry.path);
+        let query = entry.query.map(|query| self.scrub(query));
+        let notes: Vec<Cow<'_, str>> = entry.notes.iter().map(|note| self.scrub(note)).collect();
+        match self.format {
+            LogFormat::Text => {
+                let mut line = format!("[{}] {} {}", entry.client, entry.method, path);
+                if let Some(query) = &query {
+                    line.push('?');
+                    line.push_str(query);
+                }
+                line.push_str(" -> ");
+                if let Some(backend) = entry.backend {
+                    line.push_str(backend);
+                    line.push(' ');
+                }
+                match entry.status {
+                    Some(status) => {
+                        let _ = write!(line, "{} {}", status, reason_phrase(status));
+                    }
+                    None => line.push('-'),
+                }
+                let _ = write!(line, ", {} bytes in {:.1} ms", entry.response_bytes, duration_ms);
+                for note in &notes {
+                    let _ = write!(line, " ({})", note);
+                }
+                line
+            }
+            LogFormat::Json => serde_json::json!({
+                "timestamp": rfc3339(entry.time),
+                "client_ip": entry.client.ip().to_string(),
+                "client_port": entry.client.port(),
+                "client_asn": entry.asn,
+                "method": entry.method,
+                "path": path,
+                "query": query,
+                "route": entry.route,
+                "backend": entry.backend,
+                "status": entry.status,
+                "request_bytes": entry.request_bytes,
+                "response_bytes": entry.response_bytes,
+                "duration_ms": (duration_ms * 1000.0).round() / 1000.0,
+                "timings": entry.timings.phases().collect::<std::collections::BTreeMap<_, _>>(),
+                "notes": notes,
+            }).to_string(),
+            LogFormat::Logfmt => {
+                let mut line = format!("time={} client_ip={} client_port={} method={}", rfc3339(entry.time), entry.client.ip(), entry.client.port(), logfmt_value(entry.method));
+                if let Some(asn) = entry.asn {
+                    let _ = write!(line, " client_asn={}", asn);
+                }
+                let optional = [("path", Some(&*path)), ("query", query.as_deref()), ("route", entry.route), ("backend", entry.backend)];
+                for (key, value) in optional {
+                    if let Some(value) = value {
+                        let _ = write!(line, " {}={}", key, logfmt_value(value));
+                    }
+                }
+                if let Some(status) = entry.status {
+                    let _ = write!(line, " status={}", status);
+                }
+                let _ = write!(line, " request_bytes={} response_bytes={} duration_ms={:.3}", entry.request_bytes, entry.response_bytes, duration_ms);
+                for (name, ms) in entry.timings.phases() {
+                    let _ = write!(line, " {}={:.3}", name, ms);
+                }
+                if !notes.is_empty() {
+                    let _ = write!(line, " notes={}", logfmt_value(&notes.join("; ")));
+                }
+                line
+            }
+        }
+    }
+}
+
+/// A logfmt value, quoted when it is empty or holds spaces, quotes or `=`
+fn logfmt_value(value: &str) -> Cow<'_, str> {
+    if !value.is_empty() && !value.contains(|c: char| c.is_whitespace() || c.is_control() || c == '"' || c == '=' || c == '\\') {
+        return Cow::Borrowed(value);
+    }
+    let mut quoted = String::with_capacity(value.len() + 2);
+    quoted.push('"');
+    for c in value.chars() {
+        match c {
+            '"' => quoted.push_str("\\\""),
+            '\\' => quoted.push_str("\\\\"),
+            '\n' => quoted.push_str("\\n"),
+            c if c.is_control() => {
+                let _ = write!(quoted, "\\u{{{:x}}}", c as u32);
+            }
+            c => quoted.push(c),
+        }
+    }
+    quoted.push('"');
+    Cow::Owned(quoted)
+}
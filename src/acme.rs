@@ -0,0 +1,801 @@
+//! ACME certificates for `;cert` listeners with `;acme=NAME[,NAME...]` (RFC 8555). Certificates are
+//! ordered from `--acme-directory` and validated with DNS-01 challenges, whose TXT records the
+//! `--acme-dns` provider publishes, so wildcard names such as `*.example.com` can be issued. A
+//! missing certificate is issued at startup, and one expiring within `--acme-renew` days, or not
+//! for the listener's names, is renewed in the background; the listener picks up the files written
+//! as it does any changed certificate.
+
+use crate::acmedns::{Provider, Records};
+use crate::events::EventBus;
+use crate::{certs, client, logging, response, x509};
+use base64::engine::general_purpose::{STANDARD, URL_SAFE_NO_PAD};
+use base64::Engine;
+use serde_json::{json, Value};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::{Duration, SystemTime};
+use tokio_rustls::rustls::pki_types::pem::PemObject;
+use tokio_rustls::rustls::pki_types::PrivateKeyDer;
+
+// Keys are generated and signed with aws-lc-rs when it is built in, as it is for FIPS, else ring
+#[cfg(feature = "aws-lc-rs")]
+use aws_lc_rs as crypto;
+#[cfg(not(feature = "aws-lc-rs"))]
+use ring as crypto;
+
+use crypto::rand::SystemRandom;
+use crypto::signature::{EcdsaKeyPair, EcdsaSigningAlgorithm, KeyPair, ECDSA_P256_SHA256_ASN1_SIGNING, ECDSA_P256_SHA256_FIXED_SIGNING};
+
+/// The default `--acme-directory`
+pub const LETS_ENCRYPT: &str = "https://acme-v02.api.letsencrypt.org/directory";
+
+/// How often the certificates are checked for renewal
+const RENEW_CHECK_INTERVAL: Duration = Duration::from_secs(12 * 3600);
+
+/// How often pending authorizations and orders are looked at again, and how many times
+const POLL_INTERVAL: Duration = Duration::from_secs(2);
+const POLL_ATTEMPTS: u32 = 90;
+
+const SEQUENCE: u8 = 0x30;
+const SET: u8 = 0x31;
+const INTEGER: u8 = 0x02;
+const BIT_STRING: u8 = 0x03;
+const OCTET_STRING: u8 = 0x04;
+const OID: u8 = 0x06;
+const UTF8_STRING: u8 = 0x0c;
+/// `[0]`, the attributes of a certification request
+const ATTRIBUTES: u8 = 0xa0;
+/// `[2]`, a `dNSName` GeneralName
+const DNS_NAME: u8 = 0x82;
+/// 1.2.840.10045.2.1, id-ecPublicKey
+const EC_PUBLIC_KEY: &[u8] = &[0x2a, 0x86, 0x48, 0xce, 0x3d, 0x02, 0x01];
+/// 1.2.840.10045.3.1.7, prime256v1
+const P256: &[u8] = &[0x2a, 0x86, 0x48, 0xce, 0x3d, 0x03, 0x01, 0x07];
+/// 1.2.840.10045.4.3.2, ecdsa-with-SHA256
+const ECDSA_WITH_SHA256: &[u8] = &[0x2a, 0x86, 0x48, 0xce, 0x3d, 0x04, 0x03, 0x02];
+/// 1.2.840.113549.1.9.14, extensionRequest
+const EXTENSION_REQUEST: &[u8] = &[0x2a, 0x86, 0x48, 0x86, 0xf7, 0x0d, 0x01, 0x09, 0x0e];
+/// 2.5.29.17, subjectAltName
+const SUBJECT_ALT_NAME: &[u8] = &[0x55, 0x1d, 0x11];
+/// 2.5.4.3, commonName
+const COMMON_NAME: &[u8] = &[0x55, 0x04, 0x03];
+
+/// A certificate kept issued: a listener's `;acme` names, and its `;cert` and `;key` files
+pub struct Order {
+    pub listener: String,
+    pub names: Vec<String>,
+    pub cert: PathBuf,
+    pub key: PathBuf,
+}
+
+/// An ACME account, and how it answers challenges
+pub struct Issuer {
+    directory: String,
+    key: EcdsaKeyPair,
+    /// The account key's public JWK, and its thumbprint (RFC 7638)
+    jwk: Value,
+    thumbprint: String,
+    email: Option<String>,
+    dns: Provider,
+    /// How long the TXT records are given to reach the zone's nameservers before the CA looks
+    dns_wait: Duration,
+    renew: Duration,
+}
+
+impl Issuer {
+    /// An account with the key in `account_key`, which is created when the file does not exist
+    pub fn new(directory: &str, account_key: &Path, email: Option<String>, dns: Provider, dns_wait: Duration, renew: Duration) -> Result<Self, String> {
+        let pkcs8 = match read_account_key(account_key)? {
+            Some(pkcs8) => pkcs8,
+            None => {
+                let pkcs8 = generate_key()?;
+                write_file(account_key, pem("PRIVATE KEY", &pkcs8).as_bytes(), true)?;
+                println!("Created ACME account key {}", account_key.display());
+                pkcs8
+            }
+        };
+        let key = key_pair(&ECDSA_P256_SHA256_FIXED_SIGNING, &pkcs8).map_err(|e| format!("ACME account key {}: {}", account_key.display(), e))?;
+        // An uncompressed point: 0x04, then x and y
+        let point = key.public_key().as_ref();
+        let (x, y) = (URL_SAFE_NO_PAD.encode(&point[1..33]), URL_SAFE_NO_PAD.encode(&point[33..]));
+        // The thumbprint hashes the required members in lexicographic order, without whitespace
+        let thumbprint = URL_SAFE_NO_PAD.encode(sha256(format!(r#"{{"crv":"P-256","kty":"EC","x":"{}","y":"{}"}}"#, x, y).as_bytes()));
+        let jwk = json!({"crv": "P-256", "kty": "EC", "x": x, "y": y});
+        Ok(Issuer { directory: directory.to_string(), key, jwk, thumbprint, email, dns, dns_wait, renew })
+    }
+
+    /// Issue the order's certificate if it is due. Failing to is an error only when there is no
+    /// certificate to serve; otherwise it is logged, and tried again at the next check.
+    pub async fn keep(&self, order: &Order, bus: &EventBus) -> Result<(), String> {
+        let Some(reason) = due(order, self.renew) else {
+            return Ok(());
+        };
+        let names = order.names.join(", ");
+        println!("Requesting a certificate for {} from {} for listener {}: {}", names, self.directory, order.listener, reason);
+        match self.issue(order).await {
+            Ok(expires) => {
+                println!("Issued a certificate for {} to listener {}, valid until {}", names, order.listener, response::rfc3339(expires));
+                bus.publish("certificate_issued", json!({
+                    "listener": order.listener,
+                    "names": order.names,
+                    "expires": response::rfc3339(expires),
+                }));
+                Ok(())
+            }
+            Err(e) => {
+                bus.publish("certificate_issue_failed", json!({
+                    "listener": order.listener,
+                    "names": order.names,
+                    "error": e,
+                }));
+                if certs::read(&order.cert, &order.key).is_err() {
+                    return Err(format!("Failed to issue a certificate for listener {}: {}", order.listener, e));
+                }
+                logging::error(format!("Failed to renew the certificate of listener {}: {}", order.listener, e));
+                Ok(())
+            }
+        }
+    }
+
+    /// Order a certificate for the names and write it, with a new key, to the order's files;
+    /// returns when it expires
+    async fn issue(&self, order: &Order) -> Result<SystemTime, String> {
+        let mut session = Session::open(self).await?;
+        let identifiers: Vec<Value> = order.names.iter().map(|name| json!({"type": "dns", "value": name})).collect();
+        let url = session.url("newOrder")?;
+        let response = session.post(&url, Some(&json!({"identifiers": identifiers}))).await?;
+        let order_url = response.header("location").ok_or("The ACME server gave no order URL")?.to_string();
+        let created = json_body(&response)?;
+
+        // The TXT values of the pending authorizations, by record name: a wildcard and its base
+        // name share one
+        let mut records: Vec<(String, Vec<String>)> = Vec::new();
+        let mut challenges = Vec::new();
+        for authorization in created["authorizations"].as_array().into_iter().flatten().filter_map(Value::as_str) {
+            let authz = json_body(&session.post(authorization, None).await?)?;
+            if authz["status"] == "valid" {
+                continue;
+            }
+            let domain = authz["identifier"]["value"].as_str().unwrap_or_default().to_string();
+            let challenge = dns_challenge(&authz).ok_or_else(|| format!("The ACME server offers no dns-01 challenge for {}", domain))?;
+            let (Some(url), Some(token)) = (challenge["url"].as_str(), challenge["token"].as_str()) else {
+                return Err(format!("The ACME server gave an incomplete dns-01 challenge for {}", domain));
+            };
+            let value = URL_SAFE_NO_PAD.encode(sha256(format!("{}.{}", token, self.thumbprint).as_bytes()));
+            let name = format!("_acme-challenge.{}", domain);
+            match records.iter_mut().find(|(record, _)| *record == name) {
+                Some((_, values)) => values.push(value),
+                None => records.push((name, vec![value])),
+            }
+            challenges.push((authorization.to_string(), url.to_string(), domain));
+        }
+
+        let mut added = Vec::new();
+        let answered = self.answer(&mut session, &records, &challenges, &mut added).await;
+        for records in &added {
+            if let Err(e) = self.dns.remove(records).await {
+                logging::warning(format!("Failed to remove the ACME challenge records of {}: {}", records.name, e));
+            }
+        }
+        answered?;
+
+        let pkcs8 = generate_key()?;
+        let csr = csr(&order.names, &pkcs8)?;
+        let finalize = created["finalize"].as_str().ok_or("The ACME server gave no finalize URL")?;
+        session.post(finalize, Some(&json!({"csr": URL_SAFE_NO_PAD.encode(csr)}))).await?;
+        let finalized = session.poll(&order_url).await?;
+        let Some(certificate) = finalized["certificate"].as_str().filter(|_| finalized["status"] == "valid") else {
+            return Err(format!("The ACME order was not issued: {}", problem(&finalized["error"])));
+        };
+        let chain = session.post(certificate, None).await?.body.ok_or("The ACME server sent an incomplete certificate")?;
+        save(order, &chain, &pkcs8)
+    }
+
+    /// Publish the TXT records of the challenges, and have the CA check them
+    async fn answer(&self, session: &mut Session<'_>, records: &[(String, Vec<String>)], challenges: &[(String, String, String)], added: &mut Vec<Records>) -> Result<(), String> {
+        for (name, values) in records {
+            added.push(self.dns.add(name, values).await?);
+        }
+        if !challenges.is_empty() {
+            tokio::time::sleep(self.dns_wait).await;
+        }
+        for (_, url, _) in challenges {
+            session.post(url, Some(&json!({}))).await?;
+        }
+        for (authorization, _, domain) in challenges {
+            let authz = session.poll(authorization).await?;
+            if authz["status"] != "valid" {
+                let error = dns_challenge(&authz).map_or(&Value::Null, |challenge| &challenge["error"]);
+                return Err(format!("The ACME server could not validate {}: {}", domain, problem(error)));
+            }
+        }
+        Ok(())
+    }
+
+    /// A JWS (RFC 7515) of the payload for a URL, signed with the account key: identified by its
+    /// JWK until the account has a URL. POST-as-GET requests have no payload.
+    fn jws(&self, url: &str, nonce: &str, account: Option<&str>, payload: Option<&Value>) -> Result<String, String> {
+        let mut protected = json!({"alg": "ES256", "nonce": nonce, "url": url});
+        match account {
+            Some(account) => protected["kid"] = account.into(),
+            None => protected["jwk"] = self.jwk.clone(),
+        }
+        let protected = URL_SAFE_NO_PAD.encode(protected.to_string());
+        let payload = payload.map(|payload| URL_SAFE_NO_PAD.encode(payload.to_string())).unwrap_or_default();
+        let signature = self.key.sign(&SystemRandom::new(), format!("{}.{}", protected, payload).as_bytes())
+            .map_err(|_| "Failed to sign an ACME request".to_string())?;
+        Ok(json!({"protected": protected, "payload": payload, "signature": URL_SAFE_NO_PAD.encode(signature.as_ref())}).to_string())
+    }
+}
+
+/// One conversation with the CA: its directory, the nonce to use next and the account's URL
+struct Session<'a> {
+    issuer: &'a Issuer,
+    directory: Value,
+    nonce: Option<String>,
+    account: Option<String>,
+}
+
+impl<'a> Session<'a> {
+    /// Fetch the directory and find the account, which is created if it does not exist
+    async fn open(issuer: &'a Issuer) -> Result<Session<'a>, String> {
+        let url = client::Url::parse(&issuer.directory)?;
+        let response = client::get(&url).await.map_err(|e| format!("Failed to fetch the ACME directory {}: {}", issuer.directory, e))?;
+        if response.status != 200 {
+            return Err(format!("The ACME directory {} answered {}", issuer.directory, response.status));
+        }
+        let mut session = Session { issuer, directory: json_body(&response)?, nonce: None, account: None };
+        let mut account = json!({"termsOfServiceAgreed": true});
+        if let Some(email) = &issuer.email {
+            account["contact"] = json!([format!("mailto:{}", email)]);
+        }
+        let url = session.url("newAccount")?;
+        let response = session.post(&url, Some(&account)).await?;
+        session.account = Some(response.header("location").ok_or("The ACME server gave no account URL")?.to_string());
+        Ok(session)
+    }
+
+    fn url(&self, resource: &str) -> Result<String, String> {
+        self.directory[resource].as_str().map(str::to_string).ok_or_else(|| format!("The ACME directory {} has no {}", self.issuer.directory, resource))
+    }
+
+    async fn nonce(&mut self) -> Result<String, String> {
+        if let Some(nonce) = self.nonce.take() {
+            return Ok(nonce);
+        }
+        let url = self.url("newNonce")?;
+        let response = client::request(&client::Url::parse(&url)?, "HEAD", &[], &[]).await.map_err(|e| format!("ACME request to {} failed: {}", url, e))?;
+        response.header("replay-nonce").map(str::to_string).ok_or_else(|| format!("The ACME server gave no nonce at {}", url))
+    }
+
+    /// POST a signed payload, or POST-as-GET without one; a rejected nonce is replaced once
+    async fn post(&mut self, url: &str, payload: Option<&Value>) -> Result<client::Response, String> {
+        let mut retried = false;
+        loop {
+            let nonce = self.nonce().await?;
+            let body = self.issuer.jws(url, &nonce, self.account.as_deref(), payload)?;
+            let response = client::request(&client::Url::parse(url)?, "POST", &[("Content-Type", "application/jose+json")], body.as_bytes()).await
+                .map_err(|e| format!("ACME request to {} failed: {}", url, e))?;
+            self.nonce = response.header("replay-nonce").map(str::to_string);
+            if response.status < 400 {
+                return Ok(response);
+            }
+            let error = json_body(&response).unwrap_or_default();
+            if error["type"] == "urn:ietf:params:acme:error:badNonce" && !retried {
+                retried = true;
+                continue;
+            }
+            return Err(format!("The ACME server answered {} to {}: {}", response.status, url, problem(&error)));
+        }
+    }
+
+    /// Look at a resource until it is no longer pending or processing
+    async fn poll(&mut self, url: &str) -> Result<Value, String> {
+        for _ in 0..POLL_ATTEMPTS {
+            let resource = json_body(&self.post(url, None).await?)?;
+            if !matches!(resource["status"].as_str(), Some("pending" | "processing")) {
+                return Ok(resource);
+            }
+            tokio::time::sleep(POLL_INTERVAL).await;
+        }
+        Err(format!("{} is still pending after {} seconds", url, (POLL_INTERVAL * POLL_ATTEMPTS).as_secs()))
+    }
+}
+
+/// Why the order's certificate needs issuing, or None while the one in its files is for its names
+/// and good for more than `renew`
+pub fn due(order: &Order, renew: Duration) -> Option<String> {
+    let loaded = match certs::read(&order.cert, &order.key) {
+        Ok(loaded) => loaded,
+        Err(e) => return Some(e),
+    };
+    let mut names: Vec<String> = loaded.names().iter().map(|name| name.to_ascii_lowercase()).collect();
+    let mut wanted = order.names.clone();
+    names.sort();
+    wanted.sort();
+    if names != wanted {
+        return Some(format!("the certificate is for {}", loaded.names().join(", ")));
+    }
+    let days = x509::days_remaining(loaded.expires);
+    (days * 86400.0 <= renew.as_secs_f64()).then(|| format!("the certificate expires in {:.0} days", days))
+}
+
+/// Renew the orders' certificates as they come due
+pub async fn renew(issuer: Arc<Issuer>, orders: Vec<Order>, bus: Arc<EventBus>) {
+    let mut interval = tokio::time::interval(RENEW_CHECK_INTERVAL);
+    interval.tick().await;
+
+    loop {
+        interval.tick().await;
+        for order in &orders {
+            if let Err(e) = issuer.keep(order, &bus).await {
+                logging::error(e);
+            }
+        }
+    }
+}
+
+/// The PKCS#8 document of an account key file, or None when there is no file
+pub fn read_account_key(path: &Path) -> Result<Option<Vec<u8>>, String> {
+    let pem = match std::fs::read(path) {
+        Ok(pem) => pem,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(format!("Failed to read ACME account key {}: {}", path.display(), e)),
+    };
+    match PrivateKeyDer::from_pem_slice(&pem) {
+        Ok(PrivateKeyDer::Pkcs8(key)) => {
+            let pkcs8 = key.secret_pkcs8_der().to_vec();
+            key_pair(&ECDSA_P256_SHA256_FIXED_SIGNING, &pkcs8).map_err(|e| format!("ACME account key {}: {}", path.display(), e))?;
+            Ok(Some(pkcs8))
+        }
+        _ => Err(format!("ACME account key {} is not a PKCS#8 private key (BEGIN PRIVATE KEY)", path.display())),
+    }
+}
+
+/// The order's files, replaced by the certificate issued and its key once they are checked to go
+/// together
+fn save(order: &Order, chain: &[u8], pkcs8: &[u8]) -> Result<SystemTime, String> {
+    let mut chain = chain.to_vec();
+    if !chain.ends_with(b"\n") {
+        chain.push(b'\n');
+    }
+    let key = pem("PRIVATE KEY", pkcs8);
+    let (cert, key_file) = (temporary(&order.cert), temporary(&order.key));
+    let combined = order.key == order.cert;
+    if combined {
+        write_file(&cert, &[chain, key.into_bytes()].concat(), true)?;
+    } else {
+        write_file(&key_file, key.as_bytes(), true)?;
+        write_file(&cert, &chain, false)?;
+    }
+    let checked = certs::read(&cert, if combined { &cert } else { &key_file });
+    let renamed = checked.and_then(|loaded| {
+        let rename = |from: &Path, to: &Path| std::fs::rename(from, to).map_err(|e| format!("Failed to write {}: {}", to.display(), e));
+        if !combined {
+            rename(&key_file, &order.key)?;
+        }
+        rename(&cert, &order.cert)?;
+        Ok(loaded.expires)
+    });
+    if renamed.is_err() {
+        let _ = std::fs::remove_file(&cert);
+        let _ = std::fs::remove_file(&key_file);
+    }
+    renamed
+}
+
+/// Where a file is written before it replaces `path`
+fn temporary(path: &Path) -> PathBuf {
+    let mut name = path.file_name().unwrap_or_default().to_os_string();
+    name.push(".acme");
+    path.with_file_name(name)
+}
+
+/// Write a new file, replacing any left over; private files can only be read by the owner
+fn write_file(path: &Path, contents: &[u8], private: bool) -> Result<(), String> {
+    let _ = std::fs::remove_file(path);
+    let mut options = std::fs::OpenOptions::new();
+    options.write(true).create(true).truncate(true);
+    #[cfg(unix)]
+    if private {
+        std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+    }
+    options.open(path).and_then(|mut file| file.write_all(contents)).map_err(|e| format!("Failed to write {}: {}", path.display(), e))
+}
+
+fn json_body(response: &client::Response) -> Result<Value, String> {
+    response.body.as_deref().and_then(|body| serde_json::from_slice(body).ok()).ok_or_else(|| "The ACME server sent an invalid JSON document".to_string())
+}
+
+/// The `detail` of an ACME problem document (RFC 7807), with those of its subproblems
+fn problem(error: &Value) -> String {
+    let mut detail = error["detail"].as_str().unwrap_or("no detail given").to_string();
+    for subproblem in error["subproblems"].as_array().into_iter().flatten() {
+        detail.push_str(&format!("; {}: {}", subproblem["identifier"]["value"].as_str().unwrap_or_default(), subproblem["detail"].as_str().unwrap_or_default()));
+    }
+    detail
+}
+
+fn dns_challenge(authorization: &Value) -> Option<&Value> {
+    authorization["challenges"].as_array()?.iter().find(|challenge| challenge["type"] == "dns-01")
+}
+
+/// A PKCS#10 certification request for the names with the key, the first name as its common
+/// name when it fits
+fn csr(names: &[String], pkcs8: &[u8]) -> Result<Vec<u8>, String> {
+    let key = key_pair(&ECDSA_P256_SHA256_ASN1_SIGNING, pkcs8).map_err(|e| format!("Unusable certificate key: {}", e))?;
+    let subject = match names.first().filter(|name| name.len() <= 64) {
+        Some(name) => der(SEQUENCE, &der(SET, &der(SEQUENCE, &[der(OID, COMMON_NAME), der(UTF8_STRING, name.as_bytes())].concat()))),
+        None => der(SEQUENCE, &[]),
+    };
+    let algorithm = der(SEQUENCE, &[der(OID, EC_PUBLIC_KEY), der(OID, P256)].concat());
+    let public_key = der(SEQUENCE, &[algorithm, der(BIT_STRING, &[&[0], key.public_key().as_ref()].concat())].concat());
+    let alt_names: Vec<u8> = names.iter().flat_map(|name| der(DNS_NAME, name.as_bytes())).collect();
+    let extension = der(SEQUENCE, &[der(OID, SUBJECT_ALT_NAME), der(OCTET_STRING, &der(SEQUENCE, &alt_names))].concat());
+    let attribute = der(SEQUENCE, &[der(OID, EXTENSION_REQUEST), der(SET, &der(SEQUENCE, &extension))].concat());
+    let info = der(SEQUENCE, &[der(INTEGER, &[0]), subject, public_key, der(ATTRIBUTES, &attribute)].concat());
+    let signature = key.sign(&SystemRandom::new(), &info).map_err(|_| "Failed to sign the certificate request".to_string())?;
+    let signature = der(BIT_STRING, &[&[0], signature.as_ref()].concat());
+    Ok(der(SEQUENCE, &[info, der(SEQUENCE, &der(OID, ECDSA_WITH_SHA256)), signature].concat()))
+}
+
+/// A DER element
+fn der(tag: u8, content: &[u8]) -> Vec<u8> {
+    let mut der = vec![tag];
+    match content.len() {
+        len if len < 0x80 => der.push(len as u8),
+        len if len < 0x100 => der.extend_from_slice(&[0x81, len as u8]),
+        len => der.extend_from_slice(&[0x82, (len >> 8) as u8, len as u8]),
+    }
+    der.extend_from_slice(content);
+    der
+}
+
+fn pem(label: &str, der: &[u8]) -> String {
+    let base64 = STANDARD.encode(der);
+    let lines: Vec<&str> = base64.as_bytes().chunks(64).map(|line| std::str::from_utf8(line).unwrap_or_default()).collect();
+    format!("-----BEGIN {0}-----\n{1}\n-----END {0}-----\n", label, lines.join("\n"))
+}
+
+/// A new P-256 key, as a PKCS#8 document
+fn generate_key() -> Result<Vec<u8>, String> {
+    EcdsaKeyPair::generate_pkcs8(&ECDSA_P256_SHA256_FIXED_SIGNING, &SystemRandom::new())
+        .map(|document| document.as_ref().to_vec())
+        .map_err(|_| "Failed to generate a P-256 key".to_string())
+}
+
+fn key_pair(algorithm: &'static EcdsaSigningAlgorithm, pkcs8: &[u8]) -> Result<EcdsaKeyPair, String> {
+    #[cfg(feature = "aws-lc-rs")]
+    let key = EcdsaKeyPair::from_pkcs8(algorithm, pkcs8);
+    #[cfg(not(feature = "aws-lc-rs"))]
+    let key = EcdsaKeyPair::from_pkcs8(algorithm, pkcs8, &SystemRandom::new());
+    key.map_err(|e| format!("not a P-256 key ({})", e))
+}
+
+pub fn sha256(data: &[u8]) -> Vec<u8> {
+    crypto::digest::digest(&crypto::digest::SHA256, data).as_ref().to_vec()
+}
+
+pub fn hmac_sha256(key: &[u8], data: &[u8]) -> Vec<u8> {
+    crypto::hmac::sign(&crypto::hmac::Key::new(crypto::hmac::HMAC_SHA256, key), data).as_ref().to_vec()
+}
+
+/// Whether `tag` is the HMAC-SHA256 of the data, compared in constant time
+pub fn hmac_sha256_verify(key: &[u8], data: &[u8], tag: &[u8]) -> bool {
+    crypto::hmac::verify(&crypto::hmac::Key::new(crypto::hmac::HMAC_SHA256, key), data, tag).is_ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crypto::signature::{UnparsedPublicKey, ECDSA_P256_SHA256_ASN1, ECDSA_P256_SHA256_FIXED};
+    use std::collections::HashMap;
+    use std::sync::Mutex;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::TcpListener;
+
+    fn temp_dir(test: &str) -> PathBuf {
+        crate::client::install_tls_provider(crate::client::tls_provider(None).unwrap());
+        let dir = std::env::temp_dir().join(format!("reverse-http-proxy-acme-{}-{}", test, std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    fn issuer(dir: &Path, directory: &str, dns: Provider) -> Issuer {
+        Issuer::new(directory, &dir.join("account.pem"), Some("admin@example.com".into()), dns, Duration::ZERO, Duration::from_secs(30 * 86400)).unwrap()
+    }
+
+    /// The content of the DER element at the start of `der`, and what follows it
+    fn split(der: &[u8]) -> (&[u8], &[u8]) {
+        let (len, start) = match der[1] {
+            0x81 => (der[2] as usize, 3),
+            0x82 => ((der[2] as usize) << 8 | der[3] as usize, 4),
+            len => (len as usize, 2),
+        };
+        (&der[start..start + len], &der[start + len..])
+    }
+
+    /// The whole DER element at the start of `der`
+    fn whole(der: &[u8]) -> &[u8] {
+        let (_, rest) = split(der);
+        &der[..der.len() - rest.len()]
+    }
+
+    fn verify(algorithm: &'static crypto::signature::EcdsaVerificationAlgorithm, point: &[u8], message: &[u8], signature: &[u8]) -> bool {
+        UnparsedPublicKey::new(algorithm, point).verify(message, signature).is_ok()
+    }
+
+    #[test]
+    fn account_keys_are_created_once() {
+        let dir = temp_dir("account");
+        let first = issuer(&dir, LETS_ENCRYPT, crate::acmedns::test_nameserver("example.com").0);
+        #[cfg(unix)]
+        assert_eq!(std::os::unix::fs::PermissionsExt::mode(&std::fs::metadata(dir.join("account.pem")).unwrap().permissions()) & 0o777, 0o600);
+        let second = issuer(&dir, LETS_ENCRYPT, crate::acmedns::test_nameserver("example.com").0);
+        assert_eq!(first.thumbprint, second.thumbprint);
+        assert_eq!(first.jwk["crv"], "P-256");
+
+        std::fs::write(dir.join("other.pem"), std::fs::read(Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/certs/localhost.pem")).unwrap()).unwrap();
+        assert!(read_account_key(&dir.join("other.pem")).is_err());
+        assert_eq!(read_account_key(&dir.join("missing.pem")), Ok(None));
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn requests_are_signed_with_the_account_key() {
+        let dir = temp_dir("jws");
+        let issuer = issuer(&dir, LETS_ENCRYPT, crate::acmedns::test_nameserver("example.com").0);
+        let point = issuer.key.public_key().as_ref().to_vec();
+        for (account, payload) in [(None, Some(json!({"termsOfServiceAgreed": true}))), (Some("https://ca/acct/1"), None)] {
+            let jws: Value = serde_json::from_str(&issuer.jws("https://ca/new", "nonce-1", account, payload.as_ref()).unwrap()).unwrap();
+            let (protected, body) = (jws["protected"].as_str().unwrap(), jws["payload"].as_str().unwrap());
+            let signature = URL_SAFE_NO_PAD.decode(jws["signature"].as_str().unwrap()).unwrap();
+            assert!(verify(&ECDSA_P256_SHA256_FIXED, &point, format!("{}.{}", protected, body).as_bytes(), &signature));
+            let header: Value = serde_json::from_slice(&URL_SAFE_NO_PAD.decode(protected).unwrap()).unwrap();
+            assert_eq!((&header["alg"], &header["nonce"], &header["url"]), (&json!("ES256"), &json!("nonce-1"), &json!("https://ca/new")));
+            match account {
+                Some(account) => assert_eq!((&header["kid"], &header["jwk"], body), (&json!(account), &Value::Null, "")),
+                None => assert_eq!((&header["kid"], &header["jwk"]), (&Value::Null, &issuer.jwk)),
+            }
+        }
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn certificate_requests_name_the_key_and_names() {
+        let names = ["*.example.com".to_string(), "example.com".to_string()];
+        let pkcs8 = generate_key().unwrap();
+        let csr = csr(&names, &pkcs8).unwrap();
+        let (request, rest) = split(&csr);
+        assert!(rest.is_empty());
+        let info = whole(request);
+        let (algorithm, signature) = split(&request[info.len()..]);
+        assert_eq!(split(algorithm).0, ECDSA_WITH_SHA256);
+        let point = key_pair(&ECDSA_P256_SHA256_ASN1_SIGNING, &pkcs8).unwrap().public_key().as_ref().to_vec();
+        assert!(verify(&ECDSA_P256_SHA256_ASN1, &point, info, &split(signature).0[1..]));
+
+        let (version, rest) = split(split(info).0);
+        assert_eq!(version, [0]);
+        let (subject, rest) = split(rest);
+        assert!(subject.windows(13).any(|window| window == b"*.example.com"));
+        let (public_key, rest) = split(rest);
+        assert!(public_key.ends_with(&point));
+        let alt_names: Vec<u8> = names.iter().flat_map(|name| der(DNS_NAME, name.as_bytes())).collect();
+        assert!(rest.starts_with(&[ATTRIBUTES]) && rest.ends_with(&alt_names));
+    }
+
+    /// What the mock CA knows of the order, and the nameserver's updates it looks up records in
+    struct Ca {
+        nonces: u32,
+        bad_nonce_sent: bool,
+        account_point: Vec<u8>,
+        thumbprint: String,
+        valid: HashMap<String, bool>,
+        certificate: Option<Vec<u8>>,
+        requests: Vec<String>,
+        updates: std::sync::mpsc::Receiver<crate::acmedns::TestUpdate>,
+        txt: Vec<String>,
+    }
+
+    impl Ca {
+        /// The TXT values of `_acme-challenge.example.com` published by now
+        fn published(&mut self) -> &[String] {
+            for (zone, changes) in self.updates.try_iter().filter(|(zone, _)| zone == "example.com") {
+                for (record, value, added) in changes {
+                    assert_eq!((zone.as_str(), record.as_str()), ("example.com", "_acme-challenge.example.com"));
+                    if added {
+                        self.txt.push(value);
+                    } else {
+                        self.txt.retain(|published| *published != value);
+                    }
+                }
+            }
+            &self.txt
+        }
+    }
+
+    /// A certificate for the public key and names of a certification request, expiring in 2099
+    fn certificate(csr: &[u8]) -> Vec<u8> {
+        let (request, _) = split(csr);
+        let (_, rest) = split(split(request).0);
+        let subject = whole(rest);
+        let public_key = whole(&rest[subject.len()..]);
+        let alt_names = [der(DNS_NAME, b"*.example.com"), der(DNS_NAME, b"example.com")].concat();
+        let extension = der(SEQUENCE, &[der(OID, SUBJECT_ALT_NAME), der(OCTET_STRING, &der(SEQUENCE, &alt_names))].concat());
+        let algorithm = der(SEQUENCE, &der(OID, ECDSA_WITH_SHA256));
+        let validity = der(SEQUENCE, &[der(0x17, b"240101000000Z"), der(0x18, b"20991231235959Z")].concat());
+        let tbs = der(SEQUENCE, &[der(0xa0, &der(INTEGER, &[2])), der(INTEGER, &[1]), algorithm.clone(), subject.to_vec(), validity, subject.to_vec(), public_key.to_vec(), der(0xa3, &der(SEQUENCE, &extension))].concat());
+        let issuer = key_pair(&ECDSA_P256_SHA256_ASN1_SIGNING, &generate_key().unwrap()).unwrap();
+        let signature = issuer.sign(&SystemRandom::new(), &tbs).unwrap();
+        der(SEQUENCE, &[tbs, algorithm, der(BIT_STRING, &[&[0], signature.as_ref()].concat())].concat())
+    }
+
+    /// Answer one request to the mock CA
+    fn answer(ca: &Mutex<Ca>, base: &str, method: &str, path: &str, body: &[u8]) -> (u16, Vec<(&'static str, String)>, Vec<u8>) {
+        let mut ca = ca.lock().unwrap();
+        ca.nonces += 1;
+        let mut headers = vec![("Replay-Nonce", format!("nonce-{}", ca.nonces))];
+        ca.requests.push(format!("{} {}", method, path));
+        let json = |value: Value| value.to_string().into_bytes();
+        match (method, path) {
+            ("GET", "/directory") => {
+                let directory = json!({"newNonce": format!("{}/new-nonce", base), "newAccount": format!("{}/new-account", base), "newOrder": format!("{}/new-order", base)});
+                return (200, headers, json(directory));
+            }
+            ("HEAD", "/new-nonce") => return (200, headers, Vec::new()),
+            ("POST", _) => {}
+            _ => return (404, headers, Vec::new()),
+        }
+
+        let jws: Value = serde_json::from_slice(body).unwrap();
+        let (protected, payload) = (jws["protected"].as_str().unwrap(), jws["payload"].as_str().unwrap());
+        let header: Value = serde_json::from_slice(&URL_SAFE_NO_PAD.decode(protected).unwrap()).unwrap();
+        assert_eq!(header["url"], format!("{}{}", base, path));
+        if path == "/new-account" {
+            let jwk = &header["jwk"];
+            let coordinate = |name: &str| URL_SAFE_NO_PAD.decode(jwk[name].as_str().unwrap()).unwrap();
+            ca.account_point = [vec![4], coordinate("x"), coordinate("y")].concat();
+            ca.thumbprint = URL_SAFE_NO_PAD.encode(sha256(format!(r#"{{"crv":"P-256","kty":"EC","x":"{}","y":"{}"}}"#, jwk["x"].as_str().unwrap(), jwk["y"].as_str().unwrap()).as_bytes()));
+        } else {
+            assert_eq!(header["kid"], format!("{}/account/1", base));
+        }
+        let signature = URL_SAFE_NO_PAD.decode(jws["signature"].as_str().unwrap()).unwrap();
+        assert!(verify(&ECDSA_P256_SHA256_FIXED, &ca.account_point, format!("{}.{}", protected, payload).as_bytes(), &signature));
+        let payload: Value = if payload.is_empty() { Value::Null } else { serde_json::from_slice(&URL_SAFE_NO_PAD.decode(payload).unwrap()).unwrap() };
+
+        let authorization = |ca: &Ca, name: &str| {
+            let status = if ca.valid.get(name).copied().unwrap_or_default() { "valid" } else { "pending" };
+            json!({
+                "status": status,
+                "identifier": {"type": "dns", "value": "example.com"},
+                "wildcard": name == "wildcard",
+                "challenges": [
+                    {"type": "http-01", "url": format!("{}/challenge/http", base), "token": "http"},
+                    {"type": "dns-01", "url": format!("{}/challenge/{}", base, name), "token": format!("token-{}", name), "status": status},
+                ],
+            })
+        };
+        let order = |ca: &Ca| match &ca.certificate {
+            Some(_) => json!({"status": "valid", "certificate": format!("{}/certificate", base)}),
+            None => json!({
+                "status": "pending",
+                "authorizations": [format!("{}/authorization/wildcard", base), format!("{}/authorization/base", base)],
+                "finalize": format!("{}/finalize", base),
+            }),
+        };
+        match path {
+            "/new-account" => {
+                assert_eq!(payload, json!({"termsOfServiceAgreed": true, "contact": ["mailto:admin@example.com"]}));
+                headers.push(("Location", format!("{}/account/1", base)));
+                (201, headers, json(json!({"status": "valid"})))
+            }
+            // The first order is sent with a nonce that is refused
+            "/new-order" if !ca.bad_nonce_sent => {
+                ca.bad_nonce_sent = true;
+                (400, headers, json(json!({"type": "urn:ietf:params:acme:error:badNonce", "detail": "stale nonce"})))
+            }
+            "/new-order" => {
+                assert_eq!(payload["identifiers"], json!([{"type": "dns", "value": "*.example.com"}, {"type": "dns", "value": "example.com"}]));
+                headers.push(("Location", format!("{}/order/1", base)));
+                (201, headers, json(order(&ca)))
+            }
+            "/authorization/wildcard" | "/authorization/base" => (200, headers, json(authorization(&ca, &path[15..]))),
+            "/challenge/wildcard" | "/challenge/base" => {
+                let name = &path[11..];
+                let expected = URL_SAFE_NO_PAD.encode(sha256(format!("token-{}.{}", name, ca.thumbprint).as_bytes()));
+                let valid = ca.published().contains(&expected);
+                ca.valid.insert(name.to_string(), valid);
+                (200, headers, json(json!({"type": "dns-01", "status": if valid { "valid" } else { "invalid" }})))
+            }
+            "/finalize" => {
+                let csr = URL_SAFE_NO_PAD.decode(payload["csr"].as_str().unwrap()).unwrap();
+                ca.certificate = Some(certificate(&csr));
+                (200, headers, json(json!({"status": "processing"})))
+            }
+            "/order/1" => (200, headers, json(order(&ca))),
+            "/certificate" => {
+                let certificate = ca.certificate.clone().unwrap();
+                headers.push(("Content-Type", "application/pem-certificate-chain".to_string()));
+                (200, headers, pem("CERTIFICATE", &certificate).into_bytes())
+            }
+            _ => (404, headers, Vec::new()),
+        }
+    }
+
+    /// A mock CA issuing `*.example.com` and `example.com` once the records of both challenges
+    /// are published through the test nameserver
+    #[test]
+    fn issues_certificates_with_dns_challenges() {
+        let dir = temp_dir("issue");
+        let runtime = tokio::runtime::Builder::new_current_thread().enable_all().build().unwrap();
+        runtime.block_on(async {
+            let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+            let base = format!("http://{}", listener.local_addr().unwrap());
+            let (dns, updates) = crate::acmedns::test_nameserver("example.com");
+            let ca = Ca { nonces: 0, bad_nonce_sent: false, account_point: Vec::new(), thumbprint: String::new(), valid: HashMap::new(), certificate: None, requests: Vec::new(), updates, txt: Vec::new() };
+            let ca = Arc::new(Mutex::new(ca));
+            let server = {
+                let (ca, base) = (ca.clone(), base.clone());
+                tokio::spawn(async move {
+                    loop {
+                        let (mut stream, _) = listener.accept().await.unwrap();
+                        let mut request = Vec::new();
+                        let mut buf = [0u8; 8192];
+                        let (head, length) = loop {
+                            let n = stream.read(&mut buf).await.unwrap();
+                            request.extend_from_slice(&buf[..n]);
+                            if let Some(end) = request.windows(4).position(|window| window == b"\r\n\r\n") {
+                                let head = String::from_utf8(request[..end].to_vec()).unwrap();
+                                let length = head.lines().find_map(|line| line.strip_prefix("Content-Length: ")).map_or(0, |n| n.parse().unwrap());
+                                break (head, end + 4 + length);
+                            }
+                        };
+                        while request.len() < length {
+                            let n = stream.read(&mut buf).await.unwrap();
+                            request.extend_from_slice(&buf[..n]);
+                        }
+                        let mut line = head.split(' ');
+                        let (method, path) = (line.next().unwrap(), line.next().unwrap());
+                        let body = &request[head.len() + 4..];
+                        let (status, headers, body) = answer(&ca, &base, method, path, body);
+                        let mut response = format!("HTTP/1.1 {} ACME\r\nContent-Length: {}\r\n", status, body.len());
+                        for (name, value) in headers {
+                            response.push_str(&format!("{}: {}\r\n", name, value));
+                        }
+                        response.push_str("\r\n");
+                        stream.write_all(&[response.into_bytes(), if method == "HEAD" { Vec::new() } else { body }].concat()).await.unwrap();
+                    }
+                })
+            };
+
+            let issuer = issuer(&dir, &format!("{}/directory", base), dns);
+            let order = Order { listener: "web".into(), names: vec!["*.example.com".into(), "example.com".into()], cert: dir.join("cert.pem"), key: dir.join("key.pem") };
+            let bus = EventBus::default();
+            let mut events = bus.subscribe();
+            assert!(due(&order, issuer.renew).is_some());
+            issuer.keep(&order, &bus).await.unwrap();
+
+            let loaded = certs::read(&order.cert, &order.key).unwrap();
+            assert_eq!(loaded.names(), order.names);
+            assert!(due(&order, issuer.renew).is_none());
+            let event = events.try_recv().unwrap();
+            assert_eq!((event.kind, &event.data["listener"], &event.data["expires"]), ("certificate_issued", &json!("web"), &json!("2099-12-31T23:59:59.000Z")));
+            let requests = ca.lock().unwrap().requests.clone();
+            assert_eq!(requests.iter().filter(|request| *request == "POST /new-order").count(), 2);
+            assert!(requests.contains(&"POST /certificate".to_string()));
+            assert!(!requests.iter().any(|request| request.contains("/challenge/http")));
+
+            // Both challenge values were published, and are removed again
+            assert!(ca.lock().unwrap().valid.values().all(|valid| *valid));
+            assert!(ca.lock().unwrap().published().is_empty());
+
+            // A valid certificate is kept
+            issuer.keep(&order, &bus).await.unwrap();
+            assert_eq!(ca.lock().unwrap().requests.len(), requests.len());
+            server.abort();
+        });
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -0,0 +1,618 @@
+//! DNS providers for ACME DNS-01 challenges (`--acme-dns`): the TXT records of `_acme-challenge`
+//! names are added through the Cloudflare API, the Route53 API, or RFC 2136 dynamic updates signed
+//! with TSIG (RFC 8945), and removed once the challenges are done. Credentials come from the
+//! environment, so they stay out of the command line and config file.
+
+use crate::{acme, client, dns, response};
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine;
+use serde_json::{json, Value};
+use std::io;
+use std::net::{IpAddr, SocketAddr};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tokio::net::UdpSocket;
+
+/// TTL of the records added
+const TTL: u32 = 60;
+
+const CLOUDFLARE_API: &str = "https://api.cloudflare.com/client/v4";
+const ROUTE53_HOST: &str = "route53.amazonaws.com";
+/// Route53 is a global service, signed for this region
+const ROUTE53_REGION: &str = "us-east-1";
+
+/// Longest an RFC 2136 update may take
+const UPDATE_TIMEOUT: Duration = Duration::from_secs(5);
+const TYPE_SOA: u16 = 6;
+const TYPE_TXT: u16 = 16;
+const TYPE_TSIG: u16 = 250;
+const CLASS_IN: u16 = 1;
+/// The class of the records an update deletes one by one (RFC 2136 2.5.4)
+const CLASS_NONE: u16 = 254;
+const CLASS_ANY: u16 = 255;
+const OPCODE_UPDATE: u8 = 5;
+const RCODE_NOTAUTH: u8 = 9;
+const RCODE_NOTZONE: u8 = 10;
+/// The only TSIG algorithm supported, in wire format
+const HMAC_SHA256: &[u8] = b"\x0bhmac-sha256\x00";
+/// Seconds the signing time of an update may be off from the server's clock
+const TSIG_FUDGE: u16 = 300;
+
+pub enum Provider {
+    Cloudflare { token: String },
+    Route53(Aws),
+    Rfc2136 { server: SocketAddr, key: Tsig },
+}
+
+/// AWS credentials, as the AWS CLI takes them from the environment
+pub struct Aws {
+    access_key: String,
+    secret_key: String,
+    session_token: Option<String>,
+}
+
+/// A TSIG key: its name in wire format and its secret
+pub struct Tsig {
+    name: Vec<u8>,
+    secret: Vec<u8>,
+}
+
+/// TXT records added with `Provider::add`, to be removed with `Provider::remove`
+pub struct Records {
+    pub name: String,
+    values: Vec<String>,
+    /// The zone holding them: a Cloudflare zone id, a Route53 hosted zone id or a zone name
+    zone: String,
+    /// Cloudflare's ids of the records
+    ids: Vec<String>,
+}
+
+impl Provider {
+    /// `cloudflare`, `route53` or `rfc2136:SERVER[:PORT]`, with the credentials of their
+    /// environment variables
+    pub fn parse(spec: &str) -> Result<Self, String> {
+        let var = |name: &str| std::env::var(name).ok().filter(|value| !value.is_empty());
+        let required = |name: &str| var(name).ok_or_else(|| format!("--acme-dns {} requires the {} environment variable", spec, name));
+        match spec.split_once(':') {
+            None if spec == "cloudflare" => Ok(Provider::Cloudflare { token: required("CLOUDFLARE_API_TOKEN")? }),
+            None if spec == "route53" => Ok(Provider::Route53(Aws {
+                access_key: required("AWS_ACCESS_KEY_ID")?,
+                secret_key: required("AWS_SECRET_ACCESS_KEY")?,
+                session_token: var("AWS_SESSION_TOKEN"),
+            })),
+            Some(("rfc2136", server)) => {
+                let server = server.parse::<SocketAddr>().or_else(|_| server.parse::<IpAddr>().map(|ip| SocketAddr::new(ip, 53)))
+                    .map_err(|_| format!("Invalid --acme-dns server '{}': expected IP[:PORT]", server))?;
+                let mut name = Vec::new();
+                let key_name = required("RFC2136_TSIG_KEY")?;
+                dns::encode_name(&mut name, &key_name.to_ascii_lowercase()).map_err(|_| format!("Invalid RFC2136_TSIG_KEY '{}'", key_name))?;
+                let secret = STANDARD.decode(required("RFC2136_TSIG_SECRET")?).map_err(|_| "RFC2136_TSIG_SECRET is not base64".to_string())?;
+                Ok(Provider::Rfc2136 { server, key: Tsig { name, secret } })
+            }
+            _ => Err(format!("Invalid --acme-dns '{}'. Expected cloudflare, route53 or rfc2136:SERVER[:PORT]", spec)),
+        }
+    }
+
+    /// Add TXT records with these values under a name, in the zone of the name
+    pub async fn add(&self, name: &str, values: &[String]) -> Result<Records, String> {
+        let mut records = Records { name: name.to_string(), values: values.to_vec(), zone: String::new(), ids: Vec::new() };
+        match self {
+            Provider::Cloudflare { token } => {
+                records.zone = cloudflare_zone(token, name).await?;
+                for value in values {
+                    let record = json!({"type": "TXT", "name": name, "content": value, "ttl": TTL});
+                    match cloudflare(token, "POST", &format!("/zones/{}/dns_records", records.zone), Some(&record)).await {
+                        Ok(created) => records.ids.push(created["id"].as_str().unwrap_or_default().to_string()),
+                        Err(e) => {
+                            let _ = self.remove(&records).await;
+                            return Err(e);
+                        }
+                    }
+                }
+            }
+            Provider::Route53(aws) => {
+                records.zone = aws.zone(name).await?;
+                aws.change("UPSERT", &records).await?;
+            }
+            Provider::Rfc2136 { server, key } => {
+                // The zone is the closest enclosing one the server is authoritative for
+                for zone in zones(name) {
+                    records.zone = zone.to_string();
+                    if key.update(*server, &records, true).await? {
+                        return Ok(records);
+                    }
+                }
+                return Err(format!("Nameserver {} is authoritative for no zone of {}", server, name));
+            }
+        }
+        Ok(records)
+    }
+
+    /// Remove the records `add` added
+    pub async fn remove(&self, records: &Records) -> Result<(), String> {
+        match self {
+            Provider::Cloudflare { token } => {
+                for id in &records.ids {
+                    cloudflare(token, "DELETE", &format!("/zones/{}/dns_records/{}", records.zone, id), None).await?;
+                }
+                Ok(())
+            }
+            Provider::Route53(aws) => aws.change("DELETE", records).await,
+            Provider::Rfc2136 { server, key } => key.update(*server, records, false).await.map(drop),
+        }
+    }
+}
+
+/// The zones a name could be in, closest first; zones have at least two labels
+fn zones(name: &str) -> impl Iterator<Item = &str> {
+    let labels = name.split('.').count();
+    std::iter::successors(Some(name), |zone| zone.split_once('.').map(|(_, parent)| parent)).take(labels.saturating_sub(1))
+}
+
+/// A Cloudflare API call, returning the `result` of a successful one
+async fn cloudflare(token: &str, method: &str, path: &str, body: Option<&Value>) -> Result<Value, String> {
+    let url = client::Url::parse(&format!("{}{}", CLOUDFLARE_API, path))?;
+    let authorization = format!("Bearer {}", token);
+    let body = body.map(Value::to_string).unwrap_or_default();
+    let headers = [("Authorization", authorization.as_str()), ("Content-Type", "application/json")];
+    let response = client::request(&url, method, &headers, body.as_bytes()).await.map_err(|e| format!("Cloudflare API request failed: {}", e))?;
+    let answer: Value = response.body.as_deref().and_then(|body| serde_json::from_slice(body).ok()).unwrap_or_default();
+    if !(200..300).contains(&response.status) || answer["success"] != true {
+        let message = answer["errors"][0]["message"].as_str().unwrap_or("no error message");
+        return Err(format!("Cloudflare API answered {} to {} {}: {}", response.status, method, path, message));
+    }
+    Ok(answer["result"].clone())
+}
+
+/// The id of the Cloudflare zone a name is in
+async fn cloudflare_zone(token: &str, name: &str) -> Result<String, String> {
+    for zone in zones(name) {
+        let found = cloudflare(token, "GET", &format!("/zones?name={}", zone), None).await?;
+        if let Some(id) = found[0]["id"].as_str() {
+            return Ok(id.to_string());
+        }
+    }
+    Err(format!("No Cloudflare zone the API token can see holds {}", name))
+}
+
+impl Aws {
+    /// A signed Route53 API call, returning the XML of a successful one
+    async fn request(&self, method: &str, path: &str, query: &str, body: &str) -> Result<String, String> {
+        let amz_date = amz_date(SystemTime::now());
+        let mut headers = vec![("host", ROUTE53_HOST.to_string()), ("x-amz-date", amz_date.clone())];
+        if let Some(token) = &self.session_token {
+            headers.push(("x-amz-security-token", token.clone()));
+        }
+        let request = AwsRequest { method, path, query, headers: &headers, payload: body.as_bytes() };
+        let authorization = sigv4(&self.access_key, &self.secret_key, ROUTE53_REGION, "route53", &request, &amz_date);
+        let query = if query.is_empty() { String::new() } else { format!("?{}", query) };
+        let url = client::Url::parse(&format!("https://{}{}{}", ROUTE53_HOST, path, query))?;
+        // The client sends the Host header itself
+        let mut sent: Vec<(&str, &str)> = headers[1..].iter().map(|(name, value)| (*name, value.as_str())).collect();
+        sent.push(("Authorization", &authorization));
+        let response = client::request(&url, method, &sent, body.as_bytes()).await.map_err(|e| format!("Route53 API request failed: {}", e))?;
+        let answer = String::from_utf8_lossy(response.body.as_deref().unwrap_or_default()).into_owned();
+        if response.status != 200 {
+            let message = xml_text(&answer, "Message").unwrap_or("no error message");
+            return Err(format!("Route53 API answered {} to {} {}: {}", response.status, method, path, message));
+        }
+        Ok(answer)
+    }
+
+    /// The id of the hosted zone a name is in
+    async fn zone(&self, name: &str) -> Result<String, String> {
+        for zone in zones(name) {
+            // Zones are listed from the first name at or after the one asked for
+            let listed = self.request("GET", "/2013-04-01/hostedzonesbyname", &format!("dnsname={}&maxitems=1", zone), "").await?;
+            let found = xml_text(&listed, "Name").is_some_and(|found| found.trim_end_matches('.').eq_ignore_ascii_case(zone));
+            if let Some(id) = xml_text(&listed, "Id").filter(|_| found) {
+                return Ok(id.trim_start_matches("/hostedzone/").to_string());
+            }
+        }
+        Err(format!("No Route53 hosted zone holds {}", name))
+    }
+
+    /// Apply a change (`UPSERT` or `DELETE`) of the record set of the records
+    async fn change(&self, action: &str, records: &Records) -> Result<(), String> {
+        let path = format!("/2013-04-01/hostedzone/{}/rrset", records.zone);
+        self.request("POST", &path, "", &change_batch(action, records)).await.map(drop)
+    }
+}
+
+/// A ChangeResourceRecordSets request for the TXT record set of the records
+fn change_batch(action: &str, records: &Records) -> String {
+    let values: String = records.values.iter().map(|value| format!("<ResourceRecord><Value>\"{}\"</Value></ResourceRecord>", value)).collect();
+    format!(
+        "<?xml version=\"1.0\" encoding=\"UTF-8\"?>\
+         <ChangeResourceRecordSetsRequest xmlns=\"https://route53.amazonaws.com/doc/2013-04-01/\"><ChangeBatch><Changes><Change>\
+         <Action>{}</Action><ResourceRecordSet><Name>{}.</Name><Type>TXT</Type><TTL>{}</TTL><ResourceRecords>{}</ResourceRecords></ResourceRecordSet>\
+         </Change></Changes></ChangeBatch></ChangeResourceRecordSetsRequest>",
+        action, records.name, TTL, values
+    )
+}
+
+/// The text of the first element with this name
+fn xml_text<'a>(xml: &'a str, element: &str) -> Option<&'a str> {
+    let start = xml.find(&format!("<{}>", element))? + element.len() + 2;
+    let len = xml[start..].find(&format!("</{}>", element))?;
+    Some(&xml[start..start + len])
+}
+
+/// A request to sign with AWS Signature Version 4; `headers` are the ones signed, lowercase and sorted
+struct AwsRequest<'a> {
+    method: &'a str,
+    path: &'a str,
+    /// The canonical query string: sorted, and encoded
+    query: &'a str,
+    headers: &'a [(&'a str, String)],
+    payload: &'a [u8],
+}
+
+/// The `Authorization` header of a request signed at `amz_date`
+fn sigv4(access_key: &str, secret_key: &str, region: &str, service: &str, request: &AwsRequest, amz_date: &str) -> String {
+    let canonical_headers: String = request.headers.iter().map(|(name, value)| format!("{}:{}\n", name, value.trim())).collect();
+    let signed_headers = request.headers.iter().map(|(name, _)| *name).collect::<Vec<_>>().join(";");
+    let canonical_request = format!(
+        "{}\n{}\n{}\n{}\n{}\n{}",
+        request.method, request.path, request.query, canonical_headers, signed_headers, hex(&acme::sha256(request.payload))
+    );
+    let date = &amz_date[..8];
+    let scope = format!("{}/{}/{}/aws4_request", date, region, service);
+    let string_to_sign = format!("AWS4-HMAC-SHA256\n{}\n{}\n{}", amz_date, scope, hex(&acme::sha256(canonical_request.as_bytes())));
+    let signing_key = [date, region, service, "aws4_request"].iter()
+        .fold(format!("AWS4{}", secret_key).into_bytes(), |key, part| acme::hmac_sha256(&key, part.as_bytes()));
+    let signature = hex(&acme::hmac_sha256(&signing_key, string_to_sign.as_bytes()));
+    format!("AWS4-HMAC-SHA256 Credential={}/{}, SignedHeaders={}, Signature={}", access_key, scope, signed_headers, signature)
+}
+
+/// A time as `X-Amz-Date` gives it: `20150830T123600Z`
+fn amz_date(time: SystemTime) -> String {
+    let rfc3339 = response::rfc3339(time);
+    format!("{}Z", rfc3339[..19].replace(['-', ':'], ""))
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+impl Tsig {
+    /// Send an update adding or deleting the records; Ok(false) when the server is not
+    /// authoritative for the records' zone
+    async fn update(&self, server: SocketAddr, records: &Records, add: bool) -> Result<bool, String> {
+        let id = (crate::otel::random_id() & 0xffff) as u16;
+        let mut message = update_message(id, records, add).map_err(|e| format!("Cannot update {}: {}", records.name, e))?;
+        let time = SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |d| d.as_secs());
+        let mac = self.sign(&mut message, time);
+
+        let failed = |e: io::Error| format!("Update of {} on nameserver {} failed: {}", records.name, server, e);
+        let socket = UdpSocket::bind(if server.is_ipv4() { "0.0.0.0:0" } else { "[::]:0" }).await.map_err(failed)?;
+        socket.connect(server).await.map_err(failed)?;
+        socket.send(&message).await.map_err(failed)?;
+        let mut response = vec![0u8; 4096];
+        let response = tokio::time::timeout(UPDATE_TIMEOUT, async {
+            loop {
+                let n = socket.recv(&mut response).await?;
+                // Answers to other messages, or forged ones, are skipped
+                if n >= 12 && response[..2] == id.to_be_bytes() {
+                    return Ok::<_, io::Error>(response[..n].to_vec());
+                }
+            }
+        })
+        .await
+        .map_err(|_| format!("No answer from nameserver {} to the update of {}", server, records.name))?
+        .map_err(failed)?;
+
+        let signed = tsig_record(&response);
+        match response[3] & 0x0f {
+            0 => {}
+            // A refused key is reported in the TSIG record, of an unsigned answer
+            RCODE_NOTAUTH | RCODE_NOTZONE if signed.as_ref().map_or(true, |record| record.error == 0) => return Ok(false),
+            rcode => {
+                let error = signed.map_or(0, |record| record.error);
+                return Err(format!("Nameserver {} refused the update of {}: {}", server, records.name, rcode_name(rcode, error)));
+            }
+        }
+        let record = signed.ok_or_else(|| format!("Nameserver {} answered the update of {} unsigned", server, records.name))?;
+        if !self.verify(&response, &record, &mac) {
+            return Err(format!("Nameserver {} answered the update of {} with a signature that does not verify", server, records.name));
+        }
+        Ok(true)
+    }
+
+    /// What the MAC of a message without its TSIG record is computed over: `signed` is the
+    /// record's algorithm, time signed and fudge, and `trailer` its error and other data. A
+    /// response's covers the request's MAC too.
+    fn mac_data(&self, request_mac: Option<&[u8]>, message: &[u8], signed: &[u8], trailer: &[u8]) -> Vec<u8> {
+        let mut data = Vec::new();
+        if let Some(mac) = request_mac {
+            data.extend_from_slice(&(mac.len() as u16).to_be_bytes());
+            data.extend_from_slice(mac);
+        }
+        data.extend_from_slice(message);
+        data.extend_from_slice(&self.name);
+        data.extend_from_slice(&CLASS_ANY.to_be_bytes());
+        data.extend_from_slice(&0u32.to_be_bytes());
+        data.extend_from_slice(signed);
+        data.extend_from_slice(trailer);
+        data
+    }
+
+    /// Append a TSIG record signing the message at `time`, and return its MAC
+    fn sign(&self, message: &mut Vec<u8>, time: u64) -> Vec<u8> {
+        let mut signed = HMAC_SHA256.to_vec();
+        signed.extend_from_slice(&time.to_be_bytes()[2..]);
+        signed.extend_from_slice(&TSIG_FUDGE.to_be_bytes());
+        // No error, and no other data
+        let trailer = [0u8; 4];
+        let mac = acme::hmac_sha256(&self.secret, &self.mac_data(None, message, &signed, &trailer));
+
+        let mut data = signed;
+        data.extend_from_slice(&(mac.len() as u16).to_be_bytes());
+        data.extend_from_slice(&mac);
+        data.extend_from_slice(&message[..2]);
+        data.extend_from_slice(&trailer);
+        message.extend_from_slice(&self.name);
+        message.extend_from_slice(&TYPE_TSIG.to_be_bytes());
+        message.extend_from_slice(&CLASS_ANY.to_be_bytes());
+        message.extend_from_slice(&0u32.to_be_bytes());
+        message.extend_from_slice(&(data.len() as u16).to_be_bytes());
+        message.extend_from_slice(&data);
+        let additional = u16::from_be_bytes([message[10], message[11]]) + 1;
+        message[10..12].copy_from_slice(&additional.to_be_bytes());
+        mac
+    }
+
+    /// Whether the TSIG record of a response to a request signed with `request_mac` is ours
+    fn verify(&self, response: &[u8], record: &TsigRecord, request_mac: &[u8]) -> bool {
+        // The MAC covers the response as it was before the record was added
+        let mut message = response[..record.start].to_vec();
+        message[..2].copy_from_slice(&record.original_id.to_be_bytes());
+        let additional = u16::from_be_bytes([message[10], message[11]]) - 1;
+        message[10..12].copy_from_slice(&additional.to_be_bytes());
+        let data = self.mac_data(Some(request_mac), &message, &response[record.signed.clone()], &response[record.trailer.clone()]);
+        acme::hmac_sha256_verify(&self.secret, &data, &response[record.mac.clone()])
+    }
+}
+
+/// An update of the TXT records: adding them, or deleting each
+fn update_message(id: u16, records: &Records, add: bool) -> io::Result<Vec<u8>> {
+    let mut message = id.to_be_bytes().to_vec();
+    message.extend_from_slice(&[OPCODE_UPDATE << 3, 0]);
+    // One zone, no prerequisites, the updates and no additional records
+    message.extend_from_slice(&[0, 1, 0, 0]);
+    message.extend_from_slice(&(records.values.len() as u16).to_be_bytes());
+    message.extend_from_slice(&[0, 0]);
+    dns::encode_name(&mut message, &records.zone)?;
+    message.extend_from_slice(&TYPE_SOA.to_be_bytes());
+    message.extend_from_slice(&CLASS_IN.to_be_bytes());
+    for value in &records.values {
+        dns::encode_name(&mut message, &records.name)?;
+        message.extend_from_slice(&TYPE_TXT.to_be_bytes());
+        let (class, ttl) = if add { (CLASS_IN, TTL) } else { (CLASS_NONE, 0) };
+        message.extend_from_slice(&class.to_be_bytes());
+        message.extend_from_slice(&ttl.to_be_bytes());
+        // A single character-string; challenge values are 43 characters
+        message.extend_from_slice(&(value.len() as u16 + 1).to_be_bytes());
+        message.push(value.len() as u8);
+        message.extend_from_slice(value.as_bytes());
+    }
+    Ok(message)
+}
+
+/// Where the parts of the TSIG record ending a message are
+struct TsigRecord {
+    /// Where the record starts
+    start: usize,
+    /// Algorithm, time signed and fudge
+    signed: std::ops::Range<usize>,
+    mac: std::ops::Range<usize>,
+    original_id: u16,
+    /// Error and other data
+    trailer: std::ops::Range<usize>,
+    error: u16,
+}
+
+/// The TSIG record of a message, the last of its additional records
+fn tsig_record(message: &[u8]) -> Option<TsigRecord> {
+    let u16_at = |pos: usize| message.get(pos..pos + 2).map(|b| u16::from_be_bytes([b[0], b[1]]));
+    let additional = usize::from(u16_at(10)?);
+    let records = usize::from(u16_at(6)?) + usize::from(u16_at(8)?) + additional;
+    if additional == 0 {
+        return None;
+    }
+    let mut pos = 12;
+    for _ in 0..u16_at(4)? {
+        pos = dns::skip_name(message, pos)? + 4;
+    }
+    for _ in 0..records - 1 {
+        pos = dns::skip_name(message, pos)? + 8;
+        pos += 2 + usize::from(u16_at(pos)?);
+    }
+    let start = pos;
+    pos = dns::skip_name(message, pos)?;
+    if u16_at(pos)? != TYPE_TSIG {
+        return None;
+    }
+    let data = pos + 10;
+    let end = data + usize::from(u16_at(pos + 8)?);
+    let fudge = dns::skip_name(message, data)? + 6;
+    let mac = fudge + 4..fudge + 4 + usize::from(u16_at(fudge + 2)?);
+    let (original_id, error, other) = (u16_at(mac.end)?, u16_at(mac.end + 2)?, usize::from(u16_at(mac.end + 4)?));
+    let trailer = mac.end + 2..mac.end + 6 + other;
+    if trailer.end != end || end > message.len() {
+        return None;
+    }
+    Some(TsigRecord { start, signed: data..fudge + 2, mac, original_id, trailer, error })
+}
+
+fn rcode_name(rcode: u8, tsig_error: u16) -> String {
+    let name = match (rcode, tsig_error) {
+        (_, 16) => "BADSIG, the TSIG secret is wrong",
+        (_, 17) => "BADKEY, the TSIG key is unknown",
+        (_, 18) => "BADTIME, the clocks are too far apart",
+        (2, _) => "SERVFAIL",
+        (5, _) => "REFUSED, the key may not update the name",
+        (RCODE_NOTAUTH, _) => "NOTAUTH",
+        _ => return format!("error {}", rcode),
+    };
+    name.to_string()
+}
+
+/// The changes of an update a test nameserver took: its zone, and each record's name and value
+/// with whether it was added or deleted
+#[cfg(test)]
+pub type TestUpdate = (String, Vec<(String, String, bool)>);
+
+/// An RFC 2136 nameserver for tests, authoritative for `zone` only, and a provider updating it.
+/// Each update it gets is sent on the channel.
+#[cfg(test)]
+pub fn test_nameserver(zone: &'static str) -> (Provider, std::sync::mpsc::Receiver<TestUpdate>) {
+    let server = std::net::UdpSocket::bind("127.0.0.1:0").unwrap();
+    let address = server.local_addr().unwrap();
+    let (sender, updates) = std::sync::mpsc::channel();
+    std::thread::spawn(move || {
+        let key = test_key();
+        let mut buf = [0u8; 4096];
+        while let Ok((n, client)) = server.recv_from(&mut buf) {
+            let request = &buf[..n];
+            let u16_at = |pos: usize| u16::from_be_bytes([request[pos], request[pos + 1]]);
+            let name = dns::read_name(request, 12).unwrap();
+            let mut pos = dns::skip_name(request, 12).unwrap() + 4;
+            let mut changes = Vec::new();
+            for _ in 0..u16_at(8) {
+                let owner = dns::read_name(request, pos).unwrap();
+                pos = dns::skip_name(request, pos).unwrap();
+                let value = String::from_utf8_lossy(&request[pos + 11..pos + 10 + usize::from(u16_at(pos + 8))]).into_owned();
+                changes.push((owner, value, u16_at(pos + 2) == CLASS_IN));
+                pos += 10 + usize::from(u16_at(pos + 8));
+            }
+            let rcode = if name == zone { 0 } else { RCODE_NOTAUTH };
+            let _ = sender.send((name, changes));
+            let _ = server.send_to(&test_answer(&key, request, rcode), client);
+        }
+    });
+    (Provider::Rfc2136 { server: address, key: test_key() }, updates)
+}
+
+#[cfg(test)]
+fn test_key() -> Tsig {
+    let mut name = Vec::new();
+    dns::encode_name(&mut name, "acme-key").unwrap();
+    Tsig { name, secret: b"0123456789abcdef0123456789abcdef".to_vec() }
+}
+
+/// A nameserver's signed answer to a request, with a response code
+#[cfg(test)]
+fn test_answer(key: &Tsig, request: &[u8], rcode: u8) -> Vec<u8> {
+    let record = tsig_record(request).unwrap();
+    // The zone section is echoed; the update and additional sections are not
+    let mut answer = request[..dns::skip_name(request, 12).unwrap() + 4].to_vec();
+    answer[2] |= 0x80;
+    answer[3] = rcode;
+    answer[6..12].copy_from_slice(&[0; 6]);
+    let signed = &request[record.signed.clone()];
+    let mac = acme::hmac_sha256(&key.secret, &key.mac_data(Some(&request[record.mac.clone()]), &answer, signed, &[0; 4]));
+    // A record signed at the request's time, with the MAC of an answer put in
+    let time = u64::from_be_bytes([&[0, 0][..], &signed[HMAC_SHA256.len()..HMAC_SHA256.len() + 6]].concat().try_into().unwrap());
+    key.sign(&mut answer, time);
+    let signature = tsig_record(&answer).unwrap();
+    answer[signature.mac].copy_from_slice(&mac);
+    answer
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn records(zone: &str) -> Records {
+        let values = vec!["a".repeat(43), "b".repeat(43)];
+        Records { name: "_acme-challenge.example.com".into(), values, zone: zone.into(), ids: Vec::new() }
+    }
+
+    #[test]
+    fn zones_of_names() {
+        assert_eq!(zones("_acme-challenge.www.example.com").collect::<Vec<_>>(), ["_acme-challenge.www.example.com", "www.example.com", "example.com"]);
+        assert_eq!(zones("example.com").collect::<Vec<_>>(), ["example.com"]);
+    }
+
+    #[test]
+    fn parses_providers() {
+        assert!(matches!(Provider::parse("cloudflare"), Err(e) if e.contains("CLOUDFLARE_API_TOKEN")));
+        assert!(matches!(Provider::parse("rfc2136:not-an-ip"), Err(e) if e.contains("IP[:PORT]")));
+        assert!(Provider::parse("powerdns").is_err());
+    }
+
+    /// The example of the AWS Signature Version 4 documentation: IAM's ListUsers
+    #[test]
+    fn signs_aws_requests() {
+        let headers = [
+            ("content-type", "application/x-www-form-urlencoded; charset=utf-8".to_string()),
+            ("host", "iam.amazonaws.com".to_string()),
+            ("x-amz-date", "20150830T123600Z".to_string()),
+        ];
+        let request = AwsRequest { method: "GET", path: "/", query: "Action=ListUsers&Version=2010-05-08", headers: &headers, payload: b"" };
+        let authorization = sigv4("AKIDEXAMPLE", "wJalrXUtnFEMI/K7MDENG+bPxRfiCYEXAMPLEKEY", "us-east-1", "iam", &request, "20150830T123600Z");
+        assert_eq!(authorization, "AWS4-HMAC-SHA256 Credential=AKIDEXAMPLE/20150830/us-east-1/iam/aws4_request, \
+            SignedHeaders=content-type;host;x-amz-date, Signature=5d672d79c15b13162d9279b0855cfba6789a8edb4c82c400e06b5924a6f2b5d7");
+        assert_eq!(amz_date(UNIX_EPOCH + Duration::from_secs(1_440_938_160)), "20150830T123600Z");
+    }
+
+    #[test]
+    fn route53_change_batches() {
+        let mut records = records("Z1");
+        records.values.truncate(1);
+        let batch = change_batch("UPSERT", &records);
+        assert!(batch.contains("<Action>UPSERT</Action><ResourceRecordSet><Name>_acme-challenge.example.com.</Name><Type>TXT</Type><TTL>60</TTL>"), "{}", batch);
+        assert!(batch.contains(&format!("<ResourceRecords><ResourceRecord><Value>\"{}\"</Value></ResourceRecord></ResourceRecords>", "a".repeat(43))));
+        let listed = "<ListHostedZonesByNameResponse><HostedZones><HostedZone><Id>/hostedzone/Z1</Id><Name>example.com.</Name></HostedZone></HostedZones></ListHostedZonesByNameResponse>";
+        assert_eq!((xml_text(listed, "Id"), xml_text(listed, "Name"), xml_text(listed, "Marker")), (Some("/hostedzone/Z1"), Some("example.com."), None));
+    }
+
+    #[test]
+    fn encodes_updates() {
+        let message = update_message(0x1234, &records("example.com"), false).unwrap();
+        assert_eq!(&message[..12], [0x12, 0x34, 0x28, 0, 0, 1, 0, 0, 0, 2, 0, 0]);
+        assert_eq!(&message[12..29], b"\x07example\x03com\x00\x00\x06\x00\x01");
+        // Each deletion names a record, of class NONE with TTL 0
+        let deletion = [b"\x0f_acme-challenge\x07example\x03com\x00\x00\x10\x00\xfe\x00\x00\x00\x00\x00\x2c\x2b".to_vec(), b"a".repeat(43)].concat();
+        assert_eq!(&message[29..29 + deletion.len()], deletion);
+        assert_eq!(message.len(), 29 + 2 * deletion.len());
+    }
+
+    #[test]
+    fn signs_updates_and_verifies_answers() {
+        let key = test_key();
+        let mut request = update_message(0x1234, &records("example.com"), true).unwrap();
+        let unsigned = request.clone();
+        let mac = key.sign(&mut request, 1_700_000_000);
+        assert_eq!(&request[10..12], [0, 1]);
+        let record = tsig_record(&request).unwrap();
+        assert_eq!((record.start, record.original_id, record.error), (unsigned.len(), 0x1234, 0));
+        assert_eq!(&request[record.mac.clone()], mac);
+        assert_eq!(&request[record.signed], [HMAC_SHA256, &[0, 0, 0x65, 0x53, 0xf1, 0x00, 0x01, 0x2c]].concat());
+
+        let mut response = test_answer(&key, &request, 0);
+        let record = tsig_record(&response).unwrap();
+        assert!(key.verify(&response, &record, &mac));
+        assert!(!key.verify(&response, &record, &[0; 32]));
+        // ".example.com" as ".Example.com"
+        response[13] ^= 0x20;
+        assert!(!key.verify(&response, &record, &mac));
+    }
+
+    #[test]
+    fn updates_the_closest_zone_the_server_is_authoritative_for() {
+        let (provider, updates) = test_nameserver("example.com");
+        let runtime = tokio::runtime::Builder::new_current_thread().enable_all().build().unwrap();
+        let values = records("").values;
+        let added = runtime.block_on(provider.add("_acme-challenge.example.com", &values)).unwrap();
+        assert_eq!(added.zone, "example.com");
+        runtime.block_on(provider.remove(&added)).unwrap();
+
+        let updates: Vec<_> = updates.try_iter().collect();
+        let zones: Vec<_> = updates.iter().map(|(zone, _)| zone.as_str()).collect();
+        assert_eq!(zones, ["_acme-challenge.example.com", "example.com", "example.com"]);
+        let change = |value: &String, added| ("_acme-challenge.example.com".to_string(), value.clone(), added);
+        assert_eq!(updates[1].1, values.iter().map(|value| change(value, true)).collect::<Vec<_>>());
+        assert_eq!(updates[2].1, values.iter().map(|value| change(value, false)).collect::<Vec<_>>());
+    }
+}
@@ -0,0 +1,629 @@
+use crate::alerts::Alerter;
+use crate::certs::Served;
+use crate::events::EventBus;
+use crate::fault::{Fault, Faults};
+use crate::handover::Handover;
+use crate::health::HealthChecks;
+use crate::logging;
+use crate::metrics::Metrics;
+use crate::outlier::OutlierDetector;
+use crate::region::Regions;
+use crate::request::{RequestHead, MAX_HEADERS};
+use crate::response;
+use crate::routing::{RouteConfig, SharedConfig};
+use crate::slot::{Color, Cutovers, MAX_DRAIN_SECONDS};
+use crate::state::Snapshot;
+use crate::x509;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+
+/// Number of entries returned by `/stats/largest` when `n` is not given
+const DEFAULT_TOP_N: usize = 10;
+
+/// Prometheus text exposition format
+const PROMETHEUS_CONTENT_TYPE: &str = "text/plain; version=0.0.4; charset=utf-8";
+
+/// Largest request body accepted by the write endpoints
+const MAX_BODY: usize = 64 * 1024;
+
+/// The status page, which polls the JSON endpoints and follows the event stream
+const DASHBOARD: &str = include_str!("dashboard.html");
+
+/// Interval of SSE comment lines that keep idle event streams open through intermediaries
+const EVENT_STREAM_KEEPALIVE: Duration = Duration::from_secs(15);
+
+/// Shared state exposed through the admin listener
+pub struct AdminState {
+    pub config: SharedConfig,
+    pub metrics: Arc<Metrics>,
+    /// Backend health, as far as connection attempts tell
+    pub alerter: Arc<Alerter>,
+    pub bus: Arc<EventBus>,
+    pub state_file: Option<PathBuf>,
+    /// Routes are managed by a control plane, which would overwrite changes made here
+    pub control_plane: bool,
+    /// Latency probes of backends in zones
+    pub regions: Arc<Regions>,
+    /// Health check results, with `--health-check`
+    pub health: Option<Arc<HealthChecks>>,
+    /// Ejections for failing requests
+    pub outliers: Option<Arc<OutlierDetector>>,
+    /// Faults injected into routes' requests
+    pub faults: Arc<Faults>,
+    /// Connections through blue/green slots, closed after a switch's drain period
+    pub cutovers: Arc<Cutovers>,
+    /// The certificates `;cert` listeners serve
+    pub certificates: Vec<Arc<Served>>,
+}
+
+/// A response produced by an admin endpoint
+struct Response {
+    status: &'static str,
+    content_type: &'static str,
+    body: String,
+}
+
+impl Response {
+    fn json(value: serde_json::Value) -> Self {
+        Response {
+            status: "200 OK",
+            content_type: "application/json",
+            body: value.to_string(),
+        }
+    }
+
+    fn text(content_type: &'static str, body: String) -> Self {
+        Response { status: "200 OK", content_type, body }
+    }
+
+    fn created(value: serde_json::Value) -> Self {
+        Response { status: "201 Created", ..Response::json(value) }
+    }
+
+    fn error(status: &'static str, message: &str) -> Self {
+        Response {
+            status,
+            content_type: "application/json",
+            body: serde_json::json!({ "error": message }).to_string(),
+        }
+    }
+}
+
+/// Serve the admin API on its listener, until it is handed over to a new process
+pub async fn run(listener: TcpListener, state: Arc<AdminState>, handover: Arc<Handover>) -> std::io::Result<()> {
+    println!("Admin API listening on http://{}", listener.local_addr()?);
+
+    loop {
+        let (stream, client_addr) = tokio::select! {
+            accepted = listener.accept() => accepted?,
+            _ = handover.handed_over() => return Ok(()),
+        };
+        let state = state.clone();
+
+        tokio::spawn(async move {
+            if let Err(e) = handle_connection(stream, &state).await {
+                logging::error(format!("Admin request from {} failed: {}", client_addr, e));
+            }
+        });
+    }
+}
+
+async fn handle_connection(mut stream: TcpStream, state: &AdminState) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    let mut buffer = vec![0u8; 8192];
+    let mut total_read = 0;
+
+    let header_end = loop {
+        let n = stream.read(&mut buffer[total_read..]).await?;
+        if n == 0 {
+            return Err("Connection closed before receiving complete headers".into());
+        }
+        total_read += n;
+
+        if let Some(pos) = crate::find_header_end(&buffer[..total_read]) {
+            break pos;
+        }
+        if total_read == buffer.len() {
+            return Err("Admin request headers too large".into());
+        }
+    };
+
+    let mut headers = [httparse::EMPTY_HEADER; MAX_HEADERS];
+    let response = match RequestHead::parse(&buffer[..header_end], &mut headers) {
+        Ok(head) => {
+            if (head.method, head.path) == ("GET", "/events") {
+                return stream_events(stream, state).await;
+            }
+            if let Some(refused) = refuse_cross_site(&head) {
+                return respond(stream, refused).await;
+            }
+            let content_length = head.header_str("content-length").and_then(|v| v.trim().parse().ok()).unwrap_or(0);
+            if content_length > MAX_BODY {
+                Response::error("413 Payload Too Large", "request body too large")
+            } else {
+                let mut body = buffer[header_end..total_read].to_vec();
+                while body.len() < content_length {
+                    let mut chunk = [0u8; 8192];
+                    let n = stream.read(&mut chunk).await?;
+                    if n == 0 {
+                        return Err("Connection closed before receiving the complete body".into());
+                    }
+                    body.extend_from_slice(&chunk[..n]);
+                }
+                body.truncate(content_length);
+                // Route changes compile a route table, which resolves backend host names and blocks
+                tokio::task::block_in_place(|| dispatch(head.method, head.path, head.query.unwrap_or(""), &body, state))
+            }
+        }
+        Err(_) => Response::error("400 Bad Request", "malformed request"),
+    };
+
+    respond(stream, response).await
+}
+
+async fn respond(mut stream: TcpStream, response: Response) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    let head = format!(
+        "HTTP/1.1 {}\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+        response.status, response.content_type, response.body.len()
+    );
+    stream.write_all(head.as_bytes()).await?;
+    stream.write_all(response.body.as_bytes()).await?;
+    Ok(())
+}
+
+/// Refuse requests that change state unless they could not have come from a page of another
+/// site: browsers send such requests cross-origin only as forms, which cannot be JSON, and mark
+/// them with their page's `Origin`
+fn refuse_cross_site(head: &RequestHead) -> Option<Response> {
+    if matches!(head.method, "GET" | "HEAD") {
+        return None;
+    }
+    if let Some(origin) = head.header_str("origin") {
+        let origin_host = origin.trim().split_once("://").map(|(_, host)| host);
+        let same = origin_host.zip(head.header_str("host")).is_some_and(|(origin, host)| origin.eq_ignore_ascii_case(host.trim()));
+        if !same {
+            return Some(Response::error("403 Forbidden", "cross-origin request refused"));
+        }
+    }
+    let media_type = head.header_str("content-type").and_then(|value| value.split(';').next()).map(str::trim);
+    if !media_type.is_some_and(|media_type| media_type.eq_ignore_ascii_case("application/json")) {
+        return Some(Response::error("415 Unsupported Media Type", "requests changing state need Content-Type: application/json"));
+    }
+    None
+}
+
+/// Stream bus events as Server-Sent Events until the subscriber disconnects
+async fn stream_events(mut stream: TcpStream, state: &AdminState) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    let mut events = state.bus.subscribe();
+    stream.write_all(b"HTTP/1.1 200 OK\r\nContent-Type: text/event-stream\r\nCache-Control: no-cache\r\nConnection: close\r\n\r\n").await?;
+    stream.write_all(b": connected\n\n").await?;
+
+    let mut keepalive = tokio::time::interval(EVENT_STREAM_KEEPALIVE);
+    keepalive.tick().await;
+
+    loop {
+        let chunk = tokio::select! {
+            event = events.recv() => match event {
+                Ok(event) => format!("event: {}\ndata: {}\n\n", event.kind, event.data),
+                Err(tokio::sync::broadcast::error::RecvError::Lagged(missed)) => format!(": missed {} events\n\n", missed),
+                Err(tokio::sync::broadcast::error::RecvError::Closed) => return Ok(()),
+            },
+            _ = keepalive.tick() => ": keepalive\n\n".to_string(),
+        };
+
+        if stream.write_all(chunk.as_bytes()).await.is_err() {
+            // Subscriber went away
+            return Ok(());
+        }
+    }
+}
+
+fn dispatch(method: &str, path: &str, query: &str, body: &[u8], state: &AdminState) -> Response {
+    match (method, path) {
+        ("GET", "/" | "/dashboard") => Response::text("text/html; charset=utf-8", DASHBOARD.to_string()),
+        ("GET", "/routes") => Response::json(routes_json(&state.config.load().snapshot())),
+        ("POST", "/routes") => add_route(body, state),
+        ("DELETE", "/routes") => remove_route(body, state),
+        ("GET", "/backends") => backends_json(state),
+        ("POST", "/backends/drain") => set_drained(body, state, true),
+        ("POST", "/backends/resume") => set_drained(body, state, false),
+        ("POST", "/backends/weight") => set_weight(body, state),
+        ("GET", "/faults") => Response::json(state.faults.to_json()),
+        ("POST", "/faults") => set_fault(body, state),
+        ("DELETE", "/faults") => clear_fault(body, state),
+        ("GET", "/slots") => slots_json(state),
+        ("GET", "/certificates") => certificates_json(state),
+        ("POST", "/slots/switch") => switch_slot(body, state),
+        ("GET", "/metrics") => Response::text(PROMETHEUS_CONTENT_TYPE, state.metrics.prometheus_text()),
+        ("GET", "/stats/sizes") => Response::json(state.metrics.sizes_json()),
+        ("GET", "/stats/latency") => Response::json(state.metrics.latency_json()),
+        ("GET", "/stats/largest") => {
+            let n = query_param(query, "n")
+                .and_then(|n| n.parse().ok())
+                .unwrap_or(DEFAULT_TOP_N);
+            Response::json(state.metrics.largest_json(n))
+        }
+        ("GET", "/snapshot") => Response::json(state.config.load().snapshot().to_json()),
+        ("POST", "/snapshot") => {
+            let Some(path) = &state.state_file else {
+                return Response::error("409 Conflict", "no --state-file configured");
+            };
+            let snapshot = state.config.load().snapshot();
+            match snapshot.save(path) {
+                Ok(()) => Response::json(snapshot.to_json()),
+                Err(e) => Response::error("500 Internal Server Error", &format!("failed to save state: {}", e)),
+            }
+        }
+        (_, "/" | "/dashboard" | "/metrics" | "/stats/sizes" | "/stats/latency" | "/stats/largest" | "/events" | "/snapshot" | "/routes" | "/backends" | "/backends/drain" | "/backends/resume" | "/backends/weight" | "/faults" | "/slots" | "/slots/switch" | "/certificates") => {
+            Response::error("405 Method Not Allowed", "method not allowed")
+        }
+        _ => Response::error("404 Not Found", "not found"),
+    }
+}
+
+/// The routes of a snapshot, in the notation of the command-line flags
+fn routes_json(snapshot: &Snapshot) -> serde_json::Value {
+    serde_json::json!({
+        "default_backend": snapshot.default_backend,
+        "routes": snapshot.routes,
+        "header_routes": snapshot.header_routes,
+        "query_routes": snapshot.query_routes,
+    })
+}
+
+/// The snapshot list holding routes of a kind (`type` in requests: "path", "header" or "query")
+fn route_list<'s>(snapshot: &'s mut Snapshot, kind: &str) -> Option<&'s mut Vec<String>> {
+    match kind {
+        "path" => Some(&mut snapshot.routes),
+        "header" => Some(&mut snapshot.header_routes),
+        "query" => Some(&mut snapshot.query_routes),
+        _ => None,
+    }
+}
+
+/// `POST /routes` with `{"route": "/api=127.0.0.1:4000", "type": "path"}`: add a route in `-r`,
+/// `--route-header` or `--route-query` notation
+fn add_route(body: &[u8], state: &AdminState) -> Response {
+    let (route, kind) = match parse_body(body, "route") {
+        Ok((route, request)) => (route, request["type"].as_str().unwrap_or("path").to_string()),
+        Err(response) => return response,
+    };
+    let change = serde_json::json!({ "change": "route_added", "route": route });
+    let updated = update(state, change, "400 Bad Request", |snapshot| {
+        let routes = route_list(snapshot, &kind)
+            .ok_or_else(|| Response::error("400 Bad Request", "\"type\" must be path, header or query"))?;
+        routes.push(route.clone());
+        Ok(())
+    });
+    match updated {
+        Ok(snapshot) => Response::created(routes_json(&snapshot)),
+        Err(response) => response,
+    }
+}
+
+/// `DELETE /routes` with `{"route": "/api"}`: remove the routes with this name (the route as written,
+/// without its backend and options)
+fn remove_route(body: &[u8], state: &AdminState) -> Response {
+    let name = match parse_body(body, "route") {
+        Ok((name, _)) => name,
+        Err(response) => return response,
+    };
+    let change = serde_json::json!({ "change": "route_removed", "route": name });
+    // Rules that name the route keep it from being removed
+    let updated = update(state, change, "409 Conflict", |snapshot| {
+        let mut removed = false;
+        for kind in ["path", "header", "query"] {
+            let routes = route_list(snapshot, kind).expect("known route kind");
+            let before = routes.len();
+            routes.retain(|route| crate::routing::route_name(route, kind).as_deref() != Some(name.as_str()));
+            removed |= routes.len() != before;
+        }
+        if !removed {
+            return Err(Response::error("404 Not Found", &format!("no route named '{}'", name)));
+        }
+        Ok(())
+    });
+    match updated {
+        Ok(snapshot) => Response::json(routes_json(&snapshot)),
+        Err(response) => response,
+    }
+}
+
+/// `GET /backends`: every backend with its routes, drain state, health and open connections
+fn backends_json(state: &AdminState) -> Response {
+    let config = state.config.load();
+    let backends: Vec<serde_json::Value> = config.backends().into_iter()
+        .map(|(backend, routes)| {
+            let (down, consecutive_failures) = state.alerter.backend_status(&backend.name);
+            let health = state.health.as_ref().and_then(|health| health.status(&backend.name));
+            let certificate = state.health.as_ref().and_then(|health| health.certificate(&backend.name));
+            let (ejected_for, ejections) = state.outliers.as_ref().and_then(|outliers| outliers.status(&backend.name)).unwrap_or((None, 0));
+            serde_json::json!({
+                "backend": &*backend.name,
+                "address": backend.addr.to_string(),
+                "routes": routes.iter().map(|(route, _)| &***route).collect::<Vec<&str>>(),
+                "weights": routes.iter().map(|(route, weight)| (route.to_string(), config.weight(backend, *weight).into())).collect::<serde_json::Map<String, serde_json::Value>>(),
+                "drained": config.is_drained(backend),
+                "down": down,
+                "consecutive_failures": consecutive_failures,
+                "healthy": health.as_ref().map(|(healthy, _)| *healthy),
+                "health_check_error": health.and_then(|(_, error)| error),
+                "certificate_expires": certificate.map(response::rfc3339),
+                "certificate_days_remaining": certificate.map(|expires| (x509::days_remaining(expires) * 100.0).round() / 100.0),
+                "ejected": ejected_for.is_some(),
+                "ejected_seconds": ejected_for.map(|remaining| remaining.as_secs()),
+                "ejections": ejections,
+                "active_connections": state.metrics.active_connections(&backend.name),
+                "latency_ms": state.regions.latency(&backend.name).flatten().map(|latency| latency.as_micros() as f64 / 1000.0),
+                "probe_failing": state.regions.latency(&backend.name).is_some_and(|latency| latency.is_none()),
+            })
+        })
+        .collect();
+    Response::json(serde_json::json!({ "backends": backends }))
+}
+
+/// `POST /backends/drain` or `/backends/resume` with `{"backend": "127.0.0.1:4000"}`: stop or resume
+/// sending new connections to a backend
+fn set_drained(body: &[u8], state: &AdminState, drained: bool) -> Response {
+    let backend = match parse_body(body, "backend") {
+        Ok((backend, _)) => backend,
+        Err(response) => return response,
+    };
+    let change = serde_json::json!({ "change": if drained { "backend_drained" } else { "backend_resumed" }, "backend": backend });
+    let updated = update(state, change, "400 Bad Request", |snapshot| {
+        let listed = snapshot.drained_backends.contains(&backend);
+        if drained && !listed {
+            let known = state.config.load().backends().iter().any(|(b, _)| *b.name == *backend);
+            if !known {
+                return Err(Response::error("404 Not Found", &format!("no backend named '{}'", backend)));
+            }
+            snapshot.drained_backends.push(backend.clone());
+        } else if !drained {
+            if !listed {
+                return Err(Response::error("409 Conflict", &format!("backend '{}' is not drained", backend)));
+            }
+            snapshot.drained_backends.retain(|b| *b != backend);
+        }
+        Ok(())
+    });
+    match updated {
+        Ok(_) => backends_json(state),
+        Err(response) => response,
+    }
+}
+
+/// `POST /backends/weight` with `{"backend": "127.0.0.1:4001", "weight": 10}`: give a backend a weight
+/// in all routes it serves, replacing theirs; `"weight": null` restores the routes' weights
+fn set_weight(body: &[u8], state: &AdminState) -> Response {
+    let (backend, request) = match parse_body(body, "backend") {
+        Ok(parsed) => parsed,
+        Err(response) => return response,
+    };
+    let weight = match &request["weight"] {
+        serde_json::Value::Null => None,
+        value => match value.as_u64().ok_or_else(|| "missing \"weight\" number".to_string()).and_then(crate::pool::check_weight) {
+            Ok(weight) => Some(weight),
+            Err(e) => return Response::error("400 Bad Request", &e),
+        },
+    };
+    let change = serde_json::json!({ "change": "backend_weight", "backend": backend, "weight": weight });
+    let updated = update(state, change, "400 Bad Request", |snapshot| {
+        let known = state.config.load().backends().iter().any(|(b, _)| *b.name == *backend);
+        if !known {
+            return Err(Response::error("404 Not Found", &format!("no backend named '{}'", backend)));
+        }
+        match weight {
+            Some(weight) => snapshot.backend_weights.insert(backend.clone(), weight),
+            None => snapshot.backend_weights.remove(&backend),
+        };
+        Ok(())
+    });
+    match updated {
+        Ok(_) => backends_json(state),
+        Err(response) => response,
+    }
+}
+
+/// `POST /faults` with `{"route": "/api", "delay_ms": 200, "abort_percent": 10, "abort_status": 503}`:
+/// inject faults into a route's requests, replacing those it had
+fn set_fault(body: &[u8], state: &AdminState) -> Response {
+    let (route, request) = match parse_body(body, "route") {
+        Ok(parsed) => parsed,
+        Err(response) => return response,
+    };
+    let fault = match Fault::from_json(&request) {
+        Ok(fault) => fault,
+        Err(e) => return Response::error("400 Bad Request", &e),
+    };
+    let known = state.config.load().backends().iter().any(|(_, routes)| routes.iter().any(|(name, _)| ***name == *route));
+    if !known {
+        return Response::error("404 Not Found", &format!("no route named '{}' with backends", route));
+    }
+    logging::warning(format!("Fault injection on route {}: {}", route, fault.to_json()));
+    state.bus.publish("fault_injection", serde_json::json!({ "route": route, "fault": fault.to_json() }));
+    state.faults.set(route, fault);
+    Response::json(state.faults.to_json())
+}
+
+/// `DELETE /faults` with `{"route": "/api"}`: stop injecting faults into a route's requests
+fn clear_fault(body: &[u8], state: &AdminState) -> Response {
+    let route = match parse_body(body, "route") {
+        Ok((route, _)) => route,
+        Err(response) => return response,
+    };
+    if !state.faults.clear(&route) {
+        return Response::error("409 Conflict", &format!("route '{}' has no faults", route));
+    }
+    logging::info(format!("Fault injection on route {} cleared", route));
+    state.bus.publish("fault_injection", serde_json::json!({ "route": route, "fault": null }));
+    Response::json(state.faults.to_json())
+}
+
+/// `GET /certificates`: the certificate each `;cert` listener serves, when it expires and whether
+/// that is within `--cert-warning`
+fn certificates_json(state: &AdminState) -> Response {
+    let certificates: Vec<_> = state.certificates.iter().map(|served| {
+        let expires = served.expires();
+        serde_json::json!({
+            "listener": &*served.listener,
+            "certificate": served.cert.display().to_string(),
+            "expires": response::rfc3339(expires),
+            "days_remaining": (x509::days_remaining(expires) * 100.0).round() / 100.0,
+            "expiring": served.expiring(),
+        })
+    }).collect();
+    Response::json(serde_json::json!({ "certificates": certificates }))
+}
+
+/// `GET /slots`: every blue/green slot with its pools, the color it is on and the routes using it
+fn slots_json(state: &AdminState) -> Response {
+    let config = state.config.load();
+    let slots: serde_json::Map<String, serde_json::Value> = config.slots.iter()
+        .map(|(name, slot)| (name.clone(), serde_json::json!({
+            "active": slot.active.name(),
+            "blue": slot.spec(Color::Blue),
+            "green": slot.spec(Color::Green),
+            "routes": config.slot_routes(name),
+        })))
+        .collect();
+    Response::json(serde_json::json!({ "slots": slots }))
+}
+
+/// `POST /slots/switch` with `{"slot": "checkout", "active": "green", "drain_seconds": 30}`: send new
+/// connections of the slot's routes to the pool of a color; with `drain_seconds`, connections still
+/// open to backends of the old pool are closed once that many seconds have passed
+fn switch_slot(body: &[u8], state: &AdminState) -> Response {
+    let (name, request) = match parse_body(body, "slot") {
+        Ok(parsed) => parsed,
+        Err(response) => return response,
+    };
+    let color = match request["active"].as_str().ok_or_else(|| "missing \"active\" string".to_string()).and_then(Color::parse) {
+        Ok(color) => color,
+        Err(e) => return Response::error("400 Bad Request", &e),
+    };
+    let drain = match &request["drain_seconds"] {
+        serde_json::Value::Null => None,
+        value => match value.as_u64().filter(|&seconds| seconds <= MAX_DRAIN_SECONDS) {
+            Some(seconds) => Some(Duration::from_secs(seconds)),
+            None => return Response::error("400 Bad Request", &format!("\"drain_seconds\" must be a number of seconds up to {}", MAX_DRAIN_SECONDS)),
+        },
+    };
+    let old = {
+        let config = state.config.load();
+        let Some(slot) = config.slots.get(&name) else {
+            return Response::error("404 Not Found", &format!("no slot named '{}'", name));
+        };
+        if slot.active == color {
+            return Response::error("409 Conflict", &format!("slot '{}' is already on {}", name, color));
+        }
+        if slot.spec(color).is_none() {
+            return Response::error("409 Conflict", &format!("slot '{}' has no {} pool", name, color));
+        }
+        slot.backends(slot.active)
+    };
+    let change = serde_json::json!({ "change": "slot_switched", "slot": name, "active": color.name() });
+    let updated = update(state, change, "409 Conflict", |snapshot| {
+        match color {
+            Color::Blue => snapshot.active_slots.remove(&name),
+            Color::Green => snapshot.active_slots.insert(name.clone(), color.name().to_string()),
+        };
+        Ok(())
+    });
+    if let Err(response) = updated {
+        return response;
+    }
+    logging::info(format!("Slot {} switched to {}", name, color));
+
+    if let Some(drain) = drain {
+        let (config, cutovers) = (state.config.clone(), state.cutovers.clone());
+        tokio::spawn(async move {
+            tokio::time::sleep(drain).await;
+            // Backends the slot has gone back to, or that both pools share, keep their connections
+            let config = config.load();
+            let current = config.slots.get(&name).map(|slot| slot.backends(slot.active)).unwrap_or_default();
+            let closing: Vec<Arc<str>> = old.into_iter().filter(|backend| !current.contains(backend)).collect();
+            if !closing.is_empty() {
+                let names: Vec<&str> = closing.iter().map(|backend| &**backend).collect();
+                logging::info(format!("Slot {} drained: closing its connections to {}", name, names.join(", ")));
+                cutovers.close(&name, closing);
+            }
+        });
+    }
+    slots_json(state)
+}
+
+/// Parse a JSON request body and take a required string field
+fn parse_body(body: &[u8], field: &str) -> Result<(String, serde_json::Value), Response> {
+    let request: serde_json::Value = serde_json::from_slice(body)
+        .map_err(|e| Response::error("400 Bad Request", &format!("invalid JSON body: {}", e)))?;
+    let value = request[field].as_str()
+        .ok_or_else(|| Response::error("400 Bad Request", &format!("missing \"{}\" string", field)))?;
+    Ok((value.to_string(), request))
+}
+
+/// Apply a change to the current routing state and swap in the table compiled from it. A table that
+/// fails to compile is answered with `invalid`; when the table was replaced meanwhile (reload, another
+/// admin request), the change is applied again on top of the new one instead of overwriting it.
+fn update(
+    state: &AdminState,
+    change: serde_json::Value,
+    invalid: &'static str,
+    edit: impl Fn(&mut Snapshot) -> Result<(), Response>,
+) -> Result<Snapshot, Response> {
+    if state.control_plane {
+        return Err(Response::error("409 Conflict", "routes are managed by the control plane"));
+    }
+    loop {
+        let current = state.config.load_full();
+        let mut snapshot = current.snapshot();
+        edit(&mut snapshot)?;
+        let config = RouteConfig::from_snapshot(snapshot.clone()).map_err(|e| Response::error(invalid, &e))?;
+        let previous = state.config.compare_and_swap(&current, Arc::new(config));
+        if Arc::ptr_eq(&previous, &current) {
+            let mut event = change;
+            event["source"] = "admin".into();
+            state.bus.publish("config_reload", event);
+            return Ok(snapshot);
+        }
+    }
+}
+
+/// Look up a parameter in a raw query string (no percent-decoding)
+fn query_param<'a>(query: &'a str, name: &str) -> Option<&'a str> {
+    query.split('&')
+        .filter_map(|pair| pair.split_once('='))
+        .find(|(key, _)| *key == name)
+        .map(|(_, value)| value)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// The status of the refusal of a request, or None when it goes on to its endpoint
+    fn refused(request: &str) -> Option<&'static str> {
+        let mut storage = [httparse::EMPTY_HEADER; MAX_HEADERS];
+        let head = RequestHead::parse(request.as_bytes(), &mut storage).unwrap();
+        refuse_cross_site(&head).map(|response| response.status)
+    }
+
+    #[test]
+    fn state_changes_need_json() {
+        assert_eq!(refused("GET /routes HTTP/1.1\r\nHost: 127.0.0.1:9000\r\n\r\n"), None);
+        assert_eq!(refused("POST /routes HTTP/1.1\r\nHost: 127.0.0.1:9000\r\nContent-Type: application/json\r\n\r\n"), None);
+        assert_eq!(refused("POST /routes HTTP/1.1\r\nHost: 127.0.0.1:9000\r\nContent-Type: Application/JSON; charset=utf-8\r\n\r\n"), None);
+        assert_eq!(refused("POST /routes HTTP/1.1\r\nHost: 127.0.0.1:9000\r\n\r\n"), Some("415 Unsupported Media Type"));
+        assert_eq!(refused("DELETE /faults HTTP/1.1\r\nHost: 127.0.0.1:9000\r\nContent-Type: text/plain\r\n\r\n"), Some("415 Unsupported Media Type"));
+    }
+
+    #[test]
+    fn state_changes_from_other_origins_are_refused() {
+        let request = |origin: &str| format!("POST /backends/drain HTTP/1.1\r\nHost: 127.0.0.1:9000\r\nOrigin: {}\r\nContent-Type: application/json\r\n\r\n", origin);
+        assert_eq!(refused(&request("http://127.0.0.1:9000")), None);
+        assert_eq!(refused(&request("https://evil.example")), Some("403 Forbidden"));
+        assert_eq!(refused(&request("http://127.0.0.1:8080")), Some("403 Forbidden"));
+        assert_eq!(refused(&request("null")), Some("403 Forbidden"));
+    }
+}
@@ -0,0 +1,203 @@
+//! Threshold-based alerting delivered to a (Slack-compatible) JSON webhook
+
+use crate::client::{self, Url};
+use crate::events::EventBus;
+use crate::logging;
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tokio::sync::mpsc;
+
+/// Alerting thresholds and delivery settings
+pub struct AlertConfig {
+    /// Where alerts are POSTed; without a webhook they still reach the log and the event stream
+    pub webhook: Option<Url>,
+    /// Fraction of failed requests (0.0-1.0) in a window that triggers an alert
+    pub error_rate: Option<f64>,
+    /// Mean time to first response byte in a window that triggers an alert
+    pub latency: Option<Duration>,
+    /// Consecutive connect failures after which a backend is reported down
+    pub backend_failures: u32,
+    /// Evaluation window for error rate and latency
+    pub window: Duration,
+    /// Minimum number of requests in a window before error rate and latency are evaluated
+    pub min_requests: u64,
+}
+
+#[derive(Default)]
+struct BackendState {
+    consecutive_failures: u32,
+    down: bool,
+}
+
+#[derive(Default)]
+struct State {
+    requests: u64,
+    errors: u64,
+    latency_sum: Duration,
+    latency_samples: u32,
+    error_rate_firing: bool,
+    latency_firing: bool,
+    backends: HashMap<String, BackendState>,
+}
+
+/// Collects request outcomes and raises/resolves alerts when thresholds are crossed
+pub struct Alerter {
+    config: AlertConfig,
+    state: Mutex<State>,
+    bus: std::sync::Arc<EventBus>,
+    webhook: Option<mpsc::UnboundedSender<serde_json::Value>>,
+}
+
+impl Alerter {
+    /// Create the alerter and spawn its evaluation and webhook delivery tasks
+    pub fn start(config: AlertConfig, bus: std::sync::Arc<EventBus>) -> std::sync::Arc<Self> {
+        let webhook = config.webhook.clone().map(|url| {
+            let (sender, receiver) = mpsc::unbounded_channel();
+            tokio::spawn(deliver(url, receiver));
+            sender
+        });
+
+        let alerter = std::sync::Arc::new(Alerter {
+            config,
+            state: Mutex::new(State::default()),
+            bus,
+            webhook,
+        });
+
+        let evaluator = alerter.clone();
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(evaluator.config.window);
+            interval.tick().await;
+            loop {
+                interval.tick().await;
+                evaluator.evaluate_window();
+            }
+        });
+
+        alerter
+    }
+
+    /// Record a request that reached the backend, with its time to first response byte if any
+    pub fn record_success(&self, backend: &str, ttfb: Option<Duration>) {
+        let mut state = self.state.lock().unwrap();
+        state.requests += 1;
+        if let Some(ttfb) = ttfb {
+            state.latency_sum += ttfb;
+            state.latency_samples += 1;
+        }
+
+        if let Some(backend_state) = state.backends.get_mut(backend) {
+            backend_state.consecutive_failures = 0;
+            if backend_state.down {
+                backend_state.down = false;
+                self.bus.publish("backend_up", serde_json::json!({ "backend": backend }));
+                self.emit("backend_down", "resolved", format!("Backend {} is reachable again", backend), None, None, Some(backend));
+            }
+        }
+    }
+
+    /// Whether a backend is reported down, and its consecutive failed connection attempts
+    pub fn backend_status(&self, backend: &str) -> (bool, u32) {
+        let state = self.state.lock().unwrap();
+        state.backends.get(backend).map_or((false, 0), |backend| (backend.down, backend.consecutive_failures))
+    }
+
+    /// Record a request whose transfer broke after the backend was reached
+    pub fn record_error(&self) {
+        let mut state = self.state.lock().unwrap();
+        state.requests += 1;
+        state.errors += 1;
+    }
+
+    /// Record a request that failed because the backend could not be reached
+    pub fn record_connect_failure(&self, backend: &str) {
+        let mut state = self.state.lock().unwrap();
+        state.requests += 1;
+        state.errors += 1;
+
+        let threshold = self.config.backend_failures;
+        let backend_state = state.backends.entry(backend.to_string()).or_default();
+        backend_state.consecutive_failures += 1;
+        if !backend_state.down && threshold > 0 && backend_state.consecutive_failures >= threshold {
+            backend_state.down = true;
+            self.bus.publish("backend_down", serde_json::json!({
+                "backend": backend,
+                "consecutive_failures": backend_state.consecutive_failures,
+            }));
+            let message = format!("Backend {} is down ({} consecutive failures)", backend, backend_state.consecutive_failures);
+            self.emit("backend_down", "firing", message, Some(backend_state.consecutive_failures as f64), Some(threshold as f64), Some(backend));
+        }
+    }
+
+    /// Evaluate error rate and latency for the window that just ended, then start a new one
+    fn evaluate_window(&self) {
+        let mut state = self.state.lock().unwrap();
+        let window_secs = self.config.window.as_secs();
+
+        if state.requests >= self.config.min_requests && state.requests > 0 {
+            if let Some(threshold) = self.config.error_rate {
+                let rate = state.errors as f64 / state.requests as f64;
+                let firing = rate > threshold;
+                if firing != state.error_rate_firing {
+                    state.error_rate_firing = firing;
+                    let message = format!(
+                        "Error rate {:.1}% over the last {}s (threshold {:.1}%)",
+                        rate * 100.0, window_secs, threshold * 100.0
+                    );
+                    self.emit("error_rate", if firing { "firing" } else { "resolved" }, message, Some(rate), Some(threshold), None);
+                }
+            }
+
+            if let (Some(threshold), true) = (self.config.latency, state.latency_samples > 0) {
+                let mean = state.latency_sum / state.latency_samples;
+                let firing = mean > threshold;
+                if firing != state.latency_firing {
+                    state.latency_firing = firing;
+                    let message = format!(
+                        "Mean time to first byte {}ms over the last {}s (threshold {}ms)",
+                        mean.as_millis(), window_secs, threshold.as_millis()
+                    );
+                    self.emit("latency", if firing { "firing" } else { "resolved" }, message, Some(mean.as_millis() as f64), Some(threshold.as_millis() as f64), None);
+                }
+            }
+        }
+
+        state.requests = 0;
+        state.errors = 0;
+        state.latency_sum = Duration::ZERO;
+        state.latency_samples = 0;
+    }
+
+    fn emit(&self, alert: &str, status: &str, message: String, value: Option<f64>, threshold: Option<f64>, backend: Option<&str>) {
+        println!("Alert {} {}: {}", alert, status, message);
+
+        let timestamp = SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |d| d.as_secs());
+        let marker = if status == "firing" { ":rotating_light:" } else { ":white_check_mark:" };
+        let event = serde_json::json!({
+            // `text` is what Slack-compatible webhooks display; the other fields are for generic consumers
+            "text": format!("{} [{}] {}", marker, status.to_uppercase(), message),
+            "alert": alert,
+            "status": status,
+            "message": message,
+            "value": value,
+            "threshold": threshold,
+            "backend": backend,
+            "timestamp": timestamp,
+        });
+        self.bus.publish("alert", event.clone());
+        if let Some(webhook) = &self.webhook {
+            let _ = webhook.send(event);
+        }
+    }
+}
+
+async fn deliver(webhook: Url, mut receiver: mpsc::UnboundedReceiver<serde_json::Value>) {
+    while let Some(event) = receiver.recv().await {
+        match client::post_json(&webhook, &event.to_string()).await {
+            Ok(response) if (200..300).contains(&response.status) => {}
+            Ok(response) => logging::error(format!("Alert webhook returned status {}", response.status)),
+            Err(e) => logging::error(format!("Failed to deliver alert to webhook: {}", e)),
+        }
+    }
+}
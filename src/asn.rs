@@ -0,0 +1,233 @@
+//! Autonomous system lookups (`--asn-db`) in a MaxMind DB file such as GeoLite2-ASN or
+//! DB-IP's ASN Lite, so that rate limits and access rules can treat a whole network operator as
+//! one client. The file is read into memory; a lookup walks the search tree and decodes one record.
+
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::path::Path;
+
+/// Marks the start of the metadata, near the end of the file
+const METADATA_MARKER: &[u8] = b"\xab\xcd\xefMaxMind.com";
+
+/// How far from the end the metadata is looked for
+const METADATA_MAX_SIZE: usize = 128 * 1024;
+
+/// Nesting of maps and arrays decoded at most, against malformed files
+const MAX_DEPTH: usize = 32;
+
+/// An autonomous system, as the database names it
+pub struct AutonomousSystem {
+    pub number: u32,
+    pub organization: Option<String>,
+}
+
+pub struct AsnDatabase {
+    data: Vec<u8>,
+    node_count: usize,
+    record_size: usize,
+    ip_version: u16,
+    /// Node reached after the 96 zero bits of IPv4-mapped addresses in an IPv6 tree
+    ipv4_start: usize,
+}
+
+/// A decoded data section value
+enum Value {
+    String(String),
+    Unsigned(u64),
+    Signed(i64),
+    Map(HashMap<String, Value>),
+    /// Floats, booleans, bytes and arrays, which ASN records do not need
+    Other,
+}
+
+impl Value {
+    fn unsigned(&self) -> Option<u64> {
+        match *self {
+            Value::Unsigned(value) => Some(value),
+            Value::Signed(value) => u64::try_from(value).ok(),
+            _ => None,
+        }
+    }
+}
+
+impl AsnDatabase {
+    pub fn load(path: &Path) -> Result<Self, String> {
+        let data = std::fs::read(path).map_err(|e| format!("Failed to read ASN database {}: {}", path.display(), e))?;
+        AsnDatabase::parse(data).map_err(|e| format!("Invalid ASN database {}: {}", path.display(), e))
+    }
+
+    fn parse(data: Vec<u8>) -> Result<Self, String> {
+        let search_from = data.len().saturating_sub(METADATA_MAX_SIZE);
+        let marker = data[search_from..].windows(METADATA_MARKER.len()).rposition(|w| w == METADATA_MARKER)
+            .ok_or("not a MaxMind DB file (no metadata)")?;
+        let metadata_start = search_from + marker + METADATA_MARKER.len();
+        let metadata = match (Decoder { data: &data[metadata_start..] }).decode(0, 0)?.0 {
+            Value::Map(map) => map,
+            _ => return Err("metadata is not a map".into()),
+        };
+        let field = |name: &str| metadata.get(name).and_then(Value::unsigned).ok_or_else(|| format!("metadata lacks {}", name));
+        let node_count = field("node_count")? as usize;
+        let record_size = field("record_size")? as usize;
+        let ip_version = field("ip_version")? as u16;
+        if ![24, 28, 32].contains(&record_size) {
+            return Err(format!("unsupported record size {}", record_size));
+        }
+        if ip_version != 4 && ip_version != 6 {
+            return Err(format!("unsupported IP version {}", ip_version));
+        }
+        if (record_size * 2 / 8) * node_count + 16 > metadata_start {
+            return Err("search tree larger than the file".into());
+        }
+
+        let mut database = AsnDatabase { data, node_count, record_size, ip_version, ipv4_start: 0 };
+        if ip_version == 6 {
+            let mut node = 0;
+            for _ in 0..96 {
+                if node >= node_count {
+                    break;
+                }
+                node = database.record(node, false);
+            }
+            database.ipv4_start = node;
+        }
+        Ok(database)
+    }
+
+    /// The autonomous system announcing the address, if the database knows one
+    pub fn lookup(&self, addr: IpAddr) -> Option<AutonomousSystem> {
+        let (bits, start, len): (u128, usize, u32) = match addr {
+            IpAddr::V4(v4) => (u128::from(u32::from(v4)) << 96, self.ipv4_start, 32),
+            IpAddr::V6(v6) => match v6.to_ipv4_mapped() {
+                Some(v4) => (u128::from(u32::from(v4)) << 96, self.ipv4_start, 32),
+                None if self.ip_version == 6 => (u128::from(v6), 0, 128),
+                None => return None,
+            },
+        };
+        let mut node = start;
+        for bit in 0..len {
+            if node >= self.node_count {
+                break;
+            }
+            node = self.record(node, bits & (1 << (127 - bit)) != 0);
+        }
+        if node <= self.node_count {
+            return None;
+        }
+
+        let data_section = &self.data[(self.record_size * 2 / 8) * self.node_count + 16..];
+        let offset = node - self.node_count - 16;
+        let Ok((Value::Map(record), _)) = (Decoder { data: data_section }).decode(offset, 0) else {
+            return None;
+        };
+        let number = record.get("autonomous_system_number").and_then(Value::unsigned).and_then(|n| u32::try_from(n).ok())?;
+        let organization = match record.get("autonomous_system_organization") {
+            Some(Value::String(name)) => Some(name.clone()),
+            _ => None,
+        };
+        Some(AutonomousSystem { number, organization })
+    }
+
+    /// The left or right record of a search tree node
+    fn record(&self, node: usize, right: bool) -> usize {
+        let bytes = &self.data[node * self.record_size * 2 / 8..];
+        let be = |bytes: &[u8]| bytes.iter().fold(0usize, |acc, &b| (acc << 8) | b as usize);
+        match (self.record_size, right) {
+            (24, false) => be(&bytes[0..3]),
+            (24, true) => be(&bytes[3..6]),
+            (28, false) => ((bytes[3] as usize & 0xf0) << 20) | be(&bytes[0..3]),
+            (28, true) => ((bytes[3] as usize & 0x0f) << 24) | be(&bytes[4..7]),
+            (_, false) => be(&bytes[0..4]),
+            (_, true) => be(&bytes[4..8]),
+        }
+    }
+}
+
+/// Reads values from a data section (or the metadata), bounds-checked throughout
+struct Decoder<'d> {
+    data: &'d [u8],
+}
+
+impl Decoder<'_> {
+    fn bytes(&self, offset: usize, len: usize) -> Result<&[u8], String> {
+        self.data.get(offset..offset + len).ok_or_else(|| "truncated data".to_string())
+    }
+
+    /// The value at `offset` and the offset after it; pointers are followed
+    fn decode(&self, offset: usize, depth: usize) -> Result<(Value, usize), String> {
+        if depth > MAX_DEPTH {
+            return Err("data nested too deeply".into());
+        }
+        let be = |bytes: &[u8]| bytes.iter().fold(0u64, |acc, &b| (acc << 8) | b as u64);
+        let control = self.bytes(offset, 1)?[0];
+        let mut offset = offset + 1;
+        let mut kind = control >> 5;
+
+        if kind == 1 {
+            let size = ((control >> 3) & 0x3) as usize;
+            let low = (control & 0x7) as u64;
+            let bytes = self.bytes(offset, size + 1)?;
+            let pointer = match size {
+                0 => (low << 8) | be(bytes),
+                1 => ((low << 16) | be(bytes)) + 2048,
+                2 => ((low << 24) | be(bytes)) + 526_336,
+                _ => be(bytes),
+            };
+            let (value, _) = self.decode(pointer as usize, depth + 1)?;
+            return Ok((value, offset + size + 1));
+        }
+        if kind == 0 {
+            kind = 7 + self.bytes(offset, 1)?[0];
+            offset += 1;
+        }
+        let mut size = (control & 0x1f) as usize;
+        if size >= 29 {
+            let extra = size - 28;
+            let bytes = self.bytes(offset, extra)?;
+            size = match extra {
+                1 => 29 + be(bytes) as usize,
+                2 => 285 + be(bytes) as usize,
+                _ => 65_821 + be(bytes) as usize,
+            };
+            offset += extra;
+        }
+
+        let value = match kind {
+            2 => Value::String(String::from_utf8_lossy(self.bytes(offset, size)?).into_owned()),
+            3 | 4 | 15 => Value::Other,
+            5 | 6 | 9 if size <= 8 => Value::Unsigned(be(self.bytes(offset, size)?)),
+            10 if size <= 16 => Value::Unsigned(u64::try_from(self.bytes(offset, size)?.iter().fold(0u128, |acc, &b| (acc << 8) | b as u128)).unwrap_or(u64::MAX)),
+            8 if size <= 4 => {
+                let mut bytes = [0u8; 4];
+                bytes[4 - size..].copy_from_slice(self.bytes(offset, size)?);
+                Value::Signed(i32::from_be_bytes(bytes) as i64)
+            }
+            14 => return Ok((Value::Other, offset)),
+            7 => {
+                let mut map = HashMap::with_capacity(size.min(64));
+                for _ in 0..size {
+                    let (key, next) = self.decode(offset, depth + 1)?;
+                    let (value, next) = self.decode(next, depth + 1)?;
+                    offset = next;
+                    if let Value::String(key) = key {
+                        map.insert(key, value);
+                    }
+                }
+                return Ok((Value::Map(map), offset));
+            }
+            11 => {
+                for _ in 0..size {
+                    offset = self.decode(offset, depth + 1)?.1;
+                }
+                return Ok((Value::Other, offset));
+            }
+            _ => return Err(format!("unsupported data type {}", kind)),
+        };
+        Ok((value, offset + size))
+    }
+}
+
+/// An AS number as written in options and lists: `16509` or `AS16509`
+pub fn parse_number(value: &str) -> Option<u32> {
+    let digits = value.strip_prefix("AS").or_else(|| value.strip_prefix("as")).unwrap_or(value);
+    digits.parse().ok()
+}
@@ -0,0 +1,106 @@
+//! A blocking HTTP/1.1 client for the watchers that follow a Kubernetes API server or the Docker
+//! daemon on threads of their own: one `GET` per connection, with a body that may be chunked and
+//! may stream for as long as the watch lasts.
+
+use serde_json::Value;
+use std::io::{self, Read, Write};
+
+/// Largest response head read
+const MAX_HEAD: usize = 64 * 1024;
+
+/// A connection to send a request over
+pub trait Stream: Read + Write + Send {}
+impl<T: Read + Write + Send> Stream for T {}
+
+/// Send `GET path` over a connection and read the response head. The body of a `200 OK`, or an
+/// error with the server's message: the `message` of a JSON error body, as both Kubernetes and
+/// Docker give one. `headers` are extra header lines, each ending in CRLF.
+pub fn get(mut stream: Box<dyn Stream>, host: &str, path: &str, headers: &str) -> Result<Body, String> {
+    let request = format!(
+        "GET {} HTTP/1.1\r\nHost: {}\r\nUser-Agent: reverse-http-proxy\r\nAccept: application/json\r\n{}Connection: close\r\n\r\n",
+        path, host, headers
+    );
+    stream.write_all(request.as_bytes()).map_err(|e| e.to_string())?;
+    stream.flush().map_err(|e| e.to_string())?;
+
+    let mut head = Vec::new();
+    while !head.ends_with(b"\r\n\r\n") {
+        let mut byte = [0u8];
+        match stream.read(&mut byte).map_err(|e| e.to_string())? {
+            0 => return Err("connection closed before a response".to_string()),
+            _ if head.len() > MAX_HEAD => return Err("response head too large".to_string()),
+            _ => head.push(byte[0]),
+        }
+    }
+    let status = crate::intercept::status(&head).unwrap_or(0);
+    let chunked = crate::intercept::header_value(&head, "transfer-encoding").is_some_and(|v| v.trim().eq_ignore_ascii_case("chunked"));
+    let mut body = Body { stream: Box::new(stream), chunk: chunked.then_some(0), done: false };
+    if status != 200 {
+        let mut message = Vec::new();
+        let _ = (&mut body).take(MAX_HEAD as u64).read_to_end(&mut message);
+        let message = serde_json::from_slice::<Value>(&message).ok()
+            .and_then(|error| error["message"].as_str().map(str::to_string))
+            .unwrap_or_else(|| format!("status {}", status));
+        return Err(format!("answered {}: {}", status, message));
+    }
+    Ok(body)
+}
+
+/// A response body being read, after a `200 OK` head
+pub struct Body {
+    stream: Box<dyn Read + Send>,
+    /// Bytes left of the current chunk, for chunked bodies
+    chunk: Option<u64>,
+    done: bool,
+}
+
+impl Read for Body {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.done || buf.is_empty() {
+            return Ok(0);
+        }
+        let Some(left) = self.chunk else {
+            return self.stream.read(buf);
+        };
+        if left == 0 {
+            // The line break ending the previous chunk, if there was one, then the next size
+            let mut line = read_line(&mut self.stream)?;
+            if line.is_empty() {
+                line = read_line(&mut self.stream)?;
+            }
+            let size = u64::from_str_radix(line.split(';').next().unwrap_or("").trim(), 16)
+                .map_err(|_| io::Error::new(io::ErrorKind::InvalidData, "invalid chunk size"))?;
+            if size == 0 {
+                self.done = true;
+                return Ok(0);
+            }
+            self.chunk = Some(size);
+            return self.read(buf);
+        }
+        let len = buf.len().min(left as usize);
+        let n = self.stream.read(&mut buf[..len])?;
+        if n == 0 {
+            return Err(io::ErrorKind::UnexpectedEof.into());
+        }
+        self.chunk = Some(left - n as u64);
+        Ok(n)
+    }
+}
+
+fn read_line(stream: &mut dyn Read) -> io::Result<String> {
+    let mut line = Vec::new();
+    let mut byte = [0u8];
+    while line.len() < 4096 {
+        if stream.read(&mut byte)? == 0 {
+            return Err(io::ErrorKind::UnexpectedEof.into());
+        }
+        if byte[0] == b'\n' {
+            break;
+        }
+        line.push(byte[0]);
+    }
+    if line.last() == Some(&b'\r') {
+        line.pop();
+    }
+    Ok(String::from_utf8_lossy(&line).into_owned())
+}
@@ -0,0 +1,292 @@
+//! IP blocklist feeds (`--blocklist`) for `;blocklist` routes: lists of addresses and networks
+//! from files or URLs, refreshed periodically, and DNS blocklists (DNSBLs) queried per client
+//! address. Clients found in any of them are refused, or tarpitted.
+
+use crate::client::{self, Url};
+use crate::logging;
+use arc_swap::ArcSwap;
+use std::collections::{HashMap, HashSet};
+use std::net::IpAddr;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+/// Longest a DNSBL query may take; clients are let through when it does not answer in time
+const DNSBL_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// DNSBL answers cached at most; when full, expired answers are dropped, then all
+const MAX_CACHED: usize = 100_000;
+
+/// What a `;blocklist` route does with listed clients
+#[derive(Clone, Copy, PartialEq)]
+pub enum Mode {
+    /// Refuse them with `403 Forbidden` (`;blocklist`)
+    Reject,
+    /// Hold them in the tarpit (`;blocklist=tarpit`)
+    Tarpit,
+}
+
+impl Mode {
+    pub fn parse(value: &str, route: &str) -> Result<Self, String> {
+        match value {
+            "" | "reject" => Ok(Mode::Reject),
+            "tarpit" => Ok(Mode::Tarpit),
+            _ => Err(format!("Invalid blocklist '{}' in route '{}'. Expected blocklist, blocklist=reject or blocklist=tarpit", value, route)),
+        }
+    }
+}
+
+/// Where a blocklist comes from (`--blocklist`)
+pub enum Source {
+    /// A file of addresses and networks, one per line
+    File(PathBuf),
+    /// The same, fetched over HTTP(S)
+    Url(Url),
+    /// A DNSBL zone, queried for each client address (`dnsbl:zen.spamhaus.org`)
+    Dnsbl(String),
+}
+
+impl Source {
+    pub fn parse(spec: &str) -> Result<Self, String> {
+        if let Some(zone) = spec.strip_prefix("dnsbl:") {
+            let zone = zone.trim_matches('.');
+            if zone.is_empty() || !zone.split('.').all(|label| !label.is_empty() && label.bytes().all(|b| b.is_ascii_alphanumeric() || b == b'-')) {
+                return Err(format!("Invalid DNSBL zone in blocklist '{}'", spec));
+            }
+            return Ok(Source::Dnsbl(zone.to_ascii_lowercase()));
+        }
+        if spec.starts_with("http://") || spec.starts_with("https://") {
+            return Url::parse(spec).map(Source::Url);
+        }
+        if spec.is_empty() {
+            return Err("Empty --blocklist".into());
+        }
+        Ok(Source::File(PathBuf::from(spec)))
+    }
+
+    fn name(&self) -> String {
+        match self {
+            Source::File(path) => path.display().to_string(),
+            Source::Url(url) => format!("{}://{}:{}{}", if url.tls { "https" } else { "http" }, url.host, url.port, url.path),
+            Source::Dnsbl(zone) => zone.clone(),
+        }
+    }
+
+    /// Read or fetch the list; DNSBLs have none
+    async fn load(&self) -> Result<Networks, String> {
+        let contents = match self {
+            Source::File(path) => return read_file(path),
+            Source::Url(url) => {
+                let response = client::get(url).await.map_err(|e| format!("Failed to fetch blocklist {}: {}", self.name(), e))?;
+                if response.status != 200 {
+                    return Err(format!("Failed to fetch blocklist {}: status {}", self.name(), response.status));
+                }
+                let body = response.body.ok_or_else(|| format!("Failed to fetch blocklist {}: incomplete response", self.name()))?;
+                String::from_utf8_lossy(&body).into_owned()
+            }
+            Source::Dnsbl(_) => return Ok(Networks::default()),
+        };
+        Networks::parse(&contents).map_err(|e| format!("{} in blocklist {}", e, self.name()))
+    }
+}
+
+fn read_file(path: &std::path::Path) -> Result<Networks, String> {
+    let contents = std::fs::read_to_string(path).map_err(|e| format!("Failed to read blocklist {}: {}", path.display(), e))?;
+    Networks::parse(&contents).map_err(|e| format!("{} in blocklist {}", e, path.display()))
+}
+
+/// Check a `--blocklist` without starting it: the source, and the contents of files
+pub fn validate(spec: &str) -> Result<(), String> {
+    match Source::parse(spec)? {
+        Source::File(path) => read_file(&path).map(drop),
+        _ => Ok(()),
+    }
+}
+
+/// A set of networks, looked up with one hash probe per prefix length in use
+#[derive(Default)]
+struct Networks {
+    /// Network addresses (IPv4 mapped into IPv6) by prefix length
+    by_prefix: HashMap<u8, HashSet<u128>>,
+    /// Prefix lengths in use, longest first
+    prefixes: Vec<u8>,
+}
+
+impl Networks {
+    /// One address or CIDR network per line; `#` and `;` start comments, as in common feeds
+    fn parse(contents: &str) -> Result<Self, String> {
+        let mut networks = Networks::default();
+        for (idx, line) in contents.lines().enumerate() {
+            let entry = line.split(['#', ';']).next().unwrap_or("").trim();
+            let Some(entry) = entry.split_whitespace().next() else {
+                continue;
+            };
+            let invalid = || format!("Invalid address or network '{}' on line {}", entry, idx + 1);
+            let (addr, len) = match entry.split_once('/') {
+                Some((addr, len)) => (addr, Some(len.parse::<u8>().map_err(|_| invalid())?)),
+                None => (entry, None),
+            };
+            let addr: IpAddr = addr.parse().map_err(|_| invalid())?;
+            let len = match (addr, len) {
+                (IpAddr::V4(_), Some(len)) if len <= 32 => len + 96,
+                (IpAddr::V6(_), Some(len)) if len <= 128 => len,
+                (_, Some(_)) => return Err(invalid()),
+                (_, None) => 128,
+            };
+            networks.by_prefix.entry(len).or_default().insert(mask(bits(addr), len));
+        }
+        networks.prefixes = networks.by_prefix.keys().copied().collect();
+        networks.prefixes.sort_unstable_by(|a, b| b.cmp(a));
+        Ok(networks)
+    }
+
+    fn contains(&self, addr: IpAddr) -> bool {
+        let addr = bits(addr);
+        self.prefixes.iter().any(|&len| self.by_prefix[&len].contains(&mask(addr, len)))
+    }
+
+    fn len(&self) -> usize {
+        self.by_prefix.values().map(HashSet::len).sum()
+    }
+}
+
+/// An address as 128 bits, IPv4 mapped into IPv6 (`::ffff:a.b.c.d`)
+fn bits(addr: IpAddr) -> u128 {
+    match addr {
+        IpAddr::V4(v4) => u128::from(v4.to_ipv6_mapped()),
+        IpAddr::V6(v6) => v6.to_ipv4_mapped().map_or(u128::from(v6), |v4| u128::from(v4.to_ipv6_mapped())),
+    }
+}
+
+fn mask(bits: u128, len: u8) -> u128 {
+    if len == 0 { 0 } else { bits & (u128::MAX << (128 - u32::from(len))) }
+}
+
+struct Feed {
+    source: Source,
+    networks: ArcSwap<Networks>,
+}
+
+/// The configured blocklists, shared by all connections
+pub struct Blocklists {
+    feeds: Vec<Feed>,
+    /// How often lists are refreshed, and how long DNSBL answers are cached
+    refresh: Duration,
+    /// DNSBL answers by feed and client address: whether it is listed, and until when that holds
+    cache: Mutex<HashMap<(usize, IpAddr), (bool, Instant)>>,
+}
+
+impl Blocklists {
+    /// Load the lists, refusing lists that are unreadable or malformed at startup; feeds from URLs
+    /// that cannot be fetched yet start out empty
+    pub async fn load(specs: &[String], refresh: Duration) -> Result<Self, String> {
+        let mut feeds = Vec::with_capacity(specs.len());
+        for spec in specs {
+            let source = Source::parse(spec)?;
+            let networks = match source.load().await {
+                Ok(networks) => networks,
+                Err(e) if matches!(source, Source::Url(_)) => {
+                    logging::warning(format!("{}; retrying in {} s", e, refresh.as_secs()));
+                    Networks::default()
+                }
+                Err(e) => return Err(e),
+            };
+            feeds.push(Feed { source, networks: ArcSwap::from_pointee(networks) });
+        }
+        Ok(Blocklists { feeds, refresh, cache: Mutex::new(HashMap::new()) })
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.feeds.is_empty()
+    }
+
+    /// Each feed's name, with the number of entries of lists
+    pub fn describe(&self) -> Vec<String> {
+        self.feeds.iter().map(|feed| match feed.source {
+            Source::Dnsbl(_) => format!("{} (DNSBL)", feed.source.name()),
+            _ => format!("{} ({} entries)", feed.source.name(), feed.networks.load().len()),
+        }).collect()
+    }
+
+    /// The name of a blocklist listing the address, if one does
+    pub async fn check(&self, addr: IpAddr) -> Option<String> {
+        for (idx, feed) in self.feeds.iter().enumerate() {
+            let listed = match &feed.source {
+                Source::Dnsbl(zone) => self.query(idx, zone, addr).await,
+                _ => feed.networks.load().contains(addr),
+            };
+            if listed {
+                return Some(feed.source.name());
+            }
+        }
+        None
+    }
+
+    /// Whether a DNSBL lists the address: any A record for the reversed address under the zone
+    async fn query(&self, idx: usize, zone: &str, addr: IpAddr) -> bool {
+        let now = Instant::now();
+        if let Some(&(listed, expires)) = self.cache.lock().unwrap().get(&(idx, addr)) {
+            if expires > now {
+                return listed;
+            }
+        }
+        let name = format!("{}.{}", reversed(addr), zone);
+        let answer = tokio::time::timeout(DNSBL_TIMEOUT, tokio::net::lookup_host((name.as_str(), 0))).await;
+        let listed = match answer {
+            Ok(Ok(mut addrs)) => addrs.any(|addr| addr.is_ipv4()),
+            // No such name: not listed
+            Ok(Err(_)) => false,
+            Err(_) => {
+                logging::warning(format!("DNSBL query {} timed out", name));
+                return false;
+            }
+        };
+        let mut cache = self.cache.lock().unwrap();
+        if cache.len() >= MAX_CACHED {
+            cache.retain(|_, (_, expires)| *expires > now);
+            if cache.len() >= MAX_CACHED {
+                cache.clear();
+            }
+        }
+        cache.insert((idx, addr), (listed, now + self.refresh));
+        listed
+    }
+
+    /// Reload the file and URL lists every refresh interval; a failed reload keeps the previous list
+    pub async fn refresh(self: Arc<Self>) {
+        let mut interval = tokio::time::interval(self.refresh);
+        interval.tick().await;
+        loop {
+            interval.tick().await;
+            for feed in &self.feeds {
+                if matches!(feed.source, Source::Dnsbl(_)) {
+                    continue;
+                }
+                match feed.source.load().await {
+                    Ok(networks) => feed.networks.store(Arc::new(networks)),
+                    Err(e) => logging::warning(format!("Keeping previous blocklist: {}", e)),
+                }
+            }
+        }
+    }
+}
+
+/// The address as a DNSBL query label: octets of IPv4 addresses, nibbles of IPv6 ones, reversed
+fn reversed(addr: IpAddr) -> String {
+    match addr {
+        IpAddr::V4(v4) => {
+            let [a, b, c, d] = v4.octets();
+            format!("{}.{}.{}.{}", d, c, b, a)
+        }
+        IpAddr::V6(v6) => match v6.to_ipv4_mapped() {
+            Some(v4) => reversed(IpAddr::V4(v4)),
+            None => {
+                let nibbles: Vec<String> = v6.octets().iter().rev()
+                    .flat_map(|byte| [byte & 0xf, byte >> 4])
+                    .map(|nibble| format!("{:x}", nibble))
+                    .collect();
+                nibbles.join(".")
+            }
+        },
+    }
+}
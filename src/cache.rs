@@ -0,0 +1,166 @@
+//! A response cache for `;force-cache` routes, for legacy backends that mark everything
+//! `no-cache` although it is static: `GET` responses are kept for the route's TTL whatever the
+//! backend's `Cache-Control`, `Pragma` and `Expires` say, and repeats are answered from memory.
+
+use crate::intercept::{header_value, is_header, status};
+use crate::request::RequestHead;
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+/// Responses kept at most; more are not cached until some expire
+const MAX_ENTRIES: usize = 10_000;
+
+/// Largest response cached
+const MAX_RESPONSE: usize = 8 * 1024 * 1024;
+
+/// Cached responses take this much memory at most
+const MAX_BYTES: usize = 256 * 1024 * 1024;
+
+/// Statuses cached: those HTTP lets caches store without explicit freshness
+const CACHEABLE: &[u16] = &[200, 203, 204, 300, 301, 308, 404, 405, 410, 414, 501];
+
+struct Cached {
+    /// The response as forwarded, with `Connection: close`
+    response: Arc<[u8]>,
+    /// Length of its head, without the blank line
+    head_len: usize,
+    stored: Instant,
+    expires: Instant,
+    /// The `--host-quota` host whose cache space it takes, if it counts against one
+    host: Option<Arc<str>>,
+}
+
+#[derive(Default)]
+pub struct ResponseCache {
+    entries: Mutex<Entries>,
+}
+
+#[derive(Default)]
+struct Entries {
+    by_key: HashMap<String, Cached>,
+    bytes: usize,
+    /// Bytes taken by hosts with a cache quota
+    by_host: HashMap<Arc<str>, usize>,
+}
+
+/// The cache key of a request: the route, `Host`, path and query, and `Accept-Encoding` (so that
+/// compressed responses only go to clients accepting them). None for requests that are not
+/// cached: other methods than `GET` and `HEAD`, and requests with credentials.
+pub fn key(route: &str, head: &RequestHead) -> Option<String> {
+    if head.method != "GET" && head.method != "HEAD" {
+        return None;
+    }
+    if head.header("authorization").is_some() || head.header("cookie").is_some() {
+        return None;
+    }
+    let host = head.header_str("host").unwrap_or("");
+    let query = head.query.map_or(String::new(), |query| format!("?{}", query));
+    let encoding = head.header_str("accept-encoding").unwrap_or("");
+    Some(format!("{}\n{}\n{}{}\n{}", route, host, head.path, query, encoding))
+}
+
+/// A cache hit, ready to send
+pub struct Hit {
+    pub response: Vec<u8>,
+    pub status: u16,
+    /// How long ago the backend sent it
+    pub age: Duration,
+}
+
+impl ResponseCache {
+    /// A fresh cached response for the key, with an `Age` header; only the head for HEAD requests
+    pub fn get(&self, key: &str, with_body: bool) -> Option<Hit> {
+        let now = Instant::now();
+        let entries = self.entries.lock().unwrap();
+        let cached = entries.by_key.get(key).filter(|cached| cached.expires > now)?;
+        let age = now - cached.stored;
+        let (head, rest) = cached.response.split_at(cached.head_len);
+        let end = if with_body { rest.len() } else { rest.len().min(2) };
+        let mut response = Vec::with_capacity(cached.response.len() + 16);
+        response.extend_from_slice(head);
+        response.extend_from_slice(format!("Age: {}\r\n", age.as_secs()).as_bytes());
+        response.extend_from_slice(&rest[..end]);
+        Some(Hit { status: status(&response).unwrap_or(200), response, age })
+    }
+
+    /// Cache a complete response as forwarded, unless it sets cookies or its status is not cacheable,
+    /// or it would not fit in the cache space of its host (`quota`)
+    pub fn insert(&self, key: &str, head: &[u8], body: &[u8], ttl: Duration, quota: Option<&(Arc<str>, usize)>) {
+        let cacheable = status(head).is_some_and(|status| CACHEABLE.contains(&status));
+        if !cacheable || header_value(head, "set-cookie").is_some() || head.len() + body.len() > MAX_RESPONSE {
+            return;
+        }
+
+        // Served from the cache, the response ends the connection like the proxy's other responses
+        let mut response = Vec::with_capacity(head.len() + body.len() + 19);
+        let mut head_len = 0;
+        for (idx, line) in head.split_inclusive(|&b| b == b'\n').enumerate() {
+            if idx > 0 && (is_header(line, "connection") || is_header(line, "keep-alive") || is_header(line, "age")) {
+                continue;
+            }
+            if idx > 0 && (line == b"\r\n" || line == b"\n") {
+                response.extend_from_slice(b"Connection: close\r\n");
+                head_len = response.len();
+            }
+            response.extend_from_slice(line);
+        }
+        response.extend_from_slice(body);
+
+        let now = Instant::now();
+        let mut entries = self.entries.lock().unwrap();
+        let full = |entries: &Entries| {
+            let host_full = quota.is_some_and(|(host, max)| entries.by_host.get(host).copied().unwrap_or(0) + response.len() > *max);
+            host_full || entries.by_key.len() >= MAX_ENTRIES || entries.bytes + response.len() > MAX_BYTES
+        };
+        if full(&entries) {
+            entries.expire(now);
+            if full(&entries) {
+                return;
+            }
+        }
+        let host = quota.map(|(host, _)| host.clone());
+        entries.add(&host, response.len() as isize);
+        let cached = Cached { response: response.into(), head_len, stored: now, expires: now + ttl, host };
+        if let Some(replaced) = entries.by_key.insert(key.to_string(), cached) {
+            entries.add(&replaced.host, -(replaced.response.len() as isize));
+        }
+    }
+}
+
+impl Entries {
+    fn expire(&mut self, now: Instant) {
+        let mut freed = Vec::new();
+        self.by_key.retain(|_, cached| {
+            let keep = cached.expires > now;
+            if !keep {
+                freed.push((cached.host.take(), cached.response.len()));
+            }
+            keep
+        });
+        for (host, bytes) in freed {
+            self.add(&host, -(bytes as isize));
+        }
+    }
+
+    /// Count bytes added to or freed from the cache, and from the host's space
+    fn add(&mut self, host: &Option<Arc<str>>, bytes: isize) {
+        self.bytes = self.bytes.saturating_add_signed(bytes);
+        if let Some(host) = host {
+            let used = self.by_host.entry(host.clone()).or_default();
+            *used = used.saturating_add_signed(bytes);
+            if *used == 0 {
+                self.by_host.remove(host);
+            }
+        }
+    }
+}
+
+/// Where a `GET` response on a `;force-cache` route goes once it has been forwarded
+pub struct Store<'a> {
+    pub cache: &'a ResponseCache,
+    pub key: String,
+    pub ttl: Duration,
+    /// The cache space of the request's host, if it has a `--host-quota` for it
+    pub quota: Option<(Arc<str>, usize)>,
+}
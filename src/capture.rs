@@ -0,0 +1,271 @@
+//! Payload capture for debugging (`--capture DIR`): a sample of the proxied requests is recorded
+//! as it goes over the wire, and those matching the filters (route, lowest status, content type,
+//! body size) are written to the spool directory with their responses, one file per exchange. The
+//! oldest captures are deleted to keep the spool within its size.
+
+use crate::intercept::{self, BodyFraming, BodyStatus};
+use crate::logging;
+use crate::scrub::Scrubber;
+use std::collections::VecDeque;
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll};
+use std::time::{SystemTime, UNIX_EPOCH};
+use tokio::io::{AsyncRead, ReadBuf};
+
+/// Names of capture files: `capture-MILLISECONDS-SEQUENCE.http`, in the order they were written
+const PREFIX: &str = "capture-";
+const EXTENSION: &str = ".http";
+
+/// Headers whose values are left out of captures
+const REDACTED_HEADERS: &[&str] = &["authorization", "proxy-authorization", "cookie", "set-cookie"];
+
+pub struct CaptureConfig {
+    pub dir: PathBuf,
+    /// Share of the requests recorded, above 0 and up to 1
+    pub rate: f64,
+    /// Routes whose requests are recorded; all when empty
+    pub routes: Vec<String>,
+    /// Lowest response status kept
+    pub min_status: Option<u16>,
+    /// Parts of the request's or the response's `Content-Type` to keep, such as `json`; all when empty
+    pub types: Vec<String>,
+    /// Largest request or response body kept, in bytes
+    pub max_body: usize,
+    /// Bytes the spool holds at most
+    pub spool: u64,
+}
+
+pub struct Capture {
+    config: CaptureConfig,
+    /// Applied to the heads of captures with `--scrub`
+    scrubber: Option<Arc<Scrubber>>,
+    spool: Mutex<Spool>,
+}
+
+#[derive(Default)]
+struct Spool {
+    /// Capture files, oldest first, with their sizes
+    files: VecDeque<(PathBuf, u64)>,
+    size: u64,
+    sequence: u64,
+}
+
+/// What is known of an exchange besides its bytes
+pub struct Exchange<'a> {
+    pub client: SocketAddr,
+    pub route: &'a str,
+    pub backend: &'a str,
+    pub status: Option<u16>,
+    /// A response to `HEAD` has no body
+    pub head_request: bool,
+}
+
+impl Capture {
+    /// Open the spool directory, creating it, and take stock of the captures already in it
+    pub fn open(config: CaptureConfig, scrubber: Option<Arc<Scrubber>>) -> Result<Self, String> {
+        let failed = |e: std::io::Error| format!("Failed to open capture spool {}: {}", config.dir.display(), e);
+        let mut builder = std::fs::DirBuilder::new();
+        builder.recursive(true);
+        // Captures hold request and response bodies as they are: only the proxy's user reads them
+        #[cfg(unix)]
+        std::os::unix::fs::DirBuilderExt::mode(&mut builder, 0o700);
+        builder.create(&config.dir).map_err(failed)?;
+
+        let mut files = Vec::new();
+        for dir_entry in std::fs::read_dir(&config.dir).map_err(failed)? {
+            let dir_entry = dir_entry.map_err(failed)?;
+            let name = dir_entry.file_name().to_string_lossy().into_owned();
+            if name.starts_with(PREFIX) && name.ends_with(EXTENSION) {
+                files.push((name, dir_entry.metadata().map_err(failed)?.len()));
+            }
+        }
+        files.sort();
+        let mut spool = Spool::default();
+        for (name, size) in files {
+            spool.files.push_back((config.dir.join(name), size));
+            spool.size += size;
+        }
+        let capture = Capture { config, scrubber, spool: Mutex::new(spool) };
+        capture.trim(&mut capture.spool.lock().unwrap());
+        Ok(capture)
+    }
+
+    pub fn describe(&self) -> String {
+        format!("{} ({}% of requests, bodies up to {} bytes, spool of {} bytes)",
+            self.config.dir.display(), self.config.rate * 100.0, self.config.max_body, self.config.spool)
+    }
+
+    /// A recording of the request, if it is sampled
+    pub fn sample(&self, route: &str) -> Option<Recording> {
+        if !self.config.routes.is_empty() && !self.config.routes.iter().any(|r| r == route) {
+            return None;
+        }
+        if (crate::otel::random_id() as f64 / u64::MAX as f64) >= self.config.rate {
+            return None;
+        }
+        // Room for the head, and the framing of chunked bodies
+        let limit = intercept::MAX_RESPONSE_HEAD + 2 * self.config.max_body;
+        Some(Recording { request: Buffer::new(limit), response: Buffer::new(limit) })
+    }
+
+    /// Write a recorded exchange to the spool if it passes the filters; returns the file name
+    pub fn save(&self, recording: Recording, exchange: &Exchange) -> Option<String> {
+        // Upgraded connections carry another protocol after the response
+        let status = exchange.status.filter(|&status| status != 101)?;
+        if self.config.min_status.is_some_and(|min| status < min) {
+            return None;
+        }
+        let (request_head, request_body) = recording.request.message(request_framing, self.config.max_body)?;
+        let response = recording.response.final_response()?;
+        let no_body = exchange.head_request || status == 204 || status == 304;
+        let (response_head, response_body) = response.message(|head| if no_body { None } else { intercept::framing(head) }, self.config.max_body)?;
+        if !self.config.types.is_empty() {
+            let types = [request_head, response_head].map(|head| intercept::header_value(head, "content-type").unwrap_or("").to_ascii_lowercase());
+            if !self.config.types.iter().any(|wanted| types.iter().any(|t| t.contains(wanted.as_str()))) {
+                return None;
+            }
+        }
+
+        let now = SystemTime::now();
+        let mut contents = format!("# {} {} route {} backend {} status {}\n",
+            crate::response::rfc3339(now), exchange.client, exchange.route, exchange.backend, status).into_bytes();
+        for (head, body) in [(request_head, request_body), (response_head, response_body)] {
+            contents.extend_from_slice(self.redact(head).as_bytes());
+            contents.extend_from_slice(body);
+        }
+
+        let mut spool = self.spool.lock().unwrap();
+        spool.sequence += 1;
+        let millis = now.duration_since(UNIX_EPOCH).map_or(0, |d| d.as_millis());
+        let name = format!("{}{:013}-{:06}{}", PREFIX, millis, spool.sequence % 1_000_000, EXTENSION);
+        let path = self.config.dir.join(&name);
+        if let Err(e) = std::fs::write(&path, &contents) {
+            logging::error(format!("Failed to write capture {}: {}", path.display(), e));
+            return None;
+        }
+        spool.files.push_back((path, contents.len() as u64));
+        spool.size += contents.len() as u64;
+        self.trim(&mut spool);
+        Some(name)
+    }
+
+    /// Delete the oldest captures until the spool fits
+    fn trim(&self, spool: &mut Spool) {
+        while spool.size > self.config.spool {
+            let Some((path, size)) = spool.files.pop_front() else {
+                break;
+            };
+            spool.size -= size;
+            if let Err(e) = std::fs::remove_file(&path) {
+                if e.kind() != std::io::ErrorKind::NotFound {
+                    logging::warning(format!("Failed to delete capture {}: {}", path.display(), e));
+                }
+            }
+        }
+    }
+
+    /// A head with the values of credential headers left out, and scrubbed with `--scrub`
+    fn redact(&self, head: &[u8]) -> String {
+        let mut redacted = String::with_capacity(head.len());
+        for (idx, line) in String::from_utf8_lossy(head).split_inclusive('\n').enumerate() {
+            if idx > 0 && REDACTED_HEADERS.iter().any(|name| intercept::is_header(line.as_bytes(), name)) {
+                let (name, _) = line.split_once(':').unwrap_or((line, ""));
+                redacted.push_str(name);
+                redacted.push_str(": [redacted]\r\n");
+            } else {
+                redacted.push_str(line);
+            }
+        }
+        match &self.scrubber {
+            Some(scrubber) => redacted.lines().map(|line| scrubber.scrub(line).into_owned() + "\r\n").collect(),
+            None => redacted,
+        }
+    }
+}
+
+/// How the end of a request body is found, or None for requests without a body
+fn request_framing(head: &[u8]) -> Option<BodyFraming> {
+    if intercept::header_value(head, "transfer-encoding").is_some() {
+        return Some(BodyFraming::Chunked);
+    }
+    intercept::header_value(head, "content-length")?.parse().ok().filter(|&length| length > 0).map(BodyFraming::Length)
+}
+
+/// The bytes of both directions of a sampled exchange, as far as they are recorded
+pub struct Recording {
+    pub request: Buffer,
+    pub response: Buffer,
+}
+
+/// Bytes recorded up to a limit
+pub struct Buffer {
+    data: Vec<u8>,
+    limit: usize,
+    /// Bytes went by that did not fit
+    full: bool,
+}
+
+impl Buffer {
+    fn new(limit: usize) -> Self {
+        Buffer { data: Vec::new(), limit, full: false }
+    }
+
+    pub fn extend(&mut self, bytes: &[u8]) {
+        let room = self.limit.saturating_sub(self.data.len());
+        self.full |= bytes.len() > room;
+        self.data.extend_from_slice(&bytes[..bytes.len().min(room)]);
+    }
+
+    /// The head and body bytes of the first message, as received, when it is complete and its
+    /// (decoded) body is no larger than `max_body`; later requests on the connection are left out
+    fn message(&self, framing: impl Fn(&[u8]) -> Option<BodyFraming>, max_body: usize) -> Option<(&[u8], &[u8])> {
+        let end = self.data.windows(4).position(|w| w == b"\r\n\r\n")? + 4;
+        let (head, rest) = self.data.split_at(end);
+        let Some(framing) = framing(head) else {
+            return Some((head, &[]));
+        };
+        match framing.decode(rest, !self.full) {
+            BodyStatus::Complete(body, consumed) if body.len() <= max_body => Some((head, &rest[..consumed])),
+            _ => None,
+        }
+    }
+
+    /// The recording from the final response on, after any informational ones
+    fn final_response(&self) -> Option<Buffer> {
+        let mut start = 0;
+        loop {
+            let rest = &self.data[start..];
+            if !intercept::is_informational(rest) {
+                return Some(Buffer { data: rest.to_vec(), limit: self.limit, full: self.full });
+            }
+            start += rest.windows(4).position(|w| w == b"\r\n\r\n")? + 4;
+        }
+    }
+}
+
+/// A reader copying what is read into a recording buffer, if there is one
+pub struct Tee<'b, R> {
+    inner: R,
+    buffer: Option<&'b mut Buffer>,
+}
+
+impl<'b, R> Tee<'b, R> {
+    pub fn new(inner: R, buffer: Option<&'b mut Buffer>) -> Self {
+        Tee { inner, buffer }
+    }
+}
+
+impl<R: AsyncRead + Unpin> AsyncRead for Tee<'_, R> {
+    fn poll_read(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<std::io::Result<()>> {
+        let this = self.get_mut();
+        let before = buf.filled().len();
+        let polled = Pin::new(&mut this.inner).poll_read(cx, buf);
+        if let (Poll::Ready(Ok(())), Some(buffer)) = (&polled, this.buffer.as_deref_mut()) {
+            buffer.extend(&buf.filled()[before..]);
+        }
+        polled
+    }
+}
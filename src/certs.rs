@@ -0,0 +1,274 @@
+//! Certificate TLS listeners (`--listen ADDRESS;cert=PATH[;key=PATH]`): each serves a certificate
+//! chain and key from PEM files, reloaded when the files change. When a certificate expires is
+//! exported as a metric and shown by the admin API, and a certificate expiring within
+//! `--cert-warning` days is warned of whenever it is loaded.
+
+use crate::events::EventBus;
+use crate::metrics::Metrics;
+use crate::{logging, response, x509};
+use arc_swap::ArcSwap;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::{Duration, SystemTime};
+use tokio_rustls::rustls::crypto::CryptoProvider;
+use tokio_rustls::rustls::pki_types::pem::PemObject;
+use tokio_rustls::rustls::pki_types::{CertificateDer, PrivateKeyDer};
+use tokio_rustls::rustls::server::{ClientHello, ResolvesServerCert};
+use tokio_rustls::rustls::sign::CertifiedKey;
+use tokio_rustls::rustls::ServerConfig;
+use tokio_rustls::TlsAcceptor;
+
+/// How often the certificate files are checked for changes
+const RELOAD_INTERVAL: Duration = Duration::from_secs(5);
+
+/// A certificate as read from its files
+pub struct Loaded {
+    certified: CertifiedKey,
+    /// The `notAfter` of the chain's first certificate, the one served for the listener
+    pub expires: SystemTime,
+}
+
+impl Loaded {
+    /// The DNS names of the chain's first certificate
+    pub fn names(&self) -> Vec<String> {
+        self.certified.cert.first().and_then(|cert| x509::dns_names(cert)).unwrap_or_default()
+    }
+}
+
+/// Read a certificate chain and its private key from PEM files; they may be the same file. The
+/// key must belong to the chain's first certificate.
+pub fn read(cert: &Path, key: &Path) -> Result<Loaded, String> {
+    let pem = std::fs::read(cert).map_err(|e| format!("Failed to read certificate {}: {}", cert.display(), e))?;
+    let chain = CertificateDer::pem_slice_iter(&pem).collect::<Result<Vec<_>, _>>()
+        .map_err(|e| format!("Invalid certificate {}: {}", cert.display(), e))?;
+    let Some(first) = chain.first() else {
+        return Err(format!("No certificate in {}", cert.display()));
+    };
+    let expires = x509::not_after(first).ok_or_else(|| format!("Unreadable certificate in {}", cert.display()))?;
+    let pem = if key == cert { pem } else { std::fs::read(key).map_err(|e| format!("Failed to read key {}: {}", key.display(), e))? };
+    let private_key = PrivateKeyDer::from_pem_slice(&pem).map_err(|e| format!("No private key in {}: {}", key.display(), e))?;
+    let certified = certified(chain, private_key).map_err(|e| format!("{} ({} and {})", e, cert.display(), key.display()))?;
+    Ok(Loaded { certified, expires })
+}
+
+/// The certificate a TLS listener serves, replaced when its files change
+pub struct Served {
+    pub listener: Arc<str>,
+    pub cert: PathBuf,
+    pub key: PathBuf,
+    current: ArcSwap<CertifiedKey>,
+    expires: ArcSwap<SystemTime>,
+    /// How long before it expires a certificate is warned of
+    warning: Duration,
+}
+
+impl std::fmt::Debug for Served {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "certificate {} of listener {}", self.cert.display(), self.listener)
+    }
+}
+
+impl Served {
+    pub fn load(listener: &str, cert: PathBuf, key: PathBuf, warning: Duration, metrics: &Metrics, bus: &EventBus) -> Result<Self, String> {
+        let loaded = read(&cert, &key)?;
+        let (current, expires) = (ArcSwap::from_pointee(loaded.certified), ArcSwap::from_pointee(loaded.expires));
+        let served = Served { listener: listener.into(), cert, key, current, expires, warning };
+        served.loaded(metrics, bus);
+        Ok(served)
+    }
+
+    /// When the certificate served now expires
+    pub fn expires(&self) -> SystemTime {
+        **self.expires.load()
+    }
+
+    /// Whether the certificate expires within `--cert-warning`, or has expired
+    pub fn expiring(&self) -> bool {
+        x509::days_remaining(self.expires()) * 86400.0 <= self.warning.as_secs_f64()
+    }
+
+    /// TLS handshakes with the listener's certificate, whichever is current
+    pub fn acceptor(self: &Arc<Self>) -> TlsAcceptor {
+        let mut config = ServerConfig::builder().with_no_client_auth().with_cert_resolver(self.clone());
+        // The proxy speaks HTTP/1.1 to clients
+        config.alpn_protocols = vec![b"http/1.1".to_vec()];
+        TlsAcceptor::from(Arc::new(config))
+    }
+
+    /// Export the expiry of the certificate just loaded, and warn if it is close
+    fn loaded(&self, metrics: &Metrics, bus: &EventBus) {
+        let expires = self.expires();
+        metrics.record_listener_certificate(&self.listener, expires);
+        if !self.expiring() {
+            return;
+        }
+        let days = x509::days_remaining(expires);
+        let when = response::rfc3339(expires);
+        if days < 0.0 {
+            logging::warning(format!("TLS certificate {} of listener {} expired {:.0} days ago, at {}", self.cert.display(), self.listener, -days, when));
+        } else {
+            logging::warning(format!("TLS certificate {} of listener {} expires in {:.0} days, at {}", self.cert.display(), self.listener, days, when));
+        }
+        bus.publish("certificate_expiring", serde_json::json!({
+            "listener": &*self.listener,
+            "certificate": self.cert.display().to_string(),
+            "expires": when,
+            "days_remaining": (days * 100.0).round() / 100.0,
+        }));
+    }
+
+    /// Load the files again; an invalid certificate keeps the current one
+    fn reload(&self, metrics: &Metrics, bus: &EventBus) {
+        match read(&self.cert, &self.key) {
+            Ok(Loaded { certified, expires }) => {
+                self.current.store(Arc::new(certified));
+                self.expires.store(Arc::new(expires));
+                println!("Reloaded TLS certificate {} of listener {}, valid until {}", self.cert.display(), self.listener, response::rfc3339(expires));
+                bus.publish("config_reload", serde_json::json!({
+                    "source": "certificate",
+                    "listener": &*self.listener,
+                    "expires": response::rfc3339(expires),
+                }));
+                self.loaded(metrics, bus);
+            }
+            Err(e) => {
+                logging::warning(format!("Keeping previous TLS certificate of listener {}: {}", self.listener, e));
+                bus.publish("config_rejected", serde_json::json!({
+                    "source": "certificate",
+                    "listener": &*self.listener,
+                    "error": e,
+                }));
+            }
+        }
+    }
+}
+
+impl ResolvesServerCert for Served {
+    fn resolve(&self, _client_hello: ClientHello<'_>) -> Option<Arc<CertifiedKey>> {
+        Some(self.current.load_full())
+    }
+}
+
+/// The chain with a signing key for its private key, by the installed crypto provider
+fn certified(chain: Vec<CertificateDer<'static>>, key: PrivateKeyDer<'static>) -> Result<CertifiedKey, String> {
+    let provider = CryptoProvider::get_default().expect("the TLS crypto provider is installed at startup");
+    let key = provider.key_provider.load_private_key(key).map_err(|e| format!("Unsupported private key: {}", e))?;
+    let certified = CertifiedKey::new(chain, key);
+    certified.keys_match().map_err(|e| format!("Private key does not match the certificate: {}", e))?;
+    Ok(certified)
+}
+
+/// Reload the listeners' certificates when their files change
+pub async fn watch(served: Vec<Arc<Served>>, metrics: Arc<Metrics>, bus: Arc<EventBus>) {
+    let modified = |path: &Path| std::fs::metadata(path).and_then(|m| m.modified()).ok();
+    let mut last_modified: Vec<_> = served.iter().map(|served| (modified(&served.cert), modified(&served.key))).collect();
+
+    let mut interval = tokio::time::interval(RELOAD_INTERVAL);
+    interval.tick().await;
+
+    loop {
+        interval.tick().await;
+        for (served, last) in served.iter().zip(&mut last_modified) {
+            let current = (modified(&served.cert), modified(&served.key));
+            if current != *last {
+                *last = current;
+                served.reload(&metrics, &bus);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn fixture(name: &str) -> PathBuf {
+        crate::client::install_tls_provider(crate::client::tls_provider(None).unwrap());
+        Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/certs").join(name)
+    }
+
+    /// A copy of fixture files in a directory of its own, for tests that change them
+    fn copied(test: &str, files: &[(&str, &str)]) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("reverse-http-proxy-certs-{}-{}", test, std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        for (name, from) in files {
+            std::fs::write(dir.join(name), std::fs::read(fixture(from)).unwrap()).unwrap();
+        }
+        dir
+    }
+
+    #[test]
+    fn reads_a_certificate_and_its_key() {
+        let loaded = read(&fixture("localhost.pem"), &fixture("localhost-key.pem")).unwrap();
+        // notAfter=Sep 20 10:02:59 2126 GMT, a GeneralizedTime
+        assert_eq!(loaded.expires, std::time::UNIX_EPOCH + Duration::from_secs(4_945_572_179));
+        assert_eq!(loaded.certified.cert.len(), 1);
+        assert_eq!(loaded.names(), ["localhost"]);
+    }
+
+    #[test]
+    fn reads_a_certificate_and_key_from_one_file() {
+        let dir = copied("combined", &[]);
+        let combined = dir.join("combined.pem");
+        let pem = [std::fs::read(fixture("localhost.pem")).unwrap(), std::fs::read(fixture("localhost-key.pem")).unwrap()].concat();
+        std::fs::write(&combined, pem).unwrap();
+        assert!(read(&combined, &combined).is_ok());
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn rejects_a_key_of_another_certificate() {
+        let error = read(&fixture("localhost.pem"), &fixture("other-key.pem")).err().unwrap();
+        assert!(error.starts_with("Private key does not match the certificate"), "{}", error);
+    }
+
+    #[test]
+    fn rejects_missing_certificates_and_keys() {
+        let error = read(&fixture("localhost-key.pem"), &fixture("localhost-key.pem")).err().unwrap();
+        assert!(error.starts_with("No certificate in"), "{}", error);
+        let error = read(&fixture("localhost.pem"), &fixture("localhost.pem")).err().unwrap();
+        assert!(error.starts_with("No private key in"), "{}", error);
+        let error = read(&fixture("missing.pem"), &fixture("localhost-key.pem")).err().unwrap();
+        assert!(error.starts_with("Failed to read certificate"), "{}", error);
+    }
+
+    #[test]
+    fn warns_of_expiring_certificates() {
+        let (metrics, bus) = (Metrics::default(), EventBus::default());
+        let mut events = bus.subscribe();
+        let (cert, key) = (fixture("localhost.pem"), fixture("localhost-key.pem"));
+
+        let served = Served::load("tls", cert.clone(), key.clone(), Duration::from_secs(86400 * 14), &metrics, &bus).unwrap();
+        assert!(!served.expiring());
+        assert!(events.try_recv().is_err());
+
+        // Within two centuries of expiring
+        let served = Served::load("tls", cert, key, Duration::from_secs(86400 * 365 * 200), &metrics, &bus).unwrap();
+        assert!(served.expiring());
+        let event = events.try_recv().unwrap();
+        assert_eq!(event.kind, "certificate_expiring");
+        assert_eq!(event.data["listener"], "tls");
+        assert_eq!(event.data["expires"], "2126-09-20T10:02:59.000Z");
+    }
+
+    #[test]
+    fn reload_keeps_the_certificate_when_the_key_does_not_match() {
+        let dir = copied("reload", &[("cert.pem", "localhost.pem"), ("key.pem", "localhost-key.pem")]);
+        let (metrics, bus) = (Metrics::default(), EventBus::default());
+        let served = Served::load("tls", dir.join("cert.pem"), dir.join("key.pem"), Duration::ZERO, &metrics, &bus).unwrap();
+        let before = served.current.load_full();
+        let mut events = bus.subscribe();
+
+        std::fs::write(dir.join("key.pem"), std::fs::read(fixture("other-key.pem")).unwrap()).unwrap();
+        served.reload(&metrics, &bus);
+        let event = events.try_recv().unwrap();
+        assert_eq!((event.kind, &event.data["source"]), ("config_rejected", &serde_json::json!("certificate")));
+        assert!(Arc::ptr_eq(&before, &served.current.load_full()));
+
+        std::fs::write(dir.join("key.pem"), std::fs::read(fixture("localhost-key.pem")).unwrap()).unwrap();
+        served.reload(&metrics, &bus);
+        assert_eq!(events.try_recv().unwrap().kind, "config_reload");
+        assert!(!Arc::ptr_eq(&before, &served.current.load_full()));
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -0,0 +1,221 @@
+//! Minimal outbound HTTP/1.1 client used for webhooks and other control-plane calls
+
+use std::sync::{Arc, OnceLock};
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::net::TcpStream;
+use tokio_rustls::rustls;
+
+type BoxError = Box<dyn std::error::Error + Send + Sync>;
+
+/// Upper bound for a complete outbound request, including connect and TLS handshake
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Largest response accepted from a control-plane endpoint
+const MAX_RESPONSE_SIZE: usize = 4 * 1024 * 1024;
+
+/// A parsed `http://` or `https://` URL
+#[derive(Debug, Clone)]
+pub struct Url {
+    pub tls: bool,
+    pub host: String,
+    pub port: u16,
+    /// Path and query, always starting with `/`
+    pub path: String,
+}
+
+impl Url {
+    pub fn parse(url: &str) -> Result<Self, String> {
+        let (tls, rest) = if let Some(rest) = url.strip_prefix("https://") {
+            (true, rest)
+        } else if let Some(rest) = url.strip_prefix("http://") {
+            (false, rest)
+        } else {
+            return Err(format!("Unsupported URL '{}': expected http:// or https://", url));
+        };
+
+        let (authority, path) = match rest.find('/') {
+            Some(idx) => (&rest[..idx], &rest[idx..]),
+            None => (rest, "/"),
+        };
+
+        // IPv6 literals are bracketed: [::1]:8080
+        let (host, port) = match authority.strip_prefix('[') {
+            Some(bracketed) => match bracketed.split_once(']') {
+                Some((host, rest)) => (host, rest.strip_prefix(':')),
+                None => return Err(format!("Invalid host in URL '{}'", url)),
+            },
+            None => match authority.rsplit_once(':') {
+                Some((host, port)) => (host, Some(port)),
+                None => (authority, None),
+            },
+        };
+
+        if host.is_empty() {
+            return Err(format!("Missing host in URL '{}'", url));
+        }
+
+        let port = match port {
+            Some(port) => port.parse().map_err(|_| format!("Invalid port in URL '{}'", url))?,
+            None if tls => 443,
+            None => 80,
+        };
+
+        Ok(Url {
+            tls,
+            host: host.to_string(),
+            port,
+            path: path.to_string(),
+        })
+    }
+
+    fn host_header(&self) -> String {
+        let host = if self.host.contains(':') { format!("[{}]", self.host) } else { self.host.clone() };
+        if self.port == if self.tls { 443 } else { 80 } {
+            host
+        } else {
+            format!("{}:{}", host, self.port)
+        }
+    }
+}
+
+#[cfg(not(any(feature = "ring", feature = "aws-lc-rs")))]
+compile_error!("outbound TLS needs a crypto provider: enable the `ring`, `aws-lc-rs` or `fips` feature");
+
+/// Crypto providers for outbound TLS built into this binary, by `--tls-provider` name; the first is the default
+pub const TLS_PROVIDERS: &[&str] = &[
+    #[cfg(feature = "fips")]
+    "fips",
+    #[cfg(feature = "aws-lc-rs")]
+    "aws-lc-rs",
+    #[cfg(feature = "ring")]
+    "ring",
+];
+
+/// The built-in provider with this name, or the default one
+pub fn tls_provider(name: Option<&str>) -> Result<&'static str, String> {
+    let Some(name) = name else {
+        return Ok(TLS_PROVIDERS[0]);
+    };
+    TLS_PROVIDERS.iter().copied().find(|provider| *provider == name)
+        .ok_or_else(|| format!("TLS provider '{}' is not built in (available: {})", name, TLS_PROVIDERS.join(", ")))
+}
+
+/// Make a provider the one all outbound TLS connections use; must be called before the first connection.
+/// Returns whether it runs in FIPS mode.
+pub fn install_tls_provider(name: &str) -> bool {
+    let provider = match name {
+        #[cfg(feature = "fips")]
+        "fips" => rustls::crypto::default_fips_provider(),
+        #[cfg(feature = "aws-lc-rs")]
+        "aws-lc-rs" => rustls::crypto::aws_lc_rs::default_provider(),
+        #[cfg(feature = "ring")]
+        "ring" => rustls::crypto::ring::default_provider(),
+        _ => unreachable!("unknown TLS provider {}", name),
+    };
+    let fips = provider.fips();
+    // Only the first installation takes effect
+    let _ = provider.install_default();
+    fips
+}
+
+/// A response read from an outbound request
+pub struct Response {
+    pub status: u16,
+    pub headers: Vec<(String, String)>,
+    /// The decoded body; None when it was cut short or malformed
+    pub body: Option<Vec<u8>>,
+}
+
+impl Response {
+    /// The value of the first header with this name (case-insensitive)
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers.iter().find(|(header, _)| header.eq_ignore_ascii_case(name)).map(|(_, value)| value.as_str())
+    }
+}
+
+/// Client TLS trusting the Mozilla roots
+pub fn tls_config() -> Arc<rustls::ClientConfig> {
+    static CONFIG: OnceLock<Arc<rustls::ClientConfig>> = OnceLock::new();
+    CONFIG.get_or_init(|| {
+        let roots = rustls::RootCertStore::from_iter(webpki_roots::TLS_SERVER_ROOTS.iter().cloned());
+        Arc::new(rustls::ClientConfig::builder().with_root_certificates(roots).with_no_client_auth())
+    }).clone()
+}
+
+pub fn tls_connector() -> tokio_rustls::TlsConnector {
+    tokio_rustls::TlsConnector::from(tls_config())
+}
+
+/// POST a JSON document and return the response
+pub async fn post_json(url: &Url, body: &str) -> Result<Response, BoxError> {
+    request(url, "POST", &[("Content-Type", "application/json")], body.as_bytes()).await
+}
+
+/// GET a document and return the response
+pub async fn get(url: &Url) -> Result<Response, BoxError> {
+    request(url, "GET", &[], &[]).await
+}
+
+/// Send a single request over a fresh connection (`Connection: close`)
+pub async fn request(url: &Url, method: &str, headers: &[(&str, &str)], body: &[u8]) -> Result<Response, BoxError> {
+    tokio::time::timeout(REQUEST_TIMEOUT, async {
+        let stream = TcpStream::connect((url.host.as_str(), url.port)).await?;
+
+        if url.tls {
+            let server_name = rustls::pki_types::ServerName::try_from(url.host.clone())?;
+            let stream = tls_connector().connect(server_name, stream).await?;
+            exchange(stream, url, method, headers, body).await
+        } else {
+            exchange(stream, url, method, headers, body).await
+        }
+    })
+    .await
+    .map_err(|_| format!("Request to {}:{} timed out", url.host, url.port))?
+}
+
+async fn exchange<S>(mut stream: S, url: &Url, method: &str, headers: &[(&str, &str)], body: &[u8]) -> Result<Response, BoxError>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let mut head = format!(
+        "{} {} HTTP/1.1\r\nHost: {}\r\nUser-Agent: reverse-http-proxy\r\nConnection: close\r\nContent-Length: {}\r\n",
+        method, url.path, url.host_header(), body.len()
+    );
+    for (name, value) in headers {
+        head.push_str(&format!("{}: {}\r\n", name, value));
+    }
+    head.push_str("\r\n");
+
+    stream.write_all(head.as_bytes()).await?;
+    stream.write_all(body).await?;
+    stream.flush().await?;
+
+    let mut raw = Vec::new();
+    let mut limited = (&mut stream).take(MAX_RESPONSE_SIZE as u64);
+    // Servers commonly drop TLS connections without close_notify, so keep whatever was received
+    if let Err(e) = limited.read_to_end(&mut raw).await {
+        if raw.is_empty() {
+            return Err(e.into());
+        }
+    }
+
+    parse_response(&raw)
+}
+
+fn parse_response(raw: &[u8]) -> Result<Response, BoxError> {
+    let mut headers = [httparse::EMPTY_HEADER; 64];
+    let mut res = httparse::Response::new(&mut headers);
+    match res.parse(raw)? {
+        httparse::Status::Complete(head_len) => {
+            let status = res.code.unwrap_or(0);
+            let headers = res.headers.iter().map(|header| (header.name.to_string(), String::from_utf8_lossy(header.value).into_owned())).collect();
+            let body = crate::intercept::framing(&raw[..head_len]).and_then(|framing| match framing.decode(&raw[head_len..], true) {
+                crate::intercept::BodyStatus::Complete(body, _) => Some(body.into_owned()),
+                _ => None,
+            });
+            Ok(Response { status, headers, body })
+        }
+        httparse::Status::Partial => Err("Incomplete response headers".into()),
+    }
+}
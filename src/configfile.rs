@@ -0,0 +1,265 @@
+//! TOML configuration files (`--config proxy.toml`). Keys are the command-line options in snake_case;
+//! options given on the command line override the file.
+
+use crate::Args;
+use clap::parser::ValueSource;
+use clap::ArgMatches;
+use serde::Deserialize;
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+
+/// Keys whose values are not expanded: `${name}` there refers to a regex capture group
+const UNEXPANDED: &[&str] = &["rewrite_rules", "sub_filters"];
+
+/// Keys named differently from their options in `Args`, with the options' names
+pub const RENAMED: &[(&str, &str)] = &[("listen", "listen_address"), ("admin", "admin_address"), ("sub_filters", "body_filters")];
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ConfigFile {
+    #[serde(rename = "listen")]
+    listen_address: Option<String>,
+    default_backend: Option<String>,
+    routes: Option<Vec<RouteEntry>>,
+    header_routes: Option<Vec<String>>,
+    query_routes: Option<Vec<String>>,
+    not_found_status: Option<u16>,
+    not_found_body: Option<String>,
+    max_header_size: Option<String>,
+    rewrite: Option<bool>,
+    preserve_host: Option<bool>,
+    lb: Option<String>,
+    zone: Option<String>,
+    probe_interval: Option<u64>,
+    dns_ttl: Option<u64>,
+    dns_server: Option<String>,
+    k8s_api: Option<String>,
+    docker: Option<String>,
+    health_check: Option<String>,
+    health_status: Option<String>,
+    health_body: Option<String>,
+    health_json: Option<Vec<String>>,
+    health_interval: Option<u64>,
+    health_timeout: Option<u64>,
+    healthy_threshold: Option<u32>,
+    unhealthy_threshold: Option<u32>,
+    health_jitter: Option<f64>,
+    health_overrides: Option<Vec<String>>,
+    backend_cert_warning: Option<u64>,
+    cert_warning: Option<u64>,
+    acme_directory: Option<String>,
+    acme_email: Option<String>,
+    acme_account_key: Option<PathBuf>,
+    acme_dns: Option<String>,
+    acme_dns_wait: Option<u64>,
+    acme_renew: Option<u64>,
+    outlier_consecutive_errors: Option<u32>,
+    outlier_error_rate: Option<f64>,
+    outlier_window: Option<u64>,
+    outlier_min_requests: Option<u64>,
+    outlier_ejection: Option<u64>,
+    outlier_max_ejection: Option<u64>,
+    retry_budget: Option<u32>,
+    rewrite_rules: Option<Vec<String>>,
+    #[serde(rename = "sub_filters")]
+    body_filters: Option<Vec<String>>,
+    set_headers: Option<Vec<String>>,
+    remove_headers: Option<Vec<String>>,
+    set_response_headers: Option<Vec<String>>,
+    remove_response_headers: Option<Vec<String>>,
+    early_hints: Option<Vec<String>>,
+    rate_limit_groups: Option<Vec<String>>,
+    slots: Option<Vec<String>>,
+    user_identity: Option<String>,
+    rate_limit_plans: Option<PathBuf>,
+    redirect_map: Option<PathBuf>,
+    custom_errors: Option<PathBuf>,
+    transparent: Option<bool>,
+    robots_txt: Option<Vec<String>>,
+    security_txt: Option<Vec<String>>,
+    host_quotas: Option<Vec<String>>,
+    psk_listen: Option<String>,
+    listeners: Option<Vec<String>>,
+    psk_keys: Option<PathBuf>,
+    tls_provider: Option<String>,
+    icap: Option<String>,
+    blocklists: Option<Vec<String>>,
+    blocklist_refresh: Option<u64>,
+    tarpit_max: Option<usize>,
+    tarpit_duration: Option<u64>,
+    asn_db: Option<PathBuf>,
+    sniff: Option<Vec<String>>,
+    #[serde(rename = "admin")]
+    admin_address: Option<String>,
+    alert_webhook: Option<String>,
+    log_format: Option<String>,
+    log_target: Option<String>,
+    syslog_facility: Option<String>,
+    syslog_tag: Option<String>,
+    access_log: Option<PathBuf>,
+    access_log_rotate: Option<String>,
+    access_log_keep: Option<usize>,
+    access_log_sink: Option<String>,
+    access_log_batch: Option<usize>,
+    access_log_queue: Option<usize>,
+    access_log_drop: Option<String>,
+    scrub: Option<bool>,
+    scrub_params: Option<Vec<String>>,
+    scrub_patterns: Option<Vec<String>>,
+    capture: Option<PathBuf>,
+    capture_rate: Option<f64>,
+    capture_routes: Option<Vec<String>>,
+    capture_status: Option<u16>,
+    capture_types: Option<Vec<String>>,
+    capture_max_body: Option<String>,
+    capture_spool: Option<String>,
+    statsd: Option<String>,
+    statsd_prefix: Option<String>,
+    statsd_tags: Option<Vec<String>>,
+    statsd_format: Option<String>,
+    statsd_interval: Option<u64>,
+    otlp_endpoint: Option<String>,
+    otel_service_name: Option<String>,
+    alert_error_rate: Option<f64>,
+    alert_latency_ms: Option<u64>,
+    alert_backend_failures: Option<u32>,
+    alert_window: Option<u64>,
+    alert_min_requests: Option<u64>,
+    metrics_summary: Option<u64>,
+    state_file: Option<PathBuf>,
+    drain_timeout: Option<u64>,
+    control_plane: Option<String>,
+    node_id: Option<String>,
+}
+
+/// A route in `-r` notation, or as a table whose other keys are route options:
+/// `{ match = "/api", backend = "127.0.0.1:4000", strip-prefix = true, priority = 10 }`
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum RouteEntry {
+    Spec(String),
+    Table {
+        #[serde(rename = "match")]
+        pattern: String,
+        backend: String,
+        #[serde(flatten)]
+        options: BTreeMap<String, toml::Value>,
+    },
+}
+
+impl RouteEntry {
+    /// The route in `-r` notation; options become `;key` (true), `;key=value`, or one `;key=value` per array item
+    fn into_spec(self) -> Result<String, String> {
+        let (pattern, backend, options) = match self {
+            RouteEntry::Spec(spec) => return Ok(spec),
+            RouteEntry::Table { pattern, backend, options } => (pattern, backend, options),
+        };
+
+        let mut spec = format!("{}={}", pattern, backend);
+        for (key, value) in options {
+            let values = match value {
+                toml::Value::Array(values) => values,
+                value => vec![value],
+            };
+            for value in values {
+                match value {
+                    toml::Value::Boolean(true) => spec.push_str(&format!(";{}", key)),
+                    toml::Value::Boolean(false) => {}
+                    toml::Value::String(value) => spec.push_str(&format!(";{}={}", key, value)),
+                    toml::Value::Integer(value) => spec.push_str(&format!(";{}={}", key, value)),
+                    _ => return Err(format!("Invalid value for option '{}' of route '{}'", key, pattern)),
+                }
+            }
+        }
+        Ok(spec)
+    }
+}
+
+impl ConfigFile {
+    pub fn load(path: &Path) -> Result<Self, String> {
+        let contents = std::fs::read_to_string(path)
+            .map_err(|e| format!("Failed to read config file {}: {}", path.display(), e))?;
+        let invalid = |e: String| format!("Invalid config file {}: {}", path.display(), e);
+        let mut table: toml::Table = toml::from_str(&contents).map_err(|e| invalid(e.to_string()))?;
+        for (key, value) in table.iter_mut() {
+            if !UNEXPANDED.contains(&key.as_str()) {
+                expand_env(value).map_err(invalid)?;
+            }
+        }
+        toml::Value::Table(table).try_into().map_err(|e: toml::de::Error| invalid(e.to_string()))
+    }
+
+    /// The keys the file accepts, in declaration order, as the `Deserialize` implementation
+    /// knows them
+    pub fn keys() -> &'static [&'static str] {
+        let mut keys = None;
+        let _ = ConfigFile::deserialize(KeyRecorder(&mut keys));
+        keys.unwrap_or_default()
+    }
+
+    /// Fill in the options that were not given on the command line
+    pub fn apply(self, args: &mut Args, matches: &ArgMatches) -> Result<(), String> {
+        let from_cli = |id: &str| matches.value_source(id) == Some(ValueSource::CommandLine);
+
+        macro_rules! merge {
+            // Options that are plain values in `Args`
+            ($($field:ident),* ; optional: $($optional:ident),*) => {
+                $(if let Some(value) = self.$field.filter(|_| !from_cli(stringify!($field))) {
+                    args.$field = value;
+                })*
+                $(if let Some(value) = self.$optional.filter(|_| !from_cli(stringify!($optional))) {
+                    args.$optional = Some(value);
+                })*
+            };
+        }
+
+        if let Some(routes) = self.routes.filter(|_| !from_cli("routes")) {
+            args.routes = routes.into_iter().map(RouteEntry::into_spec).collect::<Result<_, _>>()?;
+        }
+        merge!(
+            header_routes, query_routes, not_found_status, not_found_body, max_header_size, rewrite, preserve_host, lb, probe_interval,
+            dns_ttl, health_json, health_interval, health_timeout, healthy_threshold, unhealthy_threshold, health_jitter, health_overrides, backend_cert_warning, cert_warning,
+            acme_directory, acme_dns_wait, acme_renew, outlier_window, outlier_min_requests, outlier_ejection, outlier_max_ejection, retry_budget,
+            rewrite_rules, body_filters, set_headers, remove_headers, set_response_headers,
+            remove_response_headers, early_hints, rate_limit_groups, slots, transparent, robots_txt, security_txt, host_quotas, sniff, blocklists, blocklist_refresh, tarpit_max, tarpit_duration, alert_backend_failures,
+            alert_window, alert_min_requests, log_format, log_target, syslog_facility, syslog_tag, access_log_keep, access_log_batch, access_log_queue, access_log_drop, scrub, scrub_params, scrub_patterns,
+            capture_rate, capture_routes, capture_types, capture_max_body, capture_spool, otel_service_name,
+            statsd_prefix, statsd_tags, statsd_format, statsd_interval, drain_timeout, listeners;
+            optional: listen_address, default_backend, acme_email, acme_account_key, acme_dns, zone, dns_server, k8s_api, docker, health_check, health_status, health_body, outlier_consecutive_errors, outlier_error_rate, user_identity, rate_limit_plans, redirect_map, custom_errors, psk_listen, psk_keys, tls_provider, icap, asn_db,
+            admin_address, alert_webhook, alert_error_rate, metrics_summary, access_log, access_log_rotate, access_log_sink, capture, capture_status, statsd, otlp_endpoint, alert_latency_ms, state_file, control_plane, node_id
+        );
+        Ok(())
+    }
+}
+
+/// Expand environment variables in all strings of a value
+fn expand_env(value: &mut toml::Value) -> Result<(), String> {
+    match value {
+        toml::Value::String(s) => *s = crate::env::expand(s)?,
+        toml::Value::Array(values) => values.iter_mut().try_for_each(expand_env)?,
+        toml::Value::Table(table) => table.iter_mut().try_for_each(|(_, value)| expand_env(value))?,
+        _ => {}
+    }
+    Ok(())
+}
+
+/// A deserializer that only records the field names of the struct asked of it
+struct KeyRecorder<'a>(&'a mut Option<&'static [&'static str]>);
+
+impl<'de, 'a> serde::Deserializer<'de> for KeyRecorder<'a> {
+    type Error = serde::de::value::Error;
+
+    fn deserialize_any<V: serde::de::Visitor<'de>>(self, _visitor: V) -> Result<V::Value, Self::Error> {
+        Err(serde::de::Error::custom("not a struct"))
+    }
+
+    fn deserialize_struct<V: serde::de::Visitor<'de>>(self, _name: &'static str, fields: &'static [&'static str], _visitor: V) -> Result<V::Value, Self::Error> {
+        *self.0 = Some(fields);
+        Err(serde::de::Error::custom("keys recorded"))
+    }
+
+    serde::forward_to_deserialize_any! {
+        bool i8 i16 i32 i64 i128 u8 u16 u32 u64 u128 f32 f64 char str string bytes byte_buf option unit
+        unit_struct newtype_struct seq tuple tuple_struct map enum identifier ignored_any
+    }
+}
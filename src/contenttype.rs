@@ -0,0 +1,120 @@
+//! Detection of response body types from their first bytes, for `;content-type-guard` routes: a
+//! body its `Content-Type` does not describe, such as an uploaded HTML file served as an image, is
+//! blocked or relabelled before a browser gets to render it.
+
+use std::fmt;
+
+/// Body bytes examined
+pub const SNIFF_LEN: usize = 512;
+
+/// Binary formats recognized by their leading bytes
+const SIGNATURES: &[(&[u8], &str)] = &[
+    (b"\x89PNG\r\n\x1a\n", "image/png"),
+    (b"\xff\xd8\xff", "image/jpeg"),
+    (b"GIF87a", "image/gif"),
+    (b"GIF89a", "image/gif"),
+    (b"\x00\x00\x01\x00", "image/x-icon"),
+    (b"%PDF-", "application/pdf"),
+    (b"PK\x03\x04", "application/zip"),
+    (b"\x1f\x8b\x08", "application/gzip"),
+];
+
+/// Tags that make browsers treat an unlabelled document as HTML (from the WHATWG MIME sniffing
+/// standard), followed by a space or `>`
+const HTML_TAGS: &[&str] = &[
+    "!doctype html", "html", "head", "script", "iframe", "h1", "div", "font", "table", "a", "style",
+    "title", "b", "body", "br", "p", "!--",
+];
+
+/// Types browsers render as documents that can run scripts
+const MARKUP_TYPES: &[&str] = &["text/html", "application/xhtml+xml", "text/xml", "application/xml", "image/svg+xml"];
+
+/// A body that contradicts its `Content-Type`
+pub struct Mismatch {
+    /// The declared type without parameters, or None when the response has no `Content-Type`
+    pub declared: Option<String>,
+    /// What the body was detected as, or None when it has none of the declared type's signature
+    /// and is not otherwise recognized
+    pub detected: Option<&'static str>,
+}
+
+impl Mismatch {
+    /// A `Content-Type` the body can safely be served with: markup is shown as plain text rather
+    /// than rendered, unrecognized bodies are downloaded
+    pub fn corrected_type(&self) -> &'static str {
+        match self.detected {
+            Some(detected) if MARKUP_TYPES.contains(&detected) => "text/plain; charset=utf-8",
+            Some(detected) => detected,
+            None => "application/octet-stream",
+        }
+    }
+}
+
+impl fmt::Display for Mismatch {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let declared = self.declared.as_deref().unwrap_or("no Content-Type");
+        write!(f, "declared {}, body is {}", declared, self.detected.unwrap_or("unrecognized"))
+    }
+}
+
+/// Check a body's first bytes against its `Content-Type` header value. Markup is only accepted
+/// under markup types, and bodies of the image types with a known signature must carry it.
+pub fn check(content_type: Option<&str>, body: &[u8]) -> Option<Mismatch> {
+    let declared = content_type
+        .map(|value| value.split(';').next().unwrap_or("").trim().to_ascii_lowercase())
+        .filter(|mime| !mime.is_empty());
+    let declared_mime = declared.as_deref().map(|mime| match mime {
+        "image/jpg" | "image/pjpeg" => "image/jpeg",
+        "image/vnd.microsoft.icon" => "image/x-icon",
+        mime => mime,
+    });
+    let detected = detect(body);
+    let mismatch = |detected| Some(Mismatch { declared: declared.clone(), detected });
+
+    let is_markup_type = |mime: &str| MARKUP_TYPES.contains(&mime) || mime.ends_with("+xml");
+    match detected {
+        Some(detected) if MARKUP_TYPES.contains(&detected) => {
+            if declared_mime.is_some_and(is_markup_type) {
+                None
+            } else {
+                mismatch(Some(detected))
+            }
+        }
+        _ => {
+            let declared_mime = declared_mime?;
+            let has_signature = SIGNATURES.iter().any(|(_, mime)| *mime == declared_mime) || declared_mime == "image/webp";
+            if !has_signature || detected == Some(declared_mime) {
+                return None;
+            }
+            mismatch(detected)
+        }
+    }
+}
+
+/// The type of a body from its first bytes, if recognized
+fn detect(body: &[u8]) -> Option<&'static str> {
+    if let Some((_, mime)) = SIGNATURES.iter().find(|(signature, _)| body.starts_with(signature)) {
+        return Some(mime);
+    }
+    if body.len() >= 12 && &body[..4] == b"RIFF" && &body[8..12] == b"WEBP" {
+        return Some("image/webp");
+    }
+
+    // Markup may follow a byte order mark and whitespace
+    let text = body.strip_prefix(b"\xef\xbb\xbf").unwrap_or(body);
+    let text = &text[text.iter().position(|b| !b.is_ascii_whitespace()).unwrap_or(text.len())..];
+    let rest = text.strip_prefix(b"<")?;
+    let starts_with = |prefix: &str| rest.len() >= prefix.len() && rest[..prefix.len()].eq_ignore_ascii_case(prefix.as_bytes());
+    let is_html = HTML_TAGS.iter().any(|tag| {
+        starts_with(tag) && (*tag == "!--" || matches!(rest.get(tag.len()), Some(b' ' | b'\t' | b'\n' | b'\r' | b'>')))
+    });
+    if is_html {
+        Some("text/html")
+    } else if starts_with("svg") {
+        Some("image/svg+xml")
+    } else if starts_with("?xml") {
+        Some("text/xml")
+    } else {
+        None
+    }
+}
@@ -0,0 +1,186 @@
+//! gRPC control-plane client ("xDS-lite"): subscribes to a controller that pushes route tables.
+//! The protocol is described in `proto/control.proto`; messages are defined by hand below so no
+//! protobuf compiler is needed at build time.
+
+use crate::events::EventBus;
+use crate::logging;
+use crate::routing::{RouteConfig, SharedConfig};
+use crate::state::Snapshot;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::mpsc;
+use tokio_stream::wrappers::ReceiverStream;
+use tonic::codegen::http::uri::PathAndQuery;
+
+const STREAM_ROUTES_PATH: &str = "/reverse_http_proxy.control.v1.RouteDiscovery/StreamRoutes";
+
+/// Reconnect delay after the first failure, doubled up to `MAX_RECONNECT_DELAY`
+const INITIAL_RECONNECT_DELAY: Duration = Duration::from_secs(1);
+const MAX_RECONNECT_DELAY: Duration = Duration::from_secs(30);
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct DiscoveryRequest {
+    #[prost(string, tag = "1")]
+    pub node_id: String,
+    #[prost(string, tag = "2")]
+    pub version_info: String,
+    #[prost(string, tag = "3")]
+    pub response_nonce: String,
+    #[prost(string, tag = "4")]
+    pub error_detail: String,
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct DiscoveryResponse {
+    #[prost(string, tag = "1")]
+    pub version_info: String,
+    #[prost(string, tag = "2")]
+    pub nonce: String,
+    #[prost(message, optional, tag = "3")]
+    pub routes: Option<RouteTable>,
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct RouteTable {
+    #[prost(string, tag = "1")]
+    pub default_backend: String,
+    #[prost(string, repeated, tag = "2")]
+    pub routes: Vec<String>,
+    #[prost(string, repeated, tag = "3")]
+    pub header_routes: Vec<String>,
+    #[prost(string, repeated, tag = "4")]
+    pub query_routes: Vec<String>,
+    #[prost(bool, tag = "5")]
+    pub rewrite: bool,
+    #[prost(string, repeated, tag = "6")]
+    pub rewrite_rules: Vec<String>,
+    #[prost(bool, tag = "7")]
+    pub backend_host: bool,
+    #[prost(string, repeated, tag = "8")]
+    pub body_filters: Vec<String>,
+    #[prost(string, repeated, tag = "9")]
+    pub set_headers: Vec<String>,
+    #[prost(string, repeated, tag = "10")]
+    pub remove_headers: Vec<String>,
+    #[prost(string, repeated, tag = "11")]
+    pub set_response_headers: Vec<String>,
+    #[prost(string, repeated, tag = "12")]
+    pub remove_response_headers: Vec<String>,
+    #[prost(string, repeated, tag = "13")]
+    pub early_hints: Vec<String>,
+    #[prost(string, repeated, tag = "14")]
+    pub rate_limit_groups: Vec<String>,
+}
+
+impl RouteTable {
+    fn into_snapshot(self) -> Snapshot {
+        // proto3 has no optional strings or default-true bools: an empty default backend means none,
+        // and Host preservation is expressed as its inverse
+        let default_backend = Some(self.default_backend).filter(|backend| !backend.is_empty());
+        Snapshot {
+            default_backend,
+            routes: self.routes,
+            header_routes: self.header_routes,
+            query_routes: self.query_routes,
+            rewrite: self.rewrite,
+            rewrite_rules: self.rewrite_rules,
+            preserve_host: !self.backend_host,
+            body_filters: self.body_filters,
+            set_headers: self.set_headers,
+            remove_headers: self.remove_headers,
+            set_response_headers: self.set_response_headers,
+            remove_response_headers: self.remove_response_headers,
+            early_hints: self.early_hints,
+            rate_limit_groups: self.rate_limit_groups,
+            ..Snapshot::default()
+        }
+    }
+}
+
+/// Keep a route subscription open to the controller, reconnecting with backoff
+pub async fn run(endpoint: String, node_id: String, config: SharedConfig, bus: Arc<EventBus>) {
+    let mut delay = INITIAL_RECONNECT_DELAY;
+    // The last applied version survives reconnects, so the controller can skip unchanged tables
+    let mut version_info = String::new();
+
+    loop {
+        match subscribe(&endpoint, &node_id, &mut version_info, &config, &bus).await {
+            Ok(()) => {
+                logging::error(format!("Control plane {} closed the route stream", endpoint));
+                delay = INITIAL_RECONNECT_DELAY;
+            }
+            Err(e) => logging::error(format!("Control plane {} error: {}", endpoint, e)),
+        }
+
+        tokio::time::sleep(delay).await;
+        delay = (delay * 2).min(MAX_RECONNECT_DELAY);
+    }
+}
+
+async fn subscribe(
+    endpoint: &str,
+    node_id: &str,
+    version_info: &mut String,
+    config: &SharedConfig,
+    bus: &EventBus,
+) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    let channel = tonic::transport::Endpoint::from_shared(endpoint.to_string())?
+        .connect_timeout(Duration::from_secs(10))
+        .connect()
+        .await?;
+    let mut grpc = tonic::client::Grpc::new(channel);
+    grpc.ready().await?;
+
+    let (requests, outbound) = mpsc::channel(4);
+    requests.send(DiscoveryRequest {
+        node_id: node_id.to_string(),
+        version_info: version_info.clone(),
+        ..Default::default()
+    }).await?;
+
+    let codec: tonic::codec::ProstCodec<DiscoveryRequest, DiscoveryResponse> = tonic::codec::ProstCodec::default();
+    let mut responses = grpc
+        .streaming(tonic::Request::new(ReceiverStream::new(outbound)), PathAndQuery::from_static(STREAM_ROUTES_PATH), codec)
+        .await?
+        .into_inner();
+    println!("Subscribed to control plane {} as node '{}'", endpoint, node_id);
+
+    while let Some(response) = responses.message().await? {
+        let result = match response.routes.clone() {
+            Some(table) => RouteConfig::compile(table.into_snapshot()).await,
+            None => Err("response carries no route table".to_string()),
+        };
+
+        let error_detail = match result {
+            Ok(new_config) => {
+                config.store(Arc::new(new_config));
+                *version_info = response.version_info.clone();
+                println!("Applied route table version '{}' from control plane", response.version_info);
+                bus.publish("config_reload", serde_json::json!({
+                    "source": "control_plane",
+                    "version": response.version_info,
+                }));
+                String::new()
+            }
+            Err(e) => {
+                logging::error(format!("Rejected route table version '{}' from control plane: {}", response.version_info, e));
+                bus.publish("config_rejected", serde_json::json!({
+                    "source": "control_plane",
+                    "version": response.version_info,
+                    "error": e,
+                }));
+                e
+            }
+        };
+
+        // ACK (applied) or NACK (rejected, still on the previous version)
+        requests.send(DiscoveryRequest {
+            node_id: node_id.to_string(),
+            version_info: version_info.clone(),
+            response_nonce: response.nonce,
+            error_detail,
+        }).await?;
+    }
+
+    Ok(())
+}
@@ -0,0 +1,158 @@
+//! `reverse-http-proxy dashboard export`: a Grafana dashboard for the metrics of `GET /metrics`,
+//! built from the same names, so that it can be imported as soon as Prometheus scrapes the proxy
+
+use crate::metrics::METRIC_PREFIX;
+use serde_json::{json, Value};
+
+/// Grafana's dashboard JSON schema the export is written for
+const SCHEMA_VERSION: u32 = 39;
+
+/// Width of the dashboard grid; panels are half of it
+const GRID_WIDTH: u32 = 24;
+const PANEL_HEIGHT: u32 = 8;
+
+/// Label filters of route and backend series, from the dashboard's variables
+const ROUTE: &str = r#"route=~"$route""#;
+const BACKEND: &str = r#"backend=~"$backend""#;
+
+/// The dashboard, titled and with a fixed uid so that imports replace earlier ones
+pub fn export(title: &str, uid: &str) -> Value {
+    let prefix = METRIC_PREFIX;
+    let routed = format!("{}, {}", ROUTE, BACKEND);
+    let quantiles = |metric: &str, by: &str, filter: &str| -> Vec<Value> {
+        [("p50", 0.5), ("p95", 0.95), ("p99", 0.99)].iter()
+            .map(|(name, q)| target(
+                &format!("histogram_quantile({}, sum by (le, {}) (rate({}{}_bucket{{{}}}[$__rate_interval])))", q, by, prefix, metric, filter),
+                &format!("{{{{{}}}}} {}", by, name),
+            ))
+            .collect()
+    };
+
+    let mut panels = vec![
+        stat("Version", &format!("{}build_info", prefix), "{{version}}", "none"),
+        stat("Uptime", &format!("time() - max({}start_time_seconds)", prefix), "", "s"),
+        stat("Requests/s", &format!("sum(rate({}requests_total{{{}}}[$__rate_interval]))", prefix, routed), "", "reqps"),
+        stat("5xx responses", &format!(
+            "sum(rate({p}responses_total{{{f}, status_class=\"5xx\"}}[$__rate_interval])) / sum(rate({p}responses_total{{{f}}}[$__rate_interval]))",
+            p = prefix, f = routed,
+        ), "", "percentunit"),
+        panel("Requests per second by route", "reqps", vec![
+            target(&format!("sum by (route) (rate({}requests_total{{{}}}[$__rate_interval]))", prefix, routed), "{{route}}"),
+        ]),
+        panel("Responses by status class", "reqps", vec![
+            target(&format!("sum by (status_class) (rate({}responses_total{{{}}}[$__rate_interval]))", prefix, routed), "{{status_class}}"),
+        ]),
+        panel("5xx ratio by route", "percentunit", vec![
+            target(&format!(
+                "sum by (route) (rate({p}responses_total{{{f}, status_class=\"5xx\"}}[$__rate_interval])) / sum by (route) (rate({p}responses_total{{{f}}}[$__rate_interval]))",
+                p = prefix, f = routed,
+            ), "{{route}}"),
+        ]),
+        panel("Client aborts by route", "reqps", vec![
+            target(&format!("sum by (route) (rate({}client_aborts_total{{{}}}[$__rate_interval]))", prefix, routed), "{{route}}"),
+        ]),
+        panel("Request duration by route", "s", quantiles("request_duration_seconds", "route", &routed)),
+        panel("Time to first byte by backend", "s", quantiles("response_first_byte_duration_seconds", "backend", &routed)),
+        panel("Backend connect time", "s", quantiles("backend_connect_duration_seconds", "backend", &routed)),
+        panel("Backend connect failures", "ops", vec![
+            target(&format!("sum by (backend) (rate({}backend_connect_failures_total{{{}}}[$__rate_interval]))", prefix, BACKEND), "{{backend}}"),
+        ]),
+        panel("Backend certificate days remaining", "d", vec![
+            target(&format!("min by (backend) ({}backend_tls_certificate_days_remaining{{{}}})", prefix, BACKEND), "{{backend}}"),
+        ]),
+        panel("Throughput", "Bps", vec![
+            target(&format!("sum(rate({}request_bytes_total{{{}}}[$__rate_interval]))", prefix, routed), "to backends"),
+            target(&format!("sum(rate({}response_bytes_total{{{}}}[$__rate_interval]))", prefix, routed), "to clients"),
+        ]),
+        panel("Response size by route", "bytes", quantiles("response_size_bytes", "route", ROUTE)),
+        panel("Open client connections", "short", vec![
+            target(&format!("sum by (listener) ({}client_connections_active)", prefix), "{{listener}}"),
+        ]),
+        panel("Open backend connections", "short", vec![
+            target(&format!("sum by (backend) ({}backend_connections_active{{{}}})", prefix, BACKEND), "{{backend}}"),
+        ]),
+    ];
+
+    // Four stats across the top, then the graphs two by two
+    let (mut x, mut y, mut row_height) = (0, 0, 0);
+    for (id, panel) in panels.iter_mut().enumerate() {
+        let width = if panel["type"] == "stat" { GRID_WIDTH / 4 } else { GRID_WIDTH / 2 };
+        let height = if panel["type"] == "stat" { PANEL_HEIGHT / 2 } else { PANEL_HEIGHT };
+        if x + width > GRID_WIDTH {
+            (x, y, row_height) = (0, y + row_height, 0);
+        }
+        panel["id"] = json!(id + 1);
+        panel["gridPos"] = json!({"x": x, "y": y, "w": width, "h": height});
+        x += width;
+        row_height = row_height.max(height);
+    }
+
+    json!({
+        "title": title,
+        "uid": uid,
+        "tags": ["reverse-http-proxy"],
+        "timezone": "browser",
+        "schemaVersion": SCHEMA_VERSION,
+        "refresh": "30s",
+        "time": {"from": "now-1h", "to": "now"},
+        "templating": {"list": [
+            {"name": "datasource", "label": "Data source", "type": "datasource", "query": "prometheus"},
+            variable("route", &format!("label_values({}requests_total, route)", prefix)),
+            variable("backend", &format!("label_values({}requests_total, backend)", prefix)),
+        ]},
+        "panels": panels,
+    })
+}
+
+/// A time series panel
+fn panel(title: &str, unit: &str, mut targets: Vec<Value>) -> Value {
+    for (idx, target) in targets.iter_mut().enumerate() {
+        target["refId"] = json!(char::from(b'A' + idx as u8).to_string());
+    }
+    json!({
+        "type": "timeseries",
+        "title": title,
+        "datasource": datasource(),
+        "fieldConfig": {"defaults": {"unit": unit}, "overrides": []},
+        "options": {"legend": {"displayMode": "list", "placement": "bottom"}, "tooltip": {"mode": "multi"}},
+        "targets": targets,
+    })
+}
+
+/// A single value panel
+fn stat(title: &str, expr: &str, legend: &str, unit: &str) -> Value {
+    // The version is in the series name, not its value
+    let text = if legend.is_empty() { "value" } else { "name" };
+    json!({
+        "type": "stat",
+        "title": title,
+        "datasource": datasource(),
+        "fieldConfig": {"defaults": {"unit": unit}, "overrides": []},
+        "options": {"reduceOptions": {"calcs": ["lastNotNull"]}, "textMode": text, "colorMode": "none", "graphMode": "none"},
+        "targets": [target(expr, legend)],
+    })
+}
+
+fn target(expr: &str, legend: &str) -> Value {
+    json!({"datasource": datasource(), "expr": expr, "legendFormat": legend, "refId": "A"})
+}
+
+/// A variable of all the values a label has, all of them selected by default
+fn variable(name: &str, query: &str) -> Value {
+    json!({
+        "name": name,
+        "type": "query",
+        "datasource": datasource(),
+        "query": {"query": query, "refId": name},
+        "refresh": 2,
+        "includeAll": true,
+        "multi": true,
+        "allValue": ".*",
+        "current": {"text": "All", "value": "$__all"},
+        "sort": 1,
+    })
+}
+
+fn datasource() -> Value {
+    json!({"type": "prometheus", "uid": "${datasource}"})
+}
@@ -0,0 +1,180 @@
+//! Body checksums for `;digest` routes: request bodies are checked against their `Content-MD5`
+//! (RFC 1864), `Digest` (RFC 3230) and `Content-Digest` (RFC 9530) headers, and `Digest: sha-256=`
+//! headers are generated for requests and responses. SHA-256 comes from the outbound TLS crypto
+//! provider; MD5, which no provider offers, is implemented here.
+
+use crate::request::RequestHead;
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine;
+use std::sync::OnceLock;
+use tokio_rustls::rustls::crypto::hash::{Hash, HashAlgorithm};
+use tokio_rustls::rustls::crypto::CryptoProvider;
+
+/// Check a request body against the checksums it was sent with. Algorithms other than MD5 and
+/// SHA-256 are skipped; Err names the header that does not match.
+pub fn verify(head: &RequestHead, body: &[u8]) -> Result<(), String> {
+    if let Some(value) = head.header_str("content-md5") {
+        check("Content-MD5", "md5", value, body)?;
+    }
+    if let Some(value) = head.header_str("digest") {
+        for (algorithm, value) in value.split(',').filter_map(|part| part.split_once('=')) {
+            check("Digest", algorithm, value, body)?;
+        }
+    }
+    if let Some(value) = head.header_str("content-digest") {
+        // A structured field dictionary of byte sequences: sha-256=:BASE64:
+        for (algorithm, value) in value.split(',').filter_map(|part| part.split_once('=')) {
+            let value = value.trim().strip_prefix(':').and_then(|value| value.strip_suffix(':'))
+                .ok_or_else(|| format!("invalid Content-Digest {} value", algorithm.trim()))?;
+            check("Content-Digest", algorithm, value, body)?;
+        }
+    }
+    Ok(())
+}
+
+/// The `Digest` header value for a body
+pub fn header_value(body: &[u8]) -> String {
+    format!("sha-256={}", STANDARD.encode(sha256(body)))
+}
+
+fn check(header: &str, algorithm: &str, expected: &str, body: &[u8]) -> Result<(), String> {
+    let algorithm = algorithm.trim().to_ascii_lowercase();
+    let actual = match algorithm.as_str() {
+        "md5" => md5(body).to_vec(),
+        "sha-256" => sha256(body),
+        _ => return Ok(()),
+    };
+    let expected = STANDARD.decode(expected.trim()).map_err(|_| format!("invalid {} {} value", header, algorithm))?;
+    if expected != actual {
+        return Err(format!("{} {} mismatch", header, algorithm));
+    }
+    Ok(())
+}
+
+fn sha256(data: &[u8]) -> Vec<u8> {
+    static SHA256: OnceLock<&'static dyn Hash> = OnceLock::new();
+    let hash = SHA256.get_or_init(|| {
+        let provider = CryptoProvider::get_default().expect("the TLS crypto provider is installed at startup");
+        provider.cipher_suites.iter()
+            .filter_map(|suite| suite.tls13())
+            .map(|suite| suite.common.hash_provider)
+            .find(|hash| hash.algorithm() == HashAlgorithm::SHA256)
+            .expect("every crypto provider has a SHA-256 cipher suite")
+    });
+    hash.hash(data).as_ref().to_vec()
+}
+
+/// Per-round shift amounts
+const MD5_SHIFTS: [u32; 16] = [7, 12, 17, 22, 5, 9, 14, 20, 4, 11, 16, 23, 6, 10, 15, 21];
+
+/// `floor(abs(sin(i + 1)) * 2^32)`
+const MD5_CONSTANTS: [u32; 64] = [
+    0xd76aa478, 0xe8c7b756, 0x242070db, 0xc1bdceee,
+    0xf57c0faf, 0x4787c62a, 0xa8304613, 0xfd469501,
+    0x698098d8, 0x8b44f7af, 0xffff5bb1, 0x895cd7be,
+    0x6b901122, 0xfd987193, 0xa679438e, 0x49b40821,
+    0xf61e2562, 0xc040b340, 0x265e5a51, 0xe9b6c7aa,
+    0xd62f105d, 0x02441453, 0xd8a1e681, 0xe7d3fbc8,
+    0x21e1cde6, 0xc33707d6, 0xf4d50d87, 0x455a14ed,
+    0xa9e3e905, 0xfcefa3f8, 0x676f02d9, 0x8d2a4c8a,
+    0xfffa3942, 0x8771f681, 0x6d9d6122, 0xfde5380c,
+    0xa4beea44, 0x4bdecfa9, 0xf6bb4b60, 0xbebfbc70,
+    0x289b7ec6, 0xeaa127fa, 0xd4ef3085, 0x04881d05,
+    0xd9d4d039, 0xe6db99e5, 0x1fa27cf8, 0xc4ac5665,
+    0xf4292244, 0x432aff97, 0xab9423a7, 0xfc93a039,
+    0x655b59c3, 0x8f0ccc92, 0xffeff47d, 0x85845dd1,
+    0x6fa87e4f, 0xfe2ce6e0, 0xa3014314, 0x4e0811a1,
+    0xf7537e82, 0xbd3af235, 0x2ad7d2bb, 0xeb86d391,
+];
+
+/// MD5 (RFC 1321), for `Content-MD5` only: it detects corruption, not tampering
+fn md5(data: &[u8]) -> [u8; 16] {
+    let mut state: [u32; 4] = [0x67452301, 0xefcdab89, 0x98badcfe, 0x10325476];
+
+    // Padding: a 1 bit, zeros up to 56 bytes mod 64, then the length in bits
+    let mut message = data.to_vec();
+    message.push(0x80);
+    while message.len() % 64 != 56 {
+        message.push(0);
+    }
+    message.extend_from_slice(&((data.len() as u64).wrapping_mul(8)).to_le_bytes());
+
+    for block in message.chunks_exact(64) {
+        let words: Vec<u32> = block.chunks_exact(4).map(|w| u32::from_le_bytes([w[0], w[1], w[2], w[3]])).collect();
+        let [mut a, mut b, mut c, mut d] = state;
+        for i in 0..64 {
+            let (f, g) = match i / 16 {
+                0 => ((b & c) | (!b & d), i),
+                1 => ((d & b) | (!d & c), (5 * i + 1) % 16),
+                2 => (b ^ c ^ d, (3 * i + 5) % 16),
+                _ => (c ^ (b | !d), (7 * i) % 16),
+            };
+            let rotated = a.wrapping_add(f).wrapping_add(MD5_CONSTANTS[i]).wrapping_add(words[g])
+                .rotate_left(MD5_SHIFTS[(i / 16) * 4 + i % 4]);
+            (a, b, c, d) = (d, b.wrapping_add(rotated), b, c);
+        }
+        for (word, value) in state.iter_mut().zip([a, b, c, d]) {
+            *word = word.wrapping_add(value);
+        }
+    }
+
+    let mut digest = [0u8; 16];
+    for (bytes, word) in digest.chunks_exact_mut(4).zip(state) {
+        bytes.copy_from_slice(&word.to_le_bytes());
+    }
+    digest
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::request::MAX_HEADERS;
+
+    fn hex(digest: &[u8]) -> String {
+        digest.iter().map(|b| format!("{:02x}", b)).collect()
+    }
+
+    fn verified(headers: &str, body: &[u8]) -> Result<(), String> {
+        crate::client::install_tls_provider(crate::client::tls_provider(None).unwrap());
+        let data = format!("POST / HTTP/1.1\r\nHost: example.com\r\n{}\r\n", headers);
+        let mut storage = [httparse::EMPTY_HEADER; MAX_HEADERS];
+        verify(&RequestHead::parse(data.as_bytes(), &mut storage).unwrap(), body)
+    }
+
+    #[test]
+    fn md5_test_vectors() {
+        assert_eq!(hex(&md5(b"")), "d41d8cd98f00b204e9800998ecf8427e");
+        assert_eq!(hex(&md5(b"abc")), "900150983cd24fb0d6963f7d28e17f72");
+        assert_eq!(hex(&md5(b"The quick brown fox jumps over the lazy dog")), "9e107d9d372bb6826bd81d3542a419d6");
+        // Padding spills into a second block from 56 bytes on
+        assert_eq!(hex(&md5(&[b'a'; 56])), "3b0c8ac703f828b04c6c197006d17218");
+        assert_eq!(
+            hex(&md5(b"12345678901234567890123456789012345678901234567890123456789012345678901234567890")),
+            "57edf4a22be3c955ac49da2e2107b67a"
+        );
+    }
+
+    #[test]
+    fn digest_header_is_sha256() {
+        crate::client::install_tls_provider(crate::client::tls_provider(None).unwrap());
+        assert_eq!(header_value(b""), "sha-256=47DEQpj8HBSa+/TImW+5JCeuQeRkm5NMpJWZG3hSuFU=");
+        assert_eq!(header_value(b"hello"), "sha-256=LPJNul+wow4m6DsqxbninhsWHlwfp0JecwQzYpOLmCQ=");
+    }
+
+    #[test]
+    fn verifies_request_checksums() {
+        assert_eq!(verified("", b"hello"), Ok(()));
+        assert_eq!(verified("Content-MD5: XUFAKrxLKna5cZ2REBfFkg==\r\n", b"hello"), Ok(()));
+        assert_eq!(verified("Content-MD5: XUFAKrxLKna5cZ2REBfFkg==\r\n", b"hellO"), Err("Content-MD5 md5 mismatch".into()));
+        assert_eq!(verified("Content-MD5: not base64\r\n", b"hello"), Err("invalid Content-MD5 md5 value".into()));
+
+        let digest = "Digest: SHA-256=LPJNul+wow4m6DsqxbninhsWHlwfp0JecwQzYpOLmCQ=, unixsum=30637\r\n";
+        assert_eq!(verified(digest, b"hello"), Ok(()));
+        assert_eq!(verified(digest, b"world"), Err("Digest sha-256 mismatch".into()));
+
+        let content_digest = "Content-Digest: sha-512=:AAAA:, sha-256=:LPJNul+wow4m6DsqxbninhsWHlwfp0JecwQzYpOLmCQ=:\r\n";
+        assert_eq!(verified(content_digest, b"hello"), Ok(()));
+        assert_eq!(verified(content_digest, b""), Err("Content-Digest sha-256 mismatch".into()));
+        assert_eq!(verified("Content-Digest: sha-256=LPJNul==\r\n", b"hello"), Err("invalid Content-Digest sha-256 value".into()));
+    }
+}
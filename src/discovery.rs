@@ -0,0 +1,375 @@
+//! Backend discovery: route targets whose backends are looked up elsewhere and follow its changes.
+//! `srv:_http._tcp.api.service.consul` is a pool of the host:port pairs its DNS SRV records name,
+//! weighted by their weights; records of the lowest priority take the traffic, those of the next
+//! one only once none of these can. `k8s:NAMESPACE/SERVICE:PORT` is a pool of the ready addresses
+//! of a Kubernetes service (see `k8s`). `@PATH` is a pool of the backends an upstream file lists,
+//! one `BACKEND[:WEIGHT]` per line.
+//!
+//! Backends are looked up when the route table is loaded, then SRV records again after their TTL,
+//! Kubernetes Endpoints watched and upstream files read again when they are modified; when a set
+//! changes, the route table is rebuilt. A lookup that fails or comes back empty keeps the set of
+//! the last one.
+
+use crate::events::EventBus;
+use crate::k8s;
+use crate::logging;
+use crate::routing::{Backend, RouteConfig, SharedConfig};
+use crate::{dns, pool};
+use std::collections::{HashMap, HashSet};
+use std::net::{SocketAddr, ToSocketAddrs};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, OnceLock};
+use std::time::{Duration, Instant, SystemTime};
+
+/// Prefixes of route targets discovered through SRV records, Kubernetes Endpoints and upstream files
+pub const SRV_PREFIX: &str = "srv:";
+pub const K8S_PREFIX: &str = "k8s:";
+pub const FILE_PREFIX: &str = "@";
+
+/// Least time between two lookups of an SRV name, for records with a short TTL or none (Consul's
+/// default), and between attempts at a source that failed
+const MIN_REFRESH: Duration = Duration::from_secs(5);
+
+/// How often sources are checked for due lookups and changes
+const CHECK_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Highest weight a record gets in the pool, as for configured weights
+const MAX_WEIGHT: u32 = 10_000;
+
+/// A discovered backend
+#[derive(Clone, PartialEq)]
+pub struct Member {
+    /// `target:port`, or `ip:port`
+    pub name: String,
+    pub addr: SocketAddr,
+    pub weight: u32,
+    pub priority: u16,
+    /// Listed by host name in an upstream file, and looked up again when connected to like
+    /// configured backends; other addresses come from the source, and change with it
+    pub named: bool,
+}
+
+impl Member {
+    pub fn backend(&self) -> Backend {
+        Backend { name: self.name.as_str().into(), addr: self.addr, named: self.named }
+    }
+}
+
+/// Where a pool's backends are discovered
+enum Source {
+    Srv(String),
+    Kubernetes(k8s::Target),
+    File(PathBuf),
+}
+
+impl Source {
+    fn parse(spec: &str) -> Result<Self, String> {
+        if let Some(name) = spec.strip_prefix(SRV_PREFIX) {
+            return Ok(Source::Srv(name.to_string()));
+        }
+        if let Some(path) = spec.strip_prefix(FILE_PREFIX) {
+            return Ok(Source::File(PathBuf::from(path)));
+        }
+        let target = spec.strip_prefix(K8S_PREFIX).ok_or_else(|| format!("Unknown discovery target '{}'", spec))?;
+        k8s::Target::parse(target).map(Source::Kubernetes)
+    }
+
+    /// Look the members up, which blocks; with when to look again, None for sources that are watched
+    fn look_up(&self) -> Result<(Vec<Member>, Option<Duration>), String> {
+        match self {
+            Source::Srv(name) => look_up_srv(name).map(|(members, ttl)| (members, Some(ttl.max(MIN_REFRESH)))),
+            Source::Kubernetes(target) => k8s::list(target).map(|(members, _)| (members, None)),
+            Source::File(path) => read_file(path).map(|members| (members, None)),
+        }
+    }
+
+    /// The modification time of an upstream file, None for other sources and missing files
+    fn modified(&self) -> Option<SystemTime> {
+        match self {
+            Source::File(path) => std::fs::metadata(path).and_then(|m| m.modified()).ok(),
+            _ => None,
+        }
+    }
+}
+
+/// Whether a route target is discovered
+pub fn is_discovered(spec: &str) -> bool {
+    spec.starts_with(SRV_PREFIX) || spec.starts_with(K8S_PREFIX) || spec.starts_with(FILE_PREFIX)
+}
+
+struct Discovered {
+    source: Source,
+    /// Sorted by priority and name, so changes show as such whatever order the answers come in
+    members: Vec<Member>,
+    /// When an SRV name is looked up again; None for watched sources
+    refresh_at: Option<Instant>,
+    /// Set apart from other entries of the same target that came and went, for its watcher
+    id: u64,
+    /// A watcher thread follows the source
+    watched: bool,
+    /// When an upstream file was modified before it was last read
+    modified: Option<SystemTime>,
+    /// The latest lookup failed
+    failing: bool,
+    /// The members changed since the route table was last rebuilt
+    changed: bool,
+}
+
+fn registry() -> &'static Mutex<HashMap<String, Discovered>> {
+    static REGISTRY: OnceLock<Mutex<HashMap<String, Discovered>>> = OnceLock::new();
+    REGISTRY.get_or_init(Mutex::default)
+}
+
+/// The members of a discovered route target, from the latest lookup; the first use of a target
+/// looks it up, which blocks, and fails if it finds no backends
+pub fn members(spec: &str, route: &str) -> Result<Vec<Member>, String> {
+    if let Some(discovered) = registry().lock().unwrap().get(spec) {
+        return Ok(discovered.members.clone());
+    }
+    let failed = |e: String| format!("Failed to discover backends of route '{}' from {}: {}", route, spec, e);
+    let source = Source::parse(spec)?;
+    // Before the read, so that a change during it is read again
+    let modified = source.modified();
+    let (members, refresh) = source.look_up().map_err(failed)?;
+    static IDS: AtomicU64 = AtomicU64::new(0);
+    let discovered = Discovered {
+        source,
+        members: members.clone(),
+        refresh_at: refresh.map(|refresh| Instant::now() + refresh),
+        id: IDS.fetch_add(1, Ordering::Relaxed),
+        watched: false,
+        modified,
+        failing: false,
+        changed: false,
+    };
+    registry().lock().unwrap().insert(spec.to_string(), discovered);
+    Ok(members)
+}
+
+/// Look the SRV records of a name up, with the addresses of their targets
+fn look_up_srv(name: &str) -> Result<(Vec<Member>, Duration), String> {
+    if name.is_empty() {
+        return Err("no name given".to_string());
+    }
+    let (records, ttl) = dns::query_srv(name).map_err(|e| e.to_string())?;
+    let mut members = Vec::new();
+    for record in records {
+        if record.port == 0 || record.target.is_empty() {
+            continue; // "." as target: the service is decidedly not available there
+        }
+        let addr = match record.addrs.first() {
+            Some(&ip) => SocketAddr::new(ip, record.port),
+            None => match (record.target.as_str(), record.port).to_socket_addrs().map(|mut addrs| addrs.next()) {
+                Ok(Some(addr)) => addr,
+                Ok(None) | Err(_) => {
+                    logging::warning(format!("Skipping {}:{} of {}{}: the target does not resolve", record.target, record.port, SRV_PREFIX, name));
+                    continue;
+                }
+            },
+        };
+        let name = format!("{}:{}", record.target, record.port);
+        if members.iter().any(|m: &Member| m.name == name) {
+            continue;
+        }
+        // Weight 0 is for targets that should get little of the traffic, not none (RFC 2782)
+        let weight = u32::from(record.weight).clamp(1, MAX_WEIGHT);
+        members.push(Member { name, addr, weight, priority: record.priority, named: false });
+    }
+    if members.is_empty() {
+        return Err("no usable SRV records".to_string());
+    }
+    members.sort_by(|a, b| (a.priority, &a.name).cmp(&(b.priority, &b.name)));
+    Ok((members, ttl))
+}
+
+/// Read the backends an upstream file lists: `BACKEND[:WEIGHT]` per line, as in routes, with blank
+/// lines and `#` comments ignored
+fn read_file(path: &Path) -> Result<Vec<Member>, String> {
+    let contents = std::fs::read_to_string(path).map_err(|e| e.to_string())?;
+    let mut members = Vec::new();
+    for (idx, line) in contents.lines().enumerate() {
+        let line = line.split('#').next().unwrap_or("").trim();
+        if line.is_empty() {
+            continue;
+        }
+        let invalid = |e: String| format!("line {}: {}", idx + 1, e);
+        let (address, weight) = pool::split_weight(line);
+        let weight = match weight {
+            Some(weight) => pool::parse_weight(weight).ok_or_else(|| invalid(format!("invalid weight '{}' of backend '{}'", weight, address)))?,
+            None => pool::DEFAULT_WEIGHT,
+        };
+        let (addr, named) = match address.parse() {
+            Ok(addr) => (addr, false),
+            Err(_) => {
+                let addr = address.to_socket_addrs().map_err(|e| invalid(format!("invalid backend '{}': {}", address, e)))?
+                    .next()
+                    .ok_or_else(|| invalid(format!("backend '{}' resolves to no addresses", address)))?;
+                (addr, true)
+            }
+        };
+        if members.iter().any(|m: &Member| m.name == address) {
+            return Err(invalid(format!("backend '{}' is listed twice", address)));
+        }
+        members.push(Member { name: address.to_string(), addr, weight, priority: 0, named });
+    }
+    if members.is_empty() {
+        return Err("no backends listed".to_string());
+    }
+    if members.iter().all(|m| m.weight == 0) {
+        return Err("no backend has a weight above 0".to_string());
+    }
+    members.sort_by(|a, b| a.name.cmp(&b.name));
+    Ok(members)
+}
+
+/// Record the outcome of a lookup of a target; false when the entry it was made for is gone
+fn update(spec: &str, id: u64, answer: Result<Vec<Member>, String>) -> bool {
+    let mut registry = registry().lock().unwrap();
+    let Some(discovered) = registry.get_mut(spec).filter(|discovered| discovered.id == id) else {
+        return false;
+    };
+    match answer {
+        Ok(members) => {
+            if discovered.failing {
+                logging::info(format!("{} resolves again", spec));
+                discovered.failing = false;
+            }
+            if members != discovered.members {
+                logging::info(format!("{} now has {}", spec, list(&members)));
+                discovered.members = members;
+                discovered.changed = true;
+            }
+        }
+        Err(e) => {
+            if !discovered.failing {
+                logging::warning(format!("Failed to look up {}, keeping {}: {}", spec, list(&discovered.members), e));
+                discovered.failing = true;
+            }
+        }
+    }
+    true
+}
+
+/// Follow a Kubernetes target until its entry is gone: list its Endpoints, watch them from there,
+/// and list again when the watch ends
+fn watch(spec: String, target: k8s::Target, id: u64) {
+    loop {
+        let version = match k8s::list(&target) {
+            Ok((members, version)) => {
+                if !update(&spec, id, Ok(members)) {
+                    return;
+                }
+                version
+            }
+            Err(e) => {
+                if !update(&spec, id, Err(e)) {
+                    return;
+                }
+                std::thread::sleep(MIN_REFRESH);
+                continue;
+            }
+        };
+        let mut current = true;
+        let watched = k8s::watch(&target, &version, |members| {
+            current = update(&spec, id, members);
+            current
+        });
+        if !current {
+            return;
+        }
+        // A watch from a version the server no longer has is normal; listing again catches up
+        if watched.is_err() {
+            std::thread::sleep(CHECK_INTERVAL);
+        }
+    }
+}
+
+/// Look discovered targets of the routes up again as they are due, keep their watchers running,
+/// and swap in a rebuilt route table when a set of backends changed
+pub async fn run(config: SharedConfig, bus: Arc<EventBus>) {
+    let mut interval = tokio::time::interval(CHECK_INTERVAL);
+    loop {
+        interval.tick().await;
+        let mut due = Vec::new();
+        {
+            let in_use: HashSet<Arc<str>> = config.load().discovered_names().into_iter().collect();
+            let mut registry = registry().lock().unwrap();
+            // Targets of routes since removed are forgotten, and their watchers stop
+            registry.retain(|spec, _| in_use.contains(spec.as_str()));
+            let now = Instant::now();
+            for (spec, discovered) in registry.iter_mut() {
+                match &discovered.source {
+                    Source::Srv(_) if discovered.refresh_at.is_some_and(|at| at <= now) => due.push((spec.clone(), discovered.id)),
+                    Source::File(_) => {
+                        let modified = discovered.source.modified();
+                        if modified != discovered.modified {
+                            discovered.modified = modified;
+                            due.push((spec.clone(), discovered.id));
+                        }
+                    }
+                    Source::Kubernetes(target) if !discovered.watched => {
+                        discovered.watched = true;
+                        let (watched, target, id) = (spec.clone(), target.clone(), discovered.id);
+                        let started = std::thread::Builder::new().name("k8s-watch".to_string()).spawn(move || watch(watched, target, id));
+                        if let Err(e) = started {
+                            logging::error(format!("Failed to start watching {}: {}", spec, e));
+                            discovered.watched = false;
+                        }
+                    }
+                    _ => {}
+                }
+            }
+        }
+
+        for (spec, id) in due {
+            let answer = {
+                let spec = spec.clone();
+                tokio::task::spawn_blocking(move || {
+                    let source = Source::parse(&spec)?;
+                    source.look_up()
+                }).await.unwrap_or_else(|e| Err(e.to_string()))
+            };
+            let refresh = answer.as_ref().ok().and_then(|(_, refresh)| *refresh).unwrap_or(MIN_REFRESH);
+            if update(&spec, id, answer.map(|(members, _)| members)) {
+                if let Some(discovered) = registry().lock().unwrap().get_mut(&spec).filter(|discovered| discovered.refresh_at.is_some()) {
+                    discovered.refresh_at = Some(Instant::now() + refresh);
+                }
+            }
+        }
+
+        let changed: Vec<String> = registry().lock().unwrap().iter_mut()
+            .filter_map(|(spec, discovered)| std::mem::take(&mut discovered.changed).then(|| spec.clone()))
+            .collect();
+        if changed.is_empty() {
+            continue;
+        }
+        match RouteConfig::compile(config.load().snapshot()).await {
+            Ok(new_config) => {
+                bus.publish("config_reload", serde_json::json!({
+                    "source": "discovery",
+                    "names": changed,
+                }));
+                config.store(Arc::new(new_config));
+            }
+            Err(e) => {
+                logging::warning(format!("Keeping previous configuration: {}", e));
+                bus.publish("config_rejected", serde_json::json!({
+                    "source": "discovery",
+                    "names": changed,
+                    "error": e,
+                }));
+            }
+        }
+    }
+}
+
+fn list(members: &[Member]) -> String {
+    members.iter()
+        .map(|m| match m.name == m.addr.to_string() {
+            true => format!("{} (weight {}, priority {})", m.name, m.weight, m.priority),
+            false => format!("{} ({}, weight {}, priority {})", m.name, m.addr, m.weight, m.priority),
+        })
+        .collect::<Vec<_>>()
+        .join(", ")
+}
@@ -0,0 +1,275 @@
+//! A minimal DNS client for what the system resolver does not answer: SRV records. Queries go to
+//! the first nameserver of `/etc/resolv.conf`, or `--dns-server`, over UDP, and again over TCP
+//! when the answer is truncated.
+
+use std::io::{self, Read, Write};
+use std::net::{IpAddr, SocketAddr, TcpStream, UdpSocket};
+use std::sync::OnceLock;
+use std::time::Duration;
+
+/// Longest a query may take, per transport
+const QUERY_TIMEOUT: Duration = Duration::from_secs(3);
+
+const TYPE_A: u16 = 1;
+const TYPE_AAAA: u16 = 28;
+const TYPE_SRV: u16 = 33;
+const CLASS_IN: u16 = 1;
+
+static NAMESERVER: OnceLock<SocketAddr> = OnceLock::new();
+
+/// Send queries to this nameserver instead of the system's; only the first call takes effect
+pub fn set_nameserver(addr: SocketAddr) {
+    let _ = NAMESERVER.set(addr);
+}
+
+fn nameserver() -> SocketAddr {
+    *NAMESERVER.get_or_init(|| {
+        let configured = std::fs::read_to_string("/etc/resolv.conf").ok().and_then(|conf| {
+            conf.lines()
+                .filter_map(|line| line.trim().strip_prefix("nameserver"))
+                .find_map(|server| server.trim().parse::<IpAddr>().ok())
+        });
+        SocketAddr::new(configured.unwrap_or([127, 0, 0, 1].into()), 53)
+    })
+}
+
+/// An SRV record: a target host and port, with its priority (lower first) and weight among its priority
+pub struct SrvRecord {
+    pub priority: u16,
+    pub weight: u16,
+    pub port: u16,
+    pub target: String,
+    /// Addresses of the target given along with the answer, as Consul does; empty when not given
+    pub addrs: Vec<IpAddr>,
+}
+
+/// Look up the SRV records of a name, with the lowest TTL among them. This blocks.
+pub fn query_srv(name: &str) -> io::Result<(Vec<SrvRecord>, Duration)> {
+    let id = (crate::otel::random_id() & 0xffff) as u16;
+    let query = encode_query(id, name)?;
+    let server = nameserver();
+
+    let socket = UdpSocket::bind(if server.is_ipv4() { "0.0.0.0:0" } else { "[::]:0" })?;
+    socket.set_read_timeout(Some(QUERY_TIMEOUT))?;
+    socket.connect(server)?;
+    socket.send(&query)?;
+    let mut response = vec![0u8; 4096];
+    let response = loop {
+        let n = socket.recv(&mut response).map_err(|e| timed_out(e, server))?;
+        // Answers to other queries, or forged ones, are skipped
+        if n >= 12 && response[..2] == id.to_be_bytes() {
+            break response[..n].to_vec();
+        }
+    };
+    let response = if response[2] & 0x02 != 0 { query_tcp(server, &query)? } else { response };
+    decode_srv(&response, id)
+}
+
+fn timed_out(e: io::Error, server: SocketAddr) -> io::Error {
+    match e.kind() {
+        io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut => io::Error::new(io::ErrorKind::TimedOut, format!("no answer from nameserver {}", server)),
+        _ => e,
+    }
+}
+
+/// Send a query over TCP, for answers too large for UDP
+fn query_tcp(server: SocketAddr, query: &[u8]) -> io::Result<Vec<u8>> {
+    let mut stream = TcpStream::connect_timeout(&server, QUERY_TIMEOUT)?;
+    stream.set_read_timeout(Some(QUERY_TIMEOUT))?;
+    stream.write_all(&(query.len() as u16).to_be_bytes())?;
+    stream.write_all(query)?;
+    let mut len = [0u8; 2];
+    stream.read_exact(&mut len).map_err(|e| timed_out(e, server))?;
+    let mut response = vec![0u8; u16::from_be_bytes(len) as usize];
+    stream.read_exact(&mut response).map_err(|e| timed_out(e, server))?;
+    Ok(response)
+}
+
+fn encode_query(id: u16, name: &str) -> io::Result<Vec<u8>> {
+    let mut query = Vec::with_capacity(18 + name.len());
+    query.extend_from_slice(&id.to_be_bytes());
+    query.extend_from_slice(&[0x01, 0x00]); // recursion desired
+    query.extend_from_slice(&[0, 1, 0, 0, 0, 0, 0, 0]); // one question
+    encode_name(&mut query, name)?;
+    query.extend_from_slice(&TYPE_SRV.to_be_bytes());
+    query.extend_from_slice(&CLASS_IN.to_be_bytes());
+    Ok(query)
+}
+
+/// Append a name in wire format, uncompressed; a trailing dot is optional
+pub fn encode_name(out: &mut Vec<u8>, name: &str) -> io::Result<()> {
+    for label in name.trim_end_matches('.').split('.') {
+        if label.is_empty() || label.len() > 63 {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, format!("invalid name '{}'", name)));
+        }
+        out.push(label.len() as u8);
+        out.extend_from_slice(label.as_bytes());
+    }
+    out.push(0);
+    Ok(())
+}
+
+fn decode_srv(message: &[u8], id: u16) -> io::Result<(Vec<SrvRecord>, Duration)> {
+    let invalid = || io::Error::new(io::ErrorKind::InvalidData, "malformed DNS response");
+    if message.len() < 12 || message[..2] != id.to_be_bytes() {
+        return Err(invalid());
+    }
+    match message[3] & 0x0f {
+        0 => {}
+        3 => return Err(io::Error::new(io::ErrorKind::NotFound, "no such name")),
+        rcode => return Err(io::Error::new(io::ErrorKind::Other, format!("nameserver answered with error {}", rcode))),
+    }
+    let u16_at = |pos: usize| message.get(pos..pos + 2).map(|b| u16::from_be_bytes([b[0], b[1]])).ok_or_else(invalid);
+    let questions = u16_at(4)?;
+    let answers = usize::from(u16_at(6)?);
+    // Authority and additional records follow; the latter may give the targets' addresses
+    let records_total = answers + usize::from(u16_at(8)?) + usize::from(u16_at(10)?);
+
+    let mut pos = 12;
+    for _ in 0..questions {
+        pos = skip_name(message, pos).ok_or_else(invalid)? + 4;
+    }
+    let mut records = Vec::new();
+    let mut addresses: Vec<(String, IpAddr)> = Vec::new();
+    let mut ttl = u32::MAX;
+    for idx in 0..records_total {
+        let owner = pos;
+        pos = skip_name(message, pos).ok_or_else(invalid)?;
+        let (kind, class) = (u16_at(pos)?, u16_at(pos + 2)?);
+        let record_ttl = message.get(pos + 4..pos + 8).map(|b| u32::from_be_bytes([b[0], b[1], b[2], b[3]])).ok_or_else(invalid)?;
+        let length = u16_at(pos + 8)? as usize;
+        let data = pos + 10;
+        pos = data + length;
+        if pos > message.len() {
+            return Err(invalid());
+        }
+        if class != CLASS_IN {
+            continue;
+        }
+        // CNAMEs on the way to the records are followed by the nameserver, and skipped here
+        match (kind, length) {
+            (TYPE_SRV, _) if length >= 7 && idx < answers => {
+                let target = read_name(message, data + 6).ok_or_else(invalid)?;
+                records.push(SrvRecord { priority: u16_at(data)?, weight: u16_at(data + 2)?, port: u16_at(data + 4)?, target, addrs: Vec::new() });
+                ttl = ttl.min(record_ttl);
+            }
+            (TYPE_A, 4) => {
+                let ip: [u8; 4] = message[data..pos].try_into().map_err(|_| invalid())?;
+                addresses.push((read_name(message, owner).ok_or_else(invalid)?, IpAddr::from(ip)));
+            }
+            (TYPE_AAAA, 16) => {
+                let ip: [u8; 16] = message[data..pos].try_into().map_err(|_| invalid())?;
+                addresses.push((read_name(message, owner).ok_or_else(invalid)?, IpAddr::from(ip)));
+            }
+            _ => {}
+        }
+    }
+    for record in &mut records {
+        record.addrs = addresses.iter().filter(|(name, _)| name.eq_ignore_ascii_case(&record.target)).map(|(_, ip)| *ip).collect();
+    }
+    let ttl = if records.is_empty() { 0 } else { ttl };
+    Ok((records, Duration::from_secs(u64::from(ttl))))
+}
+
+/// The position after a name, which may end in a compression pointer
+pub fn skip_name(message: &[u8], mut pos: usize) -> Option<usize> {
+    loop {
+        let len = *message.get(pos)?;
+        match len {
+            0 => return Some(pos + 1),
+            len if len & 0xc0 == 0xc0 => return Some(pos + 2),
+            len => pos += 1 + len as usize,
+        }
+    }
+}
+
+/// Read a name, following compression pointers
+pub fn read_name(message: &[u8], mut pos: usize) -> Option<String> {
+    let mut labels: Vec<String> = Vec::new();
+    // Pointers only lead backwards in well-formed messages; a bound guards against loops
+    for _ in 0..128 {
+        let len = *message.get(pos)?;
+        match len {
+            0 => return Some(labels.join(".")),
+            len if len & 0xc0 == 0xc0 => pos = (usize::from(len & 0x3f) << 8) | usize::from(*message.get(pos + 1)?),
+            len => {
+                let label = message.get(pos + 1..pos + 1 + len as usize)?;
+                labels.push(String::from_utf8_lossy(label).into_owned());
+                pos += 1 + len as usize;
+            }
+        }
+    }
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A resource record: owner name bytes, type, TTL and data
+    fn record(owner: &[u8], kind: u16, ttl: u32, data: &[u8]) -> Vec<u8> {
+        let mut record = owner.to_vec();
+        record.extend_from_slice(&kind.to_be_bytes());
+        record.extend_from_slice(&CLASS_IN.to_be_bytes());
+        record.extend_from_slice(&ttl.to_be_bytes());
+        record.extend_from_slice(&(data.len() as u16).to_be_bytes());
+        record.extend_from_slice(data);
+        record
+    }
+
+    /// An answer to the query for `_http._tcp.web` with the given answer and additional records
+    fn response(answers: &[Vec<u8>], additional: &[Vec<u8>]) -> Vec<u8> {
+        let mut message = encode_query(0x1234, "_http._tcp.web").unwrap();
+        message[2..4].copy_from_slice(&[0x81, 0x80]);
+        message[6..8].copy_from_slice(&(answers.len() as u16).to_be_bytes());
+        message[10..12].copy_from_slice(&(additional.len() as u16).to_be_bytes());
+        for record in answers.iter().chain(additional) {
+            message.extend_from_slice(record);
+        }
+        message
+    }
+
+    #[test]
+    fn encodes_queries() {
+        let query = encode_query(0xabcd, "_http._tcp.web.").unwrap();
+        assert_eq!(&query[..12], [0xab, 0xcd, 0x01, 0x00, 0, 1, 0, 0, 0, 0, 0, 0]);
+        assert_eq!(&query[12..], b"\x05_http\x04_tcp\x03web\x00\x00\x21\x00\x01");
+        assert!(encode_query(1, "a..b").is_err());
+        assert!(encode_query(1, &"x".repeat(64)).is_err());
+    }
+
+    #[test]
+    fn decodes_srv_answers_with_their_addresses() {
+        // The question's name is at offset 12; the first target's name follows the first SRV's data
+        let first = record(&[0xc0, 12], TYPE_SRV, 300, b"\x00\x0a\x00\x05\x1f\x90\x01a\x03web\x00");
+        let target = 12 + 16 + 4 + first.len() - 7;
+        let second = record(&[0xc0, 12], TYPE_SRV, 60, &[0, 20, 0, 0, 0x1f, 0x91, 1, b'b', 0xc0, target as u8 + 2]);
+        let address = record(&[0xc0, target as u8], TYPE_A, 30, &[10, 0, 0, 1]);
+        let message = response(&[first, second], &[address]);
+
+        let (records, ttl) = decode_srv(&message, 0x1234).unwrap();
+        assert_eq!(ttl, Duration::from_secs(60));
+        let summary: Vec<_> = records.iter().map(|r| (r.priority, r.weight, r.port, r.target.as_str(), r.addrs.clone())).collect();
+        assert_eq!(summary, [(10, 5, 8080, "a.web", vec![IpAddr::from([10, 0, 0, 1])]), (20, 0, 8081, "b.web", vec![])]);
+    }
+
+    #[test]
+    fn rejects_errors_and_malformed_answers() {
+        let mut message = response(&[], &[]);
+        assert!(decode_srv(&message, 0x4321).is_err());
+        assert_eq!(decode_srv(&message, 0x1234).unwrap().1, Duration::ZERO);
+        message[3] = 0x83;
+        assert_eq!(decode_srv(&message, 0x1234).err().unwrap().kind(), io::ErrorKind::NotFound);
+
+        let srv = record(&[0xc0, 12], TYPE_SRV, 300, b"\x00\x0a\x00\x05\x1f\x90\x01a\x00");
+        let message = response(&[srv], &[]);
+        assert!(decode_srv(&message[..message.len() - 3], 0x1234).is_err());
+
+        // A compression pointer to itself
+        let looping = record(&[0xc0, 12], TYPE_SRV, 300, &[0, 1, 0, 1, 0, 80, 0xc0, 0]);
+        let mut message = response(&[looping], &[]);
+        let at = message.len() - 2;
+        message[at + 1] = at as u8;
+        assert!(decode_srv(&message, 0x1234).is_err());
+    }
+}
@@ -0,0 +1,324 @@
+//! Routes from Docker container labels (`--docker`): the proxy lists the running containers over
+//! the Docker socket, and every container with a `proxy.path` or `proxy.host` label gets a route to
+//! its address. Containers asking for the same route share it as a pool, so that a service scaled
+//! up takes the new containers in. The daemon's events are watched, so routes come and go as
+//! containers start and stop.
+//!
+//! | Label | Meaning |
+//! |-------|---------|
+//! | `proxy.path` | Path prefix of the route (`/api`) |
+//! | `proxy.host` | Host of the route (`app.localhost`), with or without a path |
+//! | `proxy.port` | Container port to connect to; default the one port it exposes |
+//! | `proxy.network` | Network whose address of the container is used; default its first |
+//! | `proxy.options` | Route options, as after `;` in `-r` (`strip-prefix;retry=2`) |
+//!
+//! Generated routes are added after the configured ones, are not part of the snapshot and must
+//! not conflict with other routes; those that do are left out with a warning.
+
+use crate::blocking::{self, Body};
+use crate::events::EventBus;
+use crate::logging;
+use crate::routing::{RouteConfig, SharedConfig};
+use crate::state::Snapshot;
+use serde_json::Value;
+use std::collections::{BTreeMap, HashSet};
+use std::io::{BufRead, BufReader, Read};
+use std::sync::{Arc, Mutex, OnceLock};
+use std::time::Duration;
+
+/// The socket of a local Docker daemon
+pub const DEFAULT_SOCKET: &str = "/var/run/docker.sock";
+
+const LABEL_PREFIX: &str = "proxy.";
+
+/// Longest a list of containers may take
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Time between attempts at a daemon that cannot be reached
+const RETRY_INTERVAL: Duration = Duration::from_secs(5);
+
+/// How often the routes are checked for changes
+const CHECK_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Container events that may change the routes
+const EVENTS: &str = r#"{"type":["container"],"event":["start","die","stop","kill","pause","unpause","health_status"]}"#;
+
+#[derive(Default)]
+struct Routes {
+    /// The routes the running containers ask for, with the containers asking
+    wanted: Vec<(String, Vec<String>)>,
+    /// Those of them in the route table
+    accepted: Vec<String>,
+    /// Routes left out, so that each is only warned about once
+    rejected: HashSet<String>,
+    /// Why containers with labels get no route, likewise
+    skipped: HashSet<String>,
+    /// `wanted` changed since the route table was last rebuilt
+    changed: bool,
+    /// The latest attempt to list the containers failed
+    failing: bool,
+}
+
+fn state() -> &'static Mutex<Routes> {
+    static STATE: OnceLock<Mutex<Routes>> = OnceLock::new();
+    STATE.get_or_init(Mutex::default)
+}
+
+/// The routes generated from container labels, for the route table; empty without `--docker`
+pub fn routes() -> Vec<String> {
+    state().lock().unwrap().accepted.clone()
+}
+
+/// List the containers once, which blocks, and take in the routes they ask for that fit beside
+/// those of the snapshot
+pub fn start(socket: &str, snapshot: &Snapshot) -> Result<(), String> {
+    let listing = list(socket).map_err(|e| format!("Failed to list Docker containers: {}", e))?;
+    update(Ok(listing));
+    let mut state = state().lock().unwrap();
+    state.changed = false;
+    accept(&mut state, snapshot);
+    Ok(())
+}
+
+/// Watch the daemon for container changes on a thread of its own, and swap in a rebuilt route
+/// table when the routes change
+pub async fn run(socket: String, config: SharedConfig, bus: Arc<EventBus>) {
+    let watched = socket.clone();
+    if let Err(e) = std::thread::Builder::new().name("docker-watch".to_string()).spawn(move || watch(&watched)) {
+        logging::error(format!("Failed to start watching Docker at {}: {}", socket, e));
+        return;
+    }
+    let mut interval = tokio::time::interval(CHECK_INTERVAL);
+    loop {
+        interval.tick().await;
+        let snapshot = config.load().snapshot();
+        // Trying the routes compiles route tables, which resolves backend host names and blocks
+        let changed = tokio::task::spawn_blocking(move || {
+            let mut state = state().lock().unwrap();
+            if !std::mem::take(&mut state.changed) {
+                return None;
+            }
+            let before = state.accepted.clone();
+            accept(&mut state, &snapshot);
+            if state.accepted == before {
+                return None;
+            }
+            Some((state.accepted.clone(), snapshot))
+        }).await;
+        let Ok(Some((routes, snapshot))) = changed else {
+            continue;
+        };
+        match RouteConfig::compile(snapshot).await {
+            Ok(new_config) => {
+                bus.publish("config_reload", serde_json::json!({
+                    "source": "docker",
+                    "routes": routes,
+                }));
+                config.store(Arc::new(new_config));
+            }
+            Err(e) => {
+                logging::warning(format!("Keeping previous configuration: {}", e));
+                bus.publish("config_rejected", serde_json::json!({
+                    "source": "docker",
+                    "routes": routes,
+                    "error": e,
+                }));
+            }
+        }
+    }
+}
+
+/// Take in the wanted routes one by one, each only if the table still builds with it
+fn accept(state: &mut Routes, snapshot: &Snapshot) {
+    let mut accepted: Vec<String> = Vec::new();
+    let mut rejected = HashSet::new();
+    for (route, containers) in &state.wanted {
+        accepted.push(route.clone());
+        if let Err(e) = RouteConfig::with_generated(snapshot.clone(), &accepted) {
+            accepted.pop();
+            if !state.rejected.contains(route) {
+                logging::warning(format!("Leaving out route '{}' of containers {}: {}", route, containers.join(", "), e));
+            }
+            rejected.insert(route.clone());
+            continue;
+        }
+        if !state.accepted.contains(route) {
+            logging::info(format!("Added route '{}' for containers {}", route, containers.join(", ")));
+        }
+    }
+    for route in state.accepted.iter().filter(|route| !accepted.contains(route)) {
+        logging::info(format!("Removed route '{}'", route));
+    }
+    state.accepted = accepted;
+    state.rejected = rejected;
+}
+
+/// Follow the daemon's container events, listing the containers again after each, and start over
+/// when the event stream fails
+fn watch(socket: &str) {
+    loop {
+        update(Err(follow(socket)));
+        std::thread::sleep(RETRY_INTERVAL);
+    }
+}
+
+/// Follow the event stream until it fails, with the failure
+fn follow(socket: &str) -> String {
+    // The events are subscribed to before listing, so that no change falls in between
+    let events = match get(socket, &format!("/events?filters={}", percent_encode(EVENTS)), None) {
+        Ok(events) => events,
+        Err(e) => return e,
+    };
+    update(list(socket));
+    let mut events = BufReader::new(events);
+    let mut line = Vec::new();
+    loop {
+        line.clear();
+        match (&mut events).take(1024 * 1024).read_until(b'\n', &mut line) {
+            Ok(0) => return format!("Docker at {} ended the event stream", socket),
+            Ok(_) if line.iter().all(u8::is_ascii_whitespace) => {}
+            Ok(_) => update(list(socket)),
+            Err(e) => return format!("Docker at {}: {}", socket, e),
+        }
+    }
+}
+
+/// Record a new list of the wanted routes, or the failure to get one, which keeps the last list
+fn update(listing: Result<Listing, String>) {
+    let mut state = state().lock().unwrap();
+    match listing {
+        Ok(Listing { wanted, skipped }) => {
+            if state.failing {
+                logging::info("Docker is reachable again");
+                state.failing = false;
+            }
+            for reason in skipped.iter().filter(|reason| !state.skipped.contains(*reason)) {
+                logging::warning(reason);
+            }
+            state.skipped = skipped;
+            if wanted != state.wanted {
+                state.wanted = wanted;
+                state.changed = true;
+            }
+        }
+        Err(e) => {
+            if !state.failing {
+                logging::warning(format!("Failed to follow Docker containers, keeping their routes: {}", e));
+                state.failing = true;
+            }
+        }
+    }
+}
+
+/// What the running containers ask for
+struct Listing {
+    /// The routes, sorted, with the names of the containers asking
+    wanted: Vec<(String, Vec<String>)>,
+    /// Why containers asking for a route get none
+    skipped: HashSet<String>,
+}
+
+fn list(socket: &str) -> Result<Listing, String> {
+    let mut body = Vec::new();
+    get(socket, "/containers/json", Some(REQUEST_TIMEOUT))?
+        .read_to_end(&mut body).map_err(|e| e.to_string())?;
+    let containers: Value = serde_json::from_slice(&body).map_err(|e| format!("invalid container list: {}", e))?;
+
+    // Routes by host and path, each with its options and its containers' backends
+    let mut routes: BTreeMap<String, (String, Vec<(String, String)>)> = BTreeMap::new();
+    let mut skipped = HashSet::new();
+    for container in containers.as_array().into_iter().flatten() {
+        let labels = &container["Labels"];
+        let label = |name: &str| labels[format!("{}{}", LABEL_PREFIX, name)].as_str().map(str::trim).filter(|value| !value.is_empty());
+        let (path, host) = (label("path"), label("host"));
+        if path.is_none() && host.is_none() {
+            continue;
+        }
+        let name = container["Names"][0].as_str().map_or_else(|| container["Id"].as_str().unwrap_or("?").chars().take(12).collect(), |name| name.trim_start_matches('/').to_string());
+        // Containers still starting or failing their health check get no traffic
+        let status = container["Status"].as_str().unwrap_or("");
+        if status.contains("(unhealthy)") || status.contains("(health: starting)") {
+            continue;
+        }
+        let backend = match backend(container, label("port"), label("network")) {
+            Ok(backend) => backend,
+            Err(e) => {
+                skipped.insert(format!("Not routing to container {}: {}", name, e));
+                continue;
+            }
+        };
+        let target = format!("{}{}", host.unwrap_or(""), path.unwrap_or(""));
+        let options = label("options").map(|options| format!(";{}", options.trim_start_matches(';'))).unwrap_or_default();
+        let (_, members) = routes.entry(target).or_insert_with(|| (options, Vec::new()));
+        members.push((name, backend));
+    }
+
+    let wanted = routes.into_iter().map(|(target, (options, mut members))| {
+        // The options of the first container by name count, whatever order the daemon lists them in
+        members.sort();
+        let backends: Vec<&str> = members.iter().map(|(_, backend)| backend.as_str()).collect();
+        let route = format!("{}={}{}", target, backends.join(","), options);
+        (route, members.into_iter().map(|(name, _)| name).collect())
+    }).collect();
+    Ok(Listing { wanted, skipped })
+}
+
+/// The `ip:port` a container is reached at
+fn backend(container: &Value, port: Option<&str>, network: Option<&str>) -> Result<String, String> {
+    let port = match port {
+        Some(port) => port.parse::<u16>().ok().filter(|&port| port > 0).ok_or_else(|| format!("invalid {}port '{}'", LABEL_PREFIX, port))?,
+        None => {
+            let mut exposed: Vec<u64> = container["Ports"].as_array().into_iter().flatten()
+                .filter(|port| port["Type"].as_str() == Some("tcp"))
+                .filter_map(|port| port["PrivatePort"].as_u64())
+                .collect();
+            exposed.sort_unstable();
+            exposed.dedup();
+            match exposed[..] {
+                [port] => u16::try_from(port).map_err(|_| "invalid exposed port".to_string())?,
+                [] => return Err(format!("it exposes no port; give one with {}port", LABEL_PREFIX)),
+                _ => return Err(format!("it exposes several ports; choose one with {}port", LABEL_PREFIX)),
+            }
+        }
+    };
+    let networks = container["NetworkSettings"]["Networks"].as_object().ok_or("no networks")?;
+    let ip = match network {
+        Some(network) => networks.get(network).ok_or_else(|| format!("not on network '{}'", network))?["IPAddress"].as_str(),
+        None => networks.values().filter_map(|network| network["IPAddress"].as_str()).find(|ip| !ip.is_empty()),
+    };
+    match ip.filter(|ip| !ip.is_empty()) {
+        Some(ip) if ip.contains(':') => Ok(format!("[{}]:{}", ip, port)),
+        Some(ip) => Ok(format!("{}:{}", ip, port)),
+        // Host networking: the container listens on the host's addresses
+        None if networks.contains_key("host") => Ok(format!("127.0.0.1:{}", port)),
+        None => Err("it has no address".to_string()),
+    }
+}
+
+/// GET a path from the Docker daemon's API
+fn get(socket: &str, path: &str, read_timeout: Option<Duration>) -> Result<Body, String> {
+    connect(socket, read_timeout)
+        .and_then(|stream| blocking::get(stream, "docker", path, ""))
+        .map_err(|e| format!("Docker at {}: {}", socket, e))
+}
+
+#[cfg(unix)]
+fn connect(socket: &str, read_timeout: Option<Duration>) -> Result<Box<dyn blocking::Stream>, String> {
+    let fail = |e: std::io::Error| e.to_string();
+    let stream = std::os::unix::net::UnixStream::connect(socket).map_err(fail)?;
+    stream.set_read_timeout(read_timeout).map_err(fail)?;
+    stream.set_write_timeout(Some(REQUEST_TIMEOUT)).map_err(fail)?;
+    Ok(Box::new(stream))
+}
+
+#[cfg(not(unix))]
+fn connect(_socket: &str, _read_timeout: Option<Duration>) -> Result<Box<dyn blocking::Stream>, String> {
+    Err("--docker is only supported on Unix".to_string())
+}
+
+fn percent_encode(text: &str) -> String {
+    text.bytes().map(|b| match b {
+        b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'_' | b'.' | b'~' => (b as char).to_string(),
+        b => format!("%{:02X}", b),
+    }).collect()
+}
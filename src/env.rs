@@ -0,0 +1,41 @@
+//! Environment variable expansion in routes and config file values: `${NAME}`, `${NAME:-default}`,
+//! and `$${` for a literal `${`
+
+/// Expand the variables in a value; an unset variable without a default is an error
+pub fn expand(value: &str) -> Result<String, String> {
+    let mut expanded = String::with_capacity(value.len());
+    let mut rest = value;
+    while let Some(idx) = rest.find("${") {
+        // `$${` escapes the expansion
+        if rest[..idx].ends_with('$') {
+            expanded.push_str(&rest[..idx - 1]);
+            expanded.push_str("${");
+            rest = &rest[idx + 2..];
+            continue;
+        }
+        expanded.push_str(&rest[..idx]);
+
+        let reference = &rest[idx + 2..];
+        let end = reference.find('}')
+            .ok_or_else(|| format!("Unterminated ${{...}} in '{}'", value))?;
+        let (name, default) = match reference[..end].split_once(":-") {
+            Some((name, default)) => (name, Some(default)),
+            None => (&reference[..end], None),
+        };
+        let valid_name = name.starts_with(|c: char| c.is_ascii_alphabetic() || c == '_')
+            && name.bytes().all(|b| b.is_ascii_alphanumeric() || b == b'_');
+        if !valid_name {
+            return Err(format!("Invalid variable name '{}' in '{}'", name, value));
+        }
+
+        // Like the shell, an empty variable also takes the default
+        match (std::env::var(name).ok().filter(|v| !v.is_empty() || default.is_none()), default) {
+            (Some(v), _) => expanded.push_str(&v),
+            (None, Some(default)) => expanded.push_str(default),
+            (None, None) => return Err(format!("Environment variable '{}' is not set (in '{}'); use ${{{}:-default}} for a fallback", name, value, name)),
+        }
+        rest = &reference[end + 1..];
+    }
+    expanded.push_str(rest);
+    Ok(expanded)
+}
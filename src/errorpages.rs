@@ -0,0 +1,69 @@
+//! Error pages from disk that replace backend 5xx responses and the proxy's own 502 (`--custom-errors`)
+
+use crate::response::LocalResponse;
+use std::collections::HashMap;
+use std::path::Path;
+
+/// File covering the 5xx statuses without a page of their own
+const FALLBACK_PAGE: &str = "5xx.html";
+
+/// Error pages by status
+pub struct ErrorPages {
+    pages: HashMap<u16, LocalResponse>,
+}
+
+impl ErrorPages {
+    /// Load `5NN.html` pages from a directory, plus `5xx.html` for the other 5xx statuses. Pages may use
+    /// the placeholders of fixed responses (`{request_id}`, `{date}`, ...); other files are ignored.
+    pub fn load(dir: &Path) -> Result<Self, String> {
+        let entries = std::fs::read_dir(dir)
+            .map_err(|e| format!("Failed to read error page directory {}: {}", dir.display(), e))?;
+
+        let mut files: HashMap<u16, String> = HashMap::new();
+        let mut fallback = None;
+        for entry in entries {
+            let path = entry.map_err(|e| format!("Failed to read error page directory {}: {}", dir.display(), e))?.path();
+            let Some(name) = path.file_name().and_then(|name| name.to_str()) else {
+                continue;
+            };
+            let status = match name.strip_suffix(".html") {
+                Some(_) if name == FALLBACK_PAGE => None,
+                Some(status) => match status.parse::<u16>() {
+                    Ok(status) if (500..=599).contains(&status) => Some(status),
+                    _ => continue,
+                },
+                None => continue,
+            };
+
+            let contents = std::fs::read_to_string(&path)
+                .map_err(|e| format!("Failed to read error page {}: {}", path.display(), e))?;
+            match status {
+                Some(status) => {
+                    files.insert(status, contents);
+                }
+                None => fallback = Some(contents),
+            }
+        }
+        if files.is_empty() && fallback.is_none() {
+            return Err(format!("Error page directory {} has no 5NN.html or {} pages", dir.display(), FALLBACK_PAGE));
+        }
+
+        let mut pages = HashMap::new();
+        for status in 500..=599 {
+            if let Some(contents) = files.get(&status).or(fallback.as_ref()) {
+                pages.insert(status, LocalResponse::new(status, "text/html; charset=utf-8", contents, &[])?);
+            }
+        }
+        Ok(ErrorPages { pages })
+    }
+
+    /// The page replacing a response with this status
+    pub fn get(&self, status: u16) -> Option<&LocalResponse> {
+        self.pages.get(&status)
+    }
+
+    /// Number of statuses with a page
+    pub fn len(&self) -> usize {
+        self.pages.len()
+    }
+}
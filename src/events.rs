@@ -0,0 +1,41 @@
+//! In-process bus for proxy events, streamed to admin API subscribers
+
+use std::time::{SystemTime, UNIX_EPOCH};
+use tokio::sync::broadcast;
+
+/// Events buffered per subscriber before slow subscribers start missing events
+const EVENT_BUFFER: usize = 256;
+
+/// A published event: its type and JSON payload
+#[derive(Clone)]
+pub struct Event {
+    pub kind: &'static str,
+    pub data: serde_json::Value,
+}
+
+pub struct EventBus {
+    sender: broadcast::Sender<Event>,
+}
+
+impl Default for EventBus {
+    fn default() -> Self {
+        let (sender, _) = broadcast::channel(EVENT_BUFFER);
+        EventBus { sender }
+    }
+}
+
+impl EventBus {
+    /// Publish an event to all current subscribers; a `timestamp` field is added to object payloads
+    pub fn publish(&self, kind: &'static str, mut data: serde_json::Value) {
+        if let Some(fields) = data.as_object_mut() {
+            let timestamp = SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |d| d.as_secs());
+            fields.entry("timestamp").or_insert(timestamp.into());
+        }
+        // Sending only fails when nobody is subscribed
+        let _ = self.sender.send(Event { kind, data });
+    }
+
+    pub fn subscribe(&self) -> broadcast::Receiver<Event> {
+        self.sender.subscribe()
+    }
+}
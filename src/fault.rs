@@ -0,0 +1,123 @@
+//! Fault injection for resilience testing: faults set on a route through the admin API
+//! (`POST /faults`) delay its requests, answer a share of them with an error status or drop their
+//! connections, in place of a failing backend. Faults are kept in memory only, so a restart clears them.
+
+use serde_json::json;
+use std::collections::BTreeMap;
+use std::sync::Mutex;
+use std::time::Duration;
+
+/// Longest delay or jitter accepted
+const MAX_DELAY: Duration = Duration::from_secs(300);
+
+/// What is done to a route's requests
+#[derive(Clone, Copy)]
+pub struct Fault {
+    delay: Duration,
+    /// Delays vary by up to this much either way
+    jitter: Duration,
+    /// Share of the requests answered with `abort_status`, from 0 to 1
+    abort: f64,
+    abort_status: u16,
+    /// Share of the requests whose connection is closed without an answer, from 0 to 1
+    drop: f64,
+}
+
+/// What happens to a request after its delay
+pub enum Injected {
+    /// Answered with this status
+    Abort(u16),
+    /// Connection closed without an answer
+    Drop,
+}
+
+impl Fault {
+    /// `{"delay_ms": 200, "jitter_ms": 100, "abort_percent": 10, "abort_status": 503, "drop_percent": 5}`, all optional
+    pub fn from_json(request: &serde_json::Value) -> Result<Self, String> {
+        let millis = |field: &str| match &request[field] {
+            serde_json::Value::Null => Ok(Duration::ZERO),
+            value => value.as_u64().map(Duration::from_millis).filter(|&delay| delay <= MAX_DELAY)
+                .ok_or_else(|| format!("\"{}\" must be a number of milliseconds up to {}", field, MAX_DELAY.as_millis())),
+        };
+        let share = |field: &str| match &request[field] {
+            serde_json::Value::Null => Ok(0.0),
+            value => value.as_f64().filter(|percent| (0.0..=100.0).contains(percent)).map(|percent| percent / 100.0)
+                .ok_or_else(|| format!("\"{}\" must be a percentage from 0 to 100", field)),
+        };
+        let abort_status = match &request["abort_status"] {
+            serde_json::Value::Null => 503,
+            value => value.as_u64().filter(|status| (200..600).contains(status)).map(|status| status as u16)
+                .ok_or("\"abort_status\" must be a status from 200 to 599")?,
+        };
+        let fault = Fault { delay: millis("delay_ms")?, jitter: millis("jitter_ms")?, abort: share("abort_percent")?, abort_status, drop: share("drop_percent")? };
+        if fault.abort + fault.drop > 1.0 {
+            return Err("\"abort_percent\" and \"drop_percent\" add up to more than 100".to_string());
+        }
+        if fault.delay.is_zero() && fault.jitter.is_zero() && fault.abort == 0.0 && fault.drop == 0.0 {
+            return Err("no fault given: expected \"delay_ms\", \"jitter_ms\", \"abort_percent\" or \"drop_percent\"".to_string());
+        }
+        Ok(fault)
+    }
+
+    pub fn to_json(self) -> serde_json::Value {
+        json!({
+            "delay_ms": self.delay.as_millis() as u64,
+            "jitter_ms": self.jitter.as_millis() as u64,
+            "abort_percent": self.abort * 100.0,
+            "abort_status": self.abort_status,
+            "drop_percent": self.drop * 100.0,
+        })
+    }
+
+    /// The delay of a request, and what happens to it next, if anything
+    pub fn inject(&self) -> (Duration, Option<Injected>) {
+        let delay = if self.jitter.is_zero() {
+            self.delay
+        } else {
+            // Anywhere from `delay - jitter` to `delay + jitter`
+            let jitter = self.jitter.mul_f64(2.0 * random());
+            (self.delay + jitter).saturating_sub(self.jitter)
+        };
+        let roll = random();
+        let injected = if roll < self.drop {
+            Some(Injected::Drop)
+        } else if roll < self.drop + self.abort {
+            Some(Injected::Abort(self.abort_status))
+        } else {
+            None
+        };
+        (delay, injected)
+    }
+}
+
+/// A random number from 0 (inclusive) to 1 (exclusive)
+fn random() -> f64 {
+    (crate::otel::random_id() >> 11) as f64 / (1u64 << 53) as f64
+}
+
+/// The faults in effect, by route name
+#[derive(Default)]
+pub struct Faults {
+    routes: Mutex<BTreeMap<String, Fault>>,
+}
+
+impl Faults {
+    pub fn get(&self, route: &str) -> Option<Fault> {
+        self.routes.lock().unwrap().get(route).copied()
+    }
+
+    pub fn set(&self, route: String, fault: Fault) {
+        self.routes.lock().unwrap().insert(route, fault);
+    }
+
+    /// Clear a route's faults; returns whether it had any
+    pub fn clear(&self, route: &str) -> bool {
+        self.routes.lock().unwrap().remove(route).is_some()
+    }
+
+    pub fn to_json(&self) -> serde_json::Value {
+        let routes = self.routes.lock().unwrap();
+        let faults: serde_json::Map<String, serde_json::Value> = routes.iter().map(|(route, fault)| (route.clone(), fault.to_json())).collect();
+        json!({ "faults": faults })
+    }
+}
@@ -0,0 +1,526 @@
+//! HTTP/2 cleartext upgrades (RFC 7540 section 3.2). A client on a plaintext listener that asks to
+//! switch its connection to h2c with its first request gets `101 Switching Protocols`, and the
+//! connection is served as HTTP/2 from then on. The upgrade request becomes stream 1, as the RFC
+//! has it: it is handed to the HTTP/2 server as a HEADERS frame of its own, after the client's
+//! preface. Each stream is translated to an HTTP/1.1 request and runs through the proxy like the
+//! request of a connection of its own; its HTTP/1.1 response is translated back.
+
+use crate::intercept::{self, BodyFraming};
+use crate::request::RequestHead;
+use base64::Engine;
+use bytes::Bytes;
+use h2::server::SendResponse;
+use h2::{RecvStream, SendStream};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, DuplexStream};
+
+/// What a client sends first on an HTTP/2 connection, before its SETTINGS frame
+const PREFACE: &[u8] = b"PRI * HTTP/2.0\r\n\r\nSM\r\n\r\n";
+
+const SWITCHING_PROTOCOLS: &[u8] = b"HTTP/1.1 101 Switching Protocols\r\nConnection: Upgrade\r\nUpgrade: h2c\r\n\r\n";
+
+const FRAME_HEADER: usize = 9;
+const HEADERS: u8 = 0x1;
+const SETTINGS: u8 = 0x4;
+const CONTINUATION: u8 = 0x9;
+const END_STREAM: u8 = 0x1;
+const END_HEADERS: u8 = 0x4;
+
+/// Largest frame payload a peer must accept before its SETTINGS say otherwise
+const MAX_FRAME_SIZE: usize = 16 * 1024;
+
+/// Largest client SETTINGS frame waited for after the switch
+const MAX_SETTINGS: usize = 64 * 1024;
+
+/// Streams of a connection handled at once
+pub const MAX_CONCURRENT_STREAMS: u32 = 100;
+
+/// Buffer between a stream and the HTTP/1.1 handling of its request
+pub const STREAM_BUFFER: usize = 64 * 1024;
+
+/// Headers of one hop only, which HTTP/2 does without
+const CONNECTION_HEADERS: [&str; 8] = ["connection", "keep-alive", "proxy-connection", "transfer-encoding", "upgrade", "http2-settings", "te", "host"];
+
+type Error = Box<dyn std::error::Error + Send + Sync>;
+
+/// Whether a request asks to switch to h2c in a way the proxy honors: an HTTP/1.1 request without
+/// a body, naming `h2c` in `Upgrade` and both `Upgrade` and `HTTP2-Settings` in `Connection`, with
+/// one well-formed `HTTP2-Settings` header
+pub fn is_upgrade(head: &RequestHead) -> bool {
+    let has_token = |name: &str, token: &str| head.header_str(name).is_some_and(|value| value.split(',').any(|t| t.trim().eq_ignore_ascii_case(token)));
+    let mut settings = head.headers.iter().filter(|h| h.name.eq_ignore_ascii_case("http2-settings"));
+    let settings_valid = match (settings.next(), settings.next()) {
+        (Some(header), None) => std::str::from_utf8(header.value).ok().and_then(|value| {
+            base64::engine::general_purpose::URL_SAFE_NO_PAD.decode(value.trim().trim_end_matches('=')).ok()
+        }).is_some_and(|payload| payload.len() % 6 == 0),
+        _ => false,
+    };
+    let has_body = head.header("transfer-encoding").is_some()
+        || head.header_str("content-length").is_some_and(|length| length.trim() != "0");
+    head.version == 1
+        && has_token("upgrade", "h2c")
+        && has_token("connection", "upgrade")
+        && has_token("connection", "http2-settings")
+        && settings_valid
+        && !has_body
+        && head.ambiguity().is_none()
+}
+
+/// Answer the upgrade request with `101 Switching Protocols` and read the client's preface and
+/// SETTINGS frame. Returns the bytes the HTTP/2 server is to read before the rest of the
+/// connection: those, then the upgrade request as stream 1, then whatever followed them.
+pub async fn switch<S: AsyncRead + AsyncWrite + Unpin>(stream: &mut S, head: &RequestHead<'_>, mut received: Vec<u8>) -> Result<Vec<u8>, Error> {
+    let request = stream_one(head)?;
+    stream.write_all(SWITCHING_PROTOCOLS).await?;
+
+    let settings_end = loop {
+        let checked = received.len().min(PREFACE.len());
+        if received[..checked] != PREFACE[..checked] {
+            return Err("client sent no HTTP/2 connection preface after the switch".into());
+        }
+        if let Some(frame) = received.get(PREFACE.len()..PREFACE.len() + FRAME_HEADER) {
+            if frame[3] != SETTINGS {
+                return Err("client did not start with a SETTINGS frame".into());
+            }
+            let length = u32::from_be_bytes([0, frame[0], frame[1], frame[2]]) as usize;
+            if length > MAX_SETTINGS {
+                return Err("client SETTINGS frame too large".into());
+            }
+            let end = PREFACE.len() + FRAME_HEADER + length;
+            if received.len() >= end {
+                break end;
+            }
+        }
+        let mut chunk = [0u8; 8192];
+        let n = stream.read(&mut chunk).await?;
+        if n == 0 {
+            return Err("connection closed before the HTTP/2 connection preface".into());
+        }
+        received.extend_from_slice(&chunk[..n]);
+    };
+    let rest = received.split_off(settings_end);
+    received.extend_from_slice(&request);
+    received.extend_from_slice(&rest);
+    Ok(received)
+}
+
+/// The upgrade request as the HEADERS (and CONTINUATION) frames of stream 1, which it has ended
+fn stream_one(head: &RequestHead) -> Result<Vec<u8>, Error> {
+    let authority = head.header_str("host").ok_or("upgrade request without Host")?;
+    let target = head.target();
+    let named: Vec<String> = head.header_str("connection").map_or(Vec::new(), |value| value.split(',').map(|t| t.trim().to_ascii_lowercase()).collect());
+
+    let mut block = Vec::new();
+    for (name, value) in [(":method", head.method), (":scheme", "http"), (":authority", authority.trim()), (":path", &target)] {
+        literal(&mut block, name.as_bytes(), value.as_bytes());
+    }
+    for header in head.headers {
+        let name = header.name.to_ascii_lowercase();
+        if !CONNECTION_HEADERS.contains(&name.as_str()) && !named.contains(&name) {
+            literal(&mut block, name.as_bytes(), header.value);
+        }
+    }
+
+    let mut frames = Vec::with_capacity(block.len() + FRAME_HEADER);
+    let mut fragments = block.chunks(MAX_FRAME_SIZE).peekable();
+    let mut kind = HEADERS;
+    let mut flags = END_STREAM;
+    while let Some(fragment) = fragments.next() {
+        if fragments.peek().is_none() {
+            flags |= END_HEADERS;
+        }
+        frames.extend_from_slice(&(fragment.len() as u32).to_be_bytes()[1..]);
+        frames.extend_from_slice(&[kind, flags]);
+        frames.extend_from_slice(&1u32.to_be_bytes());
+        frames.extend_from_slice(fragment);
+        (kind, flags) = (CONTINUATION, 0);
+    }
+    Ok(frames)
+}
+
+/// An HPACK literal header field without indexing, with a literal name (RFC 7541 section 6.2.2)
+fn literal(block: &mut Vec<u8>, name: &[u8], value: &[u8]) {
+    block.push(0);
+    for string in [name, value] {
+        integer(block, string.len(), 7, 0);
+        block.extend_from_slice(string);
+    }
+}
+
+/// An HPACK integer with an N-bit prefix (RFC 7541 section 5.1)
+fn integer(block: &mut Vec<u8>, mut value: usize, prefix_bits: u32, flags: u8) {
+    let max = (1usize << prefix_bits) - 1;
+    if value < max {
+        block.push(flags | value as u8);
+        return;
+    }
+    block.push(flags | max as u8);
+    value -= max;
+    while value >= 128 {
+        block.push((value % 128) as u8 | 0x80);
+        value /= 128;
+    }
+    block.push(value as u8);
+}
+
+/// The HTTP/1.1 request head of a stream, closing the connection after its response, and whether
+/// its body is sent chunked; None for requests without a path (`CONNECT`) or authority
+pub fn request_head(request: &http::request::Parts, has_body: bool) -> Option<(Vec<u8>, bool)> {
+    let target = request.uri.path_and_query()?.as_str();
+    let authority = match request.uri.authority() {
+        Some(authority) => authority.as_str(),
+        None => request.headers.get(http::header::HOST)?.to_str().ok()?,
+    };
+    let mut head = format!("{} {} HTTP/1.1\r\nHost: {}\r\n", request.method, target, authority).into_bytes();
+    let mut cookies: Vec<&[u8]> = Vec::new();
+    for (name, value) in &request.headers {
+        match name.as_str() {
+            // Split into several headers for HTTP/2's header compression, they are joined again
+            "cookie" => cookies.push(value.as_bytes()),
+            name if CONNECTION_HEADERS.contains(&name) => {}
+            name => push_header(&mut head, name.as_bytes(), value.as_bytes()),
+        }
+    }
+    if !cookies.is_empty() {
+        push_header(&mut head, b"cookie", &cookies.join(&b"; "[..]));
+    }
+    let chunked = has_body && !request.headers.contains_key(http::header::CONTENT_LENGTH);
+    if chunked {
+        head.extend_from_slice(b"transfer-encoding: chunked\r\n");
+    }
+    head.extend_from_slice(b"connection: close\r\n\r\n");
+    Some((head, chunked))
+}
+
+fn push_header(head: &mut Vec<u8>, name: &[u8], value: &[u8]) {
+    head.extend_from_slice(name);
+    head.extend_from_slice(b": ");
+    head.extend_from_slice(value);
+    head.extend_from_slice(b"\r\n");
+}
+
+/// Send a stream's request to its HTTP/1.1 handling on `connection`, and the response back to the
+/// client. Returns once the response is complete; the request body is not waited for after that.
+pub async fn exchange(connection: DuplexStream, head: Vec<u8>, chunked: bool, body: RecvStream, respond: SendResponse<Bytes>, head_request: bool) -> Result<(), Error> {
+    let (reader, writer) = tokio::io::split(connection);
+    // Dropping the write half does not close the connection: closing it before the response is
+    // complete would read as the client going away
+    let upload = send_request(writer, head, chunked, body);
+    let download = send_response(reader, respond, head_request);
+    tokio::pin!(download);
+    tokio::select! {
+        result = &mut download => result,
+        result = upload => {
+            // The client resetting its stream ends the exchange; a request the proxy stopped
+            // reading, as it answered early, still gets its response
+            if let Err(e) = result {
+                if e.is::<h2::Error>() {
+                    return Err(e);
+                }
+            }
+            download.await
+        }
+    }
+}
+
+async fn send_request<W: AsyncWrite + Unpin>(mut connection: W, head: Vec<u8>, chunked: bool, mut body: RecvStream) -> Result<(), Error> {
+    connection.write_all(&head).await?;
+    while let Some(data) = body.data().await {
+        let data = data?;
+        body.flow_control().release_capacity(data.len())?;
+        if chunked {
+            connection.write_all(format!("{:x}\r\n", data.len()).as_bytes()).await?;
+            connection.write_all(&data).await?;
+            connection.write_all(b"\r\n").await?;
+        } else {
+            connection.write_all(&data).await?;
+        }
+    }
+    if chunked {
+        connection.write_all(b"0\r\n\r\n").await?;
+    }
+    Ok(())
+}
+
+async fn send_response<R: AsyncRead + Unpin>(mut connection: R, mut respond: SendResponse<Bytes>, head_request: bool) -> Result<(), Error> {
+    let mut buffer = Vec::new();
+    let mut chunk = vec![0u8; 16 * 1024];
+    // Informational responses (`100 Continue`, `103 Early Hints`) are left out
+    let head_end = loop {
+        match crate::find_header_end(&buffer) {
+            Some(end) if intercept::is_informational(&buffer[..end]) => {
+                buffer.drain(..end);
+                continue;
+            }
+            Some(end) => break end,
+            None if buffer.len() > intercept::MAX_RESPONSE_HEAD => return Err("response head too large".into()),
+            None => {}
+        }
+        let n = connection.read(&mut chunk).await?;
+        if n == 0 {
+            return Err("connection closed before the response head".into());
+        }
+        buffer.extend_from_slice(&chunk[..n]);
+    };
+    let head = &buffer[..head_end];
+    let status = intercept::status(head).ok_or("malformed response head")?;
+    let response = response(head, status)?;
+    let framing = match status {
+        204 | 304 => None,
+        _ if head_request => None,
+        _ => Some(intercept::framing(head).ok_or("response with an unsupported framing")?),
+    };
+    let mut body = match framing {
+        None | Some(BodyFraming::Length(0)) => {
+            respond.send_response(response, true)?;
+            return Ok(());
+        }
+        Some(framing) => Body::new(framing),
+    };
+    let mut stream = respond.send_response(response, false)?;
+
+    let mut decoded = Vec::new();
+    let mut done = body.decode(&buffer[head_end..], &mut decoded)?;
+    loop {
+        send_data(&mut stream, std::mem::take(&mut decoded).into()).await?;
+        if done {
+            break;
+        }
+        let n = connection.read(&mut chunk).await?;
+        if n == 0 {
+            if matches!(body, Body::Close) {
+                break;
+            }
+            return Err("connection closed before the end of the response".into());
+        }
+        done = body.decode(&chunk[..n], &mut decoded)?;
+    }
+    stream.send_data(Bytes::new(), true)?;
+    Ok(())
+}
+
+/// The HTTP/2 response of an HTTP/1.1 response head, without its connection headers
+fn response(head: &[u8], status: u16) -> Result<http::Response<()>, Error> {
+    let mut headers = [httparse::EMPTY_HEADER; crate::request::MAX_HEADERS];
+    let mut parsed = httparse::Response::new(&mut headers);
+    parsed.parse(head)?;
+    let named: Vec<String> = parsed.headers.iter()
+        .filter(|h| h.name.eq_ignore_ascii_case("connection"))
+        .flat_map(|h| String::from_utf8_lossy(h.value).split(',').map(|t| t.trim().to_ascii_lowercase()).collect::<Vec<_>>())
+        .collect();
+    let mut response = http::Response::builder().status(status);
+    for header in parsed.headers.iter() {
+        let name = header.name.to_ascii_lowercase();
+        if CONNECTION_HEADERS.contains(&name.as_str()) || named.contains(&name) {
+            continue;
+        }
+        if let (Ok(name), Ok(value)) = (http::HeaderName::from_bytes(name.as_bytes()), http::HeaderValue::from_bytes(header.value)) {
+            response = response.header(name, value);
+        }
+    }
+    Ok(response.body(())?)
+}
+
+/// Send body data as the client's flow control window allows
+async fn send_data(stream: &mut SendStream<Bytes>, mut data: Bytes) -> Result<(), Error> {
+    while !data.is_empty() {
+        stream.reserve_capacity(data.len());
+        let capacity = std::future::poll_fn(|cx| stream.poll_capacity(cx)).await.ok_or("stream closed by the client")??;
+        if capacity > 0 {
+            stream.send_data(data.split_to(capacity.min(data.len())), false)?;
+        }
+    }
+    Ok(())
+}
+
+/// An HTTP/1.1 response body being decoded into the data of its stream
+enum Body {
+    Length(usize),
+    Chunked(Chunks),
+    /// The body ends when the connection closes
+    Close,
+}
+
+/// Where a chunked body is, between the bytes of it decoded so far
+enum Chunks {
+    /// A chunk size line so far
+    Size(Vec<u8>),
+    /// Chunk data bytes still to come
+    Data(usize),
+    /// The line break after chunk data
+    DataEnd,
+    /// A trailer line so far; an empty one ends the body. Trailers are dropped.
+    Trailer(Vec<u8>),
+    Done,
+}
+
+impl Body {
+    fn new(framing: BodyFraming) -> Self {
+        match framing {
+            BodyFraming::Length(length) => Body::Length(length),
+            BodyFraming::Chunked => Body::Chunked(Chunks::Size(Vec::new())),
+            BodyFraming::Close => Body::Close,
+        }
+    }
+
+    /// Decode the next bytes of the body; true once its end is among them. Bytes after the end are ignored.
+    fn decode(&mut self, mut data: &[u8], decoded: &mut Vec<u8>) -> Result<bool, Error> {
+        let chunks = match self {
+            Body::Close => {
+                decoded.extend_from_slice(data);
+                return Ok(false);
+            }
+            Body::Length(remaining) => {
+                let n = data.len().min(*remaining);
+                decoded.extend_from_slice(&data[..n]);
+                *remaining -= n;
+                return Ok(*remaining == 0);
+            }
+            Body::Chunked(chunks) => chunks,
+        };
+        loop {
+            match chunks {
+                Chunks::Done => return Ok(true),
+                _ if data.is_empty() => return Ok(false),
+                Chunks::Size(line) | Chunks::Trailer(line) => {
+                    let Some(newline) = data.iter().position(|&b| b == b'\n') else {
+                        line.extend_from_slice(data);
+                        if line.len() > intercept::MAX_RESPONSE_HEAD {
+                            return Err("chunk size or trailer line too long".into());
+                        }
+                        return Ok(false);
+                    };
+                    line.extend_from_slice(&data[..newline]);
+                    data = &data[newline + 1..];
+                    let line = std::mem::take(line);
+                    let line = line.strip_suffix(b"\r").unwrap_or(&line);
+                    *chunks = match chunks {
+                        Chunks::Size(_) => match intercept::chunk_size(line) {
+                            Some(0) => Chunks::Trailer(Vec::new()),
+                            Some(size) => Chunks::Data(size),
+                            None => return Err("malformed chunk size".into()),
+                        },
+                        _ if line.is_empty() => Chunks::Done,
+                        _ => Chunks::Trailer(Vec::new()),
+                    };
+                }
+                Chunks::Data(remaining) => {
+                    let n = data.len().min(*remaining);
+                    decoded.extend_from_slice(&data[..n]);
+                    data = &data[n..];
+                    *remaining -= n;
+                    if *remaining == 0 {
+                        *chunks = Chunks::DataEnd;
+                    }
+                }
+                Chunks::DataEnd => match data {
+                    [b'\r'] => return Ok(false),
+                    [b'\r', b'\n', ..] | [b'\n', ..] => {
+                        data = &data[if data[0] == b'\r' { 2 } else { 1 }..];
+                        *chunks = Chunks::Size(Vec::new());
+                    }
+                    _ => return Err("chunk data not followed by a line break".into()),
+                },
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::request::MAX_HEADERS;
+
+    fn upgrade(request: &str) -> bool {
+        let mut storage = [httparse::EMPTY_HEADER; MAX_HEADERS];
+        is_upgrade(&RequestHead::parse(request.as_bytes(), &mut storage).unwrap())
+    }
+
+    #[test]
+    fn upgrades_need_the_tokens_and_one_settings_header() {
+        let request = |headers: &str| format!("GET / HTTP/1.1\r\nHost: example.com\r\n{}\r\n", headers);
+        assert!(upgrade(&request("Connection: Upgrade, HTTP2-Settings\r\nUpgrade: h2c\r\nHTTP2-Settings: AAMAAABkAAQAoAAAAAIAAAAA\r\n")));
+        assert!(upgrade(&request("Connection: upgrade,http2-settings\r\nUpgrade: H2C\r\nHTTP2-Settings: \r\n")));
+        assert!(!upgrade(&request("Connection: Upgrade\r\nUpgrade: h2c\r\nHTTP2-Settings: AAMAAABkAAQAoAAAAAIAAAAA\r\n")));
+        assert!(!upgrade(&request("Connection: Upgrade, HTTP2-Settings\r\nUpgrade: websocket\r\nHTTP2-Settings: AAMAAABkAAQAoAAAAAIAAAAA\r\n")));
+        assert!(!upgrade(&request("Connection: Upgrade, HTTP2-Settings\r\nUpgrade: h2c\r\n")));
+        assert!(!upgrade(&request("Connection: Upgrade, HTTP2-Settings\r\nUpgrade: h2c\r\nHTTP2-Settings: AAMAAABk\r\nHTTP2-Settings: AAMAAABk\r\n")));
+        assert!(!upgrade(&request("Connection: Upgrade, HTTP2-Settings\r\nUpgrade: h2c\r\nHTTP2-Settings: AAMAAA\r\n")));
+        assert!(!upgrade(&request("Connection: Upgrade, HTTP2-Settings\r\nUpgrade: h2c\r\nHTTP2-Settings: AAMAAABk\r\nContent-Length: 5\r\n")));
+    }
+
+    #[test]
+    fn hpack_integers_continue_past_their_prefix() {
+        let encoded = |value: usize| {
+            let mut block = Vec::new();
+            integer(&mut block, value, 5, 0);
+            block
+        };
+        assert_eq!(encoded(10), [10]);
+        assert_eq!(encoded(31), [31, 0]);
+        assert_eq!(encoded(1337), [31, 154, 10]);
+    }
+
+    #[test]
+    fn stream_one_carries_the_upgrade_request_without_connection_headers() {
+        let data = "GET /items?page=2 HTTP/1.1\r\nHost: example.com\r\nConnection: Upgrade, HTTP2-Settings, X-Hop\r\nUpgrade: h2c\r\nHTTP2-Settings: AAMAAABk\r\nX-Hop: 1\r\nAccept: */*\r\n\r\n";
+        let mut storage = [httparse::EMPTY_HEADER; MAX_HEADERS];
+        let frames = stream_one(&RequestHead::parse(data.as_bytes(), &mut storage).unwrap()).unwrap();
+        assert_eq!(&frames[3..9], [HEADERS, END_STREAM | END_HEADERS, 0, 0, 0, 1]);
+        assert_eq!(u32::from_be_bytes([0, frames[0], frames[1], frames[2]]) as usize, frames.len() - FRAME_HEADER);
+
+        let mut block = Vec::new();
+        for (name, value) in [(":method", "GET"), (":scheme", "http"), (":authority", "example.com"), (":path", "/items?page=2"), ("accept", "*/*")] {
+            literal(&mut block, name.as_bytes(), value.as_bytes());
+        }
+        assert_eq!(&frames[FRAME_HEADER..], block);
+    }
+
+    #[test]
+    fn large_upgrade_requests_continue_in_continuation_frames() {
+        let data = format!("GET / HTTP/1.1\r\nHost: example.com\r\nX-Large: {}\r\n\r\n", "a".repeat(MAX_FRAME_SIZE));
+        let mut storage = [httparse::EMPTY_HEADER; MAX_HEADERS];
+        let frames = stream_one(&RequestHead::parse(data.as_bytes(), &mut storage).unwrap()).unwrap();
+        assert_eq!(&frames[..9], [0x00, 0x40, 0x00, HEADERS, END_STREAM, 0, 0, 0, 1]);
+        let continuation = &frames[FRAME_HEADER + MAX_FRAME_SIZE..];
+        assert_eq!(&continuation[3..9], [CONTINUATION, END_HEADERS, 0, 0, 0, 1]);
+    }
+
+    #[test]
+    fn request_head_joins_cookies_and_frames_bodies_without_length() {
+        let request = http::Request::builder()
+            .method("POST")
+            .uri("http://example.com/upload?x=1")
+            .header("cookie", "a=1")
+            .header("cookie", "b=2")
+            .header("te", "trailers")
+            .header("x-request", "yes")
+            .body(())
+            .unwrap();
+        let (parts, ()) = request.into_parts();
+        let (head, chunked) = request_head(&parts, true).unwrap();
+        assert!(chunked);
+        assert_eq!(
+            String::from_utf8(head).unwrap(),
+            "POST /upload?x=1 HTTP/1.1\r\nHost: example.com\r\nx-request: yes\r\ncookie: a=1; b=2\r\ntransfer-encoding: chunked\r\nconnection: close\r\n\r\n"
+        );
+
+        let connect = http::Request::builder().method("CONNECT").uri("example.com:443").body(()).unwrap().into_parts().0;
+        assert!(request_head(&connect, false).is_none());
+    }
+
+    #[test]
+    fn chunked_bodies_are_decoded_across_reads() {
+        let mut body = Body::new(BodyFraming::Chunked);
+        let mut decoded = Vec::new();
+        let data = b"5\r\nhello\r\n6;ext=1\r\n world\r\n0\r\nExpires: never\r\n\r\n";
+        let (first, rest) = data.split_at(9);
+        assert!(!body.decode(first, &mut decoded).unwrap());
+        assert!(body.decode(rest, &mut decoded).unwrap());
+        assert_eq!(decoded, b"hello world");
+
+        assert!(Body::new(BodyFraming::Chunked).decode(b"+5\r\nhello\r\n", &mut Vec::new()).is_err());
+        let mut length = Body::new(BodyFraming::Length(3));
+        assert!(length.decode(b"abcdef", &mut decoded).unwrap());
+        assert!(decoded.ends_with(b"worldabc"));
+    }
+}
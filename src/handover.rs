@@ -0,0 +1,261 @@
+//! Zero-downtime binary upgrades (Linux only): on SIGUSR2 the proxy starts a new process with the
+//! same command line, which inherits the listening sockets instead of binding its own. Once the new
+//! process accepts, the old one stops accepting, finishes its open connections for up to
+//! `--drain-timeout` and exits. Connections waiting to be accepted are the new process's to take,
+//! as the listeners are the same sockets, so none is refused or reset along the way.
+//! Listeners passed by systemd socket activation are taken up here too.
+
+use crate::events::EventBus;
+use crate::logging;
+use crate::systemd;
+use serde_json::json;
+use std::collections::HashMap;
+use std::io;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::net::TcpListener;
+use tokio::sync::watch;
+
+/// Tells a new process what it inherits: `NAME=FD` pairs separated by commas, its listeners by
+/// name, and `ready`, the socket to report on once it accepts
+const ENV: &str = "REVERSE_PROXY_INHERITED_FDS";
+
+/// How long a new process has to start accepting before the upgrade is given up
+const READY_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// What a new process writes on the `ready` socket
+const READY: &[u8] = b"ready\n";
+
+pub struct Handover {
+    /// Descriptors inherited from the old process, until taken
+    inherited: Mutex<HashMap<String, i32>>,
+    /// Sockets passed by systemd, until taken
+    activated: Mutex<Vec<i32>>,
+    /// This process's listeners, passed on to the next one
+    listening: Mutex<Vec<(String, i32)>>,
+    handed_over: watch::Sender<bool>,
+}
+
+impl Handover {
+    /// The descriptors this process inherited, if an old one or systemd started it
+    pub fn from_env() -> Self {
+        let inherited = std::env::var(ENV).ok().map_or_else(HashMap::new, |fds| {
+            fds.split(',').filter_map(|pair| pair.split_once('=')).filter_map(|(name, fd)| Some((name.to_string(), fd.parse().ok()?))).collect()
+        });
+        Handover {
+            inherited: Mutex::new(inherited),
+            activated: Mutex::new(systemd::listen_fds()),
+            listening: Mutex::new(Vec::new()),
+            handed_over: watch::channel(false).0,
+        }
+    }
+
+    /// A listener on `addr`: the one inherited under `name` if it listens there, else a socket
+    /// systemd passed for the address, else a new one
+    pub async fn listen(&self, name: &str, addr: SocketAddr) -> io::Result<TcpListener> {
+        let inherited = self.inherited.lock().unwrap().remove(name);
+        let adopted = inherited.map(|fd| adopt(fd, addr)).transpose()
+            .map_err(|e| io::Error::new(e.kind(), format!("inherited {} listener: {}", name, e)))?;
+        let activated = {
+            let mut activated = self.activated.lock().unwrap();
+            let position = activated.iter().position(|&fd| listens_on(fd, addr));
+            position.map(|position| activated.remove(position))
+        };
+        let listener = match adopted.flatten() {
+            Some(listener) => listener,
+            None => match activated {
+                Some(fd) => adopt(fd, addr)?.ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "socket-activated listener moved"))?,
+                None => TcpListener::bind(addr).await?,
+            },
+        };
+        self.listening.lock().unwrap().push((name.to_string(), raw_fd(&listener)));
+        Ok(listener)
+    }
+
+    /// Tell the old process, if one started this one, that the listeners are accepting; returns
+    /// whether one did
+    pub fn ready(&self) -> bool {
+        let mut inherited = self.inherited.lock().unwrap();
+        let took_over = inherited.remove("ready").map(|fd| {
+            if let Err(e) = report_ready(fd) {
+                logging::warning(format!("Failed to tell the old process that this one is ready: {}", e));
+            }
+        }).is_some();
+        // Listeners of the old process this one has no use for, such as one whose address changed
+        for (_, fd) in inherited.drain() {
+            close(fd);
+        }
+        for fd in self.activated.lock().unwrap().drain(..) {
+            logging::warning(format!("Closing socket {} passed by systemd: no listener has its address", fd));
+            close(fd);
+        }
+        took_over
+    }
+
+    /// Resolves once the listeners have been handed over to a new process
+    pub async fn handed_over(&self) {
+        let _ = self.handed_over.subscribe().wait_for(|&handed_over| handed_over).await;
+    }
+
+    pub fn is_handed_over(&self) -> bool {
+        *self.handed_over.borrow()
+    }
+
+    /// Hand the listeners over to a new process on SIGUSR2, calling `prepare` before starting it.
+    /// Until one accepts, this process keeps serving.
+    pub async fn run(self: Arc<Self>, bus: Arc<EventBus>, prepare: impl Fn() + Send + 'static) {
+        #[cfg(unix)]
+        {
+            let Ok(mut signal) = tokio::signal::unix::signal(tokio::signal::unix::SignalKind::user_defined2()) else {
+                return;
+            };
+            while signal.recv().await.is_some() {
+                logging::info("Received SIGUSR2, starting a new process");
+                prepare();
+                match self.start().await {
+                    Ok(pid) => {
+                        logging::info(format!("Handed the listeners over to process {}", pid));
+                        bus.publish("process_upgrade", json!({ "pid": pid }));
+                        self.handed_over.send_replace(true);
+                        return;
+                    }
+                    Err(e) => {
+                        logging::error(format!("Upgrade failed, this process keeps serving: {}", e));
+                        bus.publish("process_upgrade_failed", json!({ "error": e.to_string() }));
+                    }
+                }
+            }
+        }
+        #[cfg(not(unix))]
+        let _ = (bus, prepare);
+    }
+
+    /// Start a new process with the listeners, and wait until it accepts; returns its process ID
+    #[cfg(target_os = "linux")]
+    async fn start(&self) -> io::Result<u32> {
+        use std::os::unix::io::AsRawFd;
+        use tokio::io::AsyncReadExt;
+
+        let (ours, theirs) = std::os::unix::net::UnixStream::pair()?;
+        let mut passed = self.listening.lock().unwrap().clone();
+        passed.push(("ready".to_string(), theirs.as_raw_fd()));
+        let fds: Vec<String> = passed.iter().map(|(name, fd)| format!("{}={}", name, fd)).collect();
+
+        // argv[0] rather than current_exe(), which names the old binary once a new one replaces it
+        let args: Vec<_> = std::env::args_os().collect();
+        let (program, args) = args.split_first().ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, "no program name to start"))?;
+        // Descriptors are opened close-on-exec; these are kept open just while the process starts
+        set_inheritable(&passed, true)?;
+        let spawned = tokio::process::Command::new(program).args(args).env(ENV, fds.join(",")).spawn();
+        set_inheritable(&passed, false)?;
+        let mut child = spawned?;
+        drop(theirs);
+        let pid = child.id().unwrap_or_default();
+
+        ours.set_nonblocking(true)?;
+        let mut ours = tokio::net::UnixStream::from_std(ours)?;
+        let mut answer = Vec::new();
+        match tokio::time::timeout(READY_TIMEOUT, ours.read_to_end(&mut answer)).await {
+            Ok(Ok(_)) if answer == READY => Ok(pid),
+            outcome => {
+                let _ = child.start_kill();
+                let _ = child.wait().await;
+                Err(match outcome {
+                    Err(_) => io::Error::new(io::ErrorKind::TimedOut, format!("process {} did not start accepting within {} s", pid, READY_TIMEOUT.as_secs())),
+                    Ok(Err(e)) => e,
+                    Ok(Ok(_)) => io::Error::new(io::ErrorKind::Other, format!("process {} exited before it was ready", pid)),
+                })
+            }
+        }
+    }
+
+    #[cfg(not(target_os = "linux"))]
+    async fn start(&self) -> io::Result<u32> {
+        Err(io::Error::new(io::ErrorKind::Unsupported, "handing listeners over is only supported on Linux"))
+    }
+}
+
+#[cfg(target_os = "linux")]
+fn adopt(fd: i32, addr: SocketAddr) -> io::Result<Option<TcpListener>> {
+    use std::os::unix::io::FromRawFd;
+
+    // SAFETY: the old process passed this descriptor for the listener, and nothing else here owns it
+    let listener = unsafe { std::net::TcpListener::from_raw_fd(fd) };
+    if listener.local_addr()? != addr {
+        // Dropped, so closed; the new address gets a listener of its own
+        return Ok(None);
+    }
+    socket2::SockRef::from(&listener).set_cloexec(true)?;
+    listener.set_nonblocking(true)?;
+    TcpListener::from_std(listener).map(Some)
+}
+
+#[cfg(not(target_os = "linux"))]
+fn adopt(_fd: i32, _addr: SocketAddr) -> io::Result<Option<TcpListener>> {
+    Ok(None)
+}
+
+/// Whether a passed descriptor is a socket bound to `addr`
+#[cfg(target_os = "linux")]
+fn listens_on(fd: i32, addr: SocketAddr) -> bool {
+    use std::os::unix::io::BorrowedFd;
+
+    // SAFETY: a descriptor systemd passed, open until taken
+    let fd = unsafe { BorrowedFd::borrow_raw(fd) };
+    socket2::SockRef::from(&fd).local_addr().ok().and_then(|local| local.as_socket()) == Some(addr)
+}
+
+#[cfg(not(target_os = "linux"))]
+fn listens_on(_fd: i32, _addr: SocketAddr) -> bool {
+    false
+}
+
+#[cfg(target_os = "linux")]
+fn report_ready(fd: i32) -> io::Result<()> {
+    use std::io::Write;
+    use std::os::unix::io::FromRawFd;
+
+    // SAFETY: the old process passed this descriptor for the ready socket, and nothing else here owns it
+    let mut socket = unsafe { std::os::unix::net::UnixStream::from_raw_fd(fd) };
+    socket.write_all(READY)
+}
+
+#[cfg(not(target_os = "linux"))]
+fn report_ready(_fd: i32) -> io::Result<()> {
+    Ok(())
+}
+
+#[cfg(target_os = "linux")]
+fn close(fd: i32) {
+    use std::os::unix::io::{FromRawFd, OwnedFd};
+
+    // SAFETY: an inherited descriptor nothing here has taken
+    drop(unsafe { OwnedFd::from_raw_fd(fd) });
+}
+
+#[cfg(not(target_os = "linux"))]
+fn close(_fd: i32) {}
+
+#[cfg(target_os = "linux")]
+fn set_inheritable(fds: &[(String, i32)], inheritable: bool) -> io::Result<()> {
+    use std::os::unix::io::BorrowedFd;
+
+    for &(_, fd) in fds {
+        // SAFETY: the descriptors of this process's listeners and ready socket, all open
+        let fd = unsafe { BorrowedFd::borrow_raw(fd) };
+        socket2::SockRef::from(&fd).set_cloexec(!inheritable)?;
+    }
+    Ok(())
+}
+
+#[cfg(target_os = "linux")]
+fn raw_fd(listener: &TcpListener) -> i32 {
+    use std::os::unix::io::AsRawFd;
+    listener.as_raw_fd()
+}
+
+#[cfg(not(target_os = "linux"))]
+fn raw_fd(_listener: &TcpListener) -> i32 {
+    -1
+}
@@ -0,0 +1,160 @@
+//! Per-route header rules: headers set on or removed from proxied requests and their responses
+//! (`--set-header`, `--remove-header`, `--set-response-header`, `--remove-response-header`), and
+//! the `Link` headers of `103 Early Hints` responses (`--early-hint`)
+
+use crate::state::Snapshot;
+use std::collections::HashMap;
+
+/// Headers that request rules cannot change: message framing, and Host, which has the `;host=` route option
+pub const REQUEST_MANAGED: &[&str] = &["host", "content-length", "transfer-encoding"];
+
+/// Headers that response rules cannot change
+pub const RESPONSE_MANAGED: &[&str] = &["content-length", "transfer-encoding"];
+
+/// Client headers that `;allow-headers` routes forward without their being listed: the managed
+/// ones, and `Connection`, which tells the backend whether the client keeps the connection open
+const ALWAYS_ALLOWED: &[&str] = &["host", "content-length", "transfer-encoding", "connection"];
+
+/// Whether a request header line is forwarded by a route that only allows the given headers
+pub fn allowed(line: &[u8], allowed: &[String]) -> bool {
+    let name = line.iter().position(|&b| b == b':').map_or(&[][..], |idx| &line[..idx]);
+    ALWAYS_ALLOWED.iter().any(|always| always.as_bytes().eq_ignore_ascii_case(name))
+        || allowed.iter().any(|allowed| allowed.as_bytes().eq_ignore_ascii_case(name))
+}
+
+/// A route's header rules, for each direction
+#[derive(Default)]
+pub struct HeaderRules {
+    pub request: HeaderEdits,
+    pub response: HeaderEdits,
+    /// The `103 Early Hints` response sent ahead of the backend's, or empty for none
+    pub early_hints: Vec<u8>,
+}
+
+/// Header changes for one direction. A set header replaces all headers of that name.
+#[derive(Default)]
+pub struct HeaderEdits {
+    /// Headers to add, in definition order
+    set: Vec<(String, String)>,
+    /// Lowercase names of headers to drop: the removed ones and the set ones
+    dropped: Vec<String>,
+}
+
+impl HeaderEdits {
+    pub fn is_empty(&self) -> bool {
+        self.dropped.is_empty()
+    }
+
+    pub fn set(&mut self, name: String, value: String) {
+        self.remove(&name);
+        self.set.push((name, value));
+    }
+
+    pub fn remove(&mut self, name: &str) {
+        let name = name.to_ascii_lowercase();
+        if !self.dropped.contains(&name) {
+            self.dropped.push(name);
+        }
+    }
+
+    /// Whether a header line is replaced or removed by the rules
+    pub fn drops(&self, line: &[u8]) -> bool {
+        let name = line.iter().position(|&b| b == b':').map_or(&[][..], |idx| &line[..idx]);
+        self.dropped.iter().any(|dropped| dropped.as_bytes().eq_ignore_ascii_case(name))
+    }
+
+    /// Append the set headers, to be placed before the blank line that ends a head
+    pub fn append_to(&self, head: &mut Vec<u8>) {
+        for (name, value) in &self.set {
+            head.extend_from_slice(format!("{}: {}\r\n", name, value).as_bytes());
+        }
+    }
+}
+
+/// Compile a snapshot's header rules by route name; every rule must name one of `route_names`
+pub fn compile(snapshot: &Snapshot, route_names: &[&str]) -> Result<HashMap<String, HeaderRules>, String> {
+    fn rules_for<'r>(rules: &'r mut HashMap<String, HeaderRules>, route_names: &[&str], route: String, flag: &str) -> Result<&'r mut HeaderRules, String> {
+        if !route_names.contains(&route.as_str()) {
+            return Err(format!("The {} rule for '{}' names an unknown route", flag, route));
+        }
+        Ok(rules.entry(route).or_default())
+    }
+
+    let mut rules: HashMap<String, HeaderRules> = HashMap::new();
+    for spec in &snapshot.set_headers {
+        let (route, name, value) = parse_set(spec, "--set-header", REQUEST_MANAGED)?;
+        rules_for(&mut rules, route_names, route, "--set-header")?.request.set(name, value);
+    }
+    for spec in &snapshot.remove_headers {
+        let (route, name) = parse_remove(spec, "--remove-header", REQUEST_MANAGED)?;
+        rules_for(&mut rules, route_names, route, "--remove-header")?.request.remove(&name);
+    }
+    for spec in &snapshot.set_response_headers {
+        let (route, name, value) = parse_set(spec, "--set-response-header", RESPONSE_MANAGED)?;
+        rules_for(&mut rules, route_names, route, "--set-response-header")?.response.set(name, value);
+    }
+    for spec in &snapshot.remove_response_headers {
+        let (route, name) = parse_remove(spec, "--remove-response-header", RESPONSE_MANAGED)?;
+        rules_for(&mut rules, route_names, route, "--remove-response-header")?.response.remove(&name);
+    }
+    for spec in &snapshot.early_hints {
+        let (route, link) = parse_hint(spec)?;
+        let hints = &mut rules_for(&mut rules, route_names, route, "--early-hint")?.early_hints;
+        if hints.is_empty() {
+            hints.extend_from_slice(b"HTTP/1.1 103 Early Hints\r\n\r\n");
+        }
+        // Before the blank line
+        hints.truncate(hints.len() - 2);
+        hints.extend_from_slice(format!("Link: {}\r\n\r\n", link).as_bytes());
+    }
+    Ok(rules)
+}
+
+/// Parse `ROUTE:<URL>; rel=preload`; returns the route name and `Link` value
+fn parse_hint(spec: &str) -> Result<(String, String), String> {
+    // Link values start with the bracketed URL; route names may contain ':' themselves (`re:^/api`)
+    let (route, link) = spec.find(":<").map(|idx| (&spec[..idx], spec[idx + 1..].trim()))
+        .ok_or_else(|| format!("Invalid --early-hint '{}'. Expected format: ROUTE:<URL>; rel=preload", spec))?;
+    if route.is_empty() {
+        return Err(format!("Missing route in --early-hint '{}'", spec));
+    }
+    if !link.contains('>') || link.contains(|c: char| c.is_control()) {
+        return Err(format!("Invalid Link value in --early-hint '{}'", spec));
+    }
+    Ok((route.to_string(), link.to_string()))
+}
+
+/// Parse `ROUTE:Name: value`; returns the route name, header name and value
+fn parse_set(spec: &str, flag: &str, managed: &[&str]) -> Result<(String, String, String), String> {
+    let invalid = || format!("Invalid {} '{}'. Expected format: ROUTE:Name: value", flag, spec);
+    // Route names may contain ':' themselves (`re:^/api`); header names never do
+    let (target, value) = spec.split_once(": ").ok_or_else(invalid)?;
+    let (route, name) = target.rsplit_once(':').ok_or_else(invalid)?;
+    validate(route, name, flag, spec, managed)?;
+    if value.contains(['\r', '\n']) {
+        return Err(format!("Invalid value in {} '{}'", flag, spec));
+    }
+    Ok((route.to_string(), name.to_string(), value.trim().to_string()))
+}
+
+/// Parse `ROUTE:Name`; returns the route name and header name
+fn parse_remove(spec: &str, flag: &str, managed: &[&str]) -> Result<(String, String), String> {
+    let (route, name) = spec.rsplit_once(':')
+        .ok_or_else(|| format!("Invalid {} '{}'. Expected format: ROUTE:Name", flag, spec))?;
+    validate(route, name, flag, spec, managed)?;
+    Ok((route.to_string(), name.to_string()))
+}
+
+fn validate(route: &str, name: &str, flag: &str, spec: &str, managed: &[&str]) -> Result<(), String> {
+    if route.is_empty() {
+        return Err(format!("Missing route in {} '{}'", flag, spec));
+    }
+    let valid_name = !name.is_empty() && name.bytes().all(|b| b.is_ascii_alphanumeric() || b"!#$%&'*+-.^_`|~".contains(&b));
+    if !valid_name {
+        return Err(format!("Invalid header name '{}' in {} '{}'", name, flag, spec));
+    }
+    if managed.iter().any(|m| name.eq_ignore_ascii_case(m)) {
+        return Err(format!("Header '{}' is managed by the proxy and cannot be changed by {}", name, flag));
+    }
+    Ok(())
+}
@@ -0,0 +1,468 @@
+//! Active health checks (`--health-check`): every backend is probed periodically with a TCP connect
+//! or an HTTP `GET`, and taken out of rotation after `--unhealthy-threshold` failed checks in a row,
+//! until `--healthy-threshold` checks in a row pass again. HTTP checks may also assert on the
+//! response's status and body (`--health-status`, `--health-body`, `--health-json`). Each backend is
+//! checked on its own schedule, spread by `--health-jitter`, and `--health-override` gives single
+//! backends their own interval, timeout and thresholds. HTTP checks of TLS backends also note when
+//! their certificates expire, and warn ahead of it (`--backend-cert-warning`).
+
+use crate::events::EventBus;
+use crate::intercept::{self, BodyStatus};
+use crate::logging;
+use crate::metrics::Metrics;
+use crate::resolver::Resolver;
+use crate::routing::{Backend, BackendTls, SharedConfig};
+use crate::response;
+use crate::upstream::BackendStream;
+use crate::x509;
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+use tokio::sync::mpsc;
+use tokio::time::Instant;
+
+/// What a check does
+#[derive(Clone)]
+pub enum Check {
+    /// Pass when the backend accepts a connection (`tcp`)
+    Tcp,
+    /// Pass when `GET PATH` gets a 2xx or 3xx response (`http:/healthz`)
+    Http(String),
+}
+
+impl Check {
+    pub fn parse(spec: &str) -> Result<Self, String> {
+        match spec {
+            "tcp" => Ok(Check::Tcp),
+            "http" => Ok(Check::Http("/".to_string())),
+            _ => match spec.strip_prefix("http:") {
+                Some(path) if path.starts_with('/') && !path.bytes().any(|b| b.is_ascii_whitespace() || b.is_ascii_control()) => {
+                    Ok(Check::Http(path.to_string()))
+                }
+                _ => Err(format!("Invalid health check '{}'. Expected tcp, http or http:/PATH", spec)),
+            },
+        }
+    }
+}
+
+impl std::fmt::Display for Check {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Check::Tcp => f.write_str("TCP connect"),
+            Check::Http(path) => write!(f, "GET {}", path),
+        }
+    }
+}
+
+/// Largest response body an HTTP check reads to match it
+const MAX_BODY: usize = 1024 * 1024;
+
+/// What the response to an HTTP check has to be like to pass
+#[derive(Default)]
+pub struct Expect {
+    /// Passing statuses, as inclusive ranges; 2xx and 3xx when empty
+    statuses: Vec<(u16, u16)>,
+    /// Text the body has to contain
+    body: Option<String>,
+    /// JSON pointers into the body, with the field they were given as and the value they need
+    fields: Vec<(String, String, String)>,
+}
+
+impl Expect {
+    /// `statuses` as codes, ranges and classes (`200,204`, `200-299`, `2xx`), and `fields` as `FIELD=VALUE`
+    /// with dotted fields (`status=ok`, `checks.db.status=up`)
+    pub fn parse(statuses: Option<&str>, body: Option<&str>, fields: &[String]) -> Result<Self, String> {
+        let mut expect = Expect { body: body.map(str::to_string), ..Expect::default() };
+        for status in statuses.into_iter().flat_map(|statuses| statuses.split(',')).map(str::trim) {
+            let invalid = || format!("Invalid health check status '{}'. Expected a status such as 200, a range such as 200-299 or a class such as 2xx", status);
+            let code = |code: &str| code.parse::<u16>().ok().filter(|code| (100..600).contains(code));
+            let range = match (status.split_once('-'), status.strip_suffix("xx").and_then(code_class)) {
+                (Some((low, high)), _) => (code(low).ok_or_else(invalid)?, code(high).ok_or_else(invalid)?),
+                (None, Some(class)) => (class * 100, class * 100 + 99),
+                (None, None) => code(status).map(|code| (code, code)).ok_or_else(invalid)?,
+            };
+            if range.0 > range.1 {
+                return Err(invalid());
+            }
+            expect.statuses.push(range);
+        }
+        for field in fields {
+            match field.split_once('=') {
+                Some((name, value)) if !name.is_empty() && !name.split('.').any(str::is_empty) => {
+                    let pointer = name.split('.').map(|part| format!("/{}", part.replace('~', "~0").replace('/', "~1"))).collect();
+                    expect.fields.push((pointer, name.to_string(), value.to_string()));
+                }
+                _ => return Err(format!("Invalid health check JSON field '{}'. Expected FIELD=VALUE, such as status=ok", field)),
+            }
+        }
+        Ok(expect)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.statuses.is_empty() && !self.reads_body()
+    }
+
+    fn reads_body(&self) -> bool {
+        self.body.is_some() || !self.fields.is_empty()
+    }
+
+    fn check_status(&self, status: u16) -> Result<(), String> {
+        let passes = match self.statuses.is_empty() {
+            true => (200..400).contains(&status),
+            false => self.statuses.iter().any(|&(low, high)| (low..=high).contains(&status)),
+        };
+        if passes { Ok(()) } else { Err(format!("status {}", status)) }
+    }
+
+    fn check_body(&self, body: &[u8]) -> Result<(), String> {
+        if let Some(text) = &self.body {
+            if !body.windows(text.len().max(1)).any(|w| w == text.as_bytes()) {
+                return Err(format!("body without '{}'", text));
+            }
+        }
+        if self.fields.is_empty() {
+            return Ok(());
+        }
+        let json: serde_json::Value = serde_json::from_slice(body).map_err(|e| format!("body is not JSON: {}", e))?;
+        for (pointer, name, wanted) in &self.fields {
+            let value = match json.pointer(pointer) {
+                Some(serde_json::Value::String(value)) => value.clone(),
+                Some(value) => value.to_string(),
+                None => return Err(format!("no JSON field {}", name)),
+            };
+            if value != *wanted {
+                return Err(format!("JSON field {} is '{}', not '{}'", name, value, wanted));
+            }
+        }
+        Ok(())
+    }
+}
+
+/// The class of `Nxx`
+fn code_class(digit: &str) -> Option<u16> {
+    digit.parse().ok().filter(|class| (1..6).contains(class))
+}
+
+impl std::fmt::Display for Expect {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let mut parts = Vec::new();
+        if !self.statuses.is_empty() {
+            let statuses: Vec<String> = self.statuses.iter()
+                .map(|&(low, high)| if low == high { low.to_string() } else { format!("{}-{}", low, high) })
+                .collect();
+            parts.push(format!("status {}", statuses.join(",")));
+        }
+        if let Some(text) = &self.body {
+            parts.push(format!("body containing '{}'", text));
+        }
+        for (_, name, value) in &self.fields {
+            parts.push(format!("{} = '{}'", name, value));
+        }
+        f.write_str(&parts.join(", "))
+    }
+}
+
+/// Largest `--health-jitter`, as in HAProxy's `spread-checks`
+pub const MAX_JITTER: f64 = 50.0;
+
+/// How often new backends are looked for while no check is due
+const RESCAN: Duration = Duration::from_secs(1);
+
+/// Settings of one backend that replace the general ones (`--health-override`)
+#[derive(Default)]
+pub struct Override {
+    interval: Option<Duration>,
+    timeout: Option<Duration>,
+    rise: Option<u32>,
+    fall: Option<u32>,
+}
+
+impl Override {
+    /// `BACKEND=KEY=VALUE[,KEY=VALUE...]` with the keys `interval` and `timeout` (seconds), `rise` and `fall`
+    pub fn parse(spec: &str) -> Result<(String, Self), String> {
+        let invalid = || format!("Invalid health check override '{}'. Expected BACKEND=KEY=VALUE[,KEY=VALUE...] with the keys interval, timeout, rise and fall", spec);
+        let (backend, settings) = spec.split_once('=').filter(|(backend, _)| !backend.is_empty()).ok_or_else(invalid)?;
+        let mut result = Override::default();
+        for setting in settings.split(',') {
+            let (key, value) = setting.split_once('=').ok_or_else(invalid)?;
+            let value: u64 = value.trim().parse().ok().filter(|&value| value > 0).ok_or_else(invalid)?;
+            match key.trim() {
+                "interval" => result.interval = Some(Duration::from_secs(value)),
+                "timeout" => result.timeout = Some(Duration::from_secs(value)),
+                "rise" => result.rise = Some(u32::try_from(value).map_err(|_| invalid())?),
+                "fall" => result.fall = Some(u32::try_from(value).map_err(|_| invalid())?),
+                _ => return Err(invalid()),
+            }
+        }
+        Ok((backend.to_string(), result))
+    }
+}
+
+pub struct HealthConfig {
+    pub check: Check,
+    /// Expectations of HTTP checks' responses
+    pub expect: Expect,
+    pub interval: Duration,
+    pub timeout: Duration,
+    /// Passed checks in a row that bring an unhealthy backend back
+    pub healthy_threshold: u32,
+    /// Failed checks in a row that take a backend out of rotation
+    pub unhealthy_threshold: u32,
+    /// Share of the interval by which each wait between checks varies at random either way, up to 0.5
+    pub jitter: f64,
+    /// Backends' own settings, by backend as written in the routes
+    pub overrides: HashMap<String, Override>,
+    /// How long before a backend's TLS certificate expires it is warned of
+    pub cert_warning: Duration,
+}
+
+impl HealthConfig {
+    fn interval(&self, backend: &str) -> Duration {
+        self.overrides.get(backend).and_then(|o| o.interval).unwrap_or(self.interval)
+    }
+
+    fn timeout(&self, backend: &str) -> Duration {
+        self.overrides.get(backend).and_then(|o| o.timeout).unwrap_or(self.timeout)
+    }
+
+    /// Passed checks in a row that bring a backend back
+    fn rise(&self, backend: &str) -> u32 {
+        self.overrides.get(backend).and_then(|o| o.rise).unwrap_or(self.healthy_threshold)
+    }
+
+    /// Failed checks in a row that take a backend out
+    fn fall(&self, backend: &str) -> u32 {
+        self.overrides.get(backend).and_then(|o| o.fall).unwrap_or(self.unhealthy_threshold)
+    }
+
+    /// A backend's interval, timeout and thresholds
+    pub fn describe(&self, backend: &str) -> String {
+        format!("every {} s, timeout {} s, out after {} failures, back after {} passes",
+            self.interval(backend).as_secs(), self.timeout(backend).as_secs(), self.fall(backend), self.rise(backend))
+    }
+
+    /// The wait before a backend's next check: its interval, varied by the jitter
+    fn next_wait(&self, backend: &str) -> Duration {
+        let interval = self.interval(backend);
+        if self.jitter == 0.0 {
+            return interval;
+        }
+        interval.mul_f64(1.0 + self.jitter * (2.0 * random() - 1.0))
+    }
+
+    /// The wait before a new backend's first check, spread over the jitter's share of its interval
+    fn first_wait(&self, backend: &str) -> Duration {
+        self.interval(backend).mul_f64(self.jitter * random())
+    }
+}
+
+/// A random number from 0 to 1
+fn random() -> f64 {
+    crate::otel::random_id() as f64 / u64::MAX as f64
+}
+
+struct Status {
+    healthy: bool,
+    /// Checks in a row with the other outcome than `healthy` says
+    streak: u32,
+    /// Why the latest check failed, while the backend fails them
+    error: Option<String>,
+    /// When the backend's TLS certificate expires, as of the latest check that got one
+    certificate: Option<SystemTime>,
+    /// The expiry of the certificate last warned of, so that each one is warned of once
+    warned: Option<SystemTime>,
+}
+
+impl Status {
+    fn new() -> Self {
+        Status { healthy: true, streak: 0, error: None, certificate: None, warned: None }
+    }
+}
+
+pub struct HealthChecks {
+    config: HealthConfig,
+    status: Mutex<HashMap<Arc<str>, Status>>,
+    bus: Arc<EventBus>,
+    resolver: Arc<Resolver>,
+    metrics: Arc<Metrics>,
+}
+
+impl HealthChecks {
+    pub fn new(config: HealthConfig, bus: Arc<EventBus>, resolver: Arc<Resolver>, metrics: Arc<Metrics>) -> Self {
+        HealthChecks { config, status: Mutex::default(), bus, resolver, metrics }
+    }
+
+    /// Whether a backend takes connections; backends not checked yet do
+    pub fn is_healthy(&self, backend: &str) -> bool {
+        self.status.lock().unwrap().get(backend).map_or(true, |status| status.healthy)
+    }
+
+    /// A backend's state as checked: whether it is healthy, and why its latest check failed
+    pub fn status(&self, backend: &str) -> Option<(bool, Option<String>)> {
+        self.status.lock().unwrap().get(backend).map(|status| (status.healthy, status.error.clone()))
+    }
+
+    /// When a backend's TLS certificate expires, if a check has seen it
+    pub fn certificate(&self, backend: &str) -> Option<SystemTime> {
+        self.status.lock().unwrap().get(backend).and_then(|status| status.certificate)
+    }
+
+    /// Check the backends of the route table current at the time, each one again an interval after
+    /// its previous check ended, so that a slow backend is never checked twice at once
+    pub async fn run(self: Arc<Self>, config: SharedConfig) {
+        // When each backend is checked next; None while its check runs
+        let mut due: HashMap<Arc<str>, Option<Instant>> = HashMap::new();
+        let (results, mut finished) = mpsc::unbounded_channel();
+        let mut started = false;
+        loop {
+            let now = Instant::now();
+            let targets: Vec<(Backend, Option<BackendTls>)> = config.load().checked_backends().into_iter()
+                .map(|(backend, tls)| (backend.clone(), tls.copied()))
+                .collect();
+            // Backends no longer routed to are forgotten; those at startup are checked at once
+            due.retain(|name, _| targets.iter().any(|(backend, _)| backend.name == *name));
+            self.status.lock().unwrap().retain(|name, _| due.contains_key(name));
+            self.metrics.retain_backend_certificates(|name| due.contains_key(name));
+            for (backend, tls) in targets {
+                let next = due.entry(backend.name.clone())
+                    .or_insert_with(|| Some(if started { now + self.config.first_wait(&backend.name) } else { now }));
+                if !matches!(next, Some(at) if *at <= now) {
+                    continue;
+                }
+                *next = None;
+                let (checks, results) = (self.clone(), results.clone());
+                tokio::spawn(async move {
+                    let timeout = checks.config.timeout(&backend.name);
+                    let mut certificate = None;
+                    let checking = check(&checks.config, &backend, tls.as_ref(), &checks.resolver, &mut certificate);
+                    let result = match tokio::time::timeout(timeout, checking).await {
+                        Ok(result) => result,
+                        Err(_) => Err(format!("no answer within {} ms", timeout.as_millis())),
+                    };
+                    let _ = results.send((backend.name, result, certificate));
+                });
+            }
+            started = true;
+
+            let wake = due.values().flatten().min().map_or(now + RESCAN, |&next| next.min(now + RESCAN));
+            tokio::select! {
+                Some((name, result, certificate)) = finished.recv() => {
+                    // A check of a backend removed meanwhile is ignored
+                    if let Some(next) = due.get_mut(&name) {
+                        *next = Some(Instant::now() + self.config.next_wait(&name));
+                        if let Some(expires) = certificate {
+                            self.record_certificate(&name, expires);
+                        }
+                        self.record(name, result);
+                    }
+                }
+                _ = tokio::time::sleep_until(wake) => {}
+            }
+        }
+    }
+
+    /// Note when a backend's certificate expires, and warn once of one expiring within `--backend-cert-warning`
+    fn record_certificate(&self, name: &Arc<str>, expires: SystemTime) {
+        self.metrics.record_backend_certificate(name, expires);
+        let mut status = self.status.lock().unwrap();
+        let current = status.entry(name.clone()).or_insert_with(Status::new);
+        current.certificate = Some(expires);
+        let days = x509::days_remaining(expires);
+        if current.warned == Some(expires) || days * 86400.0 > self.config.cert_warning.as_secs_f64() {
+            return;
+        }
+        current.warned = Some(expires);
+        let when = response::rfc3339(expires);
+        if days < 0.0 {
+            logging::warning(format!("TLS certificate of backend {} expired {:.0} days ago, at {}", name, -days, when));
+        } else {
+            logging::warning(format!("TLS certificate of backend {} expires in {:.0} days, at {}", name, days, when));
+        }
+        self.bus.publish("backend_certificate_expiring", serde_json::json!({
+            "backend": &**name,
+            "expires": when,
+            "days_remaining": (days * 100.0).round() / 100.0,
+        }));
+    }
+
+    /// Count the result of a backend's check
+    fn record(&self, name: Arc<str>, result: Result<(), String>) {
+        let mut status = self.status.lock().unwrap();
+        let current = status.entry(name.clone()).or_insert_with(Status::new);
+        let passed = result.is_ok();
+        current.error = result.err();
+        if passed == current.healthy {
+            current.streak = 0;
+            return;
+        }
+        current.streak += 1;
+        let threshold = if passed { self.config.rise(&name) } else { self.config.fall(&name) };
+        if current.streak < threshold {
+            return;
+        }
+        current.healthy = passed;
+        current.streak = 0;
+        if passed {
+            logging::info(format!("Backend {} is healthy again", name));
+            self.bus.publish("backend_healthy", serde_json::json!({ "backend": &*name }));
+        } else {
+            let error = current.error.as_deref().unwrap_or_default();
+            logging::warning(format!("Backend {} is unhealthy, out of rotation after {} failed health checks in a row: {}", name, threshold, error));
+            self.bus.publish("backend_unhealthy", serde_json::json!({ "backend": &*name, "error": error }));
+        }
+    }
+}
+
+/// Run one check against a backend; an HTTP check of a TLS backend sets when its certificate expires
+async fn check(config: &HealthConfig, backend: &Backend, tls: Option<&BackendTls>, resolver: &Arc<Resolver>, certificate: &mut Option<SystemTime>) -> Result<(), String> {
+    let tcp = resolver.connect(backend, &mut None, TcpStream::connect).await.map_err(|e| format!("connect failed: {}", e))?;
+    let Check::Http(path) = &config.check else {
+        return Ok(());
+    };
+    let mut stream = BackendStream::connect(tcp, backend, tls).await.map_err(|e| format!("TLS handshake failed: {}", e))?;
+    *certificate = stream.certificate_expiry();
+    let request = format!("GET {} HTTP/1.1\r\nHost: {}\r\nUser-Agent: reverse-http-proxy health check\r\nConnection: close\r\n\r\n", path, backend.name);
+    stream.write_all(request.as_bytes()).await.map_err(|e| format!("request failed: {}", e))?;
+
+    // Without expectations of the body, only the status line matters
+    let mut response = Vec::with_capacity(256);
+    let mut buf = [0u8; 4096];
+    let mut eof = false;
+    while !response.contains(&b'\n') && response.len() < 1024 {
+        eof = read(&mut stream, &mut buf, &mut response).await?;
+        if eof {
+            break;
+        }
+    }
+    let status = intercept::status(&response).ok_or("no HTTP response")?;
+    config.expect.check_status(status)?;
+    if !config.expect.reads_body() {
+        return Ok(());
+    }
+
+    loop {
+        if let Some(end) = response.windows(4).position(|w| w == b"\r\n\r\n") {
+            let (head, rest) = response.split_at(end + 4);
+            match intercept::framing(head).map(|framing| framing.decode(rest, eof)) {
+                Some(BodyStatus::Complete(body, _)) => return config.expect.check_body(&body),
+                Some(BodyStatus::Incomplete) => {}
+                _ => return Err("invalid response body".to_string()),
+            }
+        } else if eof || response.len() > intercept::MAX_RESPONSE_HEAD {
+            return Err("invalid response head".to_string());
+        }
+        if response.len() > intercept::MAX_RESPONSE_HEAD + MAX_BODY {
+            return Err(format!("body larger than {} bytes", MAX_BODY));
+        }
+        eof = read(&mut stream, &mut buf, &mut response).await?;
+    }
+}
+
+/// Read more of a response; returns whether the backend closed the connection
+async fn read(stream: &mut BackendStream, buf: &mut [u8], response: &mut Vec<u8>) -> Result<bool, String> {
+    let n = stream.read(buf).await.map_err(|e| format!("response failed: {}", e))?;
+    response.extend_from_slice(&buf[..n]);
+    Ok(n == 0)
+}
@@ -0,0 +1,236 @@
+//! Heartbeats for long polls (`;heartbeat`): while a backend is silent, the proxy sends bytes the
+//! client ignores, so that load balancers and other intermediaries do not time out the idle
+//! response. Before the response head they are `102 Processing` interim responses; in chunked
+//! and close-delimited bodies they are the route's heartbeat data, in an extra chunk for chunked
+//! ones. Bodies with a `Content-Length` cannot take extra bytes and get none.
+
+use crate::intercept::{self, BodyFraming};
+use std::future::Future;
+use std::io;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use std::time::Duration;
+use tokio::io::{AsyncRead, ReadBuf};
+use tokio::time::{Instant, Sleep};
+
+/// Longest heartbeat data accepted
+const MAX_DATA: usize = 1024;
+
+/// Longest chunk size line tracked; longer ones end the heartbeats rather than being buffered
+const MAX_CHUNK_LINE: usize = 4096;
+
+const PROCESSING: &[u8] = b"HTTP/1.1 102 Processing\r\n\r\n";
+
+/// A route's heartbeat (`;heartbeat=SECONDS[:DATA]`)
+#[derive(Clone, PartialEq)]
+pub struct Heartbeat {
+    /// Silence after which a heartbeat is sent
+    interval: Duration,
+    /// Body bytes of a heartbeat; a newline, which JSON and event stream clients skip, by default
+    data: Vec<u8>,
+}
+
+impl Heartbeat {
+    pub fn parse(value: &str, route: &str) -> Result<Self, String> {
+        let invalid = || format!("Invalid heartbeat '{}' in route '{}'. Expected format: heartbeat=SECONDS[:DATA]", value, route);
+        let (seconds, data) = value.split_once(':').unwrap_or((value, "\\n"));
+        let seconds: u64 = seconds.parse().ok().filter(|&seconds| seconds > 0).ok_or_else(invalid)?;
+        let data = unescape(data).filter(|data| !data.is_empty() && data.len() <= MAX_DATA).ok_or_else(invalid)?;
+        Ok(Heartbeat { interval: Duration::from_secs(seconds), data })
+    }
+}
+
+/// Heartbeat data with `\n`, `\r`, `\t` and `\\` escapes resolved
+fn unescape(data: &str) -> Option<Vec<u8>> {
+    let mut bytes = Vec::with_capacity(data.len());
+    let mut chars = data.chars();
+    while let Some(c) = chars.next() {
+        let c = match c {
+            '\\' => match chars.next()? {
+                'n' => '\n',
+                'r' => '\r',
+                't' => '\t',
+                '\\' => '\\',
+                _ => return None,
+            },
+            c => c,
+        };
+        let mut utf8 = [0; 4];
+        bytes.extend_from_slice(c.encode_utf8(&mut utf8).as_bytes());
+    }
+    Some(bytes)
+}
+
+/// Where the backend's response stream is, as far as heartbeats go
+enum State {
+    /// Before a response head, with the part of it received so far
+    Head(Vec<u8>),
+    /// In a chunk size line, with the part of it received so far
+    ChunkSize(Vec<u8>),
+    /// In the data of a chunk, with the bytes left
+    ChunkData(u64),
+    /// In the CRLF after a chunk's data, with the bytes left
+    ChunkEnd(usize),
+    /// In a body that ends when the connection closes
+    Close,
+    /// Past the first response's body, or in one that cannot take heartbeats
+    Done,
+}
+
+/// The backend side of a connection, producing heartbeats while the backend is silent on the
+/// first response. Without a heartbeat it reads the backend unchanged.
+pub struct HeartbeatReader<'h, R> {
+    inner: R,
+    heartbeat: Option<&'h Heartbeat>,
+    /// Whether the client may be sent interim responses (HTTP/1.1)
+    interim: bool,
+    /// Whether the request was HEAD, whose response has no body
+    head_request: bool,
+    state: State,
+    timer: Pin<Box<Sleep>>,
+    /// A heartbeat not yet read in full, and how much of it was
+    pending: Vec<u8>,
+    pending_pos: usize,
+}
+
+impl<'h, R: AsyncRead + Unpin> HeartbeatReader<'h, R> {
+    pub fn new(inner: R, heartbeat: Option<&'h Heartbeat>, interim: bool, head_request: bool) -> Self {
+        let interval = heartbeat.map_or(Duration::ZERO, |heartbeat| heartbeat.interval);
+        HeartbeatReader {
+            inner,
+            heartbeat,
+            interim,
+            head_request,
+            state: State::Head(Vec::new()),
+            timer: Box::pin(tokio::time::sleep(interval)),
+            pending: Vec::new(),
+            pending_pos: 0,
+        }
+    }
+
+    /// Follow the response stream through `data`, the next bytes from the backend
+    fn track(&mut self, mut data: &[u8]) {
+        while !data.is_empty() {
+            match &mut self.state {
+                State::Head(seen) => {
+                    let searched = seen.len().saturating_sub(3);
+                    seen.extend_from_slice(data);
+                    let Some(end) = seen[searched..].windows(4).position(|w| w == b"\r\n\r\n").map(|pos| searched + pos + 4) else {
+                        if seen.len() > intercept::MAX_RESPONSE_HEAD {
+                            self.state = State::Done;
+                        }
+                        return;
+                    };
+                    data = &data[data.len() - (seen.len() - end)..];
+                    let head = std::mem::take(seen);
+                    self.state = self.after_head(&head[..end]);
+                }
+                State::ChunkSize(line) => {
+                    let Some(newline) = data.iter().position(|&b| b == b'\n') else {
+                        line.extend_from_slice(data);
+                        if line.len() > MAX_CHUNK_LINE {
+                            self.state = State::Done;
+                        }
+                        return;
+                    };
+                    line.extend_from_slice(&data[..newline]);
+                    data = &data[newline + 1..];
+                    let size = std::str::from_utf8(line).ok()
+                        .and_then(|line| u64::from_str_radix(line.split(';').next().unwrap_or("").trim(), 16).ok());
+                    // The last chunk (and its trailers) ends the response
+                    self.state = match size {
+                        Some(size) if size > 0 => State::ChunkData(size),
+                        _ => State::Done,
+                    };
+                }
+                State::ChunkData(left) => {
+                    let taken = (*left).min(data.len() as u64);
+                    *left -= taken;
+                    data = &data[taken as usize..];
+                    if *left == 0 {
+                        self.state = State::ChunkEnd(2);
+                    }
+                }
+                State::ChunkEnd(left) => {
+                    let taken = (*left).min(data.len());
+                    *left -= taken;
+                    data = &data[taken..];
+                    if *left == 0 {
+                        self.state = State::ChunkSize(Vec::new());
+                    }
+                }
+                State::Close | State::Done => return,
+            }
+        }
+    }
+
+    /// The state after a complete response head
+    fn after_head(&self, head: &[u8]) -> State {
+        match intercept::status(head) {
+            // The connection carries another protocol after 101
+            Some(101) => State::Done,
+            Some(100..=199) => State::Head(Vec::new()),
+            Some(204) | Some(304) => State::Done,
+            Some(_) if self.head_request => State::Done,
+            Some(_) => match intercept::framing(head) {
+                Some(BodyFraming::Chunked) => State::ChunkSize(Vec::new()),
+                Some(BodyFraming::Close) => State::Close,
+                _ => State::Done,
+            },
+            None => State::Done,
+        }
+    }
+
+    /// The heartbeat that fits where the stream is, if any does
+    fn beat(&self, heartbeat: &Heartbeat) -> Option<Vec<u8>> {
+        match &self.state {
+            State::Head(seen) if seen.is_empty() && self.interim => Some(PROCESSING.to_vec()),
+            State::ChunkSize(line) if line.is_empty() => {
+                let mut chunk = format!("{:x}\r\n", heartbeat.data.len()).into_bytes();
+                chunk.extend_from_slice(&heartbeat.data);
+                chunk.extend_from_slice(b"\r\n");
+                Some(chunk)
+            }
+            State::Close => Some(heartbeat.data.clone()),
+            _ => None,
+        }
+    }
+}
+
+impl<R: AsyncRead + Unpin> AsyncRead for HeartbeatReader<'_, R> {
+    fn poll_read(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        let Some(heartbeat) = this.heartbeat.filter(|_| !matches!(this.state, State::Done)) else {
+            return Pin::new(&mut this.inner).poll_read(cx, buf);
+        };
+
+        if this.pending_pos < this.pending.len() {
+            let n = buf.remaining().min(this.pending.len() - this.pending_pos);
+            buf.put_slice(&this.pending[this.pending_pos..this.pending_pos + n]);
+            this.pending_pos += n;
+            return Poll::Ready(Ok(()));
+        }
+
+        let before = buf.filled().len();
+        match Pin::new(&mut this.inner).poll_read(cx, buf) {
+            Poll::Ready(Ok(())) => {
+                this.track(&buf.filled()[before..]);
+                this.timer.as_mut().reset(Instant::now() + heartbeat.interval);
+                Poll::Ready(Ok(()))
+            }
+            Poll::Pending => {
+                while this.timer.as_mut().poll(cx).is_ready() {
+                    this.timer.as_mut().reset(Instant::now() + heartbeat.interval);
+                    if let Some(beat) = this.beat(heartbeat) {
+                        let n = buf.remaining().min(beat.len());
+                        buf.put_slice(&beat[..n]);
+                        (this.pending, this.pending_pos) = (beat, n);
+                        return Poll::Ready(Ok(()));
+                    }
+                }
+                Poll::Pending
+            }
+            ready => ready,
+        }
+    }
+}
@@ -0,0 +1,199 @@
+//! Upload scanning through an ICAP server (RFC 3507), such as c-icap with ClamAV: request bodies
+//! on `;scan` routes are sent to the scanner with REQMOD before they reach the backend, and
+//! requests the scanner rejects are blocked.
+
+use std::io;
+use std::time::Duration;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+
+/// Default ICAP port
+const ICAP_PORT: u16 = 1344;
+
+/// Upper bound for a scan, from connecting to the verdict
+const SCAN_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Largest ICAP response head accepted; the body of a blocking response is not read
+const MAX_RESPONSE_HEAD: usize = 64 * 1024;
+
+/// An ICAP REQMOD service (`icap://scanner:1344/avscan`)
+pub struct Scanner {
+    /// `host:port`, for connecting and the `Host` header
+    authority: String,
+    url: String,
+}
+
+/// The scanner's decision on a request
+pub enum Verdict {
+    Clean,
+    /// The scanner answered with a response of its own; what it found, when it says
+    Blocked(Option<String>),
+}
+
+impl Scanner {
+    pub fn parse(url: &str) -> Result<Self, String> {
+        let rest = url.strip_prefix("icap://")
+            .ok_or_else(|| format!("Unsupported ICAP URL '{}': expected icap://HOST[:PORT]/SERVICE", url))?;
+        let (authority, service) = rest.split_once('/').unwrap_or((rest, ""));
+        if authority.is_empty() || authority.starts_with(':') {
+            return Err(format!("Missing host in ICAP URL '{}'", url));
+        }
+        // IPv6 literals are bracketed: [::1]:1344
+        let has_port = if authority.starts_with('[') { authority.contains("]:") } else { authority.contains(':') };
+        let authority = match authority.rsplit_once(':').filter(|_| has_port) {
+            Some((_, port)) => {
+                port.parse::<u16>().map_err(|_| format!("Invalid port in ICAP URL '{}'", url))?;
+                authority.to_string()
+            }
+            None => format!("{}:{}", authority, ICAP_PORT),
+        };
+        Ok(Scanner { url: format!("icap://{}/{}", authority, service), authority })
+    }
+
+    /// Scan a request: `head` is the HTTP request head (blank line included), `body` its decoded body
+    pub async fn scan(&self, head: &[u8], body: &[u8]) -> io::Result<Verdict> {
+        tokio::time::timeout(SCAN_TIMEOUT, self.reqmod(head, body)).await
+            .map_err(|_| io::Error::new(io::ErrorKind::TimedOut, "ICAP scan timed out"))?
+    }
+
+    async fn reqmod(&self, head: &[u8], body: &[u8]) -> io::Result<Verdict> {
+        let mut stream = TcpStream::connect(&self.authority).await?;
+
+        // `Allow: 204` lets the scanner answer "unmodified" without echoing the request back
+        let icap_head = format!(
+            "REQMOD {} ICAP/1.0\r\nHost: {}\r\nAllow: 204\r\nEncapsulated: req-hdr=0, req-body={}\r\n\r\n",
+            self.url, self.authority, head.len()
+        );
+        let mut message = Vec::with_capacity(icap_head.len() + head.len() + body.len() + 32);
+        message.extend_from_slice(icap_head.as_bytes());
+        message.extend_from_slice(head);
+        if !body.is_empty() {
+            message.extend_from_slice(format!("{:x}\r\n", body.len()).as_bytes());
+            message.extend_from_slice(body);
+            message.extend_from_slice(b"\r\n");
+        }
+        message.extend_from_slice(b"0\r\n\r\n");
+        stream.write_all(&message).await?;
+
+        let response = read_head(&mut stream).await?;
+        let response = String::from_utf8_lossy(&response);
+        let status_line = response.lines().next().unwrap_or("");
+        let status = status_line.strip_prefix("ICAP/1.0 ")
+            .and_then(|rest| rest.get(..3))
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, format!("invalid ICAP response '{}'", status_line)))?;
+
+        let header = |name: &str| {
+            response.lines().skip(1)
+                .filter_map(|line| line.split_once(':'))
+                .find(|(key, _)| key.trim().eq_ignore_ascii_case(name))
+                .map(|(_, value)| value.trim().to_string())
+        };
+        match status {
+            "204" => Ok(Verdict::Clean),
+            // A replacement response (an error page) blocks the request; a returned request is let through
+            "200" if header("encapsulated").is_some_and(|e| e.contains("res-hdr")) => {
+                Ok(Verdict::Blocked(header("x-infection-found").or_else(|| header("x-violations-found")).map(threat_name)))
+            }
+            "200" => Ok(Verdict::Clean),
+            _ => Err(io::Error::new(io::ErrorKind::Other, format!("ICAP server answered '{}'", status_line))),
+        }
+    }
+}
+
+/// Read an ICAP response head, up to and including the blank line; encapsulated HTTP messages follow it
+async fn read_head(stream: &mut TcpStream) -> io::Result<Vec<u8>> {
+    let mut head = Vec::new();
+    let mut buf = [0u8; 4096];
+    loop {
+        if let Some(end) = head.windows(4).position(|w| w == b"\r\n\r\n") {
+            head.truncate(end + 4);
+            return Ok(head);
+        }
+        if head.len() > MAX_RESPONSE_HEAD {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "ICAP response head too large"));
+        }
+        let n = stream.read(&mut buf).await?;
+        if n == 0 {
+            return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "ICAP server closed the connection"));
+        }
+        head.extend_from_slice(&buf[..n]);
+    }
+}
+
+/// The `Threat=` part of an `X-Infection-Found` header (`Type=0; Resolution=2; Threat=Eicar-Signature;`),
+/// or the whole value
+fn threat_name(value: String) -> String {
+    let threat = value.split(';').find_map(|part| part.trim().strip_prefix("Threat=")).map(str::to_string);
+    threat.unwrap_or(value)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::net::TcpListener;
+
+    #[test]
+    fn parses_service_urls() {
+        let url = |url| Scanner::parse(url).map(|scanner| (scanner.authority, scanner.url));
+        assert_eq!(url("icap://scanner/avscan"), Ok(("scanner:1344".into(), "icap://scanner:1344/avscan".into())));
+        assert_eq!(url("icap://scanner:11344/avscan"), Ok(("scanner:11344".into(), "icap://scanner:11344/avscan".into())));
+        assert_eq!(url("icap://[::1]/srv"), Ok(("[::1]:1344".into(), "icap://[::1]:1344/srv".into())));
+        assert_eq!(url("icap://[::1]:2000"), Ok(("[::1]:2000".into(), "icap://[::1]:2000/".into())));
+        assert!(url("http://scanner/avscan").is_err());
+        assert!(url("icap://:1344/avscan").is_err());
+        assert!(url("icap://scanner:port/avscan").is_err());
+    }
+
+    #[test]
+    fn threat_names() {
+        assert_eq!(threat_name("Type=0; Resolution=2; Threat=Eicar-Signature;".into()), "Eicar-Signature");
+        assert_eq!(threat_name("Win.Test.EICAR_HDB-1".into()), "Win.Test.EICAR_HDB-1");
+    }
+
+    /// Scan a request with a scanner giving `answer`, returning the verdict and the REQMOD sent
+    fn scan(answer: &'static str, body: &'static [u8]) -> (io::Result<Verdict>, String) {
+        tokio::runtime::Builder::new_current_thread().enable_all().build().unwrap().block_on(async {
+            let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+            let scanner = Scanner::parse(&format!("icap://{}/avscan", listener.local_addr().unwrap())).unwrap();
+            let server = tokio::spawn(async move {
+                let (mut stream, _) = listener.accept().await.unwrap();
+                let mut request = Vec::new();
+                let mut buf = [0u8; 4096];
+                while !request.ends_with(b"0\r\n\r\n") {
+                    let n = stream.read(&mut buf).await.unwrap();
+                    request.extend_from_slice(&buf[..n]);
+                }
+                stream.write_all(answer.as_bytes()).await.unwrap();
+                String::from_utf8(request).unwrap()
+            });
+            let verdict = scanner.scan(b"POST /upload HTTP/1.1\r\nHost: example.com\r\n\r\n", body).await;
+            (verdict, server.await.unwrap())
+        })
+    }
+
+    #[test]
+    fn sends_requests_with_reqmod() {
+        let (verdict, request) = scan("ICAP/1.0 204 No Content\r\n\r\n", b"hello");
+        assert!(matches!(verdict, Ok(Verdict::Clean)));
+        let authority = request.lines().nth(1).unwrap().strip_prefix("Host: ").unwrap().to_string();
+        assert_eq!(request, format!(
+            "REQMOD icap://{0}/avscan ICAP/1.0\r\nHost: {0}\r\nAllow: 204\r\nEncapsulated: req-hdr=0, req-body=44\r\n\r\n\
+             POST /upload HTTP/1.1\r\nHost: example.com\r\n\r\n5\r\nhello\r\n0\r\n\r\n",
+            authority
+        ));
+        let (_, request) = scan("ICAP/1.0 204 No Content\r\n\r\n", b"");
+        assert!(request.ends_with("example.com\r\n\r\n0\r\n\r\n"));
+    }
+
+    #[test]
+    fn verdicts_from_responses() {
+        let (verdict, _) = scan("ICAP/1.0 200 OK\r\nEncapsulated: req-hdr=0, null-body=40\r\n\r\n", b"x");
+        assert!(matches!(verdict, Ok(Verdict::Clean)));
+        let blocked = "ICAP/1.0 200 OK\r\nX-Infection-Found: Type=0; Resolution=2; Threat=Eicar-Signature;\r\nEncapsulated: res-hdr=0, res-body=100\r\n\r\n";
+        assert!(matches!(scan(blocked, b"x").0, Ok(Verdict::Blocked(Some(threat))) if threat == "Eicar-Signature"));
+        let unnamed = "ICAP/1.0 200 OK\r\nEncapsulated: res-hdr=0, null-body=50\r\n\r\n";
+        assert!(matches!(scan(unnamed, b"x").0, Ok(Verdict::Blocked(None))));
+        assert!(scan("ICAP/1.0 500 Server Error\r\n\r\n", b"x").0.is_err());
+        assert!(scan("HTTP/1.1 200 OK\r\n\r\n", b"x").0.is_err());
+    }
+}
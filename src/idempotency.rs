@@ -0,0 +1,236 @@
+//! Deduplication of retried requests on `;idempotency` routes: a `POST` or `PATCH` carrying an
+//! `Idempotency-Key` header is forwarded once, and repeats within the TTL get the backend's stored
+//! response instead of being sent again, so a client retrying a payment after a timeout cannot
+//! charge twice.
+
+use crate::intercept::{is_header, status};
+use crate::request::RequestHead;
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+/// How long responses are kept when the route gives no TTL
+pub const DEFAULT_TTL: Duration = Duration::from_secs(24 * 3600);
+
+/// Keys remembered at most; more requests are forwarded without one until some expire
+const MAX_ENTRIES: usize = 10_000;
+
+/// Largest response stored
+const MAX_RESPONSE: usize = 1024 * 1024;
+
+/// Stored responses take this much memory at most
+const MAX_BYTES: usize = 64 * 1024 * 1024;
+
+/// Longest `Idempotency-Key` accepted; longer ones are refused as malformed
+const MAX_KEY_LEN: usize = 255;
+
+enum State {
+    /// The first request with the key is still being answered
+    Pending,
+    /// The backend's response, ready to send
+    Done(Arc<[u8]>),
+}
+
+struct Entry {
+    /// The request the key was first used for: method, target and body length
+    fingerprint: String,
+    state: State,
+    expires: Instant,
+}
+
+#[derive(Default)]
+pub struct IdempotencyCache {
+    entries: Mutex<Entries>,
+}
+
+#[derive(Default)]
+struct Entries {
+    by_key: HashMap<String, Entry>,
+    /// Size of the stored responses
+    bytes: usize,
+}
+
+/// What to do with a request carrying an `Idempotency-Key`
+pub enum Lookup<'a> {
+    /// Forward it, and store the response through this
+    Forward(Store<'a>),
+    /// A repeat; send the stored response
+    Replay(Arc<[u8]>),
+    /// A repeat of a request whose response has not arrived yet (`409 Conflict`)
+    InProgress,
+    /// The key was used for a different request (`422 Unprocessable Content`)
+    Mismatch,
+    /// The key is too long (`400 Bad Request`)
+    Invalid,
+}
+
+/// The cache key and fingerprint of a request that `;idempotency` applies to, or None for requests
+/// without a key or with methods that are idempotent anyway. Keys are scoped to the route, the Host
+/// and the client's credentials (`Authorization` and `Cookie`), so that one client cannot fetch
+/// another's response by guessing its key.
+pub fn key(route: &str, head: &RequestHead) -> Option<(String, String)> {
+    if head.method != "POST" && head.method != "PATCH" {
+        return None;
+    }
+    let key = head.header_str("idempotency-key")?.trim();
+    if key.is_empty() {
+        return None;
+    }
+    let host = head.header_str("host").unwrap_or("").to_ascii_lowercase();
+    let authorization = head.header_str("authorization").unwrap_or("");
+    let cookie = head.header_str("cookie").unwrap_or("");
+    let query = head.query.map_or(String::new(), |query| format!("?{}", query));
+    let length = head.header_str("content-length").unwrap_or("");
+    let scope = format!("{}\n{}\n{}\n{}", route, host, authorization, cookie);
+    Some((format!("{}\n{}", scope, key), format!("{} {}{} {}", head.method, head.path, query, length)))
+}
+
+impl IdempotencyCache {
+    /// Look a request up, marking its key as in progress when it is the first with it
+    pub fn begin(&self, key: String, fingerprint: String, ttl: Duration) -> Lookup<'_> {
+        if key.rsplit('\n').next().is_some_and(|key| key.len() > MAX_KEY_LEN) {
+            return Lookup::Invalid;
+        }
+        let now = Instant::now();
+        let mut entries = self.entries.lock().unwrap();
+        if let Some(entry) = entries.by_key.get(&key).filter(|entry| entry.expires > now) {
+            if entry.fingerprint != fingerprint {
+                return Lookup::Mismatch;
+            }
+            return match &entry.state {
+                State::Pending => Lookup::InProgress,
+                State::Done(response) => Lookup::Replay(response.clone()),
+            };
+        }
+        if entries.by_key.len() >= MAX_ENTRIES {
+            entries.expire(now);
+        }
+        if entries.by_key.len() < MAX_ENTRIES {
+            entries.remove(&key);
+            entries.by_key.insert(key.clone(), Entry { fingerprint, state: State::Pending, expires: now + ttl });
+        }
+        Lookup::Forward(Store { cache: self, key })
+    }
+}
+
+impl Entries {
+    fn remove(&mut self, key: &str) {
+        if let Some(Entry { state: State::Done(response), .. }) = self.by_key.remove(key) {
+            self.bytes -= response.len();
+        }
+    }
+
+    fn expire(&mut self, now: Instant) {
+        let mut freed = 0;
+        self.by_key.retain(|_, entry| {
+            let keep = entry.expires > now;
+            if let (false, State::Done(response)) = (keep, &entry.state) {
+                freed += response.len();
+            }
+            keep
+        });
+        self.bytes -= freed;
+    }
+}
+
+/// Where the response to a request with a new key goes once it has been forwarded. Dropped without
+/// a stored response (the backend failed, or sent one that is not kept), the key is forgotten so
+/// that the client's retry is forwarded.
+pub struct Store<'a> {
+    cache: &'a IdempotencyCache,
+    key: String,
+}
+
+impl Store<'_> {
+    /// Keep a complete response as forwarded: any but server errors and `429 Too Many Requests`,
+    /// which the client should be able to retry
+    pub fn insert(&self, head: &[u8], body: &[u8]) {
+        let storable = status(head).is_some_and(|status| status < 500 && status != 429);
+        if !storable || head.len() + body.len() > MAX_RESPONSE {
+            return;
+        }
+
+        // Replayed, the response ends the connection like the proxy's other responses
+        let mut response = Vec::with_capacity(head.len() + body.len() + 45);
+        for (idx, line) in head.split_inclusive(|&b| b == b'\n').enumerate() {
+            if idx > 0 && (is_header(line, "connection") || is_header(line, "keep-alive")) {
+                continue;
+            }
+            if idx > 0 && (line == b"\r\n" || line == b"\n") {
+                response.extend_from_slice(b"Idempotent-Replayed: true\r\nConnection: close\r\n");
+            }
+            response.extend_from_slice(line);
+        }
+        response.extend_from_slice(body);
+
+        let now = Instant::now();
+        let mut entries = self.cache.entries.lock().unwrap();
+        if entries.bytes + response.len() > MAX_BYTES {
+            entries.expire(now);
+            if entries.bytes + response.len() > MAX_BYTES {
+                return;
+            }
+        }
+        let size = response.len();
+        let Some(entry) = entries.by_key.get_mut(&self.key).filter(|entry| matches!(entry.state, State::Pending)) else {
+            return;
+        };
+        entry.state = State::Done(response.into());
+        entries.bytes += size;
+    }
+}
+
+impl Drop for Store<'_> {
+    fn drop(&mut self) {
+        let mut entries = self.cache.entries.lock().unwrap();
+        if entries.by_key.get(&self.key).is_some_and(|entry| matches!(entry.state, State::Pending)) {
+            entries.by_key.remove(&self.key);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::request::MAX_HEADERS;
+
+    fn scoped(method: &str, headers: &str) -> Option<(String, String)> {
+        let data = format!("{} /payments?x=1 HTTP/1.1\r\n{}\r\n", method, headers);
+        let mut storage = [httparse::EMPTY_HEADER; MAX_HEADERS];
+        key("/payments", &RequestHead::parse(data.as_bytes(), &mut storage).unwrap())
+    }
+
+    #[test]
+    fn keys_apply_to_post_and_patch() {
+        let (_, fingerprint) = scoped("POST", "Idempotency-Key: k1\r\nContent-Length: 12\r\n").unwrap();
+        assert_eq!(fingerprint, "POST /payments?x=1 12");
+        assert!(scoped("PATCH", "Idempotency-Key: k1\r\n").is_some());
+        assert!(scoped("PUT", "Idempotency-Key: k1\r\n").is_none());
+        assert!(scoped("POST", "Idempotency-Key:  \r\n").is_none());
+        assert!(scoped("POST", "Host: example.com\r\n").is_none());
+    }
+
+    #[test]
+    fn keys_are_scoped_to_host_and_credentials() {
+        let key = |headers: &str| scoped("POST", &format!("Idempotency-Key: k1\r\n{}", headers)).unwrap().0;
+        let base = key("Host: shop.example\r\nAuthorization: Bearer a\r\nCookie: session=1\r\n");
+        assert_eq!(base, key("Host: SHOP.example\r\nAuthorization: Bearer a\r\nCookie: session=1\r\n"));
+        assert_ne!(base, key("Host: other.example\r\nAuthorization: Bearer a\r\nCookie: session=1\r\n"));
+        assert_ne!(base, key("Host: shop.example\r\nAuthorization: Bearer b\r\nCookie: session=1\r\n"));
+        assert_ne!(base, key("Host: shop.example\r\nAuthorization: Bearer a\r\nCookie: session=2\r\n"));
+        assert_ne!(key("Cookie: session=1\r\n"), key("Cookie: session=2\r\n"));
+    }
+
+    #[test]
+    fn repeats_are_replayed_or_refused() {
+        let cache = IdempotencyCache::default();
+        let ttl = Duration::from_secs(60);
+        let Lookup::Forward(store) = cache.begin("scope\nk1".into(), "POST /a 2".into(), ttl) else { panic!("not forwarded") };
+        assert!(matches!(cache.begin("scope\nk1".into(), "POST /a 2".into(), ttl), Lookup::InProgress));
+        store.insert(b"HTTP/1.1 201 Created\r\nContent-Length: 2\r\n\r\n", b"ok");
+        drop(store);
+        assert!(matches!(cache.begin("scope\nk1".into(), "POST /a 2".into(), ttl), Lookup::Replay(_)));
+        assert!(matches!(cache.begin("scope\nk1".into(), "POST /b 2".into(), ttl), Lookup::Mismatch));
+        assert!(matches!(cache.begin(format!("scope\n{}", "k".repeat(256)), "POST /a 2".into(), ttl), Lookup::Invalid));
+    }
+}
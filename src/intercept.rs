@@ -0,0 +1,736 @@
+//! Rewriting of backend responses before they reach the client. Only the first response is
+//! touched (its head, and with `--sub-filter` or `;digest=response` its body; `;content-type-guard`
+//! looks at the start of the body); streaming stays opaque for routes that need no rewriting.
+
+use crate::cache;
+use crate::contenttype;
+use crate::digest;
+use crate::errorpages::ErrorPages;
+use crate::headers::HeaderEdits;
+use crate::idempotency;
+use crate::logging;
+use crate::preflight;
+use crate::request::RequestHead;
+use crate::response::LocalResponse;
+use crate::routing::{Backend, CookieRewrite, PathRewrite, RewriteRule, RouteMatch, TypeGuard};
+use std::borrow::Cow;
+use std::net::SocketAddr;
+
+/// Response heads larger than this are forwarded without rewriting
+pub const MAX_RESPONSE_HEAD: usize = 64 * 1024;
+
+/// Response bodies larger than this are forwarded without substitutions
+pub const MAX_FILTERED_BODY: usize = 16 * 1024 * 1024;
+
+/// Header and body rewrites for one proxied request's response
+pub struct ResponseRewrite<'a> {
+    location: Option<LocationRewrite>,
+    cookies: Option<SetCookieRewrite>,
+    /// The route's `--set-response-header` and `--remove-response-header` rules
+    headers: Option<&'a HeaderEdits>,
+    /// The route's body substitutions; empty for HEAD requests, whose responses have no body
+    body_filters: &'a [RewriteRule],
+    /// `--custom-errors` pages, unless the route passes errors through
+    error_pages: Option<ErrorIntercept<'a>>,
+    /// The route's `;content-type-guard`; None for HEAD requests
+    type_check: Option<TypeCheck<'a>>,
+    /// Add a `Digest` header for the body (`;digest=response`); false for HEAD requests
+    digest: bool,
+    /// Where the complete response is kept
+    store: Option<ResponseStore<'a>>,
+}
+
+/// Where a complete response is kept once it has been forwarded; at most one applies to a request,
+/// by its method
+pub enum ResponseStore<'a> {
+    /// The preflight cache (`;cache-preflight`)
+    Preflight(preflight::Store<'a>),
+    /// For repeats of the request's `Idempotency-Key` (`;idempotency`)
+    Idempotency(idempotency::Store<'a>),
+    /// The response cache (`;force-cache`)
+    Cache(cache::Store<'a>),
+}
+
+/// Checking response bodies against their `Content-Type`
+struct TypeCheck<'a> {
+    guard: TypeGuard,
+    /// Sent for blocked responses when there is no `502` error page
+    bad_gateway: &'a LocalResponse,
+    request: &'a RequestHead<'a>,
+    client: SocketAddr,
+}
+
+/// A response failing its route's `;content-type-guard`
+pub enum TypeVerdict<'a> {
+    /// The response replacing it
+    Blocked(Cow<'a, [u8]>),
+    /// Its head with the corrected `Content-Type`
+    Corrected(Vec<u8>),
+}
+
+/// Error pages replacing 5xx responses, and what they are rendered for
+struct ErrorIntercept<'a> {
+    pages: &'a ErrorPages,
+    request: &'a RequestHead<'a>,
+    client: SocketAddr,
+}
+
+/// How the end of a response body is found
+#[derive(Clone, Copy)]
+pub enum BodyFraming {
+    Length(usize),
+    Chunked,
+    /// The body ends when the backend closes the connection
+    Close,
+}
+
+/// How much of a response body has been buffered
+pub enum BodyStatus<'d> {
+    Incomplete,
+    /// The decoded body, and how many buffered bytes it took
+    Complete(Cow<'d, [u8]>, usize),
+    /// Malformed chunks, or the connection closed early
+    Invalid,
+}
+
+/// Rewrites `Path` and `Domain` attributes of `Set-Cookie` headers
+struct SetCookieRewrite {
+    /// The route's path rewrite and matched prefix, when `Path` is mapped back through it
+    path: Option<(PathRewrite, String)>,
+    domain: Option<String>,
+}
+
+/// Maps `Location` and `Content-Location` values from the backend's view of URLs back to the client's
+struct LocationRewrite {
+    /// The route's path rewrite and matched prefix, reversed for paths
+    path: Option<(PathRewrite, String)>,
+    /// Authorities the backend may use for itself in absolute URLs (its address, the Host sent to it)
+    backend_authorities: Vec<String>,
+    /// The client's Host header, which replaces those authorities
+    client_authority: Option<String>,
+}
+
+impl<'a> ResponseRewrite<'a> {
+    /// The rewrites a response needs, or None to stream it untouched
+    pub fn new(
+        route: &RouteMatch<'a>,
+        request: &'a RequestHead<'a>,
+        backend: &Backend,
+        error_pages: Option<&'a ErrorPages>,
+        bad_gateway: &'a LocalResponse,
+        client: SocketAddr,
+        store: Option<ResponseStore<'a>>,
+    ) -> Option<Self> {
+        let sent_host = route.host.and_then(|host| host.value(backend));
+        let location = (route.rewrite.is_some() || sent_host.is_some()).then(|| LocationRewrite {
+            path: route.rewrite.map(|rewrite| (rewrite.clone(), route.prefix.to_string())),
+            backend_authorities: std::iter::once(&*backend.name).chain(sent_host).map(str::to_string).collect(),
+            client_authority: request.header_str("host").map(str::to_string),
+        });
+
+        let cookies = route.options.cookies.as_ref().map(|cookies: &CookieRewrite| SetCookieRewrite {
+            path: route.rewrite.filter(|_| cookies.path).map(|rewrite| (rewrite.clone(), route.prefix.to_string())),
+            domain: cookies.domain.clone(),
+        });
+
+        let headers = route.header_rules.map(|rules| &rules.response).filter(|edits| !edits.is_empty());
+        let body_filters = if request.method == "HEAD" { &[] } else { route.body_filters };
+        let error_pages = error_pages.filter(|_| !route.options.pass_errors).map(|pages| ErrorIntercept { pages, request, client });
+        let type_check = route.options.type_guard.filter(|_| request.method != "HEAD")
+            .map(|guard| TypeCheck { guard, bad_gateway, request, client });
+        let digest = route.options.digest.response && request.method != "HEAD";
+
+        (location.is_some() || cookies.is_some() || headers.is_some() || !body_filters.is_empty() || error_pages.is_some() || type_check.is_some() || digest || store.is_some())
+            .then_some(ResponseRewrite { location, cookies, headers, body_filters, error_pages, type_check, digest, store })
+    }
+
+    /// The error page replacing a response, when its status has one
+    pub fn error_page(&self, head: &[u8]) -> Option<Cow<'a, [u8]>> {
+        let intercept = self.error_pages.as_ref()?;
+        let page = intercept.pages.get(status(head)?)?;
+        Some(page.bytes(intercept.request, intercept.client))
+    }
+
+    /// The framing of a response body whose start the `;content-type-guard` checks, or None when it
+    /// has no body to check (or a compressed one)
+    pub fn type_check_framing(&self, head: &[u8]) -> Option<BodyFraming> {
+        self.type_check.as_ref()?;
+        if head.get(9..12).is_some_and(|status| status == b"204" || status == b"304") {
+            return None;
+        }
+        if header_value(head, "content-encoding").is_some_and(|encoding| !encoding.eq_ignore_ascii_case("identity")) {
+            return None;
+        }
+        framing(head).filter(|framing| !matches!(framing, BodyFraming::Length(0)))
+    }
+
+    /// Check the start of a response body against the head's `Content-Type`; None when they match
+    pub fn check_type(&self, head: &[u8], body: &[u8]) -> Option<TypeVerdict<'a>> {
+        let check = self.type_check.as_ref()?;
+        let mismatch = contenttype::check(header_value(head, "content-type"), body)?;
+        let page = self.error_pages.as_ref().and_then(|intercept| intercept.pages.get(502));
+
+        Some(match check.guard {
+            TypeGuard::Block => {
+                logging::warning(format!("[{}] {} -> Content-Type mismatch ({}): blocked", check.client, check.request.path, mismatch));
+                TypeVerdict::Blocked(page.unwrap_or(check.bad_gateway).bytes(check.request, check.client))
+            }
+            TypeGuard::Correct => {
+                logging::warning(format!("[{}] {} -> Content-Type mismatch ({}): served as {}", check.client, check.request.path, mismatch, mismatch.corrected_type()));
+                let mut new_head = Vec::with_capacity(head.len() + 64);
+                for (idx, line) in head.split_inclusive(|&b| b == b'\n').enumerate() {
+                    if idx > 0 && (is_header(line, "content-type") || is_header(line, "x-content-type-options")) {
+                        continue;
+                    }
+                    if idx > 0 && (line == b"\r\n" || line == b"\n") {
+                        new_head.extend_from_slice(format!("Content-Type: {}\r\nX-Content-Type-Options: nosniff\r\n", mismatch.corrected_type()).as_bytes());
+                    }
+                    new_head.extend_from_slice(line);
+                }
+                TypeVerdict::Corrected(new_head)
+            }
+        })
+    }
+
+    /// Rewrite a complete response head (up to and including the blank line); None when nothing changed
+    pub fn apply(&self, head: &[u8]) -> Option<Vec<u8>> {
+        let mut changed = false;
+        let mut new_head = Vec::with_capacity(head.len() + 64);
+
+        // The first line is the status line; header lines are rewritten one by one
+        for (idx, line) in head.split_inclusive(|&b| b == b'\n').enumerate() {
+            if let Some(edits) = self.headers.filter(|_| idx > 0) {
+                if edits.drops(line) {
+                    changed = true;
+                    continue;
+                }
+                if line == b"\r\n" || line == b"\n" {
+                    edits.append_to(&mut new_head);
+                    changed = true;
+                }
+            }
+            let rewritten = (idx > 0).then(|| self.rewrite_line(line)).flatten();
+            match rewritten {
+                Some(new_line) => {
+                    new_head.extend_from_slice(new_line.as_bytes());
+                    changed = true;
+                }
+                None => new_head.extend_from_slice(line),
+            }
+        }
+
+        changed.then_some(new_head)
+    }
+
+    fn rewrite_line(&self, line: &[u8]) -> Option<String> {
+        let line = std::str::from_utf8(line).ok()?;
+        let (name, value) = line.split_once(':')?;
+        let value = value.trim();
+
+        let new_value = if name.eq_ignore_ascii_case("location") || name.eq_ignore_ascii_case("content-location") {
+            self.location.as_ref()?.rewrite(value)?
+        } else if name.eq_ignore_ascii_case("set-cookie") {
+            self.cookies.as_ref()?.rewrite(value)?
+        } else {
+            return None;
+        };
+        Some(format!("{}: {}\r\n", name, new_value))
+    }
+
+    /// The framing of a response body to buffer for the body filters, a `Digest` or a response
+    /// store, or None to stream it untouched: only bodies of known moderate size are buffered
+    pub fn body_framing(&self, head: &[u8]) -> Option<BodyFraming> {
+        if head.get(9..12).is_some_and(|status| status == b"204" || status == b"304") {
+            return None;
+        }
+        if !self.digest && !self.filters(head) && self.store.is_none() {
+            return None;
+        }
+        framing(head).filter(|framing| !matches!(framing, BodyFraming::Length(length) if *length > MAX_FILTERED_BODY))
+    }
+
+    /// Whether the body filters apply to a response: only uncompressed HTML and JSON bodies are substituted
+    fn filters(&self, head: &[u8]) -> bool {
+        let is_filterable = header_value(head, "content-type").is_some_and(|content_type| {
+            let mime = content_type.split(';').next().unwrap_or("").trim().to_ascii_lowercase();
+            matches!(mime.as_str(), "text/html" | "application/xhtml+xml" | "application/json") || mime.ends_with("+json")
+        });
+        let is_encoded = header_value(head, "content-encoding").is_some_and(|encoding| !encoding.eq_ignore_ascii_case("identity"));
+        !self.body_filters.is_empty() && is_filterable && !is_encoded
+    }
+
+    /// A buffered, decoded response body as forwarded, and its head with a matching `Content-Length`
+    /// (and `Digest`, when the route adds one)
+    pub fn rewrite_body<'d>(&self, head: &[u8], body: &'d [u8]) -> (Vec<u8>, Cow<'d, [u8]>) {
+        let body = if self.filters(head) { self.filter_body(body) } else { Cow::Borrowed(body) };
+        let mut new_head = with_content_length(head, body.len());
+        if self.digest {
+            let mut with_digest = Vec::with_capacity(new_head.len() + 64);
+            for (idx, line) in new_head.split_inclusive(|&b| b == b'\n').enumerate() {
+                if idx > 0 && (line == b"\r\n" || line == b"\n") {
+                    with_digest.extend_from_slice(format!("Digest: {}\r\n", digest::header_value(&body)).as_bytes());
+                } else if idx > 0 && is_header(line, "digest") {
+                    continue;
+                }
+                with_digest.extend_from_slice(line);
+            }
+            new_head = with_digest;
+        }
+        (new_head, body)
+    }
+
+    /// Keep a complete response (as forwarded) in its store
+    pub fn store(&self, head: &[u8], body: &[u8]) {
+        match &self.store {
+            Some(ResponseStore::Preflight(store)) => store.cache.insert(&store.key, head, body, store.ttl),
+            Some(ResponseStore::Idempotency(store)) => store.insert(head, body),
+            Some(ResponseStore::Cache(store)) => store.cache.insert(&store.key, head, body, store.ttl, store.quota.as_ref()),
+            None => {}
+        }
+    }
+
+    /// Apply the body filters to a decoded body; bodies that are not UTF-8 are left alone
+    fn filter_body<'d>(&self, body: &'d [u8]) -> Cow<'d, [u8]> {
+        let Ok(text) = std::str::from_utf8(body) else {
+            return Cow::Borrowed(body);
+        };
+        let mut text = Cow::Borrowed(text);
+        for filter in self.body_filters {
+            if let Cow::Owned(filtered) = filter.apply(&text) {
+                text = Cow::Owned(filtered);
+            }
+        }
+        match text {
+            Cow::Owned(text) => Cow::Owned(text.into_bytes()),
+            Cow::Borrowed(_) => Cow::Borrowed(body),
+        }
+    }
+}
+
+impl BodyFraming {
+    /// Find the complete body at the start of `data`; `eof` tells whether the backend has closed the connection
+    pub fn decode<'d>(&self, data: &'d [u8], eof: bool) -> BodyStatus<'d> {
+        let status = match *self {
+            BodyFraming::Length(length) if data.len() >= length => BodyStatus::Complete(Cow::Borrowed(&data[..length]), length),
+            BodyFraming::Length(_) => BodyStatus::Incomplete,
+            BodyFraming::Chunked => decode_chunked(data),
+            BodyFraming::Close if eof => BodyStatus::Complete(Cow::Borrowed(data), data.len()),
+            BodyFraming::Close => BodyStatus::Incomplete,
+        };
+        match status {
+            BodyStatus::Incomplete if eof => BodyStatus::Invalid,
+            status => status,
+        }
+    }
+
+    /// The first `contenttype::SNIFF_LEN` bytes of the body at the start of `data` (all of it when
+    /// shorter), or None until they are buffered
+    pub fn leading<'d>(&self, data: &'d [u8], eof: bool) -> Option<Cow<'d, [u8]>> {
+        let want = contenttype::SNIFF_LEN;
+        let leading = match *self {
+            BodyFraming::Length(length) => {
+                let want = want.min(length);
+                (data.len() >= want).then(|| Cow::Borrowed(&data[..want]))
+            }
+            BodyFraming::Close => (data.len() >= want).then(|| Cow::Borrowed(&data[..want])),
+            BodyFraming::Chunked => leading_chunks(data, want),
+        };
+        match leading {
+            None if eof => Some(Cow::Borrowed(data)),
+            leading => leading,
+        }
+    }
+}
+
+/// Longest chunk size or trailer line a `BodyTracker` follows; longer ones leave the body's end unknown
+const MAX_CHUNK_LINE: usize = 4096;
+
+/// Follows a body as its bytes stream past, without keeping them, to find where it ends
+#[derive(Clone)]
+pub struct BodyTracker {
+    state: TrackState,
+}
+
+#[derive(Clone)]
+enum TrackState {
+    /// Body bytes still to come
+    Length(u64),
+    /// A chunk size line so far
+    ChunkSize(Vec<u8>),
+    /// Chunk data bytes still to come, then the line break after them
+    ChunkData(u64),
+    ChunkEnd,
+    /// A trailer line so far; an empty one ends the body
+    Trailer(Vec<u8>),
+    /// The body ends when the connection closes, or its end is not known
+    Open,
+    Done,
+}
+
+impl BodyTracker {
+    /// Follow a body of the given framing; None for messages without a body
+    pub fn new(framing: Option<BodyFraming>) -> Self {
+        let state = match framing {
+            None | Some(BodyFraming::Length(0)) => TrackState::Done,
+            Some(BodyFraming::Length(length)) => TrackState::Length(length as u64),
+            Some(BodyFraming::Chunked) => TrackState::ChunkSize(Vec::new()),
+            Some(BodyFraming::Close) => TrackState::Open,
+        };
+        BodyTracker { state }
+    }
+
+    /// Follow the next bytes of the body; returns how many of them are the body's once its end is among them
+    pub fn advance(&mut self, mut data: &[u8]) -> Option<usize> {
+        let total = data.len();
+        loop {
+            match &mut self.state {
+                TrackState::Done => return Some(total - data.len()),
+                TrackState::Open => return None,
+                _ if data.is_empty() => return None,
+                TrackState::Length(remaining) => {
+                    let n = (*remaining).min(data.len() as u64);
+                    *remaining -= n;
+                    data = &data[n as usize..];
+                    if *remaining == 0 {
+                        self.state = TrackState::Done;
+                    }
+                }
+                TrackState::ChunkSize(line) | TrackState::Trailer(line) => {
+                    let Some(newline) = data.iter().position(|&b| b == b'\n') else {
+                        line.extend_from_slice(data);
+                        if line.len() > MAX_CHUNK_LINE {
+                            self.state = TrackState::Open;
+                        }
+                        return None;
+                    };
+                    line.extend_from_slice(&data[..newline]);
+                    data = &data[newline + 1..];
+                    let line = std::mem::take(line);
+                    let line = line.strip_suffix(b"\r").unwrap_or(&line);
+                    self.state = match self.state {
+                        TrackState::ChunkSize(_) => {
+                            match chunk_size(line) {
+                                Some(0) => TrackState::Trailer(Vec::new()),
+                                Some(size) => TrackState::ChunkData(size as u64),
+                                None => TrackState::Open,
+                            }
+                        }
+                        _ if line.is_empty() => TrackState::Done,
+                        _ => TrackState::Trailer(Vec::new()),
+                    };
+                }
+                TrackState::ChunkData(remaining) => {
+                    let n = (*remaining).min(data.len() as u64);
+                    *remaining -= n;
+                    data = &data[n as usize..];
+                    if *remaining == 0 {
+                        self.state = TrackState::ChunkEnd;
+                    }
+                }
+                // The line break after chunk data; anything else leaves the body's end unknown
+                TrackState::ChunkEnd => match data {
+                    [b'\r'] => return None,
+                    [b'\r', b'\n', ..] | [b'\n', ..] => {
+                        data = &data[if data[0] == b'\r' { 2 } else { 1 }..];
+                        self.state = TrackState::ChunkSize(Vec::new());
+                    }
+                    _ => self.state = TrackState::Open,
+                },
+            }
+        }
+    }
+
+    /// Whether the body's end cannot be found from its bytes: it ends with the connection, or is malformed
+    pub fn is_open(&self) -> bool {
+        matches!(self.state, TrackState::Open)
+    }
+}
+
+/// Up to `want` bytes of chunk data from the start of a chunked body, once that many (or the whole
+/// body) are buffered; a malformed body yields what was decoded of it
+fn leading_chunks(data: &[u8], want: usize) -> Option<Cow<'_, [u8]>> {
+    let mut leading = Vec::new();
+    let mut pos = 0;
+    while leading.len() < want {
+        let line_len = data[pos..].iter().position(|&b| b == b'\n')?;
+        let Some(size) = chunk_size(&data[pos..pos + line_len]) else {
+            break;
+        };
+        if size == 0 {
+            break;
+        }
+        pos += line_len + 1;
+        let available = &data[pos.min(data.len())..];
+        let take = size.min(want - leading.len());
+        if available.len() < take {
+            return None;
+        }
+        leading.extend_from_slice(&available[..take]);
+        if take < size {
+            break;
+        }
+        pos += size;
+        match data.get(pos..pos + 2) {
+            Some(b"\r\n") => pos += 2,
+            Some([b'\n', _]) => pos += 1,
+            Some(_) => break,
+            None => return None,
+        }
+    }
+    Some(Cow::Owned(leading))
+}
+
+/// How the end of a response body is found, from its head
+pub fn framing(head: &[u8]) -> Option<BodyFraming> {
+    if let Some(encoding) = header_value(head, "transfer-encoding") {
+        return encoding.eq_ignore_ascii_case("chunked").then_some(BodyFraming::Chunked);
+    }
+    match header_value(head, "content-length") {
+        Some(length) => length.parse().ok().map(BodyFraming::Length),
+        None => Some(BodyFraming::Close),
+    }
+}
+
+/// The size of a chunk size line (`SIZE[;ext]`, with or without its `\r`): hex digits only, as a
+/// `+` or other prefix that number parsing would take could be read differently by the backend
+pub fn chunk_size(line: &[u8]) -> Option<usize> {
+    let line = std::str::from_utf8(line).ok()?;
+    let size = line.split(';').next().unwrap_or("").trim();
+    if size.is_empty() || !size.bytes().all(|b| b.is_ascii_hexdigit()) {
+        return None;
+    }
+    usize::from_str_radix(size, 16).ok()
+}
+
+/// Decode a chunked body (`SIZE[;ext]\r\nDATA\r\n` ... `0\r\n`, optional trailers, `\r\n`).
+/// Trailers are dropped along with the chunked framing.
+fn decode_chunked(data: &[u8]) -> BodyStatus<'_> {
+    let mut body = Vec::new();
+    let mut pos = 0;
+    loop {
+        let Some(line_len) = data[pos..].iter().position(|&b| b == b'\n') else {
+            return BodyStatus::Incomplete;
+        };
+        let Some(size) = chunk_size(&data[pos..pos + line_len]) else {
+            return BodyStatus::Invalid;
+        };
+        pos += line_len + 1;
+
+        if size == 0 {
+            // Skip trailer lines up to the blank line that ends the body
+            loop {
+                let Some(line_len) = data[pos..].iter().position(|&b| b == b'\n') else {
+                    return BodyStatus::Incomplete;
+                };
+                let line = &data[pos..pos + line_len];
+                pos += line_len + 1;
+                if line.is_empty() || line == b"\r" {
+                    return BodyStatus::Complete(Cow::Owned(body), pos);
+                }
+            }
+        }
+
+        if size > MAX_FILTERED_BODY - body.len() {
+            return BodyStatus::Invalid;
+        }
+        let Some(chunk) = data.get(pos..pos + size) else {
+            return BodyStatus::Incomplete;
+        };
+        body.extend_from_slice(chunk);
+        pos += size;
+        match data.get(pos..pos + 2) {
+            Some(b"\r\n") => pos += 2,
+            Some([b'\n', _]) => pos += 1,
+            Some(_) => return BodyStatus::Invalid,
+            None => return BodyStatus::Incomplete,
+        }
+    }
+}
+
+/// A response head framed for a body of `length` bytes: `Content-Length` replaces any length or chunked encoding
+pub fn with_content_length(head: &[u8], length: usize) -> Vec<u8> {
+    let mut new_head = Vec::with_capacity(head.len() + 32);
+    for (idx, line) in head.split_inclusive(|&b| b == b'\n').enumerate() {
+        if idx > 0 && (line == b"\r\n" || line == b"\n") {
+            new_head.extend_from_slice(format!("Content-Length: {}\r\n", length).as_bytes());
+        } else if idx > 0 && (is_header(line, "content-length") || is_header(line, "transfer-encoding")) {
+            continue;
+        }
+        new_head.extend_from_slice(line);
+    }
+    new_head
+}
+
+/// The value of the first header with the given (lowercase) name in a response head
+pub fn header_value<'h>(head: &'h [u8], name: &str) -> Option<&'h str> {
+    head.split(|&b| b == b'\n')
+        .skip(1)
+        .find(|line| is_header(line, name))
+        .and_then(|line| std::str::from_utf8(&line[name.len() + 1..]).ok())
+        .map(str::trim)
+}
+
+/// Whether a header line has the given (lowercase) name
+pub fn is_header(line: &[u8], name: &str) -> bool {
+    line.len() > name.len() && line[..name.len()].eq_ignore_ascii_case(name.as_bytes()) && line[name.len()] == b':'
+}
+
+/// The status code of a response head
+pub fn status(head: &[u8]) -> Option<u16> {
+    std::str::from_utf8(head.get(9..12)?).ok()?.parse().ok()
+}
+
+/// Whether a response status is informational (`100 Continue`, `103 Early Hints`), followed by another head
+pub fn is_informational(head: &[u8]) -> bool {
+    head.starts_with(b"HTTP/1.") && head.get(9) == Some(&b'1')
+}
+
+impl LocationRewrite {
+    fn rewrite(&self, value: &str) -> Option<String> {
+        // Absolute URL: only ones naming the backend itself are rewritten to the client's host
+        if let Some((scheme, rest)) = value.split_once("://") {
+            let (authority, path) = rest.find('/').map_or((rest, ""), |idx| rest.split_at(idx));
+            let path = self.rewrite_path(path).unwrap_or_else(|| path.to_string());
+            if self.backend_authorities.iter().any(|a| a.eq_ignore_ascii_case(authority)) {
+                let client = self.client_authority.as_deref()?;
+                return Some(format!("http://{}{}", client, path));
+            }
+            let is_client = self.client_authority.as_deref().is_some_and(|c| c.eq_ignore_ascii_case(authority));
+            return is_client.then(|| format!("{}://{}{}", scheme, authority, path)).filter(|new| new != value);
+        }
+
+        // Absolute path; network-path references (`//host/...`) and relative paths are left alone
+        if value.starts_with('/') && !value.starts_with("//") {
+            return self.rewrite_path(value).filter(|new| new != value);
+        }
+        None
+    }
+
+    fn rewrite_path(&self, path: &str) -> Option<String> {
+        let (rewrite, prefix) = self.path.as_ref()?;
+        rewrite.reverse(path, prefix)
+    }
+}
+
+impl SetCookieRewrite {
+    fn rewrite(&self, value: &str) -> Option<String> {
+        let mut attributes = value.split(';');
+        let mut new_value = attributes.next()?.trim().to_string();
+
+        for attribute in attributes {
+            let attribute = attribute.trim();
+            let (key, attr_value) = attribute.split_once('=').unwrap_or((attribute, ""));
+            let replacement = if key.eq_ignore_ascii_case("path") {
+                self.path.as_ref().and_then(|(rewrite, prefix)| rewrite.reverse(attr_value, prefix)).map(|path| {
+                    // `/api/` would not cover `/api` itself
+                    let path = if path.len() > 1 { path.trim_end_matches('/') } else { &path };
+                    format!("Path={}", path)
+                })
+            } else if key.eq_ignore_ascii_case("domain") {
+                self.domain.as_ref().map(|domain| if domain.is_empty() { String::new() } else { format!("Domain={}", domain) })
+            } else {
+                None
+            };
+
+            match replacement.as_deref().unwrap_or(attribute) {
+                "" => {}
+                attribute => {
+                    new_value.push_str("; ");
+                    new_value.push_str(attribute);
+                }
+            }
+        }
+
+        (new_value != value).then_some(new_value)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Feed a body to a tracker in pieces of `size` bytes; how many bytes it took, once ended
+    fn tracked(framing: Option<BodyFraming>, data: &[u8], size: usize) -> (Option<usize>, bool) {
+        let mut tracker = BodyTracker::new(framing);
+        let mut taken = 0;
+        for piece in data.chunks(size) {
+            if let Some(n) = tracker.advance(piece) {
+                return (Some(taken + n), tracker.is_open());
+            }
+            taken += piece.len();
+        }
+        (None, tracker.is_open())
+    }
+
+    #[test]
+    fn tracker_finds_the_end_of_a_length_body() {
+        let data = b"helloGET /next HTTP/1.1\r\n";
+        for size in [1, 3, data.len()] {
+            assert_eq!(tracked(Some(BodyFraming::Length(5)), data, size), (Some(5), false));
+        }
+        assert_eq!(tracked(None, b"GET / HTTP/1.1\r\n", 4), (Some(0), false));
+        assert_eq!(tracked(Some(BodyFraming::Length(0)), b"next", 4), (Some(0), false));
+        assert_eq!(tracked(Some(BodyFraming::Length(10)), b"short", 5), (None, false));
+    }
+
+    #[test]
+    fn tracker_finds_the_end_of_a_chunked_body() {
+        let body = b"5;ext=1\r\nhello\r\nA\r\n0123456789\r\n0\r\nTrailer: x\r\n\r\n";
+        let data = [&body[..], b"GET /next HTTP/1.1\r\n"].concat();
+        for size in [1, 2, 7, data.len()] {
+            assert_eq!(tracked(Some(BodyFraming::Chunked), &data, size), (Some(body.len()), false));
+        }
+        assert_eq!(tracked(Some(BodyFraming::Chunked), b"3\nabc\n0\n\nrest", 1), (Some(9), false));
+        assert_eq!(tracked(Some(BodyFraming::Chunked), b"5\r\nhel", 3), (None, false));
+    }
+
+    #[test]
+    fn tracker_leaves_malformed_chunks_open() {
+        for data in [&b"+5\r\nhello\r\n0\r\n\r\n"[..], b"zz\r\n", b"\r\n", b"3\r\nabcXY0\r\n\r\n", b"0x5\r\nhello\r\n"] {
+            assert_eq!(tracked(Some(BodyFraming::Chunked), data, data.len()), (None, true), "{:?}", String::from_utf8_lossy(data));
+        }
+        let long = [&[b'1'; MAX_CHUNK_LINE + 1][..], b"\r\n"].concat();
+        assert_eq!(tracked(Some(BodyFraming::Chunked), &long, 100), (None, true));
+        assert_eq!(tracked(Some(BodyFraming::Close), b"anything", 3), (None, true));
+    }
+
+    #[test]
+    fn decodes_chunked_bodies() {
+        let data = b"5\r\nhello\r\n6;name=value\r\n world\r\n0\r\nTrailer: x\r\n\r\nnext";
+        let BodyStatus::Complete(body, used) = decode_chunked(data) else { panic!("not complete") };
+        assert_eq!((&*body, used), (&b"hello world"[..], data.len() - 4));
+        assert!(matches!(decode_chunked(b"0\n\n"), BodyStatus::Complete(body, 3) if body.is_empty()));
+
+        for partial in [&b"5\r\nhel"[..], b"5\r\nhello", b"5\r\nhello\r\n0\r\n", b"5"] {
+            assert!(matches!(decode_chunked(partial), BodyStatus::Incomplete), "{:?}", String::from_utf8_lossy(partial));
+        }
+        for invalid in [&b"+5\r\nhello\r\n0\r\n\r\n"[..], b"-1\r\n", b"g\r\n", b"\r\n", b"3\r\nabcXY", b"ffffffffffff\r\n"] {
+            assert!(matches!(decode_chunked(invalid), BodyStatus::Invalid), "{:?}", String::from_utf8_lossy(invalid));
+        }
+    }
+
+    #[test]
+    fn incomplete_bodies_are_invalid_at_eof() {
+        assert!(matches!(BodyFraming::Length(5).decode(b"abc", true), BodyStatus::Invalid));
+        assert!(matches!(BodyFraming::Length(5).decode(b"abc", false), BodyStatus::Incomplete));
+        assert!(matches!(BodyFraming::Close.decode(b"abc", false), BodyStatus::Incomplete));
+        assert!(matches!(BodyFraming::Close.decode(b"abc", true), BodyStatus::Complete(body, 3) if &*body == b"abc"));
+        assert!(matches!(BodyFraming::Chunked.decode(b"5\r\nab", true), BodyStatus::Invalid));
+    }
+
+    #[test]
+    fn framing_from_response_heads() {
+        let framing = |head: &str| framing(head.as_bytes());
+        assert!(matches!(framing("HTTP/1.1 200 OK\r\nContent-Length: 12\r\n\r\n"), Some(BodyFraming::Length(12))));
+        assert!(matches!(framing("HTTP/1.1 200 OK\r\nTransfer-Encoding: Chunked\r\nContent-Length: 12\r\n\r\n"), Some(BodyFraming::Chunked)));
+        assert!(matches!(framing("HTTP/1.1 200 OK\r\n\r\n"), Some(BodyFraming::Close)));
+        assert!(framing("HTTP/1.1 200 OK\r\nTransfer-Encoding: gzip\r\n\r\n").is_none());
+        assert!(framing("HTTP/1.1 200 OK\r\nContent-Length: twelve\r\n\r\n").is_none());
+    }
+
+    #[test]
+    fn content_length_replaces_the_framing_headers() {
+        let head = b"HTTP/1.1 200 OK\r\nTransfer-Encoding: chunked\r\nContent-Type: text/plain\r\ncontent-length: 3\r\n\r\n";
+        assert_eq!(with_content_length(head, 11), b"HTTP/1.1 200 OK\r\nContent-Type: text/plain\r\nContent-Length: 11\r\n\r\n");
+    }
+}
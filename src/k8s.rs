@@ -0,0 +1,206 @@
+//! Kubernetes Endpoints discovery: a route target `k8s:NAMESPACE/SERVICE:PORT` is a pool of the
+//! ready addresses of the service's Endpoints object, at the port of that name or number. The
+//! proxy reads the object from the API server, in the cluster with the pod's service account or at
+//! `--k8s-api`, then watches it and passes every change on (see `discovery`).
+//!
+//! API calls block: they are made while the route table is loaded and on watcher threads.
+
+use crate::blocking::{self, Body};
+use crate::client::Url;
+use crate::discovery::Member;
+use serde_json::Value;
+use std::io::{BufRead, BufReader, Read};
+use std::net::{SocketAddr, TcpStream, ToSocketAddrs};
+use std::sync::{Arc, OnceLock};
+use std::time::Duration;
+use tokio_rustls::rustls;
+use tokio_rustls::rustls::pki_types::pem::PemObject;
+use tokio_rustls::rustls::pki_types::{CertificateDer, ServerName};
+
+/// Where in-cluster pods find their service account
+const SERVICE_ACCOUNT: &str = "/var/run/secrets/kubernetes.io/serviceaccount";
+
+/// Longest a connection to the API server may take to open, or a list to be answered
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// How long a watch runs before the API server ends it and it is started again
+const WATCH_SECONDS: u64 = 300;
+
+/// Largest Endpoints object or watch event read
+const MAX_OBJECT: u64 = 4 * 1024 * 1024;
+
+/// A `NAMESPACE/SERVICE:PORT` target
+#[derive(Clone)]
+pub struct Target {
+    pub namespace: String,
+    pub service: String,
+    /// The port's name, or its number
+    pub port: String,
+}
+
+impl Target {
+    pub fn parse(spec: &str) -> Result<Self, String> {
+        let invalid = || format!("Invalid Kubernetes target 'k8s:{}'. Expected k8s:NAMESPACE/SERVICE:PORT", spec);
+        let (namespace, rest) = spec.split_once('/').ok_or_else(invalid)?;
+        let (service, port) = rest.rsplit_once(':').ok_or_else(invalid)?;
+        // Names of namespaces, services and ports are DNS labels
+        let label = |name: &str| !name.is_empty() && name.len() <= 63 && name.bytes().all(|b| b.is_ascii_lowercase() || b.is_ascii_digit() || b == b'-');
+        if !label(namespace) || !label(service) || !label(port) {
+            return Err(invalid());
+        }
+        Ok(Target { namespace: namespace.to_string(), service: service.to_string(), port: port.to_string() })
+    }
+}
+
+impl std::fmt::Display for Target {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}/{}:{}", self.namespace, self.service, self.port)
+    }
+}
+
+/// How the API server is reached
+struct Api {
+    url: Url,
+    /// TLS trusting the cluster's CA in the cluster, and the public roots at an `https://` `--k8s-api`
+    tls: Option<Arc<rustls::ClientConfig>>,
+    /// File of the service account token, read again for every request since tokens are rotated
+    token: Option<String>,
+}
+
+static API: OnceLock<Result<Api, String>> = OnceLock::new();
+
+/// Reach the API server at this URL (such as `kubectl proxy`'s) instead of from within the
+/// cluster; only the first call takes effect
+pub fn set_api(url: &str) -> Result<(), String> {
+    let url = Url::parse(url).map_err(|e| format!("Invalid --k8s-api: {}", e))?;
+    let tls = url.tls.then(crate::client::tls_config);
+    let _ = API.set(Ok(Api { url, tls, token: None }));
+    Ok(())
+}
+
+fn api() -> Result<&'static Api, String> {
+    API.get_or_init(|| {
+        let host = std::env::var("KUBERNETES_SERVICE_HOST")
+            .map_err(|_| "not running in a Kubernetes cluster (KUBERNETES_SERVICE_HOST is not set); give the API server with --k8s-api".to_string())?;
+        let port = std::env::var("KUBERNETES_SERVICE_PORT").unwrap_or_else(|_| "443".to_string());
+        let host = if host.contains(':') { format!("[{}]", host) } else { host };
+        let url = Url::parse(&format!("https://{}:{}", host, port))?;
+
+        let ca = format!("{}/ca.crt", SERVICE_ACCOUNT);
+        let pem = std::fs::read(&ca).map_err(|e| format!("Failed to read the cluster CA {}: {}", ca, e))?;
+        let mut roots = rustls::RootCertStore::empty();
+        for cert in CertificateDer::pem_slice_iter(&pem) {
+            let cert = cert.map_err(|e| format!("Invalid cluster CA {}: {}", ca, e))?;
+            roots.add(cert).map_err(|e| format!("Invalid cluster CA {}: {}", ca, e))?;
+        }
+        let tls = Arc::new(rustls::ClientConfig::builder().with_root_certificates(roots).with_no_client_auth());
+        Ok(Api { url, tls: Some(tls), token: Some(format!("{}/token", SERVICE_ACCOUNT)) })
+    })
+    .as_ref()
+    .map_err(Clone::clone)
+}
+
+/// The ready addresses of the target's Endpoints, with the object's resource version
+pub fn list(target: &Target) -> Result<(Vec<Member>, String), String> {
+    let path = format!("/api/v1/namespaces/{}/endpoints/{}", target.namespace, target.service);
+    let mut response = get(&path, Some(REQUEST_TIMEOUT))?;
+    let mut body = Vec::new();
+    response.read_to_end(&mut body).map_err(|e| format!("failed to read Endpoints: {}", e))?;
+    let object: Value = serde_json::from_slice(&body).map_err(|e| format!("invalid Endpoints: {}", e))?;
+    let version = object["metadata"]["resourceVersion"].as_str().unwrap_or("").to_string();
+    Ok((members(&object, &target.port)?, version))
+}
+
+/// Watch the target's Endpoints from a resource version on, passing each new set of addresses to
+/// `changed` until the watch ends or `changed` returns false. Err when it has to be listed again.
+pub fn watch(target: &Target, version: &str, mut changed: impl FnMut(Result<Vec<Member>, String>) -> bool) -> Result<(), String> {
+    let path = format!(
+        "/api/v1/namespaces/{}/endpoints?watch=1&allowWatchBookmarks=false&fieldSelector=metadata.name%3D{}&resourceVersion={}&timeoutSeconds={}",
+        target.namespace, target.service, version, WATCH_SECONDS
+    );
+    // The server ends the watch after WATCH_SECONDS; a connection silent for longer is dead
+    let response = get(&path, Some(Duration::from_secs(WATCH_SECONDS + 30)))?;
+    let mut events = BufReader::new(response);
+    let mut line = Vec::new();
+    loop {
+        line.clear();
+        let read = (&mut events).take(MAX_OBJECT).read_until(b'\n', &mut line).map_err(|e| format!("watch failed: {}", e))?;
+        if read == 0 {
+            return Ok(());
+        }
+        if line.iter().all(u8::is_ascii_whitespace) {
+            continue;
+        }
+        let event: Value = serde_json::from_slice(&line).map_err(|e| format!("invalid watch event: {}", e))?;
+        let object = &event["object"];
+        let members = match event["type"].as_str() {
+            Some("ADDED" | "MODIFIED") => members(object, &target.port),
+            Some("DELETED") => Err("the Endpoints object was deleted".to_string()),
+            // Most often 410 Gone: the resource version is too old to watch from
+            Some("ERROR") => return Err(format!("watch ended: {}", object["message"].as_str().unwrap_or("error"))),
+            _ => continue,
+        };
+        if !changed(members) {
+            return Ok(());
+        }
+    }
+}
+
+/// The ready addresses of an Endpoints object at a port, sorted
+fn members(object: &Value, port: &str) -> Result<Vec<Member>, String> {
+    let mut members = Vec::new();
+    let mut has_port = false;
+    for subset in object["subsets"].as_array().into_iter().flatten() {
+        let number = subset["ports"].as_array().into_iter().flatten()
+            .find(|p| p["name"].as_str() == Some(port) || p["port"].as_u64().is_some_and(|n| n.to_string() == port))
+            .and_then(|p| p["port"].as_u64())
+            .and_then(|n| u16::try_from(n).ok());
+        let Some(number) = number else {
+            continue;
+        };
+        has_port = true;
+        for address in subset["addresses"].as_array().into_iter().flatten() {
+            let Some(ip) = address["ip"].as_str().and_then(|ip| ip.parse().ok()) else {
+                continue;
+            };
+            let addr = SocketAddr::new(ip, number);
+            members.push(Member { name: addr.to_string(), addr, weight: 1, priority: 0, named: false });
+        }
+    }
+    if members.is_empty() {
+        return Err(if has_port { "no ready addresses".to_string() } else { format!("no port {}", port) });
+    }
+    members.sort_by(|a, b| a.name.cmp(&b.name));
+    members.dedup_by(|a, b| a.name == b.name);
+    Ok(members)
+}
+/// GET a path from the API server; the body of a `200 OK`, or the error
+fn get(path: &str, read_timeout: Option<Duration>) -> Result<Body, String> {
+    let api = api()?;
+    let url = &api.url;
+    let addr = (url.host.as_str(), url.port).to_socket_addrs().ok().and_then(|mut addrs| addrs.next())
+        .ok_or_else(|| format!("API server {} does not resolve", url.host))?;
+    let fail = |e: String| format!("API server {}:{}: {}", url.host, url.port, e);
+    let tcp = TcpStream::connect_timeout(&addr, REQUEST_TIMEOUT).map_err(|e| fail(e.to_string()))?;
+    tcp.set_read_timeout(read_timeout).map_err(|e| fail(e.to_string()))?;
+    tcp.set_write_timeout(Some(REQUEST_TIMEOUT)).map_err(|e| fail(e.to_string()))?;
+    let stream: Box<dyn blocking::Stream> = match &api.tls {
+        Some(tls) => {
+            let name = ServerName::try_from(url.host.clone()).map_err(|e| fail(e.to_string()))?;
+            let connection = rustls::ClientConnection::new(tls.clone(), name).map_err(|e| fail(e.to_string()))?;
+            Box::new(rustls::StreamOwned::new(connection, tcp))
+        }
+        None => Box::new(tcp),
+    };
+
+    let token = match &api.token {
+        Some(file) => {
+            let token = std::fs::read_to_string(file).map_err(|e| format!("Failed to read the service account token {}: {}", file, e))?;
+            format!("Authorization: Bearer {}\r\n", token.trim())
+        }
+        None => String::new(),
+    };
+    let host = if url.host.contains(':') { format!("[{}]:{}", url.host, url.port) } else { format!("{}:{}", url.host, url.port) };
+    let path = format!("{}{}", url.path.trim_end_matches('/'), path);
+    blocking::get(stream, &host, &path, &token).map_err(fail)
+}
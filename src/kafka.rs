@@ -0,0 +1,430 @@
+//! A minimal Kafka producer for shipping the access log (`--access-log-sink kafka://...`): it looks
+//! up the leaders of the topic's partitions, and sends each batch as one uncompressed record batch
+//! to the next partition in turn, acknowledged by the leader. Plaintext listeners only; there is no
+//! TLS or SASL.
+
+use std::collections::hash_map::Entry;
+use std::collections::HashMap;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+
+/// Longest a request to a broker may take, connecting included
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Largest response accepted from a broker
+const MAX_RESPONSE_SIZE: usize = 1024 * 1024;
+
+const API_PRODUCE: i16 = 0;
+const API_METADATA: i16 = 3;
+
+/// Where batches go: the bootstrap brokers and the topic
+pub struct KafkaTarget {
+    pub brokers: Vec<String>,
+    pub topic: String,
+}
+
+impl KafkaTarget {
+    /// Parse the part of a sink after `kafka://`: `HOST:PORT[,HOST:PORT...]/TOPIC`
+    pub fn parse(spec: &str) -> Result<Self, String> {
+        let invalid = || format!("Invalid Kafka sink 'kafka://{}'. Expected kafka://HOST:PORT[,HOST:PORT...]/TOPIC", spec);
+        let (brokers, topic) = spec.split_once('/').ok_or_else(invalid)?;
+        let brokers: Vec<String> = brokers.split(',').map(str::trim).map(str::to_string).collect();
+        let valid_broker = |broker: &String| broker.rsplit_once(':').is_some_and(|(host, port)| !host.is_empty() && port.parse::<u16>().is_ok());
+        // Topic names are limited to these characters by Kafka itself
+        let valid_topic = !topic.is_empty() && topic.len() <= 249 && topic.bytes().all(|b| b.is_ascii_alphanumeric() || matches!(b, b'.' | b'_' | b'-'));
+        if !brokers.iter().all(valid_broker) || !valid_topic {
+            return Err(invalid());
+        }
+        Ok(KafkaTarget { brokers, topic: topic.to_string() })
+    }
+}
+
+impl std::fmt::Display for KafkaTarget {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "kafka://{}/{}", self.brokers.join(","), self.topic)
+    }
+}
+
+/// A producer for one topic, keeping its broker connections between batches
+pub struct Producer {
+    target: KafkaTarget,
+    /// Broker addresses by node id, from the latest metadata
+    brokers: HashMap<i32, String>,
+    /// Partitions with their leader's node id; empty until metadata was fetched
+    partitions: Vec<(i32, i32)>,
+    connections: HashMap<i32, TcpStream>,
+    next_partition: usize,
+    correlation_id: i32,
+}
+
+impl Producer {
+    pub fn new(target: KafkaTarget) -> Self {
+        Producer { target, brokers: HashMap::new(), partitions: Vec::new(), connections: HashMap::new(), next_partition: 0, correlation_id: 0 }
+    }
+
+    /// Send values as the records of one batch. After a failure, connections and metadata are
+    /// dropped, to be fetched again by the next call.
+    pub async fn send(&mut self, values: &[String]) -> Result<(), String> {
+        let result = self.try_send(values).await;
+        if result.is_err() {
+            self.connections.clear();
+            self.partitions.clear();
+        }
+        result
+    }
+
+    async fn try_send(&mut self, values: &[String]) -> Result<(), String> {
+        if self.partitions.is_empty() {
+            self.fetch_metadata().await?;
+        }
+        let (partition, leader) = self.partitions[self.next_partition % self.partitions.len()];
+        self.next_partition = self.next_partition.wrapping_add(1);
+
+        let mut body = Encoder::default();
+        body.nullable_string(None); // transactional_id
+        body.i16(1); // acks: the leader's
+        body.i32(REQUEST_TIMEOUT.as_millis() as i32);
+        body.i32(1);
+        body.string(&self.target.topic);
+        body.i32(1);
+        body.i32(partition);
+        let batch = record_batch(values);
+        body.i32(batch.len() as i32);
+        body.0.extend_from_slice(&batch);
+
+        let address = self.brokers.get(&leader).cloned().ok_or_else(|| format!("no address for partition leader {}", leader))?;
+        let response = self.request(leader, &address, API_PRODUCE, 3, &body.0).await?;
+        let mut response = Decoder(&response);
+        for _ in 0..response.i32()? {
+            response.string()?;
+            for _ in 0..response.i32()? {
+                let index = response.i32()?;
+                let error = response.i16()?;
+                response.i64()?;
+                response.i64()?;
+                if error != 0 {
+                    return Err(format!("broker refused the batch for partition {} of {}: {}", index, self.target.topic, error_name(error)));
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Look up the partitions of the topic and their leaders, asking the bootstrap brokers in turn
+    async fn fetch_metadata(&mut self) -> Result<(), String> {
+        let mut body = Encoder::default();
+        body.i32(1);
+        body.string(&self.target.topic);
+
+        let mut last_error = String::new();
+        for broker in self.target.brokers.clone() {
+            // Bootstrap connections take ids that no broker has
+            let response = self.request(-1, &broker, API_METADATA, 1, &body.0).await;
+            self.connections.remove(&-1);
+            match response {
+                Ok(response) => return self.read_metadata(&response),
+                Err(e) => last_error = e,
+            }
+        }
+        Err(last_error)
+    }
+
+    fn read_metadata(&mut self, response: &[u8]) -> Result<(), String> {
+        let mut response = Decoder(response);
+        self.brokers.clear();
+        for _ in 0..response.i32()? {
+            let node = response.i32()?;
+            let host = response.string()?;
+            let port = response.i32()?;
+            response.nullable_string()?; // rack
+            let host = if host.contains(':') { format!("[{}]", host) } else { host };
+            self.brokers.insert(node, format!("{}:{}", host, port));
+        }
+        response.i32()?; // controller
+        let mut partitions = Vec::new();
+        for _ in 0..response.i32()? {
+            let error = response.i16()?;
+            let name = response.string()?;
+            response.i8()?; // is_internal
+            if error != 0 {
+                return Err(format!("topic {}: {}", name, error_name(error)));
+            }
+            for _ in 0..response.i32()? {
+                let error = response.i16()?;
+                let index = response.i32()?;
+                let leader = response.i32()?;
+                for _ in 0..2 {
+                    for _ in 0..response.i32()? {
+                        response.i32()?;
+                    }
+                }
+                if error == 0 && leader >= 0 {
+                    partitions.push((index, leader));
+                }
+            }
+        }
+        if partitions.is_empty() {
+            return Err(format!("topic {} has no partition with a leader", self.target.topic));
+        }
+        partitions.sort_unstable();
+        self.partitions = partitions;
+        Ok(())
+    }
+
+    /// Send a request to a broker over its connection, opened when there is none, and read the response
+    async fn request(&mut self, node: i32, address: &str, api_key: i16, api_version: i16, body: &[u8]) -> Result<Vec<u8>, String> {
+        self.correlation_id = self.correlation_id.wrapping_add(1);
+        let correlation_id = self.correlation_id;
+        let mut request = Encoder::default();
+        request.i16(api_key);
+        request.i16(api_version);
+        request.i32(correlation_id);
+        request.nullable_string(Some("reverse-http-proxy"));
+        request.0.extend_from_slice(body);
+
+        let connections = &mut self.connections;
+        let exchange = async {
+            let stream = match connections.entry(node) {
+                Entry::Occupied(entry) => entry.into_mut(),
+                Entry::Vacant(entry) => {
+                    let stream = TcpStream::connect(address).await?;
+                    stream.set_nodelay(true)?;
+                    entry.insert(stream)
+                }
+            };
+            stream.write_all(&(request.0.len() as i32).to_be_bytes()).await?;
+            stream.write_all(&request.0).await?;
+            let size = stream.read_i32().await?;
+            if size < 4 || size as usize > MAX_RESPONSE_SIZE {
+                return Err(std::io::Error::new(std::io::ErrorKind::InvalidData, format!("response of {} bytes", size)));
+            }
+            let mut response = vec![0u8; size as usize];
+            stream.read_exact(&mut response).await?;
+            Ok(response)
+        };
+        let response = tokio::time::timeout(REQUEST_TIMEOUT, exchange).await
+            .map_err(|_| format!("no answer from {} within {} s", address, REQUEST_TIMEOUT.as_secs()))?
+            .map_err(|e| format!("{}: {}", address, e))?;
+        if response[..4] != correlation_id.to_be_bytes() {
+            return Err(format!("{}: response out of order", address));
+        }
+        Ok(response[4..].to_vec())
+    }
+}
+
+/// A record batch (magic 2) holding one record per value, without keys or headers
+fn record_batch(values: &[String]) -> Vec<u8> {
+    let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_millis() as i64;
+    let mut records = Encoder::default();
+    for (offset, value) in values.iter().enumerate() {
+        let mut record = Encoder::default();
+        record.i8(0); // attributes
+        record.varint(0); // timestamp delta
+        record.varint(offset as i64);
+        record.varint(-1); // no key
+        record.varint(value.len() as i64);
+        record.0.extend_from_slice(value.as_bytes());
+        record.varint(0); // no headers
+        records.varint(record.0.len() as i64);
+        records.0.extend_from_slice(&record.0);
+    }
+
+    // The CRC covers everything from the attributes on
+    let mut checked = Encoder::default();
+    checked.i16(0); // attributes: no compression, create time
+    checked.i32(values.len() as i32 - 1);
+    checked.i64(now);
+    checked.i64(now);
+    checked.i64(-1); // producer id
+    checked.i16(-1); // producer epoch
+    checked.i32(-1); // base sequence
+    checked.i32(values.len() as i32);
+    checked.0.extend_from_slice(&records.0);
+
+    let mut batch = Encoder::default();
+    batch.i64(0); // base offset, assigned by the broker
+    batch.i32((4 + 1 + 4 + checked.0.len()) as i32);
+    batch.i32(-1); // partition leader epoch
+    batch.i8(2); // magic
+    batch.0.extend_from_slice(&crc32c(&checked.0).to_be_bytes());
+    batch.0.extend_from_slice(&checked.0);
+    batch.0
+}
+
+/// CRC-32C (Castagnoli), as record batches use
+fn crc32c(data: &[u8]) -> u32 {
+    static TABLE: std::sync::OnceLock<[u32; 256]> = std::sync::OnceLock::new();
+    let table = TABLE.get_or_init(|| {
+        let mut table = [0u32; 256];
+        for (i, entry) in table.iter_mut().enumerate() {
+            let mut crc = i as u32;
+            for _ in 0..8 {
+                crc = if crc & 1 == 1 { (crc >> 1) ^ 0x82f6_3b78 } else { crc >> 1 };
+            }
+            *entry = crc;
+        }
+        table
+    });
+    !data.iter().fold(!0u32, |crc, &b| table[((crc ^ u32::from(b)) & 0xff) as usize] ^ (crc >> 8))
+}
+
+fn error_name(code: i16) -> String {
+    match code {
+        2 => "CORRUPT_MESSAGE".into(),
+        3 => "UNKNOWN_TOPIC_OR_PARTITION".into(),
+        5 => "LEADER_NOT_AVAILABLE".into(),
+        6 => "NOT_LEADER_OR_FOLLOWER".into(),
+        7 => "REQUEST_TIMED_OUT".into(),
+        10 => "MESSAGE_TOO_LARGE".into(),
+        19 => "NOT_ENOUGH_REPLICAS".into(),
+        29 => "TOPIC_AUTHORIZATION_FAILED".into(),
+        code => format!("error {}", code),
+    }
+}
+
+#[derive(Default)]
+struct Encoder(Vec<u8>);
+
+impl Encoder {
+    fn i8(&mut self, value: i8) {
+        self.0.push(value as u8);
+    }
+
+    fn i16(&mut self, value: i16) {
+        self.0.extend_from_slice(&value.to_be_bytes());
+    }
+
+    fn i32(&mut self, value: i32) {
+        self.0.extend_from_slice(&value.to_be_bytes());
+    }
+
+    fn i64(&mut self, value: i64) {
+        self.0.extend_from_slice(&value.to_be_bytes());
+    }
+
+    fn string(&mut self, value: &str) {
+        self.i16(value.len() as i16);
+        self.0.extend_from_slice(value.as_bytes());
+    }
+
+    fn nullable_string(&mut self, value: Option<&str>) {
+        match value {
+            Some(value) => self.string(value),
+            None => self.i16(-1),
+        }
+    }
+
+    /// A zigzag-encoded variable-length integer
+    fn varint(&mut self, value: i64) {
+        let mut value = ((value << 1) ^ (value >> 63)) as u64;
+        while value >= 0x80 {
+            self.0.push((value as u8 & 0x7f) | 0x80);
+            value >>= 7;
+        }
+        self.0.push(value as u8);
+    }
+}
+
+struct Decoder<'a>(&'a [u8]);
+
+impl Decoder<'_> {
+    fn take(&mut self, n: usize) -> Result<&[u8], String> {
+        if self.0.len() < n {
+            return Err("truncated response".to_string());
+        }
+        let (taken, rest) = self.0.split_at(n);
+        self.0 = rest;
+        Ok(taken)
+    }
+
+    fn i8(&mut self) -> Result<i8, String> {
+        Ok(self.take(1)?[0] as i8)
+    }
+
+    fn i16(&mut self) -> Result<i16, String> {
+        Ok(i16::from_be_bytes(self.take(2)?.try_into().expect("2 bytes")))
+    }
+
+    fn i32(&mut self) -> Result<i32, String> {
+        Ok(i32::from_be_bytes(self.take(4)?.try_into().expect("4 bytes")))
+    }
+
+    fn i64(&mut self) -> Result<i64, String> {
+        Ok(i64::from_be_bytes(self.take(8)?.try_into().expect("8 bytes")))
+    }
+
+    fn nullable_string(&mut self) -> Result<Option<String>, String> {
+        let len = self.i16()?;
+        if len < 0 {
+            return Ok(None);
+        }
+        Ok(Some(String::from_utf8_lossy(self.take(len as usize)?).into_owned()))
+    }
+
+    fn string(&mut self) -> Result<String, String> {
+        Ok(self.nullable_string()?.unwrap_or_default())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn crc32c_check_values() {
+        assert_eq!(crc32c(b""), 0);
+        assert_eq!(crc32c(b"123456789"), 0xe306_9283);
+        assert_eq!(crc32c(&[0; 32]), 0x8a91_36aa);
+        assert_eq!(crc32c(&[0xff; 32]), 0x62a8_ab43);
+    }
+
+    #[test]
+    fn varints_are_zigzag_encoded() {
+        let encoded = |value| {
+            let mut encoder = Encoder::default();
+            encoder.varint(value);
+            encoder.0
+        };
+        assert_eq!(encoded(0), [0x00]);
+        assert_eq!(encoded(-1), [0x01]);
+        assert_eq!(encoded(1), [0x02]);
+        assert_eq!(encoded(63), [0x7e]);
+        assert_eq!(encoded(64), [0x80, 0x01]);
+        assert_eq!(encoded(-65), [0x81, 0x01]);
+        assert_eq!(encoded(300), [0xd8, 0x04]);
+    }
+
+    #[test]
+    fn record_batch_layout() {
+        let batch = record_batch(&["one".to_string(), "three".to_string()]);
+        let mut decoder = Decoder(&batch);
+        assert_eq!(decoder.i64().unwrap(), 0); // base offset
+        assert_eq!(decoder.i32().unwrap() as usize, batch.len() - 12);
+        assert_eq!(decoder.i32().unwrap(), -1); // partition leader epoch
+        assert_eq!(decoder.i8().unwrap(), 2); // magic
+        let crc = decoder.i32().unwrap() as u32;
+        assert_eq!(crc, crc32c(decoder.0));
+
+        assert_eq!(decoder.i16().unwrap(), 0); // attributes
+        assert_eq!(decoder.i32().unwrap(), 1); // last offset delta
+        let (first, max) = (decoder.i64().unwrap(), decoder.i64().unwrap());
+        assert_eq!(first, max);
+        assert_eq!(decoder.take(14).unwrap(), [0xff; 14]); // producer id, epoch, base sequence
+        assert_eq!(decoder.i32().unwrap(), 2); // records
+
+        // length, attributes, timestamp delta, offset delta, no key, value length, value, no headers
+        assert_eq!(decoder.take(10).unwrap(), [0x12, 0, 0, 0, 0x01, 0x06, b'o', b'n', b'e', 0]);
+        assert_eq!(decoder.take(12).unwrap(), [0x16, 0, 0, 0x02, 0x01, 0x0a, b't', b'h', b'r', b'e', b'e', 0]);
+        assert!(decoder.0.is_empty());
+    }
+
+    #[test]
+    fn decoder_rejects_truncated_input() {
+        let mut decoder = Decoder(&[0, 5, b'a', b'b']);
+        assert_eq!(decoder.nullable_string(), Err("truncated response".to_string()));
+        assert_eq!(Decoder(&[0xff, 0xff]).nullable_string(), Ok(None));
+        assert_eq!(Decoder(&[0, 2, b'o', b'k']).string(), Ok("ok".to_string()));
+        assert!(Decoder(&[0, 0, 1]).i32().is_err());
+    }
+}
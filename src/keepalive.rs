@@ -0,0 +1,366 @@
+//! Later requests on a kept-alive client connection. A connection is routed by its first request,
+//! and the requests after it are relayed to the same backend connection as they arrive. Routes that
+//! check or count each of their requests (`;psk`, `;rate-limit` and the like) must not have requests
+//! slip past them that way: while the route table has such routes, the requests after the first are
+//! followed, and one that is not for the connection's route, or whose route checks requests, is
+//! handed back to be handled like the first request of a new connection once the responses before
+//! it are complete.
+
+use crate::intercept::BodyTracker;
+use crate::request::{RequestHead, MAX_HEADERS};
+use crate::upstream::BackendStream;
+use std::collections::VecDeque;
+use std::io;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::task::{ready, Context, Poll, Waker};
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, ReadBuf};
+use tokio::sync::Notify;
+
+/// What a request handed back takes to its handling
+#[derive(Default)]
+pub struct Handed {
+    /// The request, with what the client sent after it
+    pub received: Vec<u8>,
+    pub pinned: Option<Pinned>,
+}
+
+/// The backend connection an NTLM- or Negotiate-authenticated client connection is pinned to: they
+/// authenticate the connection, so the requests after the handshake must reach the backend on it
+pub struct Pinned {
+    pub backend: Arc<str>,
+    pub stream: BackendStream,
+}
+
+impl Pinned {
+    /// The connection, unless the backend has closed it (or sent something unasked) meanwhile
+    pub async fn alive(mut self) -> Option<BackendStream> {
+        let mut byte = [0u8; 1];
+        let idle = tokio::time::timeout(Duration::ZERO, self.stream.read(&mut byte)).await.is_err();
+        idle.then_some(self.stream)
+    }
+}
+
+/// What is known of the requests relayed on a connection and of their responses
+pub struct Exchanges {
+    state: Mutex<Shared>,
+    /// Wakes the response copy when a response completes or a request is handed back
+    changed: Notify,
+}
+
+struct Shared {
+    /// Requests relayed to the backend, the first included
+    relayed: usize,
+    /// Responses the client has had in full
+    answered: usize,
+    /// Whether each relayed request whose response has not started yet is a `HEAD`
+    queued: VecDeque<bool>,
+    /// A response switched protocols: no more requests follow
+    switched: bool,
+    /// The request handed back, with what the client sent after it
+    handed: Option<Vec<u8>>,
+    /// The connection closes after the current response, so nothing is handed back
+    closing: bool,
+    /// The gate, while it waits for the response to an upgrade request
+    waker: Option<Waker>,
+}
+
+impl Shared {
+    fn settled(&self) -> bool {
+        self.handed.is_some() && self.answered >= self.relayed
+    }
+
+    fn continues(&self) -> bool {
+        self.settled() && !self.closing
+    }
+}
+
+impl Exchanges {
+    /// The exchanges after a first request, which has been relayed
+    pub fn new() -> Self {
+        let shared = Shared { relayed: 1, answered: 0, queued: VecDeque::new(), switched: false, handed: None, closing: false, waker: None };
+        Exchanges { state: Mutex::new(shared), changed: Notify::new() }
+    }
+
+    fn lock(&self) -> MutexGuard<'_, Shared> {
+        self.state.lock().unwrap()
+    }
+
+    /// A later request relayed to the backend
+    fn relay(&self, head_request: bool) {
+        let mut state = self.lock();
+        state.relayed += 1;
+        state.queued.push_back(head_request);
+    }
+
+    /// Whether the response starting is to a `HEAD` request; None when no relayed request awaits one
+    pub fn next_request(&self) -> Option<bool> {
+        self.lock().queued.pop_front()
+    }
+
+    /// A response has reached the client in full
+    pub fn answered(&self) {
+        let mut state = self.lock();
+        state.answered += 1;
+        if let Some(waker) = state.waker.take() {
+            waker.wake();
+        }
+        drop(state);
+        self.changed.notify_one();
+    }
+
+    /// A response switched the connection to another protocol
+    pub fn switched(&self) {
+        self.lock().switched = true;
+    }
+
+    /// The connection closes after the current response, a request handed back with it
+    pub fn close(&self) {
+        self.lock().closing = true;
+    }
+
+    /// The request handed back to be handled on its own, with what the client sent after it; None
+    /// when the connection closes instead
+    pub fn handed_back(&self) -> Option<Vec<u8>> {
+        let mut state = self.lock();
+        if !state.continues() {
+            return None;
+        }
+        state.handed.take()
+    }
+
+    /// Whether the client connection goes on with a request handed back: the responses before it
+    /// are complete and none closes the connection
+    pub fn continues(&self) -> bool {
+        self.lock().continues()
+    }
+
+    fn hand_back(&self, data: Vec<u8>) {
+        self.lock().handed = Some(data);
+        self.changed.notify_one();
+    }
+
+    /// Whether a request has been handed back, which ends what the backend is sent
+    pub fn hands_back(&self) -> bool {
+        self.lock().handed.is_some()
+    }
+
+    /// Whether a request was handed back and the responses to all requests relayed before it are complete
+    fn settled(&self) -> bool {
+        self.lock().settled()
+    }
+
+    /// Ready once the response to the upgrade request relayed as the `index`th is complete, with
+    /// whether it switched protocols
+    fn poll_switch(&self, cx: &mut Context<'_>, index: usize) -> Poll<bool> {
+        let mut state = self.lock();
+        if state.answered >= index {
+            return Poll::Ready(state.switched);
+        }
+        state.waker = Some(cx.waker().clone());
+        Poll::Pending
+    }
+}
+
+/// Copy responses to the client until the backend closes the connection or, once a request has been
+/// handed back, the responses to all requests before it are complete; returns the bytes copied
+pub async fn copy_responses<R, W>(backend: &mut R, client: &mut W, exchanges: &Exchanges) -> io::Result<u64>
+where
+    R: AsyncRead + Unpin,
+    W: AsyncWrite + Unpin,
+{
+    let mut buffer = vec![0u8; 16384];
+    let mut copied = 0;
+    while !exchanges.settled() {
+        let n = tokio::select! {
+            // Looked at again by the loop condition
+            _ = exchanges.changed.notified() => continue,
+            read = backend.read(&mut buffer) => read?,
+        };
+        if n == 0 {
+            break;
+        }
+        client.write_all(&buffer[..n]).await?;
+        client.flush().await?;
+        copied += n as u64;
+    }
+    Ok(copied)
+}
+
+/// Where the gate is in the requests the client sends
+enum GateState {
+    /// The body of a relayed request; `upgrade` when the request asks to switch protocols
+    Body { body: BodyTracker, upgrade: bool },
+    /// The head of the next request so far, in the buffer
+    Head,
+    /// The response to the upgrade request relayed as the given one is awaited
+    Switch(usize),
+    /// The connection carries another protocol, passed on as it comes
+    Through,
+    /// Nothing more is passed on
+    Closed,
+}
+
+/// The client's side of a connection, which passes on everything, or with `Following` the requests
+/// after the first only when they may go to the connection's backend connection; the first that may
+/// not is handed back through the exchanges, and ends what the backend is sent
+pub struct Gate<'e, R> {
+    inner: R,
+    following: Option<Following<'e>>,
+}
+
+/// Given the head of a later request, whether it may be relayed
+type Relays<'e> = Box<dyn FnMut(&[u8]) -> bool + Send + 'e>;
+
+/// What a gate needs to follow the requests on a connection
+pub struct Following<'e> {
+    exchanges: &'e Exchanges,
+    relays: Relays<'e>,
+    /// Largest request head followed (`--max-header-size`); larger ones are handed back
+    max_head: usize,
+    state: GateState,
+    /// Bytes read from the client and not passed on yet
+    buffer: Vec<u8>,
+    /// Bytes at the start of the buffer passed on as they are: the head of a relayed request
+    ready: usize,
+}
+
+impl<'e, R> Gate<'e, R> {
+    pub fn new(inner: R, following: Option<Following<'e>>) -> Self {
+        Gate { inner, following }
+    }
+}
+
+impl<'e> Following<'e> {
+    /// Follow the client from within the first request's body; `received` are the bytes the client
+    /// sent after the part of it already forwarded
+    pub fn new(
+        exchanges: &'e Exchanges,
+        body: BodyTracker,
+        upgrade: bool,
+        received: Vec<u8>,
+        max_head: usize,
+        relays: impl FnMut(&[u8]) -> bool + Send + 'e,
+    ) -> Self {
+        let state = GateState::Body { body, upgrade };
+        Following { exchanges, relays: Box::new(relays), max_head, state, buffer: received, ready: 0 }
+    }
+
+    pub fn exchanges(&self) -> &'e Exchanges {
+        self.exchanges
+    }
+
+    /// Move up to `n` bytes from the start of the buffer into `buf`
+    fn pass(&mut self, n: usize, buf: &mut ReadBuf<'_>) {
+        let n = n.min(buf.remaining());
+        buf.put_slice(&self.buffer[..n]);
+        self.buffer.drain(..n);
+    }
+
+    /// Decide on the complete request head at the start of the buffer
+    fn next_request(&mut self, head_len: usize) {
+        let mut storage = [httparse::EMPTY_HEADER; MAX_HEADERS];
+        let parsed = RequestHead::parse(&self.buffer[..head_len], &mut storage);
+        match parsed {
+            Ok(head) if head.ambiguity().is_none() && (self.relays)(&self.buffer[..head_len]) => {
+                self.exchanges.relay(head.method == "HEAD");
+                let upgrade = head.header("upgrade").is_some();
+                self.state = GateState::Body { body: BodyTracker::new(crate::request_body_framing(&head)), upgrade };
+                self.ready = head_len;
+            }
+            // Malformed and ambiguous heads are handed back too, to be answered as such
+            _ => self.hand_back(),
+        }
+    }
+
+    fn hand_back(&mut self) {
+        self.exchanges.hand_back(std::mem::take(&mut self.buffer));
+        self.state = GateState::Closed;
+    }
+
+    /// Pass on what may be of the buffer: Some when `buf` is filled or the gate is done, None when
+    /// more of the client's bytes are needed
+    fn poll_buffered(&mut self, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Option<Poll<()>> {
+        loop {
+            if self.ready > 0 {
+                let n = self.ready.min(buf.remaining());
+                self.pass(n, buf);
+                self.ready -= n;
+                return Some(Poll::Ready(()));
+            }
+            match &mut self.state {
+                GateState::Closed => return Some(Poll::Ready(())),
+                GateState::Through if self.buffer.is_empty() => return None,
+                GateState::Through => {
+                    self.pass(self.buffer.len(), buf);
+                    return Some(Poll::Ready(()));
+                }
+                GateState::Body { body, upgrade } => {
+                    let n = self.buffer.len().min(buf.remaining());
+                    match body.advance(&self.buffer[..n]) {
+                        Some(end) => {
+                            self.state = match *upgrade {
+                                true => GateState::Switch(self.exchanges.lock().relayed),
+                                false => GateState::Head,
+                            };
+                            if end > 0 {
+                                self.pass(end, buf);
+                                return Some(Poll::Ready(()));
+                            }
+                        }
+                        // Without a known end there is no telling where the next request starts
+                        None if body.is_open() => {
+                            self.state = GateState::Closed;
+                            self.pass(n, buf);
+                            return Some(Poll::Ready(()));
+                        }
+                        None if n > 0 => {
+                            self.pass(n, buf);
+                            return Some(Poll::Ready(()));
+                        }
+                        None => return None,
+                    }
+                }
+                GateState::Head => match crate::find_header_end(&self.buffer) {
+                    Some(end) if end <= self.max_head => self.next_request(end),
+                    // Handed back to be refused as too large
+                    Some(_) => self.hand_back(),
+                    None if self.buffer.len() > self.max_head => self.hand_back(),
+                    None => return None,
+                },
+                GateState::Switch(index) => match self.exchanges.poll_switch(cx, *index) {
+                    Poll::Ready(switched) => self.state = if switched { GateState::Through } else { GateState::Head },
+                    Poll::Pending => return Some(Poll::Pending),
+                },
+            }
+        }
+    }
+}
+
+impl<R: AsyncRead + Unpin> AsyncRead for Gate<'_, R> {
+    fn poll_read(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        let Some(following) = &mut this.following else {
+            return Pin::new(&mut this.inner).poll_read(cx, buf);
+        };
+        loop {
+            if let Some(passed) = following.poll_buffered(cx, buf) {
+                return passed.map(Ok);
+            }
+            if matches!(following.state, GateState::Through) {
+                return Pin::new(&mut this.inner).poll_read(cx, buf);
+            }
+            // More of the client's bytes are needed
+            let mut chunk = [0u8; 8192];
+            let mut read = ReadBuf::new(&mut chunk);
+            ready!(Pin::new(&mut this.inner).poll_read(cx, &mut read))?;
+            if read.filled().is_empty() {
+                // The client closed the connection; an incomplete request head is not passed on
+                following.state = GateState::Closed;
+                return Poll::Ready(Ok(()));
+            }
+            following.buffer.extend_from_slice(read.filled());
+        }
+    }
+}
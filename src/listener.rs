@@ -0,0 +1,136 @@
+//! Listeners (`LISTEN_ADDRESS`, `--listen ADDRESS[;name=NAME][;psk|;cert=PATH[;key=PATH][;acme=NAME,...]]`,
+//! `--psk-listen`): the addresses the proxy accepts clients on, each with a name that
+//! `;listener=NAME` routes are restricted to.
+
+use std::net::SocketAddr;
+use std::path::PathBuf;
+
+/// Name of the LISTEN_ADDRESS listener
+pub const MAIN: &str = "main";
+
+/// Name of the `--psk-listen` listener
+pub const PSK: &str = "psk";
+
+pub struct Listener {
+    pub addr: SocketAddr,
+    pub name: String,
+    /// Whether clients connect with TLS-PSK, as on `--psk-listen`
+    pub psk: bool,
+    /// The PEM certificate chain and private key files clients connect with TLS to
+    pub certificate: Option<(PathBuf, PathBuf)>,
+    /// The DNS names the certificate is issued for through ACME, written to its files; empty when
+    /// the files come from elsewhere
+    pub acme: Vec<String>,
+}
+
+impl Listener {
+    /// `ADDRESS[;name=NAME][;psk|;cert=PATH[;key=PATH][;acme=NAME,...]]`; without a name the
+    /// listener is known by its address, and without a key file the key is read from the
+    /// certificate file
+    pub fn parse(spec: &str) -> Result<Self, String> {
+        const FORMAT: &str = "ADDRESS[;name=NAME][;psk|;cert=PATH[;key=PATH][;acme=NAME,...]]";
+        let mut parts = spec.split(';');
+        let address = parts.next().unwrap_or_default();
+        let addr = address.parse::<SocketAddr>().map_err(|e| format!("Invalid --listen address '{}': {}", address, e))?;
+        let mut listener = Listener { addr, name: address.to_string(), psk: false, certificate: None, acme: Vec::new() };
+        let (mut cert, mut key) = (None, None);
+        for option in parts {
+            match option.split_once('=') {
+                Some(("name", name)) if valid_name(name) => listener.name = name.to_string(),
+                Some(("cert", path)) if !path.is_empty() => cert = Some(PathBuf::from(path)),
+                Some(("key", path)) if !path.is_empty() => key = Some(PathBuf::from(path)),
+                Some(("acme", names)) if names.split(',').all(valid_domain) => listener.acme = names.split(',').map(str::to_ascii_lowercase).collect(),
+                None if option == "psk" => listener.psk = true,
+                _ => return Err(format!("Invalid --listen option '{}' in '{}'. Expected format: {}", option, spec, FORMAT)),
+            }
+        }
+        match (cert, key) {
+            (Some(_), _) if listener.psk => return Err(format!("--listen '{}' has both ;psk and ;cert. Expected format: {}", spec, FORMAT)),
+            (None, Some(_)) => return Err(format!("--listen '{}' has a ;key but no ;cert. Expected format: {}", spec, FORMAT)),
+            (None, None) if !listener.acme.is_empty() => return Err(format!("--listen '{}' has ;acme but no ;cert to write the certificate to. Expected format: {}", spec, FORMAT)),
+            (Some(cert), key) => listener.certificate = Some((cert.clone(), key.unwrap_or(cert))),
+            (None, None) => {}
+        }
+        Ok(listener)
+    }
+
+    /// The key a new process inherits the listener under on a binary upgrade
+    pub fn handover_key(&self) -> String {
+        format!("{}@{}", self.kind(), self.addr)
+    }
+
+    /// What clients speak to the listener: `psk`, `tls` or `http`
+    pub fn kind(&self) -> &'static str {
+        if self.psk {
+            PSK
+        } else if self.certificate.is_some() {
+            "tls"
+        } else {
+            "http"
+        }
+    }
+}
+
+/// Listener names are letters, digits, `-`, `_`, `.` and `:` (so addresses are names too), and
+/// may be bracketed IPv6 addresses
+pub fn valid_name(name: &str) -> bool {
+    !name.is_empty() && name.bytes().all(|b| b.is_ascii_alphanumeric() || b"-_.:[]".contains(&b))
+}
+
+/// Names a certificate can be issued for: two or more labels of letters, digits and inner `-`, the
+/// first of which may be `*`
+fn valid_domain(name: &str) -> bool {
+    let labels: Vec<&str> = name.strip_prefix("*.").unwrap_or(name).split('.').collect();
+    labels.len() >= 2 && labels.iter().all(|label| {
+        (1..=63).contains(&label.len())
+            && label.bytes().all(|b| b.is_ascii_alphanumeric() || b == b'-')
+            && !label.starts_with('-')
+            && !label.ends_with('-')
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_listeners() {
+        let listener = Listener::parse("127.0.0.1:8080").unwrap();
+        assert_eq!((listener.name.as_str(), listener.kind()), ("127.0.0.1:8080", "http"));
+        let listener = Listener::parse("[::1]:8443;name=internal;psk").unwrap();
+        assert_eq!((listener.name.as_str(), listener.kind(), listener.handover_key()), ("internal", "psk", "psk@[::1]:8443".to_string()));
+    }
+
+    #[test]
+    fn certificate_listeners() {
+        let listener = Listener::parse("0.0.0.0:443;cert=/etc/tls/site.pem;key=/etc/tls/site.key").unwrap();
+        assert_eq!(listener.certificate, Some((PathBuf::from("/etc/tls/site.pem"), PathBuf::from("/etc/tls/site.key"))));
+        assert_eq!((listener.kind(), listener.handover_key()), ("tls", "tls@0.0.0.0:443".to_string()));
+        // The key is read from the certificate file when not given
+        let listener = Listener::parse("0.0.0.0:443;cert=/etc/tls/site.pem").unwrap();
+        assert_eq!(listener.certificate, Some((PathBuf::from("/etc/tls/site.pem"), PathBuf::from("/etc/tls/site.pem"))));
+        assert!(listener.acme.is_empty());
+        let listener = Listener::parse("0.0.0.0:443;cert=/etc/tls/site.pem;acme=*.Example.com,example.com").unwrap();
+        assert_eq!(listener.acme, ["*.example.com", "example.com"]);
+    }
+
+    #[test]
+    fn rejects_invalid_listeners() {
+        for spec in [
+            "localhost:80",
+            "127.0.0.1:80;name=",
+            "127.0.0.1:80;name=a/b",
+            "127.0.0.1:80;tls",
+            "127.0.0.1:80;cert=",
+            "127.0.0.1:80;key=/etc/tls/site.key",
+            "127.0.0.1:80;psk;cert=/etc/tls/site.pem",
+            "127.0.0.1:80;acme=example.com",
+            "127.0.0.1:80;cert=/etc/tls/site.pem;acme=",
+            "127.0.0.1:80;cert=/etc/tls/site.pem;acme=localhost",
+            "127.0.0.1:80;cert=/etc/tls/site.pem;acme=a.*.example.com",
+            "127.0.0.1:80;cert=/etc/tls/site.pem;acme=-a.example.com",
+        ] {
+            assert!(Listener::parse(spec).is_err(), "{}", spec);
+        }
+    }
+}
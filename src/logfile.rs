@@ -0,0 +1,211 @@
+//! Log files with rotation (`--access-log`): lines are queued for a writer thread, so a slow disk
+//! delays the log rather than requests, and the file is rotated by size or time to
+//! `access.log.1`, `access.log.2`, ...
+
+use crate::logging;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufWriter, Write};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::mpsc;
+use std::sync::Arc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// Lines waiting for the writer; further ones are dropped (and counted) while the disk lags
+const QUEUE_SIZE: usize = 16 * 1024;
+
+/// Longest the shutdown waits for queued lines to be written
+const FLUSH_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// When a log file is rotated (`--access-log-rotate`): at a size, at the start of each hour or
+/// day (UTC), or whichever comes first
+#[derive(Clone, Copy, Default)]
+pub struct Rotation {
+    max_size: Option<u64>,
+    /// Length of a period in seconds, counted from the Unix epoch
+    period: Option<u64>,
+}
+
+impl Rotation {
+    /// Parse comma-separated rules: `hourly`, `daily` and sizes such as `100M` (K, M and G suffixes)
+    pub fn parse(rules: &str) -> Result<Self, String> {
+        let mut rotation = Rotation::default();
+        for rule in rules.split(',').map(str::trim) {
+            match rule {
+                "hourly" => rotation.period = Some(3600),
+                "daily" => rotation.period = Some(86400),
+                size => rotation.max_size = Some(parse_size(size).ok_or_else(|| {
+                    format!("Invalid rotation rule '{}'. Expected hourly, daily or a size like 100M", size)
+                })?),
+            }
+        }
+        Ok(rotation)
+    }
+}
+
+/// Parse a size such as `100M`: a number of bytes with an optional K, M or G suffix
+pub fn parse_size(size: &str) -> Option<u64> {
+    let (digits, unit) = match size.char_indices().find(|(_, c)| !c.is_ascii_digit()) {
+        Some((i, _)) => size.split_at(i),
+        None => (size, ""),
+    };
+    let multiplier = match unit.to_ascii_uppercase().trim_end_matches('B') {
+        "" => 1,
+        "K" => 1 << 10,
+        "M" => 1 << 20,
+        "G" => 1 << 30,
+        _ => return None,
+    };
+    digits.parse::<u64>().ok().filter(|&n| n > 0)?.checked_mul(multiplier)
+}
+
+enum Message {
+    Line(String),
+    Flush(mpsc::Sender<()>),
+}
+
+/// A log file written in the background
+pub struct LogFile {
+    sender: mpsc::SyncSender<Message>,
+    dropped: Arc<AtomicU64>,
+}
+
+impl LogFile {
+    /// Open (or create) the file for appending, keeping `keep` rotated files, and start its writer
+    pub fn open(path: &Path, rotation: Rotation, keep: usize) -> Result<Self, String> {
+        let writer = Writer::open(path.to_path_buf(), rotation, keep)
+            .map_err(|e| format!("Failed to open log file {}: {}", path.display(), e))?;
+        let (sender, receiver) = mpsc::sync_channel(QUEUE_SIZE);
+        let dropped = Arc::new(AtomicU64::new(0));
+        let counter = dropped.clone();
+        std::thread::Builder::new()
+            .name("log-writer".into())
+            .spawn(move || writer.run(receiver, counter))
+            .map_err(|e| format!("Failed to start the log writer: {}", e))?;
+        Ok(LogFile { sender, dropped })
+    }
+
+    /// Queue a line (without its newline); never blocks
+    pub fn write(&self, line: String) {
+        if self.sender.try_send(Message::Line(line)).is_err() {
+            self.dropped.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    /// Wait until the lines queued so far are on disk, or for a couple of seconds at most
+    pub fn flush(&self) {
+        let (done, wait) = mpsc::channel();
+        if self.sender.send(Message::Flush(done)).is_ok() {
+            let _ = wait.recv_timeout(FLUSH_TIMEOUT);
+        }
+    }
+}
+
+struct Writer {
+    path: PathBuf,
+    rotation: Rotation,
+    keep: usize,
+    file: BufWriter<File>,
+    size: u64,
+    /// The rotation period the file's entries belong to
+    period: u64,
+    /// Whether the last write failed, so errors are reported once rather than per line
+    failing: bool,
+}
+
+impl Writer {
+    fn open(path: PathBuf, rotation: Rotation, keep: usize) -> io::Result<Self> {
+        let file = OpenOptions::new().create(true).append(true).open(&path)?;
+        let metadata = file.metadata()?;
+        // A file left from an earlier run belongs to the period it was last written in
+        let period = rotation.period.map_or(0, |length| unix_secs(metadata.modified().unwrap_or_else(|_| SystemTime::now())) / length);
+        Ok(Writer { path, rotation, keep, file: BufWriter::new(file), size: metadata.len(), period, failing: false })
+    }
+
+    fn run(mut self, receiver: mpsc::Receiver<Message>, dropped: Arc<AtomicU64>) {
+        while let Ok(mut message) = receiver.recv() {
+            // Write everything queued, then flush once
+            loop {
+                match message {
+                    Message::Line(line) => {
+                        let result = self.write(&line);
+                        self.report(result);
+                    }
+                    Message::Flush(done) => {
+                        let result = self.file.flush();
+                        self.report(result);
+                        let _ = done.send(());
+                    }
+                }
+                match receiver.try_recv() {
+                    Ok(next) => message = next,
+                    Err(_) => break,
+                }
+            }
+            let result = self.file.flush();
+            self.report(result);
+            let dropped = dropped.swap(0, Ordering::Relaxed);
+            if dropped > 0 {
+                logging::warning(format!("Log file {} fell behind: {} lines dropped", self.path.display(), dropped));
+            }
+        }
+    }
+
+    fn write(&mut self, line: &str) -> io::Result<()> {
+        let len = line.len() as u64 + 1;
+        let period = self.rotation.period.map_or(0, |length| unix_secs(SystemTime::now()) / length);
+        let full = self.rotation.max_size.is_some_and(|max| self.size + len > max);
+        if self.size > 0 && (period != self.period || full) {
+            self.rotate()?;
+        }
+        self.period = period;
+        self.file.write_all(line.as_bytes())?;
+        self.file.write_all(b"\n")?;
+        self.size += len;
+        Ok(())
+    }
+
+    /// Shift `path.1` to `path.2` and so on, dropping the oldest, move the current file to `path.1`
+    /// and start a new one
+    fn rotate(&mut self) -> io::Result<()> {
+        self.file.flush()?;
+        let rotated = |n: usize| {
+            let mut name = self.path.clone().into_os_string();
+            name.push(format!(".{}", n));
+            PathBuf::from(name)
+        };
+        if self.keep == 0 {
+            fs::remove_file(&self.path)?;
+        } else {
+            for n in (1..self.keep).rev() {
+                match fs::rename(rotated(n), rotated(n + 1)) {
+                    Err(e) if e.kind() != io::ErrorKind::NotFound => return Err(e),
+                    _ => {}
+                }
+            }
+            fs::rename(&self.path, rotated(1))?;
+        }
+        let file = OpenOptions::new().create(true).append(true).open(&self.path)?;
+        self.file = BufWriter::new(file);
+        self.size = 0;
+        Ok(())
+    }
+
+    fn report(&mut self, result: io::Result<()>) {
+        match result {
+            Err(e) if !self.failing => {
+                logging::error(format!("Failed to write log file {}: {}", self.path.display(), e));
+                self.failing = true;
+            }
+            Ok(()) if self.failing => {
+                logging::warning(format!("Writing log file {} again", self.path.display()));
+                self.failing = false;
+            }
+            _ => {}
+        }
+    }
+}
+
+fn unix_secs(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH).unwrap_or_default().as_secs()
+}
@@ -0,0 +1,163 @@
+//! Where error messages and the access log go (`--log-target`): stderr and stdout, the local
+//! syslog daemon, or the systemd journal, with priorities, a facility and a tag
+//! (`--syslog-facility`, `--syslog-tag`).
+
+use std::fmt::Display;
+use std::sync::OnceLock;
+
+/// Message priorities, as syslog numbers them
+#[derive(Clone, Copy)]
+pub enum Priority {
+    Error = 3,
+    Warning = 4,
+    Info = 6,
+}
+
+#[derive(Clone, Copy, PartialEq)]
+pub enum Target {
+    /// Errors to stderr, the access log to stdout
+    Stdout,
+    Syslog,
+    Journald,
+}
+
+impl Target {
+    pub fn parse(name: &str) -> Result<Self, String> {
+        match name {
+            "stdout" => Ok(Target::Stdout),
+            "syslog" if cfg!(unix) => Ok(Target::Syslog),
+            "journald" if cfg!(target_os = "linux") => Ok(Target::Journald),
+            "syslog" | "journald" => Err(format!("--log-target {} is not supported on this platform", name)),
+            _ => Err(format!("Invalid log target '{}'. Expected stdout, syslog or journald", name)),
+        }
+    }
+}
+
+/// Syslog facility numbers by name
+const FACILITIES: &[(&str, u8)] = &[
+    ("kern", 0), ("user", 1), ("mail", 2), ("daemon", 3), ("auth", 4), ("syslog", 5), ("lpr", 6),
+    ("news", 7), ("uucp", 8), ("cron", 9), ("authpriv", 10), ("ftp", 11),
+    ("local0", 16), ("local1", 17), ("local2", 18), ("local3", 19),
+    ("local4", 20), ("local5", 21), ("local6", 22), ("local7", 23),
+];
+
+pub fn parse_facility(name: &str) -> Result<u8, String> {
+    FACILITIES.iter().find(|(facility, _)| *facility == name).map(|&(_, code)| code)
+        .ok_or_else(|| format!("Invalid syslog facility '{}'. Expected daemon, user, local0 ... local7 or another syslog facility", name))
+}
+
+static SYSTEM_LOG: OnceLock<SystemLog> = OnceLock::new();
+
+/// Send errors and the access log to the target from now on; with `Stdout` nothing changes
+pub fn init(target: Target, facility: u8, tag: &str) -> Result<(), String> {
+    if target == Target::Stdout {
+        return Ok(());
+    }
+    let log = SystemLog::connect(target, facility, tag)?;
+    let _ = SYSTEM_LOG.set(log);
+    Ok(())
+}
+
+pub fn error(message: impl Display) {
+    log(Priority::Error, message);
+}
+
+pub fn warning(message: impl Display) {
+    log(Priority::Warning, message);
+}
+
+/// An access log line or other routine message: to stdout, or with the info priority
+pub fn info(message: impl Display) {
+    match SYSTEM_LOG.get() {
+        Some(log) => log.send(Priority::Info, &message.to_string()),
+        None => println!("{}", message),
+    }
+}
+
+fn log(priority: Priority, message: impl Display) {
+    match SYSTEM_LOG.get() {
+        Some(log) => log.send(priority, &message.to_string()),
+        None => eprintln!("{}", message),
+    }
+}
+
+#[cfg(unix)]
+struct SystemLog {
+    socket: std::os::unix::net::UnixDatagram,
+    target: Target,
+    facility: u8,
+    tag: String,
+}
+
+#[cfg(unix)]
+impl SystemLog {
+    fn connect(target: Target, facility: u8, tag: &str) -> Result<Self, String> {
+        use std::os::unix::net::UnixDatagram;
+
+        let paths: &[&str] = match target {
+            Target::Journald => &["/run/systemd/journal/socket"],
+            // Linux and the BSDs, then macOS
+            _ => &["/dev/log", "/var/run/syslog", "/var/run/log"],
+        };
+        let socket = UnixDatagram::unbound().map_err(|e| format!("Failed to create the log socket: {}", e))?;
+        let mut last_error = None;
+        for path in paths {
+            match socket.connect(path) {
+                Ok(()) => {
+                    // A busy log daemon costs messages (on stderr instead), never a stalled request
+                    socket.set_nonblocking(true).map_err(|e| format!("Failed to set up the log socket: {}", e))?;
+                    return Ok(SystemLog { socket, target, facility, tag: tag.to_string() });
+                }
+                Err(e) => last_error = Some(format!("{}: {}", path, e)),
+            }
+        }
+        let name = if target == Target::Journald { "the systemd journal" } else { "syslog" };
+        Err(format!("Failed to connect to {} ({})", name, last_error.unwrap_or_default()))
+    }
+
+    fn send(&self, priority: Priority, message: &str) {
+        let datagram = match self.target {
+            Target::Journald => self.journal_entry(priority, message),
+            _ => format!("<{}>{}[{}]: {}", self.facility as u16 * 8 + priority as u16, self.tag, std::process::id(), message).into_bytes(),
+        };
+        if self.socket.send(&datagram).is_err() {
+            eprintln!("{}", message);
+        }
+    }
+
+    /// A journal entry in the native protocol: `FIELD=value` lines, or for values with newlines the
+    /// field name, a newline and the value prefixed with its 64-bit little-endian length
+    fn journal_entry(&self, priority: Priority, message: &str) -> Vec<u8> {
+        let mut entry = Vec::with_capacity(message.len() + 96);
+        let priority = (priority as u8).to_string();
+        let facility = self.facility.to_string();
+        let fields = [("MESSAGE", message), ("PRIORITY", &priority), ("SYSLOG_FACILITY", &facility), ("SYSLOG_IDENTIFIER", &self.tag)];
+        for (name, value) in fields {
+            entry.extend_from_slice(name.as_bytes());
+            if value.contains('\n') {
+                entry.push(b'\n');
+                entry.extend_from_slice(&(value.len() as u64).to_le_bytes());
+            } else {
+                entry.push(b'=');
+            }
+            entry.extend_from_slice(value.as_bytes());
+            entry.push(b'\n');
+        }
+        entry
+    }
+}
+
+/// Stand-in for platforms without Unix sockets, where only `--log-target stdout` parses
+#[cfg(not(unix))]
+enum SystemLog {}
+
+#[cfg(not(unix))]
+impl SystemLog {
+    fn connect(_target: Target, _facility: u8, _tag: &str) -> Result<Self, String> {
+        Err("--log-target syslog and journald need Unix sockets".into())
+    }
+
+    fn send(&self, _priority: Priority, _message: &str) {
+        match *self {}
+    }
+}
@@ -0,0 +1,235 @@
+//! Access log shipping (`--access-log-sink`): entries are queued and sent in batches to an HTTP
+//! endpoint, as newline-delimited lines in a `POST`, or to a Kafka topic, one record per entry.
+//! A sink that is down or slow holds its batch back and retries it; entries arriving meanwhile
+//! wait in the queue, and once it is full (`--access-log-queue`) new or old entries are dropped
+//! (`--access-log-drop`), so requests never wait on the log.
+
+use crate::client::{self, Url};
+use crate::kafka::{KafkaTarget, Producer};
+use crate::logging;
+use std::collections::VecDeque;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::sync::Notify;
+
+/// Longest a batch waits for more entries before it is sent
+const FLUSH_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Wait before retrying a failed batch, doubled for each failure in a row up to `MAX_BACKOFF`
+const BACKOFF: Duration = Duration::from_secs(1);
+const MAX_BACKOFF: Duration = Duration::from_secs(30);
+
+/// Least time between two warnings about dropped entries
+const DROP_REPORT_INTERVAL: Duration = Duration::from_secs(10);
+
+/// Longest the shutdown waits for queued entries to be sent
+const FLUSH_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// Where entries are shipped
+pub enum Target {
+    Http(Url),
+    Kafka(KafkaTarget),
+}
+
+impl Target {
+    /// Parse `http://...`, `https://...` or `kafka://HOST:PORT[,...]/TOPIC`
+    pub fn parse(spec: &str) -> Result<Self, String> {
+        match spec.strip_prefix("kafka://") {
+            Some(kafka) => KafkaTarget::parse(kafka).map(Target::Kafka),
+            None => Url::parse(spec).map(Target::Http)
+                .map_err(|e| format!("Invalid access log sink '{}' ({}). Expected an http(s):// URL or kafka://HOST:PORT/TOPIC", spec, e)),
+        }
+    }
+}
+
+impl std::fmt::Display for Target {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Target::Http(url) => write!(f, "{}://{}:{}{}", if url.tls { "https" } else { "http" }, url.host, url.port, url.path),
+            Target::Kafka(target) => target.fmt(f),
+        }
+    }
+}
+
+/// Which entries go when the queue is full
+#[derive(Clone, Copy)]
+pub enum DropPolicy {
+    /// Keep what is queued, drop entries arriving (`newest`)
+    Newest,
+    /// Make room by dropping the longest queued entries (`oldest`)
+    Oldest,
+}
+
+impl DropPolicy {
+    pub fn parse(name: &str) -> Result<Self, String> {
+        match name {
+            "newest" => Ok(DropPolicy::Newest),
+            "oldest" => Ok(DropPolicy::Oldest),
+            _ => Err(format!("Invalid access log drop policy '{}'. Expected newest or oldest", name)),
+        }
+    }
+}
+
+pub struct SinkConfig {
+    pub target: Target,
+    /// Entries sent at most per batch
+    pub batch: usize,
+    /// Entries queued at most
+    pub queue: usize,
+    pub drop: DropPolicy,
+    /// The entries are JSON documents (`--log-format json`)
+    pub json: bool,
+}
+
+struct Shared {
+    queue: Mutex<Queue>,
+    /// Wakes the shipper once a batch is full
+    ready: Notify,
+    /// Wakes the shutdown once the queue is sent
+    drained: Notify,
+}
+
+#[derive(Default)]
+struct Queue {
+    lines: VecDeque<String>,
+    /// Dropped since the latest warning
+    dropped: u64,
+    /// A batch is out, taken from the queue but not sent yet
+    sending: bool,
+}
+
+/// The queue of a sink, shipped by a background task
+pub struct LogSink {
+    shared: Arc<Shared>,
+    batch: usize,
+    queue: usize,
+    drop: DropPolicy,
+}
+
+impl LogSink {
+    /// Start shipping to the sink
+    pub fn start(config: SinkConfig) -> Self {
+        let shared = Arc::new(Shared { queue: Mutex::default(), ready: Notify::new(), drained: Notify::new() });
+        let sink = LogSink { shared: shared.clone(), batch: config.batch.max(1), queue: config.queue.max(1), drop: config.drop };
+        tokio::spawn(ship(config, shared));
+        sink
+    }
+
+    /// Queue an entry, or drop one when the queue is full
+    pub fn push(&self, line: String) {
+        let mut queue = self.shared.queue.lock().unwrap();
+        if queue.lines.len() >= self.queue {
+            queue.dropped += 1;
+            match self.drop {
+                DropPolicy::Newest => return,
+                DropPolicy::Oldest => {
+                    queue.lines.pop_front();
+                }
+            }
+        }
+        queue.lines.push_back(line);
+        if queue.lines.len() == self.batch {
+            self.shared.ready.notify_one();
+        }
+    }
+
+    /// Wait, up to `FLUSH_TIMEOUT`, for the queued entries to be sent
+    pub async fn flush(&self) {
+        let drained = async {
+            loop {
+                let notified = self.shared.drained.notified();
+                {
+                    let queue = self.shared.queue.lock().unwrap();
+                    if queue.lines.is_empty() && !queue.sending {
+                        return;
+                    }
+                }
+                self.shared.ready.notify_one();
+                notified.await;
+            }
+        };
+        if tokio::time::timeout(FLUSH_TIMEOUT, drained).await.is_err() {
+            let left = self.shared.queue.lock().unwrap().lines.len();
+            logging::warning(format!("Access log sink: {} entries not sent at shutdown", left));
+        }
+    }
+}
+
+enum Sender {
+    Http { url: Url, content_type: &'static str },
+    Kafka(Box<Producer>),
+}
+
+impl Sender {
+    async fn send(&mut self, lines: &[String]) -> Result<(), String> {
+        match self {
+            Sender::Http { url, content_type } => {
+                let mut body = lines.join("\n");
+                body.push('\n');
+                match client::request(url, "POST", &[("Content-Type", content_type)], body.as_bytes()).await {
+                    Ok(response) if (200..300).contains(&response.status) => Ok(()),
+                    Ok(response) => Err(format!("status {}", response.status)),
+                    Err(e) => Err(e.to_string()),
+                }
+            }
+            Sender::Kafka(producer) => producer.send(lines).await,
+        }
+    }
+}
+
+/// Send batches as they fill up, or once a second; a failed batch is retried until it goes through
+async fn ship(config: SinkConfig, shared: Arc<Shared>) {
+    let target = config.target.to_string();
+    let mut sender = match config.target {
+        Target::Http(url) => Sender::Http { url, content_type: if config.json { "application/x-ndjson" } else { "text/plain; charset=utf-8" } },
+        Target::Kafka(target) => Sender::Kafka(Box::new(Producer::new(target))),
+    };
+    let mut failures = 0u32;
+    let mut last_report = Instant::now() - DROP_REPORT_INTERVAL;
+    loop {
+        let _ = tokio::time::timeout(FLUSH_INTERVAL, shared.ready.notified()).await;
+        loop {
+            let batch: Vec<String> = {
+                let mut queue = shared.queue.lock().unwrap();
+                if queue.dropped > 0 && last_report.elapsed() >= DROP_REPORT_INTERVAL {
+                    logging::warning(format!("Access log sink {} is behind: dropped {} entries", target, queue.dropped));
+                    queue.dropped = 0;
+                    last_report = Instant::now();
+                }
+                let n = queue.lines.len().min(config.batch.max(1));
+                queue.sending = n > 0;
+                queue.lines.drain(..n).collect()
+            };
+            if batch.is_empty() {
+                shared.drained.notify_waiters();
+                break;
+            }
+            // The batch is held back while the sink fails; the queue takes the backpressure
+            loop {
+                match sender.send(&batch).await {
+                    Ok(()) => {
+                        if failures > 0 {
+                            logging::info(format!("Access log sink {} is reachable again", target));
+                        }
+                        failures = 0;
+                        break;
+                    }
+                    Err(e) => {
+                        if failures == 0 {
+                            logging::error(format!("Failed to ship access log to {}, retrying: {}", target, e));
+                        }
+                        let backoff = BACKOFF.saturating_mul(1 << failures.min(5)).min(MAX_BACKOFF);
+                        failures += 1;
+                        tokio::time::sleep(backoff).await;
+                    }
+                }
+            }
+            shared.queue.lock().unwrap().sending = false;
+            // Only full batches go out at once; the rest waits for the interval
+            if shared.queue.lock().unwrap().lines.len() < config.batch.max(1) {
+                shared.drained.notify_waiters();
+                break;
+            }
+        }
+    }
+}
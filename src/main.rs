@@ -1,137 +1,809 @@
 use tokio::net::{TcpListener, TcpStream};
-use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use std::io::IoSlice;
 use std::net::SocketAddr;
-use std::collections::HashMap;
-use clap::Parser;
+use std::time::{Duration, Instant};
+use clap::{CommandFactory, FromArgMatches, Parser};
+
+mod abort;
+mod accesslog;
+mod acme;
+mod acmedns;
+mod admin;
+mod alerts;
+mod asn;
+mod blocking;
+mod blocklist;
+mod cache;
+mod capture;
+mod certs;
+mod client;
+mod configfile;
+mod contenttype;
+mod control;
+mod dashboard;
+mod digest;
+mod discovery;
+mod dns;
+mod docker;
+mod env;
+mod errorpages;
+mod events;
+mod fault;
+mod h2c;
+mod handover;
+mod headers;
+mod health;
+mod heartbeat;
+mod icap;
+mod idempotency;
+mod intercept;
+mod k8s;
+mod kafka;
+mod keepalive;
+mod listener;
+mod logfile;
+mod logsink;
+mod logging;
+mod outlier;
+mod metrics;
+mod mirror;
+mod otel;
+mod plans;
+mod pool;
+mod preflight;
+#[cfg(feature = "psk")]
+mod psk;
+mod quota;
+mod ratelimit;
+mod redirects;
+mod region;
+mod reload;
+mod request;
+mod resolver;
+mod response;
+mod retry;
+mod routetest;
+mod routing;
+mod schema;
+mod scrub;
+mod shaper;
+mod slot;
+mod sniff;
+mod statsd;
+mod systemd;
+mod tarpit;
+mod state;
+mod transparent;
+mod trie;
+mod upstream;
+mod validate;
+mod wellknown;
+mod x509;
+
+use request::RequestHead;
+use routing::{Action, Backend, RouteConfig, RouteMatch};
+use std::borrow::Cow;
+use std::sync::atomic::{AtomicBool, Ordering};
+#[cfg(feature = "psk")]
+use psk::PskAcceptor;
 
 #[derive(Parser, Debug)]
 #[command(name = "reverse-http-proxy")]
 #[command(about = "Path-based reverse proxy with bidirectional binary streaming", long_about = None)]
 struct Args {
-    /// Address to listen on (format: ip:port)
+    /// Address to listen on (format: ip:port); required unless `--listen` or the config file's
+    /// `listen` gives one
     #[arg(value_name = "LISTEN_ADDRESS")]
-    listen_address: String,
+    listen_address: Option<String>,
 
-    /// Default backend address (format: ip:port)
+    /// Default backend address for unmatched requests (format: ip:port); without one they get a 404
     #[arg(value_name = "DEFAULT_BACKEND")]
-    default_backend: String,
+    default_backend: Option<String>,
 
-    /// Path-based routes in the format /path=ip:port (can be specified multiple times)
+    /// Routes in the format [METHOD ]/path=ip:port, host=ip:port or host/path=ip:port (can be specified multiple times)
     #[arg(short = 'r', long = "route", value_name = "PATH=BACKEND")]
     routes: Vec<String>,
 
+    /// Header-based routes in the format Header-Name=value=ip:port, taking priority over path routes
+    #[arg(long = "route-header", value_name = "HEADER=VALUE=BACKEND")]
+    header_routes: Vec<String>,
+
+    /// Query-parameter routes in the format name=value=ip:port, evaluated after header routes
+    #[arg(long = "route-query", value_name = "PARAM=VALUE=BACKEND")]
+    query_routes: Vec<String>,
+
+    /// Status code of the response to requests no route matches, when there is no default backend
+    #[arg(long = "not-found-status", value_name = "CODE", default_value_t = 404)]
+    not_found_status: u16,
+
+    /// Body of the response to requests no route matches, when there is no default backend
+    #[arg(long = "not-found-body", value_name = "TEXT", default_value = "Not Found")]
+    not_found_body: String,
+
+    /// Largest request head (request line and headers) accepted; larger ones get 431
+    #[arg(long = "max-header-size", value_name = "SIZE", default_value = "64K")]
+    max_header_size: String,
+
     /// Enable path rewriting (strip matched route prefix from forwarded requests)
     #[arg(long = "rewrite", default_value_t = false)]
     rewrite: bool,
-}
 
-struct RouteConfig {
-    default_backend: String,
-    routes: HashMap<String, String>,
-    rewrite_paths: bool,
-}
+    /// Forward the client's Host header to backends (the default); with false, routes without a
+    /// `;host=` option send the backend address instead
+    #[arg(long = "preserve-host", value_name = "BOOL", default_value_t = true, action = clap::ArgAction::Set)]
+    preserve_host: bool,
 
-impl RouteConfig {
-    fn new(default_backend: String, route_args: Vec<String>, rewrite_paths: bool) -> Result<Self, String> {
-        let mut routes = HashMap::new();
+    /// How routes with several backends spread connections: round-robin (by weight) or ip-hash
+    /// (each client address sticks to a backend)
+    #[arg(long = "lb", value_name = "STRATEGY", default_value = "round-robin")]
+    lb: String,
 
-        for route in route_args {
-            let parts: Vec<&str> = route.split('=').collect();
-            if parts.len() != 2 {
-                return Err(format!("Invalid route format: '{}'. Expected format: /path=ip:port", route));
-            }
+    /// Zone the proxy runs in: routes whose backends are tagged `@ZONE` prefer the backends in it
+    #[arg(long = "zone", value_name = "NAME")]
+    zone: Option<String>,
 
-            let path = parts[0].to_string();
-            let backend = parts[1].to_string();
+    /// Seconds between the latency probes of the backends of routes with zones
+    #[arg(long = "probe-interval", value_name = "SECONDS", default_value_t = 10)]
+    probe_interval: u64,
 
-            if !path.starts_with('/') {
-                return Err(format!("Path must start with '/': {}", path));
-            }
+    /// Seconds the addresses of backends given by host name are used before they are looked up
+    /// again (0 looks them up for every connection)
+    #[arg(long = "dns-ttl", value_name = "SECONDS", default_value_t = 30)]
+    dns_ttl: u64,
 
-            routes.insert(path, backend);
-        }
+    /// Nameserver to send SRV queries of `srv:` routes to, instead of the first one of /etc/resolv.conf
+    #[arg(long = "dns-server", value_name = "ADDRESS")]
+    dns_server: Option<String>,
+
+    /// Kubernetes API server to watch the Endpoints of `k8s:` routes on, such as `kubectl proxy`'s
+    /// http://127.0.0.1:8001; in a pod, its service account's API server, credentials and CA
+    #[arg(long = "k8s-api", value_name = "URL")]
+    k8s_api: Option<String>,
+
+    /// Route to running containers by their `proxy.*` labels, following them as they start and
+    /// stop, through the Docker socket (default /var/run/docker.sock)
+    #[arg(long = "docker", value_name = "SOCKET", num_args = 0..=1, default_missing_value = docker::DEFAULT_SOCKET)]
+    docker: Option<String>,
+
+    /// Check every backend periodically and take failing ones out of rotation: tcp (connect only),
+    /// http (GET /) or http:/PATH (a 2xx or 3xx response passes)
+    #[arg(long = "health-check", value_name = "CHECK")]
+    health_check: Option<String>,
+
+    /// Statuses that pass an HTTP health check instead of 2xx and 3xx: codes, ranges and classes,
+    /// such as 200,204 or 200-299 or 2xx
+    #[arg(long = "health-status", value_name = "STATUSES")]
+    health_status: Option<String>,
+
+    /// Text the body of an HTTP health check's response has to contain
+    #[arg(long = "health-body", value_name = "TEXT")]
+    health_body: Option<String>,
+
+    /// A field of the JSON body of HTTP health checks' responses and the value it has to have, such
+    /// as status=ok or checks.db.status=up (repeatable; all have to match)
+    #[arg(long = "health-json", value_name = "FIELD=VALUE")]
+    health_json: Vec<String>,
+
+    /// Seconds between health checks
+    #[arg(long = "health-interval", value_name = "SECONDS", default_value_t = 5)]
+    health_interval: u64,
+
+    /// Seconds a health check may take before it counts as failed
+    #[arg(long = "health-timeout", value_name = "SECONDS", default_value_t = 2)]
+    health_timeout: u64,
+
+    /// Passed health checks in a row that bring an unhealthy backend back into rotation
+    #[arg(long = "healthy-threshold", visible_alias = "health-rise", value_name = "COUNT", default_value_t = 2)]
+    healthy_threshold: u32,
+
+    /// Failed health checks in a row that take a backend out of rotation
+    #[arg(long = "unhealthy-threshold", visible_alias = "health-fall", value_name = "COUNT", default_value_t = 3)]
+    unhealthy_threshold: u32,
+
+    /// Vary each wait between a backend's health checks at random by up to this percentage of the
+    /// interval either way (up to 50), so that checks do not hit backends in lockstep
+    #[arg(long = "health-jitter", value_name = "PERCENT", default_value_t = 0.0)]
+    health_jitter: f64,
+
+    /// A backend's own health check settings instead of the general ones, such as
+    /// 10.0.0.1:8080=interval=10,timeout=5,rise=3,fall=2 (repeatable)
+    #[arg(long = "health-override", value_name = "BACKEND=KEY=VALUE,...")]
+    health_overrides: Vec<String>,
+
+    /// Warn when the TLS certificate of a backend, as seen by HTTP health checks, expires within
+    /// this many days
+    #[arg(long = "backend-cert-warning", value_name = "DAYS", default_value_t = 14)]
+    backend_cert_warning: u64,
+
+    /// Warn when the TLS certificate of a `--listen ADDRESS;cert=PATH` listener expires within this
+    /// many days, when it is loaded and whenever it is reloaded
+    #[arg(long = "cert-warning", value_name = "DAYS", default_value_t = 14)]
+    cert_warning: u64,
+
+    /// ACME directory the certificates of `--listen ADDRESS;cert=PATH;acme=NAME,...` listeners are
+    /// ordered from
+    #[arg(long = "acme-directory", value_name = "URL", default_value = acme::LETS_ENCRYPT)]
+    acme_directory: String,
+
+    /// Contact address of the ACME account, for the CA's notices
+    #[arg(long = "acme-email", value_name = "EMAIL")]
+    acme_email: Option<String>,
+
+    /// PEM (PKCS#8) P-256 key of the ACME account, created when the file does not exist; required
+    /// by `;acme` listeners
+    #[arg(long = "acme-account-key", value_name = "PATH")]
+    acme_account_key: Option<std::path::PathBuf>,
+
+    /// Where DNS-01 challenge records are published: cloudflare, route53 or rfc2136:SERVER[:PORT],
+    /// with credentials from the environment; required by `;acme` listeners
+    #[arg(long = "acme-dns", value_name = "PROVIDER")]
+    acme_dns: Option<String>,
+
+    /// Seconds challenge records are given to reach the zone's nameservers before the CA checks them
+    #[arg(long = "acme-dns-wait", value_name = "SECONDS", default_value_t = 30)]
+    acme_dns_wait: u64,
+
+    /// Renew the certificates of `;acme` listeners this many days before they expire
+    #[arg(long = "acme-renew", value_name = "DAYS", default_value_t = 30)]
+    acme_renew: u64,
+
+    /// Eject a backend from rotation after this many connect failures and 5xx responses in a row
+    #[arg(long = "outlier-consecutive-errors", value_name = "COUNT")]
+    outlier_consecutive_errors: Option<u32>,
+
+    /// Eject a backend from rotation when this percentage of its requests in a window fail
+    #[arg(long = "outlier-error-rate", value_name = "PERCENT")]
+    outlier_error_rate: Option<f64>,
+
+    /// Seconds over which --outlier-error-rate is computed
+    #[arg(long = "outlier-window", value_name = "SECONDS", default_value_t = 10)]
+    outlier_window: u64,
+
+    /// Requests in a window before --outlier-error-rate applies
+    #[arg(long = "outlier-min-requests", value_name = "COUNT", default_value_t = 20)]
+    outlier_min_requests: u64,
+
+    /// Seconds a first ejection lasts; each ejection in a row lasts twice as long as the one before
+    #[arg(long = "outlier-ejection", value_name = "SECONDS", default_value_t = 30)]
+    outlier_ejection: u64,
+
+    /// Seconds an ejection lasts at most
+    #[arg(long = "outlier-max-ejection", value_name = "SECONDS", default_value_t = 300)]
+    outlier_max_ejection: u64,
+
+    /// Retries of `;retry` routes allowed per 100 of their requests, over 10 seconds (at least 10 in
+    /// each period)
+    #[arg(long = "retry-budget", value_name = "PERCENT", default_value_t = 20)]
+    retry_budget: u32,
+
+    /// Regex path rewrite for a route, applied after routing (format: 'route=/api s|^/api/(v\d+)/|/$1/|')
+    #[arg(long = "rewrite-rule", value_name = "RULE")]
+    rewrite_rules: Vec<String>,
+
+    /// Regex substitution in a route's HTML and JSON response bodies, replacing every match
+    /// (format: 'route=/app s|http://10.0.0.5:8080/|https://example.com/app/|')
+    #[arg(long = "sub-filter", value_name = "RULE")]
+    body_filters: Vec<String>,
+
+    /// Header to set on a route's proxied requests, replacing any the client sent (format: 'ROUTE:Name: value')
+    #[arg(long = "set-header", value_name = "RULE")]
+    set_headers: Vec<String>,
+
+    /// Header to remove from a route's proxied requests (format: 'ROUTE:Name')
+    #[arg(long = "remove-header", value_name = "RULE")]
+    remove_headers: Vec<String>,
+
+    /// Header to set on the responses of a route's backends (format: 'ROUTE:Name: value')
+    #[arg(long = "set-response-header", value_name = "RULE")]
+    set_response_headers: Vec<String>,
+
+    /// Header to remove from the responses of a route's backends (format: 'ROUTE:Name')
+    #[arg(long = "remove-response-header", value_name = "RULE")]
+    remove_response_headers: Vec<String>,
+
+    /// Link header of a `103 Early Hints` response sent to HTTP/1.1 clients of a route before the
+    /// backend answers (format: 'ROUTE:</app.css>; rel=preload'; can be specified multiple times)
+    #[arg(long = "early-hint", value_name = "RULE")]
+    early_hints: Vec<String>,
+
+    /// Rate limit shared by the routes naming it with `;rate-limit=@NAME` (format: 'NAME=100/s[:ip|:asn|:all|:user]')
+    #[arg(long = "rate-limit-group", value_name = "NAME=LIMIT")]
+    rate_limit_groups: Vec<String>,
+
+    /// Pool of one color of a blue/green slot, taking the connections of routes to slot:NAME while
+    /// the slot is on that color (format: 'NAME:blue=BACKENDS' or 'NAME:green=BACKENDS'; blue is
+    /// active at first; can be specified multiple times)
+    #[arg(long = "slot", value_name = "NAME:COLOR=BACKENDS")]
+    slots: Vec<String>,
+
+    /// Where the user identity of `;rate-limit=...:user` limits comes from: psk, header:NAME, or a
+    /// bearer token's JWT claim as jwt[:CLAIM] (default claim: sub)
+    #[arg(long = "user-identity", value_name = "SOURCE")]
+    user_identity: Option<String>,
+
+    /// File of per-user limit tiers (`plan NAME REQUESTS/UNIT` and `USER PLAN` lines) replacing the
+    /// route's limit for the users listed, reloaded when it changes
+    #[arg(long = "rate-limit-plans", value_name = "PATH")]
+    rate_limit_plans: Option<std::path::PathBuf>,
+
+    /// File of legacy paths to redirect (`OLD_PATH NEW_URL [STATUS]` per line), checked before all routes
+    /// and reloaded when it changes
+    #[arg(long = "redirect-map", value_name = "PATH")]
+    redirect_map: Option<std::path::PathBuf>,
+
+    /// Directory of error pages (`502.html`, `503.html`, ..., `5xx.html` for the rest) replacing 5xx
+    /// responses from backends and the proxy's own 502; routes with `;pass-errors` keep the originals
+    #[arg(long = "custom-errors", value_name = "DIR")]
+    custom_errors: Option<std::path::PathBuf>,
+
+    /// Connect to backends from the client's IP address instead of the proxy's (Linux only; needs
+    /// CAP_NET_ADMIN and policy routing for the backends' replies)
+    #[arg(long = "transparent", default_value_t = false)]
+    transparent: bool,
+
+    /// Serve /robots.txt from a file instead of the backends (format: [HOST=]FILE; can be specified
+    /// once for all hosts and once per virtual host)
+    #[arg(long = "robots-txt", value_name = "[HOST=]FILE")]
+    robots_txt: Vec<String>,
+
+    /// Serve /.well-known/security.txt from a file instead of the backends (format: [HOST=]FILE)
+    #[arg(long = "security-txt", value_name = "[HOST=]FILE")]
+    security_txt: Vec<String>,
+
+    /// Limit what the requests for a virtual host may take: open connections, bandwidth per second
+    /// and force-cache space (format: HOST=connections=N,bandwidth=SIZE,cache=SIZE, each optional;
+    /// can be specified once per host)
+    #[arg(long = "host-quota", value_name = "HOST=LIMITS")]
+    host_quotas: Vec<String>,
+
+    /// Additional TLS listener authenticating clients by pre-shared key (format: ip:port; requires
+    /// the `psk` build feature)
+    #[arg(long = "psk-listen", value_name = "ADDRESS")]
+    psk_listen: Option<String>,
+
+    /// Additional listener (format: ip:port[;name=NAME][;psk|;cert=PATH[;key=PATH][;acme=NAME,...]]):
+    /// `;listener=NAME` routes are only served on the listeners they name, `;psk` listeners take
+    /// TLS-PSK clients and `;cert` listeners take TLS clients with the PEM certificate chain and key
+    /// (from the certificate file without `;key`), issued for the names through ACME with `;acme`
+    /// (can be specified multiple times)
+    #[arg(long = "listen", value_name = "ADDRESS")]
+    listeners: Vec<String>,
+
+    /// Pre-shared keys for --psk-listen and `--sniff tls=terminate`, one IDENTITY:HEXKEY per line
+    #[arg(long = "psk-keys", value_name = "PATH")]
+    psk_keys: Option<std::path::PathBuf>,
+
+    /// Sniff the protocol of connections to LISTEN_ADDRESS: HTTP is proxied, TLS and other protocols
+    /// are terminated (TLS-PSK), passed through to a backend or rejected (format: tls|other=ACTION,
+    /// ACTION being terminate, reject or ip:port; unset protocols are rejected)
+    #[arg(long = "sniff", value_name = "PROTOCOL=ACTION")]
+    sniff: Vec<String>,
+
+    /// Crypto provider for outbound TLS (alert webhooks, trace export): fips, aws-lc-rs or ring, as built in with the
+    /// features of the same names (defaults to the first of these that is built in)
+    #[arg(long = "tls-provider", value_name = "NAME")]
+    tls_provider: Option<String>,
+
+    /// ICAP service scanning request bodies on `;scan` routes before they are forwarded
+    /// (format: icap://host[:port]/service, e.g. c-icap with ClamAV)
+    #[arg(long = "icap", value_name = "URL")]
+    icap: Option<String>,
+
+    /// Blocklist refusing the clients it lists on `;blocklist` routes: a file or an http(s):// URL of
+    /// addresses and CIDR networks, or a DNS blocklist as dnsbl:ZONE (can be specified multiple times)
+    #[arg(long = "blocklist", value_name = "SOURCE")]
+    blocklists: Vec<String>,
+
+    /// How often blocklist files and URLs are reloaded, and how long DNSBL answers are cached, in seconds
+    #[arg(long = "blocklist-refresh", value_name = "SECONDS", default_value_t = 300)]
+    blocklist_refresh: u64,
 
-        Ok(RouteConfig {
-            default_backend,
-            routes,
-            rewrite_paths,
-        })
+    /// Connections held in the tarpit of `;blocklist=tarpit` routes at once at most; further listed
+    /// clients are refused
+    #[arg(long = "tarpit-max", value_name = "COUNT", default_value_t = 100)]
+    tarpit_max: usize,
+
+    /// How long a tarpitted connection is held before it is closed, in seconds
+    #[arg(long = "tarpit-duration", value_name = "SECONDS", default_value_t = 300)]
+    tarpit_duration: u64,
+
+    /// MaxMind DB file mapping addresses to autonomous systems (e.g. GeoLite2-ASN.mmdb), for
+    /// `;deny-asn`, `;allow-asn` and `;rate-limit=...:asn` routes
+    #[arg(long = "asn-db", value_name = "PATH")]
+    asn_db: Option<std::path::PathBuf>,
+
+    /// Address for the admin API listener (format: ip:port, disabled by default)
+    #[arg(long = "admin", value_name = "ADMIN_ADDRESS")]
+    admin_address: Option<String>,
+
+    /// Webhook URL receiving JSON alert events (Slack-compatible, disabled by default)
+    #[arg(long = "alert-webhook", value_name = "URL")]
+    alert_webhook: Option<String>,
+
+    /// Alert when the percentage of failed requests in a window exceeds this value
+    #[arg(long = "alert-error-rate", value_name = "PERCENT")]
+    alert_error_rate: Option<f64>,
+
+    /// Alert when the mean time to first response byte in a window exceeds this value
+    #[arg(long = "alert-latency-ms", value_name = "MILLISECONDS")]
+    alert_latency_ms: Option<u64>,
+
+    /// Alert when a backend fails this many consecutive connection attempts (0 disables)
+    #[arg(long = "alert-backend-failures", value_name = "COUNT", default_value_t = 3)]
+    alert_backend_failures: u32,
+
+    /// Length of the error rate and latency evaluation window in seconds
+    #[arg(long = "alert-window", value_name = "SECONDS", default_value_t = 60)]
+    alert_window: u64,
+
+    /// Minimum requests in a window before error rate and latency alerts are evaluated
+    #[arg(long = "alert-min-requests", value_name = "COUNT", default_value_t = 10)]
+    alert_min_requests: u64,
+
+    /// Log a summary of each route's requests, status classes, latency percentiles and bytes at this interval
+    #[arg(long = "metrics-summary", value_name = "SECONDS")]
+    metrics_summary: Option<u64>,
+
+    /// StatsD server receiving request counters, latencies and error rates over UDP (format: host:port)
+    #[arg(long = "statsd", value_name = "ADDRESS")]
+    statsd: Option<String>,
+
+    /// Prefix of the metric names sent to StatsD
+    #[arg(long = "statsd-prefix", value_name = "PREFIX", default_value = "reverse_proxy.")]
+    statsd_prefix: String,
+
+    /// Tag sent with every StatsD metric, as NAME:VALUE (can be specified multiple times)
+    #[arg(long = "statsd-tag", value_name = "TAG")]
+    statsd_tags: Vec<String>,
+
+    /// StatsD dialect: dogstatsd (labels as tags) or statsd (labels in the metric names)
+    #[arg(long = "statsd-format", value_name = "FORMAT", default_value = "dogstatsd")]
+    statsd_format: String,
+
+    /// How often metrics are sent to StatsD, in seconds
+    #[arg(long = "statsd-interval", value_name = "SECONDS", default_value_t = 10)]
+    statsd_interval: u64,
+
+    /// Access log line format: text, json or logfmt
+    #[arg(long = "log-format", value_name = "FORMAT", default_value = "text")]
+    log_format: String,
+
+    /// Where errors and the access log go: stdout (errors to stderr), syslog or journald
+    #[arg(long = "log-target", value_name = "TARGET", default_value = "stdout")]
+    log_target: String,
+
+    /// Syslog facility of the messages for --log-target syslog and journald
+    #[arg(long = "syslog-facility", value_name = "FACILITY", default_value = "daemon")]
+    syslog_facility: String,
+
+    /// Tag (identifier) of the messages for --log-target syslog and journald
+    #[arg(long = "syslog-tag", value_name = "TAG", default_value = "reverse-http-proxy")]
+    syslog_tag: String,
+
+    /// Write the access log to this file instead of stdout or the log target
+    #[arg(long = "access-log", value_name = "PATH")]
+    access_log: Option<std::path::PathBuf>,
+
+    /// When to rotate the access log file: hourly, daily and/or a size such as 100M (comma-separated)
+    #[arg(long = "access-log-rotate", value_name = "RULES")]
+    access_log_rotate: Option<String>,
+
+    /// Rotated access log files to keep (access.log.1, access.log.2, ...)
+    #[arg(long = "access-log-keep", value_name = "COUNT", default_value_t = 7)]
+    access_log_keep: usize,
+
+    /// Ship the access log in batches to an HTTP endpoint (POST of newline-delimited entries) or a
+    /// Kafka topic (kafka://HOST:PORT[,HOST:PORT...]/TOPIC), instead of writing it to stdout
+    #[arg(long = "access-log-sink", value_name = "URL")]
+    access_log_sink: Option<String>,
+
+    /// Entries sent at most per batch to the access log sink; smaller batches go every second
+    #[arg(long = "access-log-batch", value_name = "COUNT", default_value_t = 500)]
+    access_log_batch: usize,
+
+    /// Entries queued at most for the access log sink while it is slow or down
+    #[arg(long = "access-log-queue", value_name = "COUNT", default_value_t = 10000)]
+    access_log_queue: usize,
+
+    /// Entries dropped when the sink's queue is full: newest (those arriving) or oldest
+    #[arg(long = "access-log-drop", value_name = "POLICY", default_value = "newest")]
+    access_log_drop: String,
+
+    /// Scrub credentials in query parameters and URLs, and e-mail addresses, from access log
+    /// entries and traces
+    #[arg(long = "scrub", default_value_t = false)]
+    scrub: bool,
+
+    /// Another query parameter whose values are scrubbed, implying --scrub (can be specified multiple times)
+    #[arg(long = "scrub-param", value_name = "NAME")]
+    scrub_params: Vec<String>,
+
+    /// Regex whose matches are scrubbed, implying --scrub (can be specified multiple times)
+    #[arg(long = "scrub-pattern", value_name = "REGEX")]
+    scrub_patterns: Vec<String>,
+
+    /// Spool directory receiving sampled requests and their responses, bodies included, for
+    /// debugging; the oldest captures are deleted to keep it within --capture-spool
+    #[arg(long = "capture", value_name = "DIR")]
+    capture: Option<std::path::PathBuf>,
+
+    /// Percentage of the proxied requests recorded for --capture
+    #[arg(long = "capture-rate", value_name = "PERCENT", default_value_t = 1.0)]
+    capture_rate: f64,
+
+    /// Route whose requests are captured, as the access log names it (can be specified multiple
+    /// times; default: all routes)
+    #[arg(long = "capture-route", value_name = "ROUTE")]
+    capture_routes: Vec<String>,
+
+    /// Lowest response status captured, such as 500
+    #[arg(long = "capture-status", value_name = "CODE")]
+    capture_status: Option<u16>,
+
+    /// Part of the request's or the response's Content-Type to capture, such as json (can be
+    /// specified multiple times; default: all types)
+    #[arg(long = "capture-type", value_name = "TYPE")]
+    capture_types: Vec<String>,
+
+    /// Largest request or response body of a capture; larger exchanges are not captured
+    #[arg(long = "capture-max-body", value_name = "SIZE", default_value = "64K")]
+    capture_max_body: String,
+
+    /// Space the captures take at most, such as 100M
+    #[arg(long = "capture-spool", value_name = "SIZE", default_value = "100M")]
+    capture_spool: String,
+
+    /// OTLP/HTTP collector receiving a trace of each proxied request (e.g. http://collector:4318;
+    /// `/v1/traces` is appended when the URL has no path)
+    #[arg(long = "otlp-endpoint", value_name = "URL")]
+    otlp_endpoint: Option<String>,
+
+    /// `service.name` of the exported traces
+    #[arg(long = "otel-service-name", value_name = "NAME", default_value = "reverse-http-proxy")]
+    otel_service_name: String,
+
+    /// Snapshot file: restored at startup if present (replacing the routes given on the
+    /// command line) and written on shutdown or via the admin API
+    #[arg(long = "state-file", value_name = "PATH")]
+    state_file: Option<std::path::PathBuf>,
+
+    /// Seconds an old process has to finish its connections after handing its listeners over to a
+    /// new one on SIGUSR2
+    #[arg(long = "drain-timeout", value_name = "SECONDS", default_value_t = 60)]
+    drain_timeout: u64,
+
+    /// gRPC control-plane endpoint pushing route tables (e.g. http://controller:18000)
+    #[arg(long = "control-plane", value_name = "URL")]
+    control_plane: Option<String>,
+
+    /// Node identifier reported to the control plane (defaults to $HOSTNAME)
+    #[arg(long = "node-id", value_name = "NAME")]
+    node_id: Option<String>,
+
+    /// TOML file with any of the options above; options given on the command line override it
+    #[arg(long = "config", value_name = "PATH")]
+    config: Option<std::path::PathBuf>,
+}
+
+impl Args {
+    /// Parse the command line and fill in the options it does not give from the config file
+    fn load(matches: &clap::ArgMatches) -> Result<Self, String> {
+        let mut args = Args::from_arg_matches(matches).map_err(|e| e.to_string())?;
+        // Quoted routes on the command line may still name environment variables
+        let expanded = args.routes.iter_mut()
+            .chain(&mut args.header_routes)
+            .chain(&mut args.query_routes)
+            .chain(&mut args.default_backend)
+            .chain(&mut args.listen_address)
+            .chain(&mut args.listeners);
+        for value in expanded {
+            *value = env::expand(value)?;
+        }
+        if let Some(path) = args.config.clone() {
+            configfile::ConfigFile::load(&path)?.apply(&mut args, matches)?;
+        }
+        Ok(args)
     }
 
-    fn get_backend_and_prefix<'a>(&'a self, path: &str) -> (&'a str, &'a str) {
-        // Try exact match first
-        if let Some(backend) = self.routes.get(path) {
-            // For exact match, return the route path from the HashMap
-            for route_path in self.routes.keys() {
-                if route_path == path {
-                    return (backend.as_str(), route_path.as_str());
-                }
+    /// LISTEN_ADDRESS, `--psk-listen` and the `--listen` listeners, in that order
+    fn listeners(&self) -> Result<Vec<listener::Listener>, String> {
+        let mut listeners = Vec::new();
+        if let Some(address) = &self.listen_address {
+            let addr = address.parse().map_err(|e| format!("Invalid LISTEN_ADDRESS '{}': {}", address, e))?;
+            listeners.push(listener::Listener { addr, name: listener::MAIN.to_string(), psk: false, certificate: None, acme: Vec::new() });
+        }
+        if let Some(address) = &self.psk_listen {
+            let addr = address.parse().map_err(|e| format!("Invalid --psk-listen '{}': {}", address, e))?;
+            listeners.push(listener::Listener { addr, name: listener::PSK.to_string(), psk: true, certificate: None, acme: Vec::new() });
+        }
+        for spec in &self.listeners {
+            let listener = listener::Listener::parse(spec)?;
+            if let Some(other) = listeners.iter().find(|other| other.name == listener.name || other.addr == listener.addr) {
+                return Err(format!("--listen '{}' has the name or address of listener {} ({})", spec, other.name, other.addr));
             }
+            listeners.push(listener);
+        }
+        Ok(listeners)
+    }
+
+    /// Checks between options that clap cannot express
+    fn check(&self) -> Result<(), String> {
+        if self.listen_address.is_none() && self.listeners.is_empty() {
+            return Err("Missing LISTEN_ADDRESS: give it on the command line, with --listen or as `listen` in the config file".into());
+        }
+        if self.psk_listen.is_some() && self.psk_keys.is_none() {
+            return Err("--psk-listen requires --psk-keys".into());
+        }
+        if self.listeners.iter().filter_map(|spec| listener::Listener::parse(spec).ok()).any(|listener| listener.psk) && self.psk_keys.is_none() {
+            return Err("--listen ADDRESS;psk requires --psk-keys".into());
+        }
+        if self.listeners.iter().filter_map(|spec| listener::Listener::parse(spec).ok()).any(|listener| !listener.acme.is_empty())
+            && (self.acme_account_key.is_none() || self.acme_dns.is_none())
+        {
+            return Err("--listen ADDRESS;acme requires --acme-account-key and --acme-dns".into());
+        }
+        if let Some(spec) = &self.acme_dns {
+            acmedns::Provider::parse(spec)?;
+        }
+        if self.sniff.iter().any(|spec| spec == "tls=terminate") && self.psk_keys.is_none() {
+            return Err("--sniff tls=terminate requires --psk-keys".into());
+        }
+        self.max_header_size()?;
+        if self.access_log_rotate.is_some() && self.access_log.is_none() {
+            return Err("--access-log-rotate requires --access-log".into());
+        }
+        if self.outlier_consecutive_errors == Some(0) {
+            return Err("--outlier-consecutive-errors must be at least 1".into());
+        }
+        if self.outlier_error_rate.is_some_and(|percent| !(percent > 0.0 && percent <= 100.0)) {
+            return Err("--outlier-error-rate must be a percentage above 0 and up to 100".into());
+        }
+        if self.healthy_threshold == 0 || self.unhealthy_threshold == 0 {
+            return Err("--healthy-threshold and --unhealthy-threshold must be at least 1".into());
+        }
+        if self.transparent && !cfg!(target_os = "linux") {
+            return Err("--transparent is only supported on Linux".into());
+        }
+        if self.capture.is_none() && (!self.capture_routes.is_empty() || self.capture_status.is_some() || !self.capture_types.is_empty()) {
+            return Err("--capture-route, --capture-status and --capture-type require --capture".into());
         }
+        Ok(())
+    }
 
-        // Try prefix matching (longest match wins)
-        let mut best_match: &str = "";
-        let mut best_backend = self.default_backend.as_str();
+    /// How `--health-check` probes backends
+    fn health_config(&self) -> Result<Option<health::HealthConfig>, String> {
+        let expect = health::Expect::parse(self.health_status.as_deref(), self.health_body.as_deref(), &self.health_json)?;
+        let Some(check) = self.health_check.as_deref().map(health::Check::parse).transpose()? else {
+            return match expect.is_empty() {
+                true => Ok(None),
+                false => Err("--health-status, --health-body and --health-json require --health-check".into()),
+            };
+        };
+        if matches!(check, health::Check::Tcp) && !expect.is_empty() {
+            return Err("--health-status, --health-body and --health-json require an http health check".into());
+        }
+        if !(0.0..=health::MAX_JITTER).contains(&self.health_jitter) {
+            return Err(format!("--health-jitter must be a percentage from 0 to {}", health::MAX_JITTER));
+        }
+        let overrides = self.health_overrides.iter().map(|spec| health::Override::parse(spec)).collect::<Result<_, _>>()?;
+        Ok(Some(health::HealthConfig {
+            check,
+            expect,
+            interval: Duration::from_secs(self.health_interval.max(1)),
+            timeout: Duration::from_secs(self.health_timeout.max(1)),
+            healthy_threshold: self.healthy_threshold,
+            unhealthy_threshold: self.unhealthy_threshold,
+            jitter: self.health_jitter / 100.0,
+            overrides,
+            cert_warning: Duration::from_secs(self.backend_cert_warning * 86400),
+        }))
+    }
 
-        for (route_path, backend) in &self.routes {
-            if path.starts_with(route_path.as_str()) && route_path.len() > best_match.len() {
-                best_match = route_path.as_str();
-                best_backend = backend.as_str();
-            }
+    /// What `--capture` records and keeps
+    fn capture_config(&self) -> Result<Option<capture::CaptureConfig>, String> {
+        let Some(dir) = &self.capture else {
+            return Ok(None);
+        };
+        if !(self.capture_rate > 0.0 && self.capture_rate <= 100.0) {
+            return Err("--capture-rate must be a percentage above 0 and up to 100".into());
         }
+        let size = |flag: &str, size: &str| logfile::parse_size(size).ok_or_else(|| format!("Invalid {} '{}': expected a size such as 64K", flag, size));
+        Ok(Some(capture::CaptureConfig {
+            dir: dir.clone(),
+            rate: self.capture_rate / 100.0,
+            routes: self.capture_routes.clone(),
+            min_status: self.capture_status,
+            types: self.capture_types.iter().map(|t| t.to_ascii_lowercase()).collect(),
+            max_body: size("--capture-max-body", &self.capture_max_body)? as usize,
+            spool: size("--capture-spool", &self.capture_spool)?,
+        }))
+    }
+
+    /// The largest request head accepted, in bytes
+    fn max_header_size(&self) -> Result<usize, String> {
+        logfile::parse_size(&self.max_header_size)
+            .map(|size| size as usize)
+            .ok_or_else(|| format!("Invalid --max-header-size '{}': expected a size such as 64K", self.max_header_size))
+    }
 
-        (best_backend, best_match)
+    /// The routing state the options describe
+    fn snapshot(&self) -> state::Snapshot {
+        state::Snapshot {
+            default_backend: self.default_backend.clone(),
+            routes: self.routes.clone(),
+            header_routes: self.header_routes.clone(),
+            query_routes: self.query_routes.clone(),
+            rewrite: self.rewrite,
+            rewrite_rules: self.rewrite_rules.clone(),
+            preserve_host: self.preserve_host,
+            body_filters: self.body_filters.clone(),
+            set_headers: self.set_headers.clone(),
+            remove_headers: self.remove_headers.clone(),
+            set_response_headers: self.set_response_headers.clone(),
+            remove_response_headers: self.remove_response_headers.clone(),
+            early_hints: self.early_hints.clone(),
+            rate_limit_groups: self.rate_limit_groups.clone(),
+            slots: self.slots.clone(),
+            ..Default::default()
+        }
     }
 }
 
-/// Parse the HTTP request to extract the path
-/// Returns the path and the original request bytes
-async fn parse_http_request(stream: &mut TcpStream) -> Result<(String, Vec<u8>), Box<dyn std::error::Error + Send + Sync>> {
-    let mut buffer = vec![0u8; 8192];
-    let mut total_read = 0;
+/// Answer to request heads over `--max-header-size`
+const HEADER_TOO_LARGE: &[u8] = b"HTTP/1.1 431 Request Header Fields Too Large\r\nContent-Type: text/plain; charset=utf-8\r\nContent-Length: 33\r\nConnection: close\r\n\r\nRequest Header Fields Too Large\r\n";
+
+/// Read a request head of at most `max` bytes, after the bytes already `received`. Returns all bytes
+/// received so far (the head and any body bytes) and the length of the head, or None when the head
+/// is larger
+async fn read_request_head<S: AsyncRead + Unpin>(stream: &mut S, mut buffer: Vec<u8>, max: usize) -> Result<Option<(Vec<u8>, usize)>, Box<dyn std::error::Error + Send + Sync>> {
+    let mut chunk = vec![0u8; 8192.min(max + 4)];
 
     // Read until we have the complete HTTP headers
     loop {
-        let n = stream.read(&mut buffer[total_read..]).await?;
+        // Check if we have the complete headers (look for \r\n\r\n)
+        if let Some(pos) = find_header_end(&buffer).filter(|&pos| pos <= max) {
+            return Ok(Some((buffer, pos)));
+        }
+        if buffer.len() > max {
+            return Ok(None);
+        }
+
+        let n = stream.read(&mut chunk).await?;
         if n == 0 {
             return Err("Connection closed before receiving complete headers".into());
         }
+        buffer.extend_from_slice(&chunk[..n]);
+    }
+}
 
-        total_read += n;
-
-        // Check if we have the complete headers (look for \r\n\r\n)
-        if let Some(pos) = find_header_end(&buffer[..total_read]) {
-            // Parse just the headers to extract the path
-            let headers_slice = &buffer[..pos];
-            let mut headers = [httparse::EMPTY_HEADER; 64];
-            let mut req = httparse::Request::new(&mut headers);
-
-            match req.parse(headers_slice) {
-                Ok(httparse::Status::Complete(_)) => {
-                    let path = req.path.unwrap_or("/").to_string();
-
-                    // Return the path and all the data we've read so far
-                    let request_data = buffer[..total_read].to_vec();
-                    return Ok((path, request_data));
-                }
-                Ok(httparse::Status::Partial) => {
-                    // Need more data, continue reading
-                    if total_read >= buffer.len() {
-                        // Resize buffer if needed
-                        buffer.resize(buffer.len() * 2, 0);
-                    }
-                    continue;
-                }
-                Err(e) => {
-                    return Err(format!("Failed to parse HTTP request: {}", e).into());
-                }
-            }
-        }
+/// How the end of a request body is found, or None for requests without a body. Requests with an
+/// ambiguous framing (`RequestHead::ambiguity`) are refused before their body is looked at.
+fn request_body_framing(head: &RequestHead) -> Option<intercept::BodyFraming> {
+    if head.header("transfer-encoding").is_some() {
+        return Some(intercept::BodyFraming::Chunked);
+    }
+    head.header_str("content-length")
+        .and_then(|length| length.trim().parse().ok())
+        .filter(|&length| length > 0)
+        .map(intercept::BodyFraming::Length)
+}
 
-        // If buffer is full and we haven't found headers end, resize it
-        if total_read >= buffer.len() {
-            buffer.resize(buffer.len() * 2, 0);
+/// Read the rest of a request body after the bytes already received. Returns all bytes received,
+/// or None when the body exceeds `intercept::MAX_FILTERED_BODY` or is malformed.
+async fn read_request_body<S: AsyncRead + Unpin>(stream: &mut S, mut data: Vec<u8>, framing: intercept::BodyFraming) -> std::io::Result<Option<Vec<u8>>> {
+    let mut chunk = vec![0u8; 16384];
+    let mut eof = false;
+    loop {
+        match framing.decode(&data, eof) {
+            intercept::BodyStatus::Complete(..) => return Ok(Some(data)),
+            intercept::BodyStatus::Invalid => return Ok(None),
+            intercept::BodyStatus::Incomplete if data.len() > intercept::MAX_FILTERED_BODY => return Ok(None),
+            intercept::BodyStatus::Incomplete => {}
         }
+        let n = stream.read(&mut chunk).await?;
+        eof = n == 0;
+        data.extend_from_slice(&chunk[..n]);
     }
 }
 
@@ -145,151 +817,2078 @@ fn find_header_end(data: &[u8]) -> Option<usize> {
     None
 }
 
-/// Rewrite the HTTP request path by stripping the matched route prefix
-/// Returns the modified request data
-fn rewrite_request_path(request_data: &[u8], _original_path: &str, prefix_to_strip: &str) -> Vec<u8> {
-    // If no prefix to strip or prefix is empty, return original data
-    if prefix_to_strip.is_empty() {
-        return request_data.to_vec();
-    }
+/// The bytes forwarded to the backend: an optional replacement for the start of the request head,
+/// then the buffered bytes from the client starting at `rest`
+struct ForwardedRequest<'a> {
+    head: Option<Vec<u8>>,
+    rest: &'a [u8],
+    /// The rewritten request target (path and query), if it changed
+    target: Option<String>,
+}
+
+impl<'a> ForwardedRequest<'a> {
+    /// Apply the route's path, Host header and header rule rewrites to a request, and drop the client headers
+    /// `;allow-headers` does not list. Routes with body filters ask for uncompressed responses, so
+    /// `Accept-Encoding` is dropped for them. `added` headers (`traceparent`,
+    /// `digest`) replace the client's of the same name.
+    /// Only the replaced part of the head is built anew; the buffered bytes are referenced, never copied.
+    /// `original_line` is the request line as the client sent it, when its path was normalized.
+    fn new(request_data: &'a [u8], head_len: usize, original_line: Option<&[u8]>, request: &RequestHead, route: &RouteMatch, backend: &Backend, added: &[(&str, String)]) -> Self {
+        // `;verbatim` routes get the request as the client sent it, without a `traceparent` either
+        if route.options.verbatim {
+            let Some(line) = original_line else {
+                return ForwardedRequest { head: None, rest: request_data, target: None };
+            };
+            let line_end = request_data.iter().position(|&b| b == b'\r' || b == b'\n').unwrap_or(head_len);
+            return ForwardedRequest { head: Some(line.to_vec()), rest: &request_data[line_end..], target: None };
+        }
+        let target = (route.rewrite.is_some() || !route.rules.is_empty())
+            .then(|| rewrite_target(&request.target(), route))
+            .flatten();
+        let host = route.host.and_then(|host| host.value(backend));
+        let drop_encoding = !route.body_filters.is_empty();
+        let edits = route.header_rules.map(|rules| &rules.request).filter(|edits| !edits.is_empty());
+        let allowed = route.options.allow_headers.as_deref();
+        let edit_headers = host.is_some() || drop_encoding || edits.is_some() || allowed.is_some() || !added.is_empty();
+        if target.is_none() && !edit_headers {
+            return ForwardedRequest { head: None, rest: request_data, target: None };
+        }
 
-    // Parse the request line to find where the path is
-    let request_str = String::from_utf8_lossy(request_data);
-    let lines: Vec<&str> = request_str.lines().collect();
+        // The request line ends at the first line break
+        let first_line_end = request_data.iter().position(|&b| b == b'\r' || b == b'\n').unwrap_or(head_len);
+        let mut new_head = match &target {
+            Some(target) => format!("{} {} HTTP/1.{}", request.method, target, request.version).into_bytes(),
+            None => request_data[..first_line_end].to_vec(),
+        };
+        if !edit_headers {
+            // The new request line replaces the original one; the line ending and everything after it is kept
+            return ForwardedRequest { head: Some(new_head), rest: &request_data[first_line_end..], target };
+        }
 
-    if lines.is_empty() {
-        return request_data.to_vec();
+        // Copy the header lines, replacing the first Host header (and dropping any others) and applying header rules
+        let mut host_written = false;
+        // The first piece ends the request line; a blank line ends the head
+        for (idx, line) in request_data[first_line_end..head_len].split_inclusive(|&b| b == b'\n').enumerate() {
+            let is_host = host.is_some() && intercept::is_header(line, "host");
+            let is_end = idx > 0 && (line == b"\r\n" || line == b"\n");
+            let is_dropped = is_host
+                || (drop_encoding && intercept::is_header(line, "accept-encoding"))
+                || (idx > 0 && added.iter().any(|(name, _)| intercept::is_header(line, name)))
+                || edits.is_some_and(|edits| idx > 0 && edits.drops(line))
+                || allowed.is_some_and(|allowed| idx > 0 && !is_end && !headers::allowed(line, allowed));
+            if let Some(host) = host.filter(|_| (is_host || is_end) && !host_written) {
+                new_head.extend_from_slice(format!("Host: {}\r\n", host).as_bytes());
+                host_written = true;
+            }
+            if let Some(edits) = edits.filter(|_| is_end) {
+                edits.append_to(&mut new_head);
+            }
+            for (name, value) in added.iter().filter(|_| is_end) {
+                new_head.extend_from_slice(format!("{}: {}\r\n", name, value).as_bytes());
+            }
+            if !is_dropped {
+                new_head.extend_from_slice(line);
+            }
+        }
+        ForwardedRequest { head: Some(new_head), rest: &request_data[head_len..], target }
     }
 
-    // Parse the first line: "METHOD /path HTTP/version"
-    let first_line = lines[0];
-    let parts: Vec<&str> = first_line.split_whitespace().collect();
+    fn len(&self) -> usize {
+        self.head.as_ref().map_or(0, Vec::len) + self.rest.len()
+    }
 
-    if parts.len() != 3 {
-        return request_data.to_vec();
+    fn to_vec(&self) -> Vec<u8> {
+        [self.head.as_deref().unwrap_or_default(), self.rest].concat()
     }
 
-    let method = parts[0];
-    let path = parts[1];
-    let version = parts[2];
+    /// Write the request with a single vectored write where possible, without concatenating the parts
+    async fn write_to<W: AsyncWrite + Unpin>(&self, stream: &mut W) -> std::io::Result<()> {
+        let Some(head) = &self.head else {
+            return stream.write_all(self.rest).await;
+        };
 
-    // Strip the prefix from the path
-    let new_path = if path.starts_with(prefix_to_strip) {
-        let stripped = &path[prefix_to_strip.len()..];
-        // Ensure the path starts with / (if it's empty, use /)
-        if stripped.is_empty() || !stripped.starts_with('/') {
-            format!("/{}", stripped)
-        } else {
-            stripped.to_string()
+        let (mut head, mut rest) = (head.as_slice(), self.rest);
+        while !head.is_empty() {
+            let n = stream.write_vectored(&[IoSlice::new(head), IoSlice::new(rest)]).await?;
+            if n == 0 {
+                return Err(std::io::ErrorKind::WriteZero.into());
+            }
+            if n < head.len() {
+                head = &head[n..];
+            } else {
+                rest = &rest[n - head.len()..];
+                head = &[];
+            }
         }
-    } else {
-        path.to_string()
-    };
+        stream.write_all(rest).await
+    }
+}
 
-    // Reconstruct the request
-    let new_first_line = format!("{} {} {}", method, new_path, version);
+/// The request target after the route's prefix rewrite and regex rules, or None when unchanged
+fn rewrite_target(target: &str, route: &RouteMatch) -> Option<String> {
+    // Targets not starting with the matched prefix (e.g. absolute-form URLs) keep their prefix
+    let mut new_target = match route.rewrite {
+        Some(rewrite) if target.starts_with(route.prefix) => Cow::Owned(rewrite.apply(target, route.prefix)),
+        _ => Cow::Borrowed(target),
+    };
+    // Regex rules see the path without the query string
+    for rule in route.rules {
+        let (path, query) = new_target.split_once('?').map_or((&*new_target, None), |(p, q)| (p, Some(q)));
+        if let Cow::Owned(rewritten) = rule.apply(path) {
+            new_target = Cow::Owned(match query {
+                Some(query) => format!("{}?{}", rewritten, query),
+                None => rewritten,
+            });
+        }
+    }
+    match new_target {
+        Cow::Owned(target) => Some(target),
+        Cow::Borrowed(_) => None,
+    }
+}
 
-    // Find where the first line ends in the original request
-    if let Some(first_line_end) = request_data.iter().position(|&b| b == b'\r' || b == b'\n') {
-        let mut new_request = Vec::new();
-        new_request.extend_from_slice(new_first_line.as_bytes());
-        new_request.extend_from_slice(&request_data[first_line_end..]);
-        new_request
-    } else {
-        request_data.to_vec()
+/// Routes with `;scan` need a scanner to pass their uploads
+fn check_scanner(config: &RouteConfig, scanner: Option<&icap::Scanner>) -> Result<(), String> {
+    let scanned = config.scanned_routes();
+    if scanner.is_none() && !scanned.is_empty() {
+        return Err(format!("The scan option needs an --icap scanner, in routes: {}", scanned.join(", ")));
     }
+    Ok(())
 }
 
-#[tokio::main]
-async fn main() -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
-    let args = Args::parse();
+/// Routes with `;blocklist` need a blocklist to check their clients against
+fn check_blocklists(config: &RouteConfig, blocklists: bool) -> Result<(), String> {
+    let blocklisted = config.blocklisted_routes();
+    if !blocklists && !blocklisted.is_empty() {
+        return Err(format!("The blocklist option needs a --blocklist, in routes: {}", blocklisted.join(", ")));
+    }
+    Ok(())
+}
 
-    // Parse the routing configuration
-    let config = RouteConfig::new(args.default_backend.clone(), args.routes, args.rewrite)?;
+/// Routes with ASN rules or per-ASN limits need a database to look clients up in
+fn check_asn_db(config: &RouteConfig, asn_db: bool) -> Result<(), String> {
+    let routes = config.asn_routes();
+    if !asn_db && !routes.is_empty() {
+        return Err(format!("The deny-asn, allow-asn and rate-limit=...:asn options need an --asn-db, in routes: {}", routes.join(", ")));
+    }
+    Ok(())
+}
 
-    let addr = args.listen_address.parse::<SocketAddr>()?;
-    let listener = TcpListener::bind(addr).await?;
+/// Routes with per-user limits need a source of user identities
+fn check_user_identity(config: &RouteConfig, user_identity: bool) -> Result<(), String> {
+    let routes = config.user_routes();
+    if !user_identity && !routes.is_empty() {
+        return Err(format!("The rate-limit=...:user option needs a --user-identity, in routes: {}", routes.join(", ")));
+    }
+    Ok(())
+}
 
-    println!("Reverse proxy listening on http://{}", addr);
-    println!("Default backend: http://{}", config.default_backend);
-    println!("Path rewriting: {}", if config.rewrite_paths { "enabled" } else { "disabled" });
+/// Quotas are for the virtual hosts of the route table
+fn check_host_quotas(config: &RouteConfig, quotas: &quota::Quotas) -> Result<(), String> {
+    let unknown: Vec<&str> = quotas.iter().map(|host| &*host.name).filter(|name| !config.virtual_hosts.contains_key(*name)).collect();
+    if !unknown.is_empty() {
+        return Err(format!("--host-quota names hosts without routes of their own: {}", unknown.join(", ")));
+    }
+    Ok(())
+}
 
-    if !config.routes.is_empty() {
-        println!("\nPath-based routes:");
-        for (path, backend) in &config.routes {
-            println!("  {} -> http://{}", path, backend);
-        }
+/// `;listener=` routes name listeners of the command line
+fn check_listeners(config: &RouteConfig, listeners: &[listener::Listener]) -> Result<(), String> {
+    let unknown: Vec<String> = config.listener_names().into_iter()
+        .filter(|(name, _)| !listeners.iter().any(|listener| listener.name == *name))
+        .map(|(name, route)| format!("{} (route {})", name, route))
+        .collect();
+    if !unknown.is_empty() {
+        return Err(format!("Routes name listeners that are not defined: {}", unknown.join(", ")));
     }
+    Ok(())
+}
 
-    let config = std::sync::Arc::new(config);
+/// An autonomous system for log notes: `AS16509 (AMAZON-02)`
+fn describe_as(system: &asn::AutonomousSystem) -> String {
+    match &system.organization {
+        Some(organization) => format!("AS{} ({})", system.number, organization),
+        None => format!("AS{}", system.number),
+    }
+}
 
-    loop {
-        let (mut client_stream, client_addr) = listener.accept().await?;
-        let config = config.clone();
+/// Suffix for a route in the startup listing
+fn tls_note(tls: Option<routing::BackendTls>) -> &'static str {
+    match tls {
+        None => "",
+        Some(routing::BackendTls::Verified) => " (TLS)",
+        Some(routing::BackendTls::Legacy) => " (LEGACY TLS)",
+    }
+}
 
-        tokio::spawn(async move {
-            // Parse the HTTP request to determine the path
-            let (path, request_data) = match parse_http_request(&mut client_stream).await {
-                Ok(result) => result,
-                Err(e) => {
-                    eprintln!("Failed to parse request from {}: {}", client_addr, e);
-                    return;
-                }
-            };
+/// Stream bytes in both directions until both sides are done, like `copy_bidirectional`.
+/// The first response is rewritten when `rewrite` is given, and kept alive with the route's heartbeat;
+/// what goes by is recorded into a sampled request's `recording`, and what the client sends is copied
+/// to the `shadow` of a mirrored connection.
+/// A client closing the connection before that response is complete aborts the transfer, as
+/// `progress` tells afterwards. With `following`, the requests after the first are passed on only
+/// while they may go to this backend connection; the transfer ends at the first that may not,
+/// leaving the client connection open for it.
+/// Returns the bytes sent upstream and downstream, and when the first response byte arrived.
+#[allow(clippy::too_many_arguments)]
+async fn stream_bidirectional<S: AsyncRead + AsyncWrite + Unpin, B: AsyncRead + AsyncWrite + Unpin>(
+    client: &mut S,
+    backend: &mut B,
+    rewrite: Option<&intercept::ResponseRewrite<'_>>,
+    request: &RequestHead<'_>,
+    heartbeat: Option<&heartbeat::Heartbeat>,
+    progress: &abort::ResponseProgress,
+    recording: Option<&mut capture::Recording>,
+    shadow: Option<&mut mirror::Shadow>,
+    following: Option<keepalive::Following<'_>>,
+) -> std::io::Result<(u64, u64, Option<Instant>, Option<u16>)> {
+    let exchanges = following.as_ref().map(keepalive::Following::exchanges);
+    let mut client = abort::WatchedClient::new(client, progress, request.method == "HEAD", exchanges);
+    let (client_read, mut client_write) = tokio::io::split(&mut client);
+    let (backend_read, mut backend_write) = tokio::io::split(backend);
+    let (request_capture, response_capture) = recording.map(|recording| (&mut recording.request, &mut recording.response)).unzip();
+    let client_read = keepalive::Gate::new(client_read, following);
+    let mut client_read = mirror::Mirrored::new(capture::Tee::new(client_read, request_capture), shadow);
+    let mut backend_read = heartbeat::HeartbeatReader::new(capture::Tee::new(backend_read, response_capture), heartbeat, request.version == 1, request.method == "HEAD");
 
-            // Determine which backend to use based on the path and get the matched prefix
-            let (backend_addr, matched_prefix) = config.get_backend_and_prefix(&path);
+    let upstream = async {
+        let sent = tokio::io::copy(&mut client_read, &mut backend_write).await?;
+        if progress.is_aborted() {
+            return Err(std::io::Error::new(std::io::ErrorKind::ConnectionAborted, "client closed the connection"));
+        }
+        // A request handed back ends what the backend is sent, not the backend connection
+        if !exchanges.is_some_and(keepalive::Exchanges::hands_back) {
+            backend_write.shutdown().await?;
+        }
+        Ok::<_, std::io::Error>(sent)
+    };
 
-            // Rewrite the path if enabled
-            let final_request_data = if config.rewrite_paths {
-                let rewritten = rewrite_request_path(&request_data, &path, matched_prefix);
+    let downstream = async {
+        let (first, first_byte_at, replaced, status) = forward_response_head(&mut backend_read, &mut client_write, rewrite).await?;
+        // The rest of a response replaced by an error page is discarded; the page closes the connection
+        let rest = match exchanges {
+            Some(exchanges) if replaced => {
+                exchanges.close();
+                0
+            }
+            _ if replaced => 0,
+            Some(exchanges) => keepalive::copy_responses(&mut backend_read, &mut client_write, exchanges).await?,
+            None => tokio::io::copy(&mut backend_read, &mut client_write).await?,
+        };
+        progress.backend_done();
+        // The client connection stays open for a request handed back
+        if !exchanges.is_some_and(keepalive::Exchanges::continues) {
+            client_write.shutdown().await?;
+        }
+        Ok::<_, std::io::Error>((first + rest, first_byte_at, status))
+    };
 
-                // Extract the new path for logging
-                let new_path = if !matched_prefix.is_empty() && path.starts_with(matched_prefix) {
-                    let stripped = &path[matched_prefix.len()..];
-                    if stripped.is_empty() || !stripped.starts_with('/') {
-                        format!("/{}", stripped)
-                    } else {
-                        stripped.to_string()
-                    }
-                } else {
-                    path.clone()
-                };
+    let (sent, (received, first_byte_at, status)) = tokio::try_join!(upstream, downstream)?;
+    Ok((sent, received, first_byte_at, status))
+}
 
-                println!("[{}] {} -> {} (rewritten to {})", client_addr, path, backend_addr, new_path);
-                rewritten
+/// Forward the start of the response. With a rewrite, informational responses are forwarded as they
+/// arrive, and the first final response head is buffered and rewritten, and so is its body when the route filters it;
+/// otherwise only the first chunk is forwarded.
+/// Returns the bytes written, when the first response byte arrived, and whether the response was
+/// replaced by an error page.
+async fn forward_response_head<R, W>(
+    backend: &mut R,
+    client: &mut W,
+    rewrite: Option<&intercept::ResponseRewrite<'_>>,
+) -> std::io::Result<(u64, Option<Instant>, bool, Option<u16>)>
+where
+    R: tokio::io::AsyncRead + Unpin,
+    W: tokio::io::AsyncWrite + Unpin,
+{
+    let mut buffer = vec![0u8; 8192];
+    let mut len = backend.read(&mut buffer).await?;
+    let first_byte_at = (len > 0).then(Instant::now);
+    let Some(rewrite) = rewrite else {
+        // Forwarded as it arrives; the status is picked up on the way, from the start of the stream
+        let (mut written, mut seen) = (0, Vec::new());
+        loop {
+            client.write_all(&buffer[..len]).await?;
+            written += len as u64;
+            let scan = if seen.is_empty() {
+                response_status(&buffer[..len])
             } else {
-                println!("[{}] {} -> {}", client_addr, path, backend_addr);
-                request_data
+                seen.extend_from_slice(&buffer[..len]);
+                response_status(&seen)
             };
-
-            // Connect to the backend server
-            let mut backend_stream = match TcpStream::connect(backend_addr).await {
-                Ok(s) => s,
-                Err(e) => {
-                    eprintln!("Failed to connect to backend {}: {}", backend_addr, e);
-
-                    // Send 502 Bad Gateway response
-                    let response = b"HTTP/1.1 502 Bad Gateway\r\nContent-Length: 15\r\n\r\nBad Gateway\r\n";
-                    let _ = client_stream.write_all(response).await;
-                    return;
+            match scan {
+                StatusScan::Incomplete if len > 0 && seen.len() + len <= intercept::MAX_RESPONSE_HEAD => {
+                    if seen.is_empty() {
+                        seen.extend_from_slice(&buffer[..len]);
+                    }
                 }
-            };
-
-            // Forward the (possibly rewritten) request to the backend
-            if let Err(e) = backend_stream.write_all(&final_request_data).await {
-                eprintln!("Failed to forward request to backend: {}", e);
-                return;
+                StatusScan::Final(status) => return Ok((written, first_byte_at, false, Some(status))),
+                _ => return Ok((written, first_byte_at, false, None)),
             }
+            len = backend.read(&mut buffer).await?;
+        }
+    };
 
-            // Now do bidirectional streaming between client and backend
-            if let Err(e) = tokio::io::copy_bidirectional(&mut client_stream, &mut backend_stream).await {
-                // Connection errors are common and expected when clients/servers close connections
-                if e.kind() != std::io::ErrorKind::UnexpectedEof
-                    && e.kind() != std::io::ErrorKind::ConnectionReset {
-                    eprintln!("Proxy forwarding error: {}", e);
+    // Start of the head being looked at, after the informational responses already forwarded
+    let mut start = 0;
+    while len > 0 {
+        if let Some(end) = find_header_end(&buffer[start..len]).map(|end| start + end) {
+            // After `101 Switching Protocols` the connection carries another protocol (WebSocket, h2c),
+            // whose bytes are relayed as they come: some servers speak first
+            if intercept::status(&buffer[start..end]) == Some(101) {
+                client.write_all(&buffer[start..len]).await?;
+                return Ok((len as u64, first_byte_at, false, Some(101)));
+            }
+            if intercept::is_informational(&buffer[start..end]) {
+                client.write_all(&buffer[start..end]).await?;
+                start = end;
+                continue;
+            }
+            if let Some(page) = rewrite.error_page(&buffer[start..end]) {
+                client.write_all(&page).await?;
+                return Ok(((start + page.len()) as u64, first_byte_at, true, intercept::status(&page)));
+            }
+            let mut corrected = None;
+            if let Some(framing) = rewrite.type_check_framing(&buffer[start..end]) {
+                let mut eof = false;
+                let leading = loop {
+                    if let Some(leading) = framing.leading(&buffer[end..len], eof) {
+                        break leading.into_owned();
+                    }
+                    if len == buffer.len() {
+                        buffer.resize(buffer.len() * 2, 0);
+                    }
+                    let n = backend.read(&mut buffer[len..]).await?;
+                    eof = n == 0;
+                    len += n;
+                };
+                match rewrite.check_type(&buffer[start..end], &leading) {
+                    Some(intercept::TypeVerdict::Blocked(page)) => {
+                        client.write_all(&page).await?;
+                        return Ok(((start + page.len()) as u64, first_byte_at, true, intercept::status(&page)));
+                    }
+                    Some(intercept::TypeVerdict::Corrected(head)) => corrected = Some(head),
+                    None => {}
                 }
             }
-        });
+            let original = corrected.as_deref().unwrap_or(&buffer[start..end]);
+            let new_head = rewrite.apply(original);
+            let head = new_head.as_deref().unwrap_or(original);
+            let status = intercept::status(head);
+            if let Some(framing) = rewrite.body_framing(head) {
+                let head = head.to_vec();
+                buffer.truncate(len);
+                let body = buffer.split_off(end);
+                let written = forward_filtered_body(backend, client, rewrite, &head, body, framing).await?;
+                return Ok((start as u64 + written, first_byte_at, false, status));
+            }
+            client.write_all(head).await?;
+            client.write_all(&buffer[end..len]).await?;
+            if status == Some(204) {
+                rewrite.store(head, &[]);
+            }
+            return Ok(((start + head.len() + len - end) as u64, first_byte_at, false, status));
+        }
+
+        if len == buffer.len() {
+            if buffer.len() >= intercept::MAX_RESPONSE_HEAD {
+                break;
+            }
+            buffer.resize(buffer.len() * 2, 0);
+        }
+        let n = backend.read(&mut buffer[len..]).await?;
+        if n == 0 {
+            break;
+        }
+        len += n;
+    }
+
+    // No complete head arrived: forward what did unchanged
+    client.write_all(&buffer[start..len]).await?;
+    Ok((len as u64, first_byte_at, false, None))
+}
+
+/// Read a backend's response up to the status of its first final response, to decide on a retry
+/// before anything reaches the client. Returns the bytes read, and the status if one arrived.
+async fn read_response_status<B: AsyncRead + Unpin>(backend: &mut B) -> (Vec<u8>, std::io::Result<Option<u16>>) {
+    let mut data = Vec::new();
+    let mut buffer = [0u8; 8192];
+    loop {
+        match response_status(&data) {
+            StatusScan::Final(status) => return (data, Ok(Some(status))),
+            StatusScan::Incomplete if data.len() <= intercept::MAX_RESPONSE_HEAD => {}
+            _ => return (data, Ok(None)),
+        }
+        match backend.read(&mut buffer).await {
+            Ok(0) => return (data, Ok(None)),
+            Ok(n) => data.extend_from_slice(&buffer[..n]),
+            Err(e) => return (data, Err(e)),
+        }
+    }
+}
+
+/// What the start of a backend's response stream tells about its status
+enum StatusScan {
+    Final(u16),
+    /// More bytes are needed
+    Incomplete,
+    /// Not an HTTP/1 response
+    Unknown,
+}
+
+/// The status of the first final response in `data`, after any informational ones (`101 Switching
+/// Protocols` is final: the connection carries another protocol after it)
+fn response_status(data: &[u8]) -> StatusScan {
+    let mut start = 0;
+    loop {
+        let rest = &data[start..];
+        if rest.len() < 12 {
+            let could_be_http = b"HTTP/1.".starts_with(&rest[..rest.len().min(7)]);
+            return if could_be_http { StatusScan::Incomplete } else { StatusScan::Unknown };
+        }
+        let Some(status) = rest.starts_with(b"HTTP/1.").then(|| intercept::status(rest)).flatten() else {
+            return StatusScan::Unknown;
+        };
+        if !(100..200).contains(&status) || status == 101 {
+            return StatusScan::Final(status);
+        }
+        match find_header_end(rest) {
+            Some(end) => start += end,
+            None => return StatusScan::Incomplete,
+        }
+    }
+}
+
+/// Buffer a response body, apply the route's body filters and forward it with a new `Content-Length` (and `Digest`).
+/// `buffer` holds the bytes received after the head. Bodies that exceed `MAX_FILTERED_BODY` or cannot
+/// be decoded are forwarded unchanged. Returns the bytes written.
+async fn forward_filtered_body<R, W>(
+    backend: &mut R,
+    client: &mut W,
+    rewrite: &intercept::ResponseRewrite<'_>,
+    head: &[u8],
+    mut buffer: Vec<u8>,
+    framing: intercept::BodyFraming,
+) -> std::io::Result<u64>
+where
+    R: tokio::io::AsyncRead + Unpin,
+    W: tokio::io::AsyncWrite + Unpin,
+{
+    let mut chunk = vec![0u8; 16384];
+    let mut eof = false;
+    loop {
+        match framing.decode(&buffer, eof) {
+            intercept::BodyStatus::Complete(body, consumed) => {
+                let (head, body) = rewrite.rewrite_body(head, &body);
+                client.write_all(&head).await?;
+                client.write_all(&body).await?;
+                rewrite.store(&head, &body);
+                // Anything after the body belongs to later responses, which are streamed as they are
+                client.write_all(&buffer[consumed..]).await?;
+                return Ok((head.len() + body.len() + buffer.len() - consumed) as u64);
+            }
+            intercept::BodyStatus::Invalid => break,
+            intercept::BodyStatus::Incomplete if buffer.len() > intercept::MAX_FILTERED_BODY => break,
+            intercept::BodyStatus::Incomplete => {}
+        }
+        let n = backend.read(&mut chunk).await?;
+        eof = n == 0;
+        buffer.extend_from_slice(&chunk[..n]);
+    }
+
+    client.write_all(head).await?;
+    client.write_all(&buffer).await?;
+    Ok((head.len() + buffer.len()) as u64)
+}
+
+/// State shared by all client connections
+struct Proxy {
+    config: routing::SharedConfig,
+    metrics: std::sync::Arc<metrics::Metrics>,
+    alerter: std::sync::Arc<alerts::Alerter>,
+    not_found: response::LocalResponse,
+    redirect_map: Option<redirects::SharedRedirectMap>,
+    well_known: wellknown::WellKnownFiles,
+    /// Response to requests for PSK-restricted routes from clients without an allowed identity
+    forbidden: response::LocalResponse,
+    /// Response when a backend cannot be reached, unless an error page replaces it
+    bad_gateway: response::LocalResponse,
+    /// Response to requests for drained backends, unless an error page replaces it
+    unavailable: response::LocalResponse,
+    /// Response to uploads on `;scan` and `;digest` routes too large to inspect
+    too_large: response::LocalResponse,
+    /// Response to uploads failing their checksums, or unreadable
+    bad_request: response::LocalResponse,
+    scanner: Option<icap::Scanner>,
+    /// Clients refused on `;blocklist` routes
+    blocklists: std::sync::Arc<blocklist::Blocklists>,
+    /// Autonomous systems of client addresses, for ASN rules, limits and the access log
+    asn_db: Option<asn::AsnDatabase>,
+    /// Request counts of `;rate-limit` routes
+    rate_limiter: ratelimit::RateLimiter,
+    /// Where the users of `:user` limits are identified from
+    user_identity: Option<plans::IdentitySource>,
+    /// Limit tiers of the users listed in `--rate-limit-plans`
+    rate_limit_plans: Option<plans::SharedPlans>,
+    /// Request schedules of backends on `;backend-rate` routes
+    shaper: shaper::Shaper,
+    /// Limits of virtual hosts (`--host-quota`)
+    quotas: quota::Quotas,
+    /// Retries left to `;retry` routes
+    retry_budget: retry::RetryBudget,
+    resolver: std::sync::Arc<resolver::Resolver>,
+    /// Where listed clients of `;blocklist=tarpit` routes are held
+    tarpit: tarpit::Tarpit,
+    /// Answers to CORS preflights on `;cache-preflight` routes
+    preflight_cache: preflight::PreflightCache,
+    /// Responses replayed for repeated `Idempotency-Key`s on `;idempotency` routes
+    idempotency_cache: idempotency::IdempotencyCache,
+    /// Responses of `;force-cache` routes
+    response_cache: cache::ResponseCache,
+    /// Exporter for request traces (`--otlp-endpoint`)
+    tracer: Option<otel::Tracer>,
+    access_log: accesslog::AccessLog,
+    /// Scrubs what the access log and traces record of requests (`--scrub`)
+    scrubber: Option<std::sync::Arc<scrub::Scrubber>>,
+    /// Sampled requests and responses written to the spool (`--capture`)
+    capture: Option<capture::Capture>,
+    error_pages: Option<errorpages::ErrorPages>,
+    /// How pools of several backends choose one (`--lb`)
+    lb: pool::Strategy,
+    /// Zone preference and latency probes for routes with zones
+    regions: std::sync::Arc<region::Regions>,
+    /// Backends taken out of rotation by `--health-check`
+    health: Option<std::sync::Arc<health::HealthChecks>>,
+    /// Backends ejected for failing requests
+    outliers: Option<std::sync::Arc<outlier::OutlierDetector>>,
+    /// Faults injected into routes' requests through the admin API
+    faults: std::sync::Arc<fault::Faults>,
+    /// Connections through blue/green slots, closed after a switch's drain period
+    cutovers: std::sync::Arc<slot::Cutovers>,
+    /// Connect to backends from client addresses (`--transparent`)
+    transparent: bool,
+    /// Largest request head accepted (`--max-header-size`)
+    max_header_size: usize,
+    /// What to do with TLS and other non-HTTP connections to the main listener (`--sniff`)
+    sniffer: Option<sniff::Sniffer>,
+    /// TLS-PSK handshakes for `--psk-listen` and `--sniff tls=terminate`
+    psk: Option<std::sync::Arc<PskAcceptor>>,
+}
+
+impl Proxy {
+    /// Serve a connection to a plaintext listener, sniffing its protocol first if `--sniff` is set
+    async fn accept(self: &std::sync::Arc<Self>, tcp: TcpStream, client_addr: SocketAddr, listener: &str) {
+        let Some(sniffer) = &self.sniffer else {
+            return self.serve_plaintext(tcp, client_addr, listener).await;
+        };
+        let protocol = match sniff::detect(&tcp).await {
+            Ok(protocol) => protocol,
+            Err(e) => {
+                logging::error(format!("Failed to read request from {}: {}", client_addr, e));
+                return;
+            }
+        };
+        match sniffer.action(protocol) {
+            None => self.serve_plaintext(tcp, client_addr, listener).await,
+            Some(sniff::SniffAction::Reject) => println!("[{}] {} connection rejected", client_addr, protocol),
+            Some(sniff::SniffAction::Terminate) => self.handle_psk_connection(tcp, client_addr, listener).await,
+            Some(sniff::SniffAction::Forward { name, addr }) => {
+                println!("[{}] {} connection -> {} (passthrough)", client_addr, protocol, name);
+                if let Err(e) = self.relay(tcp, *addr, client_addr).await {
+                    logging::error(format!("Passthrough to {} failed: {}", name, e));
+                }
+            }
+        }
+    }
+
+    /// Serve the HTTP of a plaintext connection, switching it to HTTP/2 when its first request asks
+    /// for an h2c upgrade
+    async fn serve_plaintext(self: &std::sync::Arc<Self>, mut tcp: TcpStream, client_addr: SocketAddr, listener: &str) {
+        let (received, head_len) = match read_request_head(&mut tcp, Vec::new(), self.max_header_size).await {
+            Ok(Some(result)) => result,
+            Ok(None) => {
+                logging::warning(format!("Request head from {} exceeds --max-header-size of {} bytes", client_addr, self.max_header_size));
+                let _ = tcp.write_all(HEADER_TOO_LARGE).await;
+                return;
+            }
+            Err(e) => {
+                logging::error(format!("Failed to read request from {}: {}", client_addr, e));
+                return;
+            }
+        };
+        let mut header_storage = [httparse::EMPTY_HEADER; request::MAX_HEADERS];
+        let upgrade = RequestHead::parse(&received[..head_len], &mut header_storage).ok().filter(h2c::is_upgrade);
+        let Some(head) = upgrade else {
+            return self.handle_connection_from(tcp, client_addr, None, listener, received).await;
+        };
+        let ahead = match h2c::switch(&mut tcp, &head, received[head_len..].to_vec()).await {
+            Ok(ahead) => ahead,
+            Err(e) => {
+                logging::error(format!("h2c upgrade from {} failed: {}", client_addr, e));
+                return;
+            }
+        };
+        let mut connection = match h2::server::Builder::new()
+            .max_concurrent_streams(h2c::MAX_CONCURRENT_STREAMS)
+            .handshake::<_, bytes::Bytes>(retry::ReadAhead::new(tcp, ahead))
+            .await
+        {
+            Ok(connection) => connection,
+            Err(e) => {
+                logging::error(format!("HTTP/2 handshake with {} failed: {}", client_addr, e));
+                return;
+            }
+        };
+        let listener: std::sync::Arc<str> = listener.into();
+        while let Some(accepted) = connection.accept().await {
+            let (request, respond) = match accepted {
+                Ok(accepted) => accepted,
+                Err(e) => {
+                    if !e.is_go_away() && !e.is_io() {
+                        logging::error(format!("HTTP/2 connection from {} failed: {}", client_addr, e));
+                    }
+                    return;
+                }
+            };
+            let (proxy, listener) = (self.clone(), listener.clone());
+            tokio::spawn(async move {
+                proxy.serve_h2_stream(request, respond, client_addr, &listener).await;
+            });
+        }
+    }
+
+    /// Serve a stream of an HTTP/2 connection as the HTTP/1.1 request of a connection of its own
+    async fn serve_h2_stream(&self, request: http::Request<h2::RecvStream>, mut respond: h2::server::SendResponse<bytes::Bytes>, client_addr: SocketAddr, listener: &str) {
+        let (parts, body) = request.into_parts();
+        let Some((head, chunked)) = h2c::request_head(&parts, !body.is_end_stream()) else {
+            let response = http::Response::builder().status(400).body(()).expect("valid response");
+            let _ = respond.send_response(response, true);
+            return;
+        };
+        let (connection, handled) = tokio::io::duplex(h2c::STREAM_BUFFER);
+        let exchange = h2c::exchange(connection, head, chunked, body, respond, parts.method == http::Method::HEAD);
+        let (_, result) = tokio::join!(self.handle_connection(handled, client_addr, None, listener), exchange);
+        if let Err(e) = result {
+            if !e.is::<h2::Error>() {
+                logging::error(format!("HTTP/2 stream from {} failed: {}", client_addr, e));
+            }
+        }
+    }
+
+    /// Complete a TLS-PSK handshake and serve the HTTP inside
+    async fn handle_psk_connection(&self, tcp: TcpStream, client_addr: SocketAddr, listener: &str) {
+        let Some(acceptor) = &self.psk else {
+            return;
+        };
+        match acceptor.accept(tcp).await {
+            Ok((stream, identity)) => self.handle_connection(stream, client_addr, Some(identity), listener).await,
+            Err(e) => logging::error(format!("TLS-PSK handshake with {} failed: {}", client_addr, e)),
+        }
+    }
+
+    /// Complete a TLS handshake with a `;cert` listener's certificate and serve the HTTP inside
+    async fn handle_tls_connection(&self, acceptor: &tokio_rustls::TlsAcceptor, tcp: TcpStream, client_addr: SocketAddr, listener: &str) {
+        match acceptor.accept(tcp).await {
+            Ok(stream) => self.handle_connection(stream, client_addr, None, listener).await,
+            Err(e) => logging::error(format!("TLS handshake with {} failed: {}", client_addr, e)),
+        }
+    }
+
+    /// Relay a connection byte for byte to a backend, whatever its protocol
+    async fn relay(&self, mut tcp: TcpStream, backend: SocketAddr, client_addr: SocketAddr) -> std::io::Result<()> {
+        let mut backend_stream = if self.transparent {
+            transparent::connect(backend, client_addr).await?
+        } else {
+            TcpStream::connect(backend).await?
+        };
+        tokio::io::copy_bidirectional(&mut tcp, &mut backend_stream).await?;
+        Ok(())
+    }
+
+    /// Read the whole body of a request on a route that inspects uploads (`;scan`, `;digest`) or may
+    /// send them again (`;retry`). Returns the body bytes received and the decoded body (None for
+    /// requests without a body), or the status and reason refusing it.
+    async fn read_upload<S>(&self, client: &mut S, head: &RequestHead<'_>, received: &[u8]) -> Result<Option<(Vec<u8>, Vec<u8>)>, (u16, String)>
+    where
+        S: AsyncRead + AsyncWrite + Unpin,
+    {
+        let Some(framing) = request_body_framing(head) else {
+            return Ok(None);
+        };
+        if let intercept::BodyFraming::Length(length) = framing {
+            if length > intercept::MAX_FILTERED_BODY {
+                return Err((413, format!("{} byte upload too large to inspect", length)));
+            }
+        }
+
+        // Clients waiting for `100 Continue` would not send the body; the backend's own is forwarded later
+        if received.is_empty() && head.header_str("expect").is_some_and(|e| e.eq_ignore_ascii_case("100-continue")) {
+            client.write_all(b"HTTP/1.1 100 Continue\r\n\r\n").await.map_err(|e| (400, format!("failed to read upload: {}", e)))?;
+        }
+        let data = match read_request_body(client, received.to_vec(), framing).await {
+            Ok(Some(data)) => data,
+            Ok(None) => return Err((413, "upload too large to inspect, or malformed".to_string())),
+            Err(e) => return Err((400, format!("failed to read upload: {}", e))),
+        };
+        let body = match framing.decode(&data, true) {
+            intercept::BodyStatus::Complete(body, _) => body.into_owned(),
+            _ => return Err((413, "upload too large to inspect, or malformed".to_string())),
+        };
+        Ok(Some((data, body)))
+    }
+
+    /// Check a request body as its route asks: with the `;scan` scanner, and against its checksums with
+    /// `;digest=verify`. Returns the `Digest` header to send with `;digest=request`, or the status and
+    /// reason refusing the request.
+    async fn inspect_upload(&self, route: &RouteMatch<'_>, head: &RequestHead<'_>, request_head: &[u8], body: &[u8]) -> Result<Option<String>, (u16, String)> {
+        if route.options.scan {
+            let Some(scanner) = &self.scanner else {
+                return Err((503, "no --icap scanner for the scan route".to_string()));
+            };
+            match scanner.scan(request_head, body).await {
+                Ok(icap::Verdict::Clean) => {}
+                Ok(icap::Verdict::Blocked(threat)) => return Err((403, format!("blocked by scanner: {}", threat.as_deref().unwrap_or("no reason given")))),
+                Err(e) => return Err((503, format!("scan failed: {}", e))),
+            }
+        }
+        if route.options.digest.verify {
+            digest::verify(head, body).map_err(|reason| (400, reason))?;
+        }
+        Ok(route.options.digest.request.then(|| digest::header_value(body)))
+    }
+
+    /// Wait for a retry's turn at its backend on `;backend-rate` routes; false when the backend's
+    /// queue is full
+    async fn wait_for_rate(&self, route: &RouteMatch<'_>, backend: &routing::Backend, queued: &mut Option<Duration>) -> bool {
+        let Some(rate) = route.options.backend_rate.as_ref() else {
+            return true;
+        };
+        match self.shaper.reserve(&backend.name, rate) {
+            Ok(wait) => {
+                tokio::time::sleep(wait).await;
+                accesslog::Timings::add(queued, wait);
+                true
+            }
+            Err(_) => false,
+        }
+    }
+
+    /// Classify a request whose client went away before its response was complete
+    fn record_abort<'e>(&self, entry: &mut accesslog::Entry<'e>, route: &RouteMatch<'_>, backend: &std::sync::Arc<str>, note: impl Into<Cow<'e, str>>) {
+        entry.status = Some(abort::CLIENT_CLOSED);
+        entry.note(note);
+        self.metrics.record_abort(route.name(), backend);
+    }
+
+    /// Send a response from the proxy itself and log the request
+    async fn respond<'e, S>(&self, client: &mut S, mut entry: accesslog::Entry<'e>, status: u16, response: &[u8], note: impl Into<Cow<'e, str>>)
+    where
+        S: AsyncWrite + Unpin,
+    {
+        let _ = client.write_all(response).await;
+        entry.local(status, response.len(), note);
+        self.access_log.log(&entry);
+    }
+
+    /// Serve a client connection: its first request, then each request handed back after it.
+    /// `psk_identity` is the identity a TLS-PSK client authenticated as, and `listener` the name of
+    /// the listener that accepted the connection.
+    async fn handle_connection<S>(&self, client_stream: S, client_addr: SocketAddr, psk_identity: Option<String>, listener: &str)
+    where
+        S: AsyncRead + AsyncWrite + Unpin,
+    {
+        self.handle_connection_from(client_stream, client_addr, psk_identity, listener, Vec::new()).await
+    }
+
+    /// Serve a connection whose first bytes have been `received` already
+    async fn handle_connection_from<S>(&self, mut client_stream: S, client_addr: SocketAddr, psk_identity: Option<String>, listener: &str, received: Vec<u8>)
+    where
+        S: AsyncRead + AsyncWrite + Unpin,
+    {
+        let mut handed = keepalive::Handed { received, pinned: None };
+        loop {
+            let mut next = None;
+            self.handle_request(&mut client_stream, client_addr, psk_identity.as_deref(), listener, handed, &mut next).await;
+            match next {
+                Some(handed_back) => handed = handed_back,
+                None => return,
+            }
+        }
+    }
+
+    /// Route a request, starting with the bytes of it `handed` on, and stream it to and from its
+    /// backend, with the requests after it that may go the same way. A request that may not is left
+    /// in `next`, to be handled the same way; None closes the connection.
+    async fn handle_request<S>(&self, client_stream: &mut S, client_addr: SocketAddr, psk_identity: Option<&str>, listener: &str, handed: keepalive::Handed, next: &mut Option<keepalive::Handed>)
+    where
+        S: AsyncRead + AsyncWrite + Unpin,
+    {
+        let keepalive::Handed { received, pinned } = handed;
+        // Lock-free: the request keeps the tables it started with, even if they are reloaded meanwhile
+        let config = self.config.load_full();
+        let redirect_map = self.redirect_map.as_ref().map(|map| map.load_full());
+
+        // Read and parse the request head; everything below borrows from this buffer
+        let (request_data, head_len) = match read_request_head(client_stream, received, self.max_header_size).await {
+            Ok(Some(result)) => result,
+            Ok(None) => {
+                logging::warning(format!("Request head from {} exceeds --max-header-size of {} bytes", client_addr, self.max_header_size));
+                let _ = client_stream.write_all(HEADER_TOO_LARGE).await;
+                return;
+            }
+            Err(e) => {
+                logging::error(format!("Failed to read request from {}: {}", client_addr, e));
+                return;
+            }
+        };
+        // Routes see the path in its normal form, and so does the backend, but for `;verbatim`
+        // routes: they are sent the request line as the client wrote it
+        let (request_data, head_len, original_line) = match request::normalize(&request_data, head_len) {
+            Some((normalized, normalized_len)) => {
+                let line_end = request_data.iter().position(|&b| b == b'\r' || b == b'\n').unwrap_or(head_len);
+                (normalized, normalized_len, Some(request_data[..line_end].to_vec()))
+            }
+            None => (request_data, head_len, None),
+        };
+        let mut header_storage = [httparse::EMPTY_HEADER; request::MAX_HEADERS];
+        let head = match RequestHead::parse(&request_data[..head_len], &mut header_storage) {
+            Ok(head) => head,
+            Err(e) => {
+                logging::error(format!("Failed to parse request from {}: {}", client_addr, e));
+                return;
+            }
+        };
+        let path = head.path;
+        let request_start = Instant::now();
+        // Span names and attributes are only built when traced: they cost allocations on every request
+        let name = if self.tracer.is_some() { head.method.to_string() } else { String::new() };
+        let mut trace = otel::RequestTrace::new(self.tracer.as_ref(), name, head.header_str("traceparent"));
+        let mut entry = accesslog::Entry::new(&head, head_len, client_addr, request_start);
+        let client_as = self.asn_db.as_ref().and_then(|db| db.lookup(client_addr.ip()));
+        entry.asn = client_as.as_ref().map(|system| system.number);
+
+        // A request the backend could frame or address differently is refused before anything else
+        if let Some(ambiguity) = head.ambiguity() {
+            let bad_request = &self.bad_request;
+            return self.respond(client_stream, entry, bad_request.status, &bad_request.bytes(&head, client_addr), ambiguity).await;
+        }
+
+        // Policy files, then legacy URLs in the redirect map, take precedence over all routes
+        if let Some(file) = self.well_known.get(&head) {
+            return self.respond(client_stream, entry, file.status, &file.bytes(&head, client_addr), "served by proxy").await;
+        }
+        if let Some(redirect) = redirect_map.as_ref().and_then(|map| map.get(path)) {
+            let location = redirect.location("", head.query);
+            let note = format!("redirect map to {}", location);
+            return self.respond(client_stream, entry, redirect.status, &redirect.response(&location), note).await;
+        }
+
+        // Determine which backend to use based on the path and get the matched prefix
+        let mut route_match = trace.start("route match", otel::Kind::Internal);
+        let route = config.get_backend_and_prefix(&head);
+        route_match.attribute("http.route", &**route.name());
+        trace.end(route_match);
+        entry.route = route.action.is_some().then(|| &**route.name());
+        // A route on other listeners is not there for this one's clients
+        if !route.on_listener(listener) {
+            let not_found = &self.not_found;
+            return self.respond(client_stream, entry, not_found.status, &not_found.bytes(&head, client_addr), format!("route not on listener {}", listener)).await;
+        }
+        if !route.allows(psk_identity) {
+            let forbidden = &self.forbidden;
+            let reason = psk_identity.map_or("requires a PSK client".to_string(), |id| format!("not allowed for PSK identity {}", id));
+            return self.respond(client_stream, entry, forbidden.status, &forbidden.bytes(&head, client_addr), reason).await;
+        }
+        if route.options.asn_rule.as_ref().is_some_and(|rule| !rule.allows(entry.asn)) {
+            let forbidden = &self.forbidden;
+            let reason = match &client_as {
+                Some(system) => format!("{} not allowed", describe_as(system)),
+                None => "autonomous system unknown".to_string(),
+            };
+            return self.respond(client_stream, entry, forbidden.status, &forbidden.bytes(&head, client_addr), reason).await;
+        }
+        if let Some(mode) = route.options.blocklist {
+            if let Some(list) = self.blocklists.check(client_addr.ip()).await {
+                // A full tarpit refuses like the route does without one
+                if let Some(place) = self.tarpit.enter().filter(|_| mode == blocklist::Mode::Tarpit) {
+                    let start = Instant::now();
+                    entry.response_bytes = place.drip(client_stream).await;
+                    entry.note(format!("blocklisted by {}, tarpitted for {} s", list, start.elapsed().as_secs()));
+                    return self.access_log.log(&entry);
+                }
+                let forbidden = &self.forbidden;
+                return self.respond(client_stream, entry, forbidden.status, &forbidden.bytes(&head, client_addr), format!("blocklisted by {}", list)).await;
+            }
+        }
+        if let Some((counter, limit)) = route.options.rate_limit.as_ref().and_then(|limit| config.rate_limit(limit, route.name())) {
+            let user = match (&self.user_identity, limit.key) {
+                (Some(source), ratelimit::LimitKey::User) => source.identify(&head, psk_identity),
+                _ => None,
+            };
+            // Listed users count against their plan's limit instead of the route's
+            let plans = user.as_ref().and(self.rate_limit_plans.as_ref()).map(|plans| plans.load_full());
+            let plan = user.as_deref().zip(plans.as_deref()).and_then(|(user, plans)| plans.get(user));
+            let limit = plan.map_or(limit, |(_, limit)| limit);
+            let client = ratelimit::Client::new(limit, client_addr.ip(), entry.asn, user.as_deref());
+            if let Err(retry_after) = self.rate_limiter.acquire(counter, limit, client.clone()) {
+                let group = counter.strip_prefix('@').map_or(String::new(), |group| format!(" by group {}", group));
+                let reason = match (&client, &client_as, plan) {
+                    (ratelimit::Client::Asn(_), Some(system), _) => format!("rate limited{}, {}", group, describe_as(system)),
+                    (ratelimit::Client::User(user), _, Some((plan, _))) => format!("rate limited{}, user {} on plan {}", group, user, plan),
+                    (ratelimit::Client::User(user), _, None) => format!("rate limited{}, user {}", group, user),
+                    _ => format!("rate limited{}", group),
+                };
+                let response = ratelimit::too_many_requests(retry_after, head.method != "HEAD");
+                return self.respond(client_stream, entry, 429, &response, reason).await;
+            }
+        }
+        let pool = match route.action {
+            Some(Action::Proxy(pool)) => pool,
+            Some(Action::Respond(response)) => {
+                return self.respond(client_stream, entry, response.status, &response.bytes(&head, client_addr), "").await;
+            }
+            Some(Action::Redirect(redirect)) => {
+                // The matched prefix is always a prefix of the path; the rest is preserved
+                let location = redirect.location(&path[route.prefix.len()..], head.query);
+                let note = format!("redirect to {}", location);
+                return self.respond(client_stream, entry, redirect.status, &redirect.response(&location), note).await;
+            }
+            None => {
+                let not_found = &self.not_found;
+                return self.respond(client_stream, entry, not_found.status, &not_found.bytes(&head, client_addr), "no route").await;
+            }
+        };
+        // Faults injected through the admin API stand in for a slow or failing backend
+        if let Some(fault) = self.faults.get(route.name()) {
+            let (delay, injected) = fault.inject();
+            if !delay.is_zero() {
+                tokio::time::sleep(delay).await;
+                entry.note(format!("fault: delayed {} ms", delay.as_millis()));
+            }
+            match injected {
+                Some(fault::Injected::Abort(status)) => {
+                    let response = response::LocalResponse::literal(status, "text/plain; charset=utf-8", "Fault injected\r\n").expect("valid status");
+                    return self.respond(client_stream, entry, status, &response.bytes(&head, client_addr), "fault: aborted").await;
+                }
+                Some(fault::Injected::Drop) => {
+                    entry.note("fault: connection dropped");
+                    return self.access_log.log(&entry);
+                }
+                None => {}
+            }
+        }
+        // A cached answer to a CORS preflight spares the backend the request
+        let preflight_key = route.options.cache_preflight.and_then(|_| preflight::key(route.name(), &head));
+        if let Some(response) = preflight_key.as_deref().and_then(|key| self.preflight_cache.get(key)) {
+            let status = intercept::status(&response).unwrap_or(200);
+            return self.respond(client_stream, entry, status, &response, "preflight cached").await;
+        }
+        // Responses of `;force-cache` routes are answered from memory while fresh
+        let cache_key = route.options.force_cache.and_then(|_| cache::key(route.name(), &head));
+        if let Some(hit) = cache_key.as_deref().and_then(|key| self.response_cache.get(key, head.method != "HEAD")) {
+            let note = format!("force-cached, {} s old", hit.age.as_secs());
+            return self.respond(client_stream, entry, hit.status, &hit.response, note).await;
+        }
+        // A retried request with an `Idempotency-Key` gets the response to the first one
+        let mut store = None;
+        if let Some((key, fingerprint)) = route.options.idempotency.and_then(|_| idempotency::key(route.name(), &head)) {
+            let conflict = match self.idempotency_cache.begin(key, fingerprint, route.options.idempotency.unwrap()) {
+                idempotency::Lookup::Forward(idempotency) => {
+                    store = Some(intercept::ResponseStore::Idempotency(idempotency));
+                    None
+                }
+                idempotency::Lookup::Replay(response) => {
+                    let status = intercept::status(&response).unwrap_or(200);
+                    return self.respond(client_stream, entry, status, &response, "idempotent replay").await;
+                }
+                idempotency::Lookup::InProgress => Some((409, "Idempotency-Key in use by a request in progress\r\n", "idempotency key in use")),
+                idempotency::Lookup::Mismatch => Some((422, "Idempotency-Key used for a different request\r\n", "idempotency key reused")),
+                idempotency::Lookup::Invalid => Some((400, "Invalid Idempotency-Key\r\n", "invalid idempotency key")),
+            };
+            if let Some((status, body, note)) = conflict {
+                let response = response::LocalResponse::literal(status, "text/plain; charset=utf-8", body).expect("valid status");
+                return self.respond(client_stream, entry, status, &response.bytes(&head, client_addr), note).await;
+            }
+        }
+        // A host at its connection quota waits for one of them to finish
+        let host_quota = self.quotas.get(&head);
+        let _slot = match host_quota.map(quota::Host::enter) {
+            Some(None) => {
+                let page = self.error_pages.as_ref().filter(|_| !route.options.pass_errors).and_then(|pages| pages.get(503));
+                let response = page.unwrap_or(&self.unavailable);
+                let note = format!("host {} at its quota of {} connections", host_quota.unwrap().name, host_quota.unwrap().quota.connections.unwrap_or(0));
+                return self.respond(client_stream, entry, response.status, &response.bytes(&head, client_addr), note).await;
+            }
+            Some(slot) => slot,
+            None => None,
+        };
+        // A drained backend takes no new connections; the ones it has finish undisturbed
+        let healthy = |backend: &routing::Backend| self.health.as_ref().map_or(true, |health| health.is_healthy(&backend.name));
+        let ejected = |backend: &routing::Backend| self.outliers.as_ref().is_some_and(|outliers| outliers.is_ejected(&backend.name));
+        // An NTLM- or Negotiate-authenticated connection stays on its backend connection while the route has that backend
+        let pinned_backend = pinned.as_ref().and_then(|pinned| pool.members().find(|(backend, _)| backend.name == pinned.backend)).map(|(backend, _)| backend);
+        let mut pinned_stream = match (pinned_backend, pinned) {
+            (Some(_), Some(pinned)) => pinned.alive().await,
+            _ => None,
+        };
+        let picked = pinned_backend.filter(|_| pinned_stream.is_some())
+            .or_else(|| config.pick(pool, self.lb, client_addr.ip(), &self.regions, |backend| healthy(backend) && !ejected(backend)))
+            // Ejecting every backend of a route would turn errors into an outage: then they all stay in
+            .or_else(|| self.outliers.as_ref().and_then(|_| config.pick(pool, self.lb, client_addr.ip(), &self.regions, healthy)));
+        // A backend connection for NTLM or Negotiate serves its client connection only, and is kept for it
+        let pinning = AtomicBool::new(pinned_stream.is_some() || head.connection_auth().is_some());
+        let Some(backend_addr) = picked else {
+            let page = self.error_pages.as_ref().filter(|_| !route.options.pass_errors).and_then(|pages| pages.get(503));
+            let response = page.unwrap_or(&self.unavailable);
+            let note = match pool.only() {
+                Some(backend) if config.is_drained(backend) => format!("backend {} drained", backend),
+                Some(backend) => format!("backend {} unhealthy", backend),
+                None => "no backend available (drained, unhealthy or weighted 0)".to_string(),
+            };
+            return self.respond(client_stream, entry, response.status, &response.bytes(&head, client_addr), note).await;
+        };
+        // Uploads on `;scan` routes reach the backend only once the scanner has passed them, and on
+        // `;digest` routes once their checksums are verified
+        let inspect = route.options.scan || route.options.digest.reads_request();
+        // Uploads on `;retry` routes are kept whole too, when they can be sent again
+        let keep = route.options.retry.as_ref().is_some() && retry::is_idempotent(head.method)
+            && matches!(request_body_framing(&head), Some(intercept::BodyFraming::Length(length)) if length <= intercept::MAX_FILTERED_BODY);
+        // Request bytes read from the client while it is watched for going away, forwarded after the request
+        let mut pending = Vec::new();
+        // Requests over a backend's rate wait for their turn, unless too many already do
+        if let Some(rate) = route.options.backend_rate.as_ref() {
+            match self.shaper.reserve(&backend_addr.name, rate) {
+                Ok(wait) if wait.is_zero() => {}
+                // Uploads read in full next are not watched, their body has to stay unread
+                Ok(wait) if inspect || keep => {
+                    tokio::time::sleep(wait).await;
+                    entry.timings.queue = Some(wait);
+                    entry.note(format!("queued {} ms for backend rate", wait.as_millis()));
+                }
+                Ok(wait) => {
+                    let queued = Instant::now();
+                    let slept = abort::unless_client_leaves(client_stream, &mut pending, tokio::time::sleep(wait)).await;
+                    entry.timings.queue = Some(queued.elapsed());
+                    if slept.is_none() {
+                        self.record_abort(&mut entry, &route, &backend_addr.name, "client disconnected while queued for backend rate");
+                        return self.access_log.log(&entry);
+                    }
+                    entry.note(format!("queued {} ms for backend rate", wait.as_millis()));
+                }
+                Err(retry_after) => {
+                    let response = shaper::queue_full(retry_after, head.method != "HEAD");
+                    return self.respond(client_stream, entry, 503, &response, format!("backend {} queue full", backend_addr)).await;
+                }
+            }
+        }
+        // Preload hints go out at once, while the backend works on its response; HTTP/1.0 clients
+        // do not expect interim responses
+        let early_hints = route.header_rules.map_or(&[][..], |rules| &rules.early_hints);
+        let early_hints = if head.version == 1 { early_hints } else { &[] };
+        if !early_hints.is_empty() {
+            let sent = match client_stream.write_all(early_hints).await {
+                Ok(()) => client_stream.flush().await,
+                Err(e) => Err(e),
+            };
+            if let Err(e) = sent {
+                entry.note(format!("failed to send early hints: {}", e));
+                return self.access_log.log(&entry);
+            }
+        }
+        let mut added_headers = Vec::new();
+        let upload = if inspect || keep {
+            let checked = match self.read_upload(client_stream, &head, &request_data[head_len..]).await {
+                Ok(Some((data, body))) if inspect => self.inspect_upload(&route, &head, &request_data[..head_len], &body).await.map(|digest| {
+                    added_headers.extend(digest.map(|digest| ("digest", digest)));
+                    Some(data)
+                }),
+                other => other.map(|upload| upload.map(|(data, _)| data)),
+            };
+            match checked {
+                Ok(data) => data,
+                Err((status, reason)) => {
+                    let response = match status {
+                        403 => &self.forbidden,
+                        413 => &self.too_large,
+                        503 => self.error_pages.as_ref().filter(|_| !route.options.pass_errors).and_then(|pages| pages.get(503)).unwrap_or(&self.unavailable),
+                        _ => &self.bad_request,
+                    };
+                    return self.respond(client_stream, entry, response.status, &response.bytes(&head, client_addr), reason).await;
+                }
+            }
+        } else {
+            None
+        };
+
+        if self.tracer.is_some() {
+            let root = trace.root();
+            root.rename(format!("{} {}", head.method, route.name()));
+            root.attribute("http.request.method", head.method);
+            root.attribute("http.route", &**route.name());
+            root.attribute("url.path", self.scrubber.as_ref().map_or(Cow::Borrowed(path), |scrubber| scrubber.scrub(path)).into_owned());
+            root.attribute("client.address", client_addr.ip().to_string());
+        }
+        // The backend's spans are children of the transfer span
+        let mut transfer = trace.start("transfer", otel::Kind::Client);
+        transfer.attribute("server.address", &*backend_addr.name);
+        if let Some(traceparent) = trace.traceparent(&transfer) {
+            added_headers.push(("traceparent", traceparent));
+        }
+
+        // Rewrite the path and headers if the route (or --rewrite, --rewrite-rule, --preserve-host, --set-header) asks for it
+        let buffered_request = upload.map(|body| [&request_data[..head_len], &body].concat());
+        // While routes check each of their requests, the requests after this one are followed by
+        // the gate; only this one's bytes are forwarded here, what the client sent after it is not
+        let follow = config.checks_requests();
+        let mut body = intercept::BodyTracker::new(request_body_framing(&head));
+        let received = buffered_request.as_deref().unwrap_or(&request_data);
+        let (received, carry) = match body.advance(&received[head_len..]) {
+            Some(end) if follow => received.split_at(head_len + end),
+            _ => (received, &[][..]),
+        };
+        // How many of the pending bytes are this request's
+        let own = |pending: &[u8]| if follow { body.clone().advance(pending).unwrap_or(pending.len()) } else { pending.len() };
+        // A request the backend answered can be sent again only when it is all in hand, and the
+        // backend cannot have acted on it
+        // NTLM and Negotiate handshake legs cannot go to another backend
+        let replayable = route.options.retry.as_ref().is_some() && retry::is_idempotent(head.method) && (buffered_request.is_some() || request_body_framing(&head).is_none())
+            && !pinning.load(Ordering::Relaxed);
+        if route.options.retry.as_ref().is_some() {
+            self.retry_budget.record_request();
+        }
+        // Another backend for a failed attempt, while the route's attempts and the retry budget allow
+        let retry_backend = |tried: &[&str]| {
+            let attempts = route.options.retry.as_ref()?.attempts as usize;
+            if tried.len() > attempts {
+                return None;
+            }
+            let untried = |backend: &routing::Backend| !tried.contains(&&*backend.name);
+            let next = config.pick(pool, self.lb, client_addr.ip(), &self.regions, |backend| untried(backend) && healthy(backend) && !ejected(backend))
+                .or_else(|| self.outliers.as_ref().and_then(|_| config.pick(pool, self.lb, client_addr.ip(), &self.regions, |backend| untried(backend) && healthy(backend))))?;
+            self.retry_budget.try_retry().then_some(next)
+        };
+        let mut backend_addr = backend_addr;
+        let mut tried: Vec<&str> = Vec::new();
+        // Pending bytes the backend has had; each attempt sends them again after the request
+        let mut forwarded_pending;
+        // When the final attempt's request was written, if its response has not been waited for yet
+        let mut awaiting_since;
+        let (mut backend_stream, final_request_data) = loop {
+            let final_request_data = ForwardedRequest::new(received, head_len, original_line.as_deref(), &head, &route, backend_addr, &added_headers);
+            // NTLM and Negotiate authenticate the connection: it stays pinned to this backend connection, which is never shared
+            entry.backend = Some(&backend_addr.name);
+            if tried.is_empty() {
+                if let Some(target) = &final_request_data.target {
+                    entry.note(format!("rewritten to {}", target));
+                }
+                if pinned_stream.is_some() {
+                    entry.note("pinned connection reused");
+                } else if let Some(scheme) = head.connection_auth() {
+                    entry.note(format!("{}, connection pinned", scheme));
+                }
+                if buffered_request.is_some() && route.options.scan {
+                    entry.note("upload scanned");
+                }
+            }
+
+            self.metrics.record_request(route.name(), &backend_addr.name);
+
+            // Connect to the backend server
+            let mut connect = trace.start("backend connect", otel::Kind::Internal);
+            connect.attribute("server.address", &*backend_addr.name);
+            let connect_start = Instant::now();
+            let (mut tcp_connected, mut dns) = (None, None);
+            let connecting = async {
+                let connected = self.resolver.connect(backend_addr, &mut dns, |addr| async move {
+                    if self.transparent {
+                        transparent::connect(addr, client_addr).await
+                    } else {
+                        TcpStream::connect(addr).await
+                    }
+                }).await;
+                match connected {
+                    Ok(tcp) => {
+                        tcp_connected = Some(Instant::now());
+                        upstream::BackendStream::connect(tcp, backend_addr, route.options.tls.as_ref()).await
+                    }
+                    Err(e) => Err(e),
+                }
+            };
+            let connected = match pinned_stream.take() {
+                Some(stream) => {
+                    drop(connecting);
+                    Some(Ok(stream))
+                }
+                None => abort::unless_client_leaves(client_stream, &mut pending, connecting).await,
+            };
+            let timings = &mut entry.timings;
+            if let Some(dns) = dns {
+                accesslog::Timings::add(&mut timings.dns, dns);
+            }
+            accesslog::Timings::add(&mut timings.connect, (tcp_connected.unwrap_or_else(Instant::now) - connect_start).saturating_sub(dns.unwrap_or_default()));
+            if let Some(tcp_connected) = tcp_connected.filter(|_| route.options.tls.as_ref().is_some()) {
+                accesslog::Timings::add(&mut timings.tls, tcp_connected.elapsed());
+            }
+            let mut backend_stream = match connected {
+                Some(Ok(s)) => s,
+                None => {
+                    trace.end(connect);
+                    trace.root().error("client disconnected");
+                    trace.finish();
+                    self.record_abort(&mut entry, &route, &backend_addr.name, "client disconnected while connecting to backend");
+                    return self.access_log.log(&entry);
+                }
+                Some(Err(e)) => {
+                    logging::error(format!("Failed to connect to backend {}: {}", backend_addr, e));
+                    connect.error(&e);
+                    trace.end(connect);
+                    self.alerter.record_connect_failure(&backend_addr.name);
+                    self.metrics.record_connect_failure(&backend_addr.name);
+                    if let Some(outliers) = &self.outliers {
+                        outliers.record(&backend_addr.name, true);
+                    }
+                    tried.push(&backend_addr.name);
+                    let next = match retry_backend(&tried) {
+                        Some(next) if self.wait_for_rate(&route, next, &mut entry.timings.queue).await => Some(next),
+                        _ => None,
+                    };
+                    if let Some(next) = next {
+                        entry.note(format!("connect to {} failed, retried on {}", backend_addr, next));
+                        backend_addr = next;
+                        continue;
+                    }
+                    trace.root().error("backend connect failed");
+
+                    // Send 502 Bad Gateway response
+                    let page = self.error_pages.as_ref().filter(|_| !route.options.pass_errors).and_then(|pages| pages.get(502));
+                    let response = page.unwrap_or(&self.bad_gateway);
+                    trace.root().attribute("http.response.status_code", response.status);
+                    trace.finish();
+                    return self.respond(client_stream, entry, response.status, &response.bytes(&head, client_addr), "backend connect failed").await;
+                }
+            };
+            trace.end(connect);
+            self.metrics.record_connect(route.name(), &backend_addr.name, connect_start.elapsed());
+
+            // Forward the (possibly rewritten) request to the backend
+            transfer.begin();
+            let write_start = Instant::now();
+            let forwarded = match abort::unless_client_leaves(client_stream, &mut pending, final_request_data.write_to(&mut backend_stream)).await {
+                Some(Ok(())) => backend_stream.write_all(&pending[..own(&pending)]).await,
+                Some(Err(e)) => Err(e),
+                None => {
+                    accesslog::Timings::add(&mut entry.timings.write, write_start.elapsed());
+                    backend_stream.abort();
+                    transfer.error("client disconnected");
+                    trace.end(transfer);
+                    trace.finish();
+                    self.record_abort(&mut entry, &route, &backend_addr.name, "client disconnected while the request was forwarded");
+                    return self.access_log.log(&entry);
+                }
+            };
+            if let Err(e) = forwarded {
+                logging::error(format!("Failed to forward request to backend: {}", e));
+                transfer.error(&e);
+                trace.end(transfer);
+                trace.finish();
+                entry.note("failed to forward request");
+                return self.access_log.log(&entry);
+            }
+            forwarded_pending = own(&pending);
+            let forwarded_at = Instant::now();
+            accesslog::Timings::add(&mut entry.timings.write, forwarded_at - write_start);
+
+            // With retries left, the response is held back until its status shows whether to keep it
+            let attempts = route.options.retry.as_ref().map_or(0, |retry| retry.attempts as usize);
+            if !replayable || tried.len() >= attempts {
+                awaiting_since = Some(forwarded_at);
+                break (retry::ReadAhead::new(backend_stream, Vec::new()), final_request_data);
+            }
+            // The wait for the status read ahead counts as the time to first byte
+            awaiting_since = None;
+            let peeked = abort::unless_client_leaves(client_stream, &mut pending, read_response_status(&mut backend_stream)).await;
+            accesslog::Timings::add(&mut entry.timings.first_byte, forwarded_at.elapsed());
+            let Some((ahead, status)) = peeked else {
+                backend_stream.abort();
+                transfer.error("client disconnected");
+                trace.end(transfer);
+                trace.finish();
+                self.record_abort(&mut entry, &route, &backend_addr.name, "client disconnected while waiting for the response");
+                return self.access_log.log(&entry);
+            };
+            let failure = match status {
+                Ok(Some(status)) if route.options.retry.as_ref().is_some_and(|retry| retry.retries(status)) => format!("{} from {}", status, backend_addr),
+                Ok(None) if ahead.is_empty() => format!("no response from {}", backend_addr),
+                Err(e) => format!("response from {} failed: {}", backend_addr, e),
+                Ok(_) => break (retry::ReadAhead::new(backend_stream, ahead), final_request_data),
+            };
+            tried.push(&backend_addr.name);
+            let next = match retry_backend(&tried) {
+                Some(next) if self.wait_for_rate(&route, next, &mut entry.timings.queue).await => Some(next),
+                _ => None,
+            };
+            let Some(next) = next else {
+                break (retry::ReadAhead::new(backend_stream, ahead), final_request_data);
+            };
+            if let Some(outliers) = &self.outliers {
+                outliers.record(&backend_addr.name, true);
+            }
+            entry.note(format!("{}, retried on {}", failure, next));
+            backend_addr = next;
+        };
+
+        // The client may have sent more while the response status was awaited
+        let (pending, after) = pending.split_at(own(&pending));
+        body.advance(pending);
+        if forwarded_pending < pending.len() {
+            if let Err(e) = backend_stream.write_all(&pending[forwarded_pending..]).await {
+                entry.note(format!("transfer failed: {}", e));
+                trace.end(transfer);
+                trace.finish();
+                return self.access_log.log(&entry);
+            }
+        }
+        let _connection = self.metrics.open_connection(&backend_addr.name);
+        // A sampled request is recorded as the client sent it, from what has arrived of it so far
+        let mut recording = self.capture.as_ref().and_then(|capture| capture.sample(route.name()));
+        if let Some(recording) = &mut recording {
+            recording.request.extend(received);
+            recording.request.extend(pending);
+        }
+        // A mirrored connection starts on the shadow with the request as it would have been sent to it;
+        // upgraded and NTLM- or Negotiate-authenticated connections only make sense to one backend
+        let mut shadow = route.options.mirror.as_ref()
+            .filter(|mirror| head.header("upgrade").is_none() && head.connection_auth().is_none() && mirror.sample())
+            .map(|mirror| {
+                let mut request = ForwardedRequest::new(received, head_len, original_line.as_deref(), &head, &route, &mirror.backend, &added_headers).to_vec();
+                request.extend_from_slice(pending);
+                mirror.start(route.name(), request, route.options.tls, self.resolver.clone())
+            });
+        if let Some(shadow) = &shadow {
+            entry.note(format!("mirrored to {}", shadow.backend()));
+        }
+
+        // Now do bidirectional streaming between client and backend. The backend connection serves this
+        // client connection only, which connection-based authentication (Negotiate) relies on.
+        // Map redirects from the backend's view of paths and hosts back to the client's, apply body filters
+        // and replace 5xx responses with error pages; keep the response when a cache or idempotency key wants it
+        let store = store
+            .or_else(|| preflight_key.zip(route.options.cache_preflight).map(|(key, ttl)| intercept::ResponseStore::Preflight(preflight::Store { cache: &self.preflight_cache, key, ttl })))
+            .or_else(|| cache_key.filter(|_| head.method == "GET").zip(route.options.force_cache).map(|(key, ttl)| intercept::ResponseStore::Cache(cache::Store { cache: &self.response_cache, key, ttl, quota: host_quota.and_then(quota::Host::cache_quota) })));
+        let response_rewrite = intercept::ResponseRewrite::new(&route, &head, backend_addr, self.error_pages.as_ref(), &self.bad_gateway, client_addr, store);
+        let progress = abort::ResponseProgress::default();
+        let streaming_start = Instant::now();
+        // A connection through a slot is closed once a switch away from its pool has drained
+        let closing = self.cutovers.closing(pool, &backend_addr.name);
+        let mut cut = None;
+        // A later request goes to this backend connection only when it is for the same route, and
+        // that route neither checks nor changes each of its requests
+        let exchanges = keepalive::Exchanges::new();
+        let following = follow.then(|| {
+            let (config, options, pinning) = (&config, route.options, &pinning);
+            let relays = move |data: &[u8]| {
+                let (data, head_len) = request::normalize(data, data.len()).unwrap_or_else(|| (data.to_vec(), data.len()));
+                let mut storage = [httparse::EMPTY_HEADER; request::MAX_HEADERS];
+                let Ok(later) = RequestHead::parse(&data[..head_len], &mut storage) else {
+                    return false;
+                };
+                // A later request starting an NTLM or Negotiate handshake pins the connection too
+                if later.connection_auth().is_some() {
+                    pinning.store(true, Ordering::Relaxed);
+                }
+                let matched = config.get_backend_and_prefix(&later);
+                !matched.checks_requests() && std::ptr::eq(matched.options, options)
+            };
+            keepalive::Following::new(&exchanges, body, head.header("upgrade").is_some(), [carry, after].concat(), self.max_header_size, relays)
+        });
+        let mut throttled = quota::Throttled::new(client_stream, host_quota);
+        let streamed = tokio::select! {
+            streamed = stream_bidirectional(&mut throttled, &mut backend_stream, response_rewrite.as_ref(), &head, route.options.heartbeat.as_ref(), &progress, recording.as_mut(), shadow.as_mut(), following) => streamed,
+            slot = closing => {
+                cut = Some(slot);
+                Err(std::io::Error::new(std::io::ErrorKind::Interrupted, "closed by a slot switch"))
+            }
+        };
+        match streamed {
+            Err(_) if cut.is_some() => {
+                backend_stream.get_ref().abort();
+                entry.note(format!("closed after the switch of slot {} drained", cut.unwrap()));
+            }
+            Ok((request_bytes, response_bytes, first_byte_at, status)) => {
+                *next = exchanges.handed_back().map(|received| {
+                    let stream = backend_stream.into_inner();
+                    let pinned = pinning.load(Ordering::Relaxed).then(|| keepalive::Pinned { backend: backend_addr.name.clone(), stream });
+                    keepalive::Handed { received, pinned }
+                });
+                transfer.attribute("http.request.size", final_request_data.len() as u64 + pending.len() as u64 + request_bytes);
+                transfer.attribute("http.response.size", response_bytes);
+                if let Some(status) = status {
+                    trace.root().attribute("http.response.status_code", status);
+                }
+                entry.status = status;
+                entry.request_bytes = final_request_data.len() as u64 + pending.len() as u64 + request_bytes;
+                entry.response_bytes = early_hints.len() as u64 + response_bytes;
+                let first_byte = first_byte_at.map(|at| at - request_start);
+                let response_from = match awaiting_since {
+                    Some(since) => first_byte_at.map(|at| {
+                        accesslog::Timings::add(&mut entry.timings.first_byte, at - since);
+                        at
+                    }),
+                    None => Some(streaming_start),
+                };
+                entry.timings.transfer = response_from.map(|from| from.elapsed());
+                self.alerter.record_success(&backend_addr.name, first_byte);
+                if let Some(outliers) = &self.outliers {
+                    outliers.record(&backend_addr.name, status.is_some_and(|status| status >= 500));
+                }
+                self.metrics.record_transfer(
+                    route.name(),
+                    &backend_addr.name,
+                    &client_addr.to_string(),
+                    path,
+                    final_request_data.len() as u64 + pending.len() as u64 + request_bytes,
+                    response_bytes,
+                    status,
+                    first_byte,
+                    request_start.elapsed(),
+                );
+                if let Some((capture, recording)) = self.capture.as_ref().zip(recording) {
+                    let exchange = capture::Exchange { client: client_addr, route: route.name(), backend: &backend_addr.name, status, head_request: head.method == "HEAD" };
+                    if let Some(name) = capture.save(recording, &exchange) {
+                        entry.note(format!("captured as {}", name));
+                    }
+                }
+            }
+            Err(e) if progress.is_aborted() => {
+                // The backend would go on with a response nobody reads
+                backend_stream.get_ref().abort();
+                let note = match e.kind() {
+                    std::io::ErrorKind::ConnectionAborted => "client disconnected, backend request aborted".to_string(),
+                    _ => format!("client connection failed ({}), backend request aborted", e),
+                };
+                self.record_abort(&mut entry, &route, &backend_addr.name, note);
+            }
+            Err(e) => {
+                // Connection errors are common and expected when clients/servers close connections
+                if e.kind() != std::io::ErrorKind::UnexpectedEof
+                    && e.kind() != std::io::ErrorKind::ConnectionReset {
+                    logging::error(format!("Proxy forwarding error: {}", e));
+                    self.alerter.record_error();
+                    transfer.error(&e);
+                }
+                entry.note(format!("transfer failed: {}", e));
+            }
+        }
+        trace.end(transfer);
+        trace.finish();
+        self.access_log.log(&entry);
+    }
+}
+
+#[tokio::main]
+async fn main() -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    let validate = Args::command().name("validate")
+        .about("Check the configuration (routes, backends, files) without starting the proxy; exits non-zero on problems");
+    let export = clap::Command::new("export")
+        .about("Print a Grafana dashboard (JSON) for the metrics of the admin API's GET /metrics, to import into Grafana")
+        .arg(clap::Arg::new("title").long("title").value_name("TITLE").default_value("Reverse HTTP Proxy").help("Title of the dashboard"))
+        .arg(clap::Arg::new("uid").long("uid").value_name("UID").default_value("reverse-http-proxy").help("Grafana uid of the dashboard; importing it again replaces the dashboard with this uid"));
+    let dashboard = clap::Command::new("dashboard")
+        .about("Grafana dashboard for the proxy's Prometheus metrics")
+        .subcommand_required(true)
+        .subcommand(export);
+    let test = Args::command().name("test")
+        .about("Show which route a request matches and why, the backend it goes to and the request as forwarded; takes the proxy's options after METHOD and PATH")
+        .arg(clap::Arg::new("method").value_name("METHOD").required(true).index(1).help("Method of the request (GET, POST, ...)"))
+        .arg(clap::Arg::new("path").value_name("PATH").required(true).index(2).help("Path of the request, with any query string"))
+        .mut_arg("listen_address", |arg| arg.index(3))
+        .mut_arg("default_backend", |arg| arg.index(4))
+        .arg(clap::Arg::new("header").short('H').long("header").value_name("NAME: VALUE").action(clap::ArgAction::Append)
+            .help("Header of the request (can be specified multiple times)"));
+    let routes = clap::Command::new("routes")
+        .about("Inspect the route table")
+        .subcommand_required(true)
+        .subcommand(test);
+    let config = clap::Command::new("config")
+        .about("The configuration file format")
+        .subcommand_required(true)
+        .subcommand(clap::Command::new("schema").about("Print a JSON Schema of the configuration file, for editors and CI to check --config files against"));
+    let matches = Args::command()
+        .subcommand(validate)
+        .subcommand(dashboard)
+        .subcommand(routes)
+        .subcommand(config)
+        .args_conflicts_with_subcommands(true)
+        .disable_help_subcommand(true)
+        .get_matches();
+    if let Some(matches) = matches.subcommand_matches("validate") {
+        std::process::exit(if validate::run(matches) { 0 } else { 1 });
+    }
+    if let Some(matches) = matches.subcommand_matches("routes").and_then(|matches| matches.subcommand_matches("test")) {
+        std::process::exit(if routetest::run(matches) { 0 } else { 1 });
+    }
+    if matches.subcommand_matches("config").and_then(|matches| matches.subcommand_matches("schema")).is_some() {
+        println!("{}", serde_json::to_string_pretty(&schema::export())?);
+        return Ok(());
+    }
+    if let Some(matches) = matches.subcommand_matches("dashboard").and_then(|matches| matches.subcommand_matches("export")) {
+        let title = matches.get_one::<String>("title").unwrap();
+        let uid = matches.get_one::<String>("uid").unwrap();
+        println!("{}", serde_json::to_string_pretty(&dashboard::export(title, uid))?);
+        return Ok(());
+    }
+
+    let args = Args::load(&matches)?;
+    args.check()?;
+    logging::init(logging::Target::parse(&args.log_target)?, logging::parse_facility(&args.syslog_facility)?, &args.syslog_tag)?;
+    let tls_provider = client::tls_provider(args.tls_provider.as_deref())?;
+    let fips = client::install_tls_provider(tls_provider);
+    let sniffer = sniff::Sniffer::parse(&args.sniff)?;
+    let psk = args.psk_keys.as_deref().map(PskAcceptor::load).transpose()?.map(std::sync::Arc::new);
+    let scanner = args.icap.as_deref().map(icap::Scanner::parse).transpose()?;
+    let rotation = args.access_log_rotate.as_deref().map(logfile::Rotation::parse).transpose()?.unwrap_or_default();
+    let log_file = args.access_log.as_deref().map(|path| logfile::LogFile::open(path, rotation, args.access_log_keep)).transpose()?;
+    let lb = pool::Strategy::parse(&args.lb)?;
+    let health_config = args.health_config()?;
+    let log_format = accesslog::LogFormat::parse(&args.log_format)?;
+    let drop_policy = logsink::DropPolicy::parse(&args.access_log_drop)?;
+    let log_sink = args.access_log_sink.as_deref().map(logsink::Target::parse).transpose()?.map(|target| logsink::LogSink::start(logsink::SinkConfig {
+        target,
+        batch: args.access_log_batch,
+        queue: args.access_log_queue,
+        drop: drop_policy,
+        json: matches!(log_format, accesslog::LogFormat::Json),
+    }));
+    let scrubber = (args.scrub || !args.scrub_params.is_empty() || !args.scrub_patterns.is_empty())
+        .then(|| scrub::Scrubber::new(&args.scrub_params, &args.scrub_patterns)).transpose()?
+        .map(std::sync::Arc::new);
+    let access_log = accesslog::AccessLog::new(log_format, log_file, log_sink, scrubber.clone());
+    let capture = args.capture_config()?.map(|config| capture::Capture::open(config, scrubber.clone())).transpose()?;
+    let tracer = args.otlp_endpoint.as_deref().map(otel::endpoint).transpose()?
+        .map(|url| otel::Tracer::start(url, args.otel_service_name.clone()));
+    let asn_db = args.asn_db.as_deref().map(asn::AsnDatabase::load).transpose()?;
+    let blocklists = std::sync::Arc::new(blocklist::Blocklists::load(&args.blocklists, Duration::from_secs(args.blocklist_refresh.max(1))).await?);
+
+    if let Some(server) = &args.dns_server {
+        dns::set_nameserver(server.parse().map_err(|e| format!("Invalid --dns-server '{}': {}", server, e))?);
+    }
+    if let Some(url) = &args.k8s_api {
+        k8s::set_api(url)?;
+    }
+
+    // Parse the routing configuration, preferring a previously saved snapshot
+    let snapshot = match &args.state_file {
+        Some(path) => state::Snapshot::load(path)?,
+        None => None,
+    };
+    let snapshot = match snapshot {
+        Some(snapshot) => {
+            println!("Restoring routes from state file {}", args.state_file.as_ref().unwrap().display());
+            snapshot
+        }
+        None => args.snapshot(),
+    };
+    if let Some(socket) = &args.docker {
+        docker::start(socket, &snapshot)?;
+    }
+    let config = RouteConfig::from_snapshot(snapshot)?;
+    check_scanner(&config, scanner.as_ref())?;
+    check_blocklists(&config, !blocklists.is_empty())?;
+    check_asn_db(&config, asn_db.is_some())?;
+    let user_identity = args.user_identity.as_deref().map(plans::IdentitySource::parse).transpose()?;
+    check_user_identity(&config, user_identity.is_some())?;
+    let mut quotas = quota::Quotas::default();
+    for spec in &args.host_quotas {
+        let (host, quota) = quota::HostQuota::parse(spec)?;
+        quotas.add(host, quota);
+    }
+    check_host_quotas(&config, &quotas)?;
+    let rate_limit_plans = args.rate_limit_plans.as_deref().map(plans::Plans::load).transpose()?;
+
+    let not_found = response::LocalResponse::new(args.not_found_status, "text/plain; charset=utf-8", &args.not_found_body, &[])?;
+    let max_header_size = args.max_header_size()?;
+
+    let redirect_map = args.redirect_map.as_deref().map(redirects::RedirectMap::load).transpose()?;
+    let error_pages = args.custom_errors.as_deref().map(errorpages::ErrorPages::load).transpose()?;
+
+    let mut well_known = wellknown::WellKnownFiles::default();
+    for spec in &args.robots_txt {
+        well_known.add(wellknown::ROBOTS_TXT, spec)?;
+    }
+    for spec in &args.security_txt {
+        well_known.add(wellknown::SECURITY_TXT, spec)?;
+    }
+
+    let listeners = args.listeners()?;
+    check_listeners(&config, &listeners)?;
+    let handover = std::sync::Arc::new(handover::Handover::from_env());
+    let mut bound = Vec::new();
+    for listener in listeners {
+        bound.push((handover.listen(&listener.handover_key(), listener.addr).await?, listener));
+    }
+
+    for (_, listener) in &bound {
+        match (listener.kind(), listener.name.as_str()) {
+            ("http", listener::MAIN) => println!("Reverse proxy listening on http://{}", listener.addr),
+            (listener::PSK, listener::PSK) => println!("TLS-PSK listener on {}", listener.addr),
+            ("http", name) => println!("Reverse proxy listening on http://{} (listener {})", listener.addr, name),
+            (listener::PSK, name) => println!("TLS-PSK listener on {} (listener {})", listener.addr, name),
+            (_, name) => println!("TLS listener on https://{} (listener {})", listener.addr, name),
+        }
+    }
+    match &config.default_backend {
+        Some(action) => println!("Default backend: {}", action),
+        None => println!("Default backend: none (unmatched requests get {} {})", not_found.status, not_found.reason()),
+    }
+    println!("Path rewriting: {}", if config.rewrite_paths { "enabled" } else { "disabled" });
+    println!("Host header: {}", if config.preserve_host { "preserved" } else { "backend address" });
+    println!("Load balancing: {}", lb);
+    if let Some(health_config) = &health_config {
+        println!("Health checks: {} every {} s{}, out after {} failures, back after {} passes",
+            health_config.check, health_config.interval.as_secs(),
+            if health_config.jitter > 0.0 { format!(" ± {}%", health_config.jitter * 100.0) } else { String::new() },
+            health_config.unhealthy_threshold, health_config.healthy_threshold);
+        for backend in health_config.overrides.keys() {
+            println!("Health checks of {}: {}", backend, health_config.describe(backend));
+        }
+        if !health_config.expect.is_empty() {
+            println!("Health check responses: {}", health_config.expect);
+        }
+    }
+    if let Some(zone) = &args.zone {
+        println!("Zone: {}", zone);
+    }
+    if let (Some(path), Some(map)) = (&args.redirect_map, &redirect_map) {
+        println!("Redirect map: {} ({} entries)", path.display(), map.len());
+    }
+    println!("Outbound TLS: {}{}", tls_provider, if fips { " (FIPS mode)" } else { "" });
+    if let Some(url) = &args.icap {
+        println!("Upload scanning: {}", url);
+    }
+    for list in blocklists.describe() {
+        println!("Blocklist: {}", list);
+    }
+    if let Some(path) = &args.asn_db {
+        println!("ASN database: {}", path.display());
+    }
+    if let Some(source) = &user_identity {
+        println!("User identity: {}", source);
+    }
+    if let (Some(path), Some(plans)) = (&args.rate_limit_plans, &rate_limit_plans) {
+        println!("Rate limit plans: {} ({})", path.display(), plans.describe());
+    }
+    if let Some(path) = &args.access_log {
+        println!("Access log: {}", path.display());
+    }
+    if let Some(sink) = &args.access_log_sink {
+        println!("Access log shipped to {} (batches of up to {}, {} queued at most)", sink, args.access_log_batch.max(1), args.access_log_queue.max(1));
+    }
+    if let Some(address) = &args.statsd {
+        println!("StatsD: {} every {} s", address, args.statsd_interval.max(1));
+    }
+    if let Some(url) = &args.otlp_endpoint {
+        println!("Tracing: OTLP to {} as {}", url, args.otel_service_name);
+    }
+    if scrubber.is_some() {
+        println!("Scrubbing: credentials and e-mail addresses ({} more parameters, {} more patterns)", args.scrub_params.len(), args.scrub_patterns.len());
+    }
+    if let Some(capture) = &capture {
+        println!("Payload capture: {}", capture.describe());
+    }
+    if let Some(sniffer) = &sniffer {
+        println!("Protocol sniffing: HTTP -> proxy, TLS -> {}, other -> {}", sniffer.tls, sniffer.other);
+    }
+    if args.transparent {
+        println!("Transparent mode: backend connections use client addresses");
+    }
+    if let Some(socket) = &args.docker {
+        println!("Docker: routes from container labels, following {}", socket);
+    }
+    if let (Some(dir), Some(pages)) = (&args.custom_errors, &error_pages) {
+        println!("Error pages: {} ({} statuses)", dir.display(), pages.len());
+    }
+    if !well_known.is_empty() {
+        println!("\nServed by the proxy:");
+        for (path, host) in well_known.list() {
+            println!("  {}{}", host.unwrap_or(""), path);
+        }
+    }
+
+    if !config.routes.is_empty() {
+        println!("\nPath-based routes:");
+        for target in config.routes.iter() {
+            println!("  {} -> {}{}", target.name, target.action, tls_note(target.options.tls));
+        }
+    }
+
+    if !config.header_routes.is_empty() {
+        println!("\nHeader-based routes:");
+        for route in config.header_routes.iter() {
+            println!("  {} -> {}{}", route.source, route.action, tls_note(route.options.tls));
+        }
+    }
+
+    if !config.query_routes.is_empty() {
+        println!("\nQuery-based routes:");
+        for route in config.query_routes.iter() {
+            println!("  {} -> {}{}", route.source, route.action, tls_note(route.options.tls));
+        }
+    }
+
+    if !config.virtual_hosts.is_empty() {
+        println!("\nHost-based routes:");
+        for vhost in config.virtual_hosts.values() {
+            for target in vhost.backends.iter().chain(vhost.routes.iter()) {
+                println!("  {} -> {}{}", target.name, target.action, tls_note(target.options.tls));
+            }
+        }
+    }
+    if !quotas.is_empty() {
+        println!("\nHost quotas:");
+        let mut hosts: Vec<&quota::Host> = quotas.iter().collect();
+        hosts.sort_by(|a, b| a.name.cmp(&b.name));
+        for host in hosts {
+            println!("  {}: {}", host.name, host.quota);
+        }
+    }
+    if config.slots.iter().next().is_some() {
+        println!("\nBlue/green slots:");
+        for (name, slot) in config.slots.iter() {
+            let pools: Vec<String> = [slot::Color::Blue, slot::Color::Green].into_iter()
+                .filter_map(|color| slot.spec(color).map(|spec| format!("{} {}{}", color, spec, if color == slot.active { " (active)" } else { "" })))
+                .collect();
+            println!("  {}: {}", name, pools.join(", "));
+        }
+    }
+    let force_cached = config.force_cached_routes();
+    if !force_cached.is_empty() {
+        println!();
+        logging::warning(format!("WARNING: caching responses regardless of Cache-Control (;force-cache) on: {}", force_cached.join(", ")));
+    }
+    let legacy_routes = config.legacy_tls_routes();
+    if !legacy_routes.is_empty() {
+        println!();
+        logging::warning(format!("WARNING: legacy TLS (TLS 1.0/1.1, weak ciphers, unsafe renegotiation) to the backends of: {}", legacy_routes.join(", ")));
+    }
+
+    let config: routing::SharedConfig = std::sync::Arc::new(arc_swap::ArcSwap::from_pointee(config));
+    let metrics = std::sync::Arc::new(metrics::Metrics::default());
+    let bus = std::sync::Arc::new(events::EventBus::default());
+    let orders: Vec<_> = bound.iter().filter_map(|(_, listener)| {
+        let (cert, key) = listener.certificate.clone().filter(|_| !listener.acme.is_empty())?;
+        Some(acme::Order { listener: listener.name.clone(), names: listener.acme.clone(), cert, key })
+    }).collect();
+    let issuer = match (&args.acme_account_key, &args.acme_dns) {
+        (Some(account_key), Some(dns)) if !orders.is_empty() => {
+            let (dns_wait, renew) = (Duration::from_secs(args.acme_dns_wait), Duration::from_secs(args.acme_renew * 86400));
+            let dns = acmedns::Provider::parse(dns)?;
+            Some(std::sync::Arc::new(acme::Issuer::new(&args.acme_directory, account_key, args.acme_email.clone(), dns, dns_wait, renew)?))
+        }
+        _ => None,
+    };
+    // Certificates are issued before the listeners load them
+    if let Some(issuer) = &issuer {
+        for order in &orders {
+            issuer.keep(order, &bus).await?;
+        }
+    }
+    let cert_warning = Duration::from_secs(args.cert_warning * 86400);
+    let mut certificates = Vec::new();
+    for (_, listener) in &bound {
+        if let Some((cert, key)) = &listener.certificate {
+            certificates.push(std::sync::Arc::new(certs::Served::load(&listener.name, cert.clone(), key.clone(), cert_warning, &metrics, &bus)?));
+        }
+    }
+
+    if let Some(address) = &args.statsd {
+        let config = statsd::StatsdConfig {
+            address: address.clone(),
+            prefix: args.statsd_prefix.clone(),
+            tags: args.statsd_tags.clone(),
+            format: statsd::Format::parse(&args.statsd_format)?,
+            interval: Duration::from_secs(args.statsd_interval.max(1)),
+        };
+        args.statsd_tags.iter().try_for_each(|tag| statsd::check_tag(tag))?;
+        tokio::spawn(statsd::run(config, metrics.clone()));
+    }
+
+    if let Some(seconds) = args.metrics_summary {
+        let metrics = metrics.clone();
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(Duration::from_secs(seconds.max(1)));
+            interval.tick().await;
+            loop {
+                interval.tick().await;
+                for line in metrics.take_summary() {
+                    logging::info(line);
+                }
+            }
+        });
+    }
+
+    let regions = std::sync::Arc::new(region::Regions::new(args.zone.clone()));
+    tokio::spawn(regions.clone().probe(config.clone(), Duration::from_secs(args.probe_interval.max(1))));
+    let outliers = (args.outlier_consecutive_errors.is_some() || args.outlier_error_rate.is_some()).then(|| {
+        std::sync::Arc::new(outlier::OutlierDetector::new(outlier::OutlierConfig {
+            consecutive_errors: args.outlier_consecutive_errors,
+            error_rate: args.outlier_error_rate.map(|percent| percent / 100.0),
+            window: Duration::from_secs(args.outlier_window.max(1)),
+            min_requests: args.outlier_min_requests,
+            ejection: Duration::from_secs(args.outlier_ejection.max(1)),
+            max_ejection: Duration::from_secs(args.outlier_max_ejection.max(args.outlier_ejection).max(1)),
+        }, bus.clone()))
+    });
+    let resolver = std::sync::Arc::new(resolver::Resolver::new(Duration::from_secs(args.dns_ttl)));
+    let health = health_config.map(|health_config| std::sync::Arc::new(health::HealthChecks::new(health_config, bus.clone(), resolver.clone(), metrics.clone())));
+    if let Some(health) = &health {
+        tokio::spawn(health.clone().run(config.clone()));
+    }
+
+    let alerter = alerts::Alerter::start(alerts::AlertConfig {
+        webhook: args.alert_webhook.as_deref().map(client::Url::parse).transpose()?,
+        error_rate: args.alert_error_rate.map(|percent| percent / 100.0),
+        latency: args.alert_latency_ms.map(Duration::from_millis),
+        backend_failures: args.alert_backend_failures,
+        window: Duration::from_secs(args.alert_window.max(1)),
+        min_requests: args.alert_min_requests,
+    }, bus.clone());
+
+    let faults = std::sync::Arc::new(fault::Faults::default());
+    let cutovers = std::sync::Arc::new(slot::Cutovers::default());
+    if let Some(admin_address) = args.admin_address {
+        let admin_addr = admin_address.parse::<SocketAddr>()?;
+        let state = std::sync::Arc::new(admin::AdminState {
+            config: config.clone(),
+            metrics: metrics.clone(),
+            alerter: alerter.clone(),
+            bus: bus.clone(),
+            state_file: args.state_file.clone(),
+            control_plane: args.control_plane.is_some(),
+            regions: regions.clone(),
+            health: health.clone(),
+            outliers: outliers.clone(),
+            faults: faults.clone(),
+            cutovers: cutovers.clone(),
+            certificates: certificates.clone(),
+        });
+        match handover.listen("admin", admin_addr).await {
+            Ok(admin_listener) => {
+                let handover = handover.clone();
+                tokio::spawn(async move {
+                    if let Err(e) = admin::run(admin_listener, state, handover).await {
+                        logging::error(format!("Admin API error: {}", e));
+                    }
+                });
+            }
+            Err(e) => logging::error(format!("Admin API error: {}", e)),
+        }
+    }
+
+    let redirect_map: Option<redirects::SharedRedirectMap> = redirect_map.map(|map| std::sync::Arc::new(arc_swap::ArcSwap::from_pointee(map)));
+    if let (Some(path), Some(map)) = (args.redirect_map, &redirect_map) {
+        tokio::spawn(redirects::watch(path, map.clone(), bus.clone()));
+    }
+    let rate_limit_plans: Option<plans::SharedPlans> = rate_limit_plans.map(|plans| std::sync::Arc::new(arc_swap::ArcSwap::from_pointee(plans)));
+    if let (Some(path), Some(plans)) = (args.rate_limit_plans, &rate_limit_plans) {
+        tokio::spawn(plans::watch(path, plans.clone(), bus.clone()));
+    }
+
+    let shutdown = shutdown_signal();
+    tokio::pin!(shutdown);
+
+    if !blocklists.is_empty() {
+        tokio::spawn(blocklists.clone().refresh());
+    }
+    tokio::spawn(reload::run(matches, config.clone(), bus.clone(), args.control_plane.is_some()));
+    tokio::spawn(discovery::run(config.clone(), bus.clone()));
+    if let Some(socket) = args.docker.clone() {
+        tokio::spawn(docker::run(socket, config.clone(), bus.clone()));
+    }
+
+    if let Some(endpoint) = args.control_plane {
+        let node_id = args.node_id
+            .or_else(|| std::env::var("HOSTNAME").ok())
+            .unwrap_or_else(|| "reverse-http-proxy".to_string());
+        tokio::spawn(control::run(endpoint, node_id, config.clone(), bus.clone()));
+    }
+
+    let forbidden = response::LocalResponse::literal(403, "text/plain; charset=utf-8", "Forbidden")?;
+    let bad_gateway = response::LocalResponse::literal(502, "text/plain; charset=utf-8", "Bad Gateway\r\n")?;
+    let unavailable = response::LocalResponse::literal(503, "text/plain; charset=utf-8", "Service Unavailable\r\n")?;
+    let too_large = response::LocalResponse::literal(413, "text/plain; charset=utf-8", "Payload Too Large\r\n")?;
+    let bad_request = response::LocalResponse::literal(400, "text/plain; charset=utf-8", "Bad Request\r\n")?;
+    let proxy = std::sync::Arc::new(Proxy {
+        config: config.clone(),
+        metrics,
+        alerter,
+        not_found,
+        redirect_map,
+        well_known,
+        forbidden,
+        bad_gateway,
+        unavailable,
+        too_large,
+        bad_request,
+        scanner,
+        blocklists,
+        asn_db,
+        rate_limiter: ratelimit::RateLimiter::default(),
+        user_identity,
+        rate_limit_plans,
+        shaper: shaper::Shaper::default(),
+        retry_budget: retry::RetryBudget::new(args.retry_budget),
+        resolver: resolver.clone(),
+        tarpit: tarpit::Tarpit::new(args.tarpit_max, Duration::from_secs(args.tarpit_duration)),
+        quotas,
+        preflight_cache: preflight::PreflightCache::default(),
+        idempotency_cache: idempotency::IdempotencyCache::default(),
+        response_cache: cache::ResponseCache::default(),
+        tracer,
+        access_log,
+        scrubber,
+        capture,
+        error_pages,
+        lb,
+        regions,
+        health,
+        outliers,
+        faults,
+        cutovers,
+        transparent: args.transparent,
+        max_header_size,
+        sniffer,
+        psk,
+    });
+
+    for (tcp_listener, listener) in bound {
+        let tls = certificates.iter().find(|served| *served.listener == listener.name).map(certs::Served::acceptor);
+        tokio::spawn(serve(tcp_listener, listener, tls, proxy.clone(), handover.clone()));
+    }
+    if !certificates.is_empty() {
+        tokio::spawn(certs::watch(certificates, proxy.metrics.clone(), bus.clone()));
+    }
+    if let Some(issuer) = issuer {
+        tokio::spawn(acme::renew(issuer, orders, bus.clone()));
+    }
+
+    // The state is saved for the new process to restore before it starts
+    let prepare = {
+        let (config, state_file) = (config.clone(), args.state_file.clone());
+        move || save_state(&config, state_file.as_deref())
+    };
+    tokio::spawn(handover.clone().run(bus.clone(), prepare));
+    let took_over = handover.ready();
+    systemd::ready(took_over);
+    tokio::spawn(systemd::watchdog(took_over));
+
+    tokio::select! {
+        _ = handover.handed_over() => {}
+        _ = &mut shutdown => {}
+    }
+
+    if handover.is_handed_over() {
+        // The new process accepts from here on; this one only finishes what it has
+        println!("Finishing {} open connections", proxy.metrics.open_clients());
+        let drained = async {
+            while proxy.metrics.open_clients() > 0 {
+                tokio::time::sleep(Duration::from_millis(100)).await;
+            }
+        };
+        tokio::select! {
+            _ = drained => {}
+            _ = tokio::time::sleep(Duration::from_secs(args.drain_timeout)) => {
+                logging::warning(format!("Closing {} connections still open after {} s", proxy.metrics.open_clients(), args.drain_timeout));
+            }
+            _ = &mut shutdown => {}
+        }
+    }
+
+    println!("Shutting down");
+    // A process that handed its listeners over is no longer the service's main process
+    if !handover.is_handed_over() {
+        systemd::stopping();
+    }
+    proxy.access_log.flush().await;
+    // After a handover, the state is the new process's to save
+    if !handover.is_handed_over() {
+        save_state(&config, args.state_file.as_deref());
+    }
+
+    Ok(())
+}
+
+fn save_state(config: &routing::SharedConfig, path: Option<&std::path::Path>) {
+    if let Some(path) = path {
+        match config.load().snapshot().save(path) {
+            Ok(()) => println!("Saved state to {}", path.display()),
+            Err(e) => logging::error(format!("Failed to save state to {}: {}", path.display(), e)),
+        }
+    }
+}
+
+/// Accept clients on a listener until it is handed over to a new process
+async fn serve(tcp_listener: TcpListener, listener: listener::Listener, tls: Option<tokio_rustls::TlsAcceptor>, proxy: std::sync::Arc<Proxy>, handover: std::sync::Arc<handover::Handover>) {
+    let kind = listener.kind();
+    let name: std::sync::Arc<str> = listener.name.into();
+    loop {
+        let accepted = tokio::select! {
+            accepted = tcp_listener.accept() => accepted,
+            _ = handover.handed_over() => return,
+        };
+        let (tcp, client_addr) = match accepted {
+            Ok(accepted) => accepted,
+            Err(e) => {
+                logging::error(format!("Listener {} error: {}", name, e));
+                continue;
+            }
+        };
+        let (proxy, name, tls) = (proxy.clone(), name.clone(), tls.clone());
+        let connection = proxy.metrics.accept_connection(kind);
+        tokio::spawn(async move {
+            if let Some(tls) = tls {
+                proxy.handle_tls_connection(&tls, tcp, client_addr, &name).await;
+            } else if listener.psk {
+                proxy.handle_psk_connection(tcp, client_addr, &name).await;
+            } else {
+                proxy.accept(tcp, client_addr, &name).await;
+            }
+            drop(connection);
+        });
+    }
+}
+
+/// Stand-in for builds without the `psk` feature: it cannot be constructed, so no TLS-PSK
+/// connection is ever accepted
+#[cfg(not(feature = "psk"))]
+enum PskAcceptor {}
+
+#[cfg(not(feature = "psk"))]
+impl PskAcceptor {
+    fn load(_path: &std::path::Path) -> Result<Self, String> {
+        Err("--psk-keys requires a build with the `psk` feature (cargo build --features psk)".into())
+    }
+
+    async fn accept(&self, _tcp: TcpStream) -> Result<(TcpStream, String), Box<dyn std::error::Error + Send + Sync>> {
+        match *self {}
+    }
+}
+
+/// Resolve on Ctrl-C, or SIGTERM on Unix
+async fn shutdown_signal() {
+    #[cfg(unix)]
+    {
+        let mut terminate = match tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate()) {
+            Ok(signal) => signal,
+            Err(_) => return std::future::pending().await,
+        };
+        tokio::select! {
+            _ = tokio::signal::ctrl_c() => {}
+            _ = terminate.recv() => {}
+        }
+    }
+
+    #[cfg(not(unix))]
+    {
+        let _ = tokio::signal::ctrl_c().await;
     }
 }
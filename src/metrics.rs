@@ -0,0 +1,668 @@
+use crate::x509;
+use serde::Serialize;
+use std::borrow::Cow;
+use std::collections::{BTreeMap, HashMap, VecDeque};
+use std::fmt::Write;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// Upper bounds (in bytes) of the size histogram buckets; larger sizes land in a final overflow bucket
+const SIZE_BUCKETS: [u64; 8] = [1 << 10, 1 << 12, 1 << 14, 1 << 16, 1 << 18, 1 << 20, 1 << 22, 1 << 24];
+
+/// Upper bounds (in seconds) of the request duration histogram buckets
+const DURATION_BUCKETS: [f64; 11] = [0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0];
+
+/// Number of recent transfers retained for the "largest recent" report
+const RECENT_TRANSFERS: usize = 1024;
+
+/// Distribution of transfer sizes using fixed power-of-four buckets
+#[derive(Default)]
+struct SizeHistogram {
+    buckets: [u64; SIZE_BUCKETS.len() + 1],
+    count: u64,
+    sum: u64,
+    max: u64,
+}
+
+impl SizeHistogram {
+    fn record(&mut self, size: u64) {
+        let idx = SIZE_BUCKETS.iter().position(|&bound| size <= bound).unwrap_or(SIZE_BUCKETS.len());
+        self.buckets[idx] += 1;
+        self.count += 1;
+        self.sum += size;
+        self.max = self.max.max(size);
+    }
+
+    fn to_json(&self) -> serde_json::Value {
+        let buckets: Vec<serde_json::Value> = self.buckets.iter().enumerate()
+            .map(|(idx, count)| {
+                let le = SIZE_BUCKETS.get(idx).map_or(serde_json::Value::from("+Inf"), |&b| b.into());
+                serde_json::json!({ "le": le, "count": count })
+            })
+            .collect();
+
+        serde_json::json!({
+            "count": self.count,
+            "sum": self.sum,
+            "max": self.max,
+            "mean": self.sum.checked_div(self.count).unwrap_or(0),
+            "buckets": buckets,
+        })
+    }
+}
+
+/// Distribution of request durations, in the Prometheus histogram layout
+#[derive(Default, Clone)]
+struct DurationHistogram {
+    buckets: [u64; DURATION_BUCKETS.len() + 1],
+    count: u64,
+    sum: f64,
+}
+
+impl DurationHistogram {
+    fn record(&mut self, duration: Duration) {
+        let seconds = duration.as_secs_f64();
+        let idx = DURATION_BUCKETS.iter().position(|&bound| seconds <= bound).unwrap_or(DURATION_BUCKETS.len());
+        self.buckets[idx] += 1;
+        self.count += 1;
+        self.sum += seconds;
+    }
+
+    /// The durations recorded since `earlier`, an older copy of this histogram
+    fn since(&self, earlier: &DurationHistogram) -> DurationHistogram {
+        let mut delta = self.clone();
+        for (bucket, count) in delta.buckets.iter_mut().zip(earlier.buckets) {
+            *bucket -= count;
+        }
+        delta.count -= earlier.count;
+        delta.sum -= earlier.sum;
+        delta
+    }
+
+    fn merge(&mut self, other: &DurationHistogram) {
+        for (bucket, count) in self.buckets.iter_mut().zip(other.buckets) {
+            *bucket += count;
+        }
+        self.count += other.count;
+        self.sum += other.sum;
+    }
+
+    /// The `q` quantile in seconds, estimated like Prometheus' `histogram_quantile`: interpolated
+    /// within its bucket, and the largest bound for durations beyond it
+    fn quantile(&self, q: f64) -> Option<f64> {
+        if self.count == 0 {
+            return None;
+        }
+        let rank = q * self.count as f64;
+        let mut cumulative = 0;
+        for (idx, &count) in self.buckets.iter().enumerate() {
+            if count > 0 && (cumulative + count) as f64 >= rank {
+                let Some(&upper) = DURATION_BUCKETS.get(idx) else { break };
+                let lower = if idx == 0 { 0.0 } else { DURATION_BUCKETS[idx - 1] };
+                return Some(lower + (upper - lower) * (rank - cumulative as f64) / count as f64);
+            }
+            cumulative += count;
+        }
+        Some(DURATION_BUCKETS[DURATION_BUCKETS.len() - 1])
+    }
+
+    /// Count and p50/p95/p99 in milliseconds
+    fn to_json(&self) -> serde_json::Value {
+        let ms = |q| self.quantile(q).map(|seconds| (seconds * 1e6).round() / 1000.0);
+        serde_json::json!({
+            "count": self.count,
+            "p50_ms": ms(0.5),
+            "p95_ms": ms(0.95),
+            "p99_ms": ms(0.99),
+        })
+    }
+}
+
+#[derive(Default)]
+struct RouteSizes {
+    requests: SizeHistogram,
+    responses: SizeHistogram,
+}
+
+/// A single completed client connection, as seen by the size metrics
+#[derive(Clone, Serialize)]
+pub struct Transfer {
+    pub route: Arc<str>,
+    pub backend: Arc<str>,
+    pub client: String,
+    pub path: String,
+    pub request_bytes: u64,
+    pub response_bytes: u64,
+    /// Completion time in seconds since the Unix epoch
+    pub timestamp: u64,
+}
+
+/// Status classes by the first digit of the status
+const STATUS_CLASSES: [&str; 5] = ["1xx", "2xx", "3xx", "4xx", "5xx"];
+
+/// Proxied requests of one route to one backend
+#[derive(Default, Clone)]
+struct Traffic {
+    requests: u64,
+    request_bytes: u64,
+    response_bytes: u64,
+    /// Completed exchanges by status class of the backend's response
+    statuses: [u64; STATUS_CLASSES.len()],
+    /// Requests whose client went away before their response was complete
+    aborted: u64,
+    durations: DurationHistogram,
+    /// Time to connect to the backend (including its TLS handshake)
+    connects: DurationHistogram,
+    /// Time from the request to the first byte of the backend's response
+    first_bytes: DurationHistogram,
+}
+
+impl Traffic {
+    fn record_response(&mut self, status: Option<u16>, request_bytes: u64, response_bytes: u64, first_byte: Option<Duration>, duration: Duration) {
+        self.request_bytes += request_bytes;
+        self.response_bytes += response_bytes;
+        if let Some(status @ 100..=599) = status {
+            self.statuses[status as usize / 100 - 1] += 1;
+        }
+        if let Some(first_byte) = first_byte {
+            self.first_bytes.record(first_byte);
+        }
+        self.durations.record(duration);
+    }
+
+    fn merge(&mut self, other: &Traffic) {
+        self.requests += other.requests;
+        self.request_bytes += other.request_bytes;
+        self.response_bytes += other.response_bytes;
+        for (class, count) in self.statuses.iter_mut().zip(other.statuses) {
+            *class += count;
+        }
+        self.aborted += other.aborted;
+        self.durations.merge(&other.durations);
+        self.connects.merge(&other.connects);
+        self.first_bytes.merge(&other.first_bytes);
+    }
+
+    fn to_json(&self) -> serde_json::Value {
+        let statuses: serde_json::Map<String, serde_json::Value> = STATUS_CLASSES.iter().zip(self.statuses)
+            .map(|(class, count)| (class.to_string(), count.into()))
+            .collect();
+        serde_json::json!({
+            "requests": self.requests,
+            "statuses": statuses,
+            "aborted": self.aborted,
+            "request_bytes": self.request_bytes,
+            "response_bytes": self.response_bytes,
+            "duration": self.durations.to_json(),
+            "connect": self.connects.to_json(),
+            "first_byte": self.first_bytes.to_json(),
+        })
+    }
+}
+
+#[derive(Default)]
+struct Inner {
+    routes: HashMap<Arc<str>, RouteSizes>,
+    recent: VecDeque<Transfer>,
+    /// Open backend connections by backend
+    active: HashMap<Arc<str>, u64>,
+    /// Requests by route and backend
+    traffic: HashMap<(Arc<str>, Arc<str>), Traffic>,
+    /// Requests by route since the last summary (`--metrics-summary`)
+    window: HashMap<Arc<str>, Traffic>,
+    /// Failed backend connection attempts by backend
+    connect_failures: HashMap<Arc<str>, u64>,
+    /// When backends' TLS certificates expire, as health checks saw them
+    certificates: HashMap<Arc<str>, SystemTime>,
+    /// When the TLS certificates of `;cert` listeners expire, by listener
+    listener_certificates: BTreeMap<Arc<str>, SystemTime>,
+    /// Client connections accepted, and currently open, by listener
+    clients_accepted: BTreeMap<&'static str, u64>,
+    clients_active: BTreeMap<&'static str, u64>,
+}
+
+/// A metric value pushed to a collector (`--statsd`)
+pub struct Sample {
+    pub name: Cow<'static, str>,
+    pub labels: Vec<(&'static str, Arc<str>)>,
+    pub value: f64,
+    /// Counters carry the change since the last push, gauges the current value
+    pub counter: bool,
+}
+
+/// The totals as of the last push, which the next one reports changes against
+#[derive(Default)]
+pub struct Pushed {
+    traffic: HashMap<(Arc<str>, Arc<str>), Traffic>,
+    connect_failures: HashMap<Arc<str>, u64>,
+    clients_accepted: BTreeMap<&'static str, u64>,
+}
+
+/// Request/response size metrics, shared between the proxy and the admin listener
+pub struct Metrics {
+    inner: Mutex<Inner>,
+    started: SystemTime,
+}
+
+impl Default for Metrics {
+    fn default() -> Self {
+        Metrics { inner: Mutex::default(), started: SystemTime::now() }
+    }
+}
+
+impl Metrics {
+    /// Count a client connection to a listener (`http`, `psk`) as open until the returned guard is dropped
+    pub fn accept_connection(self: &Arc<Self>, listener: &'static str) -> ClientConnection {
+        let mut inner = self.inner.lock().unwrap();
+        *inner.clients_accepted.entry(listener).or_default() += 1;
+        *inner.clients_active.entry(listener).or_default() += 1;
+        ClientConnection { metrics: self.clone(), listener }
+    }
+
+    /// Client connections open on all listeners
+    pub fn open_clients(&self) -> u64 {
+        self.inner.lock().unwrap().clients_active.values().sum()
+    }
+
+    /// Count a request routed to a backend, before connecting to it
+    pub fn record_request(&self, route: &Arc<str>, backend: &Arc<str>) {
+        let mut inner = self.inner.lock().unwrap();
+        inner.traffic.entry((route.clone(), backend.clone())).or_default().requests += 1;
+        inner.window.entry(route.clone()).or_default().requests += 1;
+    }
+
+    /// Record the time a successful connection to a backend took
+    pub fn record_connect(&self, route: &Arc<str>, backend: &Arc<str>, duration: Duration) {
+        let mut inner = self.inner.lock().unwrap();
+        inner.traffic.entry((route.clone(), backend.clone())).or_default().connects.record(duration);
+        inner.window.entry(route.clone()).or_default().connects.record(duration);
+    }
+
+    /// Count a request whose client went away before its response was complete
+    pub fn record_abort(&self, route: &Arc<str>, backend: &Arc<str>) {
+        let mut inner = self.inner.lock().unwrap();
+        inner.traffic.entry((route.clone(), backend.clone())).or_default().aborted += 1;
+        inner.window.entry(route.clone()).or_default().aborted += 1;
+    }
+
+    /// Count a failed connection attempt to a backend
+    pub fn record_connect_failure(&self, backend: &Arc<str>) {
+        *self.inner.lock().unwrap().connect_failures.entry(backend.clone()).or_default() += 1;
+    }
+
+    /// Note when a backend's TLS certificate expires, as seen by a health check
+    pub fn record_backend_certificate(&self, backend: &Arc<str>, expires: SystemTime) {
+        self.inner.lock().unwrap().certificates.insert(backend.clone(), expires);
+    }
+
+    /// Note when the certificate a `;cert` listener serves now expires
+    pub fn record_listener_certificate(&self, listener: &Arc<str>, expires: SystemTime) {
+        self.inner.lock().unwrap().listener_certificates.insert(listener.clone(), expires);
+    }
+
+    /// Forget the certificates of backends no longer checked
+    pub fn retain_backend_certificates(&self, keep: impl Fn(&str) -> bool) {
+        self.inner.lock().unwrap().certificates.retain(|backend, _| keep(backend));
+    }
+
+    /// Record a completed transfer; route and backend are shared with the route table, not copied.
+    /// `first_byte` and `duration` run from the request to the backend's first response byte and
+    /// to the end of the connection.
+    #[allow(clippy::too_many_arguments)]
+    pub fn record_transfer(&self, route: &Arc<str>, backend: &Arc<str>, client: &str, path: &str, request_bytes: u64, response_bytes: u64, status: Option<u16>, first_byte: Option<Duration>, duration: Duration) {
+        let timestamp = SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |d| d.as_secs());
+        let mut inner = self.inner.lock().unwrap();
+
+        inner.traffic.entry((route.clone(), backend.clone())).or_default()
+            .record_response(status, request_bytes, response_bytes, first_byte, duration);
+        inner.window.entry(route.clone()).or_default()
+            .record_response(status, request_bytes, response_bytes, first_byte, duration);
+
+        let sizes = match inner.routes.get_mut(&**route) {
+            Some(sizes) => sizes,
+            None => inner.routes.entry(route.clone()).or_default(),
+        };
+        sizes.requests.record(request_bytes);
+        sizes.responses.record(response_bytes);
+
+        if inner.recent.len() == RECENT_TRANSFERS {
+            inner.recent.pop_front();
+        }
+        inner.recent.push_back(Transfer {
+            route: route.clone(),
+            backend: backend.clone(),
+            client: client.to_string(),
+            path: path.to_string(),
+            request_bytes,
+            response_bytes,
+            timestamp,
+        });
+    }
+
+    /// Count a connection to a backend as open until the returned guard is dropped
+    pub fn open_connection(self: &Arc<Self>, backend: &Arc<str>) -> OpenConnection {
+        *self.inner.lock().unwrap().active.entry(backend.clone()).or_default() += 1;
+        OpenConnection { metrics: self.clone(), backend: backend.clone() }
+    }
+
+    /// Number of open connections to a backend
+    pub fn active_connections(&self, backend: &str) -> u64 {
+        self.inner.lock().unwrap().active.get(backend).copied().unwrap_or(0)
+    }
+
+    /// Size distributions per route
+    pub fn sizes_json(&self) -> serde_json::Value {
+        let inner = self.inner.lock().unwrap();
+        let routes: serde_json::Map<String, serde_json::Value> = inner.routes.iter()
+            .map(|(route, sizes)| {
+                (route.to_string(), serde_json::json!({
+                    "requests": sizes.requests.to_json(),
+                    "responses": sizes.responses.to_json(),
+                }))
+            })
+            .collect();
+
+        serde_json::json!({ "routes": routes })
+    }
+
+    /// Request counts, status classes, bytes and latency percentiles per route and per backend
+    pub fn latency_json(&self) -> serde_json::Value {
+        let inner = self.inner.lock().unwrap();
+        let mut routes: BTreeMap<&str, Traffic> = BTreeMap::new();
+        let mut backends: BTreeMap<&str, Traffic> = BTreeMap::new();
+        for ((route, backend), traffic) in &inner.traffic {
+            routes.entry(route).or_default().merge(traffic);
+            backends.entry(backend).or_default().merge(traffic);
+        }
+        let to_json = |totals: BTreeMap<&str, Traffic>| totals.into_iter()
+            .map(|(name, traffic)| (name.to_string(), traffic.to_json()))
+            .collect::<serde_json::Map<_, _>>();
+
+        serde_json::json!({ "routes": to_json(routes), "backends": to_json(backends) })
+    }
+
+    /// One line per route with requests since the last summary, and a fresh window
+    pub fn take_summary(&self) -> Vec<String> {
+        let window = std::mem::take(&mut self.inner.lock().unwrap().window);
+        let mut routes: Vec<_> = window.into_iter().filter(|(_, traffic)| traffic.requests > 0).collect();
+        routes.sort_by(|(a, _), (b, _)| a.cmp(b));
+        routes.into_iter().map(|(route, traffic)| {
+            let mut line = format!("Route {}: {} requests", route, traffic.requests);
+            let statuses: Vec<String> = STATUS_CLASSES.iter().zip(traffic.statuses)
+                .filter(|(_, count)| *count > 0)
+                .map(|(class, count)| format!("{} {}", class, count))
+                .collect();
+            let mut statuses = statuses;
+            if traffic.aborted > 0 {
+                statuses.push(format!("{} aborted by clients", traffic.aborted));
+            }
+            if !statuses.is_empty() {
+                let _ = write!(line, " ({})", statuses.join(", "));
+            }
+            for (name, q) in [("p50", 0.5), ("p95", 0.95), ("p99", 0.99)] {
+                if let Some(seconds) = traffic.durations.quantile(q) {
+                    let _ = write!(line, ", {} {:.1} ms", name, seconds * 1000.0);
+                }
+            }
+            let _ = write!(line, ", {} bytes in, {} bytes out", traffic.request_bytes, traffic.response_bytes);
+            line
+        }).collect()
+    }
+
+    /// Counter changes since `pushed`, which is updated, and the current gauges: per route and
+    /// backend the requests, bytes, responses by status class, 5xx errors and their rate, and latency
+    /// percentiles of the requests completed in between
+    pub fn samples(&self, pushed: &mut Pushed) -> Vec<Sample> {
+        let inner = self.inner.lock().unwrap();
+        let mut samples = Vec::new();
+        let mut push = |name: Cow<'static, str>, labels: Vec<(&'static str, Arc<str>)>, value: f64, counter: bool| {
+            if !counter || value > 0.0 {
+                samples.push(Sample { name, labels, value, counter });
+            }
+        };
+
+        for (listener, &count) in &inner.clients_accepted {
+            let before = pushed.clients_accepted.insert(listener, count).unwrap_or(0);
+            push("client_connections".into(), vec![("listener", Arc::from(*listener))], (count - before) as f64, true);
+        }
+        for (listener, &count) in &inner.clients_active {
+            push("client_connections_active".into(), vec![("listener", Arc::from(*listener))], count as f64, false);
+        }
+
+        for ((route, backend), traffic) in &inner.traffic {
+            let labels = || vec![("route", route.clone()), ("backend", backend.clone())];
+            let before = pushed.traffic.insert((route.clone(), backend.clone()), traffic.clone()).unwrap_or_default();
+            push("requests".into(), labels(), (traffic.requests - before.requests) as f64, true);
+            push("request_bytes".into(), labels(), (traffic.request_bytes - before.request_bytes) as f64, true);
+            push("response_bytes".into(), labels(), (traffic.response_bytes - before.response_bytes) as f64, true);
+            let mut responses = 0;
+            for ((class, count), earlier) in STATUS_CLASSES.iter().zip(traffic.statuses).zip(before.statuses) {
+                let mut class_labels = labels();
+                class_labels.push(("status_class", Arc::from(*class)));
+                push("responses".into(), class_labels, (count - earlier) as f64, true);
+                responses += count - earlier;
+            }
+            push("client_aborts".into(), labels(), (traffic.aborted - before.aborted) as f64, true);
+            let errors = traffic.statuses[4] - before.statuses[4];
+            push("errors".into(), labels(), errors as f64, true);
+            if responses > 0 {
+                push("error_rate".into(), labels(), errors as f64 * 100.0 / responses as f64, false);
+            }
+
+            let durations = [
+                ("request_duration_ms", traffic.durations.since(&before.durations)),
+                ("backend_connect_duration_ms", traffic.connects.since(&before.connects)),
+                ("response_first_byte_duration_ms", traffic.first_bytes.since(&before.first_bytes)),
+            ];
+            for (name, histogram) in durations {
+                for (quantile, q) in [("p50", 0.5), ("p95", 0.95), ("p99", 0.99)] {
+                    if let Some(seconds) = histogram.quantile(q) {
+                        push(format!("{}.{}", name, quantile).into(), labels(), seconds * 1000.0, false);
+                    }
+                }
+            }
+        }
+
+        for (backend, &count) in &inner.connect_failures {
+            let before = pushed.connect_failures.insert(backend.clone(), count).unwrap_or(0);
+            push("backend_connect_failures".into(), vec![("backend", backend.clone())], (count - before) as f64, true);
+        }
+        for (backend, &count) in &inner.active {
+            push("backend_connections_active".into(), vec![("backend", backend.clone())], count as f64, false);
+        }
+        for (backend, &expires) in &inner.certificates {
+            push("backend_tls_certificate_days_remaining".into(), vec![("backend", backend.clone())], x509::days_remaining(expires), false);
+        }
+        for (listener, &expires) in &inner.listener_certificates {
+            push("tls_certificate_expiry_timestamp_seconds".into(), vec![("listener", listener.clone())], expires.duration_since(UNIX_EPOCH).map_or(0, |d| d.as_secs()) as f64, false);
+        }
+        samples
+    }
+
+    /// The `n` largest requests and responses among the recently completed transfers
+    pub fn largest_json(&self, n: usize) -> serde_json::Value {
+        let inner = self.inner.lock().unwrap();
+
+        let mut by_request: Vec<&Transfer> = inner.recent.iter().collect();
+        by_request.sort_by_key(|t| std::cmp::Reverse(t.request_bytes));
+        by_request.truncate(n);
+
+        let mut by_response: Vec<&Transfer> = inner.recent.iter().collect();
+        by_response.sort_by_key(|t| std::cmp::Reverse(t.response_bytes));
+        by_response.truncate(n);
+
+        serde_json::json!({
+            "window": inner.recent.len(),
+            "requests": by_request,
+            "responses": by_response,
+        })
+    }
+
+    /// All metrics in the Prometheus text exposition format
+    pub fn prometheus_text(&self) -> String {
+        let inner = self.inner.lock().unwrap();
+        let mut out = String::new();
+
+        family(&mut out, "build_info", "gauge", "Always 1, with the version of the proxy");
+        sample(&mut out, "build_info", &[("version", env!("CARGO_PKG_VERSION"))], 1);
+        family(&mut out, "start_time_seconds", "gauge", "When the proxy started, in seconds since the Unix epoch");
+        sample(&mut out, "start_time_seconds", &[], self.started.duration_since(UNIX_EPOCH).map_or(0, |d| d.as_secs()));
+        family(&mut out, "client_connections_total", "counter", "Client connections accepted, by listener");
+        for (listener, count) in &inner.clients_accepted {
+            sample(&mut out, "client_connections_total", &[("listener", listener)], *count);
+        }
+        family(&mut out, "client_connections_active", "gauge", "Open client connections, by listener");
+        for (listener, count) in &inner.clients_active {
+            sample(&mut out, "client_connections_active", &[("listener", listener)], *count);
+        }
+
+        let mut traffic: Vec<_> = inner.traffic.iter().collect();
+        traffic.sort_by_key(|(key, _)| *key);
+        let counters: [Counter<Traffic>; 4] = [
+            ("requests_total", "Requests proxied, by route and backend", |t| t.requests),
+            ("client_aborts_total", "Requests whose client went away before the response was complete, by route and backend", |t| t.aborted),
+            ("request_bytes_total", "Bytes sent to backends (request heads and bodies)", |t| t.request_bytes),
+            ("response_bytes_total", "Bytes sent to clients from backends", |t| t.response_bytes),
+        ];
+        for (name, help, value) in counters {
+            family(&mut out, name, "counter", help);
+            for ((route, backend), t) in &traffic {
+                sample(&mut out, name, &[("route", route), ("backend", backend)], value(t));
+            }
+        }
+
+        family(&mut out, "responses_total", "counter", "Backend responses, by route, backend and status class");
+        for ((route, backend), t) in &traffic {
+            for (class, count) in STATUS_CLASSES.iter().zip(t.statuses) {
+                sample(&mut out, "responses_total", &[("route", route), ("backend", backend), ("status_class", class)], count);
+            }
+        }
+
+        let durations: [Durations<Traffic>; 3] = [
+            ("request_duration_seconds", "Time from the request to the end of the proxied connection", |t| &t.durations),
+            ("backend_connect_duration_seconds", "Time to connect to the backend, TLS handshake included", |t| &t.connects),
+            ("response_first_byte_duration_seconds", "Time from the request to the first byte of the backend's response", |t| &t.first_bytes),
+        ];
+        for (name, help, value) in durations {
+            family(&mut out, name, "histogram", help);
+            for ((route, backend), t) in &traffic {
+                let labels = [("route", &**route), ("backend", &**backend)];
+                let bounds = DURATION_BUCKETS.iter().map(|bound| bound.to_string());
+                let h = value(t);
+                histogram(&mut out, name, &labels, bounds, &h.buckets, h.count, h.sum);
+            }
+        }
+
+        let mut routes: Vec<_> = inner.routes.iter().collect();
+        routes.sort_by_key(|(route, _)| *route);
+        let sizes = [
+            ("request_size_bytes", "Bytes per request, by route", false),
+            ("response_size_bytes", "Bytes per response, by route", true),
+        ];
+        for (name, help, responses) in sizes {
+            family(&mut out, name, "histogram", help);
+            for (route, route_sizes) in &routes {
+                let h = if responses { &route_sizes.responses } else { &route_sizes.requests };
+                let bounds = SIZE_BUCKETS.iter().map(|bound| bound.to_string());
+                histogram(&mut out, name, &[("route", route)], bounds, &h.buckets, h.count, h.sum as f64);
+            }
+        }
+
+        let mut active: Vec<_> = inner.active.iter().collect();
+        active.sort();
+        family(&mut out, "backend_connections_active", "gauge", "Open backend connections, by backend");
+        for (backend, count) in active {
+            sample(&mut out, "backend_connections_active", &[("backend", backend)], *count);
+        }
+        let mut failures: Vec<_> = inner.connect_failures.iter().collect();
+        failures.sort();
+        family(&mut out, "backend_connect_failures_total", "counter", "Failed backend connection attempts, by backend");
+        for (backend, count) in failures {
+            sample(&mut out, "backend_connect_failures_total", &[("backend", backend)], *count);
+        }
+        let mut certificates: Vec<_> = inner.certificates.iter().collect();
+        certificates.sort();
+        family(&mut out, "backend_tls_certificate_days_remaining", "gauge", "Days until the TLS certificate of the backend expires, as of its latest health check; negative once expired");
+        for (backend, expires) in certificates {
+            sample(&mut out, "backend_tls_certificate_days_remaining", &[("backend", backend)], format!("{:.2}", x509::days_remaining(*expires)));
+        }
+        family(&mut out, "tls_certificate_expiry_timestamp_seconds", "gauge", "When the TLS certificate served on the listener expires (its notAfter), in Unix seconds");
+        for (listener, expires) in &inner.listener_certificates {
+            sample(&mut out, "tls_certificate_expiry_timestamp_seconds", &[("listener", listener)], expires.duration_since(UNIX_EPOCH).map_or(0, |d| d.as_secs()));
+        }
+        out
+    }
+}
+
+/// A counter's name, its help text, and how to get its value from the tracked data
+type Counter<T> = (&'static str, &'static str, fn(&T) -> u64);
+
+/// A duration histogram's name, its help text, and where it is in the tracked data
+type Durations<T> = (&'static str, &'static str, fn(&T) -> &DurationHistogram);
+
+/// Prefix of all exported metric names
+pub const METRIC_PREFIX: &str = "reverse_proxy_";
+
+fn family(out: &mut String, name: &str, kind: &str, help: &str) {
+    let _ = writeln!(out, "# HELP {}{} {}\n# TYPE {}{} {}", METRIC_PREFIX, name, help, METRIC_PREFIX, name, kind);
+}
+
+fn sample(out: &mut String, name: &str, labels: &[(&str, &str)], value: impl std::fmt::Display) {
+    let _ = writeln!(out, "{}{}{} {}", METRIC_PREFIX, name, label_set(labels), value);
+}
+
+/// Cumulative `_bucket` samples from per-bucket counts, the last bucket being `+Inf`, then `_sum` and `_count`
+fn histogram(out: &mut String, name: &str, labels: &[(&str, &str)], bounds: impl Iterator<Item = String>, buckets: &[u64], count: u64, sum: f64) {
+    let mut cumulative = 0;
+    for (bound, bucket) in bounds.chain(std::iter::once("+Inf".to_string())).zip(buckets) {
+        cumulative += bucket;
+        let mut bucket_labels = labels.to_vec();
+        bucket_labels.push(("le", &bound));
+        sample(out, &format!("{}_bucket", name), &bucket_labels, cumulative);
+    }
+    sample(out, &format!("{}_sum", name), labels, sum);
+    sample(out, &format!("{}_count", name), labels, count);
+}
+
+/// `{name="value",...}` with values escaped, or nothing without labels
+fn label_set(labels: &[(&str, &str)]) -> String {
+    if labels.is_empty() {
+        return String::new();
+    }
+    let pairs: Vec<String> = labels.iter()
+        .map(|(name, value)| format!("{}=\"{}\"", name, value.replace('\\', "\\\\").replace('"', "\\\"").replace('\n', "\\n")))
+        .collect();
+    format!("{{{}}}", pairs.join(","))
+}
+
+/// An open client connection, counted until dropped
+pub struct ClientConnection {
+    metrics: Arc<Metrics>,
+    listener: &'static str,
+}
+
+impl Drop for ClientConnection {
+    fn drop(&mut self) {
+        if let Some(count) = self.metrics.inner.lock().unwrap().clients_active.get_mut(self.listener) {
+            *count -= 1;
+        }
+    }
+}
+
+/// An open backend connection, counted until dropped
+pub struct OpenConnection {
+    metrics: Arc<Metrics>,
+    backend: Arc<str>,
+}
+
+impl Drop for OpenConnection {
+    fn drop(&mut self) {
+        let mut inner = self.metrics.inner.lock().unwrap();
+        if let Some(count) = inner.active.get_mut(&self.backend) {
+            *count -= 1;
+            if *count == 0 {
+                inner.active.remove(&self.backend);
+            }
+        }
+    }
+}
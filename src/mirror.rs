@@ -0,0 +1,152 @@
+//! Traffic mirroring (`;mirror=HOST:PORT[:PERCENT]`): a share of a route's connections is copied to
+//! a shadow backend as the client sends it. The shadow's responses are read and discarded, and
+//! nothing that happens to it reaches the client: a shadow that fails or falls behind is cut off.
+
+use crate::logging;
+use crate::pool;
+use crate::resolver::Resolver;
+use crate::routing::{Backend, BackendTls};
+use crate::upstream::BackendStream;
+use std::io;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncWriteExt, ReadBuf};
+use tokio::net::TcpStream;
+use tokio::sync::{mpsc, oneshot};
+
+/// Reads from the client queued for a shadow; one that falls further behind is cut off rather than slow the client down
+const QUEUE: usize = 64;
+
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// How long a shadow's responses are still read after the client's connection has ended
+const LINGER: Duration = Duration::from_secs(10);
+
+#[derive(Clone)]
+pub struct Mirror {
+    pub backend: Backend,
+    /// Share of the route's connections mirrored, above 0 and up to 1
+    rate: f64,
+}
+
+impl Mirror {
+    /// `HOST:PORT`, all of the route's connections, or `HOST:PORT:PERCENT`
+    pub fn parse(value: &str, route: &str) -> Result<Self, String> {
+        let (address, percent) = pool::split_weight(value);
+        let percent = match percent {
+            Some(percent) => percent.parse::<f64>().ok().filter(|&percent| percent > 0.0 && percent <= 100.0)
+                .ok_or_else(|| format!("Invalid mirror '{}' in route '{}'. Expected format: mirror=HOST:PORT[:PERCENT], a percentage above 0 and up to 100", value, route))?,
+            None => 100.0,
+        };
+        Ok(Mirror { backend: Backend::parse(address, route)?, rate: percent / 100.0 })
+    }
+
+    /// Whether a connection is mirrored
+    pub fn sample(&self) -> bool {
+        self.rate >= 1.0 || (crate::otel::random_id() as f64 / u64::MAX as f64) < self.rate
+    }
+
+    /// Connect to the shadow in the background and send it `request`, the start of the connection
+    /// as forwarded to it, followed by what the returned `Shadow` is given
+    pub fn start(&self, route: &str, request: Vec<u8>, tls: Option<BackendTls>, resolver: Arc<Resolver>) -> Shadow {
+        let (sender, receiver) = mpsc::channel(QUEUE);
+        let shadow = Shadow { sender: Some(sender), route: route.to_string(), backend: self.backend.name.to_string() };
+        let (backend, route) = (self.backend.clone(), route.to_string());
+        tokio::spawn(async move {
+            if let Err(e) = run(&backend, tls, &resolver, request, receiver).await {
+                logging::warning(format!("Mirroring route {} to {} failed: {}", route, backend, e));
+            }
+        });
+        shadow
+    }
+}
+
+async fn run(backend: &Backend, tls: Option<BackendTls>, resolver: &Arc<Resolver>, request: Vec<u8>, mut receiver: mpsc::Receiver<Vec<u8>>) -> io::Result<()> {
+    let connecting = async {
+        let tcp = resolver.connect(backend, &mut None, TcpStream::connect).await?;
+        BackendStream::connect(tcp, backend, tls.as_ref()).await
+    };
+    let stream = tokio::time::timeout(CONNECT_TIMEOUT, connecting).await
+        .map_err(|_| io::Error::new(io::ErrorKind::TimedOut, "no connection within 5 s"))??;
+    let (mut read, mut write) = tokio::io::split(stream);
+    let (done, client_done) = oneshot::channel::<()>();
+
+    let upstream = async {
+        write.write_all(&request).await?;
+        while let Some(bytes) = receiver.recv().await {
+            write.write_all(&bytes).await?;
+        }
+        let _ = done.send(());
+        write.shutdown().await
+    };
+    // Responses are only read to be discarded, until the shadow closes or a while after the client has
+    let downstream = async {
+        let mut discard = tokio::io::sink();
+        tokio::select! {
+            discarded = tokio::io::copy(&mut read, &mut discard) => discarded.map(drop),
+            _ = async {
+                let _ = client_done.await;
+                tokio::time::sleep(LINGER).await;
+            } => Ok(()),
+        }
+    };
+    tokio::try_join!(upstream, downstream).map(drop)
+}
+
+/// The sending end of a mirrored connection
+pub struct Shadow {
+    /// None once the shadow is cut off
+    sender: Option<mpsc::Sender<Vec<u8>>>,
+    route: String,
+    backend: String,
+}
+
+impl Shadow {
+    pub fn backend(&self) -> &str {
+        &self.backend
+    }
+
+    /// Queue bytes the client sent for the shadow
+    fn send(&mut self, bytes: &[u8]) {
+        let Some(sender) = &self.sender else {
+            return;
+        };
+        match sender.try_send(bytes.to_vec()) {
+            Ok(()) => {}
+            Err(mpsc::error::TrySendError::Full(_)) => {
+                logging::warning(format!("Mirror {} of route {} fell behind; the rest of the connection is not mirrored", self.backend, self.route));
+                self.sender = None;
+            }
+            // The shadow failed, which has been logged
+            Err(mpsc::error::TrySendError::Closed(_)) => self.sender = None,
+        }
+    }
+}
+
+/// A client reader copying what is read to a shadow, if the connection is mirrored
+pub struct Mirrored<'s, R> {
+    inner: R,
+    shadow: Option<&'s mut Shadow>,
+}
+
+impl<'s, R> Mirrored<'s, R> {
+    pub fn new(inner: R, shadow: Option<&'s mut Shadow>) -> Self {
+        Mirrored { inner, shadow }
+    }
+}
+
+impl<R: AsyncRead + Unpin> AsyncRead for Mirrored<'_, R> {
+    fn poll_read(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        let before = buf.filled().len();
+        let polled = Pin::new(&mut this.inner).poll_read(cx, buf);
+        if let (Poll::Ready(Ok(())), Some(shadow)) = (&polled, this.shadow.as_deref_mut()) {
+            if buf.filled().len() > before {
+                shadow.send(&buf.filled()[before..]);
+            }
+        }
+        polled
+    }
+}
@@ -0,0 +1,267 @@
+//! OpenTelemetry tracing: a span for each proxied request, with child spans for the route match, the
+//! backend connection and the transfer, exported as OTLP/HTTP JSON. W3C `traceparent` headers from
+//! clients are continued, and backends receive one naming the proxy's transfer span as their parent.
+
+use crate::client::{self, Url};
+use crate::logging;
+use serde_json::{json, Value};
+use std::collections::hash_map::RandomState;
+use std::hash::{BuildHasher, Hasher};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::OnceLock;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tokio::sync::mpsc;
+
+/// Spans per export request
+const BATCH_SIZE: usize = 512;
+
+/// Longest a finished span waits for its batch to be exported
+const EXPORT_INTERVAL: Duration = Duration::from_secs(5);
+
+/// Finished traces waiting for the exporter; further ones are dropped while the collector lags
+const QUEUE_SIZE: usize = 4096;
+
+/// Exports the spans of finished requests to an OTLP collector
+pub struct Tracer {
+    sender: mpsc::Sender<Vec<Span>>,
+}
+
+/// The OTLP/HTTP traces URL for `--otlp-endpoint`: a bare collector address gets the standard
+/// `/v1/traces` path
+pub fn endpoint(url: &str) -> Result<Url, String> {
+    let mut url = Url::parse(url)?;
+    if url.path == "/" {
+        url.path = "/v1/traces".to_string();
+    }
+    Ok(url)
+}
+
+impl Tracer {
+    /// Spawn the exporter task
+    pub fn start(url: Url, service_name: String) -> Self {
+        let (sender, receiver) = mpsc::channel(QUEUE_SIZE);
+        tokio::spawn(export(url, service_name, receiver));
+        Tracer { sender }
+    }
+}
+
+/// The span kinds used here, with their OTLP numbers
+#[derive(Clone, Copy)]
+pub enum Kind {
+    Internal = 1,
+    Server = 2,
+    Client = 3,
+}
+
+/// A span being recorded; ended with [`RequestTrace::end`]
+pub struct Span {
+    trace_id: u128,
+    name: String,
+    kind: Kind,
+    span_id: u64,
+    parent: Option<u64>,
+    start: SystemTime,
+    end: SystemTime,
+    attributes: Vec<(&'static str, Value)>,
+    error: Option<String>,
+}
+
+impl Span {
+    pub fn rename(&mut self, name: String) {
+        self.name = name;
+    }
+
+    /// Restart the span's clock, for a span whose id was needed before its work began
+    pub fn begin(&mut self) {
+        self.start = SystemTime::now();
+    }
+
+    /// Set an attribute; a no-op when tracing is off
+    pub fn attribute(&mut self, key: &'static str, value: impl Into<Value>) {
+        if self.span_id != 0 {
+            self.attributes.push((key, value.into()));
+        }
+    }
+
+    /// Mark the span as failed
+    pub fn error(&mut self, message: impl ToString) {
+        if self.span_id != 0 {
+            self.error = Some(message.to_string());
+        }
+    }
+}
+
+/// The trace of one request: its server span and the child spans ended so far. Without a tracer
+/// (or when the client's trace is not sampled) nothing is recorded or exported.
+pub struct RequestTrace<'a> {
+    tracer: Option<&'a Tracer>,
+    sampled: bool,
+    root: Span,
+    children: Vec<Span>,
+}
+
+impl<'a> RequestTrace<'a> {
+    /// Start the trace of a request, continuing the caller's trace if its `traceparent` is valid
+    pub fn new(tracer: Option<&'a Tracer>, name: String, traceparent: Option<&str>) -> Self {
+        let (trace_id, parent, sampled) = match tracer.and(traceparent).and_then(parse_traceparent) {
+            Some((trace_id, parent, sampled)) => (trace_id, Some(parent), sampled),
+            None if tracer.is_some() => ((random_id() as u128) << 64 | random_id() as u128, None, true),
+            None => (0, None, false),
+        };
+        let root = span(trace_id, name, Kind::Server, tracer.map_or(0, |_| random_id()), parent);
+        RequestTrace { tracer, sampled, root, children: Vec::new() }
+    }
+
+    /// Start a child span of the request
+    pub fn start(&self, name: &str, kind: Kind) -> Span {
+        let (span_id, name) = match self.tracer {
+            Some(_) => (random_id(), name.to_string()),
+            None => (0, String::new()),
+        };
+        span(self.root.trace_id, name, kind, span_id, Some(self.root.span_id))
+    }
+
+    /// End a child span
+    pub fn end(&mut self, mut span: Span) {
+        if span.span_id != 0 {
+            span.end = SystemTime::now();
+            self.children.push(span);
+        }
+    }
+
+    /// The request's server span, for its attributes
+    pub fn root(&mut self) -> &mut Span {
+        &mut self.root
+    }
+
+    /// The `traceparent` header for requests made within `span`, or None when tracing is off
+    pub fn traceparent(&self, span: &Span) -> Option<String> {
+        self.tracer?;
+        Some(format!("00-{:032x}-{:016x}-{:02x}", span.trace_id, span.span_id, self.sampled as u8))
+    }
+
+    /// End the server span and queue the trace for export
+    pub fn finish(mut self) {
+        let Some(tracer) = self.tracer.filter(|_| self.sampled) else {
+            return;
+        };
+        self.root.end = SystemTime::now();
+        let mut spans = std::mem::take(&mut self.children);
+        spans.push(self.root);
+        // A full queue means the collector is not keeping up; losing traces beats blocking requests
+        let _ = tracer.sender.try_send(spans);
+    }
+}
+
+fn span(trace_id: u128, name: String, kind: Kind, span_id: u64, parent: Option<u64>) -> Span {
+    let now = SystemTime::now();
+    Span { trace_id, name, kind, span_id, parent, start: now, end: now, attributes: Vec::new(), error: None }
+}
+
+/// Trace id, parent span id and sampled flag of a W3C `traceparent` header
+/// (`00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01`)
+fn parse_traceparent(value: &str) -> Option<(u128, u64, bool)> {
+    let mut parts = value.trim().split('-');
+    let (version, trace_id, parent_id, flags) = (parts.next()?, parts.next()?, parts.next()?, parts.next()?);
+    let is_hex = |part: &str, len: usize| part.len() == len && part.bytes().all(|b| b.is_ascii_digit() || (b'a'..=b'f').contains(&b));
+    // Later versions may append fields; version ff is invalid
+    let extra_fields = parts.next().is_some();
+    if !is_hex(version, 2) || version == "ff" || (version == "00" && extra_fields)
+        || !is_hex(trace_id, 32) || !is_hex(parent_id, 16) || !is_hex(flags, 2) {
+        return None;
+    }
+    let trace_id = u128::from_str_radix(trace_id, 16).ok().filter(|&id| id != 0)?;
+    let parent_id = u64::from_str_radix(parent_id, 16).ok().filter(|&id| id != 0)?;
+    let flags = u8::from_str_radix(flags, 16).ok()?;
+    Some((trace_id, parent_id, flags & 1 == 1))
+}
+
+/// A random non-zero id; the seed comes from the OS through `RandomState`
+pub fn random_id() -> u64 {
+    static SEED: OnceLock<u64> = OnceLock::new();
+    static COUNTER: AtomicU64 = AtomicU64::new(0);
+    let seed = *SEED.get_or_init(|| RandomState::new().build_hasher().finish());
+    // splitmix64
+    let mut z = seed.wrapping_add(COUNTER.fetch_add(1, Ordering::Relaxed).wrapping_mul(0x9e37_79b9_7f4a_7c15));
+    z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+    z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+    (z ^ (z >> 31)).max(1)
+}
+
+async fn export(url: Url, service_name: String, mut receiver: mpsc::Receiver<Vec<Span>>) {
+    let mut batch = Vec::new();
+    let mut interval = tokio::time::interval(EXPORT_INTERVAL);
+    loop {
+        tokio::select! {
+            spans = receiver.recv() => match spans {
+                Some(spans) => {
+                    batch.extend(spans);
+                    if batch.len() < BATCH_SIZE {
+                        continue;
+                    }
+                }
+                None => return,
+            },
+            _ = interval.tick() => {
+                if batch.is_empty() {
+                    continue;
+                }
+            }
+        }
+        let body = document(&service_name, &batch);
+        batch.clear();
+        match client::post_json(&url, &body.to_string()).await {
+            Ok(response) if (200..300).contains(&response.status) => {}
+            Ok(response) => logging::error(format!("OTLP collector returned status {}", response.status)),
+            Err(e) => logging::error(format!("Failed to export spans: {}", e)),
+        }
+    }
+}
+
+/// An OTLP `ExportTraceServiceRequest` in its JSON encoding
+fn document(service_name: &str, spans: &[Span]) -> Value {
+    let spans: Vec<Value> = spans.iter().map(|span| {
+        let mut value = json!({
+            "traceId": format!("{:032x}", span.trace_id),
+            "spanId": format!("{:016x}", span.span_id),
+            "name": span.name,
+            "kind": span.kind as u8,
+            "startTimeUnixNano": unix_nanos(span.start),
+            "endTimeUnixNano": unix_nanos(span.end),
+            "attributes": attributes(&span.attributes),
+        });
+        if let Some(parent) = span.parent {
+            value["parentSpanId"] = json!(format!("{:016x}", parent));
+        }
+        if let Some(message) = &span.error {
+            value["status"] = json!({ "code": 2, "message": message });
+        }
+        value
+    }).collect();
+    json!({
+        "resourceSpans": [{
+            "resource": { "attributes": attributes(&[("service.name", json!(service_name))]) },
+            "scopeSpans": [{
+                "scope": { "name": env!("CARGO_PKG_NAME"), "version": env!("CARGO_PKG_VERSION") },
+                "spans": spans,
+            }],
+        }],
+    })
+}
+
+/// OTLP key-value pairs; integers are encoded as strings, as the JSON mapping wants for 64-bit values
+fn attributes(attributes: &[(&'static str, Value)]) -> Value {
+    Value::Array(attributes.iter().map(|(key, value)| {
+        let value = match value {
+            Value::Number(n) => json!({ "intValue": n.to_string() }),
+            Value::Bool(b) => json!({ "boolValue": b }),
+            Value::String(s) => json!({ "stringValue": s }),
+            other => json!({ "stringValue": other.to_string() }),
+        };
+        json!({ "key": key, "value": value })
+    }).collect())
+}
+
+fn unix_nanos(time: SystemTime) -> String {
+    time.duration_since(UNIX_EPOCH).unwrap_or_default().as_nanos().to_string()
+}
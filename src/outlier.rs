@@ -0,0 +1,121 @@
+//! Passive health checks: backends are watched through the traffic they get, and ejected from
+//! rotation for a while when their connections fail or they answer with server errors, either
+//! several times in a row (`--outlier-consecutive-errors`) or for too large a share of their requests
+//! (`--outlier-error-rate`). Each ejection in a row lasts twice as long as the one before.
+
+use crate::events::EventBus;
+use crate::logging;
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+/// Backends tracked at most; more are not ejected until some are forgotten
+const MAX_BACKENDS: usize = 10_000;
+
+pub struct OutlierConfig {
+    /// Connect failures and 5xx responses in a row that eject a backend
+    pub consecutive_errors: Option<u32>,
+    /// Share of a window's requests failing that ejects a backend
+    pub error_rate: Option<f64>,
+    pub window: Duration,
+    /// Requests in a window before its error rate counts
+    pub min_requests: u64,
+    /// Length of a first ejection
+    pub ejection: Duration,
+    /// Longest ejection, however often a backend is ejected
+    pub max_ejection: Duration,
+}
+
+#[derive(Default)]
+struct Backend {
+    consecutive_errors: u32,
+    window_start: Option<Instant>,
+    requests: u64,
+    errors: u64,
+    ejected_until: Option<Instant>,
+    /// Ejections in a row, each readmission being followed by a new ejection soon
+    ejections: u32,
+}
+
+pub struct OutlierDetector {
+    config: OutlierConfig,
+    backends: Mutex<HashMap<Arc<str>, Backend>>,
+    bus: Arc<EventBus>,
+}
+
+impl OutlierDetector {
+    pub fn new(config: OutlierConfig, bus: Arc<EventBus>) -> Self {
+        OutlierDetector { config, backends: Mutex::default(), bus }
+    }
+
+    /// Whether a backend is ejected now
+    pub fn is_ejected(&self, backend: &str) -> bool {
+        let backends = self.backends.lock().unwrap();
+        backends.get(backend).and_then(|backend| backend.ejected_until).is_some_and(|until| until > Instant::now())
+    }
+
+    /// How long a backend stays ejected, and its ejections in a row
+    pub fn status(&self, backend: &str) -> Option<(Option<Duration>, u32)> {
+        let now = Instant::now();
+        let backends = self.backends.lock().unwrap();
+        let backend = backends.get(backend)?;
+        let remaining = backend.ejected_until.filter(|&until| until > now).map(|until| until - now);
+        Some((remaining, backend.ejections))
+    }
+
+    /// Record the outcome of a request to a backend: whether it failed, by not accepting the
+    /// connection or answering with a server error
+    pub fn record(&self, name: &Arc<str>, failed: bool) {
+        let now = Instant::now();
+        let mut backends = self.backends.lock().unwrap();
+        if !backends.contains_key(name) && backends.len() >= MAX_BACKENDS {
+            let forget_after = self.config.max_ejection.max(self.config.window);
+            backends.retain(|_, backend| backend.window_start.is_some_and(|start| now - start < forget_after));
+            if backends.len() >= MAX_BACKENDS {
+                return;
+            }
+        }
+        let backend = backends.entry(name.clone()).or_default();
+        if backend.window_start.map_or(true, |start| now - start >= self.config.window) {
+            backend.window_start = Some(now);
+            backend.requests = 0;
+            backend.errors = 0;
+        }
+        backend.requests += 1;
+        if !failed {
+            backend.consecutive_errors = 0;
+            return;
+        }
+        backend.errors += 1;
+        backend.consecutive_errors += 1;
+
+        // Requests that were on their way while it got ejected do not eject it again
+        if backend.ejected_until.is_some_and(|until| until > now) {
+            return;
+        }
+        let reason = match (self.config.consecutive_errors, self.config.error_rate) {
+            (Some(limit), _) if backend.consecutive_errors >= limit => format!("{} errors in a row", backend.consecutive_errors),
+            (_, Some(rate)) if backend.requests >= self.config.min_requests && backend.errors as f64 >= rate * backend.requests as f64 => {
+                format!("{} of {} requests failed", backend.errors, backend.requests)
+            }
+            _ => return,
+        };
+
+        // A backend that behaved for the longest ejection since its last one starts over
+        let recent = backend.ejected_until.is_some_and(|until| now - until < self.config.max_ejection);
+        backend.ejections = if recent { backend.ejections + 1 } else { 1 };
+        let duration = self.config.ejection.saturating_mul(1 << (backend.ejections - 1).min(16)).min(self.config.max_ejection);
+        backend.ejected_until = Some(now + duration);
+        backend.consecutive_errors = 0;
+        backend.window_start = Some(now);
+        backend.requests = 0;
+        backend.errors = 0;
+        logging::warning(format!("Backend {} ejected for {} s: {}", name, duration.as_secs(), reason));
+        self.bus.publish("backend_ejected", serde_json::json!({
+            "backend": &**name,
+            "reason": reason,
+            "seconds": duration.as_secs(),
+            "ejections": backend.ejections,
+        }));
+    }
+}
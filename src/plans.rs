@@ -0,0 +1,179 @@
+//! Per-user rate limits (`;rate-limit=100/m:user`): requests are counted by the identity an
+//! authentication layer gave them (`--user-identity`), and a plans file (`--rate-limit-plans`)
+//! gives each user the limit of their plan instead of the route's. The file is reloaded when it
+//! changes, so plans can be changed without a restart.
+
+use crate::events::EventBus;
+use crate::logging;
+use crate::ratelimit::{LimitKey, RateLimit};
+use crate::request::RequestHead;
+use arc_swap::ArcSwap;
+use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+use base64::Engine;
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::{Duration, SystemTime};
+
+/// How often the plans file is checked for changes
+const RELOAD_INTERVAL: Duration = Duration::from_secs(5);
+
+/// Longest identity counted; longer ones count by client address
+const MAX_IDENTITY: usize = 256;
+
+/// Where a request's user identity comes from
+pub enum IdentitySource {
+    /// The identity a TLS-PSK client authenticated as (`psk`)
+    Psk,
+    /// A header set by an authenticating layer in front, or an API key (`header:X-Api-Key`)
+    Header(String),
+    /// A claim of the bearer token's JWT payload (`jwt`, `jwt:CLAIM`); the signature is not checked
+    Jwt(String),
+}
+
+impl IdentitySource {
+    pub fn parse(spec: &str) -> Result<Self, String> {
+        let invalid = || format!("Invalid user identity '{}'. Expected psk, header:NAME, jwt or jwt:CLAIM", spec);
+        match spec.split_once(':') {
+            None if spec == "psk" => Ok(IdentitySource::Psk),
+            None if spec == "jwt" => Ok(IdentitySource::Jwt("sub".to_string())),
+            Some(("header", name)) if !name.is_empty() && name.bytes().all(|b| b.is_ascii_alphanumeric() || matches!(b, b'-' | b'_')) => {
+                Ok(IdentitySource::Header(name.to_string()))
+            }
+            Some(("jwt", claim)) if !claim.is_empty() => Ok(IdentitySource::Jwt(claim.to_string())),
+            _ => Err(invalid()),
+        }
+    }
+
+    /// The identity of a request, if it has one
+    pub fn identify(&self, head: &RequestHead, psk_identity: Option<&str>) -> Option<String> {
+        let identity = match self {
+            IdentitySource::Psk => psk_identity.map(str::to_string),
+            IdentitySource::Header(name) => head.header_str(name).map(|value| value.trim().to_string()),
+            IdentitySource::Jwt(claim) => {
+                let token = head.header_str("authorization")?.trim();
+                let token = token.get(..7).filter(|scheme| scheme.eq_ignore_ascii_case("bearer ")).map(|_| token[7..].trim())?;
+                let payload = token.split('.').nth(1)?;
+                let payload = URL_SAFE_NO_PAD.decode(payload.trim_end_matches('=')).ok()?;
+                match serde_json::from_slice::<serde_json::Value>(&payload).ok()?.get(claim)? {
+                    serde_json::Value::String(value) => Some(value.clone()),
+                    serde_json::Value::Number(value) => Some(value.to_string()),
+                    _ => None,
+                }
+            }
+        };
+        identity.filter(|identity| !identity.is_empty() && identity.len() <= MAX_IDENTITY)
+    }
+}
+
+impl std::fmt::Display for IdentitySource {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            IdentitySource::Psk => f.write_str("TLS-PSK identity"),
+            IdentitySource::Header(name) => write!(f, "{} header", name),
+            IdentitySource::Jwt(claim) => write!(f, "JWT claim {} (signature not verified)", claim),
+        }
+    }
+}
+
+/// The active plans, replaced atomically when the file changes
+pub type SharedPlans = Arc<ArcSwap<Plans>>;
+
+/// Limit tiers by name, and the plan of each user listed
+#[derive(Default)]
+pub struct Plans {
+    plans: HashMap<String, RateLimit>,
+    users: HashMap<String, String>,
+}
+
+impl Plans {
+    /// Parse a plans file: `plan NAME REQUESTS/UNIT` lines define the tiers, `USER PLAN` lines put
+    /// users on them, `#` starts a comment
+    pub fn load(path: &Path) -> Result<Self, String> {
+        let contents = std::fs::read_to_string(path)
+            .map_err(|e| format!("Failed to read rate limit plans {}: {}", path.display(), e))?;
+
+        let mut plans = HashMap::new();
+        let mut users = Vec::new();
+        for (idx, line) in contents.lines().enumerate() {
+            let line = line.split_once('#').map_or(line, |(entry, _)| entry);
+            let fields: Vec<&str> = line.split_whitespace().collect();
+            let location = || format!("{} line {}", path.display(), idx + 1);
+
+            match fields[..] {
+                [] => continue,
+                ["plan", name, limit] => {
+                    if limit.contains(':') {
+                        return Err(format!("Plan limits count per user and take no key, in {}: {}", location(), limit));
+                    }
+                    let mut limit = RateLimit::parse(limit, name).map_err(|e| format!("{} in {}", e, location()))?;
+                    limit.key = LimitKey::User;
+                    if plans.insert(name.to_string(), limit).is_some() {
+                        return Err(format!("Duplicate plan '{}' in {}", name, location()));
+                    }
+                }
+                [user, plan] => users.push((user, plan, location())),
+                _ => return Err(format!("Invalid entry in {}. Expected 'plan NAME REQUESTS/UNIT' or 'USER PLAN'", location())),
+            }
+        }
+
+        // Plans may be defined after the users on them
+        let mut assigned = HashMap::new();
+        for (user, plan, location) in users {
+            if !plans.contains_key(plan) {
+                return Err(format!("Undefined plan '{}' in {}", plan, location));
+            }
+            if assigned.insert(user.to_string(), plan.to_string()).is_some() {
+                return Err(format!("Duplicate user '{}' in {}", user, location));
+            }
+        }
+        Ok(Plans { plans, users: assigned })
+    }
+
+    /// The plan of a user and its limit, if the user is listed
+    pub fn get(&self, user: &str) -> Option<(&str, &RateLimit)> {
+        let plan = self.users.get(user)?;
+        self.plans.get(plan).map(|limit| (plan.as_str(), limit))
+    }
+
+    pub fn describe(&self) -> String {
+        format!("{} plans, {} users", self.plans.len(), self.users.len())
+    }
+}
+
+/// Reload the plans whenever the file's modification time changes; invalid files keep the current plans
+pub async fn watch(path: PathBuf, plans: SharedPlans, bus: Arc<EventBus>) {
+    let modified = |path: &Path| std::fs::metadata(path).and_then(|m| m.modified()).ok();
+    let mut last_modified: Option<SystemTime> = modified(&path);
+
+    let mut interval = tokio::time::interval(RELOAD_INTERVAL);
+    interval.tick().await;
+
+    loop {
+        interval.tick().await;
+        let current = modified(&path);
+        if current == last_modified {
+            continue;
+        }
+        last_modified = current;
+
+        match Plans::load(&path) {
+            Ok(new_plans) => {
+                println!("Reloaded rate limit plans {} ({})", path.display(), new_plans.describe());
+                bus.publish("config_reload", serde_json::json!({
+                    "source": "rate_limit_plans",
+                    "plans": new_plans.plans.len(),
+                    "users": new_plans.users.len(),
+                }));
+                plans.store(Arc::new(new_plans));
+            }
+            Err(e) => {
+                logging::warning(format!("Keeping previous rate limit plans: {}", e));
+                bus.publish("config_rejected", serde_json::json!({
+                    "source": "rate_limit_plans",
+                    "error": e,
+                }));
+            }
+        }
+    }
+}
@@ -0,0 +1,293 @@
+//! Backend pools: a route's backends with their weights (`127.0.0.1:4000:90,127.0.0.1:4001:10`),
+//! so that a share of the traffic can be steered to a canary. Connections are spread by smooth
+//! weighted round-robin, which is exact over every cycle of the weights and interleaves the backends
+//! instead of sending runs of requests to one of them. With `--lb ip-hash`, each client address is
+//! hashed to a backend instead (weighted rendezvous hashing), so clients keep their backend and only
+//! those of a backend that leaves or joins the pool move. Backends may be tagged with a zone
+//! (`10.0.0.1:8080@eu-west`), so that connections stay in one zone (see `region`). A pool may also
+//! be discovered from SRV records (`srv:NAME`), Kubernetes Endpoints
+//! (`k8s:NAMESPACE/SERVICE:PORT`) or an upstream file (`@PATH`), see `discovery`, or be the active
+//! pool of a blue/green slot (`slot:NAME`), see `slot`.
+
+use crate::discovery;
+use crate::routing::Backend;
+use std::fmt;
+use std::net::IpAddr;
+use std::sync::{Arc, Mutex};
+
+/// Weight of backends given without one
+pub const DEFAULT_WEIGHT: u32 = 1;
+
+/// Highest weight accepted
+const MAX_WEIGHT: u32 = 10_000;
+
+/// How pools choose the backend of a connection (`--lb`)
+#[derive(Clone, Copy, PartialEq)]
+pub enum Strategy {
+    /// Take turns by weight (the default)
+    RoundRobin,
+    /// The same backend for each client address, by consistent hashing
+    IpHash,
+}
+
+impl Strategy {
+    pub fn parse(name: &str) -> Result<Self, String> {
+        match name {
+            "round-robin" => Ok(Strategy::RoundRobin),
+            "ip-hash" => Ok(Strategy::IpHash),
+            _ => Err(format!("Invalid load balancing strategy '{}'. Expected round-robin or ip-hash", name)),
+        }
+    }
+}
+
+impl fmt::Display for Strategy {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Strategy::RoundRobin => "weighted round-robin",
+            Strategy::IpHash => "consistent hashing of client addresses",
+        })
+    }
+}
+
+pub struct Pool {
+    backends: Vec<Backend>,
+    weights: Vec<u32>,
+    /// The zone of each backend; all None in pools without zones
+    zones: Vec<Option<Arc<str>>>,
+    /// The priority of each backend, lower first; all 0 in pools not discovered
+    priorities: Vec<u16>,
+    /// The distinct priorities, in order
+    tiers: Vec<u16>,
+    /// The route target the backends were discovered from, `srv:NAME`, `k8s:...` or `@PATH`
+    source: Option<Arc<str>>,
+    /// The slot whose active pool this is, for `slot:NAME` targets
+    slot: Option<Arc<str>>,
+    /// Each backend's current weight in the round-robin
+    current: Mutex<Vec<i64>>,
+}
+
+impl Pool {
+    /// Parse `BACKEND[:WEIGHT][@ZONE][,BACKEND[:WEIGHT][@ZONE]...]`, `srv:NAME`,
+    /// `k8s:NAMESPACE/SERVICE:PORT` or `@PATH`
+    pub fn parse(spec: &str, route: &str) -> Result<Self, String> {
+        if discovery::is_discovered(spec) {
+            return Self::discover(spec, route);
+        }
+        let mut backends = Vec::new();
+        let mut weights = Vec::new();
+        let mut zones = Vec::new();
+        for member in spec.split(',') {
+            let (member, zone) = match member.rsplit_once('@') {
+                Some((member, zone)) => (member, Some(parse_zone(zone, route)?)),
+                None => (member, None),
+            };
+            let (address, weight) = split_weight(member);
+            let weight = match weight {
+                Some(weight) => parse_weight(weight)
+                    .ok_or_else(|| format!("Invalid weight '{}' of backend '{}' in route '{}'. Expected 0-{}", weight, address, route, MAX_WEIGHT))?,
+                None => DEFAULT_WEIGHT,
+            };
+            let backend = Backend::parse(address, route)?;
+            if backends.iter().any(|b: &Backend| b.name == backend.name) {
+                return Err(format!("Backend '{}' is listed twice in route '{}'", address, route));
+            }
+            backends.push(backend);
+            weights.push(weight);
+            zones.push(zone);
+        }
+        if weights.iter().all(|&weight| weight == 0) {
+            return Err(format!("At least one backend needs a weight above 0 in route '{}'", route));
+        }
+        if zones.iter().any(Option::is_some) && zones.iter().any(Option::is_none) {
+            return Err(format!("Either all backends or none need a @ZONE in route '{}'", route));
+        }
+        let current = Mutex::new(vec![0; backends.len()]);
+        let priorities = vec![0; backends.len()];
+        Ok(Pool { backends, weights, zones, priorities, tiers: vec![0], source: None, slot: None, current })
+    }
+
+    /// The pool of the backends a discovered target has now
+    fn discover(spec: &str, route: &str) -> Result<Self, String> {
+        let members = discovery::members(spec, route)?;
+        let priorities: Vec<u16> = members.iter().map(|m| m.priority).collect();
+        let mut tiers = priorities.clone();
+        tiers.dedup(); // members come sorted by priority
+        Ok(Pool {
+            backends: members.iter().map(discovery::Member::backend).collect(),
+            weights: members.iter().map(|m| m.weight).collect(),
+            zones: vec![None; members.len()],
+            priorities,
+            tiers,
+            source: Some(spec.into()),
+            slot: None,
+            current: Mutex::new(vec![0; members.len()]),
+        })
+    }
+
+    /// The target a discovered pool follows
+    pub fn source(&self) -> Option<&Arc<str>> {
+        self.source.as_ref()
+    }
+
+    /// The pool as the active one of a slot
+    pub fn in_slot(self, slot: &str) -> Self {
+        Pool { slot: Some(slot.into()), ..self }
+    }
+
+    /// The slot the pool is the active one of
+    pub fn slot(&self) -> Option<&Arc<str>> {
+        self.slot.as_ref()
+    }
+
+    /// The backends with their weights as configured
+    pub fn members(&self) -> impl Iterator<Item = (&Backend, u32)> {
+        self.backends.iter().zip(self.weights.iter().copied())
+    }
+
+    /// The backends with their zones, if the pool has zones
+    pub fn zoned(&self) -> impl Iterator<Item = (&Backend, u32, &str)> {
+        self.members().zip(self.zones.iter()).filter_map(|((backend, weight), zone)| Some((backend, weight, zone.as_deref()?)))
+    }
+
+    /// The backend of a pool of one
+    pub fn only(&self) -> Option<&Backend> {
+        match self.backends.as_slice() {
+            [backend] => Some(backend),
+            _ => None,
+        }
+    }
+
+    /// Choose the backend for a new connection from a client, among those in the zone if given.
+    /// `weight` gives each backend's effective weight from its configured one: 0 for backends that
+    /// take no connections now. None when none does.
+    pub fn pick(&self, strategy: Strategy, client: IpAddr, zone: Option<&str>, weight: impl Fn(&Backend, u32) -> u32) -> Option<&Backend> {
+        if let Some(backend) = self.only() {
+            return (weight(backend, self.weights[0]) > 0).then_some(backend);
+        }
+        // Backends of a priority only take connections when none of a lower one can
+        self.tiers.iter().find_map(|&tier| {
+            let weight = |idx: usize| match zone {
+                _ if self.priorities[idx] != tier => 0,
+                Some(zone) if self.zones[idx].as_deref() != Some(zone) => 0,
+                _ => weight(&self.backends[idx], self.weights[idx]),
+            };
+            match strategy {
+                Strategy::RoundRobin => self.next(weight),
+                Strategy::IpHash => self.hashed(client, weight),
+            }
+        })
+    }
+
+    fn next(&self, weight: impl Fn(usize) -> u32) -> Option<&Backend> {
+        let mut current = self.current.lock().unwrap();
+        let mut total = 0i64;
+        let mut best: Option<usize> = None;
+        for idx in 0..self.backends.len() {
+            let weight = i64::from(weight(idx));
+            if weight == 0 {
+                continue;
+            }
+            current[idx] += weight;
+            total += weight;
+            if best.map_or(true, |best| current[idx] > current[best]) {
+                best = Some(idx);
+            }
+        }
+        let best = best?;
+        current[best] -= total;
+        Some(&self.backends[best])
+    }
+
+    /// The backend with the highest score for the client, each score being derived from a hash of the
+    /// client and the backend alone: taking a backend out only moves its own clients
+    fn hashed(&self, client: IpAddr, weight: impl Fn(usize) -> u32) -> Option<&Backend> {
+        // IPv4 clients arriving on a dual-stack socket hash like on an IPv4 one
+        let client = match client {
+            IpAddr::V6(v6) => v6.to_ipv4_mapped().map_or(IpAddr::V6(v6), IpAddr::V4),
+            v4 => v4,
+        };
+        let client = client.to_string();
+        let mut best: Option<(f64, &Backend)> = None;
+        for (idx, backend) in self.backends.iter().enumerate() {
+            let weight = weight(idx);
+            if weight == 0 {
+                continue;
+            }
+            // A uniform draw in (0, 1) turned into a score that a backend wins in proportion to its weight
+            let draw = ((hash(&client, &backend.name) >> 11) as f64 + 0.5) / (1u64 << 53) as f64;
+            let score = f64::from(weight) / -draw.ln();
+            if best.map_or(true, |(best, _)| score > best) {
+                best = Some((score, backend));
+            }
+        }
+        best.map(|(_, backend)| backend)
+    }
+}
+
+/// A hash of a client and backend that stays the same across restarts and builds: FNV-1a, with
+/// the bits mixed by the SplitMix64 finalizer
+fn hash(client: &str, backend: &str) -> u64 {
+    let mut hash = 0xcbf2_9ce4_8422_2325u64;
+    for byte in client.bytes().chain([0]).chain(backend.bytes()) {
+        hash = (hash ^ u64::from(byte)).wrapping_mul(0x100_0000_01b3);
+    }
+    hash = (hash ^ (hash >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+    hash = (hash ^ (hash >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+    hash ^ (hash >> 31)
+}
+
+/// Split a `:WEIGHT` off a backend address: a third `:`-separated part after `host:port`
+pub fn split_weight(member: &str) -> (&str, Option<&str>) {
+    let Some((address, weight)) = member.rsplit_once(':') else {
+        return (member, None);
+    };
+    // `[::1]:4000` is an address with a port; `[::1]:4000:10` has a weight
+    let has_port = address.rsplit_once(':').is_some_and(|(_, port)| !port.is_empty() && port.bytes().all(|b| b.is_ascii_digit()));
+    if has_port { (address, Some(weight)) } else { (member, None) }
+}
+
+fn parse_zone(zone: &str, route: &str) -> Result<Arc<str>, String> {
+    let valid = !zone.is_empty() && zone.bytes().all(|b| b.is_ascii_alphanumeric() || matches!(b, b'-' | b'_' | b'.'));
+    if !valid {
+        return Err(format!("Invalid zone '{}' in route '{}'. Expected letters, digits, '-', '_' and '.'", zone, route));
+    }
+    Ok(zone.into())
+}
+
+pub fn parse_weight(weight: &str) -> Option<u32> {
+    weight.parse().ok().filter(|&weight| weight <= MAX_WEIGHT)
+}
+
+/// Check a weight given through the admin API
+pub fn check_weight(weight: u64) -> Result<u32, String> {
+    u32::try_from(weight).ok().filter(|&weight| weight <= MAX_WEIGHT)
+        .ok_or_else(|| format!("invalid weight {}: expected 0-{}", weight, MAX_WEIGHT))
+}
+
+impl fmt::Display for Pool {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if let Some(source) = &self.source {
+            write!(f, "{}: ", source)?;
+        }
+        if let Some(slot) = &self.slot {
+            write!(f, "{}{}: ", crate::slot::SLOT_PREFIX, slot)?;
+        }
+        if let Some(backend) = self.only() {
+            return write!(f, "http://{}", backend);
+        }
+        for (idx, (backend, weight)) in self.members().enumerate() {
+            if idx > 0 {
+                f.write_str(", ")?;
+            }
+            write!(f, "http://{} (weight {}", backend, weight)?;
+            if self.tiers.len() > 1 {
+                write!(f, ", priority {}", self.priorities[idx])?;
+            }
+            match &self.zones[idx] {
+                Some(zone) => write!(f, ", zone {})", zone)?,
+                None => f.write_str(")")?,
+            }
+        }
+        Ok(())
+    }
+}
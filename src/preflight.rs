@@ -0,0 +1,93 @@
+//! A cache of CORS preflight responses for `;cache-preflight` routes: a browser's `OPTIONS`
+//! request asking whether it may send a cross-origin request is answered from the backend's last
+//! answer to the same question, sparing the backend a round trip per page and origin.
+
+use crate::intercept::{header_value, is_header, status};
+use crate::request::RequestHead;
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+/// Cache lifetime of a preflight response when the route gives none
+pub const DEFAULT_TTL: Duration = Duration::from_secs(600);
+
+/// Preflight responses kept at most; more are not cached until some expire
+const MAX_ENTRIES: usize = 10_000;
+
+/// Largest preflight response cached
+const MAX_RESPONSE: usize = 16 * 1024;
+
+struct Cached {
+    response: Arc<[u8]>,
+    expires: Instant,
+}
+
+#[derive(Default)]
+pub struct PreflightCache {
+    entries: Mutex<HashMap<String, Cached>>,
+}
+
+/// The cache key of a preflight request: the route and everything a CORS policy looks at, or None
+/// for requests that are not preflights
+pub fn key(route: &str, head: &RequestHead) -> Option<String> {
+    if head.method != "OPTIONS" {
+        return None;
+    }
+    let origin = head.header_str("origin")?;
+    let method = head.header_str("access-control-request-method")?;
+    // Browsers send the requested headers lowercase and sorted, but be forgiving
+    let mut headers: Vec<String> = head.header_str("access-control-request-headers").unwrap_or("")
+        .split(',').map(|name| name.trim().to_ascii_lowercase()).filter(|name| !name.is_empty()).collect();
+    headers.sort();
+    let host = head.header_str("host").unwrap_or("");
+    let query = head.query.map_or(String::new(), |query| format!("?{}", query));
+    Some(format!("{}\n{}\n{}{}\n{}\n{}\n{}", route, host, head.path, query, origin, method, headers.join(",")))
+}
+
+impl PreflightCache {
+    /// A fresh cached response for the key, ready to send
+    pub fn get(&self, key: &str) -> Option<Arc<[u8]>> {
+        let entries = self.entries.lock().unwrap();
+        entries.get(key).filter(|cached| cached.expires > Instant::now()).map(|cached| cached.response.clone())
+    }
+
+    /// Cache a backend's answer to a preflight: only successful ones that allow an origin and may be stored
+    pub fn insert(&self, key: &str, head: &[u8], body: &[u8], ttl: Duration) {
+        let allowed = matches!(status(head), Some(200) | Some(204)) && header_value(head, "access-control-allow-origin").is_some();
+        let storable = !header_value(head, "cache-control").is_some_and(|value| value.contains("no-store") || value.contains("private"))
+            && header_value(head, "set-cookie").is_none();
+        if !allowed || !storable || head.len() + body.len() > MAX_RESPONSE {
+            return;
+        }
+
+        // Served from the cache, the response ends the connection like the proxy's other responses
+        let mut response = Vec::with_capacity(head.len() + body.len() + 19);
+        for (idx, line) in head.split_inclusive(|&b| b == b'\n').enumerate() {
+            if idx > 0 && (is_header(line, "connection") || is_header(line, "keep-alive")) {
+                continue;
+            }
+            if idx > 0 && (line == b"\r\n" || line == b"\n") {
+                response.extend_from_slice(b"Connection: close\r\n");
+            }
+            response.extend_from_slice(line);
+        }
+        response.extend_from_slice(body);
+
+        let now = Instant::now();
+        let mut entries = self.entries.lock().unwrap();
+        if entries.len() >= MAX_ENTRIES {
+            entries.retain(|_, cached| cached.expires > now);
+            if entries.len() >= MAX_ENTRIES {
+                return;
+            }
+        }
+        entries.insert(key.to_string(), Cached { response: response.into(), expires: now + ttl });
+    }
+}
+
+/// Where a backend's answer to a preflight goes once it has been forwarded
+pub struct Store<'a> {
+    pub cache: &'a PreflightCache,
+    pub key: String,
+    pub ttl: Duration,
+}
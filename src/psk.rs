@@ -0,0 +1,99 @@
+//! TLS listeners authenticating clients by pre-shared key instead of certificates (`--psk-listen`)
+
+use openssl::ex_data::Index;
+use openssl::ssl::{Ssl, SslContext, SslContextBuilder, SslMethod, SslVersion};
+use std::collections::HashMap;
+use std::path::Path;
+use std::pin::Pin;
+use std::sync::{Arc, OnceLock};
+use tokio::net::TcpStream;
+use tokio_openssl::SslStream;
+
+/// TLS 1.2 cipher suites restricted to PSK key exchange; TLS 1.3 uses its default suites
+const PSK_CIPHERS: &str = "PSK-AES256-GCM-SHA384:PSK-AES128-GCM-SHA256:PSK-CHACHA20-POLY1305";
+
+/// Keys shorter than this are rejected as too easy to guess
+const MIN_KEY_LEN: usize = 16;
+
+/// Where the handshake leaves the identity the client authenticated as
+fn identity_index() -> Index<Ssl, String> {
+    static INDEX: OnceLock<Index<Ssl, String>> = OnceLock::new();
+    *INDEX.get_or_init(|| Ssl::new_ex_index().expect("failed to allocate OpenSSL ex_data index"))
+}
+
+/// Accepts TLS-PSK connections with keys from a key file
+pub struct PskAcceptor {
+    context: SslContext,
+}
+
+impl PskAcceptor {
+    /// Load `IDENTITY:HEXKEY` lines (`#` starts a comment) and prepare the TLS context
+    pub fn load(path: &Path) -> Result<Self, String> {
+        let contents = std::fs::read_to_string(path)
+            .map_err(|e| format!("Failed to read PSK key file {}: {}", path.display(), e))?;
+
+        let mut keys: HashMap<Vec<u8>, Vec<u8>> = HashMap::new();
+        for (idx, line) in contents.lines().enumerate() {
+            let line = line.split_once('#').map_or(line, |(entry, _)| entry).trim();
+            if line.is_empty() {
+                continue;
+            }
+            let location = format!("{} line {}", path.display(), idx + 1);
+            let (identity, key) = line.split_once(':')
+                .filter(|(identity, _)| !identity.is_empty())
+                .ok_or_else(|| format!("Invalid PSK entry in {}. Expected format: IDENTITY:HEXKEY", location))?;
+            let key = decode_hex(key.trim()).ok_or_else(|| format!("Invalid hex key in {}", location))?;
+            if key.len() < MIN_KEY_LEN {
+                return Err(format!("PSK key in {} is shorter than {} bytes", location, MIN_KEY_LEN));
+            }
+            if keys.insert(identity.as_bytes().to_vec(), key).is_some() {
+                return Err(format!("Duplicate PSK identity '{}' in {}", identity, location));
+            }
+        }
+        if keys.is_empty() {
+            return Err(format!("PSK key file {} has no keys", path.display()));
+        }
+
+        let tls_error = |e: openssl::error::ErrorStack| format!("Failed to set up TLS-PSK: {}", e);
+        let mut builder = SslContextBuilder::new(SslMethod::tls_server()).map_err(tls_error)?;
+        builder.set_min_proto_version(Some(SslVersion::TLS1_2)).map_err(tls_error)?;
+        builder.set_cipher_list(PSK_CIPHERS).map_err(tls_error)?;
+
+        let keys = Arc::new(keys);
+        builder.set_psk_server_callback(move |ssl, identity, out| {
+            // Unknown identities get a zero-length key, which fails the handshake
+            let Some((identity, key)) = identity.and_then(|id| keys.get_key_value(id)) else {
+                return Ok(0);
+            };
+            if key.len() > out.len() {
+                return Ok(0);
+            }
+            out[..key.len()].copy_from_slice(key);
+            ssl.set_ex_data(identity_index(), String::from_utf8_lossy(identity).into_owned());
+            Ok(key.len())
+        });
+
+        Ok(PskAcceptor { context: builder.build() })
+    }
+
+    /// Complete the TLS handshake; returns the stream and the identity the client authenticated as
+    pub async fn accept(&self, tcp: TcpStream) -> Result<(SslStream<TcpStream>, String), Box<dyn std::error::Error + Send + Sync>> {
+        let ssl = Ssl::new(&self.context)?;
+        let mut stream = SslStream::new(ssl, tcp)?;
+        Pin::new(&mut stream).accept().await?;
+
+        let identity = stream.ssl().ex_data(identity_index()).cloned()
+            .ok_or("TLS handshake completed without a pre-shared key")?;
+        Ok((stream, identity))
+    }
+}
+
+fn decode_hex(hex: &str) -> Option<Vec<u8>> {
+    if hex.len() % 2 != 0 {
+        return None;
+    }
+    (0..hex.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(hex.get(i..i + 2)?, 16).ok())
+        .collect()
+}
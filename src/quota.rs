@@ -0,0 +1,233 @@
+//! Virtual host quotas (`--host-quota`): caps on what the requests for one Host may take of the
+//! proxy, so that one tenant cannot starve the others sharing it. A host may be limited in open
+//! connections, beyond which its requests are refused with `503`, in bandwidth, shared by all its
+//! connections in both directions, and in the memory its `;force-cache` responses take.
+
+use crate::logfile::parse_size;
+use crate::request::RequestHead;
+use crate::routing::normalize_host;
+use std::collections::HashMap;
+use std::future::Future;
+use std::io;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll};
+use std::time::{Duration, Instant};
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio::time::Sleep;
+
+/// Limits of a virtual host; None for those it does not have
+pub struct HostQuota {
+    pub connections: Option<u32>,
+    /// Bytes per second
+    pub bandwidth: Option<u64>,
+    /// Bytes of cached responses
+    pub cache: Option<usize>,
+}
+
+impl HostQuota {
+    /// Parse `HOST=LIMIT[,LIMIT...]`, each limit being `connections=N`, `bandwidth=SIZE` (per
+    /// second) or `cache=SIZE`, sizes with K, M and G suffixes
+    pub fn parse(spec: &str) -> Result<(String, Self), String> {
+        let invalid = || format!("Invalid host quota '{}'. Expected HOST=LIMIT[,LIMIT...] with connections=N, bandwidth=SIZE or cache=SIZE", spec);
+        let (host, limits) = spec.split_once('=').ok_or_else(invalid)?;
+        let host = normalize_host(host).into_owned();
+        if host.is_empty() {
+            return Err(invalid());
+        }
+        let mut quota = HostQuota { connections: None, bandwidth: None, cache: None };
+        for limit in limits.split(',').map(str::trim) {
+            let (name, value) = limit.split_once('=').ok_or_else(invalid)?;
+            let bad_value = || format!("Invalid {} '{}' in host quota for {}", name, value, host);
+            match name {
+                "connections" => quota.connections = Some(value.parse().ok().filter(|&n| n > 0).ok_or_else(bad_value)?),
+                "bandwidth" => quota.bandwidth = Some(parse_size(value.trim_end_matches("/s")).ok_or_else(bad_value)?),
+                "cache" => quota.cache = Some(parse_size(value).and_then(|size| usize::try_from(size).ok()).ok_or_else(bad_value)?),
+                _ => return Err(invalid()),
+            }
+        }
+        Ok((host, quota))
+    }
+}
+
+impl std::fmt::Display for HostQuota {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let mut limits = Vec::new();
+        if let Some(connections) = self.connections {
+            limits.push(format!("{} connections", connections));
+        }
+        if let Some(bandwidth) = self.bandwidth {
+            limits.push(format!("{} bytes/s", bandwidth));
+        }
+        if let Some(cache) = self.cache {
+            limits.push(format!("{} bytes of cache", cache));
+        }
+        f.write_str(&limits.join(", "))
+    }
+}
+
+/// The quotas of all limited hosts, with what each uses of them
+#[derive(Default)]
+pub struct Quotas {
+    hosts: HashMap<String, Host>,
+}
+
+impl Quotas {
+    pub fn add(&mut self, name: String, quota: HostQuota) {
+        let bucket = quota.bandwidth.map(|rate| Arc::new(Bucket::new(rate)));
+        let host = Host { name: name.as_str().into(), quota, open: AtomicU32::new(0), bucket };
+        self.hosts.insert(name, host);
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.hosts.is_empty()
+    }
+
+    /// The limited host a request is for, if it is for one
+    pub fn get(&self, head: &RequestHead) -> Option<&Host> {
+        if self.hosts.is_empty() {
+            return None;
+        }
+        self.hosts.get(normalize_host(head.header_str("host")?).as_ref())
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &Host> {
+        self.hosts.values()
+    }
+}
+
+/// A limited host
+pub struct Host {
+    pub name: Arc<str>,
+    pub quota: HostQuota,
+    /// Connections open to its backends, and those being answered
+    open: AtomicU32,
+    bucket: Option<Arc<Bucket>>,
+}
+
+impl Host {
+    /// A place among the host's connections, or None while it has all its quota allows
+    pub fn enter(&self) -> Option<Slot<'_>> {
+        let max = self.quota.connections.unwrap_or(u32::MAX);
+        self.open.fetch_update(Ordering::AcqRel, Ordering::Acquire, |open| (open < max).then_some(open + 1)).ok()?;
+        Some(Slot { host: self })
+    }
+
+    /// The cache space of the host, for `ResponseCache::insert`
+    pub fn cache_quota(&self) -> Option<(Arc<str>, usize)> {
+        self.quota.cache.map(|cache| (self.name.clone(), cache))
+    }
+}
+
+/// A connection of a limited host, counted until dropped
+pub struct Slot<'h> {
+    host: &'h Host,
+}
+
+impl Drop for Slot<'_> {
+    fn drop(&mut self) {
+        self.host.open.fetch_sub(1, Ordering::AcqRel);
+    }
+}
+
+/// Bandwidth shared by a host's connections: a token bucket that holds up to one second's worth of
+/// bytes. Transfers take what they moved afterwards and may leave it owing; the next one waits
+/// until the debt is paid back.
+struct Bucket {
+    rate: f64,
+    state: Mutex<(f64, Instant)>,
+}
+
+impl Bucket {
+    fn new(rate: u64) -> Self {
+        Bucket { rate: rate as f64, state: Mutex::new((rate as f64, Instant::now())) }
+    }
+
+    /// How long to wait before moving more bytes; zero when the bucket is not in debt
+    fn wait(&self) -> Duration {
+        let mut state = self.state.lock().unwrap();
+        let now = Instant::now();
+        let (tokens, last) = &mut *state;
+        *tokens = (*tokens + (now - *last).as_secs_f64() * self.rate).min(self.rate);
+        *last = now;
+        if *tokens >= 0.0 { Duration::ZERO } else { Duration::from_secs_f64(-*tokens / self.rate) }
+    }
+
+    fn take(&self, bytes: usize) {
+        self.state.lock().unwrap().0 -= bytes as f64;
+    }
+}
+
+/// A stream whose reads and writes wait their turn in a bandwidth bucket, if it has one
+pub struct Throttled<S> {
+    inner: S,
+    bucket: Option<Arc<Bucket>>,
+    read_delay: Option<Pin<Box<Sleep>>>,
+    write_delay: Option<Pin<Box<Sleep>>>,
+}
+
+impl<S> Throttled<S> {
+    /// A client connection, held to the bandwidth of its host if that has a limit
+    pub fn new(inner: S, host: Option<&Host>) -> Self {
+        Throttled { inner, bucket: host.and_then(|host| host.bucket.clone()), read_delay: None, write_delay: None }
+    }
+}
+
+/// Wait out the bucket's debt: Pending while there is some
+fn poll_turn(bucket: &Bucket, delay: &mut Option<Pin<Box<Sleep>>>, cx: &mut Context<'_>) -> Poll<()> {
+    loop {
+        if let Some(sleep) = delay {
+            if sleep.as_mut().poll(cx).is_pending() {
+                return Poll::Pending;
+            }
+            *delay = None;
+        }
+        let wait = bucket.wait();
+        if wait.is_zero() {
+            return Poll::Ready(());
+        }
+        *delay = Some(Box::pin(tokio::time::sleep(wait)));
+    }
+}
+
+impl<S: AsyncRead + Unpin> AsyncRead for Throttled<S> {
+    fn poll_read(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        let Some(bucket) = &this.bucket else {
+            return Pin::new(&mut this.inner).poll_read(cx, buf);
+        };
+        if poll_turn(bucket, &mut this.read_delay, cx).is_pending() {
+            return Poll::Pending;
+        }
+        let filled = buf.filled().len();
+        let result = Pin::new(&mut this.inner).poll_read(cx, buf);
+        bucket.take(buf.filled().len() - filled);
+        result
+    }
+}
+
+impl<S: AsyncWrite + Unpin> AsyncWrite for Throttled<S> {
+    fn poll_write(self: Pin<&mut Self>, cx: &mut Context<'_>, data: &[u8]) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+        let Some(bucket) = &this.bucket else {
+            return Pin::new(&mut this.inner).poll_write(cx, data);
+        };
+        if poll_turn(bucket, &mut this.write_delay, cx).is_pending() {
+            return Poll::Pending;
+        }
+        let result = Pin::new(&mut this.inner).poll_write(cx, data);
+        if let Poll::Ready(Ok(n)) = &result {
+            bucket.take(*n);
+        }
+        result
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().inner).poll_shutdown(cx)
+    }
+}
@@ -0,0 +1,176 @@
+//! Client rate limits (`;rate-limit=100/s`) and autonomous system rules (`;deny-asn`,
+//! `;allow-asn`) for routes. Limits count requests per client address, or per autonomous system
+//! (`;rate-limit=1000/m:asn`) so that a hosting provider's many addresses share one budget, or
+//! for all clients together (`:all`), or per user (`:user`, see `plans`). Routes may also share one limit, defined once with
+//! `--rate-limit-group api=100/s` and referenced as `;rate-limit=@api`.
+
+use crate::asn::parse_number;
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+/// Clients tracked at most; when full, clients back within their limit are forgotten, then all
+const MAX_CLIENTS: usize = 100_000;
+
+/// What a limit counts requests by
+#[derive(Clone, Copy, PartialEq)]
+pub enum LimitKey {
+    /// Each client address (`:ip`, the default)
+    Ip,
+    /// Each client's autonomous system, by `--asn-db`; clients it does not know count by address (`:asn`)
+    Asn,
+    /// All clients together (`:all`)
+    All,
+    /// Each user, by `--user-identity`; requests without an identity count by address (`:user`)
+    User,
+}
+
+/// A `;rate-limit=REQUESTS/UNIT[:KEY]` option: up to REQUESTS at once, refilled evenly over the unit
+#[derive(Clone)]
+pub struct RateLimit {
+    pub requests: u32,
+    pub per: Duration,
+    pub key: LimitKey,
+}
+
+impl RateLimit {
+    pub fn parse(value: &str, route: &str) -> Result<Self, String> {
+        let invalid = || format!("Invalid rate-limit '{}' in route '{}'. Expected REQUESTS/s, /m or /h, optionally followed by :ip, :asn, :all or :user, or @GROUP", value, route);
+        let (rate, key) = match value.split_once(':') {
+            Some((rate, "ip")) => (rate, LimitKey::Ip),
+            Some((rate, "asn")) => (rate, LimitKey::Asn),
+            Some((rate, "all")) => (rate, LimitKey::All),
+            Some((rate, "user")) => (rate, LimitKey::User),
+            Some(_) => return Err(invalid()),
+            None => (value, LimitKey::Ip),
+        };
+        let (requests, unit) = rate.split_once('/').ok_or_else(invalid)?;
+        let requests = requests.parse().ok().filter(|&requests| requests > 0).ok_or_else(invalid)?;
+        let per = match unit {
+            "s" => Duration::from_secs(1),
+            "m" => Duration::from_secs(60),
+            "h" => Duration::from_secs(3600),
+            _ => return Err(invalid()),
+        };
+        Ok(RateLimit { requests, per, key })
+    }
+}
+
+/// A route's `;rate-limit`: its own, or a group's shared with other routes (`@api`)
+#[derive(Clone)]
+pub enum RouteLimit {
+    Own(RateLimit),
+    /// The group's name with its `@`
+    Group(Arc<str>),
+}
+
+impl RouteLimit {
+    pub fn parse(value: &str, route: &str) -> Result<Self, String> {
+        match value.strip_prefix('@') {
+            Some(name) => Ok(RouteLimit::Group(format!("@{}", parse_group_name(name, route)?).into())),
+            None => Ok(RouteLimit::Own(RateLimit::parse(value, route)?)),
+        }
+    }
+}
+
+/// Parse a `--rate-limit-group NAME=REQUESTS/UNIT[:KEY]` definition
+pub fn parse_group(spec: &str) -> Result<(Arc<str>, RateLimit), String> {
+    let (name, limit) = spec.split_once('=')
+        .ok_or_else(|| format!("Invalid rate limit group '{}'. Expected NAME=REQUESTS/UNIT[:KEY]", spec))?;
+    let name = parse_group_name(name, spec)?;
+    // A group's requests are counted under its name, which cannot collide with a route's
+    Ok((format!("@{}", name).into(), RateLimit::parse(limit, spec)?))
+}
+
+fn parse_group_name<'a>(name: &'a str, route: &str) -> Result<&'a str, String> {
+    let valid = !name.is_empty() && name.bytes().all(|b| b.is_ascii_alphanumeric() || matches!(b, b'-' | b'_' | b'.'));
+    if !valid {
+        return Err(format!("Invalid rate limit group name '{}' in '{}'. Expected letters, digits, '-', '_' and '.'", name, route));
+    }
+    Ok(name)
+}
+
+/// A `;deny-asn=` or `;allow-asn=` option
+#[derive(Clone)]
+pub struct AsnRule {
+    /// Whether the listed systems are the only ones allowed, rather than the ones refused
+    pub allow: bool,
+    pub numbers: Vec<u32>,
+}
+
+impl AsnRule {
+    pub fn parse(key: &str, value: &str, route: &str) -> Result<Self, String> {
+        let numbers: Option<Vec<u32>> = value.split(',').map(|number| parse_number(number.trim())).collect();
+        match numbers {
+            Some(numbers) if !value.is_empty() => Ok(AsnRule { allow: key == "allow-asn", numbers }),
+            _ => Err(format!("Invalid {} '{}' in route '{}'. Expected AS numbers separated by ',' (16509 or AS16509)", key, value, route)),
+        }
+    }
+
+    /// Whether a client in the autonomous system (None when unknown) may use the route
+    pub fn allows(&self, asn: Option<u32>) -> bool {
+        let listed = asn.is_some_and(|asn| self.numbers.contains(&asn));
+        listed == self.allow
+    }
+}
+
+/// Who a limit is counting: an address, an autonomous system, a user, or everyone
+#[derive(Clone, PartialEq, Eq, Hash)]
+pub enum Client {
+    Ip(IpAddr),
+    Asn(u32),
+    User(Arc<str>),
+    All,
+}
+
+impl Client {
+    /// The client as the limit counts it, given its autonomous system and user identity if known
+    pub fn new(limit: &RateLimit, addr: IpAddr, asn: Option<u32>, user: Option<&str>) -> Self {
+        match (limit.key, asn, user) {
+            (LimitKey::All, _, _) => Client::All,
+            (LimitKey::Asn, Some(asn), _) => Client::Asn(asn),
+            (LimitKey::User, _, Some(user)) => Client::User(user.into()),
+            _ => Client::Ip(addr),
+        }
+    }
+}
+
+/// The request counts of all limited routes, shared by all connections. Each client's allowance is
+/// kept as the time its next request is due (GCRA), so a client is one `Instant` per route.
+#[derive(Default)]
+pub struct RateLimiter {
+    due: Mutex<HashMap<(Arc<str>, Client), Instant>>,
+}
+
+impl RateLimiter {
+    /// Count a request against a limit, kept under the route's name or a group's, or say how long
+    /// until the client may retry
+    pub fn acquire(&self, route: &Arc<str>, limit: &RateLimit, client: Client) -> Result<(), Duration> {
+        let interval = limit.per / limit.requests;
+        let burst = limit.per - interval;
+        let now = Instant::now();
+        let mut due = self.due.lock().unwrap();
+        if due.len() >= MAX_CLIENTS {
+            due.retain(|_, due| *due > now);
+            if due.len() >= MAX_CLIENTS {
+                due.clear();
+            }
+        }
+        let next = due.entry((route.clone(), client)).or_insert(now);
+        let start = (*next).max(now);
+        if start > now + burst {
+            return Err(start - now - burst);
+        }
+        *next = start + interval;
+        Ok(())
+    }
+}
+
+/// A `429 Too Many Requests` response, telling the client when to retry
+pub fn too_many_requests(retry_after: Duration, with_body: bool) -> Vec<u8> {
+    let body = if with_body { "Too Many Requests\r\n" } else { "" };
+    // Whole seconds, rounded up so that a client retrying on time is let through
+    let seconds = retry_after.as_secs() + u64::from(retry_after.subsec_nanos() > 0);
+    format!("HTTP/1.1 429 Too Many Requests\r\nContent-Type: text/plain; charset=utf-8\r\nRetry-After: {}\r\nContent-Length: 19\r\nConnection: close\r\n\r\n{}", seconds, body).into_bytes()
+}
@@ -0,0 +1,104 @@
+//! Redirect map files: exact legacy paths redirected to new URLs, reloaded when the file changes
+
+use crate::events::EventBus;
+use crate::logging;
+use crate::response::Redirect;
+use arc_swap::ArcSwap;
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::{Duration, SystemTime};
+
+/// How often the map file is checked for changes
+const RELOAD_INTERVAL: Duration = Duration::from_secs(5);
+
+/// Status of entries that do not give one
+const DEFAULT_STATUS: u16 = 301;
+
+/// The active redirect map, replaced atomically when the file changes
+pub type SharedRedirectMap = Arc<ArcSwap<RedirectMap>>;
+
+/// Redirects by exact request path; a lookup is one hash probe however many entries there are
+#[derive(Default)]
+pub struct RedirectMap {
+    entries: HashMap<String, Redirect>,
+}
+
+impl RedirectMap {
+    /// Parse a map file: one `OLD_PATH NEW_URL [STATUS]` entry per line, `#` starts a comment
+    pub fn load(path: &Path) -> Result<Self, String> {
+        let contents = std::fs::read_to_string(path)
+            .map_err(|e| format!("Failed to read redirect map {}: {}", path.display(), e))?;
+
+        let mut entries = HashMap::new();
+        for (idx, line) in contents.lines().enumerate() {
+            let line = line.split_once('#').map_or(line, |(entry, _)| entry);
+            let fields: Vec<&str> = line.split_whitespace().collect();
+            let location = || format!("{} line {}", path.display(), idx + 1);
+
+            let (old, new, status) = match fields[..] {
+                [] => continue,
+                [old, new] => (old, new, DEFAULT_STATUS),
+                [old, new, status] => {
+                    let status = status.parse().map_err(|_| format!("Invalid status '{}' in {}", status, location()))?;
+                    (old, new, status)
+                }
+                _ => return Err(format!("Invalid redirect in {}. Expected format: OLD_PATH NEW_URL [STATUS]", location())),
+            };
+            if !old.starts_with('/') {
+                return Err(format!("Path must start with '/' in {}: {}", location(), old));
+            }
+
+            let redirect = Redirect::new(status, new, &[]).map_err(|e| format!("{} in {}", e, location()))?;
+            if entries.insert(old.to_string(), redirect).is_some() {
+                return Err(format!("Duplicate redirect for '{}' in {}", old, location()));
+            }
+        }
+
+        Ok(RedirectMap { entries })
+    }
+
+    pub fn get(&self, path: &str) -> Option<&Redirect> {
+        self.entries.get(path)
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+}
+
+/// Reload the map whenever the file's modification time changes; invalid files keep the current map
+pub async fn watch(path: PathBuf, map: SharedRedirectMap, bus: Arc<EventBus>) {
+    let modified = |path: &Path| std::fs::metadata(path).and_then(|m| m.modified()).ok();
+    let mut last_modified: Option<SystemTime> = modified(&path);
+
+    let mut interval = tokio::time::interval(RELOAD_INTERVAL);
+    interval.tick().await;
+
+    loop {
+        interval.tick().await;
+        let current = modified(&path);
+        if current == last_modified {
+            continue;
+        }
+        last_modified = current;
+
+        match RedirectMap::load(&path) {
+            Ok(new_map) => {
+                println!("Reloaded redirect map {} ({} entries)", path.display(), new_map.len());
+                bus.publish("config_reload", serde_json::json!({
+                    "source": "redirect_map",
+                    "entries": new_map.len(),
+                }));
+                map.store(Arc::new(new_map));
+            }
+            Err(e) => {
+                logging::warning(format!("Keeping previous redirect map: {}", e));
+                bus.publish("config_rejected", serde_json::json!({
+                    "source": "redirect_map",
+                    "error": e,
+                }));
+            }
+        }
+    }
+}
@@ -0,0 +1,118 @@
+//! Zone-aware backend selection. Routes whose backends are tagged with zones
+//! (`10.0.0.1:8080@eu-west,10.1.0.1:8080@us-east`) send each connection to one zone: the proxy's
+//! own (`--zone`) while it has a backend up, otherwise the zone answering fastest. Backends of
+//! zoned routes are probed with a TCP connect every `--probe-interval` seconds, which gives their
+//! latency and takes unreachable ones out of the choice.
+
+use crate::logging;
+use crate::pool::{Pool, Strategy};
+use crate::routing::{Backend, SharedConfig};
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::net::TcpStream;
+
+/// Longest a probe waits for a backend to accept
+const PROBE_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// Share of a new probe in a backend's smoothed latency
+const SMOOTHING: f64 = 0.3;
+
+pub struct Regions {
+    /// The zone the proxy runs in (`--zone`)
+    local: Option<String>,
+    /// Smoothed connect time of each probed backend, or None while it fails its probes
+    probes: Mutex<HashMap<Arc<str>, Option<Duration>>>,
+}
+
+impl Regions {
+    pub fn new(local: Option<String>) -> Self {
+        Regions { local, probes: Mutex::default() }
+    }
+
+    /// The latest probe result of a backend: None when not probed yet, `Some(None)` when unreachable
+    pub fn latency(&self, backend: &str) -> Option<Option<Duration>> {
+        self.probes.lock().unwrap().get(backend).copied()
+    }
+
+    /// Choose the backend of a new connection as `Pool::pick` does, in the zone to use now for pools
+    /// with zones
+    pub fn pick<'a>(&self, pool: &'a Pool, strategy: Strategy, client: IpAddr, weight: impl Fn(&Backend, u32) -> u32) -> Option<&'a Backend> {
+        if pool.zoned().next().is_none() {
+            return pool.pick(strategy, client, None, weight);
+        }
+        let probes = self.probes.lock().unwrap();
+        let up = |backend: &Backend| !matches!(probes.get(&backend.name), Some(None));
+        if let Some(zone) = self.zone(pool, |backend, configured| weight(backend, configured) > 0 && up(backend), &probes) {
+            return pool.pick(strategy, client, Some(zone), |backend, configured| if up(backend) { weight(backend, configured) } else { 0 });
+        }
+        // Every probe failing can also mean the network towards the prober is at fault: rather
+        // than refusing all connections, fall back to what the weights allow
+        let zone = self.zone(pool, |backend, configured| weight(backend, configured) > 0, &probes)?;
+        pool.pick(strategy, client, Some(zone), weight)
+    }
+
+    /// The zone to send connections to: the local one when one of its backends can take them, else
+    /// the one with the lowest latency, zones whose latency is not known yet last in route order
+    fn zone<'a>(&self, pool: &'a Pool, usable: impl Fn(&Backend, u32) -> bool, probes: &HashMap<Arc<str>, Option<Duration>>) -> Option<&'a str> {
+        let mut best: Option<(&str, Option<Duration>)> = None;
+        for (backend, configured, zone) in pool.zoned() {
+            if !usable(backend, configured) {
+                continue;
+            }
+            if self.local.as_deref() == Some(zone) {
+                return Some(zone);
+            }
+            let latency = probes.get(&backend.name).copied().flatten();
+            let faster = match (best, latency) {
+                (None, _) => true,
+                (Some((_, None)), Some(_)) => true,
+                (Some((_, Some(best))), Some(latency)) => latency < best,
+                (Some(_), None) => false,
+            };
+            if faster {
+                best = Some((zone, latency));
+            }
+        }
+        best.map(|(zone, _)| zone)
+    }
+
+    /// Probe the backends of zoned routes every interval, with the tables current at the time
+    pub async fn probe(self: Arc<Self>, config: SharedConfig, interval: Duration) {
+        let mut ticks = tokio::time::interval(interval);
+        loop {
+            ticks.tick().await;
+            let targets: Vec<(Arc<str>, std::net::SocketAddr)> = config.load().zoned_backends().into_iter()
+                .map(|backend| (backend.name.clone(), backend.addr))
+                .collect();
+            let probes: Vec<_> = targets.iter().map(|&(_, addr)| tokio::spawn(async move {
+                let start = Instant::now();
+                match tokio::time::timeout(PROBE_TIMEOUT, TcpStream::connect(addr)).await {
+                    Ok(Ok(_)) => Some(start.elapsed()),
+                    _ => None,
+                }
+            })).collect();
+            let mut results = Vec::with_capacity(probes.len());
+            for probe in probes {
+                results.push(probe.await.ok().flatten());
+            }
+
+            let mut latencies = self.probes.lock().unwrap();
+            latencies.retain(|name, _| targets.iter().any(|(target, _)| target == name));
+            for ((name, _), result) in targets.into_iter().zip(results) {
+                let previous = latencies.get(&name).copied();
+                let latency = match (previous, result) {
+                    (Some(Some(previous)), Some(sample)) => Some(previous.mul_f64(1.0 - SMOOTHING) + sample.mul_f64(SMOOTHING)),
+                    (_, sample) => sample,
+                };
+                match (previous, latency) {
+                    (Some(Some(_)) | None, None) => logging::error(format!("Latency probe of backend {} failed", name)),
+                    (Some(None), Some(_)) => logging::info(format!("Backend {} answers latency probes again", name)),
+                    _ => {}
+                }
+                latencies.insert(name, latency);
+            }
+        }
+    }
+}
@@ -0,0 +1,106 @@
+//! Hot reload of the routing configuration: on SIGHUP, or when the `--config` file changes, the route
+//! table is rebuilt from the command line and the file and swapped in. Connections in flight keep the
+//! table they started with; an invalid configuration keeps the current one. Blue/green slots keep
+//! the color they were switched to.
+
+use crate::events::EventBus;
+use crate::logging;
+use crate::routing::{RouteConfig, SharedConfig};
+use crate::slot;
+use crate::Args;
+use clap::ArgMatches;
+use std::path::Path;
+use std::sync::Arc;
+use std::time::{Duration, SystemTime};
+
+/// How often the config file is checked for changes
+const RELOAD_INTERVAL: Duration = Duration::from_secs(5);
+
+/// Reload on SIGHUP and config file changes until the process exits. With a control plane, the
+/// controller owns the route table and reload requests are ignored.
+pub async fn run(matches: ArgMatches, config: SharedConfig, bus: Arc<EventBus>, control_plane: bool) {
+    let path = matches.get_one::<std::path::PathBuf>("config").cloned();
+    let modified = |path: &Path| std::fs::metadata(path).and_then(|m| m.modified()).ok();
+    let mut last_modified: Option<SystemTime> = path.as_deref().and_then(modified);
+
+    let mut hangup = hangup_signal();
+    let mut interval = tokio::time::interval(RELOAD_INTERVAL);
+    interval.tick().await;
+
+    loop {
+        let trigger = tokio::select! {
+            _ = hangup.recv() => "sighup",
+            _ = interval.tick(), if path.is_some() => {
+                if path.as_deref().and_then(modified) == last_modified {
+                    continue;
+                }
+                "file_change"
+            }
+        };
+        // A SIGHUP also covers file changes made before it
+        last_modified = path.as_deref().and_then(modified);
+
+        if control_plane {
+            logging::warning(format!("Ignoring configuration reload ({}): routes are managed by the control plane", trigger));
+            continue;
+        }
+        if path.is_none() && trigger == "sighup" {
+            println!("Received SIGHUP without --config: nothing to reload");
+            continue;
+        }
+
+        // Slots stay on the color they were switched to, as long as they still have a pool of it
+        let active_slots = config.load().snapshot().active_slots;
+        let reloaded = match Args::load(&matches) {
+            Ok(args) => {
+                let mut snapshot = args.snapshot();
+                snapshot.active_slots = slot::carry_over(active_slots, &snapshot.slots);
+                RouteConfig::compile(snapshot).await
+            }
+            Err(e) => Err(e),
+        };
+        match reloaded {
+            Ok(new_config) => {
+                println!("Reloaded configuration ({})", trigger);
+                bus.publish("config_reload", serde_json::json!({
+                    "source": "config_file",
+                    "trigger": trigger,
+                }));
+                config.store(Arc::new(new_config));
+            }
+            Err(e) => {
+                logging::warning(format!("Keeping previous configuration: {}", e));
+                bus.publish("config_rejected", serde_json::json!({
+                    "source": "config_file",
+                    "trigger": trigger,
+                    "error": e,
+                }));
+            }
+        }
+    }
+}
+
+/// SIGHUP on Unix; never fires elsewhere
+struct HangupSignal {
+    #[cfg(unix)]
+    signal: Option<tokio::signal::unix::Signal>,
+}
+
+impl HangupSignal {
+    async fn recv(&mut self) {
+        #[cfg(unix)]
+        if let Some(signal) = &mut self.signal {
+            signal.recv().await;
+            return;
+        }
+        std::future::pending::<()>().await
+    }
+}
+
+fn hangup_signal() -> HangupSignal {
+    HangupSignal {
+        // Installing the handler also keeps SIGHUP from terminating the process
+        #[cfg(unix)]
+        signal: tokio::signal::unix::signal(tokio::signal::unix::SignalKind::hangup()).ok(),
+    }
+}
@@ -0,0 +1,319 @@
+//! Zero-copy view of a client request head, borrowing from the buffer it was read into
+
+use std::borrow::Cow;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::OnceLock;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Maximum number of request headers accepted
+pub const MAX_HEADERS: usize = 64;
+
+/// The routing-relevant parts of a request head
+pub struct RequestHead<'b> {
+    pub method: &'b str,
+    /// Request path without the query string
+    pub path: &'b str,
+    pub query: Option<&'b str>,
+    /// Minor HTTP version: 1 for HTTP/1.1, 0 for HTTP/1.0
+    pub version: u8,
+    pub headers: &'b [httparse::Header<'b>],
+}
+
+impl<'b> RequestHead<'b> {
+    /// Parse a complete request head (up to and including the blank line) using `storage` for the headers
+    pub fn parse(head: &'b [u8], storage: &'b mut [httparse::Header<'b>]) -> Result<Self, String> {
+        let mut req = httparse::Request::new(storage);
+        match req.parse(head) {
+            Ok(httparse::Status::Complete(_)) => {}
+            Ok(httparse::Status::Partial) => return Err("Incomplete HTTP request head".to_string()),
+            Err(e) => return Err(format!("Failed to parse HTTP request: {}", e)),
+        }
+
+        let target = req.path.unwrap_or("/");
+        let (path, query) = match target.split_once('?') {
+            Some((path, query)) => (path, Some(query)),
+            None => (target, None),
+        };
+
+        Ok(RequestHead {
+            method: req.method.unwrap_or("GET"),
+            path,
+            query,
+            version: req.version.unwrap_or(1),
+            headers: req.headers,
+        })
+    }
+
+    /// The request target as sent: path and query
+    pub fn target(&self) -> Cow<'b, str> {
+        match self.query {
+            Some(query) => Cow::Owned(format!("{}?{}", self.path, query)),
+            None => Cow::Borrowed(self.path),
+        }
+    }
+
+    /// Value of the first header with the given (case-insensitive) name
+    pub fn header(&self, name: &str) -> Option<&'b [u8]> {
+        self.headers.iter()
+            .find(|h| h.name.eq_ignore_ascii_case(name))
+            .map(|h| h.value)
+    }
+
+    /// Like `header`, for values that are valid UTF-8
+    pub fn header_str(&self, name: &str) -> Option<&'b str> {
+        self.header(name).and_then(|value| std::str::from_utf8(value).ok())
+    }
+
+    /// Why the request's framing or Host is ambiguous, so that the proxy and a backend could read it
+    /// differently (request smuggling, RFC 9112 section 6.3): several `Host` or `Content-Length`
+    /// headers, a `Content-Length` that is not just digits, `Content-Length` with
+    /// `Transfer-Encoding`, or a `Transfer-Encoding` that does not end with `chunked`
+    pub fn ambiguity(&self) -> Option<&'static str> {
+        let all = |name: &'static str| self.headers.iter().filter(move |h| h.name.eq_ignore_ascii_case(name)).map(|h| h.value);
+        if all("host").count() > 1 {
+            return Some("duplicate Host headers");
+        }
+        let lengths: Vec<&[u8]> = all("content-length").collect();
+        let encodings: Vec<&[u8]> = all("transfer-encoding").collect();
+        if !lengths.is_empty() && !encodings.is_empty() {
+            return Some("Content-Length with Transfer-Encoding");
+        }
+        if let [length] = lengths[..] {
+            let digits = std::str::from_utf8(length).unwrap_or_default().trim_matches(|c| c == ' ' || c == '\t');
+            let valid = !digits.is_empty() && digits.bytes().all(|b| b.is_ascii_digit()) && digits.parse::<usize>().is_ok();
+            return (!valid).then_some("invalid Content-Length");
+        }
+        if lengths.len() > 1 {
+            return Some("duplicate Content-Length headers");
+        }
+        if encodings.is_empty() {
+            return None;
+        }
+        let codings: Vec<&str> = encodings.iter()
+            .flat_map(|value| std::str::from_utf8(value).unwrap_or("?").split(','))
+            .map(str::trim)
+            .collect();
+        let chunked = |coding: &str| coding.eq_ignore_ascii_case("chunked");
+        let valid = codings.last().is_some_and(|coding| chunked(coding)) && codings.iter().filter(|coding| chunked(coding)).count() == 1;
+        (!valid).then_some("Transfer-Encoding other than chunked")
+    }
+
+    /// The scheme of the request's connection-based authentication, which authenticates the
+    /// connection rather than the request: `NTLM` for `NTLM <token>` and NTLM wrapped in `Negotiate`
+    /// (tokens starting with the base64 of the `NTLMSSP` signature), `Negotiate` for other
+    /// `Negotiate` tokens (Kerberos), which backends such as IIS also keep for the connection
+    pub fn connection_auth(&self) -> Option<&'static str> {
+        let value = self.header_str("authorization")?.trim();
+        let (scheme, token) = value.split_once(' ').unwrap_or((value, ""));
+        if scheme.eq_ignore_ascii_case("ntlm") || (scheme.eq_ignore_ascii_case("negotiate") && token.trim_start().starts_with("TlRMTVNT")) {
+            Some("NTLM")
+        } else if scheme.eq_ignore_ascii_case("negotiate") {
+            Some("Negotiate")
+        } else {
+            None
+        }
+    }
+}
+
+/// The request's `X-Request-Id` if it sent one, otherwise a new ID unique within this process
+pub fn request_id(request: &RequestHead) -> String {
+    static SEED: OnceLock<u64> = OnceLock::new();
+    static COUNTER: AtomicU64 = AtomicU64::new(0);
+
+    if let Some(id) = request.header_str("x-request-id").filter(|id| !id.is_empty()) {
+        return id.to_string();
+    }
+    // Seeded with the start time, so IDs from restarted processes are unlikely to collide
+    let seed = *SEED.get_or_init(|| SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |d| d.as_nanos() as u64));
+    let n = COUNTER.fetch_add(1, Ordering::Relaxed);
+    format!("{:016x}", seed.wrapping_add(n.wrapping_mul(0x9e37_79b9_7f4a_7c15)))
+}
+
+/// The request with the path of its request line normalized, and the new head length; None when
+/// the path already is. Routes are matched on normalized paths, so `//admin/` and `/%61dmin` are
+/// no way around a route for `/admin`: escapes of unreserved characters are decoded, repeated
+/// slashes merged and `.` and `..` segments resolved (RFC 3986). Other escapes, such as `%2F`,
+/// are kept, with uppercase hex digits.
+pub fn normalize(data: &[u8], head_len: usize) -> Option<(Vec<u8>, usize)> {
+    let line_end = data[..head_len].iter().position(|&b| b == b'\r' || b == b'\n')?;
+    let line = std::str::from_utf8(&data[..line_end]).ok()?;
+    let start = line.find(' ')? + 1;
+    let end = start + line[start..].find([' ', '?']).unwrap_or(line.len() - start);
+    let path = &line[start..end];
+    // Asterisk-form (`OPTIONS *`) and absolute-form targets are left as they are
+    if !path.starts_with('/') {
+        return None;
+    }
+    let normalized = normalize_path(path);
+    if normalized == path {
+        return None;
+    }
+    let mut request = Vec::with_capacity(data.len());
+    request.extend_from_slice(&data[..start]);
+    request.extend_from_slice(normalized.as_bytes());
+    request.extend_from_slice(&data[end..]);
+    Some((request, head_len - path.len() + normalized.len()))
+}
+
+/// A path starting with `/` in the normal form described at `normalize`
+fn normalize_path(path: &str) -> String {
+    let bytes = path.as_bytes();
+    let mut decoded = String::with_capacity(path.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        let escape = (bytes[i] == b'%').then(|| path.get(i + 1..i + 3)).flatten()
+            .filter(|hex| hex.bytes().all(|b| b.is_ascii_hexdigit()))
+            .and_then(|hex| u8::from_str_radix(hex, 16).ok());
+        match escape {
+            Some(byte) if byte.is_ascii_alphanumeric() || b"-._~".contains(&byte) => decoded.push(byte as char),
+            Some(byte) => decoded.push_str(&format!("%{:02X}", byte)),
+            None => {
+                let c = path[i..].chars().next().unwrap();
+                decoded.push(c);
+                i += c.len_utf8();
+                continue;
+            }
+        }
+        i += 3;
+    }
+
+    let mut segments: Vec<&str> = Vec::new();
+    let mut parts = decoded[1..].split('/').peekable();
+    // A trailing `/`, `.` or `..` leaves the path ending in a slash
+    let mut trailing_slash = false;
+    while let Some(segment) = parts.next() {
+        let last = parts.peek().is_none();
+        match segment {
+            "" | "." => trailing_slash = last,
+            ".." => {
+                segments.pop();
+                trailing_slash = last;
+            }
+            _ => {
+                segments.push(segment);
+                trailing_slash = false;
+            }
+        }
+    }
+    let mut normalized = String::with_capacity(decoded.len());
+    for segment in &segments {
+        normalized.push('/');
+        normalized.push_str(segment);
+    }
+    if trailing_slash || segments.is_empty() {
+        normalized.push('/');
+    }
+    normalized
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// The request line of a request after `normalize`, or as it was when already normal
+    fn normalized(target: &str) -> String {
+        let data = format!("GET {} HTTP/1.1\r\nHost: example.com\r\n\r\n", target);
+        let head_len = data.len();
+        let (request, new_len) = normalize(data.as_bytes(), head_len).unwrap_or_else(|| (data.clone().into_bytes(), head_len));
+        assert_eq!(new_len, request.len());
+        let request = String::from_utf8(request).unwrap();
+        request.lines().next().unwrap().to_string()
+    }
+
+    #[test]
+    fn normalize_decodes_unreserved_escapes() {
+        assert_eq!(normalized("/%61dmin/%7Euser"), "GET /admin/~user HTTP/1.1");
+        assert_eq!(normalized("/a%2fb%3f"), "GET /a%2Fb%3F HTTP/1.1");
+    }
+
+    #[test]
+    fn normalize_merges_slashes_and_resolves_dot_segments() {
+        assert_eq!(normalized("//admin"), "GET /admin HTTP/1.1");
+        assert_eq!(normalized("/a/./b/../c"), "GET /a/c HTTP/1.1");
+        assert_eq!(normalized("/../../etc/passwd"), "GET /etc/passwd HTTP/1.1");
+        assert_eq!(normalized("/a/b/.."), "GET /a/ HTTP/1.1");
+        assert_eq!(normalized("/a/"), "GET /a/ HTTP/1.1");
+    }
+
+    #[test]
+    fn normalize_keeps_the_query() {
+        assert_eq!(normalized("/a//b?x=/../y&z=%61"), "GET /a/b?x=/../y&z=%61 HTTP/1.1");
+    }
+
+    #[test]
+    fn normalize_keeps_invalid_escapes() {
+        assert_eq!(normalized("/100%"), "GET /100% HTTP/1.1");
+        assert_eq!(normalized("/%zz"), "GET /%zz HTTP/1.1");
+        assert_eq!(normalized("/%+1"), "GET /%+1 HTTP/1.1");
+    }
+
+    #[test]
+    fn normalize_leaves_normal_and_other_targets_alone() {
+        for target in ["/", "/admin", "*", "http://example.com//a"] {
+            let data = format!("GET {} HTTP/1.1\r\n\r\n", target);
+            assert!(normalize(data.as_bytes(), data.len()).is_none(), "{}", target);
+        }
+    }
+
+    #[test]
+    fn normalize_keeps_the_bytes_after_the_head() {
+        let data = b"POST //upload HTTP/1.1\r\nContent-Length: 2\r\n\r\nhi";
+        let head_len = data.len() - 2;
+        let (request, new_len) = normalize(data, head_len).unwrap();
+        assert_eq!(&request[new_len..], b"hi");
+        assert!(request.starts_with(b"POST /upload HTTP/1.1\r\n"));
+    }
+
+    fn ambiguity(headers: &str) -> Option<&'static str> {
+        let data = format!("POST /upload HTTP/1.1\r\n{}\r\n", headers);
+        let mut storage = [httparse::EMPTY_HEADER; MAX_HEADERS];
+        RequestHead::parse(data.as_bytes(), &mut storage).unwrap().ambiguity()
+    }
+
+    #[test]
+    fn unambiguous_framing() {
+        assert_eq!(ambiguity("Host: example.com\r\n"), None);
+        assert_eq!(ambiguity("Host: example.com\r\nContent-Length: 12\r\n"), None);
+        assert_eq!(ambiguity("Host: example.com\r\nTransfer-Encoding: chunked\r\n"), None);
+        assert_eq!(ambiguity("Host: example.com\r\nTransfer-Encoding: gzip, Chunked\r\n"), None);
+        assert_eq!(ambiguity("Host: example.com\r\nTransfer-Encoding: gzip\r\nTransfer-Encoding: chunked\r\n"), None);
+    }
+
+    #[test]
+    fn ambiguous_content_length() {
+        assert_eq!(ambiguity("Content-Length: 5\r\nContent-Length: 5\r\n"), Some("duplicate Content-Length headers"));
+        assert_eq!(ambiguity("Content-Length: 5\r\ncontent-length: 6\r\n"), Some("duplicate Content-Length headers"));
+        for length in ["+5", "-5", "5, 5", "0x5", "5 5", "", "99999999999999999999999"] {
+            assert_eq!(ambiguity(&format!("Content-Length: {}\r\n", length)), Some("invalid Content-Length"), "{}", length);
+        }
+        assert_eq!(ambiguity("Content-Length: 5\r\nTransfer-Encoding: chunked\r\n"), Some("Content-Length with Transfer-Encoding"));
+    }
+
+    #[test]
+    fn ambiguous_transfer_encoding() {
+        for encoding in ["gzip", "chunked, gzip", "chunked, chunked", "xchunked", ""] {
+            assert_eq!(ambiguity(&format!("Transfer-Encoding: {}\r\n", encoding)), Some("Transfer-Encoding other than chunked"), "{}", encoding);
+        }
+        assert_eq!(ambiguity("Transfer-Encoding: chunked\r\nTransfer-Encoding: chunked\r\n"), Some("Transfer-Encoding other than chunked"));
+    }
+
+    #[test]
+    fn duplicate_host() {
+        assert_eq!(ambiguity("Host: example.com\r\nHost: internal\r\n"), Some("duplicate Host headers"));
+        assert_eq!(ambiguity("Host: example.com\r\nhost: example.com\r\n"), Some("duplicate Host headers"));
+    }
+
+    #[test]
+    fn connection_authentication_schemes() {
+        let scheme = |authorization: &str| {
+            let data = format!("GET / HTTP/1.1\r\nAuthorization: {}\r\n\r\n", authorization);
+            let mut storage = [httparse::EMPTY_HEADER; MAX_HEADERS];
+            RequestHead::parse(data.as_bytes(), &mut storage).unwrap().connection_auth()
+        };
+        assert_eq!(scheme("NTLM TlRMTVNTUAABAAAA"), Some("NTLM"));
+        assert_eq!(scheme("Negotiate TlRMTVNTUAABAAAA"), Some("NTLM"));
+        assert_eq!(scheme("negotiate YIIGhgYGKwYBBQUC"), Some("Negotiate"));
+        assert_eq!(scheme("Bearer abc"), None);
+        assert_eq!(scheme("Basic dXNlcjpwYXNz"), None);
+    }
+}
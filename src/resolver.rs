@@ -0,0 +1,135 @@
+//! Backends given by host name (`app.internal:8080`) are looked up again when connections to them
+//! find their addresses older than `--dns-ttl`, so DNS-based failover and scaling reach the proxy
+//! without a restart. Connections take turns over all the addresses a name has, and move on to the
+//! next one when an address refuses; a lookup that fails keeps the addresses of the last one.
+
+use crate::logging;
+use crate::routing::Backend;
+use std::collections::HashMap;
+use std::future::Future;
+use std::io;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::net::TcpStream;
+
+/// Longest a lookup may take; the addresses on hand are used after that
+const LOOKUP_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// What is known about a backend's host name
+struct Host {
+    /// Sorted, so changes show as such whatever order the answers come in
+    addrs: Vec<SocketAddr>,
+    /// When the latest lookup finished, successful or not
+    looked_up: Option<Instant>,
+    /// Turn of the next connection among the addresses
+    next: usize,
+    /// A lookup is on its way; other connections go on with the addresses on hand
+    looking_up: bool,
+    /// The latest lookup failed
+    failing: bool,
+}
+
+pub struct Resolver {
+    ttl: Duration,
+    hosts: Mutex<HashMap<Arc<str>, Host>>,
+}
+
+impl Resolver {
+    pub fn new(ttl: Duration) -> Self {
+        Resolver { ttl, hosts: Mutex::default() }
+    }
+
+    /// Connect to a backend with `connect`, trying its addresses in turn until one accepts. The
+    /// time a lookup took is added to `dns` when this connection made one.
+    pub async fn connect<F, C>(self: &Arc<Self>, backend: &Backend, dns: &mut Option<Duration>, connect: F) -> io::Result<TcpStream>
+    where
+        F: Fn(SocketAddr) -> C,
+        C: Future<Output = io::Result<TcpStream>>,
+    {
+        if !backend.named {
+            return connect(backend.addr).await;
+        }
+        let mut last_error = None;
+        for addr in self.addresses(backend, dns).await {
+            match connect(addr).await {
+                Ok(tcp) => return Ok(tcp),
+                Err(e) => last_error = Some(e),
+            }
+        }
+        Err(last_error.expect("a host has at least one address"))
+    }
+
+    /// The addresses to try for a named backend, starting with the one whose turn it is
+    async fn addresses(self: &Arc<Self>, backend: &Backend, dns: &mut Option<Duration>) -> Vec<SocketAddr> {
+        let stale = {
+            let mut hosts = self.hosts.lock().unwrap();
+            let host = hosts.entry(backend.name.clone())
+                .or_insert_with(|| Host { addrs: vec![backend.addr], looked_up: None, next: 0, looking_up: false, failing: false });
+            let stale = !host.looking_up && host.looked_up.map_or(true, |at| at.elapsed() >= self.ttl);
+            host.looking_up |= stale;
+            stale
+        };
+        if stale {
+            // The lookup finishes and is recorded even when this connection is given up meanwhile
+            let start = Instant::now();
+            let (resolver, name) = (self.clone(), backend.name.clone());
+            let _ = tokio::spawn(async move { resolver.look_up(name).await }).await;
+            *dns = Some(dns.unwrap_or_default() + start.elapsed());
+        }
+
+        let mut hosts = self.hosts.lock().unwrap();
+        let host = hosts.get_mut(&backend.name).expect("host entered above");
+        let start = host.next % host.addrs.len();
+        host.next = host.next.wrapping_add(1);
+        let mut addrs = host.addrs.clone();
+        addrs.rotate_left(start);
+        addrs
+    }
+
+    /// Look a backend's host name up and record its addresses
+    async fn look_up(&self, name: Arc<str>) {
+        let answer = match tokio::time::timeout(LOOKUP_TIMEOUT, tokio::net::lookup_host(&*name)).await {
+            Ok(Ok(addrs)) => {
+                let mut addrs: Vec<SocketAddr> = addrs.collect();
+                addrs.sort();
+                addrs.dedup();
+                match addrs.is_empty() {
+                    true => Err("no addresses".to_string()),
+                    false => Ok(addrs),
+                }
+            }
+            Ok(Err(e)) => Err(e.to_string()),
+            Err(_) => Err(format!("no answer within {} s", LOOKUP_TIMEOUT.as_secs())),
+        };
+
+        let mut hosts = self.hosts.lock().unwrap();
+        let Some(host) = hosts.get_mut(&name) else {
+            return;
+        };
+        host.looked_up = Some(Instant::now());
+        host.looking_up = false;
+        match answer {
+            Ok(addrs) => {
+                if host.failing {
+                    logging::info(format!("Backend {} resolves again", name));
+                }
+                if addrs != host.addrs {
+                    logging::info(format!("Backend {} resolves to {}", name, list(&addrs)));
+                    host.addrs = addrs;
+                }
+                host.failing = false;
+            }
+            Err(e) => {
+                if !host.failing {
+                    logging::warning(format!("Failed to resolve backend {}, keeping {}: {}", name, list(&host.addrs), e));
+                }
+                host.failing = true;
+            }
+        }
+    }
+}
+
+fn list(addrs: &[SocketAddr]) -> String {
+    addrs.iter().map(ToString::to_string).collect::<Vec<_>>().join(", ")
+}
@@ -0,0 +1,338 @@
+//! Responses generated by the proxy itself instead of a backend
+
+use crate::request::RequestHead;
+use std::borrow::Cow;
+use std::net::SocketAddr;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// An HTTP response answered by the proxy itself: pre-serialized, or rendered per request from a template
+pub struct LocalResponse {
+    pub status: u16,
+    /// Status line, headers and body; for templates, the head up to where `Content-Length` goes
+    bytes: Vec<u8>,
+    /// Length of the status line and headers within `bytes`
+    head_len: usize,
+    template: Option<Template>,
+}
+
+/// Headers the proxy always sets itself on local responses
+const MANAGED_HEADERS: &[&str] = &["content-length", "connection", "transfer-encoding"];
+
+impl LocalResponse {
+    /// Build a response; `headers` are added as given, and a `Content-Type` among them replaces `content_type`.
+    /// Bodies containing placeholders (`{client_ip}`, `{header:User-Agent}`, ...) are rendered per request.
+    pub fn new(status: u16, content_type: &str, body: &str, headers: &[(String, String)]) -> Result<Self, String> {
+        LocalResponse::build(status, content_type, body, headers, true)
+    }
+
+    /// Build a response whose body is sent exactly as given, braces included
+    pub fn literal(status: u16, content_type: &str, body: &str) -> Result<Self, String> {
+        LocalResponse::build(status, content_type, body, &[], false)
+    }
+
+    fn build(status: u16, content_type: &str, body: &str, headers: &[(String, String)], templated: bool) -> Result<Self, String> {
+        if !(200..=599).contains(&status) {
+            return Err(format!("Invalid response status {}: expected 200-599", status));
+        }
+        let extra = format_headers(headers, MANAGED_HEADERS)?;
+
+        if matches!(status, 204 | 304) {
+            // These responses never carry a body, nor the headers describing one
+            if !body.is_empty() {
+                return Err(format!("Response status {} cannot have a body", status));
+            }
+            let head = format!("HTTP/1.1 {} {}\r\n{}Connection: close\r\n\r\n", status, reason_phrase(status), extra);
+            return Ok(LocalResponse { status, head_len: head.len(), bytes: head.into_bytes(), template: None });
+        }
+
+        let custom_type = headers.iter().find(|(name, _)| name.eq_ignore_ascii_case("content-type"));
+        let content_type_header = match custom_type {
+            Some(_) => String::new(),
+            None => format!("Content-Type: {}\r\n", content_type),
+        };
+        let content_type = custom_type.map_or(content_type, |(_, value)| value.as_str());
+        let head_start = format!("HTTP/1.1 {} {}\r\n{}{}", status, reason_phrase(status), content_type_header, extra);
+
+        if let Some(template) = templated.then(|| Template::parse(body, content_type.starts_with("application/json"))).flatten() {
+            return Ok(LocalResponse { status, head_len: head_start.len(), bytes: head_start.into_bytes(), template: Some(template) });
+        }
+
+        let head = format!("{}Content-Length: {}\r\nConnection: close\r\n\r\n", head_start, body.len());
+        let head_len = head.len();
+        let mut bytes = head.into_bytes();
+        bytes.extend_from_slice(body.as_bytes());
+
+        Ok(LocalResponse { status, bytes, head_len, template: None })
+    }
+
+    /// The response to send for a request (no body for HEAD)
+    pub fn bytes(&self, request: &RequestHead, client: SocketAddr) -> Cow<'_, [u8]> {
+        let with_body = request.method != "HEAD";
+        let Some(template) = &self.template else {
+            return Cow::Borrowed(if with_body { &self.bytes } else { &self.bytes[..self.head_len] });
+        };
+
+        let body = template.render(request, client);
+        let mut bytes = self.bytes.clone();
+        bytes.extend_from_slice(format!("Content-Length: {}\r\nConnection: close\r\n\r\n", body.len()).as_bytes());
+        if with_body {
+            bytes.extend_from_slice(body.as_bytes());
+        }
+        Cow::Owned(bytes)
+    }
+
+    pub fn reason(&self) -> &'static str {
+        reason_phrase(self.status)
+    }
+}
+
+/// A response body with request placeholders, parsed once when the route table is loaded
+struct Template {
+    parts: Vec<Part>,
+    /// Escape substituted values for use inside JSON strings
+    json: bool,
+}
+
+enum Part {
+    Literal(String),
+    ClientIp,
+    Date,
+    RequestId,
+    Method,
+    Path,
+    Query,
+    Host,
+    Header(String),
+}
+
+impl Template {
+    /// Parse a body; None when it has no placeholders. Braces that are not a known placeholder stay literal.
+    fn parse(body: &str, json: bool) -> Option<Self> {
+        let mut parts = Vec::new();
+        let mut literal = String::new();
+        let mut rest = body;
+
+        while let Some(start) = rest.find('{') {
+            let placeholder = rest[start + 1..].find('}').and_then(|end| {
+                let part = match &rest[start + 1..start + 1 + end] {
+                    "client_ip" => Part::ClientIp,
+                    "date" => Part::Date,
+                    "request_id" => Part::RequestId,
+                    "method" => Part::Method,
+                    "path" => Part::Path,
+                    "query" => Part::Query,
+                    "host" => Part::Host,
+                    name => match name.strip_prefix("header:") {
+                        Some(header) if !header.is_empty() => Part::Header(header.to_string()),
+                        _ => return None,
+                    },
+                };
+                Some((part, start + end + 2))
+            });
+
+            match placeholder {
+                Some((part, next)) => {
+                    literal.push_str(&rest[..start]);
+                    if !literal.is_empty() {
+                        parts.push(Part::Literal(std::mem::take(&mut literal)));
+                    }
+                    parts.push(part);
+                    rest = &rest[next..];
+                }
+                None => {
+                    literal.push_str(&rest[..=start]);
+                    rest = &rest[start + 1..];
+                }
+            }
+        }
+        literal.push_str(rest);
+
+        if parts.is_empty() {
+            return None;
+        }
+        if !literal.is_empty() {
+            parts.push(Part::Literal(literal));
+        }
+        Some(Template { parts, json })
+    }
+
+    fn render(&self, request: &RequestHead, client: SocketAddr) -> String {
+        let mut body = String::new();
+        for part in &self.parts {
+            let value = match part {
+                Part::Literal(text) => {
+                    body.push_str(text);
+                    continue;
+                }
+                Part::ClientIp => Cow::Owned(client.ip().to_string()),
+                Part::Date => Cow::Owned(http_date(SystemTime::now())),
+                Part::RequestId => Cow::Owned(crate::request::request_id(request)),
+                Part::Method => Cow::Borrowed(request.method),
+                Part::Path => Cow::Borrowed(request.path),
+                Part::Query => Cow::Borrowed(request.query.unwrap_or("")),
+                Part::Host => Cow::Borrowed(request.header_str("host").unwrap_or("")),
+                Part::Header(name) => request.header(name).map_or(Cow::Borrowed(""), String::from_utf8_lossy),
+            };
+            if self.json {
+                // serde_json quotes the string; keep only the escaped contents
+                let quoted = serde_json::Value::from(value.as_ref()).to_string();
+                body.push_str(&quoted[1..quoted.len() - 1]);
+            } else {
+                body.push_str(&value);
+            }
+        }
+        body
+    }
+}
+
+/// Format a time as an HTTP date (`Sun, 06 Nov 1994 08:49:37 GMT`)
+pub fn http_date(time: SystemTime) -> String {
+    const DAYS: [&str; 7] = ["Thu", "Fri", "Sat", "Sun", "Mon", "Tue", "Wed"];
+    const MONTHS: [&str; 12] = ["Jan", "Feb", "Mar", "Apr", "May", "Jun", "Jul", "Aug", "Sep", "Oct", "Nov", "Dec"];
+
+    let secs = time.duration_since(UNIX_EPOCH).map_or(0, |d| d.as_secs());
+    let (days, secs_of_day) = (secs / 86400, secs % 86400);
+    let (year, month, day) = civil_date(days);
+
+    format!(
+        "{}, {:02} {} {} {:02}:{:02}:{:02} GMT",
+        DAYS[(days % 7) as usize], day, MONTHS[(month - 1) as usize], year,
+        secs_of_day / 3600, secs_of_day % 3600 / 60, secs_of_day % 60
+    )
+}
+
+/// Format a time as an RFC 3339 UTC timestamp with milliseconds (`1994-11-06T08:49:37.000Z`)
+pub fn rfc3339(time: SystemTime) -> String {
+    let since_epoch = time.duration_since(UNIX_EPOCH).unwrap_or_default();
+    let secs = since_epoch.as_secs();
+    let (days, secs_of_day) = (secs / 86400, secs % 86400);
+    let (year, month, day) = civil_date(days);
+    format!(
+        "{}-{:02}-{:02}T{:02}:{:02}:{:02}.{:03}Z",
+        year, month, day, secs_of_day / 3600, secs_of_day % 3600 / 60, secs_of_day % 60, since_epoch.subsec_millis()
+    )
+}
+
+/// Civil date (year, month, day) from days since 1970-01-01 (Howard Hinnant's algorithm)
+fn civil_date(days: u64) -> (i64, i64, i64) {
+    let z = days as i64 + 719_468;
+    let era = z.div_euclid(146_097);
+    let doe = z - era * 146_097;
+    let yoe = (doe - doe / 1460 + doe / 36_524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    (yoe + era * 400 + i64::from(month <= 2), month, day)
+}
+
+/// Serialize extra response headers, rejecting malformed ones and those in `managed` (lowercase)
+fn format_headers(headers: &[(String, String)], managed: &[&str]) -> Result<String, String> {
+    let mut out = String::new();
+    for (name, value) in headers {
+        let valid_name = !name.is_empty() && name.bytes().all(|b| b.is_ascii_alphanumeric() || b"!#$%&'*+-.^_`|~".contains(&b));
+        if !valid_name {
+            return Err(format!("Invalid response header name '{}'", name));
+        }
+        if value.contains(['\r', '\n']) {
+            return Err(format!("Invalid value for response header '{}'", name));
+        }
+        if managed.iter().any(|m| name.eq_ignore_ascii_case(m)) {
+            return Err(format!("Response header '{}' is set by the proxy and cannot be overridden", name));
+        }
+        out.push_str(&format!("{}: {}\r\n", name, value));
+    }
+    Ok(out)
+}
+
+/// Reason phrase of a status code; unknown codes get an empty phrase, which HTTP/1.1 allows
+pub fn reason_phrase(status: u16) -> &'static str {
+    match status {
+        200 => "OK",
+        201 => "Created",
+        202 => "Accepted",
+        103 => "Early Hints",
+        204 => "No Content",
+        301 => "Moved Permanently",
+        302 => "Found",
+        303 => "See Other",
+        304 => "Not Modified",
+        307 => "Temporary Redirect",
+        308 => "Permanent Redirect",
+        400 => "Bad Request",
+        401 => "Unauthorized",
+        403 => "Forbidden",
+        404 => "Not Found",
+        405 => "Method Not Allowed",
+        409 => "Conflict",
+        410 => "Gone",
+        413 => "Payload Too Large",
+        422 => "Unprocessable Content",
+        429 => "Too Many Requests",
+        451 => "Unavailable For Legal Reasons",
+        499 => "Client Closed Request",
+        500 => "Internal Server Error",
+        501 => "Not Implemented",
+        502 => "Bad Gateway",
+        503 => "Service Unavailable",
+        504 => "Gateway Timeout",
+        _ => "",
+    }
+}
+
+/// A redirect to a URL template, answered by the proxy itself
+pub struct Redirect {
+    pub status: u16,
+    /// Target URL; `{path}` and `{query}` are substituted, otherwise both are appended
+    target: String,
+    /// Extra headers, serialized
+    headers: String,
+}
+
+impl Redirect {
+    pub fn new(status: u16, target: &str, headers: &[(String, String)]) -> Result<Self, String> {
+        if !matches!(status, 301 | 302 | 303 | 307 | 308) {
+            return Err(format!("Invalid redirect status {}: expected 301, 302, 303, 307 or 308", status));
+        }
+        if target.is_empty() || target.contains(char::is_whitespace) {
+            return Err(format!("Invalid redirect target '{}'", target));
+        }
+        let headers = format_headers(headers, &["location", "content-length", "connection", "transfer-encoding"])?;
+        Ok(Redirect { status, target: target.to_string(), headers })
+    }
+
+    /// The `Location` for a request, given the path left after the matched route prefix
+    pub fn location(&self, remaining_path: &str, query: Option<&str>) -> String {
+        let query = query.map(|q| format!("?{}", q)).unwrap_or_default();
+        // Like path rewriting: `/old` matching `/oldies` leaves `/ies`
+        let remaining_path = if remaining_path.is_empty() || remaining_path.starts_with('/') {
+            remaining_path.to_string()
+        } else {
+            format!("/{}", remaining_path)
+        };
+        let remaining_path = remaining_path.as_str();
+
+        if self.target.contains("{path}") || self.target.contains("{query}") {
+            return self.target.replace("{path}", remaining_path).replace("{query}", &query);
+        }
+
+        // A target with a query of its own gets the request's parameters appended to it
+        let query = match query.strip_prefix('?') {
+            Some(params) if self.target.contains('?') && remaining_path.is_empty() => format!("&{}", params),
+            _ => query,
+        };
+        let base = if remaining_path.starts_with('/') { self.target.trim_end_matches('/') } else { &self.target };
+        format!("{}{}{}", base, remaining_path, query)
+    }
+
+    pub fn response(&self, location: &str) -> Vec<u8> {
+        format!(
+            "HTTP/1.1 {} {}\r\nLocation: {}\r\n{}Content-Length: 0\r\nConnection: close\r\n\r\n",
+            self.status, reason_phrase(self.status), location, self.headers
+        ).into_bytes()
+    }
+
+    pub fn target(&self) -> &str {
+        &self.target
+    }
+}
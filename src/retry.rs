@@ -0,0 +1,148 @@
+//! Retries on another backend (`;retry=2`): requests whose backend refuses the connection, or
+//! answers with a retryable status before anything reached the client, are sent again to another
+//! backend of the route. Retries are limited by a budget shared by all routes (`--retry-budget`),
+//! so a struggling service does not get its load multiplied by them.
+
+use std::io;
+use std::pin::Pin;
+use std::sync::Mutex;
+use std::task::{Context, Poll};
+use std::time::{Duration, Instant};
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+
+/// Statuses retried when the route does not list its own
+const DEFAULT_STATUSES: [u16; 3] = [502, 503, 504];
+
+/// Most retries a route may give a request
+const MAX_ATTEMPTS: u32 = 10;
+
+/// Period over which the budget compares retries with requests
+const BUDGET_WINDOW: Duration = Duration::from_secs(10);
+
+/// Retries allowed in each window however few requests there were, so quiet routes can retry
+const MIN_RETRIES: u64 = 10;
+
+/// A `;retry=ATTEMPTS[:STATUS,...]` option
+#[derive(Clone)]
+pub struct RetryPolicy {
+    /// Backends tried after the first one, at most
+    pub attempts: u32,
+    /// Response statuses that have the request sent to another backend
+    pub statuses: Vec<u16>,
+}
+
+impl RetryPolicy {
+    pub fn parse(value: &str, route: &str) -> Result<Self, String> {
+        let invalid = || format!("Invalid retry '{}' in route '{}'. Expected ATTEMPTS (1-{}), optionally followed by :STATUS,... (default 502,503,504)", value, route, MAX_ATTEMPTS);
+        let (attempts, statuses) = match value.split_once(':') {
+            Some((attempts, statuses)) => {
+                let statuses: Option<Vec<u16>> = statuses.split(',').map(|status| status.trim().parse().ok().filter(|status| (500..600).contains(status))).collect();
+                (attempts, statuses.ok_or_else(invalid)?)
+            }
+            None => (value, DEFAULT_STATUSES.to_vec()),
+        };
+        let attempts = attempts.parse().ok().filter(|attempts| (1..=MAX_ATTEMPTS).contains(attempts)).ok_or_else(invalid)?;
+        Ok(RetryPolicy { attempts, statuses })
+    }
+
+    /// Whether a response status has the request retried
+    pub fn retries(&self, status: u16) -> bool {
+        self.statuses.contains(&status)
+    }
+}
+
+/// Whether a request may be sent again after a backend answered it: the backend may have acted on
+/// requests with other methods before failing
+pub fn is_idempotent(method: &str) -> bool {
+    matches!(method, "GET" | "HEAD" | "OPTIONS" | "TRACE" | "PUT" | "DELETE")
+}
+
+/// Retries allowed as a share of the requests to routes with `;retry`, counted per window
+pub struct RetryBudget {
+    /// Retries per request allowed
+    ratio: f64,
+    /// Start of the window, with its requests and retries
+    window: Mutex<(Instant, u64, u64)>,
+}
+
+impl RetryBudget {
+    pub fn new(percent: u32) -> Self {
+        RetryBudget { ratio: f64::from(percent) / 100.0, window: Mutex::new((Instant::now(), 0, 0)) }
+    }
+
+    /// Count a request that may be retried
+    pub fn record_request(&self) {
+        let mut window = self.window.lock().unwrap();
+        Self::roll(&mut window);
+        window.1 += 1;
+    }
+
+    /// Take a retry from the budget, or say there is none left
+    pub fn try_retry(&self) -> bool {
+        let mut window = self.window.lock().unwrap();
+        Self::roll(&mut window);
+        let (_, requests, retries) = *window;
+        let allowed = MIN_RETRIES.max((requests as f64 * self.ratio) as u64);
+        if retries >= allowed {
+            return false;
+        }
+        window.2 += 1;
+        true
+    }
+
+    fn roll(window: &mut (Instant, u64, u64)) {
+        if window.0.elapsed() >= BUDGET_WINDOW {
+            *window = (Instant::now(), 0, 0);
+        }
+    }
+}
+
+/// A backend connection with the start of its response read ahead, to decide on a retry before
+/// anything is forwarded. The bytes read ahead come first when the response is streamed.
+pub struct ReadAhead<B> {
+    inner: B,
+    ahead: Vec<u8>,
+    pos: usize,
+}
+
+impl<B> ReadAhead<B> {
+    pub fn new(inner: B, ahead: Vec<u8>) -> Self {
+        ReadAhead { inner, ahead, pos: 0 }
+    }
+
+    pub fn get_ref(&self) -> &B {
+        &self.inner
+    }
+
+    /// The backend connection, once the bytes read ahead have been streamed
+    pub fn into_inner(self) -> B {
+        self.inner
+    }
+}
+
+impl<B: AsyncRead + Unpin> AsyncRead for ReadAhead<B> {
+    fn poll_read(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        if this.pos < this.ahead.len() {
+            let n = buf.remaining().min(this.ahead.len() - this.pos);
+            buf.put_slice(&this.ahead[this.pos..this.pos + n]);
+            this.pos += n;
+            return Poll::Ready(Ok(()));
+        }
+        Pin::new(&mut this.inner).poll_read(cx, buf)
+    }
+}
+
+impl<B: AsyncWrite + Unpin> AsyncWrite for ReadAhead<B> {
+    fn poll_write(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.get_mut().inner).poll_write(cx, buf)
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().inner).poll_shutdown(cx)
+    }
+}
@@ -0,0 +1,111 @@
+//! `reverse-http-proxy routes test METHOD PATH [-H 'NAME: VALUE']... [OPTIONS]`: route a made-up
+//! request through the route table the proxy would start with, and print which route matches and
+//! why, the backend it goes to and the request as the backend would receive it
+
+use crate::request::{self, RequestHead, MAX_HEADERS};
+use crate::routing::{Action, RouteConfig};
+use crate::{dns, k8s, pool, state, Args, ForwardedRequest};
+use clap::ArgMatches;
+use std::net::{IpAddr, Ipv4Addr};
+
+/// The client backends are picked for, as `ip-hash` pools need one
+const CLIENT: IpAddr = IpAddr::V4(Ipv4Addr::LOCALHOST);
+
+/// Print how the request is routed; returns whether the options and request are valid
+pub fn run(matches: &ArgMatches) -> bool {
+    match test(matches) {
+        Ok(()) => true,
+        Err(e) => {
+            eprintln!("error: {}", e);
+            false
+        }
+    }
+}
+
+fn test(matches: &ArgMatches) -> Result<(), String> {
+    let args = Args::load(matches)?;
+    if let Some(server) = &args.dns_server {
+        dns::set_nameserver(server.parse().map_err(|e| format!("Invalid --dns-server '{}': {}", server, e))?);
+    }
+    if let Some(url) = &args.k8s_api {
+        k8s::set_api(url)?;
+    }
+    // The routes the proxy starts with: a state file's, if it has saved any
+    let snapshot = match &args.state_file {
+        Some(path) => state::Snapshot::load(path)?,
+        None => None,
+    };
+    let config = RouteConfig::from_snapshot(snapshot.unwrap_or_else(|| args.snapshot()))?;
+    let lb = pool::Strategy::parse(&args.lb)?;
+
+    let method = matches.get_one::<String>("method").unwrap();
+    let path = matches.get_one::<String>("path").unwrap();
+    let mut request_data = format!("{} {} HTTP/1.1\r\n", method, path);
+    for header in matches.get_many::<String>("header").into_iter().flatten() {
+        match header.split_once(':') {
+            Some((name, value)) if !name.trim().is_empty() => request_data.push_str(&format!("{}: {}\r\n", name.trim(), value.trim())),
+            _ => return Err(format!("Invalid header '{}'. Expected format: 'NAME: VALUE'", header)),
+        }
+    }
+    request_data.push_str("\r\n");
+    // The path is routed in its normal form, as the proxy does
+    let original_line = format!("{} {} HTTP/1.1", method, path).into_bytes();
+    let (request_data, original_line) = match request::normalize(request_data.as_bytes(), request_data.len()) {
+        Some((normalized, _)) => (normalized, Some(original_line)),
+        None => (request_data.into_bytes(), None),
+    };
+    let mut storage = [httparse::EMPTY_HEADER; MAX_HEADERS];
+    let head = RequestHead::parse(&request_data, &mut storage)?;
+
+    let route = config.get_backend_and_prefix(&head);
+    println!("Request:   {} {} HTTP/1.1", head.method, head.target());
+    let Some(action) = route.action else {
+        println!("Route:     none");
+        print_lines("Why:", &config.explain(&head));
+        println!("Answer:    {} (--not-found-status)", args.not_found_status);
+        return Ok(());
+    };
+    println!("Route:     {}", route.name());
+    print_lines("Why:", &config.explain(&head));
+    let mut notes = Vec::new();
+    if let Some(listeners) = route.listeners() {
+        notes.push(format!("only served on listeners {}", listeners.join(", ")));
+    }
+    if let Some(identities) = route.psk_identities() {
+        notes.push(format!("only for TLS-PSK clients with identity {}", identities.join(", ")));
+    }
+    if route.options.verbatim {
+        notes.push("verbatim: forwarded exactly as received".to_string());
+    }
+    print_lines("Notes:", &notes);
+
+    let pool = match action {
+        Action::Proxy(pool) => pool,
+        answer => {
+            println!("Answer:    {}", answer);
+            return Ok(());
+        }
+    };
+    let picked = pool.pick(lb, CLIENT, None, |backend, weight| if config.is_drained(backend) { 0 } else { config.weight(backend, weight) });
+    let Some(backend) = picked else {
+        println!("Backend:   none available from {}", pool);
+        return Ok(());
+    };
+    if pool.only().is_some() {
+        println!("Backend:   {}", backend);
+    } else {
+        println!("Backend:   {}, by {} for client {} from {}", backend, args.lb, CLIENT, pool);
+    }
+    let forwarded = ForwardedRequest::new(&request_data, request_data.len(), original_line.as_deref(), &head, &route, backend, &[]).to_vec();
+    let forwarded = String::from_utf8_lossy(&forwarded);
+    let lines: Vec<String> = forwarded.lines().filter(|line| !line.is_empty()).map(str::to_string).collect();
+    print_lines("Forwarded:", &lines);
+    Ok(())
+}
+
+/// Lines under a label, the label on the first
+fn print_lines(label: &str, lines: &[String]) {
+    for (idx, line) in lines.iter().enumerate() {
+        println!("{:<10} {}", if idx == 0 { label } else { "" }, line);
+    }
+}
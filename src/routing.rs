@@ -0,0 +1,1879 @@
+use crate::blocklist::Mode as BlocklistMode;
+use crate::headers::HeaderRules;
+use crate::heartbeat::Heartbeat;
+use crate::mirror::Mirror;
+use crate::pool::{Pool, Strategy};
+use crate::ratelimit::{AsnRule, LimitKey, RateLimit, RouteLimit};
+use crate::region::Regions;
+use crate::request::RequestHead;
+use crate::response::{LocalResponse, Redirect};
+use crate::retry::RetryPolicy;
+use crate::shaper::BackendRate;
+use crate::slot::Slots;
+use crate::state::Snapshot;
+use crate::trie::RadixTrie;
+use arc_swap::ArcSwap;
+use regex::Regex;
+use std::borrow::Cow;
+use std::collections::{BTreeMap, HashMap};
+use std::fmt;
+use std::net::{IpAddr, SocketAddr, ToSocketAddrs};
+use std::sync::Arc;
+use std::time::Duration;
+
+/// The active route table, replaced atomically when routes change at runtime.
+/// Readers never lock: they load the current `Arc`, and a reload publishes a new table.
+pub type SharedConfig = Arc<ArcSwap<RouteConfig>>;
+
+/// A backend with the routes using it and its weight in each
+pub type BackendUse<'a> = (&'a Backend, Vec<(&'a Arc<str>, u32)>);
+
+/// Route name reported for requests served by the default backend
+const DEFAULT_ROUTE: &str = "default";
+
+/// Prefixes of route actions answered by the proxy itself (`respond:200:OK`, `redirect:301:https://...`)
+const RESPOND_PREFIX: &str = "respond:";
+const REDIRECT_PREFIX: &str = "redirect:";
+
+/// A compiled route table. Backends and route names are shared `Arc<str>`s, so a lookup borrows
+/// from the table and callers can keep them past the request without copying.
+pub struct RouteConfig {
+    /// Action for requests no route matches; without one they get the proxy's not-found response
+    pub default_backend: Option<Action>,
+    default_route: Arc<str>,
+    /// The default route's options: none
+    default_options: RouteOptions,
+    /// Header routes, evaluated before host and path routes
+    pub header_routes: ParamRoutes,
+    /// Query-parameter routes, evaluated after header routes
+    pub query_routes: ParamRoutes,
+    pub routes: PathRoutes,
+    pub virtual_hosts: BTreeMap<String, VirtualHost>,
+    pub rewrite_paths: bool,
+    /// Rewrite for routes without their own rule: `Strip` with `--rewrite`
+    default_rewrite: Option<PathRewrite>,
+    pub preserve_host: bool,
+    /// Host header policy for routes without their own: `Backend` with `--preserve-host=false`
+    default_host: Option<HostHeader>,
+    /// Regex rewrites by route name, applied in definition order after any prefix rewrite
+    rewrite_rules: HashMap<String, Vec<RewriteRule>>,
+    /// Response body substitutions by route name (`--sub-filter`), applied in definition order
+    body_filters: HashMap<String, Vec<RewriteRule>>,
+    /// Header set and remove rules by route name
+    header_rules: HashMap<String, HeaderRules>,
+    /// Backends taking no new connections (`POST /backends/drain`), by name as written
+    drained: Vec<String>,
+    /// Weights replacing the routes' own for backends, by name (`POST /backends/weight`)
+    weights: HashMap<String, u32>,
+    /// Rate limits shared by the routes naming them (`--rate-limit-group`), by `@NAME`
+    limit_groups: HashMap<Arc<str>, RateLimit>,
+    /// Blue/green slots (`--slot`), by name
+    pub slots: Slots,
+    /// Some route checks or changes each of its requests (`RouteMatch::checks_requests`)
+    checks_requests: bool,
+    /// Route definitions as given, kept for snapshots
+    source: Snapshot,
+}
+
+/// Routes that only apply to requests carrying a specific Host header
+#[derive(Default)]
+pub struct VirtualHost {
+    /// Catch-all backends for the host (`api.example.com=...`), in evaluation order
+    pub backends: Vec<Target>,
+    pub routes: PathRoutes,
+}
+
+/// A backend address, resolved when the route table is loaded
+#[derive(Clone)]
+pub struct Backend {
+    /// The address as written in the route (`127.0.0.1:4000`, `app.internal:80`)
+    pub name: Arc<str>,
+    pub addr: SocketAddr,
+    /// Given by host name, and looked up again when connected to (see `resolver`)
+    pub named: bool,
+}
+
+impl Backend {
+    /// Parse an `ip:port` address, or resolve a `host:port` one to its first address
+    pub fn parse(spec: &str, route: &str) -> Result<Self, String> {
+        let (addr, named) = match spec.parse() {
+            Ok(addr) => (addr, false),
+            Err(_) => {
+                let addr = spec.to_socket_addrs()
+                    .map_err(|e| format!("Invalid backend '{}' in route '{}': {}", spec, route, e))?
+                    .next()
+                    .ok_or_else(|| format!("Backend '{}' in route '{}' resolves to no addresses", spec, route))?;
+                (addr, true)
+            }
+        };
+        Ok(Backend { name: spec.into(), addr, named })
+    }
+}
+
+impl fmt::Display for Backend {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.name)
+    }
+}
+
+/// What a matched route does with the request
+pub enum Action {
+    /// Forward to a backend (`ip:port`), or one of several by weight (`ip:port:90,ip:port:10`)
+    Proxy(Pool),
+    /// Answer with a fixed response without contacting a backend (`respond:STATUS[:BODY]`)
+    Respond(LocalResponse),
+    /// Redirect the client (`redirect:STATUS:URL`)
+    Redirect(Redirect),
+}
+
+impl Action {
+    /// Parse an action; `headers` are extra response headers, only valid for local responses, and
+    /// `slots` give the backends of `slot:NAME` targets
+    fn parse(spec: &str, route: &str, headers: &[(String, String)], slots: &Slots) -> Result<Self, String> {
+        if let Some(redirect) = spec.strip_prefix(REDIRECT_PREFIX) {
+            let (status, target) = redirect.split_once(':')
+                .ok_or_else(|| format!("Invalid redirect '{}' in route '{}'. Expected format: redirect:STATUS:URL", spec, route))?;
+            let status = status.parse()
+                .map_err(|_| format!("Invalid status '{}' in route '{}'", status, route))?;
+            return Redirect::new(status, target, headers)
+                .map(Action::Redirect)
+                .map_err(|e| format!("{} in route '{}'", e, route));
+        }
+
+        let Some(respond) = spec.strip_prefix(RESPOND_PREFIX) else {
+            if spec.is_empty() || spec.contains('=') {
+                return Err(format!("Invalid backend '{}' in route '{}'", spec, route));
+            }
+            if !headers.is_empty() {
+                return Err(format!("The header option only applies to respond and redirect routes, in route '{}'", route));
+            }
+            if let Some((slot, backends)) = slots.target(spec, route)? {
+                return Pool::parse(backends, route).map(|pool| Action::Proxy(pool.in_slot(slot)));
+            }
+            return Pool::parse(spec, route).map(Action::Proxy);
+        };
+
+        let (status, body) = respond.split_once(':').unwrap_or((respond, ""));
+        let status = status.parse()
+            .map_err(|_| format!("Invalid status '{}' in route '{}'", status, route))?;
+        let content_type = if body.starts_with(['{', '[']) { "application/json" } else { "text/plain; charset=utf-8" };
+        LocalResponse::new(status, content_type, body, headers)
+            .map(Action::Respond)
+            .map_err(|e| format!("{} in route '{}'", e, route))
+    }
+}
+
+impl fmt::Display for Action {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Action::Proxy(pool) => pool.fmt(f),
+            Action::Respond(response) => write!(f, "respond {} {}", response.status, response.reason()),
+            Action::Redirect(redirect) => write!(f, "redirect {} {}", redirect.status, redirect.target()),
+        }
+    }
+}
+
+/// How a route rewrites the request path before forwarding it
+#[derive(Clone)]
+pub enum PathRewrite {
+    /// Remove the matched prefix (`;strip-prefix`, or `--rewrite` for routes without a rule)
+    Strip,
+    /// Put a prefix in front of the full path (`;add-prefix=/internal`)
+    Add(String),
+    /// Replace the matched prefix (`;replace-prefix=/internal`)
+    Replace(String),
+}
+
+impl PathRewrite {
+    /// The rewritten request target (path and query) for a target whose path starts with `prefix`
+    pub fn apply(&self, target: &str, prefix: &str) -> String {
+        let rest = target.strip_prefix(prefix).unwrap_or(target);
+        match self {
+            PathRewrite::Strip => join_path("", rest),
+            PathRewrite::Add(added) => join_path(added, target),
+            PathRewrite::Replace(replacement) => join_path(replacement, rest),
+        }
+    }
+
+    /// Map a path as the backend sees it back to the client's view, given the matched route prefix;
+    /// None for paths outside the rewritten part (they were not produced by this rewrite)
+    pub fn reverse(&self, path: &str, prefix: &str) -> Option<String> {
+        let backend_prefix = match self {
+            PathRewrite::Strip => "",
+            PathRewrite::Add(added) | PathRewrite::Replace(added) => added.trim_end_matches('/'),
+        };
+        let rest = path.strip_prefix(backend_prefix)?;
+        if !(rest.is_empty() || rest.starts_with(['/', '?'])) {
+            return None;
+        }
+        match self {
+            PathRewrite::Add(_) => Some(join_path("", rest)),
+            PathRewrite::Strip | PathRewrite::Replace(_) => Some(join_path(prefix, rest)),
+        }
+    }
+}
+
+/// The Host header sent to a route's backend
+#[derive(Clone)]
+pub enum HostHeader {
+    /// Forward the client's Host header unchanged (`;host=preserve`, the default with `--preserve-host`)
+    Preserve,
+    /// The backend address as written in the route (`;host=backend`, the default with `--preserve-host=false`)
+    Backend,
+    /// A fixed value (`;host=app.internal`)
+    Value(String),
+}
+
+impl HostHeader {
+    fn parse(value: &str, route: &str) -> Result<Self, String> {
+        match value {
+            "preserve" => Ok(HostHeader::Preserve),
+            "backend" => Ok(HostHeader::Backend),
+            _ if value.is_empty() || value.contains(|c: char| c.is_whitespace() || c.is_control()) => {
+                Err(format!("Invalid host '{}' in route '{}'", value, route))
+            }
+            _ => Ok(HostHeader::Value(value.to_string())),
+        }
+    }
+
+    /// The Host value to send instead of the client's, if any
+    pub fn value<'a>(&'a self, backend: &'a Backend) -> Option<&'a str> {
+        match self {
+            HostHeader::Preserve => None,
+            HostHeader::Backend => Some(&backend.name),
+            HostHeader::Value(value) => Some(value),
+        }
+    }
+}
+
+/// TLS on a route's backend connections (`;tls`)
+#[derive(Clone, Copy, PartialEq)]
+pub enum BackendTls {
+    /// Certificates verified against the Mozilla roots, TLS 1.2 and 1.3 (`;tls`)
+    Verified,
+    /// TLS 1.0 and 1.1, weak ciphers and unsafe legacy renegotiation allowed, for backends on old TLS
+    /// stacks (`;tls=legacy`, requires the `legacy-tls` build feature)
+    Legacy,
+}
+
+impl BackendTls {
+    fn parse(value: &str, route: &str) -> Result<Self, String> {
+        match value {
+            "" => Ok(BackendTls::Verified),
+            "legacy" if cfg!(feature = "legacy-tls") => Ok(BackendTls::Legacy),
+            "legacy" => Err(format!("tls=legacy in route '{}' requires a build with the `legacy-tls` feature (cargo build --features legacy-tls)", route)),
+            _ => Err(format!("Invalid tls '{}' in route '{}'. Expected ;tls or ;tls=legacy", value, route)),
+        }
+    }
+}
+
+/// What happens to a response whose body does not match its `Content-Type` (`;content-type-guard`)
+#[derive(Clone, Copy, PartialEq)]
+pub enum TypeGuard {
+    /// Replace the response with `502 Bad Gateway` (`;content-type-guard` or `=block`)
+    Block,
+    /// Forward it with a type matching the body (plain text for markup) and `X-Content-Type-Options: nosniff` (`=correct`)
+    Correct,
+}
+
+impl TypeGuard {
+    fn parse(value: &str, route: &str) -> Result<Self, String> {
+        match value {
+            "" | "block" => Ok(TypeGuard::Block),
+            "correct" => Ok(TypeGuard::Correct),
+            _ => Err(format!("Invalid content-type-guard '{}' in route '{}'. Expected block or correct", value, route)),
+        }
+    }
+}
+
+/// Body checksums a route verifies and generates (`;digest=verify,request,response`)
+#[derive(Clone, Copy, Default, PartialEq)]
+pub struct DigestMode {
+    /// Reject requests whose body does not match their `Content-MD5`, `Digest` or `Content-Digest` header
+    pub verify: bool,
+    /// Add a `Digest` header to requests forwarded to the backend
+    pub request: bool,
+    /// Add a `Digest` header to responses
+    pub response: bool,
+}
+
+impl DigestMode {
+    /// Add the modes of a `;digest` option; the option may be repeated
+    fn add(&mut self, value: &str, route: &str) -> Result<(), String> {
+        for part in value.split(',') {
+            match part.trim() {
+                "" | "verify" => self.verify = true,
+                "request" => self.request = true,
+                "response" => self.response = true,
+                other => return Err(format!("Invalid digest '{}' in route '{}'. Expected verify, request and/or response", other, route)),
+            }
+        }
+        Ok(())
+    }
+
+    /// Whether request bodies are read in full before they are forwarded
+    pub fn reads_request(&self) -> bool {
+        self.verify || self.request
+    }
+}
+
+/// How a route rewrites `Set-Cookie` attributes in backend responses
+#[derive(Clone, Default)]
+pub struct CookieRewrite {
+    /// Map `Path` back through the route's path rewrite (`;cookie-path`)
+    pub path: bool,
+    /// Replace `Domain` with this value, or drop it when empty (`;cookie-domain=example.com`)
+    pub domain: Option<String>,
+}
+
+/// A sed-style regex rewrite of the request path, applied to one route after routing
+/// (`--rewrite-rule 'route=/api s|^/api/(v\d+)/|/$1/|'`), or of its response bodies (`--sub-filter`)
+pub struct RewriteRule {
+    /// Name of the route the rule applies to, as written on the command line (`/api`, `X-Tenant=acme`)
+    route: String,
+    regex: Regex,
+    /// Replacement with `$1` / `${name}` capture group references
+    replacement: String,
+    /// Replace every match (`g` flag) instead of the first one
+    global: bool,
+}
+
+impl RewriteRule {
+    /// Parse `route=NAME sDREGEXDREPLACEMENTD[g]`, where D is any punctuation character not used in the expression.
+    /// `kind` names the flag in error messages ("rewrite rule", "body filter").
+    fn parse(spec: &str, kind: &str) -> Result<Self, String> {
+        let invalid = || format!("Invalid {} '{}'. Expected format: route=NAME s|REGEX|REPLACEMENT|[g]", kind, spec);
+        let rest = spec.strip_prefix("route=").ok_or_else(invalid)?;
+
+        // Route names may contain spaces ("POST /upload"), so take the first " s" followed by a valid expression
+        for (idx, _) in rest.match_indices(" s") {
+            let expression = &rest[idx + 2..];
+            let Some(delimiter) = expression.chars().next().filter(|c| c.is_ascii_punctuation() && *c != '\\') else {
+                continue;
+            };
+            let parts: Vec<&str> = expression[1..].split(delimiter).collect();
+            let [pattern, replacement, flags] = parts[..] else {
+                continue;
+            };
+            if !matches!(flags, "" | "g") {
+                continue;
+            }
+
+            let route = rest[..idx].trim();
+            if route.is_empty() || pattern.is_empty() {
+                return Err(invalid());
+            }
+            let regex = Regex::new(pattern).map_err(|e| format!("Invalid regex in {} '{}': {}", kind, spec, e))?;
+            return Ok(RewriteRule { route: route.to_string(), regex, replacement: replacement.to_string(), global: flags == "g" });
+        }
+
+        Err(invalid())
+    }
+
+    /// Rewrite a request path (or response body), borrowing it when the regex does not match
+    pub fn apply<'p>(&self, path: &'p str) -> Cow<'p, str> {
+        if self.global {
+            self.regex.replace_all(path, self.replacement.as_str())
+        } else {
+            self.regex.replace(path, self.replacement.as_str())
+        }
+    }
+}
+
+/// Join a path prefix and the rest of a request target, so that exactly one '/' separates them
+fn join_path(prefix: &str, rest: &str) -> String {
+    let prefix = prefix.trim_end_matches('/');
+    if rest.is_empty() {
+        return if prefix.is_empty() { "/".to_string() } else { prefix.to_string() };
+    }
+    if rest.starts_with('/') || (rest.starts_with('?') && !prefix.is_empty()) {
+        format!("{}{}", prefix, rest)
+    } else {
+        format!("{}/{}", prefix, rest)
+    }
+}
+
+/// Per-route options given as `;key=value` suffixes after the backend; the routes of a table keep
+/// them, and route matches borrow them
+#[derive(Default, Clone)]
+pub struct RouteOptions {
+    /// Higher priorities win over the built-in evaluation order (default 0)
+    pub priority: i32,
+    pub rewrite: Option<PathRewrite>,
+    /// Extra headers for respond and redirect routes (`;header=Cache-Control: no-store`), in definition order
+    pub headers: Vec<(String, String)>,
+    pub host: Option<HostHeader>,
+    pub cookies: Option<CookieRewrite>,
+    /// PSK identities allowed to use the route (`;psk=sensor-1,sensor-2`, `*` for any)
+    pub psk: Option<Vec<String>>,
+    /// Forward the backend's 5xx responses instead of `--custom-errors` pages (`;pass-errors`)
+    pub pass_errors: bool,
+    pub tls: Option<BackendTls>,
+    pub type_guard: Option<TypeGuard>,
+    /// Scan request bodies with the `--icap` scanner before forwarding them (`;scan`)
+    pub scan: bool,
+    pub digest: DigestMode,
+    /// Keep idle responses alive (`;heartbeat=15`)
+    pub heartbeat: Option<Heartbeat>,
+    /// How long CORS preflight responses are cached (`;cache-preflight=600`)
+    pub cache_preflight: Option<Duration>,
+    /// How long responses to requests with an `Idempotency-Key` are replayed (`;idempotency=86400`)
+    pub idempotency: Option<Duration>,
+    /// How long responses are cached regardless of their `Cache-Control` (`;force-cache=300`)
+    pub force_cache: Option<Duration>,
+    /// Reject or tarpit clients listed in a `--blocklist` feed (`;blocklist[=tarpit]`)
+    pub blocklist: Option<BlocklistMode>,
+    /// Requests a client may send (`;rate-limit=100/s`, `;rate-limit=1000/m:asn`, `;rate-limit=@api`)
+    pub rate_limit: Option<RouteLimit>,
+    /// Requests sent to each backend, with the queue for the excess (`;backend-rate=10/s:50`)
+    pub backend_rate: Option<BackendRate>,
+    /// Other backends a failed request is sent to (`;retry=2`, `;retry=1:503`)
+    pub retry: Option<RetryPolicy>,
+    /// Autonomous systems refused, or the only ones allowed (`;deny-asn=16509`, `;allow-asn=3320`)
+    pub asn_rule: Option<AsnRule>,
+    /// Lowercase names of the only client headers forwarded (`;allow-headers=Accept,Content-Type`)
+    pub allow_headers: Option<Vec<String>>,
+    /// Shadow backend a share of the connections is copied to (`;mirror=10.0.0.9:8080:10`)
+    pub mirror: Option<Mirror>,
+    /// Forward requests exactly as received, whatever the global rewrite options (`;verbatim`)
+    pub verbatim: bool,
+    /// Listeners the route is served on (`;listener=internal`); all of them when None
+    pub listeners: Option<Vec<String>>,
+}
+
+impl RouteOptions {
+    /// Split trailing `;key=value` options off a route definition
+    fn split(route: &str) -> Result<(&str, RouteOptions), String> {
+        let mut options = RouteOptions::default();
+        let mut rest = route;
+
+        // Only trailing segments that look like options are taken, so ';' elsewhere (e.g. in a regex) is kept
+        while let Some((head, option)) = rest.rsplit_once(';') {
+            let (key, value) = option.split_once('=').unwrap_or((option, ""));
+            if key.is_empty() || !key.bytes().all(|b| b.is_ascii_lowercase() || b == b'-') {
+                break;
+            }
+
+            match key {
+                "priority" => {
+                    options.priority = value.parse()
+                        .map_err(|_| format!("Invalid priority '{}' in route '{}'", value, route))?;
+                }
+                "strip-prefix" | "add-prefix" | "replace-prefix" => {
+                    if options.rewrite.is_some() {
+                        return Err(format!("Only one of strip-prefix, add-prefix and replace-prefix may be given in route '{}'", route));
+                    }
+                    if key != "strip-prefix" && !value.starts_with('/') {
+                        return Err(format!("Invalid {} '{}' in route '{}': must start with '/'", key, value, route));
+                    }
+                    options.rewrite = Some(match key {
+                        "strip-prefix" => PathRewrite::Strip,
+                        "add-prefix" => PathRewrite::Add(value.to_string()),
+                        _ => PathRewrite::Replace(value.to_string()),
+                    });
+                }
+                "header" => {
+                    let (name, value) = value.split_once(':')
+                        .ok_or_else(|| format!("Invalid header '{}' in route '{}'. Expected format: header=Name: value", value, route))?;
+                    options.headers.push((name.trim().to_string(), value.trim().to_string()));
+                }
+                "host" => options.host = Some(HostHeader::parse(value, route)?),
+                "psk" => {
+                    let identities: Vec<String> = value.split(',').map(str::to_string).collect();
+                    if identities.iter().any(String::is_empty) {
+                        return Err(format!("Invalid psk '{}' in route '{}'. Expected format: psk=IDENTITY[,IDENTITY...] or psk=*", value, route));
+                    }
+                    options.psk = Some(identities);
+                }
+                "pass-errors" => options.pass_errors = true,
+                "verbatim" => options.verbatim = true,
+                "scan" => options.scan = true,
+                "blocklist" => options.blocklist = Some(BlocklistMode::parse(value, route)?),
+                "rate-limit" => options.rate_limit = Some(RouteLimit::parse(value, route)?),
+                "backend-rate" => options.backend_rate = Some(BackendRate::parse(value, route)?),
+                "retry" => options.retry = Some(RetryPolicy::parse(value, route)?),
+                "deny-asn" | "allow-asn" => {
+                    if options.asn_rule.is_some() {
+                        return Err(format!("Only one of deny-asn and allow-asn may be given in route '{}'", route));
+                    }
+                    options.asn_rule = Some(AsnRule::parse(key, value, route)?);
+                }
+                "allow-headers" => {
+                    let names: Vec<String> = value.split(',').map(|name| name.trim().to_ascii_lowercase()).collect();
+                    if names.iter().any(|name| name.is_empty() || !name.bytes().all(|b| b.is_ascii_alphanumeric() || b"!#$%&'*+-.^_`|~".contains(&b))) {
+                        return Err(format!("Invalid allow-headers '{}' in route '{}'. Expected format: allow-headers=Name[,Name...]", value, route));
+                    }
+                    options.allow_headers = Some(names);
+                }
+                "mirror" => options.mirror = Some(Mirror::parse(value, route)?),
+                "listener" => {
+                    let names: Vec<String> = value.split(',').map(str::to_string).collect();
+                    if names.iter().any(|name| !crate::listener::valid_name(name)) {
+                        return Err(format!("Invalid listener '{}' in route '{}'. Expected format: listener=NAME[,NAME...]", value, route));
+                    }
+                    options.listeners = Some(names);
+                }
+                "digest" => options.digest.add(value, route)?,
+                "heartbeat" => options.heartbeat = Some(Heartbeat::parse(value, route)?),
+                "cache-preflight" => {
+                    options.cache_preflight = Some(match value {
+                        "" => crate::preflight::DEFAULT_TTL,
+                        seconds => seconds.parse().ok().filter(|&seconds| seconds > 0).map(Duration::from_secs)
+                            .ok_or_else(|| format!("Invalid cache-preflight '{}' in route '{}'. Expected a number of seconds", value, route))?,
+                    });
+                }
+                "force-cache" => {
+                    options.force_cache = Some(value.parse().ok().filter(|&seconds| seconds > 0).map(Duration::from_secs)
+                        .ok_or_else(|| format!("Invalid force-cache '{}' in route '{}'. Expected a number of seconds", value, route))?);
+                }
+                "idempotency" => {
+                    options.idempotency = Some(match value {
+                        "" => crate::idempotency::DEFAULT_TTL,
+                        seconds => seconds.parse().ok().filter(|&seconds| seconds > 0).map(Duration::from_secs)
+                            .ok_or_else(|| format!("Invalid idempotency '{}' in route '{}'. Expected a number of seconds", value, route))?,
+                    });
+                }
+                "tls" => options.tls = Some(BackendTls::parse(value, route)?),
+                "content-type-guard" => options.type_guard = Some(TypeGuard::parse(value, route)?),
+                "cookie-path" => options.cookies.get_or_insert_with(CookieRewrite::default).path = true,
+                "cookie-domain" => {
+                    if value.contains(|c: char| c.is_whitespace() || c.is_control() || c == ';') {
+                        return Err(format!("Invalid cookie-domain '{}' in route '{}'", value, route));
+                    }
+                    options.cookies.get_or_insert_with(CookieRewrite::default).domain = Some(value.to_string());
+                }
+                _ => return Err(format!("Unknown route option '{}' in route '{}'", key, route)),
+            }
+            rest = head;
+        }
+
+        // Options were taken from the end
+        options.headers.reverse();
+        Ok((rest, options))
+    }
+
+    /// Reject options that do not apply to the route's action
+    fn check(&self, action: &Action, route: &str) -> Result<(), String> {
+        if !matches!(action, Action::Proxy(_)) {
+            if self.host.is_some() {
+                return Err(format!("The host option only applies to routes with a backend, in route '{}'", route));
+            }
+            if self.cookies.is_some() {
+                return Err(format!("Cookie options only apply to routes with a backend, in route '{}'", route));
+            }
+            if self.pass_errors {
+                return Err(format!("The pass-errors option only applies to routes with a backend, in route '{}'", route));
+            }
+            if self.tls.is_some() {
+                return Err(format!("The tls option only applies to routes with a backend, in route '{}'", route));
+            }
+            if self.scan {
+                return Err(format!("The scan option only applies to routes with a backend, in route '{}'", route));
+            }
+            if self.digest != DigestMode::default() {
+                return Err(format!("The digest option only applies to routes with a backend, in route '{}'", route));
+            }
+            if self.cache_preflight.is_some() {
+                return Err(format!("The cache-preflight option only applies to routes with a backend, in route '{}'", route));
+            }
+            if self.force_cache.is_some() {
+                return Err(format!("The force-cache option only applies to routes with a backend, in route '{}'", route));
+            }
+            if self.idempotency.is_some() {
+                return Err(format!("The idempotency option only applies to routes with a backend, in route '{}'", route));
+            }
+            if self.heartbeat.is_some() {
+                return Err(format!("The heartbeat option only applies to routes with a backend, in route '{}'", route));
+            }
+            if self.type_guard.is_some() {
+                return Err(format!("The content-type-guard option only applies to routes with a backend, in route '{}'", route));
+            }
+            if self.backend_rate.is_some() {
+                return Err(format!("The backend-rate option only applies to routes with a backend, in route '{}'", route));
+            }
+            if self.retry.is_some() {
+                return Err(format!("The retry option only applies to routes with a backend, in route '{}'", route));
+            }
+            if self.allow_headers.is_some() {
+                return Err(format!("The allow-headers option only applies to routes with a backend, in route '{}'", route));
+            }
+            if self.mirror.is_some() {
+                return Err(format!("The mirror option only applies to routes with a backend, in route '{}'", route));
+            }
+            if self.verbatim {
+                return Err(format!("The verbatim option only applies to routes with a backend, in route '{}'", route));
+            }
+        }
+        if self.verbatim {
+            let changing = [
+                (self.rewrite.is_some(), "strip-prefix, add-prefix or replace-prefix"),
+                (self.host.is_some(), "host"),
+                (self.allow_headers.is_some(), "allow-headers"),
+                (self.digest.request, "digest=request"),
+            ];
+            if let Some((_, option)) = changing.iter().find(|(given, _)| *given) {
+                return Err(format!("The verbatim option forwards requests unchanged and excludes {}, in route '{}'", option, route));
+            }
+        }
+        Ok(())
+    }
+
+    /// Whether the route checks or counts each of its requests, so that the requests after the first on
+    /// a connection must not be relayed past it
+    pub fn checks_requests(&self) -> bool {
+        self.psk.is_some() || self.listeners.is_some() || self.blocklist.is_some() || self.rate_limit.is_some() || self.asn_rule.is_some()
+            || self.backend_rate.is_some() || self.allow_headers.is_some() || self.scan || self.idempotency.is_some() || self.digest.reads_request()
+    }
+}
+
+/// A route selected by the exact value of a request header or query parameter
+pub struct ParamRoute {
+    /// The route as written on the command line (`X-Tenant=acme`, `version=beta`)
+    pub source: Arc<str>,
+    name: String,
+    value: String,
+    pub action: Action,
+    pub options: RouteOptions,
+}
+
+impl ParamRoute {
+    /// Parse a `name=value=backend` route definition
+    fn parse(route: &str, kind: &str, slots: &Slots) -> Result<Self, String> {
+        let (definition, options) = RouteOptions::split(route)?;
+        let parts: Vec<&str> = definition.splitn(3, '=').collect();
+        if parts.len() != 3 || parts[0].is_empty() {
+            return Err(format!("Invalid {} route format: '{}'. Expected format: name=value=ip:port or name=value=respond:STATUS[:BODY]", kind, route));
+        }
+
+        let action = Action::parse(parts[2], route, &options.headers, slots)?;
+        options.check(&action, route)?;
+        Ok(ParamRoute {
+            source: format!("{}={}", parts[0], parts[1]).into(),
+            name: parts[0].to_string(),
+            value: parts[1].to_string(),
+            action,
+            options,
+        })
+    }
+
+    /// Parse a list of routes into evaluation order: highest priority first, then definition order
+    fn parse_all(routes: &[String], kind: &str, slots: &Slots) -> Result<Vec<Self>, String> {
+        let mut parsed = routes.iter()
+            .map(|route| ParamRoute::parse(route, kind, slots))
+            .collect::<Result<Vec<_>, _>>()?;
+
+        for (idx, route) in parsed.iter().enumerate() {
+            let duplicate = parsed[..idx].iter()
+                .find(|o| o.name.eq_ignore_ascii_case(&route.name) && o.value == route.value && o.options.priority == route.options.priority);
+            if let Some(other) = duplicate {
+                return Err(format!("Conflicting {} routes: '{}' is defined twice with priority {}", kind, other.source, route.options.priority));
+            }
+        }
+
+        // Stable sort keeps definition order among equal priorities
+        parsed.sort_by_key(|route| std::cmp::Reverse(route.options.priority));
+        Ok(parsed)
+    }
+
+    fn route_match(&self) -> RouteMatch<'_> {
+        // Parameter routes never strip a prefix: the path played no part in the match
+        RouteMatch::new(Some(&self.action), "", &self.source, &self.options)
+    }
+}
+
+/// Header or query-parameter routes, indexed by name and value so a lookup costs one hash probe
+/// per distinct name (headers) or per request parameter (queries) instead of one per route
+#[derive(Default)]
+pub struct ParamRoutes {
+    /// Routes in evaluation order
+    routes: Vec<ParamRoute>,
+    /// Name (lowercased for headers) -> value -> position of the first route in evaluation order
+    index: HashMap<String, HashMap<String, usize>>,
+    case_insensitive_names: bool,
+}
+
+impl ParamRoutes {
+    fn parse(routes: &[String], kind: &str, case_insensitive_names: bool, slots: &Slots) -> Result<Self, String> {
+        let routes = ParamRoute::parse_all(routes, kind, slots)?;
+
+        let mut routes = ParamRoutes { routes, index: HashMap::new(), case_insensitive_names };
+        for (idx, route) in routes.routes.iter().enumerate() {
+            routes.index.entry(routes.index_name(route)).or_default().entry(route.value.clone()).or_insert(idx);
+        }
+        Ok(routes)
+    }
+
+    fn index_name(&self, route: &ParamRoute) -> String {
+        if self.case_insensitive_names { route.name.to_ascii_lowercase() } else { route.name.clone() }
+    }
+
+    /// Routes that never match because a higher-priority route has the same name and value,
+    /// with the route that wins instead
+    fn shadowed(&self) -> impl Iterator<Item = (&ParamRoute, &ParamRoute)> {
+        self.routes.iter().enumerate().filter_map(|(idx, route)| {
+            let first = self.index[&self.index_name(route)][&route.value];
+            (first != idx).then(|| (route, &self.routes[first]))
+        })
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.routes.is_empty()
+    }
+
+    /// All routes in evaluation order
+    pub fn iter(&self) -> impl Iterator<Item = &ParamRoute> {
+        self.routes.iter()
+    }
+
+    /// The first route in evaluation order whose header is present with its value
+    fn find_header(&self, request: &RequestHead) -> Option<&ParamRoute> {
+        self.index.iter()
+            .filter_map(|(name, values)| values.get(request.header_str(name)?))
+            .min()
+            .map(|&idx| &self.routes[idx])
+    }
+
+    /// The first route in evaluation order whose parameter occurs in the query with its value
+    fn find_query(&self, query: &str) -> Option<&ParamRoute> {
+        query.split('&')
+            .filter(|pair| !pair.is_empty())
+            .filter_map(|pair| {
+                let (name, value) = pair.split_once('=').unwrap_or((pair, ""));
+                self.index.get(percent_decode(name).as_ref())?.get(percent_decode(value).as_ref())
+            })
+            .min()
+            .map(|&idx| &self.routes[idx])
+    }
+}
+
+/// A route's action, optionally restricted to a set of request methods
+pub struct Target {
+    /// The route as written on the command line, without the backend (`POST api.example.com/upload`)
+    pub name: Arc<str>,
+    methods: Option<Vec<String>>,
+    pub action: Action,
+    pub options: RouteOptions,
+    /// Position in the route definitions, the final tie-breaker
+    order: usize,
+}
+
+impl Target {
+    fn accepts(&self, method: &str) -> bool {
+        self.methods.as_ref().map_or(true, |methods| methods.iter().any(|m| m == method))
+    }
+
+    /// Whether both targets are equally specific candidates for some request method
+    fn overlaps(&self, other: &Target) -> bool {
+        match (&self.methods, &other.methods) {
+            (None, None) => true,
+            (Some(a), Some(b)) => a.iter().any(|m| b.contains(m)),
+            // A method-restricted route always wins over an unrestricted one
+            _ => false,
+        }
+    }
+
+    /// Whether the target accepts every method `other` accepts
+    fn covers_methods(&self, other: &Target) -> bool {
+        match (&self.methods, &other.methods) {
+            (None, _) => true,
+            (Some(_), None) => false,
+            (Some(a), Some(b)) => b.iter().all(|m| a.contains(m)),
+        }
+    }
+
+    /// Tie-breakers after priority and match specificity: method-restricted routes, then definition order
+    fn tie_break(&self) -> (bool, std::cmp::Reverse<usize>) {
+        (self.methods.is_some(), std::cmp::Reverse(self.order))
+    }
+}
+
+enum Matcher {
+    /// Literal prefix (`/api`)
+    Prefix(String),
+    /// Glob (`/api/v*/users`), matched as a prefix like literal routes
+    Glob(Regex),
+    /// Regex (`re:^/items/\d+`)
+    Regex(Regex),
+}
+
+struct PathRoute {
+    /// Path part of the route as written (`/api`, `re:^/items`)
+    pattern: String,
+    matcher: Matcher,
+    target: Target,
+}
+
+/// How specifically a route matched a path; compared after priority
+#[derive(PartialEq, Eq, PartialOrd, Ord)]
+enum Specificity {
+    Regex,
+    /// Literal or glob prefix, by matched length
+    Prefix(usize),
+    Exact,
+}
+
+/// Path routes, indexed for lookup. Evaluation order is explicit: highest priority first, then
+/// exact literal match > longest literal or glob prefix > regex, then method-restricted routes,
+/// then definition order.
+#[derive(Default)]
+pub struct PathRoutes {
+    /// All routes in definition order
+    routes: Vec<PathRoute>,
+    /// Literal routes by their path, glob routes by the literal part before the first wildcard
+    trie: RadixTrie<usize>,
+    /// Regex routes, which cannot be indexed and are scanned only when they could still win
+    regexes: Vec<usize>,
+    max_regex_priority: Option<i32>,
+}
+
+impl RouteConfig {
+    /// Compile a route table from its definitions, in the notation of the command-line flags
+    pub fn from_snapshot(snapshot: Snapshot) -> Result<Self, String> {
+        Self::with_generated(snapshot, &crate::docker::routes())
+    }
+
+    /// `from_snapshot` on a blocking thread, for async code: backends given by host name are
+    /// resolved with the system resolver, which blocks
+    pub async fn compile(snapshot: Snapshot) -> Result<Self, String> {
+        tokio::task::spawn_blocking(move || Self::from_snapshot(snapshot)).await.unwrap_or_else(|e| Err(e.to_string()))
+    }
+
+    /// Compile a route table with routes generated elsewhere (`--docker`) after the snapshot's;
+    /// they are not part of the table's snapshot
+    pub fn with_generated(snapshot: Snapshot, generated: &[String]) -> Result<Self, String> {
+        let slots = Slots::parse(&snapshot.slots, &snapshot.active_slots)?;
+        let header_routes = ParamRoutes::parse(&snapshot.header_routes, "header", true, &slots)?;
+        let query_routes = ParamRoutes::parse(&snapshot.query_routes, "query", false, &slots)?;
+
+        let mut routes = PathRoutes::default();
+        let mut virtual_hosts: BTreeMap<String, VirtualHost> = BTreeMap::new();
+
+        for (order, route) in snapshot.routes.iter().chain(generated).enumerate() {
+            let (definition, options) = RouteOptions::split(route)?;
+            let (name, action) = match split_path_route(definition) {
+                Some((name, action)) => (name, Action::parse(action, route, &options.headers, &slots)?),
+                _ => return Err(format!("Invalid route format: '{}'. Expected format: [METHOD ][host]/path=ip:port, [METHOD ]host=ip:port or [METHOD ]re:REGEX=ip:port", route)),
+            };
+            options.check(&action, route)?;
+
+            // Optional method restriction: "POST /upload" or "GET,HEAD /static"
+            let (methods, target) = match name.split_once(' ') {
+                Some((methods, target)) => (Some(parse_methods(methods, route)?), target.trim_start()),
+                None => (None, name),
+            };
+            let route_target = Target {
+                name: name.into(),
+                methods,
+                action,
+                options,
+                order,
+            };
+
+            if target.starts_with('/') || target.starts_with("re:") {
+                routes.insert(target, route_target)?;
+                continue;
+            }
+
+            // Host-based route, optionally followed by a path prefix (api.example.com/v1)
+            let (host, path) = match target.find('/') {
+                Some(idx) => (&target[..idx], Some(&target[idx..])),
+                None => (target, None),
+            };
+
+            if host.is_empty() || host.contains(char::is_whitespace) {
+                return Err(format!("Invalid host in route: '{}'", route));
+            }
+
+            let vhost = virtual_hosts.entry(host.to_ascii_lowercase()).or_default();
+            match path {
+                Some(path) => vhost.routes.insert(path, route_target)?,
+                None => {
+                    let conflict = vhost.backends.iter().find(|t| t.options.priority == route_target.options.priority && t.overlaps(&route_target));
+                    if let Some(other) = conflict {
+                        return Err(conflict_error(&other.name, &route_target));
+                    }
+                    vhost.backends.push(route_target);
+                    vhost.backends.sort_by_key(|t| std::cmp::Reverse((t.options.priority, t.tie_break())));
+                }
+            }
+        }
+
+        let default_action = snapshot.default_backend.as_deref().map(|spec| Action::parse(spec, spec, &[], &slots)).transpose()?;
+
+        // Every rule must name a route that exists, so a typo does not silently disable it
+        let mut route_names: Vec<&str> = header_routes.iter().chain(query_routes.iter()).map(|r| &*r.source)
+            .chain(routes.iter().map(|t| &*t.name))
+            .chain(virtual_hosts.values().flat_map(|v| v.backends.iter().chain(v.routes.iter())).map(|t| &*t.name))
+            .collect();
+        if default_action.is_some() {
+            route_names.push(DEFAULT_ROUTE);
+        }
+        let parse_rules = |specs: &[String], kind: &str| {
+            let mut rules: HashMap<String, Vec<RewriteRule>> = HashMap::new();
+            for spec in specs {
+                let rule = RewriteRule::parse(spec, kind)?;
+                if !route_names.contains(&rule.route.as_str()) {
+                    return Err(format!("The {} '{}' names unknown route '{}'", kind, spec, rule.route));
+                }
+                rules.entry(rule.route.clone()).or_default().push(rule);
+            }
+            Ok::<_, String>(rules)
+        };
+        let rewrite_rules = parse_rules(&snapshot.rewrite_rules, "rewrite rule")?;
+        // Body filters replace every match; a `g` flag is accepted but changes nothing
+        let mut body_filters = parse_rules(&snapshot.body_filters, "body filter")?;
+        body_filters.values_mut().flatten().for_each(|rule| rule.global = true);
+        let header_rules = crate::headers::compile(&snapshot, &route_names)?;
+
+        let mut limit_groups = HashMap::new();
+        for spec in &snapshot.rate_limit_groups {
+            let (name, limit) = crate::ratelimit::parse_group(spec)?;
+            if limit_groups.insert(name.clone(), limit).is_some() {
+                return Err(format!("Rate limit group '{}' is defined twice", &name[1..]));
+            }
+        }
+
+        let config = RouteConfig {
+            default_backend: default_action,
+            default_route: DEFAULT_ROUTE.into(),
+            default_options: RouteOptions::default(),
+            header_routes,
+            query_routes,
+            routes,
+            virtual_hosts,
+            rewrite_paths: snapshot.rewrite,
+            default_rewrite: snapshot.rewrite.then_some(PathRewrite::Strip),
+            preserve_host: snapshot.preserve_host,
+            default_host: (!snapshot.preserve_host).then_some(HostHeader::Backend),
+            rewrite_rules,
+            body_filters,
+            header_rules,
+            drained: snapshot.drained_backends.clone(),
+            weights: snapshot.backend_weights.iter().map(|(name, &weight)| (name.clone(), weight)).collect(),
+            limit_groups,
+            slots,
+            checks_requests: false,
+            source: snapshot,
+        };
+        // Rules naming a verbatim route would change its requests
+        let changed = |name: &str| config.rewrite_rules.contains_key(name) || config.body_filters.contains_key(name)
+            || config.header_rules.get(name).is_some_and(|rules| !rules.request.is_empty());
+        let routes = config.route_names(|name, options| options.verbatim && changed(name));
+        if !routes.is_empty() {
+            return Err(format!("Verbatim routes are named by --rewrite-rule, --sub-filter, --set-header or --remove-header rules: {}", routes.join(", ")));
+        }
+        let undefined = |limit: &Option<RouteLimit>| matches!(limit, Some(RouteLimit::Group(name)) if !config.limit_groups.contains_key(name));
+        let routes = config.route_names(|_, options| undefined(&options.rate_limit));
+        if !routes.is_empty() {
+            return Err(format!("Routes name a rate limit group no --rate-limit-group defines: {}", routes.join(", ")));
+        }
+        // Routes whose requests are changed on the way (`RouteMatch::checks_requests`) count too
+        let changes = |name: &str, options: &RouteOptions| options.rewrite.is_some() || options.host.is_some()
+            || (!options.verbatim && (config.default_rewrite.is_some() || config.default_host.is_some()))
+            || config.rewrite_rules.contains_key(name) || config.header_rules.contains_key(name);
+        let default = config.default_backend.as_ref().map(|_| (&config.default_route, &config.default_options));
+        let checks_requests = config.all_routes().map(|(route, _, options)| (route, options)).chain(default)
+            .any(|(route, options)| options.checks_requests() || changes(route, options));
+        let config = RouteConfig { checks_requests, ..config };
+        Ok(config)
+    }
+
+    /// The limit a route's requests count against, with the name they are counted under: the route's
+    /// own, or its group's
+    pub fn rate_limit<'a>(&'a self, limit: &'a RouteLimit, route: &'a Arc<str>) -> Option<(&'a Arc<str>, &'a RateLimit)> {
+        match limit {
+            RouteLimit::Own(limit) => Some((route, limit)),
+            RouteLimit::Group(name) => self.limit_groups.get_key_value(name),
+        }
+    }
+
+    /// The definitions the table was compiled from
+    pub fn snapshot(&self) -> Snapshot {
+        self.source.clone()
+    }
+
+    /// Whether a backend is drained: requests routed to it are refused instead of forwarded
+    pub fn is_drained(&self, backend: &Backend) -> bool {
+        !self.drained.is_empty() && self.drained.iter().any(|drained| **drained == *backend.name)
+    }
+
+    /// The backend of a pool for a new connection from a client: drained backends and those weighted 0
+    /// are skipped, as are those not `usable` by health, and in pools with zones the zone is chosen first
+    pub fn pick<'a>(&self, pool: &'a Pool, strategy: Strategy, client: IpAddr, regions: &Regions, usable: impl Fn(&Backend) -> bool) -> Option<&'a Backend> {
+        regions.pick(pool, strategy, client, |backend, weight| {
+            if !usable(backend) || self.is_drained(backend) { 0 } else { self.weight(backend, weight) }
+        })
+    }
+
+    /// The weight a backend gets in a route, after any change through the admin API
+    pub fn weight(&self, backend: &Backend, configured: u32) -> u32 {
+        self.weights.get(&*backend.name).copied().unwrap_or(configured)
+    }
+
+    /// All backends of the table, with the names of the routes using them and their configured
+    /// weights there, in route order
+    pub fn backends(&self) -> Vec<BackendUse<'_>> {
+        let mut backends: Vec<BackendUse<'_>> = Vec::new();
+        for (pool, route, _) in self.pools() {
+            for (backend, weight) in pool.members() {
+                match backends.iter_mut().find(|(b, _)| b.name == backend.name) {
+                    Some((_, routes)) => routes.push((route, weight)),
+                    None => backends.push((backend, vec![(route, weight)])),
+                }
+            }
+        }
+        backends
+    }
+
+    /// The backends of routes with zones, once each, to probe
+    pub fn zoned_backends(&self) -> Vec<&Backend> {
+        let mut backends: Vec<&Backend> = Vec::new();
+        for (backend, _, _) in self.pools().flat_map(|(pool, _, _)| pool.zoned()) {
+            if !backends.iter().any(|b| b.name == backend.name) {
+                backends.push(backend);
+            }
+        }
+        backends
+    }
+
+    /// All backends once each, with the TLS of the first route using them, to health check
+    pub fn checked_backends(&self) -> Vec<(&Backend, Option<&BackendTls>)> {
+        let mut backends: Vec<(&Backend, Option<&BackendTls>)> = Vec::new();
+        for (pool, _, tls) in self.pools() {
+            for (backend, _) in pool.members() {
+                if !backends.iter().any(|(b, _)| b.name == backend.name) {
+                    backends.push((backend, tls));
+                }
+            }
+        }
+        backends
+    }
+
+    /// The names of the routes whose backends are a slot's
+    pub fn slot_routes(&self, slot: &str) -> Vec<&str> {
+        self.pools().filter(|(pool, _, _)| pool.slot().is_some_and(|s| **s == *slot)).map(|(_, route, _)| &**route).collect()
+    }
+
+    /// Whether some route checks each of its requests, so the requests after the first on a
+    /// connection are followed
+    pub fn checks_requests(&self) -> bool {
+        self.checks_requests
+    }
+
+    /// The listeners named by `;listener=` options, with the first route naming each
+    pub fn listener_names(&self) -> BTreeMap<&str, &str> {
+        let mut names = BTreeMap::new();
+        for (route, _, options) in self.all_routes() {
+            for name in options.listeners.iter().flatten() {
+                names.entry(name.as_str()).or_insert(&**route);
+            }
+        }
+        names
+    }
+
+    /// The targets routes discover their backends from (`srv:`, `k8s:` and `@`), once each
+    pub fn discovered_names(&self) -> Vec<Arc<str>> {
+        let mut names: Vec<Arc<str>> = Vec::new();
+        for source in self.pools().filter_map(|(pool, _, _)| pool.source()) {
+            if !names.contains(source) {
+                names.push(source.clone());
+            }
+        }
+        names
+    }
+
+    /// The backend pools of all routes, with the routes' names and backend TLS
+    fn pools(&self) -> impl Iterator<Item = (&Pool, &Arc<str>, Option<&BackendTls>)> {
+        let routes = self.all_routes().chain(self.default_backend.as_ref().map(|action| (&self.default_route, action, &self.default_options)));
+        routes.filter_map(|(route, action, options)| match action {
+            Action::Proxy(pool) => Some((pool, route, options.tls.as_ref())),
+            _ => None,
+        })
+    }
+
+    /// Names of the routes with `;force-cache`, to flag at startup
+    pub fn force_cached_routes(&self) -> Vec<&str> {
+        self.route_names(|_, options| options.force_cache.is_some())
+    }
+
+    /// Names of the routes with `;tls=legacy`, to flag at startup
+    pub fn legacy_tls_routes(&self) -> Vec<&str> {
+        let legacy = Some(BackendTls::Legacy);
+        self.route_names(|_, options| options.tls == legacy)
+    }
+
+    /// Names of the routes with `;scan`, which need an `--icap` scanner
+    pub fn scanned_routes(&self) -> Vec<&str> {
+        self.route_names(|_, options| options.scan)
+    }
+
+    /// Names of the routes with `;blocklist`, which need a `--blocklist` feed
+    pub fn blocklisted_routes(&self) -> Vec<&str> {
+        self.route_names(|_, options| options.blocklist.is_some())
+    }
+
+    /// Names of the routes with ASN rules or per-ASN limits, which need an `--asn-db`
+    pub fn asn_routes(&self) -> Vec<&str> {
+        let needs_asn = |limit: &Option<RouteLimit>, rule: &Option<AsnRule>| rule.is_some() || self.counts_by(limit, LimitKey::Asn);
+        self.route_names(|_, options| needs_asn(&options.rate_limit, &options.asn_rule))
+    }
+
+    /// Names of the routes with per-user limits, which need a `--user-identity`
+    pub fn user_routes(&self) -> Vec<&str> {
+        self.route_names(|_, options| self.counts_by(&options.rate_limit, LimitKey::User))
+    }
+
+    /// Whether a route's limit, its own or its group's, counts requests by the key
+    fn counts_by(&self, limit: &Option<RouteLimit>, key: LimitKey) -> bool {
+        match limit {
+            Some(RouteLimit::Own(limit)) => limit.key == key,
+            Some(RouteLimit::Group(name)) => self.limit_groups.get(name).is_some_and(|limit| limit.key == key),
+            None => false,
+        }
+    }
+
+    /// Names of the routes selected by the filter, which is given each route's name and options
+    fn route_names(&self, filter: impl Fn(&str, &RouteOptions) -> bool) -> Vec<&str> {
+        self.all_routes().filter(|(route, _, options)| filter(route, options)).map(|(route, _, _)| &**route).collect()
+    }
+
+    /// The header and query routes, then the path and host routes, with their actions and options
+    fn all_routes(&self) -> impl Iterator<Item = (&Arc<str>, &Action, &RouteOptions)> {
+        self.header_routes.iter().chain(self.query_routes.iter()).map(|r| (&r.source, &r.action, &r.options))
+            .chain(self.routes.iter().chain(self.virtual_hosts.values().flat_map(|v| v.backends.iter().chain(v.routes.iter())))
+                .map(|t| (&t.name, &t.action, &t.options)))
+    }
+
+    /// Determine the backend and matched route prefix for a request.
+    /// Header routes are tried first, then query routes, then routes for the request's virtual host,
+    /// then host-agnostic routes. Routes restricted to other methods are skipped.
+    /// The lookup neither locks nor allocates: the result borrows from the route table.
+    pub fn get_backend_and_prefix<'a>(&'a self, request: &RequestHead<'a>) -> RouteMatch<'a> {
+        let mut matched = self.find_route(request);
+        if matched.rewrite.is_none() && !matched.prefix.is_empty() && !matched.options.verbatim {
+            matched.rewrite = self.default_rewrite.as_ref();
+        }
+        if matched.host.is_none() && !matched.options.verbatim {
+            matched.host = self.default_host.as_ref();
+        }
+        if !self.rewrite_rules.is_empty() {
+            matched.rules = self.rewrite_rules.get(&**matched.route).map_or(&[], Vec::as_slice);
+        }
+        if !self.body_filters.is_empty() {
+            matched.body_filters = self.body_filters.get(&**matched.route).map_or(&[], Vec::as_slice);
+        }
+        if !self.header_rules.is_empty() {
+            matched.header_rules = self.header_rules.get(&**matched.route);
+        }
+        matched
+    }
+
+    /// Routes that can never match because another route wins every request they match, as
+    /// `(shadowed, winner)` route names. Only shadowing that follows from the patterns alone is
+    /// found: a regex route is only known to be shadowed by a `/` route.
+    pub fn shadowed_routes(&self) -> Vec<(Arc<str>, Arc<str>)> {
+        let mut shadowed: Vec<(Arc<str>, Arc<str>)> = Vec::new();
+        for routes in [&self.header_routes, &self.query_routes] {
+            shadowed.extend(routes.shadowed().map(|(route, winner)| (route.source.clone(), winner.source.clone())));
+        }
+        for vhost in self.virtual_hosts.values() {
+            shadowed.extend(vhost.routes.shadowed().map(|(route, winner)| (route.name.clone(), winner.name.clone())));
+            // Path routes of the host are tried before its catch-all routes, whatever their priority
+            let catch_all = vhost.routes.routes.iter()
+                .filter(|r| matches!(&r.matcher, Matcher::Prefix(prefix) if prefix == "/"))
+                .map(|r| &r.target);
+            for (idx, backend) in vhost.backends.iter().enumerate() {
+                let winner = catch_all.clone().chain(&vhost.backends[..idx]).find(|t| t.covers_methods(backend));
+                if let Some(winner) = winner {
+                    shadowed.push((backend.name.clone(), winner.name.clone()));
+                }
+            }
+        }
+        shadowed.extend(self.routes.shadowed().map(|(route, winner)| (route.name.clone(), winner.name.clone())));
+        shadowed
+    }
+
+    /// Why `get_backend_and_prefix` picks the route it does for a request, one line per step of
+    /// the lookup, for `routes test`
+    pub fn explain(&self, request: &RequestHead) -> Vec<String> {
+        let (method, path) = (request.method, request.path);
+        let mut steps = Vec::new();
+
+        if let Some(route) = self.header_routes.find_header(request) {
+            let value = request.header_str(&route.name).unwrap_or_default();
+            steps.push(format!("header {}: {} equals the route's value; header routes are tried first", route.name, value));
+            steps.push(format!("priority {}", route.options.priority));
+            return steps;
+        }
+        if !self.header_routes.is_empty() {
+            steps.push("no header route matches".to_string());
+        }
+
+        if let Some(route) = request.query.filter(|_| !self.query_routes.is_empty()).and_then(|q| self.query_routes.find_query(q)) {
+            steps.push(format!("query parameter {}={} is in the query; query routes are tried after header routes", route.name, route.value));
+            steps.push(format!("priority {}", route.options.priority));
+            return steps;
+        }
+        if !self.query_routes.is_empty() {
+            steps.push("no query route matches".to_string());
+        }
+
+        match request.header_str("host") {
+            Some(host) => match self.virtual_hosts.get(normalize_host(host).as_ref()) {
+                Some(vhost) => {
+                    if let Some(reasons) = vhost.routes.explain(method, path) {
+                        steps.push(format!("Host {} has routes of its own, tried before host-agnostic ones", host));
+                        steps.extend(reasons);
+                        return steps;
+                    }
+                    if let Some(target) = vhost.backends.iter().find(|t| t.accepts(method)) {
+                        steps.push(format!("no path route of Host {} matches; the host's catch-all route takes the request", host));
+                        if let Some(methods) = &target.methods {
+                            steps.push(format!("method {} is one of the route's methods {}", method, methods.join(",")));
+                        }
+                        return steps;
+                    }
+                    steps.push(format!("no route of Host {} matches", host));
+                }
+                None => steps.push(format!("no routes for Host {}", host)),
+            },
+            None => steps.push("no Host header; only host-agnostic routes apply".to_string()),
+        }
+
+        match self.routes.explain(method, path) {
+            Some(reasons) => steps.extend(reasons),
+            None if self.default_backend.is_some() => steps.push("no route matches; the default backend takes the request".to_string()),
+            None => steps.push("no route matches and there is no default backend".to_string()),
+        }
+        steps
+    }
+
+    fn find_route<'a>(&'a self, request: &RequestHead<'a>) -> RouteMatch<'a> {
+        let (method, path) = (request.method, request.path);
+
+        if let Some(route) = self.header_routes.find_header(request) {
+            return route.route_match();
+        }
+
+        if let Some(route) = request.query.filter(|_| !self.query_routes.is_empty()).and_then(|q| self.query_routes.find_query(q)) {
+            return route.route_match();
+        }
+
+        if let Some(vhost) = request.header_str("host").and_then(|h| self.virtual_hosts.get(normalize_host(h).as_ref())) {
+            if let Some(matched) = vhost.routes.find(method, path) {
+                return matched;
+            }
+            if let Some(target) = vhost.backends.iter().find(|t| t.accepts(method)) {
+                return RouteMatch::new(Some(&target.action), "", &target.name, &target.options);
+            }
+        }
+
+        self.routes.find(method, path)
+            .unwrap_or_else(|| RouteMatch::new(self.default_backend.as_ref(), "", &self.default_route, &self.default_options))
+    }
+}
+
+/// Split a path or host route definition (without options) into its name and action
+fn split_path_route(definition: &str) -> Option<(&str, &str)> {
+    // Regexes may contain '=' themselves, and so may response bodies and redirect URLs; backends never do
+    let local_action = [RESPOND_PREFIX, REDIRECT_PREFIX].iter()
+        .filter_map(|prefix| definition.find(&format!("={}", prefix)))
+        .min();
+    let split = if let Some(idx) = local_action {
+        Some((&definition[..idx], &definition[idx + 1..]))
+    } else if definition.starts_with("re:") || definition.contains(" re:") {
+        definition.rsplit_once('=')
+    } else {
+        definition.split_once('=')
+    };
+    split.filter(|(name, _)| !name.is_empty())
+}
+
+/// The name of a route definition as rules and metrics refer to it: the route as written without its
+/// action and options (`POST /upload`, `api.example.com`, `X-Tenant=acme`). `kind` is "path",
+/// "header" or "query".
+pub fn route_name(route: &str, kind: &str) -> Option<String> {
+    let (definition, _) = RouteOptions::split(route).ok()?;
+    if kind == "path" {
+        return split_path_route(definition).map(|(name, _)| name.to_string());
+    }
+    let parts: Vec<&str> = definition.splitn(3, '=').collect();
+    (parts.len() == 3).then(|| format!("{}={}", parts[0], parts[1]))
+}
+
+fn conflict_error(existing: &str, route: &Target) -> String {
+    format!(
+        "Conflicting routes: '{}' and '{}' match the same requests with priority {}; remove one or set distinct priorities",
+        existing, route.name, route.options.priority
+    )
+}
+
+/// Parse a comma-separated list of HTTP methods
+fn parse_methods(methods: &str, route: &str) -> Result<Vec<String>, String> {
+    methods.split(',')
+        .map(|method| {
+            if !method.is_empty() && method.bytes().all(|b| b.is_ascii_uppercase()) {
+                Ok(method.to_string())
+            } else {
+                Err(format!("Invalid method '{}' in route '{}'", method, route))
+            }
+        })
+        .collect()
+}
+
+impl PathRoutes {
+    fn insert(&mut self, pattern: &str, target: Target) -> Result<(), String> {
+        let matcher = if let Some(regex) = pattern.strip_prefix("re:") {
+            Matcher::Regex(Regex::new(regex).map_err(|e| format!("Invalid regex in route '{}': {}", target.name, e))?)
+        } else if !pattern.starts_with('/') {
+            return Err(format!("Path must start with '/': {}", pattern));
+        } else if pattern.contains(['*', '?']) {
+            Matcher::Glob(Regex::new(&glob_to_regex(pattern)).map_err(|e| format!("Invalid glob in route '{}': {}", target.name, e))?)
+        } else {
+            Matcher::Prefix(pattern.to_string())
+        };
+
+        let same_bucket = match &matcher {
+            Matcher::Regex(_) => &self.regexes[..],
+            _ => self.trie.get(literal_prefix(pattern)),
+        };
+        let conflict = same_bucket.iter()
+            .map(|&idx| &self.routes[idx])
+            .find(|r| r.pattern == pattern && r.target.options.priority == target.options.priority && r.target.overlaps(&target));
+        if let Some(other) = conflict {
+            return Err(conflict_error(&other.target.name, &target));
+        }
+
+        let idx = self.routes.len();
+        match matcher {
+            Matcher::Regex(_) => {
+                self.regexes.push(idx);
+                self.max_regex_priority = self.max_regex_priority.max(Some(target.options.priority));
+            }
+            _ => self.trie.insert(literal_prefix(pattern), idx),
+        }
+        self.routes.push(PathRoute { pattern: pattern.to_string(), matcher, target });
+        Ok(())
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.routes.is_empty()
+    }
+
+    /// All route targets in definition order
+    pub fn iter(&self) -> impl Iterator<Item = &Target> {
+        self.routes.iter().map(|r| &r.target)
+    }
+
+    /// Routes that never match because another route matches all their paths and methods and ranks higher
+    fn shadowed(&self) -> impl Iterator<Item = (&Target, &Target)> {
+        self.routes.iter().filter_map(move |route| {
+            self.routes.iter()
+                .find(|other| !std::ptr::eq(*other, route) && other.target.covers_methods(&route.target) && other.always_beats(route))
+                .map(|winner| (&route.target, &winner.target))
+        })
+    }
+
+    /// Find the best route for the request, following the documented evaluation order
+    fn find<'a>(&'a self, method: &str, path: &'a str) -> Option<RouteMatch<'a>> {
+        self.best(method, path).map(|(route, _, prefix)| RouteMatch::new(Some(&route.target.action), prefix, &route.target.name, &route.target.options))
+    }
+
+    /// The best route for the request with how it matched and the matched prefix
+    fn best<'a>(&'a self, method: &str, path: &'a str) -> Option<(&'a PathRoute, Specificity, &'a str)> {
+        let mut best: Option<(&'a PathRoute, Specificity, &'a str)> = None;
+
+        let candidates = self.trie.prefixes_of(path).flatten();
+        for &idx in candidates {
+            best = self.consider(best, idx, method, path);
+        }
+
+        // A regex only beats a prefix match of the same priority when nothing else matched
+        let regex_can_win = self.max_regex_priority
+            .is_some_and(|regex_priority| best.as_ref().map_or(true, |(current, _, _)| regex_priority > current.target.options.priority));
+        if regex_can_win {
+            for &idx in &self.regexes {
+                best = self.consider(best, idx, method, path);
+            }
+        }
+
+        best
+    }
+
+    /// Why the best route for the request wins, or None when no route matches
+    fn explain(&self, method: &str, path: &str) -> Option<Vec<String>> {
+        let (route, specificity, _) = self.best(method, path)?;
+        let mut reasons = vec![match (&route.matcher, specificity) {
+            (_, Specificity::Exact) => format!("path {} equals the route's path", path),
+            (Matcher::Glob(_), Specificity::Prefix(len)) => format!("glob {} matches the first {} bytes of path {}", route.pattern, len, path),
+            (_, Specificity::Prefix(len)) => format!("path {} starts with the route's prefix {} ({} bytes)", path, route.pattern, len),
+            (_, Specificity::Regex) => format!("regex {} matches path {}", route.pattern.trim_start_matches("re:"), path),
+        }];
+        if let Some(methods) = &route.target.methods {
+            reasons.push(format!("method {} is one of the route's methods {}", method, methods.join(",")));
+        }
+        let others = self.routes.iter()
+            .filter(|other| !std::ptr::eq(*other, route) && other.target.accepts(method) && other.matches(path).is_some())
+            .count();
+        reasons.push(match others {
+            0 => format!("priority {}; no other route matches", route.target.options.priority),
+            // The evaluation order of `PathRoutes`
+            n => format!("priority {}; wins over {} other matching route{} by priority, then exact > longest prefix > regex, then methods, then definition order",
+                route.target.options.priority, n, if n == 1 { "" } else { "s" }),
+        });
+        Some(reasons)
+    }
+
+    /// Keep whichever of the current best match and route `idx` ranks higher for the request
+    fn consider<'a>(
+        &'a self,
+        best: Option<(&'a PathRoute, Specificity, &'a str)>,
+        idx: usize,
+        method: &str,
+        path: &'a str,
+    ) -> Option<(&'a PathRoute, Specificity, &'a str)> {
+        let route = &self.routes[idx];
+        if !route.target.accepts(method) {
+            return best;
+        }
+        let Some((specificity, prefix)) = route.matches(path) else {
+            return best;
+        };
+
+        match best {
+            Some((current, current_specificity, current_prefix))
+                if (current.target.options.priority, &current_specificity, current.target.tie_break())
+                    > (route.target.options.priority, &specificity, route.target.tie_break()) =>
+            {
+                Some((current, current_specificity, current_prefix))
+            }
+            _ => Some((route, specificity, prefix)),
+        }
+    }
+}
+
+impl PathRoute {
+    /// Whether the route matches every path `other` matches and ranks higher on all of them
+    fn always_beats(&self, other: &PathRoute) -> bool {
+        let Matcher::Prefix(prefix) = &self.matcher else {
+            return false;
+        };
+        match &other.matcher {
+            // Longer prefixes win at equal priority; globs only match paths starting with their literal part
+            Matcher::Prefix(_) | Matcher::Glob(_) => {
+                literal_prefix(&other.pattern).starts_with(prefix.as_str()) && self.target.options.priority > other.target.options.priority
+            }
+            // Regexes lose to any prefix match of the same priority
+            Matcher::Regex(_) => prefix == "/" && self.target.options.priority >= other.target.options.priority,
+        }
+    }
+
+    /// How the route matches the path, and the matched prefix to strip when rewriting
+    fn matches<'a>(&self, path: &'a str) -> Option<(Specificity, &'a str)> {
+        match &self.matcher {
+            Matcher::Prefix(prefix) if path == prefix => Some((Specificity::Exact, path)),
+            Matcher::Prefix(prefix) => path.starts_with(prefix.as_str())
+                .then(|| (Specificity::Prefix(prefix.len()), &path[..prefix.len()])),
+            Matcher::Glob(regex) => regex.find(path).map(|m| (Specificity::Prefix(m.end()), &path[..m.end()])),
+            Matcher::Regex(regex) => regex.find(path).map(|m| {
+                // Only a match anchored at the start of the path can be stripped when rewriting
+                let prefix = if m.start() == 0 { &path[..m.end()] } else { "" };
+                (Specificity::Regex, prefix)
+            }),
+        }
+    }
+}
+
+/// The literal part of a path pattern before its first glob wildcard
+fn literal_prefix(pattern: &str) -> &str {
+    pattern.find(['*', '?']).map_or(pattern, |idx| &pattern[..idx])
+}
+
+/// Translate a path glob into an anchored regex: `*` matches within a segment, `**` across segments
+fn glob_to_regex(glob: &str) -> String {
+    let mut regex = String::from("^");
+    let mut chars = glob.chars().peekable();
+
+    while let Some(c) = chars.next() {
+        match c {
+            '*' if chars.peek() == Some(&'*') => {
+                chars.next();
+                regex.push_str(".*");
+            }
+            '*' => regex.push_str("[^/]*"),
+            '?' => regex.push_str("[^/]"),
+            c => regex.push_str(&regex::escape(&c.to_string())),
+        }
+    }
+
+    regex
+}
+
+/// The outcome of routing a request
+pub struct RouteMatch<'a> {
+    /// None when no route matched and there is no default backend
+    pub action: Option<&'a Action>,
+    /// The part of the request path matched by the route (empty for host-only routes and the default backend)
+    pub prefix: &'a str,
+    /// The matched route as written on the command line, or "default" for the fallback backend
+    route: &'a Arc<str>,
+    /// The route's path rewrite; `--rewrite` applies `Strip` to routes without their own rule
+    pub rewrite: Option<&'a PathRewrite>,
+    /// The route's `--rewrite-rule`s, applied after `rewrite`
+    pub rules: &'a [RewriteRule],
+    /// The route's `--sub-filter`s, applied to HTML and JSON response bodies
+    pub body_filters: &'a [RewriteRule],
+    /// The route's `--set-header` / `--remove-header` rules and their response counterparts
+    pub header_rules: Option<&'a HeaderRules>,
+    /// The route's Host header policy; `--preserve-host=false` applies `Backend` to routes without one
+    pub host: Option<&'a HostHeader>,
+    /// The matched route's options
+    pub options: &'a RouteOptions,
+}
+
+impl<'a> RouteMatch<'a> {
+    fn new(action: Option<&'a Action>, prefix: &'a str, route: &'a Arc<str>, options: &'a RouteOptions) -> Self {
+        RouteMatch {
+            action,
+            prefix,
+            route,
+            rewrite: options.rewrite.as_ref(),
+            rules: &[],
+            body_filters: &[],
+            header_rules: None,
+            host: options.host.as_ref(),
+            options,
+        }
+    }
+
+    pub fn name(&self) -> &'a Arc<str> {
+        self.route
+    }
+
+    /// Whether each request to the route is checked or changed on its own (its options check
+    /// requests, or it rewrites their path, Host or headers), so that the requests after it on a
+    /// kept-alive connection cannot be relayed as they are
+    pub fn checks_requests(&self) -> bool {
+        self.options.checks_requests() || self.rewrite.is_some() || self.host.is_some() || !self.rules.is_empty() || self.header_rules.is_some()
+    }
+
+    /// Whether a client that authenticated with the given PSK identity (if any) may use the route
+    pub fn allows(&self, psk_identity: Option<&str>) -> bool {
+        match (self.options.psk.as_deref(), psk_identity) {
+            (None, _) => true,
+            (Some(_), None) => false,
+            (Some(allowed), Some(identity)) => allowed.iter().any(|a| a == "*" || a == identity),
+        }
+    }
+
+    /// PSK identities allowed to use the route; None when the route is open to all clients
+    pub fn psk_identities(&self) -> Option<&'a [String]> {
+        self.options.psk.as_deref()
+    }
+
+    /// Listeners the route is served on; None when it is served on all
+    pub fn listeners(&self) -> Option<&'a [String]> {
+        self.options.listeners.as_deref()
+    }
+
+    /// Whether the route is served on the named listener
+    pub fn on_listener(&self, listener: &str) -> bool {
+        self.listeners().map_or(true, |listeners| listeners.iter().any(|name| name == listener))
+    }
+}
+
+/// Decode `%XX` escapes and `+` (as space) in a query string component, borrowing when there are none
+fn percent_decode(input: &str) -> Cow<'_, str> {
+    let bytes = input.as_bytes();
+    if !bytes.iter().any(|&b| b == b'%' || b == b'+') {
+        return Cow::Borrowed(input);
+    }
+
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+
+    while i < bytes.len() {
+        match bytes[i] {
+            b'+' => decoded.push(b' '),
+            b'%' if i + 2 < bytes.len() => {
+                let hex = |b: u8| (b as char).to_digit(16);
+                match (hex(bytes[i + 1]), hex(bytes[i + 2])) {
+                    (Some(high), Some(low)) => {
+                        decoded.push((high * 16 + low) as u8);
+                        i += 2;
+                    }
+                    _ => decoded.push(b'%'),
+                }
+            }
+            b => decoded.push(b),
+        }
+        i += 1;
+    }
+
+    Cow::Owned(String::from_utf8_lossy(&decoded).into_owned())
+}
+
+/// Lowercase the Host header value and strip any port suffix, borrowing when it is already normalized
+pub fn normalize_host(host: &str) -> Cow<'_, str> {
+    let host = host.trim();
+    let without_port = if host.starts_with('[') {
+        // IPv6 literal, e.g. [::1]:8080
+        host.find(']').map_or(host, |idx| &host[..=idx])
+    } else {
+        host.rsplit_once(':').map_or(host, |(name, _)| name)
+    };
+
+    if without_port.bytes().any(|b| b.is_ascii_uppercase()) {
+        Cow::Owned(without_port.to_ascii_lowercase())
+    } else {
+        Cow::Borrowed(without_port)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::request::MAX_HEADERS;
+
+    /// A route table of path routes, with a default backend
+    fn config(routes: &[&str]) -> Result<RouteConfig, String> {
+        let routes = routes.iter().map(|route| route.to_string()).collect();
+        RouteConfig::from_snapshot(Snapshot { default_backend: Some("127.0.0.1:3000".into()), routes, ..Snapshot::default() })
+    }
+
+    /// The name of the route a request is routed by
+    fn routed(config: &RouteConfig, method: &str, path: &str) -> String {
+        let data = format!("{} {} HTTP/1.1\r\nHost: example.com\r\n\r\n", method, path);
+        let mut storage = [httparse::EMPTY_HEADER; MAX_HEADERS];
+        let head = RequestHead::parse(data.as_bytes(), &mut storage).unwrap();
+        config.get_backend_and_prefix(&head).name().to_string()
+    }
+
+    #[test]
+    fn split_takes_trailing_options() {
+        let (definition, options) = RouteOptions::split("/api=127.0.0.1:4000;strip-prefix;priority=5;header=X-A: 1;header=X-B: 2").unwrap();
+        assert_eq!(definition, "/api=127.0.0.1:4000");
+        assert!(matches!(options.rewrite, Some(PathRewrite::Strip)));
+        assert_eq!(options.priority, 5);
+        assert_eq!(options.headers, [("X-A".to_string(), "1".to_string()), ("X-B".to_string(), "2".to_string())]);
+    }
+
+    #[test]
+    fn split_keeps_semicolons_that_are_not_options() {
+        let (definition, options) = RouteOptions::split("~^/a;b$=127.0.0.1:4000;psk=sensor-1,sensor-2").unwrap();
+        assert_eq!(definition, "~^/a;b$=127.0.0.1:4000");
+        assert_eq!(options.psk.as_deref(), Some(&["sensor-1".to_string(), "sensor-2".to_string()][..]));
+    }
+
+    #[test]
+    fn split_rejects_invalid_options() {
+        assert!(RouteOptions::split("/api=127.0.0.1:4000;no-such-option").is_err());
+        assert!(RouteOptions::split("/api=127.0.0.1:4000;priority=high").is_err());
+        assert!(RouteOptions::split("/api=127.0.0.1:4000;strip-prefix;add-prefix=/v1").is_err());
+        assert!(RouteOptions::split("/api=127.0.0.1:4000;add-prefix=v1").is_err());
+        assert!(RouteOptions::split("/api=127.0.0.1:4000;deny-asn=1;allow-asn=2").is_err());
+    }
+
+    #[test]
+    fn routes_are_tried_exact_then_longest_prefix_then_regex_then_default() {
+        let config = config(&["re:^/api/.*=127.0.0.1:4300", "/api=127.0.0.1:4000", "/api/v2=127.0.0.1:4100", "/api/v2/users=127.0.0.1:4200"]).unwrap();
+        assert_eq!(routed(&config, "GET", "/api/v2/users"), "/api/v2/users");
+        assert_eq!(routed(&config, "GET", "/api/v2/items"), "/api/v2");
+        assert_eq!(routed(&config, "GET", "/api/items"), "/api");
+        assert_eq!(routed(&config, "GET", "/other"), DEFAULT_ROUTE);
+    }
+
+    #[test]
+    fn priority_overrides_the_built_in_order() {
+        let config = config(&["/api=127.0.0.1:4000", "re:^/api/(admin|internal)=127.0.0.1:4100;priority=10"]).unwrap();
+        assert_eq!(routed(&config, "GET", "/api/admin/users"), "re:^/api/(admin|internal)");
+        assert_eq!(routed(&config, "GET", "/api/users"), "/api");
+    }
+
+    #[test]
+    fn method_restricted_routes_are_tried_first() {
+        let config = config(&["/upload=127.0.0.1:4000", "POST /upload=127.0.0.1:4100"]).unwrap();
+        assert_eq!(routed(&config, "POST", "/upload"), "POST /upload");
+        assert_eq!(routed(&config, "GET", "/upload"), "/upload");
+    }
+
+    #[test]
+    fn conflicting_routes_are_rejected() {
+        assert!(config(&["/api=127.0.0.1:4000", "/api=127.0.0.1:4100"]).is_err());
+        assert!(config(&["/api=127.0.0.1:4000", "/api=127.0.0.1:4100;priority=1"]).is_ok());
+    }
+
+    #[test]
+    fn glob_to_regex_matches_within_and_across_segments() {
+        let single = Regex::new(&glob_to_regex("/files/*.txt")).unwrap();
+        assert!(single.is_match("/files/a.txt"));
+        assert!(!single.is_match("/files/dir/a.txt"));
+
+        let double = Regex::new(&glob_to_regex("/files/**.txt")).unwrap();
+        assert!(double.is_match("/files/dir/a.txt"));
+
+        let one = Regex::new(&glob_to_regex("/v?/items")).unwrap();
+        assert!(one.is_match("/v1/items"));
+        assert!(!one.is_match("/v10/items"));
+        assert!(!one.is_match("/xv1/items"));
+    }
+
+    #[test]
+    fn glob_to_regex_escapes_regex_characters() {
+        assert_eq!(glob_to_regex("/a.b+(c)"), r"^/a\.b\+\(c\)");
+    }
+
+    #[test]
+    fn glob_and_regex_routes_match_paths() {
+        let config = config(&["/files/*.txt=127.0.0.1:4000", "/static/**=127.0.0.1:4100", "re:^/v[0-9]+/items$=127.0.0.1:4200"]).unwrap();
+        assert_eq!(routed(&config, "GET", "/files/a.txt"), "/files/*.txt");
+        assert_eq!(routed(&config, "GET", "/files/dir/a.txt"), DEFAULT_ROUTE);
+        assert_eq!(routed(&config, "GET", "/static/css/site.css"), "/static/**");
+        assert_eq!(routed(&config, "GET", "/v2/items"), "re:^/v[0-9]+/items$");
+        assert_eq!(routed(&config, "GET", "/v2/items/1"), DEFAULT_ROUTE);
+    }
+
+    #[test]
+    fn percent_decode_decodes_escapes_and_plus() {
+        assert_eq!(percent_decode("a%20b+c"), "a b c");
+        assert_eq!(percent_decode("%41%62"), "Ab");
+        assert!(matches!(percent_decode("plain"), Cow::Borrowed("plain")));
+    }
+
+    #[test]
+    fn percent_decode_keeps_invalid_escapes() {
+        assert_eq!(percent_decode("100%"), "100%");
+        assert_eq!(percent_decode("%zz"), "%zz");
+        assert_eq!(percent_decode("a%4"), "a%4");
+    }
+
+    #[test]
+    fn query_routes_match_decoded_parameters() {
+        let query_routes = vec!["version=beta test=127.0.0.1:4000".to_string()];
+        let config = RouteConfig::from_snapshot(Snapshot { default_backend: Some("127.0.0.1:3000".into()), query_routes, ..Snapshot::default() }).unwrap();
+        assert_eq!(routed(&config, "GET", "/items?page=2&version=beta+test"), "version=beta test");
+        assert_eq!(routed(&config, "GET", "/items?version=beta%20test"), "version=beta test");
+        assert_eq!(routed(&config, "GET", "/items?version=beta"), DEFAULT_ROUTE);
+    }
+
+    #[test]
+    fn normalize_host_lowercases_and_strips_the_port() {
+        assert_eq!(normalize_host("Example.COM:8080"), "example.com");
+        assert_eq!(normalize_host(" example.com "), "example.com");
+        assert_eq!(normalize_host("[::1]:8080"), "[::1]");
+        assert_eq!(normalize_host("[::1]"), "[::1]");
+        assert!(matches!(normalize_host("example.com"), Cow::Borrowed("example.com")));
+    }
+
+    #[test]
+    fn host_routes_match_the_normalized_host() {
+        let config = config(&["api.example.com=127.0.0.1:4000", "api.example.com/v2=127.0.0.1:4100"]).unwrap();
+        let route = |host: &str, path: &str| {
+            let data = format!("GET {} HTTP/1.1\r\nHost: {}\r\n\r\n", path, host);
+            let mut storage = [httparse::EMPTY_HEADER; MAX_HEADERS];
+            let head = RequestHead::parse(data.as_bytes(), &mut storage).unwrap();
+            config.get_backend_and_prefix(&head).name().to_string()
+        };
+        assert_eq!(route("API.example.com:8080", "/items"), "api.example.com");
+        assert_eq!(route("api.example.com", "/v2/items"), "api.example.com/v2");
+        assert_eq!(route("www.example.com", "/v2/items"), DEFAULT_ROUTE);
+    }
+
+    #[test]
+    fn rewrite_rule_parses_routes_with_spaces_and_flags() {
+        let rule = RewriteRule::parse(r"route=POST /upload s|^/upload/(\d+)|/files/$1|", "rewrite rule").unwrap();
+        assert_eq!(rule.route, "POST /upload");
+        assert_eq!(rule.apply("/upload/42/x"), "/files/42/x");
+        assert!(!rule.global);
+
+        let global = RewriteRule::parse("route=/api s#a#b#g", "rewrite rule").unwrap();
+        assert!(global.global);
+        assert_eq!(global.apply("/aaa"), "/bbb");
+    }
+
+    #[test]
+    fn routes_changing_requests_check_each_request() {
+        assert!(!config(&["/api=127.0.0.1:4000"]).unwrap().checks_requests());
+        for route in ["/api=127.0.0.1:4000;strip-prefix", "/api=127.0.0.1:4000;add-prefix=/v1", "/api=127.0.0.1:4000;host=api.internal"] {
+            assert!(config(&[route]).unwrap().checks_requests(), "{}", route);
+        }
+        let rules = |rewrite_rules: &[&str], set_headers: &[&str]| {
+            let snapshot = Snapshot {
+                default_backend: Some("127.0.0.1:3000".into()),
+                routes: vec!["/api=127.0.0.1:4000".into()],
+                rewrite_rules: rewrite_rules.iter().map(|rule| rule.to_string()).collect(),
+                set_headers: set_headers.iter().map(|rule| rule.to_string()).collect(),
+                ..Snapshot::default()
+            };
+            RouteConfig::from_snapshot(snapshot).unwrap().checks_requests()
+        };
+        assert!(rules(&["route=/api s|a|b|"], &[]));
+        assert!(rules(&[], &["/api:X-Api: 1"]));
+        let config = config(&["/api=127.0.0.1:4000;strip-prefix", "/web=127.0.0.1:5000"]).unwrap();
+        let matched = |path: &str| {
+            let data = format!("GET {} HTTP/1.1\r\nHost: example.com\r\n\r\n", path);
+            let mut storage = [httparse::EMPTY_HEADER; MAX_HEADERS];
+            config.get_backend_and_prefix(&RequestHead::parse(data.as_bytes(), &mut storage).unwrap()).checks_requests()
+        };
+        assert!(matched("/api/x"));
+        assert!(!matched("/web/x"));
+        assert!(!matched("/other"));
+    }
+
+    /// Lookup time on a large table: 1100 path and host routes, 20 header and 10 query routes.
+    /// Run with `cargo test --release route_lookup_benchmark -- --ignored --nocapture`.
+    #[test]
+    #[ignore]
+    fn route_lookup_benchmark() {
+        let mut routes = Vec::new();
+        for i in 0..550 {
+            routes.push(format!("/service{}/api=127.0.0.1:{}", i, 4000 + i));
+            routes.push(format!("app{}.example.com=127.0.0.1:{}", i, 5000 + i));
+        }
+        let snapshot = Snapshot {
+            default_backend: Some("127.0.0.1:3000".into()),
+            routes,
+            header_routes: (0..20).map(|i| format!("X-Tenant=tenant{}=127.0.0.1:{}", i, 6000 + i)).collect(),
+            query_routes: (0..10).map(|i| format!("version=v{}=127.0.0.1:{}", i, 7000 + i)).collect(),
+            ..Snapshot::default()
+        };
+        let config = RouteConfig::from_snapshot(snapshot).unwrap();
+        let requests = [
+            "GET /service274/api/items HTTP/1.1\r\nHost: example.com\r\n\r\n",
+            "GET /items HTTP/1.1\r\nHost: App301.example.com:8080\r\n\r\n",
+            "GET /items HTTP/1.1\r\nHost: example.com\r\nX-Tenant: tenant17\r\n\r\n",
+            "GET /nowhere?page=2&version=v9 HTTP/1.1\r\nHost: example.com\r\n\r\n",
+        ];
+        let mut storage = [[httparse::EMPTY_HEADER; MAX_HEADERS]; 4];
+        let heads: Vec<RequestHead> = requests.iter().zip(&mut storage).map(|(data, storage)| RequestHead::parse(data.as_bytes(), storage).unwrap()).collect();
+        let names: Vec<String> = heads.iter().map(|head| config.get_backend_and_prefix(head).name().to_string()).collect();
+        assert_eq!(names, ["/service274/api", "app301.example.com", "X-Tenant=tenant17", "version=v9"]);
+
+        let rounds = 250_000;
+        let start = std::time::Instant::now();
+        for _ in 0..rounds {
+            for head in &heads {
+                std::hint::black_box(config.get_backend_and_prefix(std::hint::black_box(head)));
+            }
+        }
+        let per_lookup = start.elapsed().as_nanos() / (rounds * heads.len() as u128);
+        println!("route lookup: {} ns per request", per_lookup);
+    }
+
+    #[test]
+    fn rewrite_rule_rejects_invalid_specs() {
+        assert!(RewriteRule::parse("/api s|a|b|", "rewrite rule").is_err());
+        assert!(RewriteRule::parse("route=/api s|a|b|x", "rewrite rule").is_err());
+        assert!(RewriteRule::parse("route=/api s||b|", "rewrite rule").is_err());
+        assert!(RewriteRule::parse("route= s|a|b|", "rewrite rule").is_err());
+        assert!(RewriteRule::parse("route=/api s|(|b|", "rewrite rule").is_err());
+    }
+
+    #[test]
+    fn split_marks_routes_that_check_requests() {
+        assert!(!RouteOptions::split("/api=127.0.0.1:4000;strip-prefix").unwrap().1.checks_requests());
+        assert!(RouteOptions::split("/api=127.0.0.1:4000;rate-limit=10/s").unwrap().1.checks_requests());
+        assert!(RouteOptions::split("/api=127.0.0.1:4000;listener=internal").unwrap().1.checks_requests());
+        assert!(RouteOptions::split("/api=127.0.0.1:4000;idempotency").unwrap().1.checks_requests());
+    }
+}
@@ -0,0 +1,107 @@
+//! `reverse-http-proxy config schema`: a JSON Schema of the configuration file, for editors and CI
+//! to check `--config` files against. It is built from the file's keys and the command-line options
+//! they stand for (help, type, default), so it covers every key the proxy accepts and only those.
+
+use crate::configfile::{ConfigFile, RENAMED};
+use crate::Args;
+use clap::{Arg, ArgAction, CommandFactory};
+use serde_json::{json, Map, Value};
+use std::any::TypeId;
+use std::path::PathBuf;
+
+/// The JSON Schema dialect of the export
+const DIALECT: &str = "https://json-schema.org/draft/2020-12/schema";
+
+/// The schema of the configuration file
+pub fn export() -> Value {
+    let command = Args::command();
+    let mut properties = Map::new();
+    for &key in ConfigFile::keys() {
+        let id = RENAMED.iter().find(|(renamed, _)| *renamed == key).map_or(key, |(_, id)| *id);
+        let arg = command.get_arguments().find(|arg| arg.get_id() == id);
+        let mut property = match arg {
+            _ if key == "routes" => routes(),
+            Some(arg) => option(arg),
+            // A key without an option of its own takes any value
+            None => json!({}),
+        };
+        if let Some(help) = arg.and_then(|arg| arg.get_long_help().or_else(|| arg.get_help())) {
+            property["description"] = Value::String(help.to_string());
+        }
+        properties.insert(key.to_string(), property);
+    }
+    json!({
+        "$schema": DIALECT,
+        "title": "reverse-http-proxy configuration file",
+        "description": "Keys are the command-line options in snake_case; options given on the command line override the file",
+        "type": "object",
+        "additionalProperties": false,
+        "properties": properties,
+    })
+}
+
+/// The schema of an option's value: a list of values for repeatable options
+fn option(arg: &Arg) -> Value {
+    let value = value(arg);
+    let mut schema = match arg.get_action() {
+        ArgAction::Append => json!({ "type": "array", "items": value }),
+        _ => value,
+    };
+    let defaults: Vec<Value> = arg.get_default_values().iter().map(|default| typed(arg, &default.to_string_lossy())).collect();
+    match (defaults.as_slice(), arg.get_action()) {
+        ([], _) => {}
+        (_, ArgAction::Append) => schema["default"] = Value::Array(defaults),
+        ([default], _) => schema["default"] = default.clone(),
+        _ => {}
+    }
+    schema
+}
+
+/// The schema of a single value of an option, by the type it parses to
+fn value(arg: &Arg) -> Value {
+    let type_id = arg.get_value_parser().type_id();
+    let mut schema = if type_id == TypeId::of::<bool>() {
+        json!({ "type": "boolean" })
+    } else if [TypeId::of::<u16>(), TypeId::of::<u32>(), TypeId::of::<u64>(), TypeId::of::<usize>()].iter().any(|id| type_id == *id) {
+        json!({ "type": "integer", "minimum": 0 })
+    } else if type_id == TypeId::of::<f64>() {
+        json!({ "type": "number" })
+    } else if type_id == TypeId::of::<PathBuf>() {
+        json!({ "type": "string", "format": "path" })
+    } else {
+        json!({ "type": "string" })
+    };
+    let choices: Vec<Value> = arg.get_possible_values().iter().map(|value| Value::String(value.get_name().to_string())).collect();
+    if !choices.is_empty() && type_id != TypeId::of::<bool>() {
+        schema["enum"] = Value::Array(choices);
+    }
+    schema
+}
+
+/// A default value as the type of the option
+fn typed(arg: &Arg, default: &str) -> Value {
+    match value(arg)["type"].as_str() {
+        Some("boolean") => default.parse().map_or_else(|_| Value::String(default.to_string()), Value::Bool),
+        Some("integer") => default.parse::<u64>().map_or_else(|_| Value::String(default.to_string()), Value::from),
+        Some("number") => default.parse::<f64>().map_or_else(|_| Value::String(default.to_string()), Value::from),
+        _ => Value::String(default.to_string()),
+    }
+}
+
+/// Routes are in `-r` notation, or tables whose other keys are route options
+fn routes() -> Value {
+    let option_value = json!({ "type": ["boolean", "string", "integer"] });
+    let table = json!({
+        "type": "object",
+        "required": ["match", "backend"],
+        "properties": {
+            "match": { "type": "string", "description": "The route as written before `=` in -r notation ([METHOD ]/path, host or host/path)" },
+            "backend": { "type": "string", "description": "The route's action: backends, respond:STATUS[:BODY] or redirect:STATUS:URL" },
+        },
+        "additionalProperties": {
+            "description": "A route option: true for flags, an array for repeatable options",
+            "anyOf": [option_value, { "type": "array", "items": option_value }],
+        },
+    });
+    json!({ "type": "array", "items": { "anyOf": [{ "type": "string" }, table] } })
+}
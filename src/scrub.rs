@@ -0,0 +1,61 @@
+//! Scrubbing of personal and secret data from what the proxy records about requests (`--scrub`):
+//! the values of query parameters that carry credentials, credentials in URLs, e-mail addresses,
+//! and whatever else `--scrub-pattern` matches are replaced with `[redacted]` in access log
+//! entries and traces. Request headers such as `Authorization` and `Cookie` are not recorded at all.
+
+use regex::Regex;
+use std::borrow::Cow;
+
+const REDACTED: &str = "[redacted]";
+
+/// Query parameters whose values are scrubbed, besides those with `token`, `secret` or `passw` in
+/// their names
+const PARAMS: &[&str] = &[
+    "api_key", "apikey", "key", "auth", "code", "jsessionid", "jwt", "session", "sessionid", "sid", "signature", "sig",
+    "x-amz-credential", "x-amz-signature",
+];
+
+pub struct Scrubber {
+    /// The value of a sensitive parameter, after `name=` (captured)
+    params: Regex,
+    /// E-mail addresses, URL credentials and `--scrub-pattern`s, with their replacements
+    patterns: Vec<(Regex, &'static str)>,
+}
+
+impl Scrubber {
+    /// A scrubber for the built-in data with more parameter names and patterns; `--scrub-pattern`
+    /// is named in errors
+    pub fn new(params: &[String], patterns: &[String]) -> Result<Self, String> {
+        let mut names: Vec<String> = PARAMS.iter().map(|name| regex::escape(name)).collect();
+        for name in params {
+            if name.is_empty() || name.contains(['=', '&']) {
+                return Err(format!("Invalid --scrub-param '{}'", name));
+            }
+            names.push(regex::escape(name));
+        }
+        // The name ends at `=`; the value at the next parameter, fragment, or (in notes) whitespace
+        let params = format!(r"(?i)((?:^|[?&;])(?:{}|[^=&?;\s]*(?:token|secret|passw)[^=&?;\s]*)=)[^&#;\s]*", names.join("|"));
+        let mut compiled = vec![
+            // `user:password@` of absolute URLs, keeping the scheme and the host
+            (Regex::new(r"(?i)\b([a-z][a-z0-9+.-]*://)[^/@\s]+@").unwrap(), "${1}[redacted]@"),
+            // `%40` is `@` in paths and query strings
+            (Regex::new(r"[A-Za-z0-9._%+-]+(?:@|%40)[A-Za-z0-9-]+(?:\.[A-Za-z0-9-]+)*\.[A-Za-z]{2,}").unwrap(), REDACTED),
+        ];
+        for pattern in patterns {
+            let regex = Regex::new(pattern).map_err(|e| format!("Invalid --scrub-pattern '{}': {}", pattern, e))?;
+            compiled.push((regex, REDACTED));
+        }
+        Ok(Scrubber { params: Regex::new(&params).unwrap(), patterns: compiled })
+    }
+
+    /// The text with what is to be scrubbed replaced, borrowed when there is nothing
+    pub fn scrub<'t>(&self, text: &'t str) -> Cow<'t, str> {
+        let mut scrubbed = self.params.replace_all(text, "${1}[redacted]");
+        for (pattern, replacement) in &self.patterns {
+            if let Cow::Owned(replaced) = pattern.replace_all(&scrubbed, *replacement) {
+                scrubbed = Cow::Owned(replaced);
+            }
+        }
+        scrubbed
+    }
+}
@@ -0,0 +1,68 @@
+//! Request rate caps towards backends (`;backend-rate=10/s`), for third-party APIs with strict
+//! quotas. Unlike client rate limits, requests over the rate are not refused at once but held in a
+//! bounded queue and released evenly spaced, so bursts reach the backend smoothed out.
+
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+/// A `;backend-rate=REQUESTS/UNIT[:QUEUE]` option: at most REQUESTS per unit to each backend, with up
+/// to QUEUE requests waiting (one unit's worth when not given)
+#[derive(Clone)]
+pub struct BackendRate {
+    pub requests: u32,
+    pub per: Duration,
+    pub queue: u32,
+}
+
+impl BackendRate {
+    pub fn parse(value: &str, route: &str) -> Result<Self, String> {
+        let invalid = || format!("Invalid backend-rate '{}' in route '{}'. Expected REQUESTS/s, /m or /h, optionally followed by :QUEUE", value, route);
+        let (rate, queue) = match value.split_once(':') {
+            Some((rate, queue)) => (rate, Some(queue.parse().map_err(|_| invalid())?)),
+            None => (value, None),
+        };
+        let (requests, unit) = rate.split_once('/').ok_or_else(invalid)?;
+        let requests = requests.parse().ok().filter(|&requests| requests > 0).ok_or_else(invalid)?;
+        let per = match unit {
+            "s" => Duration::from_secs(1),
+            "m" => Duration::from_secs(60),
+            "h" => Duration::from_secs(3600),
+            _ => return Err(invalid()),
+        };
+        Ok(BackendRate { requests, per, queue: queue.unwrap_or(requests) })
+    }
+}
+
+/// When each capped backend may get its next request, shared by all connections and routes
+#[derive(Default)]
+pub struct Shaper {
+    next: Mutex<HashMap<Arc<str>, Instant>>,
+}
+
+impl Shaper {
+    /// Reserve the backend's next free slot for a request: how long to wait for it, or when the
+    /// queue is full, how long until it has room
+    pub fn reserve(&self, backend: &Arc<str>, rate: &BackendRate) -> Result<Duration, Duration> {
+        let interval = rate.per / rate.requests;
+        let now = Instant::now();
+        let mut next = self.next.lock().unwrap();
+        // Backends idle for a while start over
+        next.retain(|_, next| *next > now);
+        let slot = next.get(backend).copied().unwrap_or(now);
+        let wait = slot.saturating_duration_since(now);
+        let room = interval * rate.queue;
+        if wait > room {
+            return Err(wait - room);
+        }
+        next.insert(backend.clone(), slot.max(now) + interval);
+        Ok(wait)
+    }
+}
+
+/// A `503 Service Unavailable` response for requests the backend's queue has no room for
+pub fn queue_full(retry_after: Duration, with_body: bool) -> Vec<u8> {
+    let body = if with_body { "Service Unavailable\r\n" } else { "" };
+    let seconds = retry_after.as_secs() + u64::from(retry_after.subsec_nanos() > 0);
+    format!("HTTP/1.1 503 Service Unavailable\r\nContent-Type: text/plain; charset=utf-8\r\nRetry-After: {}\r\nContent-Length: 21\r\nConnection: close\r\n\r\n{}", seconds.max(1), body).into_bytes()
+}
@@ -0,0 +1,179 @@
+//! Blue/green slots (`--slot NAME:blue=BACKENDS`, `--slot NAME:green=BACKENDS`): routes with a
+//! `slot:NAME` target send new connections to the pool of the slot's active color, and
+//! `POST /slots/switch` moves all of them to the other pool in one route table swap. Connections
+//! open at the switch stay on the old pool until they end, or, with a drain period, until it is over.
+
+use crate::discovery;
+use crate::pool::Pool;
+use std::collections::{BTreeMap, HashMap};
+use std::fmt;
+use std::future::Future;
+use std::sync::{Arc, Mutex};
+use tokio::sync::watch;
+
+/// Prefix of route targets naming a slot (`slot:checkout`)
+pub const SLOT_PREFIX: &str = "slot:";
+
+/// Longest drain period a switch accepts
+pub const MAX_DRAIN_SECONDS: u64 = 86_400;
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum Color {
+    Blue,
+    Green,
+}
+
+impl Color {
+    pub fn parse(name: &str) -> Result<Self, String> {
+        match name {
+            "blue" => Ok(Color::Blue),
+            "green" => Ok(Color::Green),
+            _ => Err(format!("Invalid slot color '{}'. Expected blue or green", name)),
+        }
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Color::Blue => "blue",
+            Color::Green => "green",
+        }
+    }
+
+    fn index(self) -> usize {
+        self as usize
+    }
+}
+
+impl fmt::Display for Color {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+/// A slot's pools by color, as written and parsed, and the color taking new connections
+pub struct Slot {
+    pools: [Option<(String, Pool)>; 2],
+    pub active: Color,
+}
+
+impl Slot {
+    /// The pool of a color as written, if the slot has one
+    pub fn spec(&self, color: Color) -> Option<&str> {
+        self.pools[color.index()].as_ref().map(|(spec, _)| spec.as_str())
+    }
+
+    /// The backends of a color's pool
+    pub fn backends(&self, color: Color) -> Vec<Arc<str>> {
+        self.pools[color.index()].iter().flat_map(|(_, pool)| pool.members().map(|(backend, _)| backend.name.clone())).collect()
+    }
+
+    fn active_spec(&self) -> &str {
+        self.spec(self.active).expect("the active color has a pool")
+    }
+}
+
+/// The slots of a route table, by name
+#[derive(Default)]
+pub struct Slots {
+    slots: BTreeMap<String, Slot>,
+}
+
+impl Slots {
+    /// Parse `NAME:COLOR=BACKENDS` definitions; `active` gives slots on another color than blue
+    pub fn parse(specs: &[String], active: &BTreeMap<String, String>) -> Result<Self, String> {
+        let mut slots: BTreeMap<String, Slot> = BTreeMap::new();
+        for spec in specs {
+            let (name, color, backends) = split(spec)
+                .ok_or_else(|| format!("Invalid slot '{}'. Expected format: NAME:blue=BACKENDS or NAME:green=BACKENDS", spec))?;
+            let color = Color::parse(color)?;
+            if discovery::is_discovered(backends) {
+                return Err(format!("Slot '{}' lists backends, not srv:, k8s: or @ targets", spec));
+            }
+            let pool = Pool::parse(backends, spec)?;
+            let slot = slots.entry(name.to_string()).or_insert(Slot { pools: [None, None], active: Color::Blue });
+            if slot.pools[color.index()].replace((backends.to_string(), pool)).is_some() {
+                return Err(format!("Slot '{}' has its {} pool defined twice", name, color));
+            }
+        }
+        for (name, color) in active {
+            let slot = slots.get_mut(name).ok_or_else(|| format!("The active color is given for unknown slot '{}'", name))?;
+            slot.active = Color::parse(color)?;
+        }
+        for (name, slot) in &slots {
+            if slot.spec(slot.active).is_none() {
+                return Err(format!("Slot '{}' is on {} but has no {} pool; define it with --slot {}:{}=BACKENDS", name, slot.active, slot.active, name, slot.active));
+            }
+        }
+        Ok(Slots { slots })
+    }
+
+    /// The backends a `slot:NAME` route target stands for now, with the slot's name; None for other targets
+    pub fn target<'a>(&'a self, spec: &'a str, route: &str) -> Result<Option<(&'a str, &'a str)>, String> {
+        let Some(name) = spec.strip_prefix(SLOT_PREFIX) else {
+            return Ok(None);
+        };
+        let slot = self.slots.get(name)
+            .ok_or_else(|| format!("Unknown slot '{}' in route '{}'; define it with --slot {}:blue=BACKENDS", name, route, name))?;
+        Ok(Some((name, slot.active_spec())))
+    }
+
+    pub fn get(&self, name: &str) -> Option<&Slot> {
+        self.slots.get(name)
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (&String, &Slot)> {
+        self.slots.iter()
+    }
+}
+
+/// `NAME:COLOR=BACKENDS` split into its parts
+fn split(spec: &str) -> Option<(&str, &str, &str)> {
+    let (key, backends) = spec.split_once('=')?;
+    let (name, color) = key.rsplit_once(':')?;
+    let valid = !name.is_empty() && !backends.is_empty() && !name.contains(|c: char| c.is_whitespace() || c == ';' || c == ':');
+    valid.then_some((name, color, backends))
+}
+
+/// The active colors of `active` that the slots of `specs` still define, to carry over a reload
+pub fn carry_over(active: BTreeMap<String, String>, specs: &[String]) -> BTreeMap<String, String> {
+    active.into_iter()
+        .filter(|(name, color)| specs.iter().filter_map(|spec| split(spec)).any(|(n, c, _)| n == name && c == color))
+        .collect()
+}
+
+/// Connections through slots, to close those left on an old pool once a switch's drain period is over
+#[derive(Default)]
+pub struct Cutovers {
+    /// The backends whose connections through each slot are to close
+    closing: Mutex<HashMap<String, watch::Sender<Vec<Arc<str>>>>>,
+}
+
+impl Cutovers {
+    /// Resolves, with the slot's name, when a connection to `backend` through `pool` is to close;
+    /// never for pools of no slot
+    pub fn closing(&self, pool: &Pool, backend: &Arc<str>) -> impl Future<Output = Arc<str>> {
+        let watched = pool.slot().map(|slot| {
+            let mut closing = self.closing.lock().unwrap();
+            let sender = closing.entry(slot.to_string()).or_insert_with(|| watch::channel(Vec::new()).0);
+            (slot.clone(), sender.subscribe())
+        });
+        let backend = backend.clone();
+        async move {
+            if let Some((slot, mut receiver)) = watched {
+                while receiver.changed().await.is_ok() {
+                    if receiver.borrow().contains(&backend) {
+                        return slot;
+                    }
+                }
+            }
+            std::future::pending().await
+        }
+    }
+
+    /// Close the connections through a slot to these backends
+    pub fn close(&self, slot: &str, backends: Vec<Arc<str>>) {
+        if let Some(sender) = self.closing.lock().unwrap().get(slot) {
+            sender.send_replace(backends);
+        }
+    }
+}
@@ -0,0 +1,151 @@
+//! Protocol sniffing on the main listener (`--sniff`): the first bytes of a connection tell plaintext
+//! HTTP, which is proxied as usual, from TLS and other protocols, which are terminated, passed through
+//! to a fixed backend or rejected.
+
+use std::fmt;
+use std::io;
+use std::net::{SocketAddr, ToSocketAddrs};
+use std::time::Duration;
+use tokio::net::TcpStream;
+use tokio::time::Instant;
+
+/// Bytes examined: enough for the longest common method (`VERSION-CONTROL`) and the space after it
+const SNIFF_LEN: usize = 16;
+
+/// How long to wait for the client to send enough bytes; clients of protocols where the server
+/// speaks first (SMTP, MySQL, ...) send nothing and are classified as other protocols
+const SNIFF_TIMEOUT: Duration = Duration::from_millis(1000);
+
+/// Delay between peeks while the client has sent only part of the bytes needed
+const PEEK_INTERVAL: Duration = Duration::from_millis(10);
+
+#[derive(Clone, Copy, PartialEq)]
+pub enum Protocol {
+    Http,
+    Tls,
+    Other,
+}
+
+impl fmt::Display for Protocol {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Protocol::Http => "HTTP",
+            Protocol::Tls => "TLS",
+            Protocol::Other => "non-HTTP",
+        })
+    }
+}
+
+/// What happens to connections of a sniffed protocol
+pub enum SniffAction {
+    /// Close the connection
+    Reject,
+    /// Complete the TLS handshake with the `--psk-keys` keys and proxy the HTTP inside
+    Terminate,
+    /// Relay the connection unmodified (`ip:port` or `host:port`)
+    Forward { name: String, addr: SocketAddr },
+}
+
+impl fmt::Display for SniffAction {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SniffAction::Reject => f.write_str("reject"),
+            SniffAction::Terminate => f.write_str("terminate (TLS-PSK)"),
+            SniffAction::Forward { name, .. } => write!(f, "{} (passthrough)", name),
+        }
+    }
+}
+
+/// The actions for TLS and other non-HTTP connections; both default to rejecting them
+pub struct Sniffer {
+    pub tls: SniffAction,
+    pub other: SniffAction,
+}
+
+impl Sniffer {
+    /// Parse `PROTOCOL=ACTION` specs (`tls=terminate`, `tls=ADDR`, `other=ADDR`, `...=reject`);
+    /// without specs, connections are not sniffed
+    pub fn parse(specs: &[String]) -> Result<Option<Self>, String> {
+        if specs.is_empty() {
+            return Ok(None);
+        }
+
+        let mut sniffer = Sniffer { tls: SniffAction::Reject, other: SniffAction::Reject };
+        for spec in specs {
+            let (protocol, action) = spec.split_once('=')
+                .ok_or_else(|| format!("Invalid --sniff '{}'. Expected format: tls|other=terminate|reject|ADDRESS", spec))?;
+            let action = match action {
+                "reject" => SniffAction::Reject,
+                "terminate" if protocol == "tls" => SniffAction::Terminate,
+                "terminate" => return Err(format!("Only TLS connections can be terminated (--sniff '{}')", spec)),
+                _ => {
+                    let addr = action.to_socket_addrs()
+                        .map_err(|e| format!("Invalid backend '{}' in --sniff '{}': {}", action, spec, e))?
+                        .next()
+                        .ok_or_else(|| format!("Backend '{}' in --sniff '{}' resolves to no addresses", action, spec))?;
+                    SniffAction::Forward { name: action.to_string(), addr }
+                }
+            };
+            match protocol {
+                "tls" => sniffer.tls = action,
+                "other" => sniffer.other = action,
+                _ => return Err(format!("Unknown protocol '{}' in --sniff '{}' (expected tls or other)", protocol, spec)),
+            }
+        }
+        Ok(Some(sniffer))
+    }
+
+    /// The action for a sniffed protocol; HTTP always goes to the proxy
+    pub fn action(&self, protocol: Protocol) -> Option<&SniffAction> {
+        match protocol {
+            Protocol::Http => None,
+            Protocol::Tls => Some(&self.tls),
+            Protocol::Other => Some(&self.other),
+        }
+    }
+}
+
+/// Classify a connection by its first bytes without consuming them
+pub async fn detect(stream: &TcpStream) -> io::Result<Protocol> {
+    let deadline = Instant::now() + SNIFF_TIMEOUT;
+    let mut buf = [0u8; SNIFF_LEN];
+    loop {
+        let len = match tokio::time::timeout_at(deadline, stream.peek(&mut buf)).await {
+            Ok(len) => len?,
+            Err(_) => return Ok(Protocol::Other),
+        };
+        if len == 0 {
+            return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "connection closed before sending data"));
+        }
+        if let Some(protocol) = classify(&buf[..len]) {
+            return Ok(protocol);
+        }
+        // Peeking returns the same bytes until more arrive
+        if Instant::now() + PEEK_INTERVAL >= deadline {
+            return Ok(Protocol::Other);
+        }
+        tokio::time::sleep(PEEK_INTERVAL).await;
+    }
+}
+
+/// The protocol the bytes start, or `None` if more bytes are needed to tell
+fn classify(data: &[u8]) -> Option<Protocol> {
+    // TLS record: handshake content type (ClientHello), then major version 3
+    if data[0] == 0x16 {
+        return match data.get(1) {
+            Some(3) => Some(Protocol::Tls),
+            Some(_) => Some(Protocol::Other),
+            None => None,
+        };
+    }
+    // HTTP request line: an uppercase method token followed by a space
+    for (idx, &b) in data.iter().enumerate() {
+        if b == b' ' && idx > 0 {
+            return Some(Protocol::Http);
+        }
+        if !(b.is_ascii_uppercase() || b == b'-' || b == b'_') {
+            return Some(Protocol::Other);
+        }
+    }
+    if data.len() >= SNIFF_LEN { Some(Protocol::Other) } else { None }
+}
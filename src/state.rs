@@ -0,0 +1,115 @@
+//! Snapshots of the effective routing state, persisted so runtime changes survive restarts
+
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::path::Path;
+
+/// Format version written to snapshot files
+const SNAPSHOT_VERSION: u32 = 1;
+
+/// The effective configuration, in the same notation as the command-line flags
+#[derive(Clone, Serialize, Deserialize)]
+pub struct Snapshot {
+    pub version: u32,
+    #[serde(default)]
+    pub default_backend: Option<String>,
+    #[serde(default)]
+    pub routes: Vec<String>,
+    #[serde(default)]
+    pub header_routes: Vec<String>,
+    #[serde(default)]
+    pub query_routes: Vec<String>,
+    #[serde(default)]
+    pub rewrite: bool,
+    #[serde(default)]
+    pub rewrite_rules: Vec<String>,
+    #[serde(default = "preserve_host_default")]
+    pub preserve_host: bool,
+    #[serde(default)]
+    pub body_filters: Vec<String>,
+    #[serde(default)]
+    pub set_headers: Vec<String>,
+    #[serde(default)]
+    pub remove_headers: Vec<String>,
+    #[serde(default)]
+    pub set_response_headers: Vec<String>,
+    #[serde(default)]
+    pub remove_response_headers: Vec<String>,
+    #[serde(default)]
+    pub early_hints: Vec<String>,
+    #[serde(default)]
+    pub rate_limit_groups: Vec<String>,
+    /// Backends taking no new connections
+    #[serde(default)]
+    pub drained_backends: Vec<String>,
+    /// Weights replacing the routes' own, by backend
+    #[serde(default)]
+    pub backend_weights: BTreeMap<String, u32>,
+    /// Blue/green slot pools, `NAME:COLOR=BACKENDS`
+    #[serde(default)]
+    pub slots: Vec<String>,
+    /// Slots on another color than blue (`POST /slots/switch`), by name
+    #[serde(default)]
+    pub active_slots: BTreeMap<String, String>,
+}
+
+fn preserve_host_default() -> bool {
+    true
+}
+
+impl Default for Snapshot {
+    /// An empty configuration with the command-line defaults
+    fn default() -> Self {
+        Snapshot {
+            version: SNAPSHOT_VERSION,
+            default_backend: None,
+            routes: Vec::new(),
+            header_routes: Vec::new(),
+            query_routes: Vec::new(),
+            rewrite: false,
+            rewrite_rules: Vec::new(),
+            preserve_host: true,
+            body_filters: Vec::new(),
+            set_headers: Vec::new(),
+            remove_headers: Vec::new(),
+            set_response_headers: Vec::new(),
+            remove_response_headers: Vec::new(),
+            early_hints: Vec::new(),
+            rate_limit_groups: Vec::new(),
+            drained_backends: Vec::new(),
+            backend_weights: BTreeMap::new(),
+            slots: Vec::new(),
+            active_slots: BTreeMap::new(),
+        }
+    }
+}
+
+impl Snapshot {
+    /// Read a snapshot file; a missing file is not an error
+    pub fn load(path: &Path) -> Result<Option<Self>, String> {
+        let contents = match std::fs::read_to_string(path) {
+            Ok(contents) => contents,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(format!("Failed to read state file {}: {}", path.display(), e)),
+        };
+
+        let snapshot: Snapshot = serde_json::from_str(&contents)
+            .map_err(|e| format!("Invalid state file {}: {}", path.display(), e))?;
+        if snapshot.version > SNAPSHOT_VERSION {
+            return Err(format!("State file {} has unsupported version {}", path.display(), snapshot.version));
+        }
+        Ok(Some(snapshot))
+    }
+
+    /// Write the snapshot atomically (write to a temporary file, then rename)
+    pub fn save(&self, path: &Path) -> std::io::Result<()> {
+        let json = serde_json::to_string_pretty(self)?;
+        let tmp = path.with_extension("tmp");
+        std::fs::write(&tmp, json)?;
+        std::fs::rename(&tmp, path)
+    }
+
+    pub fn to_json(&self) -> serde_json::Value {
+        serde_json::to_value(self).unwrap_or_default()
+    }
+}
@@ -0,0 +1,158 @@
+//! Pushing metrics to StatsD over UDP (`--statsd`): every interval, the changes of the counters
+//! behind `/metrics` and the current gauges are sent, with route, backend and other labels as
+//! DogStatsD tags or, for plain StatsD, as parts of the metric names.
+
+use crate::logging;
+use crate::metrics::{Metrics, Pushed, Sample};
+use std::fmt::Write;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::net::UdpSocket;
+
+/// Largest datagram sent, to stay below common path MTUs
+const MAX_DATAGRAM: usize = 1432;
+
+#[derive(Clone, Copy, PartialEq)]
+pub enum Format {
+    /// Labels as `|#name:value` tags
+    DogStatsd,
+    /// Labels as name segments: `requests.api.127_0_0_1_4000`
+    Statsd,
+}
+
+impl Format {
+    pub fn parse(name: &str) -> Result<Self, String> {
+        match name {
+            "dogstatsd" => Ok(Format::DogStatsd),
+            "statsd" => Ok(Format::Statsd),
+            _ => Err(format!("Invalid StatsD format '{}'. Expected dogstatsd or statsd", name)),
+        }
+    }
+}
+
+pub struct StatsdConfig {
+    /// `host:port` of the StatsD server
+    pub address: String,
+    /// Put in front of every metric name
+    pub prefix: String,
+    /// `name:value` tags sent with every metric (DogStatsD only)
+    pub tags: Vec<String>,
+    pub format: Format,
+    pub interval: Duration,
+}
+
+/// Check `--statsd-tag` values: `name:value` or a bare name, without the separators of the protocol
+pub fn check_tag(tag: &str) -> Result<(), String> {
+    if tag.is_empty() || tag.contains(|c: char| c == ',' || c == '|' || c == '#' || c.is_whitespace()) {
+        return Err(format!("Invalid StatsD tag '{}'. Expected NAME:VALUE without spaces, ',', '|' or '#'", tag));
+    }
+    Ok(())
+}
+
+/// Push the metrics every interval until the proxy exits
+pub async fn run(config: StatsdConfig, metrics: Arc<Metrics>) {
+    let mut socket = None;
+    let mut pushed = Pushed::default();
+    let mut failing = false;
+    let mut interval = tokio::time::interval(config.interval);
+    interval.tick().await;
+    loop {
+        interval.tick().await;
+        // Changes are taken while the server is unreachable too: they are lost rather than sent late
+        let samples = metrics.samples(&mut pushed);
+        if socket.is_none() {
+            socket = match connect(&config.address).await {
+                Ok(connected) => Some(connected),
+                Err(e) => {
+                    if !failing {
+                        logging::error(format!("Failed to reach StatsD server {}: {}", config.address, e));
+                        failing = true;
+                    }
+                    continue;
+                }
+            };
+        }
+        let Some(connected) = &socket else { continue };
+        let mut result = Ok(());
+        for datagram in datagrams(&samples, &config) {
+            result = connected.send(datagram.as_bytes()).await.map(drop);
+            if result.is_err() {
+                break;
+            }
+        }
+        match result {
+            Err(e) => {
+                if !failing {
+                    logging::error(format!("Failed to send metrics to StatsD server {}: {}", config.address, e));
+                    failing = true;
+                }
+                // Resolve the address again next time, in case the server moved
+                socket = None;
+            }
+            Ok(()) if failing => {
+                logging::warning(format!("Sending metrics to StatsD server {} again", config.address));
+                failing = false;
+            }
+            Ok(()) => {}
+        }
+    }
+}
+
+async fn connect(address: &str) -> std::io::Result<UdpSocket> {
+    let target = tokio::net::lookup_host(address).await?.next()
+        .ok_or_else(|| std::io::Error::new(std::io::ErrorKind::NotFound, "no address"))?;
+    let socket = UdpSocket::bind(if target.is_ipv4() { "0.0.0.0:0" } else { "[::]:0" }).await?;
+    socket.connect(target).await?;
+    Ok(socket)
+}
+
+/// The samples as StatsD lines, packed into datagrams
+fn datagrams(samples: &[Sample], config: &StatsdConfig) -> Vec<String> {
+    let mut datagrams = Vec::new();
+    let mut datagram = String::new();
+    for sample in samples {
+        let line = line(sample, config);
+        if !datagram.is_empty() && datagram.len() + 1 + line.len() > MAX_DATAGRAM {
+            datagrams.push(std::mem::take(&mut datagram));
+        }
+        if !datagram.is_empty() {
+            datagram.push('\n');
+        }
+        datagram.push_str(&line);
+    }
+    if !datagram.is_empty() {
+        datagrams.push(datagram);
+    }
+    datagrams
+}
+
+/// `prefix.name:value|c` or `|g`, with the labels as tags or name segments
+fn line(sample: &Sample, config: &StatsdConfig) -> String {
+    let mut line = format!("{}{}", config.prefix, sample.name);
+    if config.format == Format::Statsd {
+        for (_, value) in &sample.labels {
+            line.push('.');
+            line.push_str(&segment(value));
+        }
+    }
+    let value = if sample.value.fract() == 0.0 { format!("{}", sample.value as i64) } else { format!("{:.3}", sample.value) };
+    let _ = write!(line, ":{}|{}", value, if sample.counter { "c" } else { "g" });
+    if config.format == Format::DogStatsd && (!sample.labels.is_empty() || !config.tags.is_empty()) {
+        let tags = sample.labels.iter().map(|(name, value)| format!("{}:{}", name, tag_value(value))).chain(config.tags.iter().cloned());
+        let _ = write!(line, "|#{}", tags.collect::<Vec<_>>().join(","));
+    }
+    line
+}
+
+/// A label value as a name segment: letters, digits, `-` and `_` kept, everything else `_`
+fn segment(value: &str) -> String {
+    let segment: String = value.trim_matches('/').chars()
+        .map(|c| if c.is_ascii_alphanumeric() || c == '-' { c } else { '_' })
+        .collect();
+    if segment.is_empty() { "root".to_string() } else { segment }
+}
+
+/// A label value as a tag value, without the protocol's separators
+fn tag_value(value: &str) -> String {
+    value.chars().map(|c| if c == ',' || c == '|' || c == '#' || c.is_whitespace() { '_' } else { c }).collect()
+}
@@ -0,0 +1,77 @@
+//! systemd integration (Linux only): listeners passed by socket activation (`LISTEN_FDS`), and
+//! readiness, shutdown and watchdog reports through `sd_notify` (`NOTIFY_SOCKET`). Without systemd
+//! neither variable is set, and nothing here does anything.
+
+use std::time::Duration;
+
+/// The first descriptor socket activation passes
+#[cfg(target_os = "linux")]
+const LISTEN_FDS_START: i32 = 3;
+
+/// The sockets systemd passed to this process, if it started it with socket activation
+pub fn listen_fds() -> Vec<i32> {
+    let for_us = std::env::var("LISTEN_PID").ok().and_then(|pid| pid.parse::<u32>().ok()) == Some(std::process::id());
+    let count = std::env::var("LISTEN_FDS").ok().and_then(|count| count.parse::<i32>().ok()).filter(|_| for_us).unwrap_or(0);
+    activated(count)
+}
+
+#[cfg(target_os = "linux")]
+fn activated(count: i32) -> Vec<i32> {
+    (LISTEN_FDS_START..LISTEN_FDS_START + count).collect()
+}
+
+#[cfg(not(target_os = "linux"))]
+fn activated(_count: i32) -> Vec<i32> {
+    Vec::new()
+}
+
+/// Report that the proxy accepts connections; a process that took the listeners over from an old
+/// one also becomes the service's main process
+pub fn ready(took_over: bool) {
+    if took_over {
+        notify(&format!("MAINPID={}\nREADY=1", std::process::id()));
+    } else {
+        notify("READY=1");
+    }
+}
+
+/// Report that the proxy is shutting down
+pub fn stopping() {
+    notify("STOPPING=1");
+}
+
+/// Ping the service watchdog at half its interval (`WatchdogSec=`), while the runtime is responsive.
+/// A process that took the listeners over pings in place of the old one, whose `WATCHDOG_PID` it inherited.
+pub async fn watchdog(took_over: bool) {
+    let for_us = took_over || std::env::var("WATCHDOG_PID").ok().map_or(true, |pid| pid.parse::<u32>().ok() == Some(std::process::id()));
+    let Some(interval) = std::env::var("WATCHDOG_USEC").ok().and_then(|usec| usec.parse::<u64>().ok()).filter(|&usec| usec > 0 && for_us) else {
+        return;
+    };
+    let mut ticks = tokio::time::interval(Duration::from_micros(interval) / 2);
+    loop {
+        ticks.tick().await;
+        notify("WATCHDOG=1");
+    }
+}
+
+#[cfg(target_os = "linux")]
+fn notify(state: &str) {
+    use std::os::linux::net::SocketAddrExt;
+    use std::os::unix::ffi::OsStrExt;
+    use std::os::unix::net::{SocketAddr, UnixDatagram};
+
+    let Some(path) = std::env::var_os("NOTIFY_SOCKET") else {
+        return;
+    };
+    let sent = UnixDatagram::unbound().and_then(|socket| match path.as_bytes().strip_prefix(b"@") {
+        // An abstract socket address
+        Some(name) => socket.send_to_addr(state.as_bytes(), &SocketAddr::from_abstract_name(name)?),
+        None => socket.send_to(state.as_bytes(), &path),
+    });
+    if let Err(e) = sent {
+        crate::logging::warning(format!("Failed to notify systemd: {}", e));
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+fn notify(_state: &str) {}
@@ -0,0 +1,70 @@
+//! Tarpitting (`;blocklist=tarpit`): instead of a quick refusal, listed clients get a response that
+//! never finishes, one byte at a time, tying up scanners for minutes at the cost of an idle
+//! connection. The number of tarpitted connections is capped; beyond it clients are refused.
+
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::Duration;
+use tokio::io::{AsyncWrite, AsyncWriteExt};
+use tokio::time::Instant;
+
+/// Time between two bytes
+const DRIP_INTERVAL: Duration = Duration::from_secs(1);
+
+pub struct Tarpit {
+    active: AtomicUsize,
+    /// Connections tarpitted at once at most (`--tarpit-max`)
+    max: usize,
+    /// How long a connection is held before it is closed (`--tarpit-duration`)
+    duration: Duration,
+}
+
+impl Tarpit {
+    pub fn new(max: usize, duration: Duration) -> Self {
+        Tarpit { active: AtomicUsize::new(0), max, duration }
+    }
+
+    /// A place in the tarpit, or None while it is full
+    pub fn enter(&self) -> Option<Place<'_>> {
+        self.active.fetch_update(Ordering::AcqRel, Ordering::Acquire, |active| (active < self.max).then_some(active + 1)).ok()?;
+        Some(Place { tarpit: self })
+    }
+}
+
+/// A tarpitted connection, counted until dropped
+pub struct Place<'t> {
+    tarpit: &'t Tarpit,
+}
+
+impl Place<'_> {
+    /// Drip a response head of endless header lines to the client until the tarpit's duration is
+    /// over or the client gives up; returns the bytes sent
+    pub async fn drip<S: AsyncWrite + Unpin>(&self, client: &mut S) -> u64 {
+        let deadline = Instant::now() + self.tarpit.duration;
+        let mut sent = 0;
+        let mut line = b"HTTP/1.1 200 OK\r\n".to_vec();
+        let mut pos = 0;
+        while Instant::now() < deadline {
+            if pos == line.len() {
+                line = format!("X-Request-Id: {:016x}\r\n", crate::otel::random_id()).into_bytes();
+                pos = 0;
+            }
+            let written = match client.write_all(&line[pos..pos + 1]).await {
+                Ok(()) => client.flush().await,
+                Err(e) => Err(e),
+            };
+            if written.is_err() {
+                break;
+            }
+            pos += 1;
+            sent += 1;
+            tokio::time::sleep(DRIP_INTERVAL).await;
+        }
+        sent
+    }
+}
+
+impl Drop for Place<'_> {
+    fn drop(&mut self) {
+        self.tarpit.active.fetch_sub(1, Ordering::AcqRel);
+    }
+}
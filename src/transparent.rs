@@ -0,0 +1,36 @@
+//! Transparent proxying (`--transparent`, Linux only): backend connections are made from the client's
+//! address instead of the proxy's, so backends see real client addresses without trusting headers.
+//! Needs `CAP_NET_ADMIN`, and policy routing that delivers the backends' replies to the proxy.
+
+use std::io;
+use std::net::SocketAddr;
+use tokio::net::TcpStream;
+
+/// Connect to a backend with the client's IP address as the source (and an ephemeral port)
+#[cfg(target_os = "linux")]
+pub async fn connect(backend: SocketAddr, client: SocketAddr) -> io::Result<TcpStream> {
+    use std::net::IpAddr;
+    use tokio::net::TcpSocket;
+
+    // Clients of a dual-stack listener appear as IPv4-mapped IPv6 addresses
+    let source = match client.ip() {
+        IpAddr::V6(v6) => v6.to_ipv4_mapped().map_or(IpAddr::V6(v6), IpAddr::V4),
+        ip => ip,
+    };
+    if source.is_ipv4() != backend.is_ipv4() {
+        return Err(io::Error::new(io::ErrorKind::InvalidInput, format!("cannot connect to {} from client address {}", backend, source)));
+    }
+
+    let socket = if backend.is_ipv4() { TcpSocket::new_v4()? } else { TcpSocket::new_v6()? };
+    socket2::SockRef::from(&socket).set_ip_transparent(true).map_err(|e| match e.kind() {
+        io::ErrorKind::PermissionDenied => io::Error::new(e.kind(), "IP_TRANSPARENT requires CAP_NET_ADMIN"),
+        _ => e,
+    })?;
+    socket.bind(SocketAddr::new(source, 0))?;
+    socket.connect(backend).await
+}
+
+#[cfg(not(target_os = "linux"))]
+pub async fn connect(_backend: SocketAddr, _client: SocketAddr) -> io::Result<TcpStream> {
+    Err(io::Error::new(io::ErrorKind::Unsupported, "transparent proxying is only supported on Linux"))
+}
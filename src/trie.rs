@@ -0,0 +1,170 @@
+//! Radix trie over byte strings, used to find every stored key that is a prefix of a request path
+//! in time proportional to the path length rather than the number of routes.
+
+pub struct RadixTrie<T> {
+    root: Node<T>,
+}
+
+struct Node<T> {
+    /// Edge label from the parent; empty only for the root
+    label: Vec<u8>,
+    values: Vec<T>,
+    /// Children ordered by the first byte of their label, which is unique among siblings
+    children: Vec<Node<T>>,
+}
+
+impl<T> Node<T> {
+    fn new(label: &[u8]) -> Self {
+        Node { label: label.to_vec(), values: Vec::new(), children: Vec::new() }
+    }
+
+    fn child(&self, key: &[u8]) -> Option<&Node<T>> {
+        let first = *key.first()?;
+        let pos = self.children.binary_search_by_key(&first, |c| c.label[0]).ok()?;
+        Some(&self.children[pos])
+    }
+}
+
+impl<T> Default for RadixTrie<T> {
+    fn default() -> Self {
+        RadixTrie { root: Node::new(b"") }
+    }
+}
+
+impl<T> RadixTrie<T> {
+    /// Add a value under `key`; keys may hold several values
+    pub fn insert(&mut self, key: &str, value: T) {
+        let mut node = &mut self.root;
+        let mut key = key.as_bytes();
+
+        while !key.is_empty() {
+            let pos = match node.children.binary_search_by_key(&key[0], |c| c.label[0]) {
+                Ok(pos) => pos,
+                Err(pos) => {
+                    node.children.insert(pos, Node::new(key));
+                    node = &mut node.children[pos];
+                    break;
+                }
+            };
+
+            let child = &mut node.children[pos];
+            let common = child.label.iter().zip(key).take_while(|(a, b)| a == b).count();
+            if common < child.label.len() {
+                // Split the edge: the shared part becomes a new node holding the old child
+                let mut old = std::mem::replace(child, Node::new(&key[..common]));
+                old.label.drain(..common);
+                child.children.push(old);
+            }
+            key = &key[common..];
+            node = child;
+        }
+
+        node.values.push(value);
+    }
+
+    /// Values stored under exactly `key`
+    pub fn get(&self, key: &str) -> &[T] {
+        let mut node = &self.root;
+        let mut key = key.as_bytes();
+
+        while !key.is_empty() {
+            match node.child(key) {
+                Some(child) if key.starts_with(&child.label) => {
+                    key = &key[child.label.len()..];
+                    node = child;
+                }
+                _ => return &[],
+            }
+        }
+        &node.values
+    }
+
+    /// Values of every stored key that is a prefix of `path`, shortest key first
+    pub fn prefixes_of<'t, 'p>(&'t self, path: &'p str) -> Prefixes<'t, 'p, T> {
+        Prefixes { node: Some(&self.root), path: path.as_bytes(), depth: 0 }
+    }
+}
+
+pub struct Prefixes<'t, 'p, T> {
+    node: Option<&'t Node<T>>,
+    path: &'p [u8],
+    depth: usize,
+}
+
+impl<'t, T> Iterator for Prefixes<'t, '_, T> {
+    type Item = &'t [T];
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            let node = self.node.take()?;
+
+            let rest = &self.path[self.depth..];
+            if let Some(child) = node.child(rest).filter(|c| rest.starts_with(&c.label)) {
+                self.node = Some(child);
+                self.depth += child.label.len();
+            }
+
+            if !node.values.is_empty() {
+                return Some(&node.values);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn trie(keys: &[&str]) -> RadixTrie<usize> {
+        let mut trie = RadixTrie::default();
+        for (idx, key) in keys.iter().enumerate() {
+            trie.insert(key, idx);
+        }
+        trie
+    }
+
+    #[test]
+    fn get_finds_exact_keys_only() {
+        let trie = trie(&["/api", "/api/v2", "/apple", "/"]);
+        assert_eq!(trie.get("/api"), [0]);
+        assert_eq!(trie.get("/api/v2"), [1]);
+        assert_eq!(trie.get("/apple"), [2]);
+        assert_eq!(trie.get("/"), [3]);
+        assert!(trie.get("/ap").is_empty());
+        assert!(trie.get("/api/").is_empty());
+        assert!(trie.get("/banana").is_empty());
+    }
+
+    #[test]
+    fn keys_hold_several_values() {
+        let trie = trie(&["/api", "/api", "/ap"]);
+        assert_eq!(trie.get("/api"), [0, 1]);
+        assert_eq!(trie.get("/ap"), [2]);
+    }
+
+    #[test]
+    fn insert_splits_edges_in_any_order() {
+        // The shorter key arrives after the longer one, splitting its edge
+        let trie = trie(&["/api/v2/users", "/api/v1", "/api", "/b"]);
+        assert_eq!(trie.get("/api/v2/users"), [0]);
+        assert_eq!(trie.get("/api/v1"), [1]);
+        assert_eq!(trie.get("/api"), [2]);
+        assert_eq!(trie.get("/b"), [3]);
+        assert!(trie.get("/api/v").is_empty());
+    }
+
+    #[test]
+    fn prefixes_of_yields_every_prefix_shortest_first() {
+        let trie = trie(&["/api/v2", "/", "/api", "/apple", "/api/v2/users/me"]);
+        let found: Vec<&[usize]> = trie.prefixes_of("/api/v2/users").collect();
+        assert_eq!(found, [&[1][..], &[2], &[0]]);
+        assert_eq!(trie.prefixes_of("/other").collect::<Vec<_>>(), [&[1][..]]);
+    }
+
+    #[test]
+    fn prefixes_of_an_empty_trie_or_path() {
+        assert_eq!(trie(&[]).prefixes_of("/api").count(), 0);
+        assert_eq!(trie(&["/api"]).prefixes_of("").count(), 0);
+        assert_eq!(trie(&[""]).prefixes_of("/api").collect::<Vec<_>>(), [&[0][..]]);
+    }
+}
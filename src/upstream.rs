@@ -0,0 +1,164 @@
+//! TLS to backends (`;tls`): rustls with verified certificates, or OpenSSL with legacy protocol
+//! versions and renegotiation for backends stuck on old TLS stacks (`;tls=legacy`). The client-facing
+//! TLS settings are separate and never relaxed by these.
+
+use crate::routing::{Backend, BackendTls};
+use std::io;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio::net::TcpStream;
+
+/// A backend connection, encrypted or not
+pub enum BackendStream {
+    Plain(TcpStream),
+    Tls(Box<tokio_rustls::client::TlsStream<TcpStream>>),
+    #[cfg(feature = "legacy-tls")]
+    Legacy(Box<tokio_openssl::SslStream<TcpStream>>),
+}
+
+impl BackendStream {
+    /// Complete the TLS handshake a route asks for on a new backend connection
+    pub async fn connect(tcp: TcpStream, backend: &Backend, tls: Option<&BackendTls>) -> io::Result<Self> {
+        match tls {
+            None => Ok(BackendStream::Plain(tcp)),
+            Some(BackendTls::Verified) => {
+                let server_name = tokio_rustls::rustls::pki_types::ServerName::try_from(server_name(backend).to_string())
+                    .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+                let stream = crate::client::tls_connector().connect(server_name, tcp).await?;
+                Ok(BackendStream::Tls(Box::new(stream)))
+            }
+            #[cfg(feature = "legacy-tls")]
+            Some(BackendTls::Legacy) => legacy::connect(tcp, backend).await,
+            #[cfg(not(feature = "legacy-tls"))]
+            Some(BackendTls::Legacy) => unreachable!("tls=legacy is rejected at load time without the legacy-tls feature"),
+        }
+    }
+
+    /// When the backend's TLS certificate expires, on an encrypted connection
+    pub fn certificate_expiry(&self) -> Option<std::time::SystemTime> {
+        match self {
+            BackendStream::Plain(_) => None,
+            BackendStream::Tls(stream) => crate::x509::not_after(stream.get_ref().1.peer_certificates()?.first()?),
+            #[cfg(feature = "legacy-tls")]
+            BackendStream::Legacy(stream) => crate::x509::not_after(&stream.ssl().peer_certificate()?.to_der().ok()?),
+        }
+    }
+
+    /// Have the connection reset instead of closed when it is dropped, so that the backend stops
+    /// working on the request at once
+    pub fn abort(&self) {
+        let tcp = match self {
+            BackendStream::Plain(stream) => stream,
+            BackendStream::Tls(stream) => stream.get_ref().0,
+            #[cfg(feature = "legacy-tls")]
+            BackendStream::Legacy(stream) => stream.get_ref(),
+        };
+        // Only a nonzero linger blocks on close; a zero one sends a reset right away
+        #[allow(deprecated)]
+        let _ = tcp.set_linger(Some(std::time::Duration::ZERO));
+    }
+}
+
+/// The host part of a backend address, used for SNI and certificate verification
+fn server_name(backend: &Backend) -> &str {
+    let host = backend.name.rsplit_once(':').map_or(&*backend.name, |(host, _)| host);
+    host.trim_start_matches('[').trim_end_matches(']')
+}
+
+#[cfg(feature = "legacy-tls")]
+mod legacy {
+    use super::{server_name, BackendStream};
+    use crate::routing::Backend;
+    use openssl::ssl::{SslConnector, SslMethod, SslOptions, SslVersion};
+    use std::io;
+    use std::pin::Pin;
+    use std::sync::OnceLock;
+    use tokio::net::TcpStream;
+
+    /// OpenSSL keeps TLS 1.0/1.1 and SHA-1 signatures behind security level 0
+    const LEGACY_CIPHERS: &str = "DEFAULT:@SECLEVEL=0";
+
+    fn connector() -> Result<&'static SslConnector, io::Error> {
+        static CONNECTOR: OnceLock<Result<SslConnector, String>> = OnceLock::new();
+        let connector = CONNECTOR.get_or_init(|| {
+            let mut builder = SslConnector::builder(SslMethod::tls_client()).map_err(|e| e.to_string())?;
+            builder.set_min_proto_version(Some(SslVersion::TLS1)).map_err(|e| e.to_string())?;
+            builder.set_cipher_list(LEGACY_CIPHERS).map_err(|e| e.to_string())?;
+            // Also permits the initial handshake with servers lacking the renegotiation extension
+            builder.set_options(SslOptions::ALLOW_UNSAFE_LEGACY_RENEGOTIATION);
+            Ok(builder.build())
+        });
+        connector.as_ref().map_err(|e| io::Error::new(io::ErrorKind::Other, format!("failed to set up legacy TLS: {}", e)))
+    }
+
+    pub async fn connect(tcp: TcpStream, backend: &Backend) -> io::Result<BackendStream> {
+        let to_io = |e: openssl::error::ErrorStack| io::Error::new(io::ErrorKind::Other, e);
+        let ssl = connector()?.configure().map_err(to_io)?.into_ssl(server_name(backend)).map_err(to_io)?;
+        let mut stream = tokio_openssl::SslStream::new(ssl, tcp).map_err(to_io)?;
+        Pin::new(&mut stream).connect().await.map_err(|e| io::Error::new(io::ErrorKind::Other, e))?;
+
+        let ssl = stream.ssl();
+        let cipher = ssl.current_cipher().map_or("unknown cipher", |cipher| cipher.name());
+        crate::logging::warning(format!("WARNING: legacy TLS to backend {}: {} {}", backend, ssl.version_str(), cipher));
+        Ok(BackendStream::Legacy(Box::new(stream)))
+    }
+}
+
+impl AsyncRead for BackendStream {
+    fn poll_read(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            BackendStream::Plain(stream) => Pin::new(stream).poll_read(cx, buf),
+            BackendStream::Tls(stream) => Pin::new(stream).poll_read(cx, buf),
+            #[cfg(feature = "legacy-tls")]
+            BackendStream::Legacy(stream) => Pin::new(stream).poll_read(cx, buf),
+        }
+    }
+}
+
+impl AsyncWrite for BackendStream {
+    fn poll_write(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<io::Result<usize>> {
+        match self.get_mut() {
+            BackendStream::Plain(stream) => Pin::new(stream).poll_write(cx, buf),
+            BackendStream::Tls(stream) => Pin::new(stream).poll_write(cx, buf),
+            #[cfg(feature = "legacy-tls")]
+            BackendStream::Legacy(stream) => Pin::new(stream).poll_write(cx, buf),
+        }
+    }
+
+    fn poll_write_vectored(self: Pin<&mut Self>, cx: &mut Context<'_>, bufs: &[io::IoSlice<'_>]) -> Poll<io::Result<usize>> {
+        match self.get_mut() {
+            BackendStream::Plain(stream) => Pin::new(stream).poll_write_vectored(cx, bufs),
+            BackendStream::Tls(stream) => Pin::new(stream).poll_write_vectored(cx, bufs),
+            #[cfg(feature = "legacy-tls")]
+            BackendStream::Legacy(stream) => Pin::new(stream).poll_write_vectored(cx, bufs),
+        }
+    }
+
+    fn is_write_vectored(&self) -> bool {
+        match self {
+            BackendStream::Plain(stream) => stream.is_write_vectored(),
+            BackendStream::Tls(stream) => stream.is_write_vectored(),
+            #[cfg(feature = "legacy-tls")]
+            BackendStream::Legacy(stream) => stream.is_write_vectored(),
+        }
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            BackendStream::Plain(stream) => Pin::new(stream).poll_flush(cx),
+            BackendStream::Tls(stream) => Pin::new(stream).poll_flush(cx),
+            #[cfg(feature = "legacy-tls")]
+            BackendStream::Legacy(stream) => Pin::new(stream).poll_flush(cx),
+        }
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            BackendStream::Plain(stream) => Pin::new(stream).poll_shutdown(cx),
+            BackendStream::Tls(stream) => Pin::new(stream).poll_shutdown(cx),
+            #[cfg(feature = "legacy-tls")]
+            BackendStream::Legacy(stream) => Pin::new(stream).poll_shutdown(cx),
+        }
+    }
+}
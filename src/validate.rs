@@ -0,0 +1,177 @@
+//! `reverse-http-proxy validate [OPTIONS]`: load the configuration the way startup does, without
+//! binding any listener, and report every problem found instead of stopping at the first one
+
+use crate::routing::RouteConfig;
+use crate::{accesslog, acme, asn, blocklist, certs, client, dns, errorpages, icap, k8s, logfile, logging, logsink, otel, plans, pool, quota, redirects, response, scrub, sniff, state, statsd, wellknown, Args};
+use clap::ArgMatches;
+use std::net::SocketAddr;
+
+/// Print diagnostics for the options; returns whether the proxy would start with them
+pub fn run(matches: &ArgMatches) -> bool {
+    let args = match Args::load(matches) {
+        Ok(args) => args,
+        Err(e) => {
+            eprintln!("error: {}", e);
+            return false;
+        }
+    };
+    let mut errors: Vec<String> = Vec::new();
+    let mut check = |result: Result<(), String>| {
+        if let Err(e) = result {
+            errors.push(e);
+        }
+    };
+
+    check(args.check());
+    let listeners = args.listeners();
+    if let Err(e) = &listeners {
+        check(Err(e.clone()));
+    }
+    let addresses = [("--admin", &args.admin_address), ("--dns-server", &args.dns_server)];
+    for (flag, address) in addresses {
+        if let Some(address) = address {
+            check(address.parse::<SocketAddr>().map(drop).map_err(|e| format!("Invalid {} '{}': {}", flag, address, e)));
+        }
+    }
+
+    if let Some(Ok(server)) = args.dns_server.as_deref().map(str::parse) {
+        dns::set_nameserver(server);
+    }
+    if let Some(url) = &args.k8s_api {
+        check(k8s::set_api(url));
+    }
+
+    // Routes from the command line and config file, then those a state file would replace them with
+    let mut tables = vec![("routes", RouteConfig::from_snapshot(args.snapshot()))];
+    if let Some(path) = &args.state_file {
+        match state::Snapshot::load(path) {
+            Ok(Some(snapshot)) => tables.push(("state file routes", RouteConfig::from_snapshot(snapshot))),
+            Ok(None) => {}
+            Err(e) => check(Err(e)),
+        }
+    }
+    let scanner = args.icap.as_deref().map(icap::Scanner::parse).transpose();
+    let mut quotas = quota::Quotas::default();
+    for spec in &args.host_quotas {
+        match quota::HostQuota::parse(spec) {
+            Ok((host, quota)) => quotas.add(host, quota),
+            Err(e) => check(Err(e)),
+        }
+    }
+    for (source, table) in &tables {
+        match table {
+            Ok(config) => {
+                if let Ok(scanner) = &scanner {
+                    check(crate::check_scanner(config, scanner.as_ref()).map_err(|e| format!("{} ({})", e, source)));
+                }
+                check(crate::check_blocklists(config, !args.blocklists.is_empty()).map_err(|e| format!("{} ({})", e, source)));
+                check(crate::check_asn_db(config, args.asn_db.is_some()).map_err(|e| format!("{} ({})", e, source)));
+                check(crate::check_user_identity(config, args.user_identity.is_some()).map_err(|e| format!("{} ({})", e, source)));
+                check(crate::check_host_quotas(config, &quotas).map_err(|e| format!("{} ({})", e, source)));
+                if let Ok(listeners) = &listeners {
+                    check(crate::check_listeners(config, listeners).map_err(|e| format!("{} ({})", e, source)));
+                }
+                for (route, winner) in config.shadowed_routes() {
+                    check(Err(format!("Route '{}' in {} is never used: '{}' wins every request it matches", route, source, winner)));
+                }
+            }
+            Err(e) => check(Err(format!("Invalid {}: {}", source, e))),
+        }
+    }
+
+    check(response::LocalResponse::new(args.not_found_status, "text/plain; charset=utf-8", &args.not_found_body, &[]).map(drop));
+    if let Some(path) = &args.redirect_map {
+        check(redirects::RedirectMap::load(path).map(drop));
+    }
+    if let Some(dir) = &args.custom_errors {
+        check(errorpages::ErrorPages::load(dir).map(drop));
+    }
+    let mut well_known = wellknown::WellKnownFiles::default();
+    for spec in &args.robots_txt {
+        check(well_known.add(wellknown::ROBOTS_TXT, spec));
+    }
+    for spec in &args.security_txt {
+        check(well_known.add(wellknown::SECURITY_TXT, spec));
+    }
+    check(sniff::Sniffer::parse(&args.sniff).map(drop));
+    check(scanner.map(drop));
+    for spec in &args.blocklists {
+        check(blocklist::validate(spec));
+    }
+    if let Some(path) = &args.asn_db {
+        check(asn::AsnDatabase::load(path).map(drop));
+    }
+    if let Some(source) = &args.user_identity {
+        check(plans::IdentitySource::parse(source).map(drop));
+    }
+    if let Some(path) = &args.rate_limit_plans {
+        check(plans::Plans::load(path).map(drop));
+    }
+    let tls_provider = client::tls_provider(args.tls_provider.as_deref());
+    check(tls_provider.clone().map(drop));
+    // Certificates are checked against their keys with the provider startup would use
+    client::install_tls_provider(tls_provider.or_else(|_| client::tls_provider(None)).expect("the default TLS provider is built in"));
+    if let Some(path) = &args.psk_keys {
+        check(crate::PskAcceptor::load(path).map(drop));
+    }
+    for listener in listeners.iter().flatten() {
+        // Startup issues the certificates of `;acme` listeners that are missing or unusable
+        if let Some((cert, key)) = listener.certificate.as_ref().filter(|_| listener.acme.is_empty()) {
+            check(certs::read(cert, key).map(drop).map_err(|e| format!("{} (listener {})", e, listener.name)));
+        }
+    }
+    if let Some(path) = &args.acme_account_key {
+        check(acme::read_account_key(path).map(drop));
+    }
+    check(client::Url::parse(&args.acme_directory).map(drop));
+    if let Some(url) = &args.alert_webhook {
+        check(client::Url::parse(url).map(drop));
+    }
+    check(pool::Strategy::parse(&args.lb).map(drop));
+    check(args.health_config().map(drop));
+    check(accesslog::LogFormat::parse(&args.log_format).map(drop));
+    check(logsink::DropPolicy::parse(&args.access_log_drop).map(drop));
+    check(scrub::Scrubber::new(&args.scrub_params, &args.scrub_patterns).map(drop));
+    check(args.capture_config().map(drop));
+    if let Some(sink) = &args.access_log_sink {
+        check(logsink::Target::parse(sink).map(drop));
+    }
+    check(statsd::Format::parse(&args.statsd_format).map(drop));
+    for tag in &args.statsd_tags {
+        check(statsd::check_tag(tag));
+    }
+    check(logging::Target::parse(&args.log_target).map(drop));
+    check(logging::parse_facility(&args.syslog_facility).map(drop));
+    if let Some(rules) = &args.access_log_rotate {
+        check(logfile::Rotation::parse(rules).map(drop));
+    }
+    if let Some(url) = &args.otlp_endpoint {
+        check(otel::endpoint(url).map(drop));
+    }
+
+    for e in &errors {
+        eprintln!("error: {}", e);
+    }
+    if !errors.is_empty() {
+        eprintln!("{} problem{} found", errors.len(), if errors.len() == 1 { "" } else { "s" });
+        return false;
+    }
+
+    let Some((_, Ok(config))) = tables.last() else {
+        return false;
+    };
+    let backends = config.backends();
+    println!("Configuration OK: {} routes, {} backends", route_count(config), backends.len());
+    for (backend, _) in backends {
+        println!("  {} -> {}", backend.name, backend.addr);
+    }
+    true
+}
+
+/// Number of routes of all kinds, without the default backend
+fn route_count(config: &RouteConfig) -> usize {
+    config.header_routes.iter().count()
+        + config.query_routes.iter().count()
+        + config.routes.iter().count()
+        + config.virtual_hosts.values().map(|v| v.backends.len() + v.routes.iter().count()).sum::<usize>()
+}
@@ -0,0 +1,61 @@
+//! Policy files (`/robots.txt`, `/.well-known/security.txt`) served by the proxy itself, globally or
+//! per virtual host, in place of whatever the backends would return
+
+use crate::request::RequestHead;
+use crate::response::LocalResponse;
+use crate::routing::normalize_host;
+use std::collections::HashMap;
+use std::path::Path;
+
+pub const ROBOTS_TXT: &str = "/robots.txt";
+pub const SECURITY_TXT: &str = "/.well-known/security.txt";
+
+/// Configured policy files by request path, then by host (`None` for all hosts)
+#[derive(Default)]
+pub struct WellKnownFiles {
+    files: HashMap<&'static str, HashMap<Option<String>, LocalResponse>>,
+}
+
+impl WellKnownFiles {
+    /// Serve the contents of a `[HOST=]FILE` spec at `path`
+    pub fn add(&mut self, path: &'static str, spec: &str) -> Result<(), String> {
+        let (host, file) = match spec.split_once('=') {
+            Some((host, file)) if !host.is_empty() => (Some(host.to_ascii_lowercase()), file),
+            _ => (None, spec),
+        };
+        let contents = std::fs::read_to_string(Path::new(file))
+            .map_err(|e| format!("Failed to read {} contents from {}: {}", path, file, e))?;
+        let response = LocalResponse::literal(200, "text/plain; charset=utf-8", &contents)?;
+
+        let by_host = self.files.entry(path).or_default();
+        if by_host.contains_key(&host) {
+            return Err(format!("{} is configured twice for {}", path, host.as_deref().unwrap_or("all hosts")));
+        }
+        by_host.insert(host, response);
+        Ok(())
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.files.is_empty()
+    }
+
+    /// The file for a GET or HEAD request, preferring one configured for the request's host
+    pub fn get(&self, request: &RequestHead) -> Option<&LocalResponse> {
+        if !matches!(request.method, "GET" | "HEAD") {
+            return None;
+        }
+        let by_host = self.files.get(request.path)?;
+        request.header_str("host")
+            .and_then(|host| by_host.get(&Some(normalize_host(host).into_owned())))
+            .or_else(|| by_host.get(&None))
+    }
+
+    /// Configured files as `(path, host)` pairs in a stable order, for the startup summary
+    pub fn list(&self) -> Vec<(&'static str, Option<&str>)> {
+        let mut files: Vec<_> = self.files.iter()
+            .flat_map(|(path, by_host)| by_host.keys().map(move |host| (*path, host.as_deref())))
+            .collect();
+        files.sort();
+        files
+    }
+}
@@ -0,0 +1,271 @@
+//! Just enough of X.509 to tell when a certificate expires and what names it is for: the
+//! `notAfter` time of its validity and the DNS names of its subject alternative names, read from
+//! the DER encoding without a full certificate parser
+
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+const SEQUENCE: u8 = 0x30;
+/// `[0]`, the explicit version tag of a v2 or v3 certificate
+const VERSION: u8 = 0xa0;
+const UTC_TIME: u8 = 0x17;
+const GENERALIZED_TIME: u8 = 0x18;
+/// `[3]`, the explicit tag of a v3 certificate's extensions
+const EXTENSIONS: u8 = 0xa3;
+const OID: u8 = 0x06;
+const OCTET_STRING: u8 = 0x04;
+/// `[2]`, a `dNSName` GeneralName
+const DNS_NAME: u8 = 0x82;
+/// 2.5.29.17, subjectAltName
+const SUBJECT_ALT_NAME: &[u8] = &[0x55, 0x1d, 0x11];
+
+/// When a DER-encoded certificate stops being valid, or None if it cannot be read
+pub fn not_after(der: &[u8]) -> Option<SystemTime> {
+    // Certificate ::= SEQUENCE { tbsCertificate, ... }, and tbsCertificate ::= SEQUENCE { [0] version
+    // OPTIONAL, serialNumber, signature, issuer, validity SEQUENCE { notBefore, notAfter }, ... }
+    let (certificate, _) = element(der, SEQUENCE)?;
+    let (mut tbs, _) = element(certificate, SEQUENCE)?;
+    if tbs.first() == Some(&VERSION) {
+        tbs = skip(tbs)?;
+    }
+    for _ in 0..3 {
+        tbs = skip(tbs)?;
+    }
+    let (validity, _) = element(tbs, SEQUENCE)?;
+    time(skip(validity)?)
+}
+
+/// The DNS names a DER-encoded certificate is for, from its subjectAltName extension; empty
+/// without one, and None if the certificate cannot be read
+pub fn dns_names(der: &[u8]) -> Option<Vec<String>> {
+    // ..., validity, subject, subjectPublicKeyInfo, [1] issuerUniqueID OPTIONAL, [2]
+    // subjectUniqueID OPTIONAL, [3] extensions OPTIONAL
+    let (certificate, _) = element(der, SEQUENCE)?;
+    let (mut tbs, _) = element(certificate, SEQUENCE)?;
+    if tbs.first() == Some(&VERSION) {
+        tbs = skip(tbs)?;
+    }
+    for _ in 0..6 {
+        tbs = skip(tbs)?;
+    }
+    while tbs.first().is_some_and(|&tag| tag != EXTENSIONS) {
+        tbs = skip(tbs)?;
+    }
+    let Some((extensions, _)) = element(tbs, EXTENSIONS) else {
+        return Some(Vec::new());
+    };
+    let (mut extensions, _) = element(extensions, SEQUENCE)?;
+    while !extensions.is_empty() {
+        // Extension ::= SEQUENCE { extnID, critical BOOLEAN DEFAULT FALSE, extnValue OCTET STRING }
+        let (extension, rest) = element(extensions, SEQUENCE)?;
+        extensions = rest;
+        let (id, mut value) = element(extension, OID)?;
+        if id != SUBJECT_ALT_NAME {
+            continue;
+        }
+        if value.first() != Some(&OCTET_STRING) {
+            value = skip(value)?;
+        }
+        let (value, _) = element(value, OCTET_STRING)?;
+        let (mut names, _) = element(value, SEQUENCE)?;
+        let mut dns_names = Vec::new();
+        while !names.is_empty() {
+            if let Some((name, _)) = element(names, DNS_NAME) {
+                dns_names.push(String::from_utf8_lossy(name).into_owned());
+            }
+            names = skip(names)?;
+        }
+        return Some(dns_names);
+    }
+    Some(Vec::new())
+}
+
+/// The content of the element at the start of `der` if it has the tag, and what follows it
+fn element(der: &[u8], tag: u8) -> Option<(&[u8], &[u8])> {
+    let (&found, rest) = der.split_first()?;
+    let (&first, rest) = rest.split_first()?;
+    if found != tag {
+        return None;
+    }
+    let (len, rest) = if first < 0x80 {
+        (first as usize, rest)
+    } else {
+        // Long form: the low bits count the length bytes that follow
+        let count = (first & 0x7f) as usize;
+        if count == 0 || count > 4 || rest.len() < count {
+            return None;
+        }
+        let len = rest[..count].iter().fold(0, |len, &byte| len << 8 | byte as usize);
+        (len, &rest[count..])
+    };
+    (rest.len() >= len).then(|| rest.split_at(len))
+}
+
+/// What follows the element at the start of `der`, whatever its tag
+fn skip(der: &[u8]) -> Option<&[u8]> {
+    element(der, *der.first()?).map(|(_, rest)| rest)
+}
+
+/// A UTCTime (`YYMMDDHHMMSSZ`) or GeneralizedTime (`YYYYMMDDHHMMSSZ`)
+fn time(der: &[u8]) -> Option<SystemTime> {
+    let (year, rest) = match *der.first()? {
+        UTC_TIME => {
+            let (value, _) = element(der, UTC_TIME)?;
+            // RFC 5280: years from 50 on are 19YY
+            let year = number(value.get(..2)?)?;
+            (if year < 50 { 2000 + year } else { 1900 + year }, &value[2..])
+        }
+        GENERALIZED_TIME => {
+            let (value, _) = element(der, GENERALIZED_TIME)?;
+            (number(value.get(..4)?)?, &value[4..])
+        }
+        _ => return None,
+    };
+    if rest.len() != 11 || rest[10] != b'Z' {
+        return None;
+    }
+    let field = |at: usize| number(&rest[at..at + 2]);
+    let (month, day, hour, minute, second) = (field(0)?, field(2)?, field(4)?, field(6)?, field(8)?);
+    if !(1..=12).contains(&month) || !(1..=31).contains(&day) || hour > 23 || minute > 59 || second > 60 {
+        return None;
+    }
+    let secs = days_from_civil(year, month, day) * 86400 + hour * 3600 + minute * 60 + second;
+    Some(UNIX_EPOCH + Duration::from_secs(u64::try_from(secs).ok()?))
+}
+
+fn number(digits: &[u8]) -> Option<i64> {
+    digits.iter().all(u8::is_ascii_digit).then(|| digits.iter().fold(0, |n, &digit| n * 10 + i64::from(digit - b'0')))
+}
+
+/// Days since 1970-01-01 of a civil date (Howard Hinnant's algorithm)
+fn days_from_civil(year: i64, month: i64, day: i64) -> i64 {
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = year.div_euclid(400);
+    let yoe = year - era * 400;
+    let doy = (153 * ((month + 9) % 12) + 2) / 5 + day - 1;
+    let doe = yoe * 365 + yoe / 4 - yoe / 100 + doy;
+    era * 146_097 + doe - 719_468
+}
+
+/// Days from now until a certificate expires, negative once it has
+pub fn days_remaining(expires: SystemTime) -> f64 {
+    let secs = match expires.duration_since(SystemTime::now()) {
+        Ok(left) => left.as_secs_f64(),
+        Err(e) => -e.duration().as_secs_f64(),
+    };
+    secs / 86400.0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A DER element, with a long-form length when the content needs one
+    fn tlv(tag: u8, content: &[u8]) -> Vec<u8> {
+        let mut der = vec![tag];
+        match content.len() {
+            len if len < 0x80 => der.push(len as u8),
+            len if len < 0x100 => der.extend_from_slice(&[0x81, len as u8]),
+            len => der.extend_from_slice(&[0x82, (len >> 8) as u8, len as u8]),
+        }
+        der.extend_from_slice(content);
+        der
+    }
+
+    /// A certificate with the given `notAfter` time element; the issuer is long enough for long-form lengths
+    fn certificate(not_after: &[u8], versioned: bool) -> Vec<u8> {
+        with_extensions(not_after, versioned, &[])
+    }
+
+    fn with_extensions(not_after: &[u8], versioned: bool, extensions: &[u8]) -> Vec<u8> {
+        let mut tbs = Vec::new();
+        if versioned {
+            tbs.extend(tlv(VERSION, &tlv(0x02, &[2])));
+        }
+        tbs.extend(tlv(0x02, &[0x01, 0x23])); // serial number
+        tbs.extend(tlv(SEQUENCE, &tlv(0x06, &[0x2a, 0x86, 0x48]))); // signature algorithm
+        tbs.extend(tlv(SEQUENCE, &[b'x'; 300])); // issuer
+        tbs.extend(tlv(SEQUENCE, &[tlv(UTC_TIME, b"240101000000Z"), not_after.to_vec()].concat()));
+        tbs.extend(tlv(SEQUENCE, b"subject"));
+        tbs.extend(tlv(SEQUENCE, b"subjectPublicKeyInfo"));
+        if !extensions.is_empty() {
+            tbs.extend(tlv(EXTENSIONS, &tlv(SEQUENCE, extensions)));
+        }
+        tlv(SEQUENCE, &[tlv(SEQUENCE, &tbs), tlv(SEQUENCE, b"algorithm"), tlv(0x03, b"signature")].concat())
+    }
+
+    fn at(secs: u64) -> Option<SystemTime> {
+        Some(UNIX_EPOCH + Duration::from_secs(secs))
+    }
+
+    #[test]
+    fn utc_time_years_before_and_after_2050() {
+        assert_eq!(not_after(&certificate(&tlv(UTC_TIME, b"491231235959Z"), true)), at(2_524_607_999));
+        assert_eq!(not_after(&certificate(&tlv(UTC_TIME, b"991231235959Z"), true)), at(946_684_799));
+        assert_eq!(not_after(&certificate(&tlv(UTC_TIME, b"240229120000Z"), false)), at(1_709_208_000));
+        // 1950, before the epoch
+        assert_eq!(not_after(&certificate(&tlv(UTC_TIME, b"500101000000Z"), true)), None);
+    }
+
+    #[test]
+    fn generalized_time() {
+        assert_eq!(not_after(&certificate(&tlv(GENERALIZED_TIME, b"20500101000000Z"), true)), at(2_524_608_000));
+        assert_eq!(not_after(&certificate(&tlv(GENERALIZED_TIME, b"99991231235959Z"), true)), at(253_402_300_799));
+    }
+
+    #[test]
+    fn rejects_malformed_times() {
+        for time in [
+            tlv(UTC_TIME, b"491331235959Z"),
+            tlv(UTC_TIME, b"491200235959Z"),
+            tlv(UTC_TIME, b"491231245959Z"),
+            tlv(UTC_TIME, b"4912312359Z"),
+            tlv(UTC_TIME, b"491231235959+0100"),
+            tlv(GENERALIZED_TIME, b"20500101000000.5Z"),
+            tlv(GENERALIZED_TIME, b"2050010100000xZ"),
+            tlv(0x04, b"20500101000000Z"),
+        ] {
+            assert_eq!(not_after(&certificate(&time, true)), None, "{:?}", String::from_utf8_lossy(&time));
+        }
+    }
+
+    #[test]
+    fn rejects_truncated_certificates() {
+        let der = certificate(&tlv(UTC_TIME, b"491231235959Z"), true);
+        for len in 0..der.len() {
+            assert_eq!(not_after(&der[..len]), None, "{} bytes", len);
+        }
+    }
+
+    #[test]
+    fn subject_alt_names() {
+        let expiry = tlv(UTC_TIME, b"491231235959Z");
+        let names = [tlv(DNS_NAME, b"*.example.com"), tlv(0x87, &[10, 0, 0, 1]), tlv(DNS_NAME, b"example.com")].concat();
+        let san = tlv(SEQUENCE, &[tlv(OID, SUBJECT_ALT_NAME), tlv(0x01, &[0xff]), tlv(OCTET_STRING, &tlv(SEQUENCE, &names))].concat());
+        let key_usage = tlv(SEQUENCE, &[tlv(OID, &[0x55, 0x1d, 0x0f]), tlv(OCTET_STRING, &[0x03, 0x02, 0x07, 0x80])].concat());
+        let der = with_extensions(&expiry, true, &[key_usage, san].concat());
+        assert_eq!(dns_names(&der), Some(vec!["*.example.com".to_string(), "example.com".to_string()]));
+        assert_eq!(not_after(&der), at(2_524_607_999));
+        assert_eq!(dns_names(&certificate(&expiry, true)), Some(Vec::new()));
+        assert_eq!(dns_names(&der[..der.len() - 20]), None);
+    }
+
+    #[test]
+    fn long_form_lengths() {
+        assert_eq!(element(&[0x04, 0x81, 2, b'a', b'b', b'c'], 0x04), Some((&b"ab"[..], &b"c"[..])));
+        assert_eq!(element(&[0x04, 0x82, 0, 1, b'a'], 0x04), Some((&b"a"[..], &b""[..])));
+        assert_eq!(element(&[0x04, 0x80, b'a'], 0x04), None);
+        assert_eq!(element(&[0x04, 0x85, 0, 0, 0, 0, 1, b'a'], 0x04), None);
+        assert_eq!(element(&[0x04, 0x84, 0xff, 0xff, 0xff, 0xff], 0x04), None);
+        assert_eq!(element(&[0x04, 0x01, b'a'], 0x05), None);
+    }
+
+    #[test]
+    fn civil_dates() {
+        assert_eq!(days_from_civil(1970, 1, 1), 0);
+        assert_eq!(days_from_civil(1969, 12, 31), -1);
+        assert_eq!(days_from_civil(2000, 3, 1), 11_017);
+        assert_eq!(days_from_civil(2024, 2, 29), 19_782);
+        assert_eq!(days_from_civil(2100, 3, 1), 47_541);
+        assert_eq!(days_from_civil(1600, 2, 29), -135_081);
+    }
+}
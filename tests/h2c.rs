@@ -0,0 +1,105 @@
+//! h2c upgrades on a plaintext listener: the proxy switches the connection to HTTP/2 itself and
+//! routes each of its streams like the request of a connection of its own.
+
+mod common;
+
+use common::{backend, free_address, named_backend, read_response, Proxy};
+use std::collections::HashMap;
+use std::io::{BufReader, Read, Write};
+use std::net::TcpStream;
+use std::time::Duration;
+
+const HEADERS: u8 = 0x1;
+const DATA: u8 = 0x0;
+const SETTINGS: u8 = 0x4;
+const END_STREAM: u8 = 0x1;
+const END_HEADERS: u8 = 0x4;
+const ACK: u8 = 0x1;
+
+fn frame(kind: u8, flags: u8, stream: u32, payload: &[u8]) -> Vec<u8> {
+    let mut frame = (payload.len() as u32).to_be_bytes()[1..].to_vec();
+    frame.extend_from_slice(&[kind, flags]);
+    frame.extend_from_slice(&stream.to_be_bytes());
+    frame.extend_from_slice(payload);
+    frame
+}
+
+fn read_frame(stream: &mut TcpStream) -> (u8, u8, u32, Vec<u8>) {
+    let mut header = [0u8; 9];
+    stream.read_exact(&mut header).unwrap();
+    let length = u32::from_be_bytes([0, header[0], header[1], header[2]]) as usize;
+    let mut payload = vec![0u8; length];
+    stream.read_exact(&mut payload).unwrap();
+    (header[3], header[4], u32::from_be_bytes([header[5], header[6], header[7], header[8]]) & 0x7fff_ffff, payload)
+}
+
+/// A GET request's header block, in HPACK literals without indexing (names and values below 127 bytes)
+fn get(path: &str) -> Vec<u8> {
+    let mut block = Vec::new();
+    for (name, value) in [(":method", "GET"), (":scheme", "http"), (":authority", "example.com"), (":path", path)] {
+        block.push(0);
+        for string in [name, value] {
+            block.push(string.len() as u8);
+            block.extend_from_slice(string.as_bytes());
+        }
+    }
+    block
+}
+
+#[test]
+fn upgrade_switches_to_http2_and_routes_each_stream() {
+    let (listen, backend, api) = (free_address(), backend(), named_backend("api:"));
+    let route = format!("/api={};strip-prefix", api);
+    let _proxy = Proxy::start(&listen, &[&backend, "-r", &route]);
+
+    let mut stream = TcpStream::connect(&listen).unwrap();
+    stream.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+    stream.write_all(b"GET /hello HTTP/1.1\r\nHost: example.com\r\nConnection: Upgrade, HTTP2-Settings\r\nUpgrade: h2c\r\nHTTP2-Settings: AAMAAABkAAQAoAAAAAIAAAAA\r\n\r\n").unwrap();
+    let mut head = Vec::new();
+    while !head.ends_with(b"\r\n\r\n") {
+        let mut byte = [0u8; 1];
+        stream.read_exact(&mut byte).unwrap();
+        head.push(byte[0]);
+    }
+    assert!(head.starts_with(b"HTTP/1.1 101 "), "{}", String::from_utf8_lossy(&head));
+
+    let mut client = b"PRI * HTTP/2.0\r\n\r\nSM\r\n\r\n".to_vec();
+    client.extend(frame(SETTINGS, 0, 0, &[]));
+    client.extend(frame(HEADERS, END_STREAM | END_HEADERS, 3, &get("/api/x")));
+    stream.write_all(&client).unwrap();
+
+    // Stream 1 is the upgrade request
+    let (mut statuses, mut bodies, mut ended) = (HashMap::new(), HashMap::<u32, Vec<u8>>::new(), 0);
+    while ended < 2 {
+        let (kind, flags, id, payload) = read_frame(&mut stream);
+        match kind {
+            SETTINGS if flags & ACK == 0 => stream.write_all(&frame(SETTINGS, ACK, 0, &[])).unwrap(),
+            HEADERS => {
+                statuses.insert(id, payload[0]);
+            }
+            DATA => bodies.entry(id).or_default().extend_from_slice(&payload),
+            _ => continue,
+        }
+        if matches!(kind, HEADERS | DATA) && flags & END_STREAM != 0 {
+            ended += 1;
+        }
+    }
+    // 0x88 is the static table's `:status: 200`
+    assert_eq!(statuses, HashMap::from([(1, 0x88), (3, 0x88)]));
+    assert_eq!(bodies[&1], b"/hello");
+    assert_eq!(bodies[&3], b"api:/x");
+}
+
+#[test]
+fn requests_not_asking_for_h2c_properly_stay_on_http1() {
+    let (listen, backend) = (free_address(), backend());
+    let _proxy = Proxy::start(&listen, &[&backend]);
+
+    let stream = TcpStream::connect(&listen).unwrap();
+    stream.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+    let mut reader = BufReader::new(stream.try_clone().unwrap());
+    let mut stream = stream;
+    // Without HTTP2-Settings
+    stream.write_all(b"GET /plain HTTP/1.1\r\nHost: example.com\r\nConnection: Upgrade\r\nUpgrade: h2c\r\n\r\n").unwrap();
+    assert_eq!(read_response(&mut reader), (200, "/plain".to_string()));
+}
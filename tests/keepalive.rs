@@ -0,0 +1,154 @@
+//! Requests after the first on a kept-alive client connection. While some route checks or changes
+//! its requests, each later request is routed, checked and rewritten on its own; otherwise the
+//! requests go on to the backend connection of the first.
+
+mod common;
+
+use common::{backend, free_address, named_backend, read_response, Proxy};
+use std::io::{BufRead, BufReader, Write};
+use std::net::{TcpListener, TcpStream};
+use std::thread;
+use std::time::Duration;
+
+/// A backend answering each request with the number of the connection it came on
+fn counting_backend() -> String {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let address = listener.local_addr().unwrap().to_string();
+    thread::spawn(move || {
+        for (connection, stream) in listener.incoming().flatten().enumerate() {
+            thread::spawn(move || {
+                let mut reader = BufReader::new(stream.try_clone().unwrap());
+                let mut stream = stream;
+                loop {
+                    let mut line = String::new();
+                    loop {
+                        line.clear();
+                        if reader.read_line(&mut line).unwrap_or(0) == 0 {
+                            return;
+                        }
+                        if line == "\r\n" {
+                            break;
+                        }
+                    }
+                    let body = connection.to_string();
+                    let response = format!("HTTP/1.1 200 OK\r\nContent-Length: {}\r\n\r\n{}", body.len(), body);
+                    if stream.write_all(response.as_bytes()).is_err() {
+                        return;
+                    }
+                }
+            });
+        }
+    });
+    address
+}
+
+/// Send two requests on one connection, reading the first response before sending the second
+fn two_requests(proxy: &str, first: &str, second: &str) -> ((u16, String), (u16, String)) {
+    let stream = TcpStream::connect(proxy).unwrap();
+    stream.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+    let mut reader = BufReader::new(stream.try_clone().unwrap());
+    let mut stream = stream;
+    stream.write_all(format!("GET {} HTTP/1.1\r\nHost: example.com\r\n\r\n", first).as_bytes()).unwrap();
+    let first = read_response(&mut reader);
+    stream.write_all(format!("GET {} HTTP/1.1\r\nHost: example.com\r\n\r\n", second).as_bytes()).unwrap();
+    (first, read_response(&mut reader))
+}
+
+#[test]
+fn open_route_relays_every_request() {
+    let (listen, backend) = (free_address(), backend());
+    let _proxy = Proxy::start(&listen, &[&backend]);
+
+    let (first, second) = two_requests(&listen, "/one", "/two");
+    assert_eq!(first, (200, "/one".to_string()));
+    assert_eq!(second, (200, "/two".to_string()));
+}
+
+#[test]
+fn later_request_is_routed_to_its_own_guarded_route() {
+    let (listen, backend) = (free_address(), backend());
+    let route = format!("/internal={};listener=internal", backend);
+    let internal = format!("{};name=internal", free_address());
+    let _proxy = Proxy::start(&listen, &[&backend, "-r", &route, "--listen", &internal]);
+
+    let (first, second) = two_requests(&listen, "/open", "/internal/x");
+    assert_eq!(first, (200, "/open".to_string()));
+    assert_eq!(second.0, 404);
+}
+
+#[test]
+fn rate_limited_route_checks_every_request() {
+    let (listen, backend) = (free_address(), backend());
+    let route = format!("/limited={};rate-limit=1/m", backend);
+    let _proxy = Proxy::start(&listen, &[&backend, "-r", &route]);
+
+    let (first, second) = two_requests(&listen, "/limited/a", "/limited/b");
+    assert_eq!(first, (200, "/limited/a".to_string()));
+    assert_eq!(second.0, 429);
+}
+
+#[test]
+fn pipelined_requests_are_rewritten_and_routed_on_their_own() {
+    let (listen, backend, api) = (free_address(), backend(), named_backend("api:"));
+    let route = format!("/api={};strip-prefix", api);
+    let _proxy = Proxy::start(&listen, &[&backend, "-r", &route]);
+
+    let stream = TcpStream::connect(&listen).unwrap();
+    stream.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+    let mut reader = BufReader::new(stream.try_clone().unwrap());
+    let mut stream = stream;
+    let requests = ["/api/x", "/other", "/api/y"].map(|path| format!("GET {} HTTP/1.1\r\nHost: example.com\r\n\r\n", path));
+    stream.write_all(requests.concat().as_bytes()).unwrap();
+    assert_eq!(read_response(&mut reader), (200, "api:/x".to_string()));
+    assert_eq!(read_response(&mut reader), (200, "/other".to_string()));
+    assert_eq!(read_response(&mut reader), (200, "api:/y".to_string()));
+}
+
+#[test]
+fn requests_with_ambiguous_framing_are_refused() {
+    let (listen, backend) = (free_address(), backend());
+    let route = format!("/limited={};rate-limit=100/m", backend);
+    let _proxy = Proxy::start(&listen, &[&backend, "-r", &route]);
+    let smuggling = "POST /limited/b HTTP/1.1\r\nHost: example.com\r\nContent-Length: 5\r\nTransfer-Encoding: chunked\r\n\r\n0\r\n\r\n";
+
+    let stream = TcpStream::connect(&listen).unwrap();
+    stream.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+    let mut reader = BufReader::new(stream.try_clone().unwrap());
+    let mut stream = stream;
+    stream.write_all(smuggling.as_bytes()).unwrap();
+    assert_eq!(read_response(&mut reader).0, 400);
+
+    // A later request on a connection whose requests are followed
+    let stream = TcpStream::connect(&listen).unwrap();
+    stream.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+    let mut reader = BufReader::new(stream.try_clone().unwrap());
+    let mut stream = stream;
+    stream.write_all(format!("GET /limited/a HTTP/1.1\r\nHost: example.com\r\n\r\n{}", smuggling).as_bytes()).unwrap();
+    assert_eq!(read_response(&mut reader), (200, "/limited/a".to_string()));
+    assert_eq!(read_response(&mut reader).0, 400);
+}
+
+#[test]
+fn connection_authentication_pins_the_backend_connection() {
+    let (listen, backend) = (free_address(), counting_backend());
+    let route = format!("/app={};rate-limit=100/m", backend);
+    let _proxy = Proxy::start(&listen, &[&backend, "-r", &route]);
+    let exchange = |authorization: &str| {
+        let stream = TcpStream::connect(&listen).unwrap();
+        stream.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+        let mut reader = BufReader::new(stream.try_clone().unwrap());
+        let mut stream = stream;
+        stream.write_all(format!("GET /app/a HTTP/1.1\r\nHost: example.com\r\n{}\r\n", authorization).as_bytes()).unwrap();
+        let first = read_response(&mut reader).1;
+        stream.write_all(b"GET /app/b HTTP/1.1\r\nHost: example.com\r\n\r\n").unwrap();
+        (first, read_response(&mut reader).1)
+    };
+
+    // Each request of a followed connection gets a backend connection of its own
+    let (first, second) = exchange("");
+    assert_ne!(first, second);
+    for authorization in ["Authorization: Negotiate YIIGhgYGKwYBBQUCoIIGejCCBnag\r\n", "Authorization: NTLM TlRMTVNTUAABAAAAB4IIogAAAAAAAAAAAAAAAAAAAAAGAbEdAAAADw==\r\n"] {
+        let (first, second) = exchange(authorization);
+        assert_eq!(first, second, "{}", authorization);
+    }
+}
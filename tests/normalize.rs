@@ -0,0 +1,40 @@
+//! Request paths as backends receive them: routes match the normal form of a path, which is
+//! forwarded, except to `;verbatim` routes, which get the request line as the client sent it.
+
+mod common;
+
+use common::{backend, free_address, named_backend, read_response, Proxy};
+use std::io::{BufReader, Write};
+use std::net::TcpStream;
+use std::time::Duration;
+
+/// The response to one request with the given target
+fn request(proxy: &str, target: &str) -> (u16, String) {
+    let stream = TcpStream::connect(proxy).unwrap();
+    stream.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+    let mut reader = BufReader::new(stream.try_clone().unwrap());
+    let mut stream = stream;
+    stream.write_all(format!("GET {} HTTP/1.1\r\nHost: example.com\r\n\r\n", target).as_bytes()).unwrap();
+    read_response(&mut reader)
+}
+
+#[test]
+fn normalized_paths_are_routed_and_forwarded() {
+    let (listen, backend, admin) = (free_address(), backend(), named_backend("admin:"));
+    let route = format!("/admin={}", admin);
+    let _proxy = Proxy::start(&listen, &[&backend, "-r", &route]);
+
+    assert_eq!(request(&listen, "//%61dmin/x/../users?q=%7E"), (200, "admin:/admin/users?q=%7E".to_string()));
+    assert_eq!(request(&listen, "/public/../admin"), (200, "admin:/admin".to_string()));
+}
+
+#[test]
+fn verbatim_routes_get_the_request_line_as_sent() {
+    let (listen, backend, hooks) = (free_address(), backend(), named_backend("hooks:"));
+    let route = format!("/hooks={};verbatim", hooks);
+    let _proxy = Proxy::start(&listen, &[&backend, "-r", &route]);
+
+    assert_eq!(request(&listen, "/hooks//a/%7Eb/../c"), (200, "hooks:/hooks//a/%7Eb/../c".to_string()));
+    assert_eq!(request(&listen, "/x/../hooks/%2e/y"), (200, "hooks:/x/../hooks/%2e/y".to_string()));
+    assert_eq!(request(&listen, "/x/../other"), (200, "/other".to_string()));
+}
@@ -0,0 +1,118 @@
+//! Certificate TLS listeners (`--listen ADDRESS;cert=PATH;key=PATH`), with the fixture
+//! certificate for `localhost` in tests/certs.
+
+use std::io::{BufRead, BufReader, Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::path::{Path, PathBuf};
+use std::process::{Child, Command, Stdio};
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant};
+use tokio_rustls::rustls::crypto::CryptoProvider;
+use tokio_rustls::rustls::pki_types::pem::PemObject;
+use tokio_rustls::rustls::pki_types::{CertificateDer, ServerName};
+use tokio_rustls::rustls::{ClientConfig, ClientConnection, RootCertStore, StreamOwned};
+
+/// The proxy, killed when the test ends
+struct Proxy(Child);
+
+impl Proxy {
+    fn start(wait_for: &str, args: &[&str]) -> Self {
+        let child = Command::new(env!("CARGO_BIN_EXE_reverse-http-proxy"))
+            .args(args)
+            .stdout(Stdio::null())
+            .stderr(Stdio::null())
+            .spawn()
+            .expect("failed to start the proxy");
+        let proxy = Proxy(child);
+        let started = Instant::now();
+        while TcpStream::connect(wait_for).is_err() {
+            assert!(started.elapsed() < Duration::from_secs(10), "the proxy did not start listening on {}", wait_for);
+            thread::sleep(Duration::from_millis(20));
+        }
+        proxy
+    }
+}
+
+impl Drop for Proxy {
+    fn drop(&mut self) {
+        let _ = self.0.kill();
+        let _ = self.0.wait();
+    }
+}
+
+/// A local address nothing listens on yet
+fn free_address() -> String {
+    TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap().to_string()
+}
+
+fn fixture(name: &str) -> PathBuf {
+    Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/certs").join(name)
+}
+
+/// A backend answering one request per connection with `200 OK` and the path
+fn backend() -> String {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let address = listener.local_addr().unwrap().to_string();
+    thread::spawn(move || {
+        for stream in listener.incoming().flatten() {
+            let mut reader = BufReader::new(stream.try_clone().unwrap());
+            let mut stream = stream;
+            let mut request_line = String::new();
+            reader.read_line(&mut request_line).unwrap_or(0);
+            let path = request_line.split(' ').nth(1).unwrap_or_default();
+            let response = format!("HTTP/1.1 200 OK\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}", path.len(), path);
+            let _ = stream.write_all(response.as_bytes());
+        }
+    });
+    address
+}
+
+/// A TLS client trusting only the fixture certificate, offering the given ALPN protocols
+fn connect(address: &str, alpn: &[&[u8]]) -> StreamOwned<ClientConnection, TcpStream> {
+    #[cfg(feature = "ring")]
+    let provider = tokio_rustls::rustls::crypto::ring::default_provider();
+    #[cfg(not(feature = "ring"))]
+    let provider = tokio_rustls::rustls::crypto::aws_lc_rs::default_provider();
+    let mut roots = RootCertStore::empty();
+    roots.add(CertificateDer::from_pem_file(fixture("localhost.pem")).unwrap()).unwrap();
+    let mut config = ClientConfig::builder_with_provider(Arc::new(provider) as Arc<CryptoProvider>)
+        .with_safe_default_protocol_versions()
+        .unwrap()
+        .with_root_certificates(roots)
+        .with_no_client_auth();
+    config.alpn_protocols = alpn.iter().map(|protocol| protocol.to_vec()).collect();
+    let connection = ClientConnection::new(Arc::new(config), ServerName::try_from("localhost").unwrap()).unwrap();
+    let tcp = TcpStream::connect(address).unwrap();
+    tcp.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+    StreamOwned::new(connection, tcp)
+}
+
+#[test]
+fn serves_the_certificate_and_proxies_requests() {
+    let (listen, tls, backend) = (free_address(), free_address(), backend());
+    let (cert, key) = (fixture("localhost.pem"), fixture("localhost-key.pem"));
+    let tls_listener = format!("{};name=tls;cert={};key={}", tls, cert.display(), key.display());
+    let _proxy = Proxy::start(&tls, &[&listen, &backend, "--listen", &tls_listener]);
+
+    let mut stream = connect(&tls, &[b"h2", b"http/1.1"]);
+    stream.write_all(b"GET /secure HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n").unwrap();
+    let mut response = String::new();
+    stream.read_to_string(&mut response).unwrap();
+    assert!(response.starts_with("HTTP/1.1 200"), "{}", response);
+    assert!(response.ends_with("\r\n/secure"), "{}", response);
+    // The proxy speaks HTTP/1.1 to clients, whatever they offer
+    assert_eq!(stream.conn.alpn_protocol(), Some(&b"http/1.1"[..]));
+}
+
+#[test]
+fn refuses_to_start_with_a_key_of_another_certificate() {
+    let tls = format!("{};cert={};key={}", free_address(), fixture("localhost.pem").display(), fixture("other-key.pem").display());
+    let output = Command::new(env!("CARGO_BIN_EXE_reverse-http-proxy"))
+        .args([&free_address(), "127.0.0.1:9", "--listen", &tls])
+        .output()
+        .unwrap();
+    assert!(!output.status.success());
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(stderr.contains("Private key does not match the certificate"), "{}", stderr);
+}